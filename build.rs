@@ -1,5 +1,6 @@
 // build.rs
 
+use std::fs::File;
 use std::path::PathBuf;
 
 fn main() {
@@ -10,10 +11,31 @@ fn main() {
     // https://doc.rust-lang.org/nightly/cargo/reference/unstable.html#artifact-dependencies
     let kernel = PathBuf::from(std::env::var_os("CARGO_BIN_FILE_KERNEL_kernel").unwrap());
 
+    // pack the user programs, built the same way, into the initrd as bin/<name>
+    let initrd_path = out_dir.join("initrd.tar");
+    let mut initrd = tar::Builder::new(File::create(&initrd_path).unwrap());
+    initrd.mode(tar::HeaderMode::Deterministic);
+    let mut programs: Vec<_> = std::env::vars()
+        .filter_map(|(variable, path)| Some((variable.strip_prefix("CARGO_BIN_FILE_USER_")?.to_string(), path)))
+        .collect();
+    programs.sort();
+    for (name, path) in programs {
+        initrd.append_path_with_name(path, format!("bin/{name}")).unwrap();
+    }
+    // along with the boot configuration, and the font, sprites and splash image in assets/,
+    // if there are any, as assets/<name>
+    if std::path::Path::new("kernel.cfg").is_file() {
+        initrd.append_path("kernel.cfg").unwrap();
+    }
+    if std::path::Path::new("assets").is_dir() {
+        initrd.append_dir_all("assets", "assets").unwrap();
+    }
+    initrd.finish().unwrap();
+
     // create an UEFI disk image (optional)
     let uefi_path = out_dir.join("uefi.img");
-    bootloader::UefiBoot::new(&kernel).create_disk_image(&uefi_path).unwrap();
+    bootloader::UefiBoot::new(&kernel).set_ramdisk(&initrd_path).create_disk_image(&uefi_path).unwrap();
 
     // pass the disk image paths as env variables to the `main.rs`
     println!("cargo:rustc-env=UEFI_PATH={}", uefi_path.display());
-}
\ No newline at end of file
+}
@@ -0,0 +1,336 @@
+use ::acpi::fadt::Fadt;
+use ::acpi::madt::{Madt, MadtEntry};
+use ::acpi::{AcpiHandler, AcpiTables, HpetInfo, PciConfigRegions, PhysicalMapping};
+use core::fmt::Write;
+use core::ptr::NonNull;
+use core::slice;
+use kernel::serial;
+use kernel::sync::SpinLock;
+use x86_64::{PhysAddr, VirtAddr};
+
+// The ACPI tables the firmware leaves in memory. The RSDP the bootloader found points at the
+// RSDT, or the XSDT on ACPI 2.0 and later, which lists every other table. The root is checked
+// once at boot, and kept; as mappings of the tables can't be shared between tasks, each lookup
+// walks the root again, which is cheap as all of physical memory is mapped already.
+static ROOT: SpinLock<Option<Root>> = SpinLock::new(None);
+// What the MADT says, read once by `init`
+static INTERRUPT_CONTROLLERS: SpinLock<Option<InterruptControllers>> = SpinLock::new(None);
+
+// The most of each kind of MADT entry kept, more than any machine this runs on has
+const MAX_CPUS: usize = 64;
+const MAX_IO_APICS: usize = 8;
+const MAX_OVERRIDES: usize = 16;
+
+// Local APIC flags: the CPU can be used, or can be brought online later
+const CPU_ENABLED: u32 = 1 << 0;
+const CPU_ONLINE_CAPABLE: u32 = 1 << 1;
+// Interrupt override flags: the polarity and trigger mode take two bits each, where 0b11 is
+// active low or level-triggered, and 0 is what the bus does, for ISA active high and edge
+const POLARITY: u16 = 0b11;
+const ACTIVE_LOW: u16 = 0b11;
+const TRIGGER_MODE: u16 = 0b11 << 2;
+const LEVEL_TRIGGERED: u16 = 0b11 << 2;
+
+#[derive(Clone, Copy)]
+struct Root {
+    rsdp: usize,
+    handler: AcpiHandlerImpl,
+}
+
+/// Maps ACPI tables through the bootloader's mapping of all physical memory, so nothing needs
+/// mapping or unmapping.
+#[derive(Clone, Copy)]
+pub struct AcpiHandlerImpl {
+    physical_memory_offset: VirtAddr,
+}
+
+impl AcpiHandlerImpl {
+    pub fn new(physical_memory_offset: VirtAddr) -> Self {
+        Self {
+            physical_memory_offset,
+        }
+    }
+}
+
+unsafe impl Send for AcpiHandlerImpl {}
+unsafe impl Sync for AcpiHandlerImpl {}
+
+impl AcpiHandler for AcpiHandlerImpl {
+    unsafe fn map_physical_region<T>(
+        &self,
+        physical_address: usize,
+        size: usize,
+    ) -> PhysicalMapping<Self, T> {
+        let phys_addr = PhysAddr::new(physical_address as u64);
+        let virt_addr = self.physical_memory_offset + phys_addr.as_u64();
+
+        unsafe {
+            PhysicalMapping::new(
+                physical_address,
+                NonNull::new(virt_addr.as_mut_ptr()).expect("Failed to get virtual address"),
+                size,
+                size,
+                *self,
+            )
+        }
+    }
+
+    fn unmap_physical_region<T>(_region: &PhysicalMapping<Self, T>) {
+        // No unmapping necessary as we didn't create any new mappings
+    }
+}
+
+/// Where ECAM maps a PCI segment's configuration space, from the MCFG table.
+#[derive(Debug, Clone, Copy)]
+pub struct PciConfigRegion {
+    pub physical: PhysAddr,
+    pub first_bus: u8,
+    pub last_bus: u8,
+}
+
+/// A processor the MADT lists.
+#[derive(Debug, Clone, Copy)]
+pub struct Cpu {
+    pub apic_id: u32,
+}
+
+/// An I/O APIC, and the first global system interrupt its inputs take.
+#[derive(Debug, Clone, Copy)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: PhysAddr,
+    pub gsi_base: u32,
+}
+
+/// Which global system interrupt an ISA interrupt arrives as, and how it is signalled.
+#[derive(Debug, Clone, Copy)]
+pub struct IsaInterrupt {
+    pub irq: u8,
+    pub gsi: u32,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+/// The processors and interrupt controllers the MADT lists.
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptControllers {
+    pub local_apic: PhysAddr,
+    pub cpus: [Option<Cpu>; MAX_CPUS],
+    pub io_apics: [Option<IoApic>; MAX_IO_APICS],
+    /// The ISA interrupts that aren't the global system interrupt of the same number, active
+    /// high and edge-triggered.
+    pub overrides: [Option<IsaInterrupt>; MAX_OVERRIDES],
+}
+
+impl InterruptControllers {
+    /// The processors that are usable, or can be brought online.
+    pub fn cpus(&self) -> impl Iterator<Item = Cpu> + '_ {
+        self.cpus.iter().flatten().copied()
+    }
+
+    /// The I/O APIC whose inputs include global system interrupt `gsi`, going by which starts
+    /// nearest below it.
+    pub fn io_apic_for(&self, gsi: u32) -> Option<IoApic> {
+        self.io_apics.iter().flatten().filter(|io_apic| io_apic.gsi_base <= gsi).max_by_key(|io_apic| io_apic.gsi_base).copied()
+    }
+
+    /// Where ISA interrupt `irq` arrives, from its override if it has one.
+    pub fn isa_interrupt(&self, irq: u8) -> IsaInterrupt {
+        let default = IsaInterrupt { irq, gsi: u32::from(irq), active_low: false, level_triggered: false };
+        self.overrides.iter().flatten().find(|interrupt| interrupt.irq == irq).copied().unwrap_or(default)
+    }
+}
+
+/// Checks the RSDP at `rsdp` and the RSDT or XSDT it points at, and keeps them for the other
+/// functions here, then reads the MADT. Logs every table the root lists, and the processors and
+/// interrupt controllers. Called once during boot, before anything looks a table up.
+pub fn init(rsdp: usize, physical_offset: u64) {
+    let root = Root { rsdp, handler: AcpiHandlerImpl::new(VirtAddr::new(physical_offset)) };
+    let tables = match unsafe { AcpiTables::from_rsdp(root.handler, rsdp) } {
+        Ok(tables) => tables,
+        Err(error) => {
+            writeln!(serial(), "ACPI: bad RSDP or root table at {rsdp:#x}: {error:?}").unwrap();
+            return;
+        },
+    };
+    write!(serial(), "ACPI: tables").unwrap();
+    for header in tables.headers() {
+        // The header is packed, so the signature is copied out before it is formatted
+        let signature = header.signature;
+        write!(serial(), " {signature}").unwrap();
+    }
+    writeln!(serial()).unwrap();
+    *ROOT.lock() = Some(root);
+    match read_madt() {
+        Some(controllers) => {
+            log_interrupt_controllers(&controllers);
+            *INTERRUPT_CONTROLLERS.lock() = Some(controllers);
+        },
+        None => writeln!(serial(), "ACPI: no MADT").unwrap(),
+    }
+}
+
+fn read_madt() -> Option<InterruptControllers> {
+    let madt = madt()?;
+    let mut controllers = InterruptControllers {
+        local_apic: PhysAddr::new(u64::from(madt.local_apic_address)),
+        cpus: [None; MAX_CPUS],
+        io_apics: [None; MAX_IO_APICS],
+        overrides: [None; MAX_OVERRIDES],
+    };
+    let (mut cpus, mut io_apics, mut overrides) = (0, 0, 0);
+    // The entries are packed, so their fields are copied out
+    for entry in madt.entries() {
+        match entry {
+            MadtEntry::LocalApic(entry) => {
+                let flags = entry.flags;
+                if usable(flags) {
+                    add(&mut controllers.cpus, &mut cpus, Cpu { apic_id: u32::from(entry.apic_id) });
+                }
+            },
+            MadtEntry::LocalX2Apic(entry) => {
+                let flags = entry.flags;
+                if usable(flags) {
+                    add(&mut controllers.cpus, &mut cpus, Cpu { apic_id: entry.x2apic_id });
+                }
+            },
+            MadtEntry::IoApic(entry) => {
+                let io_apic = IoApic { id: entry.io_apic_id, address: PhysAddr::new(u64::from(entry.io_apic_address)), gsi_base: entry.global_system_interrupt_base };
+                add(&mut controllers.io_apics, &mut io_apics, io_apic);
+            },
+            MadtEntry::InterruptSourceOverride(entry) => {
+                let flags = entry.flags;
+                let interrupt = IsaInterrupt {
+                    irq: entry.irq,
+                    gsi: entry.global_system_interrupt,
+                    active_low: flags & POLARITY == ACTIVE_LOW,
+                    level_triggered: flags & TRIGGER_MODE == LEVEL_TRIGGERED,
+                };
+                add(&mut controllers.overrides, &mut overrides, interrupt);
+            },
+            MadtEntry::LocalApicAddressOverride(entry) => controllers.local_apic = PhysAddr::new(entry.local_apic_address),
+            _ => {},
+        }
+    }
+    Some(controllers)
+}
+
+// Whether a CPU with local APIC `flags` can be used
+fn usable(flags: u32) -> bool {
+    flags & (CPU_ENABLED | CPU_ONLINE_CAPABLE) != 0
+}
+
+// Keeps `item` in the next free slot, if there is one
+fn add<T>(slots: &mut [Option<T>], count: &mut usize, item: T) {
+    if let Some(slot) = slots.get_mut(*count) {
+        *slot = Some(item);
+        *count += 1;
+    }
+}
+
+fn log_interrupt_controllers(controllers: &InterruptControllers) {
+    write!(serial(), "ACPI: local APIC at {:#x}, CPUs with APIC ids", controllers.local_apic.as_u64()).unwrap();
+    for cpu in controllers.cpus() {
+        write!(serial(), " {}", cpu.apic_id).unwrap();
+    }
+    writeln!(serial()).unwrap();
+    for io_apic in controllers.io_apics.iter().flatten() {
+        writeln!(serial(), "ACPI: I/O APIC {} at {:#x} from GSI {}", io_apic.id, io_apic.address.as_u64(), io_apic.gsi_base).unwrap();
+    }
+    for interrupt in controllers.overrides.iter().flatten() {
+        writeln!(serial(), "ACPI: IRQ {} is GSI {}, active {}, {}-triggered", interrupt.irq, interrupt.gsi,
+            if interrupt.active_low { "low" } else { "high" }, if interrupt.level_triggered { "level" } else { "edge" }).unwrap();
+    }
+}
+
+/// The processors and interrupt controllers the MADT lists, or None if there isn't one.
+pub fn interrupt_controllers() -> Option<InterruptControllers> {
+    *INTERRUPT_CONTROLLERS.lock()
+}
+
+// The tables, walked again from the root
+fn tables() -> Option<AcpiTables<AcpiHandlerImpl>> {
+    let root = (*ROOT.lock())?;
+    // The root was checked by `init`, so this only fails if memory has been overwritten
+    unsafe { AcpiTables::from_rsdp(root.handler, root.rsdp) }.ok()
+}
+
+/// The FADT, with the power management registers and boot flags, or None without ACPI.
+pub fn fadt() -> Option<PhysicalMapping<AcpiHandlerImpl, Fadt>> {
+    tables()?.find_table::<Fadt>().ok()
+}
+
+/// The MADT, which lists the interrupt controllers and processors, or None if there isn't one.
+pub fn madt() -> Option<PhysicalMapping<AcpiHandlerImpl, Madt>> {
+    tables()?.find_table::<Madt>().ok()
+}
+
+// AML opcodes and prefixes the `\_S5` package is written with
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const NAME_OP: u8 = 0x08;
+const BYTE_PREFIX: u8 = 0x0A;
+const WORD_PREFIX: u8 = 0x0B;
+const PACKAGE_OP: u8 = 0x12;
+const ROOT_PREFIX: u8 = b'\\';
+
+// The DSDT's AML, after its header
+fn dsdt() -> Option<&'static [u8]> {
+    let root = (*ROOT.lock())?;
+    let dsdt = tables()?.dsdt().ok()?;
+    let start = root.handler.physical_memory_offset + dsdt.address as u64;
+    Some(unsafe { slice::from_raw_parts(start.as_ptr(), dsdt.length as usize) })
+}
+
+/// The SLP_TYPa and SLP_TYPb values that put the machine in S5, soft off, or None if the DSDT
+/// doesn't say.
+///
+/// They are the first two values of the `\_S5` package. Rather than running the AML, this
+/// looks for where the package is named, which is how every firmware this runs on writes it.
+pub fn s5_sleep_types() -> Option<(u16, u16)> {
+    let aml = dsdt()?;
+    let name = aml.windows(4).position(|window| window == b"_S5_")?;
+    let defined = matches!(aml[..name], [.., NAME_OP] | [.., NAME_OP, ROOT_PREFIX]);
+    let package = aml.get(name + 4..)?;
+    if !defined || *package.first()? != PACKAGE_OP {
+        return None;
+    }
+    // The package's length takes one to four bytes, the top two bits of the first saying how
+    // many follow it; then comes the number of elements
+    let length_bytes = 1 + usize::from(*package.get(1)? >> 6);
+    let elements = package.get(1 + length_bytes + 1..)?;
+    let (sleep_type_a, elements) = integer(elements)?;
+    let (sleep_type_b, _) = integer(elements)?;
+    Some((sleep_type_a, sleep_type_b))
+}
+
+// An AML integer constant at the start of `aml`, and what follows it
+fn integer(aml: &[u8]) -> Option<(u16, &[u8])> {
+    match *aml {
+        [ZERO_OP, ref rest @ ..] => Some((0, rest)),
+        [ONE_OP, ref rest @ ..] => Some((1, rest)),
+        [BYTE_PREFIX, value, ref rest @ ..] => Some((u16::from(value), rest)),
+        [WORD_PREFIX, low, high, ref rest @ ..] => Some((u16::from_le_bytes([low, high]), rest)),
+        _ => None,
+    }
+}
+
+/// Where ECAM maps configuration space for PCI segment `segment`, or None without an MCFG
+/// table that has it.
+pub fn pci_config_region(segment: u16) -> Option<PciConfigRegion> {
+    let tables = tables()?;
+    let regions = PciConfigRegions::new(&tables).ok()?;
+    let region = regions.iter().find(|region| region.segment_group == segment)?;
+    Some(PciConfigRegion {
+        physical: PhysAddr::new(region.physical_address as u64),
+        first_bus: *region.bus_range.start(),
+        last_bus: *region.bus_range.end(),
+    })
+}
+
+/// The HPET's registers and counter, or None if there is no HPET table.
+// Time still comes from the local APIC timer and the TSC
+#[allow(dead_code)]
+pub fn hpet() -> Option<HpetInfo> {
+    HpetInfo::new(&tables()?).ok()
+}
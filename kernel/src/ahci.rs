@@ -0,0 +1,301 @@
+use alloc::boxed::Box;
+use core::fmt::Write;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering, fence};
+use kernel::serial;
+use kernel::sync::SpinLock;
+use crate::ata::{Identity, WORDS_PER_SECTOR};
+use crate::block::{self, BLOCK_SIZE, BlockDevice, Error};
+use crate::memory::{self, Dma, PAGE_SIZE};
+use crate::pci::{self, Match, PciDevice};
+
+// SATA disks on AHCI controllers, which PCI finds by their class. The controller's registers,
+// the HBA's, are memory mapped from its sixth BAR: some for the whole controller, then a set
+// for each of its up to 32 ports. A port with a disk on it gets a page of DMA memory the
+// controller reads commands from and writes what the disk answers to, laid out as:
+//
+// - the command list, 32 command headers; only the first is used, one command at a time
+// - the received FIS area, where the controller puts what the disk sends back
+// - the command table the header points at: the command FIS, then the PRDT, the list of
+//   memory the data goes to or comes from
+//
+// Data goes through a bounce buffer of DMA memory, so the one PRDT entry covers it. Commands
+// are waited for by polling the port's command issue register, with its interrupts left off.
+const MASS_STORAGE_AHCI: Match = Match::Class(0x01, 0x06, 0x01);
+const ABAR: u8 = 5;
+const MAX_PORTS: usize = 32;
+
+// HBA registers, as offsets from the ABAR
+const GLOBAL_CONTROL: usize = 0x04;
+const PORTS_IMPLEMENTED: usize = 0x0C;
+const PORT_REGISTERS: usize = 0x100;
+const PORT_SIZE: usize = 0x80;
+const HBA_SIZE: u64 = (PORT_REGISTERS + MAX_PORTS * PORT_SIZE) as u64;
+// Global control bit that switches the controller to AHCI from its IDE-like legacy mode
+const AHCI_ENABLE: u32 = 1 << 31;
+
+// Port registers, as offsets from the port's
+const COMMAND_LIST: usize = 0x00;
+const COMMAND_LIST_HIGH: usize = 0x04;
+const FIS_BASE: usize = 0x08;
+const FIS_BASE_HIGH: usize = 0x0C;
+const INTERRUPT_STATUS: usize = 0x10;
+const INTERRUPT_ENABLE: usize = 0x14;
+const COMMAND_STATUS: usize = 0x18;
+const TASK_FILE: usize = 0x20;
+const SIGNATURE: usize = 0x24;
+const SATA_STATUS: usize = 0x28;
+const SATA_ERROR: usize = 0x30;
+const COMMAND_ISSUE: usize = 0x38;
+
+// Command and status bits: start processing commands, receive FISes, and whether each is
+// still running
+const START: u32 = 1 << 0;
+const FIS_RECEIVE: u32 = 1 << 4;
+const FIS_RUNNING: u32 = 1 << 14;
+const COMMANDS_RUNNING: u32 = 1 << 15;
+// Interrupt status bit for a task file error
+const TASK_FILE_ERROR: u32 = 1 << 30;
+// Task file status bits, as in the ATA status register
+const BUSY: u32 = 0x80;
+const DATA_REQUEST: u32 = 0x08;
+const FAILED: u32 = 0x01;
+
+// SATA status: a device is there and talking to the port
+const DEVICE_PRESENT: u32 = 3;
+const INTERFACE_ACTIVE: u32 = 1;
+// What a SATA disk's signature is; ATAPI drives and port multipliers have others
+const SATA_DISK: u32 = 0x0000_0101;
+
+// Where things are in a port's page of DMA memory
+const FIS_AREA: usize = 0x400;
+const COMMAND_TABLE: usize = 0x800;
+const PRDT: usize = COMMAND_TABLE + 0x80;
+
+// A host to device register FIS, which carries an ATA command, and its length in dwords
+const FIS_HOST_TO_DEVICE: u8 = 0x27;
+const FIS_COMMAND: u8 = 0x80;
+const FIS_LENGTH: u32 = 5;
+// Command header flag for a command that writes to the disk
+const HEADER_WRITE: u32 = 1 << 6;
+// The device register with LBA addressing
+const LBA_MODE: u8 = 0x40;
+
+const IDENTIFY: u8 = 0xEC;
+const READ_DMA_EXT: u8 = 0x25;
+const WRITE_DMA_EXT: u8 = 0x35;
+const FLUSH_CACHE_EXT: u8 = 0xEA;
+
+// The bounce buffer, and so the most one command moves
+const BUFFER_PAGES: u64 = 16;
+const MAX_SECTORS: usize = (BUFFER_PAGES * PAGE_SIZE) as usize / BLOCK_SIZE;
+// How many times to check on a port before giving up on it
+const TIMEOUT_POLLS: u32 = 1_000_000;
+
+const NAMES: [&str; block::MAX_DEVICES] = ["sata0", "sata1", "sata2", "sata3", "sata4", "sata5", "sata6", "sata7"];
+// Which of `NAMES` the next disk found gets
+static NEXT_NAME: AtomicUsize = AtomicUsize::new(0);
+
+// A port's registers and DMA memory
+struct Port {
+    registers: *mut u32,
+    memory: Dma,
+    buffer: Dma,
+}
+
+// Only used with the disk's lock held
+unsafe impl Send for Port {}
+
+impl Port {
+    fn read(&self, register: usize) -> u32 {
+        unsafe { self.registers.add(register / 4).read_volatile() }
+    }
+
+    fn write(&self, register: usize, value: u32) {
+        unsafe { self.registers.add(register / 4).write_volatile(value) }
+    }
+
+    // Waits until `done` is true of register `register`, or gives up
+    fn wait(&self, register: usize, done: impl Fn(u32) -> bool) -> Result<(), Error> {
+        for _ in 0..TIMEOUT_POLLS {
+            if done(self.read(register)) {
+                return Ok(());
+            }
+        }
+        Err(Error::Io)
+    }
+
+    fn stop(&self) -> Result<(), Error> {
+        self.write(COMMAND_STATUS, self.read(COMMAND_STATUS) & !(START | FIS_RECEIVE));
+        self.wait(COMMAND_STATUS, |status| status & (COMMANDS_RUNNING | FIS_RUNNING) == 0)
+    }
+
+    // Points the port at its DMA memory and starts it
+    fn start(&self) -> Result<(), Error> {
+        self.stop()?;
+        let physical = self.memory.physical.as_u64();
+        self.write(COMMAND_LIST, physical as u32);
+        self.write(COMMAND_LIST_HIGH, (physical >> 32) as u32);
+        self.write(FIS_BASE, (physical + FIS_AREA as u64) as u32);
+        self.write(FIS_BASE_HIGH, ((physical + FIS_AREA as u64) >> 32) as u32);
+        // Writing the bits set clears them
+        self.write(SATA_ERROR, u32::MAX);
+        self.write(INTERRUPT_STATUS, u32::MAX);
+        self.write(INTERRUPT_ENABLE, 0);
+        self.write(COMMAND_STATUS, self.read(COMMAND_STATUS) | FIS_RECEIVE);
+        self.wait(TASK_FILE, |status| status & (BUSY | DATA_REQUEST) == 0)?;
+        self.write(COMMAND_STATUS, self.read(COMMAND_STATUS) | START);
+        Ok(())
+    }
+
+    fn dword(&self, offset: usize) -> *mut u32 {
+        (self.memory.start + offset as u64).as_mut_ptr()
+    }
+
+    // Runs `command` on `count` sectors from `sector` on, through the bounce buffer
+    fn run(&self, command: u8, sector: u64, count: usize, write: bool) -> Result<(), Error> {
+        self.wait(TASK_FILE, |status| status & (BUSY | DATA_REQUEST) == 0)?;
+        let bytes = count * BLOCK_SIZE;
+        let table = self.memory.physical + COMMAND_TABLE as u64;
+        let lba = sector.to_le_bytes();
+        let [count_low, count_high] = (count as u16).to_le_bytes();
+        let fis = [
+            u32::from_le_bytes([FIS_HOST_TO_DEVICE, FIS_COMMAND, command, 0]),
+            u32::from_le_bytes([lba[0], lba[1], lba[2], LBA_MODE]),
+            u32::from_le_bytes([lba[3], lba[4], lba[5], 0]),
+            u32::from_le_bytes([count_low, count_high, 0, 0]),
+            0,
+        ];
+        let buffer = self.buffer.physical.as_u64();
+        let header = [
+            FIS_LENGTH | if write { HEADER_WRITE } else { 0 } | u32::from(bytes != 0) << 16,
+            0,
+            table.as_u64() as u32,
+            (table.as_u64() >> 32) as u32,
+        ];
+        // The byte count is one less than how many there are
+        let prdt = [buffer as u32, (buffer >> 32) as u32, 0, (bytes as u32).saturating_sub(1)];
+        unsafe {
+            for (index, value) in header.into_iter().enumerate() {
+                self.dword(index * 4).write_volatile(value);
+            }
+            for (index, value) in fis.into_iter().enumerate() {
+                self.dword(COMMAND_TABLE + index * 4).write_volatile(value);
+            }
+            for (index, value) in prdt.into_iter().enumerate() {
+                self.dword(PRDT + index * 4).write_volatile(value);
+            }
+        }
+        // So the controller sees all of that once it is told to go
+        fence(Ordering::SeqCst);
+        self.write(INTERRUPT_STATUS, u32::MAX);
+        self.write(COMMAND_ISSUE, 1);
+        for _ in 0..TIMEOUT_POLLS {
+            if self.read(INTERRUPT_STATUS) & TASK_FILE_ERROR != 0 {
+                return Err(Error::Io);
+            }
+            if self.read(COMMAND_ISSUE) & 1 == 0 {
+                fence(Ordering::SeqCst);
+                return if self.read(TASK_FILE) & FAILED != 0 { Err(Error::Io) } else { Ok(()) };
+            }
+        }
+        Err(Error::Io)
+    }
+
+    fn buffer(&self) -> *mut u8 {
+        self.buffer.start.as_mut_ptr()
+    }
+}
+
+// A SATA disk on a port of an AHCI controller
+struct Disk {
+    name: &'static str,
+    port: SpinLock<Port>,
+    sectors: u64,
+}
+
+impl BlockDevice for Disk {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn blocks(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read(&self, block: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        block::check(self.sectors, block, buffer.len())?;
+        let port = self.port.lock();
+        for (chunk, sectors) in (block..).step_by(MAX_SECTORS).zip(buffer.chunks_mut(MAX_SECTORS * BLOCK_SIZE)) {
+            port.run(READ_DMA_EXT, chunk, sectors.len() / BLOCK_SIZE, false)?;
+            unsafe { ptr::copy_nonoverlapping(port.buffer(), sectors.as_mut_ptr(), sectors.len()) };
+        }
+        Ok(())
+    }
+
+    fn write(&self, block: u64, bytes: &[u8]) -> Result<(), Error> {
+        block::check(self.sectors, block, bytes.len())?;
+        let port = self.port.lock();
+        for (chunk, sectors) in (block..).step_by(MAX_SECTORS).zip(bytes.chunks(MAX_SECTORS * BLOCK_SIZE)) {
+            unsafe { ptr::copy_nonoverlapping(sectors.as_ptr(), port.buffer(), sectors.len()) };
+            port.run(WRITE_DMA_EXT, chunk, sectors.len() / BLOCK_SIZE, true)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        self.port.lock().run(FLUSH_CACHE_EXT, 0, 0, false)
+    }
+}
+
+/// Registers the SATA disks on the ports of each AHCI controller on the PCI bus as block
+/// devices, sata0 on.
+pub const DRIVER: pci::Driver = pci::Driver { name: "AHCI", matches: &[MASS_STORAGE_AHCI], probe };
+
+fn probe(controller: PciDevice) -> Result<(), &'static str> {
+    let hba = controller.map_bar(ABAR)?;
+    if hba.size() < HBA_SIZE {
+        return Err("too few registers");
+    }
+    controller.enable_bus_master();
+    let hba: *mut u32 = hba.start().as_mut_ptr();
+    let implemented = unsafe {
+        let control = hba.add(GLOBAL_CONTROL / 4);
+        control.write_volatile(control.read_volatile() | AHCI_ENABLE);
+        hba.add(PORTS_IMPLEMENTED / 4).read_volatile()
+    };
+    writeln!(serial(), "AHCI controller {:04x}:{:04x} at {controller}, ports {implemented:#x}", controller.vendor_id(), controller.device_id()).unwrap();
+    for index in (0..MAX_PORTS).filter(|index| implemented & (1 << index) != 0) {
+        let registers = unsafe { hba.add((PORT_REGISTERS + index * PORT_SIZE) / 4) };
+        let Some((port, identity)) = probe_port(registers) else {
+            continue;
+        };
+        let Some(name) = NAMES.get(NEXT_NAME.fetch_add(1, Ordering::Relaxed)).copied() else {
+            writeln!(serial(), "AHCI: too many disks, port {index} not used").unwrap();
+            break;
+        };
+        writeln!(serial(), "{name}: port {index}: {}, {} sectors", identity.model.as_str(), identity.sectors).unwrap();
+        let disk = Disk { name, port: SpinLock::new(port), sectors: identity.sectors };
+        if !block::register(Box::leak(Box::new(disk))) {
+            writeln!(serial(), "{name}: too many block devices, not registered").unwrap();
+        }
+    }
+    Ok(())
+}
+
+// Sets up the port whose registers are at `registers` if it has a SATA disk, and asks the disk
+// what it is
+fn probe_port(registers: *mut u32) -> Option<(Port, Identity)> {
+    let read = |register: usize| unsafe { registers.add(register / 4).read_volatile() };
+    let status = read(SATA_STATUS);
+    if status & 0xF != DEVICE_PRESENT || (status >> 8) & 0xF != INTERFACE_ACTIVE || read(SIGNATURE) != SATA_DISK {
+        return None;
+    }
+    let port = Port { registers, memory: memory::alloc_dma(1)?, buffer: memory::alloc_dma(BUFFER_PAGES)? };
+    port.start().ok()?;
+    port.run(IDENTIFY, 0, 1, false).ok()?;
+    let mut words = [0; WORDS_PER_SECTOR];
+    unsafe { ptr::copy_nonoverlapping(port.buffer().cast(), words.as_mut_ptr(), WORDS_PER_SECTOR) };
+    let identity = Identity::parse(&words).filter(|identity| identity.sectors != 0)?;
+    Some((port, identity))
+}
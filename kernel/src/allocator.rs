@@ -18,6 +18,17 @@ fn align_up(addr: usize, align: usize) -> usize {
 
 unsafe impl GlobalAlloc for DummyAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // A task preempted half way through would hand out the same memory twice
+        x86_64::instructions::interrupts::without_interrupts(|| unsafe { bump(layout) })
+    }
+
+    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
+        writeln!(serial(), "dealloc was called at {_ptr:?}").unwrap();
+    }
+}
+
+unsafe fn bump(layout: Layout) -> *mut u8 {
+    unsafe {
         let size = layout.size();
         let align = layout.align();
 
@@ -33,10 +44,6 @@ unsafe impl GlobalAlloc for DummyAllocator {
         OFFSET = new_offset - HEAP_START;
         ptr
     }
-
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
-        writeln!(serial(), "dealloc was called at {_ptr:?}").unwrap();
-    }
 }
 
 pub fn init_heap(offset: usize) {
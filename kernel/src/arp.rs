@@ -0,0 +1,165 @@
+use core::fmt::Write;
+use core::net::Ipv4Addr;
+use kernel::serial;
+use kernel::sync::SpinLock;
+use crate::ethernet::{self, ETHERTYPE_ARP, ETHERTYPE_IPV4, Frame};
+use crate::net::{Interface, Mac};
+use crate::task::WaitQueue;
+use crate::{time, timer};
+
+// ARP, which finds the MAC address of the card with a given IPv4 address on the same network.
+// A request is broadcast, asking who has the address; the card that has it replies to the
+// asker alone. What is learnt goes in a small cache, whose entries the timer wheel expires a
+// minute later, so a machine that changes cards is found again. A card that is given an
+// address announces it with a gratuitous request, for its own address, so the others' caches
+// are brought up to date. A request from another machine for this one's address is taken to
+// say where that machine is, since a reply to it is bound to follow.
+const HARDWARE_ETHERNET: u16 = 1;
+const REQUEST: u16 = 1;
+const REPLY: u16 = 2;
+const PACKET_SIZE: usize = 28;
+
+const CACHE_SIZE: usize = 16;
+const LIFETIME_MS: u64 = 60_000;
+// How often expired entries are looked for
+const EXPIRY_PERIOD_MS: u64 = 1_000;
+// How long `resolve_wait` waits for a reply before asking again, and how many times it asks
+const RETRY_MS: u64 = 1_000;
+const TRIES: u32 = 3;
+
+/// A cached address.
+#[derive(Debug, Clone, Copy)]
+pub struct Entry {
+    /// The card it was learnt on.
+    pub interface: &'static str,
+    pub address: Ipv4Addr,
+    pub mac: Mac,
+    /// The timer tick it expires at.
+    pub expires: u64,
+}
+
+static CACHE: SpinLock<[Option<Entry>; CACHE_SIZE]> = SpinLock::new([None; CACHE_SIZE]);
+// Tasks waiting for a reply, woken by every ARP packet and every retry timer
+static RESOLVED: WaitQueue = WaitQueue::new();
+
+/// Takes the ARP packets the cards receive from now on, and starts expiring cache entries.
+/// Called once during boot, before any card is given an address.
+pub fn init() {
+    ethernet::register(ETHERTYPE_ARP, receive);
+    timer::schedule(time::ms_to_ticks(EXPIRY_PERIOD_MS), expire);
+}
+
+/// Returns the MAC address of `address` on `interface`'s network if it is cached. If it
+/// isn't, asks for it and returns None; the reply is cached when it comes.
+pub fn resolve(interface: &Interface, address: Ipv4Addr) -> Option<Mac> {
+    if address.is_broadcast() {
+        return Some(Mac::BROADCAST);
+    }
+    let cached = lookup(interface, address);
+    if cached.is_none() {
+        request(interface, address);
+    }
+    cached
+}
+
+/// Like `resolve`, but waits for a reply, asking again a few times, and returns None only once
+/// none has come. Not to be called on the network task, which takes the replies.
+pub fn resolve_wait(interface: &Interface, address: Ipv4Addr) -> Option<Mac> {
+    for _ in 0..TRIES {
+        if let Some(mac) = resolve(interface, address) {
+            return Some(mac);
+        }
+        let until = timer::now() + time::ms_to_ticks(RETRY_MS);
+        timer::schedule(time::ms_to_ticks(RETRY_MS), || RESOLVED.notify());
+        RESOLVED.wait_until(|| lookup(interface, address).is_some() || timer::now() >= until);
+    }
+    lookup(interface, address)
+}
+
+/// Tells the other machines on `interface`'s network that its address is now where it is.
+pub fn announce(interface: &Interface) {
+    if let Some(address) = interface.ipv4() {
+        send(interface, REQUEST, Mac::BROADCAST, Mac([0; 6]), address);
+    }
+}
+
+/// Returns the cached addresses.
+pub fn entries() -> impl Iterator<Item = Entry> {
+    let cache = *CACHE.lock_irq();
+    cache.into_iter().flatten()
+}
+
+fn lookup(interface: &Interface, address: Ipv4Addr) -> Option<Mac> {
+    CACHE.lock_irq().iter().flatten().find(|entry| entry.interface == interface.name && entry.address == address).map(|entry| entry.mac)
+}
+
+fn request(interface: &Interface, address: Ipv4Addr) {
+    send(interface, REQUEST, Mac::BROADCAST, Mac([0; 6]), address);
+}
+
+// Sends a packet of `operation` from `interface` to `destination`, about `target` at
+// `target_address`
+fn send(interface: &Interface, operation: u16, destination: Mac, target: Mac, target_address: Ipv4Addr) {
+    let source = interface.ipv4().unwrap_or(Ipv4Addr::UNSPECIFIED);
+    let mut packet = [0; PACKET_SIZE];
+    packet[0..2].copy_from_slice(&HARDWARE_ETHERNET.to_be_bytes());
+    packet[2..4].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+    packet[4] = 6;
+    packet[5] = 4;
+    packet[6..8].copy_from_slice(&operation.to_be_bytes());
+    packet[8..14].copy_from_slice(&interface.device.mac().0);
+    packet[14..18].copy_from_slice(&source.octets());
+    packet[18..24].copy_from_slice(&target.0);
+    packet[24..28].copy_from_slice(&target_address.octets());
+    if let Err(error) = ethernet::send(interface, destination, ETHERTYPE_ARP, &packet) {
+        writeln!(serial(), "{}: can't send ARP: {error:?}", interface.name).unwrap();
+    }
+}
+
+fn receive(interface: &'static Interface, frame: &Frame) {
+    let packet = frame.payload;
+    if packet.len() < PACKET_SIZE || packet[0..2] != HARDWARE_ETHERNET.to_be_bytes() || packet[2..4] != ETHERTYPE_IPV4.to_be_bytes() || packet[4] != 6 || packet[5] != 4 {
+        return;
+    }
+    let operation = u16::from_be_bytes([packet[6], packet[7]]);
+    let sender = Mac(packet[8..14].try_into().unwrap());
+    let sender_address = Ipv4Addr::from(<[u8; 4]>::try_from(&packet[14..18]).unwrap());
+    let target_address = Ipv4Addr::from(<[u8; 4]>::try_from(&packet[24..28]).unwrap());
+    let ours = interface.ipv4().is_some_and(|address| address == target_address);
+
+    // A sender already cached is brought up to date, and one asking for this card is added
+    if !sender_address.is_unspecified() && update(interface, sender_address, sender, ours) {
+        RESOLVED.notify();
+    }
+    if operation == REQUEST && ours {
+        send(interface, REPLY, sender, sender, sender_address);
+    }
+}
+
+// Caches `mac` for `address`, replacing an entry for it or, if `add`, the entry closest to
+// expiring. Returns whether anything was cached.
+fn update(interface: &'static Interface, address: Ipv4Addr, mac: Mac, add: bool) -> bool {
+    let expires = timer::now() + time::ms_to_ticks(LIFETIME_MS);
+    let entry = Entry { interface: interface.name, address, mac, expires };
+    let mut cache = CACHE.lock_irq();
+    let existing = cache.iter().position(|slot| slot.is_some_and(|other| other.interface == interface.name && other.address == address));
+    let slot = match existing {
+        Some(slot) => slot,
+        None if add => cache.iter().position(Option::is_none)
+            .unwrap_or_else(|| (0..CACHE_SIZE).min_by_key(|&slot| cache[slot].map_or(0, |other| other.expires)).unwrap()),
+        None => return false,
+    };
+    cache[slot] = Some(entry);
+    true
+}
+
+// Drops the entries that have expired, then checks again a while later
+fn expire() {
+    let now = timer::now();
+    for slot in CACHE.lock_irq().iter_mut() {
+        if slot.is_some_and(|entry| entry.expires <= now) {
+            *slot = None;
+        }
+    }
+    timer::schedule(time::ms_to_ticks(EXPIRY_PERIOD_MS), expire);
+}
@@ -0,0 +1,353 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use kernel::serial;
+use kernel::sync::SpinLock;
+use crate::fs::{self, File, MAX_PATH};
+use crate::screen::screenwriter;
+use crate::sprite::Sprite;
+use crate::ui::{Color, TextBuffer};
+use crate::{display, game, menu, settings_menu, time, timer};
+
+// Artwork read from files at boot rather than built into the kernel, so it can be changed
+// without building the kernel again. It is looked for in /assets on each mounted disk and
+// then on the initrd, the first found winning, and anything missing or unreadable is left to
+// what is built in:
+//
+// - font.psf, a PC screen font (version 1 or 2, as the Linux console uses), is drawn instead
+//   of the built-in font for the ASCII characters it has. Its glyphs are cut to the built-in
+//   font's character cell, so text takes the same room.
+// - <name>.ppm, a binary PPM image, is sprite `name`, with magenta (ff00ff) pixels left
+//   transparent. Games only use one the size of their own, which their play is worked out for.
+// - splash.ppm is shown in the middle of the screen for a moment when the kernel starts.
+//
+// The font and sprites are kept for good on the kernel heap, which never frees, so their sizes
+// are limited; the splash image is drawn straight from its file.
+const MAX_FONT_SIZE: u64 = 16 * 1024;
+const MAX_GLYPH_SIDE: usize = 32;
+const MAX_SPRITES: usize = 4;
+const MAX_SPRITE_SIDE: usize = 32;
+const MAX_SPRITE_NAME: usize = 16;
+const SPLASH: &str = "splash.ppm";
+const SPLASH_MS: u64 = 2000;
+const TRANSPARENT: Color = (0xff, 0, 0xff);
+
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+const PSF1_512_GLYPHS: u8 = 0x01;
+const PSF1_UNICODE_TABLES: [u8; 2] = [0x02, 0x04];
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xb5, 0x4a, 0x86];
+const PSF2_UNICODE_TABLE: u32 = 0x01;
+
+/// A bitmap font read from a PSF file.
+pub struct Font {
+    width: usize,
+    height: usize,
+    glyph_size: usize,
+    glyphs: &'static [u8],
+    // The glyph for each ASCII character, where the font has one
+    ascii: [Option<u16>; 128],
+}
+
+/// The bitmap of a character in a `Font`.
+pub struct Glyph<'a> {
+    font: &'a Font,
+    bytes: &'a [u8],
+}
+
+impl Font {
+    /// The glyph for `c`, or None if the font hasn't one.
+    pub fn glyph(&self, c: char) -> Option<Glyph<'_>> {
+        let index = usize::from(*self.ascii.get(c as usize)?.as_ref()?);
+        let bytes = &self.glyphs[index * self.glyph_size..(index + 1) * self.glyph_size];
+        Some(Glyph { font: self, bytes })
+    }
+}
+
+impl Glyph<'_> {
+    /// Whether pixel (x, y) of the glyph is drawn; none outside the glyph are.
+    pub fn pixel(&self, x: usize, y: usize) -> bool {
+        if x >= self.font.width || y >= self.font.height {
+            return false;
+        }
+        let row = &self.bytes[y * self.font.width.div_ceil(8)..];
+        row[x / 8] & (0x80 >> (x % 8)) != 0
+    }
+}
+
+static SPRITES: SpinLock<[Option<(TextBuffer<MAX_SPRITE_NAME>, &'static Sprite)>; MAX_SPRITES]> = SpinLock::new([None; MAX_SPRITES]);
+
+/// Loads the font and sprites. Called once during boot, after the filesystems are mounted.
+pub fn init() {
+    if let Some((path, file)) = open("font.psf") {
+        match read_font(file) {
+            Some(font) => {
+                writeln!(serial(), "Using font {}: {}x{}", path.as_str(), font.width, font.height).unwrap();
+                screenwriter().set_font(font);
+            },
+            None => writeln!(serial(), "Can't use font {}", path.as_str()).unwrap(),
+        }
+    }
+    for directory in directories() {
+        let Ok(entries) = fs::read_dir(directory.as_str()) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let name = entry.name.as_str();
+            let Some(sprite) = name.strip_suffix(".ppm").filter(|_| name != SPLASH && entry.kind != fs::Kind::Directory) else {
+                continue;
+            };
+            if sprite.len() > MAX_SPRITE_NAME || SPRITES.lock_irq().iter().flatten().any(|(loaded, _)| loaded.as_str() == sprite) {
+                continue;
+            }
+            let mut path = TextBuffer::<MAX_PATH>::new();
+            write!(path, "{}/{name}", directory.as_str()).unwrap();
+            load_sprite(sprite, path.as_str());
+        }
+    }
+}
+
+/// The sprite called `name` if one the size of `built_in` was loaded, or else `built_in`.
+pub fn sprite(name: &str, built_in: &'static Sprite) -> &'static Sprite {
+    let sprites = SPRITES.lock_irq();
+    let loaded = sprites.iter().flatten()
+        .find(|(loaded, sprite)| loaded.as_str() == name && sprite.width == built_in.width && sprite.height == built_in.height);
+    loaded.map_or(built_in, |(_, sprite)| sprite)
+}
+
+/// Shows the splash image, if there is one, and then the menu or the default game once it
+/// has been up for a moment; or those straight away. Called once, as the kernel starts.
+pub fn show_splash() {
+    let Some((path, file)) = open(SPLASH) else {
+        menu::show_first();
+        return;
+    };
+    if !draw_splash(file) {
+        writeln!(serial(), "Can't show {}", path.as_str()).unwrap();
+        menu::show_first();
+        return;
+    }
+    if !timer::schedule(time::ms_to_ticks(SPLASH_MS), end_splash) {
+        menu::show_first();
+    }
+}
+
+// Swaps the splash for the menu or the default game, unless a key pressed meanwhile has
+// taken it somewhere else
+fn end_splash() {
+    if !game::is_running() && !settings_menu::is_open() && !display::is_taken() {
+        menu::show_first();
+    }
+}
+
+// The directories artwork is looked for in, on the disks before the initrd
+fn directories() -> impl Iterator<Item = TextBuffer<MAX_PATH>> {
+    let mounts = fs::mounts();
+    let disks = mounts.into_iter().flatten().filter(|(path, _)| *path != "/");
+    let initrd = mounts.into_iter().flatten().filter(|(path, _)| *path == "/");
+    disks.chain(initrd).map(|(path, _)| {
+        let mut directory = TextBuffer::new();
+        write!(directory, "{}/assets", path.trim_end_matches('/')).unwrap();
+        directory
+    })
+}
+
+// Opens the first file called `name` in the asset directories, and returns its path too
+fn open(name: &str) -> Option<(TextBuffer<MAX_PATH>, File)> {
+    directories().find_map(|directory| {
+        let mut path = TextBuffer::<MAX_PATH>::new();
+        write!(path, "{}/{name}", directory.as_str()).unwrap();
+        let file = File::open(path.as_str()).ok()?;
+        Some((path, file))
+    })
+}
+
+fn read_font(mut file: File) -> Option<&'static Font> {
+    let size = file.metadata().ok()?.size;
+    if size > MAX_FONT_SIZE {
+        return None;
+    }
+    let mut bytes = alloc::vec![0; size as usize];
+    let mut read = 0;
+    while read < bytes.len() {
+        match file.read(&mut bytes[read..]).ok()? {
+            0 => return None,
+            count => read += count,
+        }
+    }
+    let font = parse_font(Vec::leak(bytes))?;
+    Some(Box::leak(Box::new(font)))
+}
+
+// Reads a PSF font: its header, its glyphs and, if it has one, the table of the characters
+// each glyph is for
+fn parse_font(bytes: &'static [u8]) -> Option<Font> {
+    let field = |offset: usize| Some(u32::from_le_bytes(bytes.get(offset..offset + 4)?.try_into().unwrap()) as usize);
+    let (width, height, count, header_size, unicode) = if bytes.starts_with(&PSF1_MAGIC) {
+        let (mode, height) = (*bytes.get(2)?, usize::from(*bytes.get(3)?));
+        let count = if mode & PSF1_512_GLYPHS != 0 { 512 } else { 256 };
+        (8, height, count, 4, PSF1_UNICODE_TABLES.iter().any(|&flag| mode & flag != 0))
+    } else if bytes.starts_with(&PSF2_MAGIC) {
+        let unicode = field(12)? as u32 & PSF2_UNICODE_TABLE != 0;
+        (field(28)?, field(24)?, field(16)?, field(8)?, unicode)
+    } else {
+        return None;
+    };
+    if !(1..=MAX_GLYPH_SIDE).contains(&width) || !(1..=MAX_GLYPH_SIDE).contains(&height) || count == 0 {
+        return None;
+    }
+    // Rows are whole bytes, as version 2 also says in its header
+    let glyph_size = width.div_ceil(8) * height;
+    if bytes.starts_with(&PSF2_MAGIC) && field(20)? != glyph_size {
+        return None;
+    }
+    let glyphs_end = count.checked_mul(glyph_size)?.checked_add(header_size)?;
+    let glyphs = bytes.get(header_size..glyphs_end)?;
+    let mut ascii = [None; 128];
+    if !unicode {
+        ascii.iter_mut().take(count).enumerate().for_each(|(index, glyph)| *glyph = Some(index as u16));
+    } else if bytes.starts_with(&PSF1_MAGIC) {
+        // Each glyph's characters in 16 bits each, ending with ffff; sequences of several
+        // characters, after fffe, are left out
+        let mut characters = bytes[glyphs_end..].chunks_exact(2).map(|pair| u16::from_le_bytes([pair[0], pair[1]]));
+        for glyph in 0..count as u16 {
+            let mut in_sequence = false;
+            for character in characters.by_ref().take_while(|&character| character != 0xffff) {
+                in_sequence |= character == 0xfffe;
+                if !in_sequence && character < 128 {
+                    ascii[usize::from(character)].get_or_insert(glyph);
+                }
+            }
+        }
+    } else {
+        // Each glyph's characters in UTF-8, ending with ff, where a byte under 80 is an ASCII
+        // character and anything else part of another; sequences come after fe
+        let mut table = bytes[glyphs_end..].iter();
+        for glyph in 0..count as u16 {
+            let mut in_sequence = false;
+            for &byte in table.by_ref().take_while(|&&byte| byte != 0xff) {
+                in_sequence |= byte == 0xfe;
+                if !in_sequence && byte < 128 {
+                    ascii[usize::from(byte)].get_or_insert(glyph);
+                }
+            }
+        }
+    }
+    Some(Font { width, height, glyph_size, glyphs, ascii })
+}
+
+fn load_sprite(name: &str, path: &str) {
+    let Some((width, height, pixels)) = File::open(path).ok().and_then(read_sprite) else {
+        writeln!(serial(), "Can't use sprite {path}").unwrap();
+        return;
+    };
+    let mut sprites = SPRITES.lock_irq();
+    let Some(slot) = sprites.iter_mut().find(|slot| slot.is_none()) else {
+        writeln!(serial(), "No room for sprite {path}").unwrap();
+        return;
+    };
+    let mut sprite_name = TextBuffer::new();
+    write!(sprite_name, "{name}").unwrap();
+    let sprite = Box::leak(Box::new(Sprite { width, height, pixels: Vec::leak(pixels) }));
+    *slot = Some((sprite_name, sprite));
+    writeln!(serial(), "Using sprite {name} from {path}: {width}x{height}").unwrap();
+}
+
+fn read_sprite(file: File) -> Option<(usize, usize, Vec<Option<Color>>)> {
+    let mut reader = Reader::new(file);
+    let (width, height) = reader.ppm_header()?;
+    if width > MAX_SPRITE_SIDE || height > MAX_SPRITE_SIDE {
+        return None;
+    }
+    let mut pixels = Vec::with_capacity(width * height);
+    for _ in 0..width * height {
+        let color = reader.pixel()?;
+        pixels.push((color != TRANSPARENT).then_some(color));
+    }
+    Some((width, height, pixels))
+}
+
+// Draws the image in `file` in the middle of the screen, as much of it as fits, pixel by pixel
+// as it is read
+fn draw_splash(file: File) -> bool {
+    let mut reader = Reader::new(file);
+    let Some((width, height)) = reader.ppm_header() else {
+        return false;
+    };
+    let mut screen = screenwriter();
+    screen.clear();
+    let (left, top) = (screen.width().saturating_sub(width) / 2, screen.height().saturating_sub(height) / 2);
+    for y in 0..height {
+        for x in 0..width {
+            let Some((r, g, b)) = reader.pixel() else {
+                return false;
+            };
+            if left + x < screen.width() && top + y < screen.height() {
+                screen.draw_pixel(left + x, top + y, r, g, b);
+            }
+        }
+    }
+    true
+}
+
+// Reads a file a byte at a time, from a buffer filled a block at a time
+struct Reader {
+    file: File,
+    buffer: [u8; 512],
+    position: usize,
+    length: usize,
+}
+
+impl Reader {
+    fn new(file: File) -> Reader {
+        Reader { file, buffer: [0; 512], position: 0, length: 0 }
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        if self.position == self.length {
+            self.length = self.file.read(&mut self.buffer).ok()?;
+            self.position = 0;
+            if self.length == 0 {
+                return None;
+            }
+        }
+        self.position += 1;
+        Some(self.buffer[self.position - 1])
+    }
+
+    // Reads the header of a binary PPM image, with a byte for each of red, green and blue,
+    // and returns its width and height
+    fn ppm_header(&mut self) -> Option<(usize, usize)> {
+        if (self.byte()?, self.byte()?) != (b'P', b'6') {
+            return None;
+        }
+        let (width, height, maximum) = (self.number()?, self.number()?, self.number()?);
+        (maximum == 255).then_some((width, height))
+    }
+
+    // Reads a number in a PPM header, after whitespace and comments, and the whitespace that
+    // ends it
+    fn number(&mut self) -> Option<usize> {
+        let mut byte = self.byte()?;
+        loop {
+            match byte {
+                b'#' => while self.byte()? != b'\n' {},
+                byte if byte.is_ascii_whitespace() => {},
+                _ => break,
+            }
+            byte = self.byte()?;
+        }
+        if !byte.is_ascii_digit() {
+            return None;
+        }
+        let mut number: usize = 0;
+        while byte.is_ascii_digit() {
+            number = number.checked_mul(10)?.checked_add(usize::from(byte - b'0'))?;
+            byte = self.byte()?;
+        }
+        byte.is_ascii_whitespace().then_some(number)
+    }
+
+    fn pixel(&mut self) -> Option<Color> {
+        Some((self.byte()?, self.byte()?, self.byte()?))
+    }
+}
@@ -0,0 +1,486 @@
+use alloc::boxed::Box;
+use core::fmt::Write;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+use kernel::serial;
+use kernel::sync::SpinLock;
+use x86_64::instructions::port::Port;
+use crate::block::{self, BLOCK_SIZE, BlockDevice, Error};
+use crate::memory::{self, Dma, PAGE_SIZE};
+use crate::pci;
+use crate::ui::TextBuffer;
+
+// ATA disks on the legacy IDE controller. Each of the two channels, primary and secondary, can
+// have a master and a slave drive; IDENTIFY finds which are there at boot and how big they
+// are. Drives that support it are addressed with 48-bit LBAs, others with 28-bit ones. ATAPI
+// drives (CD-ROMs) and ones that only take CHS addresses are left alone.
+//
+// Sectors move by bus-master DMA where the controller and drive can do it: the controller
+// copies between the drive and a bounce buffer in memory, following a table of the buffer's
+// pages (the PRD table), while the CPU only checks when it is done, instead of moving every
+// word through the data port itself as PIO does. PIO is what's left for drives without DMA,
+// and for one whose DMA fails, which then stays on PIO. Either way the status is polled
+// rather than waiting for the drives' interrupts, which are turned off, and the transfer
+// modes are left as the firmware set them up.
+//
+// Under QEMU the disk the kernel boots from is the primary master, and a disk image given
+// with DISK=<path> when running is the next drive along.
+
+// The registers, from a channel's I/O base; COMMAND and STATUS are the same port
+const DATA: u16 = 0;
+const SECTOR_COUNT: u16 = 2;
+const LBA_LOW: u16 = 3;
+const LBA_MID: u16 = 4;
+const LBA_HIGH: u16 = 5;
+const DRIVE: u16 = 6;
+const COMMAND: u16 = 7;
+const STATUS: u16 = 7;
+
+// Status bits
+const BUSY: u8 = 0x80;
+const FAULT: u8 = 0x20;
+const DATA_REQUEST: u8 = 0x08;
+const FAILED: u8 = 0x01;
+
+// The drive register: bits 7 and 5 are always set, bit 6 picks LBA addressing and bit 4 the
+// slave; the low four are the top of a 28-bit LBA
+const DRIVE_BASE: u8 = 0xA0;
+const USE_LBA: u8 = 0x40;
+const SLAVE: u8 = 0x10;
+
+// The device control register bit that stops the drive raising interrupts
+const NO_INTERRUPTS: u8 = 0x02;
+
+const IDENTIFY: u8 = 0xEC;
+const READ_SECTORS: u8 = 0x20;
+const READ_SECTORS_EXT: u8 = 0x24;
+const WRITE_SECTORS: u8 = 0x30;
+const WRITE_SECTORS_EXT: u8 = 0x34;
+const FLUSH_CACHE: u8 = 0xE7;
+const FLUSH_CACHE_EXT: u8 = 0xEA;
+const READ_DMA: u8 = 0xC8;
+const READ_DMA_EXT: u8 = 0x25;
+const WRITE_DMA: u8 = 0xCA;
+const WRITE_DMA_EXT: u8 = 0x35;
+
+// The IDE controller's PCI class and subclass, and the bit of its programming interface that
+// says it can bus master; its bus-master registers are the I/O ports of BAR 4
+const IDE_CLASS: (u8, u8) = (0x01, 0x01);
+const BUS_MASTER_CAPABLE: u8 = 0x80;
+const BUS_MASTER_BAR: u8 = 4;
+// The bus-master registers, from a channel's base; the secondary's are 8 on from the primary's
+const BUS_MASTER_COMMAND: u16 = 0;
+const BUS_MASTER_STATUS: u16 = 2;
+const BUS_MASTER_TABLE: u16 = 4;
+const SECONDARY_BUS_MASTER: u16 = 8;
+// Bus-master command bits: start, and move the sectors into memory rather than out of it
+const START: u8 = 0x01;
+const INTO_MEMORY: u8 = 0x08;
+// Bus-master status bits: still moving, failed, and the drive finished; the last two are
+// cleared by writing them
+const ACTIVE: u8 = 0x01;
+const DMA_FAILED: u8 = 0x02;
+const DMA_INTERRUPT: u8 = 0x04;
+// The bit of a PRD table entry's last word that ends the table
+const LAST_ENTRY: u64 = 1 << 63;
+// The bounce buffer, which one command fills; each of its pages has an entry of its own, as an
+// entry can't cross a 64 KiB boundary
+const DMA_PAGES: u64 = 16;
+const DMA_SECTORS: usize = (DMA_PAGES * PAGE_SIZE) as usize / BLOCK_SIZE;
+// The controller only takes 32-bit addresses
+const DMA_LIMIT: u64 = 1 << 32;
+
+// The most sectors one command moves: a sector count of 0 means 256
+const MAX_SECTORS: usize = 256;
+// How many times to read the status before giving up on the drive, about a second
+const TIMEOUT_POLLS: u32 = 1_000_000;
+/// How many words a sector is, as the data port moves them.
+pub const WORDS_PER_SECTOR: usize = BLOCK_SIZE / 2;
+
+// The drives in the order they are probed: primary master and slave, then secondary
+const NAMES: [&str; 4] = ["ata0", "ata1", "ata2", "ata3"];
+
+// An IDE channel's ports: its registers from `io` on, and its device control register, which
+// reads as the status without acknowledging anything, and its bus master if it has one
+struct Channel {
+    io: u16,
+    control: u16,
+    bus_master: Option<BusMaster>,
+}
+
+// A channel's bus-master registers from `io` on, and its PRD table and bounce buffer
+struct BusMaster {
+    io: u16,
+    table: Dma,
+    buffer: Dma,
+}
+
+// The two drives on a channel share its registers, so they take turns
+static CHANNELS: [SpinLock<Channel>; 2] = [
+    SpinLock::new(Channel { io: 0x1F0, control: 0x3F6, bus_master: None }),
+    SpinLock::new(Channel { io: 0x170, control: 0x376, bus_master: None }),
+];
+
+impl BusMaster {
+    fn read(&self, register: u16) -> u8 {
+        unsafe { Port::<u8>::new(self.io + register).read() }
+    }
+
+    fn write(&self, register: u16, value: u8) {
+        unsafe { Port::<u8>::new(self.io + register).write(value) }
+    }
+
+    // Points the PRD table at the first `bytes` bytes of the bounce buffer
+    fn fill_table(&self, bytes: usize) {
+        let table: *mut u64 = self.table.start.as_mut_ptr();
+        let pages = bytes.div_ceil(PAGE_SIZE as usize);
+        for page in 0..pages {
+            let length = (bytes - page * PAGE_SIZE as usize).min(PAGE_SIZE as usize) as u64;
+            let last = if page + 1 == pages { LAST_ENTRY } else { 0 };
+            let address = self.buffer.physical.as_u64() + page as u64 * PAGE_SIZE;
+            unsafe { table.add(page).write_volatile(address | length << 32 | last) };
+        }
+        unsafe { Port::<u32>::new(self.io + BUS_MASTER_TABLE).write(self.table.physical.as_u64() as u32) };
+    }
+}
+
+impl Channel {
+    fn read(&self, register: u16) -> u8 {
+        unsafe { Port::<u8>::new(self.io + register).read() }
+    }
+
+    fn write(&self, register: u16, value: u8) {
+        unsafe { Port::<u8>::new(self.io + register).write(value) }
+    }
+
+    // Gives a drive that was just selected or sent a command the 400 ns it may take before
+    // its status means anything, by reading the status four times
+    fn settle(&self) {
+        for _ in 0..4 {
+            unsafe { Port::<u8>::new(self.control).read() };
+        }
+    }
+
+    fn select(&self, drive: u8) {
+        self.write(DRIVE, drive);
+        self.settle();
+    }
+
+    fn command(&self, command: u8) {
+        self.write(COMMAND, command);
+        self.settle();
+    }
+
+    // Waits until the drive isn't busy, and returns its status
+    fn wait_idle(&self) -> Result<u8, Error> {
+        for _ in 0..TIMEOUT_POLLS {
+            let status = self.read(STATUS);
+            if status & BUSY == 0 {
+                return Ok(status);
+            }
+        }
+        Err(Error::Io)
+    }
+
+    // Waits until the drive has a sector to move through the data port
+    fn wait_data(&self) -> Result<(), Error> {
+        for _ in 0..TIMEOUT_POLLS {
+            let status = self.read(STATUS);
+            if status & BUSY != 0 {
+                continue;
+            }
+            if status & (FAILED | FAULT) != 0 {
+                return Err(Error::Io);
+            }
+            if status & DATA_REQUEST != 0 {
+                return Ok(());
+            }
+        }
+        Err(Error::Io)
+    }
+
+    fn read_sector(&self, buffer: &mut [u8]) {
+        let mut data = Port::<u16>::new(self.io + DATA);
+        for word in buffer.chunks_exact_mut(2) {
+            word.copy_from_slice(&unsafe { data.read() }.to_le_bytes());
+        }
+    }
+
+    fn write_sector(&self, bytes: &[u8]) {
+        let mut data = Port::<u16>::new(self.io + DATA);
+        for word in bytes.chunks_exact(2) {
+            unsafe { data.write(u16::from_le_bytes([word[0], word[1]])) };
+        }
+    }
+
+    // Asks the drive in `drive` what it is, and returns its answer, or None if there is no
+    // ATA drive there
+    fn identify(&self, drive: u8) -> Option<[u16; WORDS_PER_SECTOR]> {
+        self.select(drive);
+        for register in [SECTOR_COUNT, LBA_LOW, LBA_MID, LBA_HIGH] {
+            self.write(register, 0);
+        }
+        self.command(IDENTIFY);
+        if self.read(STATUS) == 0 {
+            return None;
+        }
+        self.wait_idle().ok()?;
+        // ATAPI and SATA drives put their signature here instead of answering
+        if self.read(LBA_MID) != 0 || self.read(LBA_HIGH) != 0 {
+            return None;
+        }
+        self.wait_data().ok()?;
+        let mut words = [0; WORDS_PER_SECTOR];
+        let mut data = Port::<u16>::new(self.io + DATA);
+        for word in &mut words {
+            *word = unsafe { data.read() };
+        }
+        Some(words)
+    }
+
+    // Moves `count` sectors from `sector` on between the drive in `drive` and the start of the
+    // bounce buffer, by DMA
+    fn transfer(&self, drive: u8, lba48: bool, sector: u64, count: usize, write: bool) -> Result<(), Error> {
+        let bus_master = self.bus_master.as_ref().ok_or(Error::Io)?;
+        bus_master.write(BUS_MASTER_COMMAND, 0);
+        bus_master.fill_table(count * BLOCK_SIZE);
+        bus_master.write(BUS_MASTER_STATUS, DMA_FAILED | DMA_INTERRUPT);
+        let direction = if write { 0 } else { INTO_MEMORY };
+        bus_master.write(BUS_MASTER_COMMAND, direction);
+        self.address(drive, lba48, sector, count)?;
+        self.command(match (write, lba48) {
+            (false, false) => READ_DMA,
+            (false, true) => READ_DMA_EXT,
+            (true, false) => WRITE_DMA,
+            (true, true) => WRITE_DMA_EXT,
+        });
+        bus_master.write(BUS_MASTER_COMMAND, direction | START);
+        let finished = (0..TIMEOUT_POLLS).any(|_| bus_master.read(BUS_MASTER_STATUS) & (ACTIVE | DMA_FAILED) != ACTIVE);
+        bus_master.write(BUS_MASTER_COMMAND, direction);
+        let status = self.wait_idle()?;
+        if !finished || bus_master.read(BUS_MASTER_STATUS) & DMA_FAILED != 0 || status & (FAILED | FAULT) != 0 {
+            return Err(Error::Io);
+        }
+        Ok(())
+    }
+
+    // Sets up a command on `count` sectors from `sector` on
+    fn address(&self, drive: u8, lba48: bool, sector: u64, count: usize) -> Result<(), Error> {
+        self.wait_idle()?;
+        let lba = sector.to_le_bytes();
+        if lba48 {
+            self.select(drive | USE_LBA);
+            // The high bytes go first, then the low bytes over them
+            self.write(SECTOR_COUNT, (count >> 8) as u8);
+            self.write(LBA_LOW, lba[3]);
+            self.write(LBA_MID, lba[4]);
+            self.write(LBA_HIGH, lba[5]);
+        } else {
+            self.select(drive | USE_LBA | (lba[3] & 0x0F));
+        }
+        self.write(SECTOR_COUNT, count as u8);
+        self.write(LBA_LOW, lba[0]);
+        self.write(LBA_MID, lba[1]);
+        self.write(LBA_HIGH, lba[2]);
+        Ok(())
+    }
+}
+
+/// What a disk says about itself in answer to IDENTIFY, which SATA disks answer the same way.
+pub struct Identity {
+    pub model: TextBuffer<40>,
+    /// How many sectors LBAs can reach, 0 if the disk only takes CHS addresses.
+    pub sectors: u64,
+    /// Whether the disk takes 48-bit LBAs, not just 28-bit ones.
+    pub lba48: bool,
+    /// Whether the disk can move sectors by DMA.
+    pub dma: bool,
+}
+
+impl Identity {
+    /// Reads the answer to IDENTIFY, or returns None if it isn't from a hard disk.
+    pub fn parse(words: &[u16; WORDS_PER_SECTOR]) -> Option<Identity> {
+        // Word 0 bit 15 is set for anything but a hard disk
+        if words[0] & 0x8000 != 0 {
+            return None;
+        }
+        let lba48 = words[83] & (1 << 10) != 0;
+        let dma = words[49] & (1 << 8) != 0;
+        let count: &[u16] = if lba48 { &words[100..104] } else { &words[60..62] };
+        let sectors = count.iter().rev().fold(0, |sectors, &word| sectors << 16 | u64::from(word));
+        // The model is text with the two bytes of each word swapped, padded with spaces
+        let mut padded = TextBuffer::<40>::new();
+        for byte in words[27..47].iter().flat_map(|word| word.to_be_bytes()) {
+            let _ = padded.write_char(char::from(byte));
+        }
+        let mut model = TextBuffer::new();
+        write!(model, "{}", padded.as_str().trim()).unwrap();
+        Some(Identity { model, sectors, lba48, dma })
+    }
+}
+
+// An ATA drive found on one of the channels
+struct Drive {
+    name: &'static str,
+    channel: &'static SpinLock<Channel>,
+    // What goes in the drive register to select it
+    drive: u8,
+    sectors: u64,
+    lba48: bool,
+    // Whether its sectors move by DMA, until that fails
+    dma: AtomicBool,
+}
+
+impl Drive {
+    fn read_dma(&self, channel: &Channel, block: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        let bounce = channel.bus_master.as_ref().ok_or(Error::Io)?.buffer;
+        for (chunk, sectors) in (block..).step_by(DMA_SECTORS).zip(buffer.chunks_mut(DMA_SECTORS * BLOCK_SIZE)) {
+            channel.transfer(self.drive, self.lba48, chunk, sectors.len() / BLOCK_SIZE, false)?;
+            unsafe { ptr::copy_nonoverlapping(bounce.start.as_ptr(), sectors.as_mut_ptr(), sectors.len()) };
+        }
+        Ok(())
+    }
+
+    fn write_dma(&self, channel: &Channel, block: u64, bytes: &[u8]) -> Result<(), Error> {
+        let bounce = channel.bus_master.as_ref().ok_or(Error::Io)?.buffer;
+        for (chunk, sectors) in (block..).step_by(DMA_SECTORS).zip(bytes.chunks(DMA_SECTORS * BLOCK_SIZE)) {
+            unsafe { ptr::copy_nonoverlapping(sectors.as_ptr(), bounce.start.as_mut_ptr(), sectors.len()) };
+            channel.transfer(self.drive, self.lba48, chunk, sectors.len() / BLOCK_SIZE, true)?;
+        }
+        Ok(())
+    }
+
+    // Runs `dma` if the drive still uses DMA, and returns true if it worked; if it failed, the
+    // drive is moved to PIO for good
+    fn try_dma(&self, dma: impl FnOnce() -> Result<(), Error>) -> bool {
+        if !self.dma.load(Ordering::Relaxed) {
+            return false;
+        }
+        if dma().is_ok() {
+            return true;
+        }
+        self.dma.store(false, Ordering::Relaxed);
+        writeln!(serial(), "{}: DMA failed, using PIO", self.name).unwrap();
+        false
+    }
+}
+
+impl BlockDevice for Drive {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn blocks(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read(&self, block: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        block::check(self.sectors, block, buffer.len())?;
+        let channel = self.channel.lock();
+        if self.try_dma(|| self.read_dma(&channel, block, buffer)) {
+            return Ok(());
+        }
+        let command = if self.lba48 { READ_SECTORS_EXT } else { READ_SECTORS };
+        for (chunk, sectors) in (block..).step_by(MAX_SECTORS).zip(buffer.chunks_mut(MAX_SECTORS * BLOCK_SIZE)) {
+            channel.address(self.drive, self.lba48, chunk, sectors.len() / BLOCK_SIZE)?;
+            channel.command(command);
+            for sector in sectors.chunks_exact_mut(BLOCK_SIZE) {
+                channel.wait_data()?;
+                channel.read_sector(sector);
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&self, block: u64, bytes: &[u8]) -> Result<(), Error> {
+        block::check(self.sectors, block, bytes.len())?;
+        let channel = self.channel.lock();
+        if self.try_dma(|| self.write_dma(&channel, block, bytes)) {
+            return Ok(());
+        }
+        let command = if self.lba48 { WRITE_SECTORS_EXT } else { WRITE_SECTORS };
+        for (chunk, sectors) in (block..).step_by(MAX_SECTORS).zip(bytes.chunks(MAX_SECTORS * BLOCK_SIZE)) {
+            channel.address(self.drive, self.lba48, chunk, sectors.len() / BLOCK_SIZE)?;
+            channel.command(command);
+            for sector in sectors.chunks_exact(BLOCK_SIZE) {
+                channel.wait_data()?;
+                channel.write_sector(sector);
+            }
+        }
+        // The drive is still writing the last sector
+        if channel.wait_idle()? & (FAILED | FAULT) != 0 {
+            return Err(Error::Io);
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        let channel = self.channel.lock();
+        // A flush takes no sectors, but this selects the drive
+        channel.address(self.drive, self.lba48, 0, 0)?;
+        channel.command(if self.lba48 { FLUSH_CACHE_EXT } else { FLUSH_CACHE });
+        if channel.wait_idle()? & (FAILED | FAULT) != 0 {
+            return Err(Error::Io);
+        }
+        Ok(())
+    }
+}
+
+/// Finds the ATA drives on the IDE controller and registers them as block devices, as ata0 to
+/// ata3. Called once during boot, after `memory::init` and `block::init`.
+pub fn init() {
+    init_bus_master();
+    let drives = CHANNELS.iter().flat_map(|channel| [(channel, DRIVE_BASE), (channel, DRIVE_BASE | SLAVE)]);
+    for (name, (channel, drive)) in NAMES.into_iter().zip(drives) {
+        let Some(drive) = probe(name, channel, drive) else {
+            continue;
+        };
+        if !block::register(Box::leak(Box::new(drive))) {
+            writeln!(serial(), "{name}: too many block devices, not registered").unwrap();
+        }
+    }
+}
+
+// Looks for a drive at `drive` on `channel`, and says what it found on the serial port
+fn probe(name: &'static str, channel: &'static SpinLock<Channel>, drive: u8) -> Option<Drive> {
+    let identity = {
+        let channel = channel.lock();
+        // With no controller there, nothing drives the bus and every register reads as 0xFF
+        if channel.read(STATUS) == 0xFF {
+            return None;
+        }
+        unsafe { Port::<u8>::new(channel.control).write(NO_INTERRUPTS) };
+        channel.identify(drive)?
+    };
+    let Identity { model, sectors, lba48, dma } = Identity::parse(&identity)?;
+    if sectors == 0 {
+        writeln!(serial(), "{name}: {} only takes CHS addresses, not used", model.as_str()).unwrap();
+        return None;
+    }
+    let dma = dma && channel.lock().bus_master.is_some();
+    writeln!(serial(), "{name}: {}, {sectors} sectors, {}-bit LBA, {}", model.as_str(), if lba48 { 48 } else { 28 }, if dma { "DMA" } else { "PIO" }).unwrap();
+    Some(Drive { name, channel, drive, sectors, lba48, dma: AtomicBool::new(dma) })
+}
+
+// Finds the IDE controller on the PCI bus and gives each channel its bus-master registers, a
+// PRD table and a bounce buffer, if the controller can bus master
+fn init_bus_master() {
+    let controller = pci::devices().find(|device| {
+        let (class, subclass, prog_if) = device.class();
+        (class, subclass) == IDE_CLASS && prog_if & BUS_MASTER_CAPABLE != 0
+    });
+    let Some((controller, io)) = controller.and_then(|controller| Some((controller, controller.io_bar(BUS_MASTER_BAR)?))) else {
+        return;
+    };
+    controller.enable_bus_master();
+    for (channel, offset) in CHANNELS.iter().zip([0, SECONDARY_BUS_MASTER]) {
+        let memory = memory::alloc_dma(1).zip(memory::alloc_dma(DMA_PAGES));
+        let Some((table, buffer)) = memory.filter(|(table, buffer)| {
+            table.physical.as_u64() + PAGE_SIZE <= DMA_LIMIT && buffer.physical.as_u64() + DMA_PAGES * PAGE_SIZE <= DMA_LIMIT
+        }) else {
+            writeln!(serial(), "ata: no memory below 4 GiB for DMA, using PIO").unwrap();
+            return;
+        };
+        channel.lock().bus_master = Some(BusMaster { io: io + offset, table, buffer });
+    }
+}
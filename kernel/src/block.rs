@@ -0,0 +1,228 @@
+use alloc::boxed::Box;
+use core::ptr;
+use kernel::sync::SpinLock;
+use crate::channel::Channel;
+use crate::initrd;
+
+// Block devices: disks, read and written a sector at a time, that filesystems are mounted from.
+// Every driver registers its disks here, by name, and filesystems only see the `BlockDevice`
+// trait, so a FAT32 volume mounts the same from an IDE drive, a SATA, NVMe or virtio disk, or
+// a disk image in the initrd: a file whose name ends in .img, used where it is in memory, so
+// what is written to it is gone after a reboot. Writes may sit in a disk's cache until it is
+// flushed, which filesystems do at the end of every change.
+//
+// Reads and writes block until they are done. Requests can also be submitted, so the task that
+// submitted one carries on meanwhile and is called back once it is done. A disk that can
+// completes them from its interrupt handler, as virtio ones do; for the rest they go to the
+// block I/O task, which carries them out one at a time with the blocking calls. Only the shell
+// submits requests so far; the filesystems still block.
+pub const BLOCK_SIZE: usize = 512;
+pub const MAX_DEVICES: usize = 8;
+// How many submitted requests can wait for the I/O task
+const MAX_REQUESTS: usize = 16;
+
+/// Why a block device operation failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// A block past the end of the device.
+    OutOfRange,
+    /// The device reported an error or stopped answering.
+    Io,
+}
+
+/// A disk, read and written in whole sectors.
+pub trait BlockDevice: Sync {
+    fn name(&self) -> &str;
+    /// How many bytes a sector is. Only disks with `BLOCK_SIZE` sectors, which is all of them
+    /// so far, can have filesystems mounted from them.
+    fn sector_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+    /// How many sectors the device has.
+    fn blocks(&self) -> u64;
+    /// How many bytes the device holds.
+    fn capacity(&self) -> u64 {
+        self.blocks() * self.sector_size() as u64
+    }
+    /// Reads the sectors from `block` on into `buffer`, whose length is a multiple of the
+    /// sector size.
+    fn read(&self, block: u64, buffer: &mut [u8]) -> Result<(), Error>;
+    /// Writes `bytes`, whose length is a multiple of the sector size, to the sectors from
+    /// `block` on. They may only be in the disk's cache until it is flushed.
+    fn write(&self, block: u64, bytes: &[u8]) -> Result<(), Error>;
+    /// Waits until everything written is on the disk itself.
+    fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+    /// Queues `request`, calling `done` once it is carried out, or gives it back if too many
+    /// are queued already. Unless the disk completes requests from its interrupt handler, they
+    /// go to the block I/O task. Safe to call from interrupt handlers.
+    fn submit(&self, request: Request, done: Callback) -> Result<(), (Request, Callback)> {
+        queue(request, done)
+    }
+}
+
+/// What a submitted request asks of its device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Read,
+    Write,
+    Flush,
+}
+
+/// A request submitted to a block device.
+pub struct Request {
+    pub device: &'static dyn BlockDevice,
+    pub operation: Operation,
+    /// The first sector read or written.
+    pub block: u64,
+    /// What a read fills or a write writes, whole sectors long, which the request holds until
+    /// it completes; a flush's is ignored.
+    pub buffer: &'static mut [u8],
+    /// Anything the submitter wants back with the completion, such as which request it was.
+    pub tag: usize,
+}
+
+/// A request carried out, with its buffer given back.
+pub struct Completion {
+    pub buffer: &'static mut [u8],
+    pub tag: usize,
+    pub result: Result<(), Error>,
+}
+
+/// Called with a request once it is done: from the disk's interrupt handler, for a disk that
+/// completes requests there, or else on the block I/O task. So it mustn't block, and has to
+/// take locks with `lock_irq`.
+pub type Callback = fn(Completion);
+
+static DEVICES: SpinLock<[Option<&'static dyn BlockDevice>; MAX_DEVICES]> = SpinLock::new([None; MAX_DEVICES]);
+// Which of the devices are in use, by their slots in DEVICES
+static CLAIMED: SpinLock<[bool; MAX_DEVICES]> = SpinLock::new([false; MAX_DEVICES]);
+// The submitted requests, in order, for the I/O task
+static REQUESTS: Channel<(Request, Callback), MAX_REQUESTS> = Channel::new();
+
+/// Registers the disk images in the initrd. Called once during boot, after `initrd::init`.
+pub fn init() {
+    for file in initrd::files().filter(|file| file.path.ends_with(".img")) {
+        // One for each image, once, so they may as well stay allocated
+        let image = Image { name: file.path, start: file.data.as_ptr().cast_mut(), length: file.data.len() };
+        register(Box::leak(Box::new(image)));
+    }
+}
+
+/// Adds `device` to the devices filesystems can be mounted from, unless there are too many.
+pub fn register(device: &'static dyn BlockDevice) -> bool {
+    let mut devices = DEVICES.lock_irq();
+    let Some(slot) = devices.iter_mut().find(|slot| slot.is_none()) else {
+        return false;
+    };
+    *slot = Some(device);
+    true
+}
+
+/// Checks that `length` bytes from `block` on are whole blocks of a device `blocks` long.
+pub fn check(blocks: u64, block: u64, length: usize) -> Result<(), Error> {
+    match block.checked_add((length / BLOCK_SIZE) as u64) {
+        Some(end) if end <= blocks && length.is_multiple_of(BLOCK_SIZE) => Ok(()),
+        _ => Err(Error::OutOfRange),
+    }
+}
+
+/// Returns the registered devices, in the order they were registered.
+pub fn devices() -> impl Iterator<Item = &'static dyn BlockDevice> {
+    let devices = *DEVICES.lock_irq();
+    devices.into_iter().flatten()
+}
+
+/// Returns the registered device called `name`.
+pub fn find(name: &str) -> Option<&'static dyn BlockDevice> {
+    devices().find(|device| device.name() == name)
+}
+
+/// Marks the registered device called `name` as in use, by a filesystem mounted from it or the
+/// partitions on it, so nothing else is mounted from it, and returns false if it already was
+/// or there is no such device.
+pub fn claim(name: &str) -> bool {
+    let Some(slot) = devices().position(|device| device.name() == name) else {
+        return false;
+    };
+    !core::mem::replace(&mut CLAIMED.lock_irq()[slot], true)
+}
+
+/// Undoes `claim`, for a mount that failed after all.
+pub fn release(name: &str) {
+    if let Some(slot) = devices().position(|device| device.name() == name) {
+        CLAIMED.lock_irq()[slot] = false;
+    }
+}
+
+/// Submits `request` to its device, which calls `done` once it is carried out. Gives the
+/// request back if too many are queued already. Safe to call from interrupt handlers.
+pub fn submit(request: Request, done: Callback) -> Result<(), Request> {
+    let device = request.device;
+    device.submit(request, done).map_err(|(request, _)| request)
+}
+
+/// Queues `request` for the block I/O task, for a device that doesn't complete requests itself.
+pub fn queue(request: Request, done: Callback) -> Result<(), (Request, Callback)> {
+    REQUESTS.sender().send((request, done))
+}
+
+/// Carries out the submitted requests, one at a time in the order they came, forever. This is
+/// the block I/O task's entry point.
+pub fn run() {
+    let mut requests = REQUESTS.receiver().unwrap();
+    loop {
+        let (Request { device, operation, block, buffer, tag }, done) = requests.recv();
+        let result = match operation {
+            Operation::Read => device.read(block, buffer),
+            Operation::Write => device.write(block, buffer),
+            Operation::Flush => device.flush(),
+        };
+        done(Completion { buffer, tag, result });
+    }
+}
+
+// A disk image in the initrd, which the bootloader maps writable
+struct Image {
+    name: &'static str,
+    start: *mut u8,
+    length: usize,
+}
+
+// The image is only reached through the filesystem on it, which keeps its own lock
+unsafe impl Send for Image {}
+unsafe impl Sync for Image {}
+
+impl Image {
+    // Where blocks `block` on, `length` bytes of them, start in the image
+    fn range(&self, block: u64, length: usize) -> Result<usize, Error> {
+        let start = usize::try_from(block).ok().and_then(|block| block.checked_mul(BLOCK_SIZE)).ok_or(Error::OutOfRange)?;
+        match start.checked_add(length) {
+            Some(end) if end <= self.length => Ok(start),
+            _ => Err(Error::OutOfRange),
+        }
+    }
+}
+
+impl BlockDevice for Image {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn blocks(&self) -> u64 {
+        (self.length / BLOCK_SIZE) as u64
+    }
+
+    fn read(&self, block: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        let start = self.range(block, buffer.len())?;
+        unsafe { ptr::copy_nonoverlapping(self.start.add(start), buffer.as_mut_ptr(), buffer.len()) };
+        Ok(())
+    }
+
+    fn write(&self, block: u64, bytes: &[u8]) -> Result<(), Error> {
+        let start = self.range(block, bytes.len())?;
+        unsafe { ptr::copy_nonoverlapping(bytes.as_ptr(), self.start.add(start), bytes.len()) };
+        Ok(())
+    }
+}
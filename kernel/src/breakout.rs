@@ -0,0 +1,371 @@
+use core::fmt::Write;
+use kernel::serial;
+use lazy_static::lazy_static;
+use pc_keyboard::{DecodedKey, KeyCode};
+use kernel::sync::SpinLock;
+use crate::{assets, physics, rand, sound, ui};
+use crate::game::Game;
+use crate::highscores::{self, Slot};
+use crate::input::InputEvent;
+use crate::screen::CHAR_HEIGHT;
+use crate::sprite::{DirtyRects, Rect, Sprite};
+
+// Playfield dimensions, matching the area pong uses
+const SCREEN_WIDTH: usize = 640;
+const SCREEN_HEIGHT: usize = 480;
+const HUD_HEIGHT: usize = 32;
+
+const BRICK_COLUMNS: usize = 10;
+const MAX_ROWS: usize = 8;
+const BRICK_WIDTH: usize = 60;
+const BRICK_HEIGHT: usize = 16;
+const BRICK_GAP: usize = 4;
+const BRICK_TOP: usize = HUD_HEIGHT + 32;
+
+const PADDLE_WIDTH: usize = 80;
+const PADDLE_HEIGHT: usize = 10;
+const PADDLE_Y: usize = SCREEN_HEIGHT - 30;
+const PADDLE_STEP: usize = 16; // Pixels the paddle moves per key press
+const PADDLE_COLOR: ui::Color = (0, 160, 255);
+
+const BALL_SIZE: usize = 8;
+const INITIAL_BALL_SPEED: i32 = 3; // Vertical speed on the first level
+const MAX_BALL_SPEED: i32 = 7; // Vertical speed cap as levels get faster
+const MAX_BALL_SPEED_X: i32 = 5; // Sideways speed after a hit on the very edge of the paddle
+const INITIAL_LIVES: u32 = 3;
+
+// Colour and points for each brick row, top row first. Levels with fewer rows
+// spread their rows over this table.
+const ROW_STYLES: [(ui::Color, u32); MAX_ROWS] = [
+    ((255, 0, 0), 7),
+    ((255, 0, 0), 7),
+    ((255, 128, 0), 5),
+    ((255, 128, 0), 5),
+    ((255, 255, 0), 3),
+    ((255, 255, 0), 3),
+    ((0, 200, 0), 1),
+    ((0, 200, 0), 1),
+];
+
+// Sound effects: (frequency in Hz, duration in ms)
+const PADDLE_SOUND: (u32, u64) = (880, 40);
+const BRICK_SOUND: (u32, u64) = (1320, 30);
+const LOST_BALL_SOUND: (u32, u64) = (110, 300);
+
+const O: Option<ui::Color> = None;
+const W: Option<ui::Color> = Some(ui::WHITE);
+static BALL_PIXELS: [Option<ui::Color>; BALL_SIZE * BALL_SIZE] = [
+    O, O, W, W, W, W, O, O,
+    O, W, W, W, W, W, W, O,
+    W, W, W, W, W, W, W, W,
+    W, W, W, W, W, W, W, W,
+    W, W, W, W, W, W, W, W,
+    W, W, W, W, W, W, W, W,
+    O, W, W, W, W, W, W, O,
+    O, O, W, W, W, W, O, O,
+];
+static BALL: Sprite = Sprite { width: BALL_SIZE, height: BALL_SIZE, pixels: &BALL_PIXELS };
+
+pub struct Breakout {
+    bricks: [[bool; BRICK_COLUMNS]; MAX_ROWS],
+    rows: usize,
+    bricks_left: usize,
+    paddle_x: usize,
+    ball_x: i32,
+    ball_y: i32,
+    vel_x: i32,
+    vel_y: i32,
+    launched: bool,
+    score: u32,
+    lives: u32,
+    level: u32,
+    game_over: bool,
+    new_high_score: bool,
+    dirty: DirtyRects<16>,
+}
+
+lazy_static! {
+    pub static ref BREAKOUT: SpinLock<Breakout> = SpinLock::new(Breakout {
+        bricks: [[false; BRICK_COLUMNS]; MAX_ROWS],
+        rows: 0,
+        bricks_left: 0,
+        paddle_x: 0,
+        ball_x: 0,
+        ball_y: 0,
+        vel_x: 0,
+        vel_y: 0,
+        launched: false,
+        score: 0,
+        lives: 0,
+        level: 0,
+        game_over: false,
+        new_high_score: false,
+        dirty: DirtyRects::new(),
+    });
+}
+
+fn brick_rect(row: usize, column: usize) -> Rect {
+    Rect::new(
+        column * (BRICK_WIDTH + BRICK_GAP) + BRICK_GAP / 2,
+        BRICK_TOP + row * (BRICK_HEIGHT + BRICK_GAP),
+        BRICK_WIDTH,
+        BRICK_HEIGHT,
+    )
+}
+
+fn row_style(row: usize, rows: usize) -> (ui::Color, u32) {
+    ROW_STYLES[row * MAX_ROWS / rows]
+}
+
+impl Breakout {
+    fn start_level(&mut self) {
+        self.rows = (3 + self.level as usize).min(MAX_ROWS);
+        self.bricks = [[false; BRICK_COLUMNS]; MAX_ROWS];
+        for row in self.bricks.iter_mut().take(self.rows) {
+            row.fill(true);
+        }
+        self.bricks_left = self.rows * BRICK_COLUMNS;
+        self.paddle_x = (SCREEN_WIDTH - PADDLE_WIDTH) / 2;
+        self.reset_ball();
+    }
+
+    /// Puts the ball back on the paddle, waiting to be launched.
+    fn reset_ball(&mut self) {
+        self.launched = false;
+        self.vel_x = 0;
+        self.vel_y = 0;
+        self.ball_x = (self.paddle_x + (PADDLE_WIDTH - BALL_SIZE) / 2) as i32;
+        self.ball_y = (PADDLE_Y - BALL_SIZE) as i32;
+    }
+
+    fn launch(&mut self) {
+        self.launched = true;
+        self.vel_x = rand::below(5) as i32 - 2;
+        self.vel_y = -(INITIAL_BALL_SPEED + self.level as i32 - 1).min(MAX_BALL_SPEED);
+    }
+
+    fn ball_rect(&self) -> Rect {
+        BALL.bounds(self.ball_x as usize, self.ball_y as usize)
+    }
+
+    fn paddle_rect(&self) -> Rect {
+        Rect::new(self.paddle_x, PADDLE_Y, PADDLE_WIDTH, PADDLE_HEIGHT)
+    }
+
+    fn brick_hit(&self, ball: &Rect) -> Option<(usize, usize)> {
+        (0..self.rows)
+            .flat_map(|row| (0..BRICK_COLUMNS).map(move |column| (row, column)))
+            .find(|&(row, column)| self.bricks[row][column] && brick_rect(row, column).intersects(ball))
+    }
+
+    fn move_paddle(&mut self, dx: isize) {
+        let old = self.paddle_rect();
+        let max_x = (SCREEN_WIDTH - PADDLE_WIDTH) as isize;
+        self.paddle_x = (self.paddle_x as isize + dx).clamp(0, max_x) as usize;
+        self.dirty.add(old);
+        self.dirty.add(self.paddle_rect());
+
+        // The ball rides on the paddle until it is launched
+        if !self.launched {
+            self.dirty.add(self.ball_rect());
+            self.ball_x = (self.paddle_x + (PADDLE_WIDTH - BALL_SIZE) / 2) as i32;
+            self.dirty.add(self.ball_rect());
+        }
+        self.redraw_dirty();
+    }
+
+    fn step(&mut self) {
+        let old = self.ball_rect();
+        let mut x = self.ball_x + self.vel_x;
+        let mut y = self.ball_y + self.vel_y;
+
+        // Side and top walls
+        if x <= 0 {
+            x = 0;
+            self.vel_x = self.vel_x.abs();
+        } else if x >= (SCREEN_WIDTH - BALL_SIZE) as i32 {
+            x = (SCREEN_WIDTH - BALL_SIZE) as i32;
+            self.vel_x = -self.vel_x.abs();
+        }
+        if y <= HUD_HEIGHT as i32 {
+            y = HUD_HEIGHT as i32;
+            self.vel_y = self.vel_y.abs();
+        }
+
+        // Fell past the paddle
+        if y >= (SCREEN_HEIGHT - BALL_SIZE) as i32 {
+            self.lose_ball(old);
+            return;
+        }
+
+        let ball = BALL.bounds(x as usize, y as usize);
+
+        // Paddle: the bounce angle depends on where the ball lands, like in pong
+        if self.vel_y > 0 && ball.intersects(&self.paddle_rect()) {
+            y = (PADDLE_Y - BALL_SIZE) as i32;
+            self.vel_y = -self.vel_y;
+            let offset = (x + BALL_SIZE as i32 / 2) - (self.paddle_x + PADDLE_WIDTH / 2) as i32;
+            self.vel_x = physics::deflection(offset, (PADDLE_WIDTH + BALL_SIZE) as i32 / 2, MAX_BALL_SPEED_X);
+            sound::beep(PADDLE_SOUND.0, PADDLE_SOUND.1);
+        }
+
+        // Bricks
+        if let Some((row, column)) = self.brick_hit(&ball) {
+            let brick = brick_rect(row, column);
+            self.bricks[row][column] = false;
+            self.bricks_left -= 1;
+            self.score += row_style(row, self.rows).1;
+            self.dirty.add(brick);
+            sound::beep(BRICK_SOUND.0, BRICK_SOUND.1);
+
+            // If the ball was beside the brick before this step it hit a side, otherwise the top or bottom
+            if old.x + old.width <= brick.x || old.x >= brick.x + brick.width {
+                self.vel_x = -self.vel_x;
+            } else {
+                self.vel_y = -self.vel_y;
+            }
+            draw_hud(self);
+
+            if self.bricks_left == 0 {
+                self.level += 1;
+                writeln!(serial(), "Breakout level {} reached", self.level).unwrap();
+                self.start_level();
+                draw_all(self);
+                return;
+            }
+        }
+
+        self.ball_x = x;
+        self.ball_y = y;
+        self.dirty.add(old);
+        self.dirty.add(self.ball_rect());
+        self.redraw_dirty();
+    }
+
+    fn lose_ball(&mut self, old: Rect) {
+        sound::beep(LOST_BALL_SOUND.0, LOST_BALL_SOUND.1);
+        self.lives -= 1;
+        self.dirty.add(old);
+        if self.lives == 0 {
+            self.game_over = true;
+            self.redraw_dirty();
+            draw_hud(self);
+            self.new_high_score = highscores::submit(Slot::Breakout, self.score);
+            writeln!(serial(), "Breakout over with score {}", self.score).unwrap();
+            draw_game_over(self.new_high_score);
+            return;
+        }
+        self.reset_ball();
+        self.dirty.add(self.ball_rect());
+        self.redraw_dirty();
+        draw_hud(self);
+    }
+
+    /// Repaints everything that changed since the last frame.
+    fn redraw_dirty(&mut self) {
+        let mut dirty = core::mem::take(&mut self.dirty);
+        dirty.drain(|rect| self.repaint(rect));
+    }
+
+    /// Redraws everything visible inside `area`, back to front.
+    fn repaint(&self, area: Rect) {
+        ui::fill_rect(area.x, area.y, area.width, area.height, ui::BLACK);
+        for row in 0..self.rows {
+            for column in 0..BRICK_COLUMNS {
+                let brick = brick_rect(row, column);
+                if self.bricks[row][column] && brick.intersects(&area) {
+                    let color = row_style(row, self.rows).0;
+                    ui::fill_rect(brick.x, brick.y, brick.width, brick.height, color);
+                }
+            }
+        }
+        let paddle = self.paddle_rect();
+        if paddle.intersects(&area) {
+            ui::fill_rect(paddle.x, paddle.y, paddle.width, paddle.height, PADDLE_COLOR);
+        }
+        if self.ball_rect().intersects(&area) {
+            assets::sprite("ball", &BALL).draw(self.ball_x as usize, self.ball_y as usize);
+        }
+    }
+}
+
+impl Game for Breakout {
+    fn name(&self) -> &'static str {
+        "Breakout"
+    }
+
+    /// Starts a new game at level 1 and draws the playfield.
+    fn init(&mut self) {
+        self.score = 0;
+        self.lives = INITIAL_LIVES;
+        self.level = 1;
+        self.game_over = false;
+        self.start_level();
+        draw_all(self);
+    }
+
+    /// Moves the ball one step. Called on every timer tick while breakout is on screen.
+    fn update(&mut self, _dt: u64) {
+        if self.launched && !self.game_over {
+            self.step();
+        }
+    }
+
+    /// Handles a key press while breakout is on screen.
+    fn handle_input(&mut self, event: InputEvent) {
+        let InputEvent::Key(key) = event;
+        if self.game_over {
+            if key == DecodedKey::Unicode(' ') {
+                self.init();
+            }
+            return;
+        }
+
+        match key {
+            DecodedKey::RawKey(KeyCode::ArrowLeft) | DecodedKey::Unicode('a') => self.move_paddle(-(PADDLE_STEP as isize)),
+            DecodedKey::RawKey(KeyCode::ArrowRight) | DecodedKey::Unicode('d') => self.move_paddle(PADDLE_STEP as isize),
+            DecodedKey::Unicode(' ') if !self.launched => {
+                self.launch();
+                draw_hud(self);
+            },
+            _ => {}
+        }
+    }
+
+    fn render(&mut self) {
+        self.redraw_dirty();
+    }
+
+    fn redraw(&mut self) {
+        draw_all(self);
+        if self.game_over {
+            draw_game_over(self.new_high_score);
+        }
+    }
+}
+
+fn draw_all(game: &mut Breakout) {
+    game.dirty = DirtyRects::new();
+    game.repaint(Rect::new(0, HUD_HEIGHT, SCREEN_WIDTH, SCREEN_HEIGHT - HUD_HEIGHT));
+    ui::fill_rect(0, HUD_HEIGHT - 1, SCREEN_WIDTH, 1, ui::GREY);
+    draw_hud(game);
+}
+
+fn draw_hud(game: &Breakout) {
+    let mut text = ui::TextBuffer::<64>::new();
+    write!(text, "Score: {}   Lives: {}   Level: {}", game.score, game.lives, game.level).unwrap();
+    ui::fill_rect(0, 0, SCREEN_WIDTH, HUD_HEIGHT - 1, ui::BLACK);
+    ui::draw_label(8, 8, text.as_str(), ui::WHITE);
+    if !game.launched && !game.game_over {
+        ui::draw_label(SCREEN_WIDTH - ui::text_width("SPACE: launch") - 8, 8, "SPACE: launch", ui::GREY);
+    }
+}
+
+fn draw_game_over(record: bool) {
+    let y = SCREEN_HEIGHT / 2;
+    let title = if record { "NEW HIGH SCORE!" } else { "GAME OVER" };
+    for (i, line) in [title, "SPACE: play again   ESC: menu"].iter().enumerate() {
+        let x = (SCREEN_WIDTH - ui::text_width(line)) / 2;
+        ui::draw_label(x, y + i * 2 * CHAR_HEIGHT, line, ui::WHITE);
+    }
+}
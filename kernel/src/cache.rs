@@ -0,0 +1,308 @@
+use alloc::boxed::Box;
+use core::fmt::Write;
+use core::{mem, ptr, slice};
+use kernel::serial;
+use kernel::sync::SpinLock;
+use crate::block::{self, BLOCK_SIZE, BlockDevice, Error};
+use crate::config;
+use crate::memory::{self, PAGE_SIZE};
+use crate::sleeplock::SleepLock;
+
+// A cache of sectors between the filesystems and the disks they are mounted from, shared by
+// every disk, so the sectors read over and over, the FAT's above all, come from memory. It
+// holds as many sectors as kernel.cfg's [cache] budget has room for, and when it is full the
+// one used longest ago makes way. Writes either go straight through to the disk as well, or,
+// in write-back mode, only mark the sector dirty until the disk is flushed or the sector
+// makes way, so a sector changed many times in one change to a file is written once.
+// Filesystems flush at the end of every change, and `flush_all` writes back every disk, for
+// shutting down.
+//
+// The sectors and what the cache knows about each are in pages of their own, mapped at boot,
+// rather than on the small heap. So that finding a sector doesn't look through every line, each
+// line with a sector in it is also in a hash table by its disk and sector, in the same pages;
+// only making way looks through them all, for the one used longest ago. The cache's lock is
+// held while a sector is read or written, since the filesystems only reach a disk one change
+// at a time anyway, so it is a sleeping lock: a disk's driver may wait for its interrupt.
+const KIB: usize = 1024;
+// Ends a chain of lines in the hash table
+const NO_LINE: usize = usize::MAX;
+
+// What the cache knows about one of its sectors, which is sector `sector` of the disk with id
+// `device`
+#[derive(Clone, Copy)]
+struct Line {
+    device: usize,
+    sector: u64,
+    dirty: bool,
+    // When it was last used, by the cache's clock; 0 for a line with no sector in it yet
+    used: u64,
+    // The next line in the same hash bucket
+    next: usize,
+}
+
+struct Lines {
+    lines: *mut Line,
+    // The first line in each bucket, a power of two of them, at least as many as lines
+    buckets: *mut usize,
+    // The lines' sectors, one after another
+    data: *mut u8,
+    capacity: usize,
+    write_back: bool,
+    // Goes up by one on every use
+    clock: u64,
+}
+
+// Only reached behind the cache's lock
+unsafe impl Send for Lines {}
+
+impl Lines {
+    fn lines(&mut self) -> &mut [Line] {
+        match self.capacity {
+            0 => &mut [],
+            capacity => unsafe { slice::from_raw_parts_mut(self.lines, capacity) },
+        }
+    }
+
+    fn buckets(&mut self) -> &mut [usize] {
+        match self.capacity {
+            0 => &mut [],
+            capacity => unsafe { slice::from_raw_parts_mut(self.buckets, bucket_count(capacity)) },
+        }
+    }
+
+    fn data(&mut self, index: usize) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.data.add(index * BLOCK_SIZE), BLOCK_SIZE) }
+    }
+
+    fn bucket(&self, device: usize, sector: u64) -> usize {
+        let hash = (sector ^ (device as u64).rotate_right(16)).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        (hash >> 32) as usize & (bucket_count(self.capacity) - 1)
+    }
+
+    fn find(&mut self, device: usize, sector: u64) -> Option<usize> {
+        let bucket = self.bucket(device, sector);
+        let mut index = self.buckets()[bucket];
+        while index != NO_LINE {
+            let line = self.lines()[index];
+            if line.device == device && line.sector == sector {
+                return Some(index);
+            }
+            index = line.next;
+        }
+        None
+    }
+
+    // Puts line `index` in the hash table, by the sector it now has
+    fn link(&mut self, index: usize) {
+        let line = self.lines()[index];
+        let bucket = self.bucket(line.device, line.sector);
+        let first = self.buckets()[bucket];
+        self.lines()[index].next = first;
+        self.buckets()[bucket] = index;
+    }
+
+    // Takes line `index` out of the hash table, before its sector makes way
+    fn unlink(&mut self, index: usize) {
+        let line = self.lines()[index];
+        let bucket = self.bucket(line.device, line.sector);
+        if self.buckets()[bucket] == index {
+            self.buckets()[bucket] = line.next;
+            return;
+        }
+        let mut previous = self.buckets()[bucket];
+        while previous != NO_LINE {
+            let next = self.lines()[previous].next;
+            if next == index {
+                self.lines()[previous].next = line.next;
+                return;
+            }
+            previous = next;
+        }
+    }
+
+    // Returns the sector, as the latest use of it
+    fn get(&mut self, device: usize, sector: u64) -> Option<&mut [u8]> {
+        let index = self.find(device, sector)?;
+        self.clock += 1;
+        let clock = self.clock;
+        self.lines()[index].used = clock;
+        Some(self.data(index))
+    }
+
+    // Caches `data` as sector `sector` of the disk with id `device`, over what was cached for it,
+    // making way for it if the cache is full
+    fn insert(&mut self, device: usize, sector: u64, data: &[u8], dirty: bool) -> Result<(), Error> {
+        self.clock += 1;
+        let clock = self.clock;
+        let index = match self.find(device, sector) {
+            Some(index) => index,
+            None => {
+                // An empty line was used longest ago of all
+                let (index, oldest) = self.lines().iter().enumerate().min_by_key(|(_, line)| line.used).map(|(index, line)| (index, *line)).unwrap();
+                if oldest.used != 0 {
+                    if oldest.dirty {
+                        let disk = DISKS.lock()[oldest.device].unwrap();
+                        disk.write(oldest.sector, self.data(index))?;
+                    }
+                    self.unlink(index);
+                }
+                self.lines()[index] = Line { device, sector, dirty: false, used: 0, next: NO_LINE };
+                self.link(index);
+                index
+            },
+        };
+        self.data(index).copy_from_slice(data);
+        let line = &mut self.lines()[index];
+        line.dirty |= dirty;
+        line.used = clock;
+        Ok(())
+    }
+
+    // Writes the dirty sectors of `disk`, whose id is `device`, to it
+    fn write_back(&mut self, device: usize, disk: &dyn BlockDevice) -> Result<(), Error> {
+        for index in 0..self.capacity {
+            let line = self.lines()[index];
+            if line.used != 0 && line.dirty && line.device == device {
+                disk.write(line.sector, self.data(index))?;
+                self.lines()[index].dirty = false;
+            }
+        }
+        Ok(())
+    }
+}
+
+static CACHE: SleepLock<Lines> = SleepLock::new(Lines { lines: ptr::null_mut(), buckets: ptr::null_mut(), data: ptr::null_mut(), capacity: 0, write_back: false, clock: 0 });
+// The disks behind the cache, whose ids are their places here
+static DISKS: SpinLock<[Option<&'static dyn BlockDevice>; block::MAX_DEVICES]> = SpinLock::new([None; block::MAX_DEVICES]);
+
+// A disk, behind the cache
+struct Cached {
+    id: usize,
+    disk: &'static dyn BlockDevice,
+}
+
+impl BlockDevice for Cached {
+    fn name(&self) -> &str {
+        self.disk.name()
+    }
+
+    fn sector_size(&self) -> usize {
+        self.disk.sector_size()
+    }
+
+    fn blocks(&self) -> u64 {
+        self.disk.blocks()
+    }
+
+    fn read(&self, block: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        block::check(self.disk.blocks(), block, buffer.len())?;
+        let mut cache = CACHE.lock();
+        let count = buffer.len() / BLOCK_SIZE;
+        let mut index = 0;
+        while index < count {
+            if let Some(sector) = cache.get(self.id, block + index as u64) {
+                buffer[index * BLOCK_SIZE..][..BLOCK_SIZE].copy_from_slice(sector);
+                index += 1;
+                continue;
+            }
+            // The sectors from here that aren't cached are read in one go
+            let missing = (index..count).take_while(|&next| cache.find(self.id, block + next as u64).is_none()).count();
+            let run = &mut buffer[index * BLOCK_SIZE..(index + missing) * BLOCK_SIZE];
+            self.disk.read(block + index as u64, run)?;
+            for (offset, sector) in run.chunks_exact(BLOCK_SIZE).enumerate() {
+                cache.insert(self.id, block + (index + offset) as u64, sector, false)?;
+            }
+            index += missing;
+        }
+        Ok(())
+    }
+
+    fn write(&self, block: u64, bytes: &[u8]) -> Result<(), Error> {
+        block::check(self.disk.blocks(), block, bytes.len())?;
+        let mut cache = CACHE.lock();
+        let write_back = cache.write_back;
+        if !write_back {
+            self.disk.write(block, bytes)?;
+        }
+        for (index, sector) in bytes.chunks_exact(BLOCK_SIZE).enumerate() {
+            cache.insert(self.id, block + index as u64, sector, write_back)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        CACHE.lock().write_back(self.id, self.disk)?;
+        self.disk.flush()
+    }
+}
+
+/// Sets the cache's size and mode from the configuration and maps its memory. Called once
+/// during boot, after `config::init` and `memory::init` and before anything is mounted.
+pub fn init() {
+    let settings = config::get().cache;
+    let capacity = settings.kib * KIB / BLOCK_SIZE;
+    if capacity == 0 {
+        writeln!(serial(), "Block cache: off").unwrap();
+        return;
+    }
+    // The lines, then the buckets, in one range of pages, and the sectors in another
+    let pages = |bytes: usize| bytes.div_ceil(PAGE_SIZE as usize) as u64;
+    let bucket_count = bucket_count(capacity);
+    let table_pages = pages(capacity * mem::size_of::<Line>() + bucket_count * mem::size_of::<usize>());
+    let Some(table) = memory::alloc_guarded_pages(table_pages) else {
+        writeln!(serial(), "Block cache: no memory for it, off").unwrap();
+        return;
+    };
+    let Some(data) = memory::alloc_guarded_pages(pages(capacity * BLOCK_SIZE)) else {
+        memory::free_pages(table, table_pages);
+        writeln!(serial(), "Block cache: no memory for it, off").unwrap();
+        return;
+    };
+    let lines: *mut Line = table.as_mut_ptr();
+    let buckets: *mut usize = unsafe { lines.add(capacity) }.cast();
+    for index in 0..capacity {
+        unsafe { lines.add(index).write(Line { device: 0, sector: 0, dirty: false, used: 0, next: NO_LINE }) };
+    }
+    for bucket in 0..bucket_count {
+        unsafe { buckets.add(bucket).write(NO_LINE) };
+    }
+    let mut cache = CACHE.lock();
+    *cache = Lines { lines, buckets, data: data.as_mut_ptr(), capacity, write_back: settings.write_back, clock: 0 };
+    let mode = if settings.write_back { "write-back" } else { "write-through" };
+    writeln!(serial(), "Block cache: {} KiB, {mode}", settings.kib).unwrap();
+}
+
+// How many hash buckets a cache of `capacity` lines has
+fn bucket_count(capacity: usize) -> usize {
+    capacity.next_power_of_two()
+}
+
+/// Returns `disk` behind the cache, to mount, or `disk` itself if the cache is turned off.
+pub fn wrap(disk: &'static dyn BlockDevice) -> &'static dyn BlockDevice {
+    // The cache's lines are whole sectors of this size
+    if CACHE.lock().capacity == 0 || disk.sector_size() != BLOCK_SIZE {
+        return disk;
+    }
+    let mut disks = DISKS.lock();
+    // A disk mounted again after failing to mount keeps its id
+    let same = |other: &Option<&dyn BlockDevice>| other.is_some_and(|other| other.name() == disk.name());
+    let Some(id) = disks.iter().position(same).or_else(|| disks.iter().position(Option::is_none)) else {
+        return disk;
+    };
+    disks[id] = Some(disk);
+    // A disk is only mounted once, so this stays allocated for as long as it could be used
+    Box::leak(Box::new(Cached { id, disk }))
+}
+
+/// Writes every dirty sector back to its disk and flushes every disk behind the cache, such as
+/// before shutting down. Returns the first error, having tried every disk.
+pub fn flush_all() -> Result<(), Error> {
+    let disks = *DISKS.lock();
+    let mut cache = CACHE.lock();
+    let mut result = Ok(());
+    for (id, disk) in disks.into_iter().enumerate().filter_map(|(id, disk)| Some((id, disk?))) {
+        let flushed = cache.write_back(id, disk).and_then(|_| disk.flush());
+        result = result.and(flushed);
+    }
+    result
+}
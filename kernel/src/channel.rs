@@ -0,0 +1,116 @@
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use crate::task::WaitQueue;
+
+// Bounded multi-producer, single-consumer channels that never lock, so interrupt handlers can
+// send on them. Positions count up forever and map onto the slots round and round; each slot
+// has a stamp saying which lap it is on and whether it holds a value: 2 * lap while it waits
+// for that lap's value, and 2 * lap + 1 once the value is in. A sender claims a position by
+// bumping `tail`, so an interrupt handler sending in the middle of another send just takes the
+// next position, and the receiver waits for the earlier one to be filled in.
+
+/// A channel holding up to `N` values in flight, meant to be a static. `sender` can be called
+/// any number of times, `receiver` only once.
+pub struct Channel<T, const N: usize> {
+    slots: [Slot<T>; N],
+    // Next position to send to and to receive from
+    tail: AtomicUsize,
+    head: AtomicUsize,
+    receiver_taken: AtomicBool,
+    // The receiving task blocks here while the channel is empty
+    waiting: WaitQueue,
+}
+
+struct Slot<T> {
+    stamp: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+// Every value is written by exactly one sender and read by the one receiver, as the stamps say
+unsafe impl<T: Send, const N: usize> Sync for Channel<T, N> {}
+
+/// Sends values on a channel. Safe to use from interrupt handlers.
+#[derive(Clone, Copy)]
+pub struct Sender<'a, T, const N: usize> {
+    channel: &'a Channel<T, N>,
+}
+
+/// Takes values off a channel, in the order they were sent.
+pub struct Receiver<'a, T, const N: usize> {
+    channel: &'a Channel<T, N>,
+}
+
+impl<T, const N: usize> Channel<T, N> {
+    pub const fn new() -> Self {
+        Channel {
+            slots: [const { Slot { stamp: AtomicUsize::new(0), value: UnsafeCell::new(MaybeUninit::uninit()) } }; N],
+            tail: AtomicUsize::new(0),
+            head: AtomicUsize::new(0),
+            receiver_taken: AtomicBool::new(false),
+            waiting: WaitQueue::new(),
+        }
+    }
+
+    pub fn sender(&self) -> Sender<'_, T, N> {
+        Sender { channel: self }
+    }
+
+    /// Returns the channel's receiving end, or None if it has been taken already.
+    pub fn receiver(&self) -> Option<Receiver<'_, T, N>> {
+        (!self.receiver_taken.swap(true, Ordering::SeqCst)).then_some(Receiver { channel: self })
+    }
+}
+
+impl<T, const N: usize> Sender<'_, T, N> {
+    /// Sends `value` and wakes the receiver. Gives the value back if the channel is full.
+    pub fn send(&self, value: T) -> Result<(), T> {
+        let channel = self.channel;
+        loop {
+            let position = channel.tail.load(Ordering::SeqCst);
+            let slot = &channel.slots[position % N];
+            let lap = position / N;
+            let stamp = slot.stamp.load(Ordering::Acquire);
+            if stamp == 2 * lap {
+                if channel.tail.compare_exchange(position, position + 1, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+                    unsafe { (*slot.value.get()).write(value) };
+                    slot.stamp.store(2 * lap + 1, Ordering::Release);
+                    channel.waiting.notify();
+                    return Ok(());
+                }
+            } else if stamp < 2 * lap {
+                // The value sent a lap earlier hasn't been received yet
+                return Err(value);
+            }
+            // Another sender got this position first
+        }
+    }
+}
+
+impl<T, const N: usize> Receiver<'_, T, N> {
+    /// Takes the oldest value, if there is one that has finished being sent.
+    pub fn try_recv(&mut self) -> Option<T> {
+        let channel = self.channel;
+        let position = channel.head.load(Ordering::SeqCst);
+        let slot = &channel.slots[position % N];
+        let lap = position / N;
+        if slot.stamp.load(Ordering::Acquire) != 2 * lap + 1 {
+            return None;
+        }
+        let value = unsafe { (*slot.value.get()).assume_init_read() };
+        slot.stamp.store(2 * (lap + 1), Ordering::Release);
+        channel.head.store(position + 1, Ordering::SeqCst);
+        Some(value)
+    }
+
+    /// Takes the oldest value, blocking the task until there is one.
+    pub fn recv(&mut self) -> T {
+        let channel = self.channel;
+        let mut value = None;
+        channel.waiting.wait_until(|| {
+            value = self.try_recv();
+            value.is_some()
+        });
+        value.unwrap()
+    }
+}
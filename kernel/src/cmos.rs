@@ -0,0 +1,61 @@
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+// CMOS RAM behind the real-time clock: 128 bytes that survive a reboot as long as the
+// board's battery (or, under QEMU, the running VM) lasts. Registers 0x00-0x0D belong to the
+// RTC; most of the rest is claimed by the firmware, but a few ranges are left unused.
+const INDEX: u16 = 0x70;
+const DATA: u16 = 0x71;
+
+// Setting the top bit of the index masks NMIs, which must not fire between selecting
+// a register and accessing it
+const NMI_DISABLE: u8 = 0x80;
+
+/// Reads the CMOS register at `index` (0-127).
+pub fn read(index: u8) -> u8 {
+    interrupts::without_interrupts(|| unsafe {
+        Port::<u8>::new(INDEX).write(NMI_DISABLE | index);
+        let value = Port::<u8>::new(DATA).read();
+        Port::<u8>::new(INDEX).write(0);
+        value
+    })
+}
+
+/// Writes `value` to the CMOS register at `index` (0-127).
+pub fn write(index: u8, value: u8) {
+    interrupts::without_interrupts(|| unsafe {
+        Port::<u8>::new(INDEX).write(NMI_DISABLE | index);
+        Port::<u8>::new(DATA).write(value);
+        Port::<u8>::new(INDEX).write(0);
+    })
+}
+
+// Blocks of saved data are laid out as a magic byte that marks the area as ours, the data
+// itself and a checksum over both
+
+fn checksum(magic: u8, data: &[u8]) -> u8 {
+    // Fletcher-style: also catches bytes that were swapped or shifted
+    let (sum, weighted) = core::iter::once(&magic).chain(data).fold((0u8, 0u8), |(sum, weighted), byte| {
+        let sum = sum.wrapping_add(*byte);
+        (sum, weighted.wrapping_add(sum))
+    });
+    sum ^ weighted.rotate_left(4)
+}
+
+/// Fills `data` from the block saved by `write_block` at register `first`. Returns false if
+/// there is no block with this `magic` byte there or it has been corrupted.
+pub fn read_block(first: u8, magic: u8, data: &mut [u8]) -> bool {
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte = read(first + 1 + i as u8);
+    }
+    read(first) == magic && read(first + 1 + data.len() as u8) == checksum(magic, data)
+}
+
+/// Saves `data` at register `first`, taking up two bytes more than `data` itself.
+pub fn write_block(first: u8, magic: u8, data: &[u8]) {
+    write(first, magic);
+    for (i, byte) in data.iter().enumerate() {
+        write(first + 1 + i as u8, *byte);
+    }
+    write(first + 1 + data.len() as u8, checksum(magic, data));
+}
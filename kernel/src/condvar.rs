@@ -0,0 +1,38 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use kernel::sync::{SpinLock, SpinLockGuard};
+use crate::task::WaitQueue;
+
+/// A condition variable: tasks wait for the data behind a `SpinLock` to reach some state, and
+/// whoever changes it calls `notify_all`. Notifying is safe in interrupt handlers.
+pub struct Condvar {
+    // Counts notifications, so a task can tell one arrived after it last looked at the data
+    notifications: AtomicU64,
+    waiting: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Condvar { notifications: AtomicU64::new(0), waiting: WaitQueue::new() }
+    }
+
+    /// Locks `lock` with interrupts disabled and returns the guard once `condition` holds for
+    /// the data, blocking the task in between. The lock isn't held while the task is blocked.
+    pub fn wait_until<'a, T>(&self, lock: &'a SpinLock<T>, mut condition: impl FnMut(&mut T) -> bool) -> SpinLockGuard<'a, T> {
+        loop {
+            let mut guard = lock.lock_irq();
+            if condition(&mut guard) {
+                return guard;
+            }
+            // Read before unlocking: a change made after that comes with a later notification
+            let seen = self.notifications.load(Ordering::SeqCst);
+            drop(guard);
+            self.waiting.wait_until(|| self.notifications.load(Ordering::SeqCst) != seen);
+        }
+    }
+
+    /// Wakes every task waiting on the condition variable to check its condition again.
+    pub fn notify_all(&self) {
+        self.notifications.fetch_add(1, Ordering::SeqCst);
+        self.waiting.notify();
+    }
+}
@@ -0,0 +1,284 @@
+use core::fmt::Write;
+use core::net::Ipv4Addr;
+use kernel::serial;
+use kernel::sync::SpinLock;
+use crate::settings::{self, Setting};
+use crate::{game, initrd};
+
+// The boot configuration: kernel.cfg in the initrd, read once at boot, in a small part of TOML.
+// Each line is a `key = value` pair, a `[section]` heading that the keys after it belong to,
+// or blank; `#` starts a comment. A value is a whole number, `true` or `false`, or text, in
+// double quotes or not. Anything it leaves out keeps its built-in value, as does anything it
+// gets wrong, which is reported on the serial port, so a bad file never stops the boot.
+const PATH: &str = "kernel.cfg";
+const MAX_TIMER_HZ: u64 = 10_000;
+// The serial port's clock, which its speed is a divisor of
+const SERIAL_CLOCK: u64 = 115_200;
+const MAX_CACHE_KIB: u64 = 64 * 1024;
+const MAX_RAMDISK_KIB: u64 = 256 * 1024;
+
+/// How much goes to the serial port, from least to most.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl LogLevel {
+    const ALL: [LogLevel; 4] = [LogLevel::Error, LogLevel::Warn, LogLevel::Info, LogLevel::Debug];
+
+    fn name(self) -> &'static str {
+        match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+        }
+    }
+}
+
+/// Pong's rules.
+#[derive(Debug, Clone, Copy)]
+pub struct Pong {
+    /// The score that wins a match.
+    pub winning_score: i32,
+    /// Pixels a player's paddle moves per tick.
+    pub paddle_speed: i32,
+    /// One in this many ticks spawns a power-up; 0 for none.
+    pub power_up_chance: u32,
+    /// How long a collected power-up lasts.
+    pub power_up_ms: u64,
+}
+
+/// The block cache's settings.
+#[derive(Debug, Clone, Copy)]
+pub struct Cache {
+    /// How much memory the cached sectors may take; 0 turns the cache off.
+    pub kib: usize,
+    /// Whether writes stay in the cache until the disk is flushed, or go through at once.
+    pub write_back: bool,
+}
+
+/// The network's settings.
+#[derive(Debug, Clone, Copy)]
+pub struct Net {
+    /// Whether the first network card asks DHCP for its address at boot.
+    pub dhcp: bool,
+    /// The first network card's IPv4 address, if DHCP is off or no server answers, or None to
+    /// leave it without one.
+    pub address: Option<Ipv4Addr>,
+    /// The mask of its network.
+    pub netmask: Ipv4Addr,
+    /// The router to other networks, if there is one.
+    pub gateway: Option<Ipv4Addr>,
+    /// The DNS server, if DHCP doesn't give one.
+    pub dns: Option<Ipv4Addr>,
+    /// The TCP port the remote console listens on, or 0, the default, for none.
+    pub console_port: u16,
+}
+
+/// Everything kernel.cfg can set.
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+    /// Timer interrupts per second, or None to leave the timer as it was set up.
+    pub timer_hz: Option<u32>,
+    /// How long a task may run before the timer interrupt lets the next one have the CPU.
+    pub time_slice_ms: u64,
+    /// The index in `game::games()` of the game started at boot, or None for the menu.
+    pub default_game: Option<usize>,
+    pub log_level: LogLevel,
+    /// The serial port's speed in bits per second, or None to leave it as it was set up.
+    pub serial_baud: Option<u32>,
+    pub pong: Pong,
+    pub cache: Cache,
+    /// How big a RAM disk to make, or 0 for none.
+    pub ramdisk_kib: u64,
+    pub net: Net,
+}
+
+impl Config {
+    const DEFAULT: Config = Config {
+        timer_hz: None,
+        time_slice_ms: 10,
+        default_game: None,
+        log_level: LogLevel::Debug,
+        serial_baud: None,
+        pong: Pong { winning_score: 5, paddle_speed: 5, power_up_chance: 150, power_up_ms: 10_000 },
+        cache: Cache { kib: 256, write_back: true },
+        ramdisk_kib: 0,
+        net: Net { dhcp: true, address: None, netmask: Ipv4Addr::new(255, 255, 255, 0), gateway: None, dns: None, console_port: 0 },
+    };
+}
+
+static CONFIG: SpinLock<Config> = SpinLock::new(Config::DEFAULT);
+
+// A value, as it was written
+#[derive(Clone, Copy)]
+enum Value<'a> {
+    Number(u64),
+    Boolean(bool),
+    Text(&'a str),
+}
+
+/// Reads kernel.cfg from the initrd, if it has one, and sets the serial port's speed and the
+/// default theme and input from it. Called once during boot, after `initrd::init` and before
+/// `settings::init`, which only uses those defaults if nothing was saved.
+pub fn init() {
+    let Some(file) = initrd::open(PATH) else {
+        writeln!(serial(), "No {PATH}, using the built-in configuration").unwrap();
+        return;
+    };
+    let Ok(text) = core::str::from_utf8(file.data) else {
+        writeln!(serial(), "{PATH} isn't text, using the built-in configuration").unwrap();
+        return;
+    };
+    let mut config = Config::DEFAULT;
+    let mut section = "";
+    for (number, line) in text.lines().enumerate().map(|(index, line)| (index + 1, line.trim())) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let result = if let Some(heading) = line.strip_prefix('[') {
+            match heading.split_once(']') {
+                Some((name, rest)) if is_blank(rest) => {
+                    section = name.trim();
+                    Ok(())
+                },
+                _ => Err("broken section heading"),
+            }
+        } else if let Some((key, value)) = line.split_once('=') {
+            value_of(value).ok_or("broken value").and_then(|value| set(&mut config, section, key.trim(), value))
+        } else {
+            Err("expected key = value")
+        };
+        if let Err(error) = result {
+            writeln!(serial(), "{PATH} line {number}: {error}, ignored").unwrap();
+        }
+    }
+    if let Some(baud) = config.serial_baud {
+        kernel::set_baud_rate(baud);
+    }
+    *CONFIG.lock_irq() = config;
+    writeln!(serial(), "Read {PATH}: {config:?}").unwrap();
+}
+
+/// Returns the configuration.
+pub fn get() -> Config {
+    *CONFIG.lock_irq()
+}
+
+/// Returns true if messages of `level` should go to the serial port.
+pub fn logs(level: LogLevel) -> bool {
+    level <= get().log_level
+}
+
+// True for the end of a line: nothing, or a comment
+fn is_blank(rest: &str) -> bool {
+    let rest = rest.trim_start();
+    rest.is_empty() || rest.starts_with('#')
+}
+
+// The value at the start of `text`, followed by nothing but a comment
+fn value_of(text: &str) -> Option<Value<'_>> {
+    let text = text.trim_start();
+    if let Some(quoted) = text.strip_prefix('"') {
+        let (inside, rest) = quoted.split_once('"')?;
+        return is_blank(rest).then_some(Value::Text(inside));
+    }
+    let word = text.split('#').next().unwrap().trim();
+    let value = match word {
+        "" => return None,
+        "true" => Value::Boolean(true),
+        "false" => Value::Boolean(false),
+        // Digits, which as in TOML may be grouped with underscores
+        _ if word.starts_with(|c: char| c.is_ascii_digit()) => {
+            let number = word.chars().filter(|&c| c != '_').try_fold(0u64, |number, c| {
+                number.checked_mul(10)?.checked_add(u64::from(c.to_digit(10)?))
+            });
+            Value::Number(number?)
+        },
+        _ => Value::Text(word),
+    };
+    Some(value)
+}
+
+fn set(config: &mut Config, section: &str, key: &str, value: Value) -> Result<(), &'static str> {
+    match (section, key) {
+        ("timer", "hz") => config.timer_hz = Some(number(value, 1, MAX_TIMER_HZ)? as u32),
+        ("timer", "time_slice_ms") => config.time_slice_ms = number(value, 1, 1000)?,
+        ("game", "default") => config.default_game = match text(value)? {
+            "menu" => None,
+            name => Some(game_index(name).ok_or("no such game")?),
+        },
+        ("log", "level") => {
+            let name = text(value)?;
+            config.log_level = LogLevel::ALL.into_iter().find(|level| level.name() == name).ok_or("no such level")?;
+        },
+        ("serial", "baud") => {
+            let baud = number(value, 1, SERIAL_CLOCK)?;
+            if !SERIAL_CLOCK.is_multiple_of(baud) {
+                return Err("not a speed the serial port can run at");
+            }
+            config.serial_baud = Some(baud as u32);
+        },
+        ("serial", "input") => {
+            let input = match value {
+                Value::Boolean(input) => input,
+                _ => return Err("expected true or false"),
+            };
+            settings::set_default(Setting::Input, u8::from(input));
+        },
+        ("", "theme") => {
+            let option = settings::find_option(Setting::Theme, text(value)?).ok_or("no such theme")?;
+            settings::set_default(Setting::Theme, option);
+        },
+        ("pong", "winning_score") => config.pong.winning_score = number(value, 1, 99)? as i32,
+        ("pong", "paddle_speed") => config.pong.paddle_speed = number(value, 1, 50)? as i32,
+        ("pong", "power_up_chance") => config.pong.power_up_chance = number(value, 0, u64::from(u32::MAX))? as u32,
+        ("pong", "power_up_ms") => config.pong.power_up_ms = number(value, 1, 600_000)?,
+        ("cache", "kib") => config.cache.kib = number(value, 0, MAX_CACHE_KIB)? as usize,
+        ("cache", "mode") => config.cache.write_back = match text(value)? {
+            "write-back" => true,
+            "write-through" => false,
+            _ => return Err("expected write-back or write-through"),
+        },
+        ("ramdisk", "kib") => config.ramdisk_kib = number(value, 0, MAX_RAMDISK_KIB)?,
+        ("net", "dhcp") => config.net.dhcp = match value {
+            Value::Boolean(dhcp) => dhcp,
+            _ => return Err("expected true or false"),
+        },
+        ("net", "address") => config.net.address = Some(address(value)?),
+        ("net", "netmask") => config.net.netmask = address(value)?,
+        ("net", "gateway") => config.net.gateway = Some(address(value)?),
+        ("net", "dns") => config.net.dns = Some(address(value)?),
+        ("net", "console_port") => config.net.console_port = number(value, 0, u64::from(u16::MAX))? as u16,
+        _ => return Err("unknown key"),
+    }
+    Ok(())
+}
+
+fn number(value: Value, min: u64, max: u64) -> Result<u64, &'static str> {
+    match value {
+        Value::Number(number) if (min..=max).contains(&number) => Ok(number),
+        Value::Number(_) => Err("number out of range"),
+        _ => Err("expected a number"),
+    }
+}
+
+fn text(value: Value<'_>) -> Result<&str, &'static str> {
+    match value {
+        Value::Text(text) => Ok(text),
+        _ => Err("expected text"),
+    }
+}
+
+fn address(value: Value) -> Result<Ipv4Addr, &'static str> {
+    text(value)?.parse().map_err(|_| "expected an IPv4 address, in quotes")
+}
+
+// Where the game called `name`, in any case, is in `game::games()`
+fn game_index(name: &str) -> Option<usize> {
+    game::games().iter().position(|game| game.lock().name().eq_ignore_ascii_case(name))
+}
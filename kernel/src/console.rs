@@ -0,0 +1,87 @@
+use kernel::serial;
+use kernel::sync::SpinLock;
+use pc_keyboard::{DecodedKey, KeyCode};
+use crate::channel::{Channel, Receiver};
+use crate::task::WaitQueue;
+
+// The console of user programs, which processes have open as handles 0 and 1 unless they were
+// started with others. What programs write goes to the serial port, and they read the
+// characters typed on the keyboard, UTF-8 encoded and without echo, with the arrow keys as
+// the escape sequences terminals send for them.
+const ESCAPE: u8 = 0x1b;
+
+static KEYS: Channel<u8, 64> = Channel::new();
+// The receiver stays here, so a program killed while it reads doesn't take it along
+static RECEIVER: SpinLock<Option<Receiver<'static, u8, 64>>> = SpinLock::new(None);
+static TYPED: WaitQueue = WaitQueue::new();
+
+/// Sets up the keyboard input for user programs. Called once during boot.
+pub fn init() {
+    *RECEIVER.lock_irq() = KEYS.receiver();
+}
+
+/// Passes a key pressed on the keyboard on to user programs. Called from the keyboard
+/// interrupt handler.
+pub fn key(key: DecodedKey) {
+    let mut bytes = [0; 4];
+    let sequence = match key {
+        DecodedKey::Unicode(character) => character.encode_utf8(&mut bytes).as_bytes(),
+        DecodedKey::RawKey(code) => {
+            let Some(letter) = arrow_letter(code) else {
+                return;
+            };
+            bytes[..3].copy_from_slice(&[ESCAPE, b'[', letter]);
+            &bytes[..3]
+        },
+    };
+    for &byte in sequence {
+        // Nobody may be reading, so whatever doesn't fit is dropped
+        let _ = KEYS.sender().send(byte);
+    }
+    TYPED.notify();
+}
+
+fn arrow_letter(code: KeyCode) -> Option<u8> {
+    match code {
+        KeyCode::ArrowUp => Some(b'A'),
+        KeyCode::ArrowDown => Some(b'B'),
+        KeyCode::ArrowRight => Some(b'C'),
+        KeyCode::ArrowLeft => Some(b'D'),
+        _ => None,
+    }
+}
+
+/// Drops what was typed before, so a program only reads what is typed while it runs.
+pub fn discard_input() {
+    if let Some(keys) = RECEIVER.lock_irq().as_mut() {
+        while keys.try_recv().is_some() {}
+    }
+}
+
+/// Prints `bytes` on the serial port, and returns how many there were.
+pub fn write(bytes: &[u8]) -> usize {
+    let mut port = serial();
+    for &byte in bytes {
+        port.send(byte);
+    }
+    bytes.len()
+}
+
+/// Reads what has been typed into `buffer`, first waiting for something to be typed if `wait`
+/// is set, and returns how many bytes were read.
+pub fn read(buffer: &mut [u8], wait: bool) -> usize {
+    let mut read = 0;
+    TYPED.wait_until(|| {
+        let mut receiver = RECEIVER.lock_irq();
+        let keys = receiver.as_mut().unwrap();
+        while read < buffer.len() {
+            let Some(byte) = keys.try_recv() else {
+                break;
+            };
+            buffer[read] = byte;
+            read += 1;
+        }
+        read > 0 || !wait || buffer.is_empty()
+    });
+    read
+}
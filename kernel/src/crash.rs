@@ -0,0 +1,66 @@
+use core::arch::asm;
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use kernel::serial;
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::registers::rflags;
+use crate::fs::{self, MAX_PATH};
+use crate::time;
+use crate::ui::TextBuffer;
+
+// The crash log: on a panic, what it was about, the registers and the last of the serial log
+// are added to crash.log on the FAT volume, so a crash on a machine with no serial cable can
+// still be looked into after a reboot. This runs in the panic handler, on whatever was going
+// on, so it only writes if no filesystem operation was under way, whose locks might be held
+// or whose changes might be half made; and it allocates nothing, in case it was the heap.
+const FILE_NAME: &str = "crash.log";
+// Room for the heading, message and registers; what doesn't fit is cut off
+const MAX_REPORT: usize = 2048;
+// As much of the serial log as the kernel keeps
+const MAX_TAIL: usize = 4096;
+
+/// Adds a report of the panic to crash.log on the FAT volume, if it is safe to. Set as the
+/// panic handler, so it runs once the panic has been printed on the serial port.
+pub fn record(info: &PanicInfo) {
+    // Before anything below adds to the serial log
+    let mut tail = [0; MAX_TAIL];
+    let tail_length = kernel::log_tail(&mut tail);
+
+    if fs::in_use() {
+        writeln!(serial(), "Not writing {FILE_NAME}: a filesystem operation was under way").unwrap();
+        return;
+    }
+    let Some(directory) = fs::mounts().into_iter().flatten().find(|&(_, name)| name == "fat32").map(|(path, _)| path) else {
+        writeln!(serial(), "Not writing {FILE_NAME}: no FAT volume is mounted").unwrap();
+        return;
+    };
+    let mut path = TextBuffer::<MAX_PATH>::new();
+    write!(path, "{directory}/{FILE_NAME}").unwrap();
+
+    let mut report = TextBuffer::<MAX_REPORT>::new();
+    // A full buffer only cuts the report short
+    let _ = write_report(&mut report, info);
+    let written = fs::File::append(path.as_str()).and_then(|mut file| {
+        file.write(report.as_str().as_bytes())?;
+        file.write(&tail[..tail_length])?;
+        file.write(b"\n\n")
+    });
+    match written {
+        Ok(_) => writeln!(serial(), "Crash report added to {}", path.as_str()).unwrap(),
+        Err(error) => writeln!(serial(), "Couldn't write {}: {error:?}", path.as_str()).unwrap(),
+    }
+}
+
+fn write_report(report: &mut impl Write, info: &PanicInfo) -> core::fmt::Result {
+    let (rsp, rbp): (u64, u64);
+    unsafe {
+        asm!("mov {}, rsp", "mov {}, rbp", out(reg) rsp, out(reg) rbp, options(nomem, nostack, preserves_flags));
+    }
+    let (cr3, _) = Cr3::read_raw();
+    writeln!(report, "==== Panic at {} ms ====", time::uptime_ms())?;
+    writeln!(report, "{info}")?;
+    writeln!(report, "rsp {rsp:#018x}  rbp {rbp:#018x}  rflags {:#010x}", rflags::read_raw())?;
+    writeln!(report, "cr0 {:#010x}  cr2 {:#018x}", Cr0::read_raw(), Cr2::read_raw())?;
+    writeln!(report, "cr3 {:#018x}  cr4 {:#010x}", cr3.start_address().as_u64(), Cr4::read_raw())?;
+    writeln!(report, "---- Last of the serial log ----")
+}
@@ -0,0 +1,124 @@
+use core::fmt::Write;
+use crate::fs::{DirEntry, Error, Filesystem, Kind, Metadata};
+use crate::ui::TextBuffer;
+use crate::{console, display, rand};
+
+// The kernel's devices as files, mounted at /dev, so the shell and user programs use them with
+// the same open, read and write as anything else:
+//
+// - serial0 is the serial console: what is written goes out on the serial port, and reads
+//   wait for something to be typed, like the console handles of a process.
+// - fb0 is the screen, 4 bytes a pixel, blue, green, red and one unused, a row at a time from
+//   the top left, as the draw system call takes them. It is read and written through
+//   `display`, the same way as the draw system call, so writing to it from a process gives
+//   that process the screen.
+// - kbd is what is typed on the keyboard, UTF-8 encoded with the arrow keys as escape
+//   sequences; reads wait for it. It can't be written.
+// - random gives random bytes, as many as are read. It can't be written.
+// - null reads as empty and takes whatever is written to it.
+//
+// Devices have no size but fb0's. Opening one to write it over empties nothing, so a device can
+// be written to the way any file is.
+const ROOT: u64 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Device {
+    Serial,
+    Framebuffer,
+    Keyboard,
+    Random,
+    Null,
+}
+
+// The devices in the order they are listed; a device's inode is its place here plus one
+const DEVICES: [(&str, Device); 5] = [
+    ("serial0", Device::Serial),
+    ("fb0", Device::Framebuffer),
+    ("kbd", Device::Keyboard),
+    ("random", Device::Random),
+    ("null", Device::Null),
+];
+
+/// The filesystem mounted at /dev.
+pub static FILESYSTEM: Devfs = Devfs;
+
+/// The kernel's devices as a filesystem.
+pub struct Devfs;
+
+// The device `inode` is, or None for the root
+fn device(inode: u64) -> Result<Option<Device>, Error> {
+    match inode {
+        ROOT => Ok(None),
+        _ => DEVICES.get(inode as usize - 1).map(|&(_, device)| Some(device)).ok_or(Error::NotFound),
+    }
+}
+
+// Like `device`, for operations on files
+fn file(inode: u64) -> Result<Device, Error> {
+    device(inode)?.ok_or(Error::IsADirectory)
+}
+
+impl Filesystem for Devfs {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn root(&self) -> u64 {
+        ROOT
+    }
+
+    fn lookup(&self, directory: u64, name: &str) -> Result<u64, Error> {
+        if device(directory)?.is_some() {
+            return Err(Error::NotADirectory);
+        }
+        let index = DEVICES.iter().position(|&(device_name, _)| device_name == name).ok_or(Error::NotFound)?;
+        Ok(index as u64 + 1)
+    }
+
+    fn metadata(&self, inode: u64) -> Result<Metadata, Error> {
+        Ok(match device(inode)? {
+            None => Metadata { kind: Kind::Directory, size: 0 },
+            Some(Device::Framebuffer) => Metadata { kind: Kind::File, size: display::size() },
+            Some(_) => Metadata { kind: Kind::File, size: 0 },
+        })
+    }
+
+    fn read(&self, inode: u64, offset: u64, buffer: &mut [u8]) -> Result<usize, Error> {
+        Ok(match file(inode)? {
+            Device::Serial | Device::Keyboard => console::read(buffer, true),
+            Device::Framebuffer => display::read(offset, buffer),
+            Device::Random => {
+                rand::fill(buffer);
+                buffer.len()
+            },
+            Device::Null => 0,
+        })
+    }
+
+    fn entry(&self, directory: u64, index: usize) -> Result<Option<DirEntry>, Error> {
+        if device(directory)?.is_some() {
+            return Err(Error::NotADirectory);
+        }
+        Ok(DEVICES.get(index).map(|(device_name, _)| {
+            let mut name = TextBuffer::new();
+            write!(name, "{device_name}").unwrap();
+            DirEntry { name, kind: Kind::File }
+        }))
+    }
+
+    fn write(&self, inode: u64, offset: u64, bytes: &[u8]) -> Result<usize, Error> {
+        match file(inode)? {
+            Device::Serial => Ok(console::write(bytes)),
+            Device::Framebuffer => display::write(offset, bytes).ok_or(Error::NoSpace),
+            Device::Null => Ok(bytes.len()),
+            Device::Keyboard | Device::Random => Err(Error::ReadOnly),
+        }
+    }
+
+    fn truncate(&self, inode: u64, _size: u64) -> Result<(), Error> {
+        match file(inode)? {
+            Device::Keyboard | Device::Random => Err(Error::ReadOnly),
+            _ => Ok(()),
+        }
+    }
+}
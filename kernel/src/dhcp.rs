@@ -0,0 +1,260 @@
+use core::fmt::Write;
+use core::net::{Ipv4Addr, SocketAddrV4};
+use kernel::serial;
+use kernel::sync::SpinLock;
+use crate::net::{self, Interface, UdpSocket};
+use crate::{config, rand, task, time};
+
+// DHCP, which asks the network for an address at boot. The first card broadcasts DISCOVER, a
+// server offers it an address with OFFER, the card asks for that address with REQUEST, and the
+// server says it may have it with ACK, along with its network's mask, its gateway and a DNS
+// server, for as long as the lease lasts. Halfway through, it asks for the same address again.
+// The card has no address until ACK comes, so it all goes out broadcast, and asks the server to
+// broadcast back. If no server answers at boot, the card takes the address `[net]` in
+// kernel.cfg gives it instead; `[net] dhcp = false` skips asking. Runs on a task of its own, as
+// it waits for replies the network task brings.
+const CLIENT_PORT: u16 = 68;
+const SERVER_PORT: u16 = 67;
+const BOOT_REQUEST: u8 = 1;
+const BOOT_REPLY: u8 = 2;
+const HARDWARE_ETHERNET: u8 = 1;
+// Asks the server to broadcast its replies, as there is no address to send them to yet
+const FLAG_BROADCAST: u16 = 0x8000;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+// Where the options start, after the fixed fields and the cookie
+const OPTIONS_OFFSET: usize = 240;
+// Messages are padded to the size BOOTP relays expect
+const MESSAGE_SIZE: usize = 300;
+
+const DISCOVER: u8 = 1;
+const OFFER: u8 = 2;
+const REQUEST: u8 = 3;
+const ACK: u8 = 5;
+const NAK: u8 = 6;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS: u8 = 6;
+const OPTION_REQUESTED_ADDRESS: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER: u8 = 54;
+const OPTION_PARAMETERS: u8 = 55;
+const OPTION_END: u8 = 255;
+// A lease that never ends
+const INFINITE: u32 = u32::MAX;
+
+// How long to wait for each reply before sending again, and how many times to send
+const REPLY_TIMEOUT_MS: u64 = 2_000;
+const TRIES: u32 = 3;
+// How long to wait before asking again after a renewal goes unanswered
+const RETRY_MS: u64 = 60_000;
+
+/// An address a DHCP server gave a card.
+#[derive(Debug, Clone, Copy)]
+pub struct Lease {
+    pub interface: &'static str,
+    pub address: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub gateway: Option<Ipv4Addr>,
+    pub dns: Option<Ipv4Addr>,
+    /// The server that gave it.
+    pub server: Ipv4Addr,
+    /// How many seconds it lasts from when it was given, or `u32::MAX` for ever.
+    pub seconds: u32,
+}
+
+// What a reply says
+struct Reply {
+    kind: u8,
+    address: Ipv4Addr,
+    netmask: Option<Ipv4Addr>,
+    gateway: Option<Ipv4Addr>,
+    dns: Option<Ipv4Addr>,
+    server: Option<Ipv4Addr>,
+    seconds: Option<u32>,
+}
+
+static LEASE: SpinLock<Option<Lease>> = SpinLock::new(None);
+
+/// The lease the first card has, if it got its address from DHCP.
+pub fn lease() -> Option<Lease> {
+    *LEASE.lock()
+}
+
+/// Gets the first card an address, then keeps it, forever. This is the DHCP task's entry
+/// point; it ends straight away if kernel.cfg turns DHCP off or there is no card.
+pub fn run() {
+    if !config::get().net.dhcp {
+        return;
+    }
+    let Some(interface) = net::interfaces().next() else {
+        return;
+    };
+    let socket = match UdpSocket::bind(CLIENT_PORT) {
+        Ok(socket) => socket,
+        Err(error) => {
+            writeln!(serial(), "{}: can't bind the DHCP port: {error:?}", interface.name).unwrap();
+            net::configure_static();
+            return;
+        },
+    };
+    let mut current = None;
+    loop {
+        // A renewal asks for the address the card has; if that is refused, start over
+        let lease = current.and_then(|current| obtain(&socket, interface, Some(current))).or_else(|| obtain(&socket, interface, None));
+        let Some(lease) = lease else {
+            if current.is_none() {
+                writeln!(serial(), "{}: no DHCP server answered", interface.name).unwrap();
+                net::configure_static();
+                return;
+            }
+            // The card keeps the address it has until a server answers
+            task::sleep(RETRY_MS);
+            continue;
+        };
+        if current.is_none_or(|current| current.address != lease.address) {
+            interface.set_ipv4(lease.address, lease.netmask, lease.gateway);
+            writeln!(serial(), "{}: address {} from DHCP server {}, netmask {}, gateway {:?}", interface.name, lease.address, lease.server, lease.netmask, lease.gateway).unwrap();
+        }
+        *LEASE.lock() = Some(lease);
+        current = Some(lease);
+        if lease.seconds == INFINITE {
+            return;
+        }
+        task::sleep(u64::from(lease.seconds) * 1000 / 2);
+    }
+}
+
+// Asks for an address through `interface`, and returns the lease given. Renewing `current`
+// asks for its address again, without looking for a server first.
+fn obtain(socket: &UdpSocket, interface: &Interface, current: Option<Lease>) -> Option<Lease> {
+    let mut id = [0; 4];
+    rand::fill(&mut id);
+    let id = u32::from_ne_bytes(id);
+    let (address, server) = match current {
+        Some(current) => (current.address, current.server),
+        None => {
+            let offer = exchange(socket, interface, &message(interface, id, DISCOVER, None, None), id, &[OFFER])?;
+            (offer.address, offer.server?)
+        },
+    };
+    let reply = exchange(socket, interface, &message(interface, id, REQUEST, Some(address), Some(server)), id, &[ACK, NAK])?;
+    if reply.kind == NAK {
+        return None;
+    }
+    Some(Lease {
+        interface: interface.name,
+        address: reply.address,
+        // A server that doesn't say gets the netmask kernel.cfg gives
+        netmask: reply.netmask.unwrap_or(config::get().net.netmask),
+        gateway: reply.gateway,
+        dns: reply.dns,
+        server: reply.server.unwrap_or(server),
+        seconds: reply.seconds.unwrap_or(INFINITE),
+    })
+}
+
+// Broadcasts `message` and waits for a reply to it of one of `kinds`, sending it again a few
+// times if none comes
+fn exchange(socket: &UdpSocket, interface: &Interface, message: &[u8], id: u32, kinds: &[u8]) -> Option<Reply> {
+    let destination = SocketAddrV4::new(Ipv4Addr::BROADCAST, SERVER_PORT);
+    let mut buffer = [0; 576];
+    for _ in 0..TRIES {
+        if let Err(error) = socket.send_from(interface, Ipv4Addr::BROADCAST, message, destination) {
+            writeln!(serial(), "{}: can't send DHCP: {error:?}", interface.name).unwrap();
+            return None;
+        }
+        // Replies to other machines, or to earlier messages, are skipped
+        let until = time::uptime_ms() + REPLY_TIMEOUT_MS;
+        while let Some(ms) = until.checked_sub(time::uptime_ms()).filter(|&ms| ms > 0) {
+            let Some((length, _)) = socket.recv_from_timeout(&mut buffer, ms) else {
+                break;
+            };
+            let reply = parse(interface, &buffer[..length], id);
+            if let Some(reply) = reply.filter(|reply| kinds.contains(&reply.kind)) {
+                return Some(reply);
+            }
+        }
+    }
+    None
+}
+
+// A message of `kind` from `interface`, with transaction `id`, asking for `requested` from
+// `server`
+fn message(interface: &Interface, id: u32, kind: u8, requested: Option<Ipv4Addr>, server: Option<Ipv4Addr>) -> [u8; MESSAGE_SIZE] {
+    let mut message = [0; MESSAGE_SIZE];
+    message[0] = BOOT_REQUEST;
+    message[1] = HARDWARE_ETHERNET;
+    message[2] = 6;
+    message[4..8].copy_from_slice(&id.to_be_bytes());
+    message[10..12].copy_from_slice(&FLAG_BROADCAST.to_be_bytes());
+    message[28..34].copy_from_slice(&interface.device.mac().0);
+    message[236..OPTIONS_OFFSET].copy_from_slice(&MAGIC_COOKIE);
+
+    let mut end = OPTIONS_OFFSET;
+    let mut put = |option: &[u8]| {
+        message[end..][..option.len()].copy_from_slice(option);
+        end += option.len();
+    };
+    put(&[OPTION_MESSAGE_TYPE, 1, kind]);
+    if let Some(requested) = requested {
+        put(&[OPTION_REQUESTED_ADDRESS, 4]);
+        put(&requested.octets());
+    }
+    if let Some(server) = server {
+        put(&[OPTION_SERVER, 4]);
+        put(&server.octets());
+    }
+    put(&[OPTION_PARAMETERS, 3, OPTION_SUBNET_MASK, OPTION_ROUTER, OPTION_DNS]);
+    put(&[OPTION_END]);
+    message
+}
+
+// Reads a reply to the message with transaction `id` from `interface`, or returns None if it
+// isn't one
+fn parse(interface: &Interface, message: &[u8], id: u32) -> Option<Reply> {
+    if message.len() < OPTIONS_OFFSET || message[0] != BOOT_REPLY || message[4..8] != id.to_be_bytes()
+        || message[28..34] != interface.device.mac().0 || message[236..OPTIONS_OFFSET] != MAGIC_COOKIE {
+        return None;
+    }
+    let mut reply = Reply {
+        kind: 0,
+        address: Ipv4Addr::from(<[u8; 4]>::try_from(&message[16..20]).unwrap()),
+        netmask: None,
+        gateway: None,
+        dns: None,
+        server: None,
+        seconds: None,
+    };
+    let mut options = &message[OPTIONS_OFFSET..];
+    while let [option, rest @ ..] = options {
+        match *option {
+            OPTION_END => break,
+            OPTION_PAD => options = rest,
+            option => {
+                let [length, rest @ ..] = rest else {
+                    break;
+                };
+                let Some(value) = rest.get(..usize::from(*length)) else {
+                    break;
+                };
+                // Options with a list of addresses start with the one to use
+                let word = value.get(..4).map(|bytes| <[u8; 4]>::try_from(bytes).unwrap());
+                let address = word.map(Ipv4Addr::from);
+                match option {
+                    OPTION_MESSAGE_TYPE => reply.kind = value.first().copied().unwrap_or(0),
+                    OPTION_SUBNET_MASK => reply.netmask = address,
+                    OPTION_ROUTER => reply.gateway = address,
+                    OPTION_DNS => reply.dns = address,
+                    OPTION_SERVER => reply.server = address,
+                    OPTION_LEASE_TIME => reply.seconds = word.map(u32::from_be_bytes),
+                    _ => {},
+                }
+                options = &rest[value.len()..];
+            },
+        }
+    }
+    Some(reply)
+}
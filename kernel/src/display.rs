@@ -0,0 +1,150 @@
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use crate::process::{self, Pid};
+use crate::screen::{ScreenWriter, screenwriter};
+use crate::syscall::{self, Error};
+
+// The screen, for user programs. A process takes it the first time it draws, and from then on
+// the menus and games don't draw or get keys until it ends, when the menu comes back. Colours
+// are 0xRRGGBB, and whatever is drawn off the screen is cut off.
+//
+// Programs draw on their own tasks, with the system calls or through /dev/fb0, and either
+// way through `draw_as_owner`, with the screen's lock held for the whole rectangle. It is a
+// sleeping lock, so a program preempted in the middle of drawing holds up the game and input
+// tasks only until it has finished, without them spinning.
+
+// Pixels are 4 bytes, blue, green, red and one unused, both in what programs draw and in fb0
+const BYTES_PER_PIXEL: usize = 4;
+
+// The process that has the screen, or 0 for none
+static OWNER: AtomicU32 = AtomicU32::new(0);
+// Set when the owner ends, until the game task has brought the menu back
+static RELEASED: AtomicBool = AtomicBool::new(false);
+
+/// Registers the screen system calls. Called once during boot.
+pub fn init() {
+    syscall::register(syscall::SCREEN_SIZE, screen_size);
+    syscall::register(syscall::DRAW, draw);
+    syscall::register(syscall::FILL, fill);
+}
+
+/// Returns true while a process has the screen.
+pub fn is_taken() -> bool {
+    OWNER.load(Ordering::SeqCst) != 0
+}
+
+/// Gives the screen back if process `pid` has it. Called when a process ends.
+pub fn release(pid: Pid) {
+    if OWNER.compare_exchange(pid, 0, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+        RELEASED.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Returns true once after a process gave the screen back, for the menu to be drawn again.
+pub fn take_released() -> bool {
+    RELEASED.swap(false, Ordering::SeqCst)
+}
+
+// Gives the screen to the calling process, if a process is calling
+fn take() {
+    if let Some(pid) = process::with_current(|process| process.pid) {
+        OWNER.store(pid, Ordering::SeqCst);
+    }
+}
+
+// Gives the screen to the calling process and runs `draw` with it locked
+fn draw_as_owner<R>(draw: impl FnOnce(&mut ScreenWriter) -> R) -> R {
+    take();
+    draw(&mut screenwriter())
+}
+
+/// How many bytes the screen is, as /dev/fb0.
+pub fn size() -> u64 {
+    let screen = screenwriter();
+    (screen.width() * screen.height() * BYTES_PER_PIXEL) as u64
+}
+
+/// Reads the screen's bytes, as /dev/fb0, from `offset` on into `buffer`, and returns how many
+/// it read.
+pub fn read(offset: u64, buffer: &mut [u8]) -> usize {
+    let screen = screenwriter();
+    let width = screen.width();
+    let size = (width * screen.height() * BYTES_PER_PIXEL) as u64;
+    let length = size.saturating_sub(offset).min(buffer.len() as u64) as usize;
+    for (position, byte) in (offset as usize..).zip(&mut buffer[..length]) {
+        let pixel = position / BYTES_PER_PIXEL;
+        let (r, g, b) = screen.read_pixel(pixel % width, pixel / width);
+        *byte = [b, g, r, 0][position % BYTES_PER_PIXEL];
+    }
+    length
+}
+
+/// Writes `bytes` to the screen's bytes, as /dev/fb0, from `offset` on, giving the calling
+/// process the screen, and returns how many it wrote. Writes needn't start or end on a whole
+/// pixel. Returns None if `offset` is past the end of the screen.
+pub fn write(offset: u64, bytes: &[u8]) -> Option<usize> {
+    draw_as_owner(|screen| {
+        let width = screen.width();
+        let size = (width * screen.height() * BYTES_PER_PIXEL) as u64;
+        if offset >= size && !bytes.is_empty() {
+            return None;
+        }
+        let length = (size - offset.min(size)).min(bytes.len() as u64) as usize;
+        for (position, &byte) in (offset as usize..).zip(&bytes[..length]) {
+            let pixel = position / BYTES_PER_PIXEL;
+            let (x, y) = (pixel % width, pixel / width);
+            let (r, g, b) = screen.read_pixel(x, y);
+            let mut bgr = [b, g, r, 0];
+            bgr[position % BYTES_PER_PIXEL] = byte;
+            screen.draw_pixel(x, y, bgr[2], bgr[1], bgr[0]);
+        }
+        Some(length)
+    })
+}
+
+// The part of a `width` by `height` rectangle at (x, y) that is on the screen, as ranges of
+// columns and rows relative to the rectangle
+fn visible(screen: &ScreenWriter, x: u64, y: u64, width: u64, height: u64) -> (core::ops::Range<usize>, core::ops::Range<usize>) {
+    let columns = (screen.width() as u64).saturating_sub(x).min(width);
+    let rows = (screen.height() as u64).saturating_sub(y).min(height);
+    (0..columns as usize, 0..rows as usize)
+}
+
+// screen_size(): the width in the upper 32 bits and the height in the lower
+fn screen_size(_: [u64; 6]) -> Result<u64, Error> {
+    let screen = screenwriter();
+    Ok((screen.width() as u64) << 32 | screen.height() as u64)
+}
+
+// draw(x, y, width, height, pixels): copies `width` by `height` pixels, a row at a time and
+// 32 bits each, to the screen at (x, y)
+fn draw(arguments: [u64; 6]) -> Result<u64, Error> {
+    let [x, y, width, height, pixels, _] = arguments;
+    let length = width.checked_mul(height).and_then(|pixels| pixels.checked_mul(BYTES_PER_PIXEL as u64)).ok_or(Error::BadArgument)?;
+    let pixels = syscall::user_bytes(pixels, length)?;
+    draw_as_owner(|screen| {
+        let (columns, rows) = visible(screen, x, y, width, height);
+        for row in rows {
+            for column in columns.clone() {
+                let offset = (row * width as usize + column) * BYTES_PER_PIXEL;
+                let [b, g, r, _] = pixels[offset..offset + BYTES_PER_PIXEL].try_into().unwrap();
+                screen.draw_pixel(x as usize + column, y as usize + row, r, g, b);
+            }
+        }
+    });
+    Ok(0)
+}
+
+// fill(x, y, width, height, colour): fills a rectangle of the screen
+fn fill(arguments: [u64; 6]) -> Result<u64, Error> {
+    let [x, y, width, height, colour, _] = arguments;
+    let [b, g, r, ..] = colour.to_le_bytes();
+    draw_as_owner(|screen| {
+        let (columns, rows) = visible(screen, x, y, width, height);
+        for row in rows {
+            for column in columns.clone() {
+                screen.draw_pixel(x as usize + column, y as usize + row, r, g, b);
+            }
+        }
+    });
+    Ok(0)
+}
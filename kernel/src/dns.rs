@@ -0,0 +1,184 @@
+use core::net::{Ipv4Addr, SocketAddrV4};
+use kernel::sync::SpinLock;
+use crate::net::{Error, UdpSocket};
+use crate::{config, dhcp, rand, time, timer};
+
+// A stub DNS resolver: it asks a server to look a name up, recursively, and takes the first
+// IPv4 address (A record) in the answer, following no referrals of its own. The server is the
+// one DHCP gave, or else `[net] dns` in kernel.cfg. A query goes to the server's port 53 from
+// a port of its own, and is sent again if no answer comes within a couple of seconds, a few
+// times; the wait is on the timer wheel, through `UdpSocket::recv_from_timeout`. Answers are
+// cached for as long as the server says they hold, up to an hour, in a few entries, the
+// oldest of which make room for new ones.
+pub const MAX_NAME: usize = 253;
+const SERVER_PORT: u16 = 53;
+const HEADER_SIZE: usize = 12;
+const MAX_LABEL: usize = 63;
+// Answers over UDP are at most this long, unless both sides agree to more
+const MAX_MESSAGE: usize = 512;
+const FLAG_RESPONSE: u16 = 1 << 15;
+const FLAG_RECURSION_DESIRED: u16 = 1 << 8;
+const RCODE: u16 = 0xF;
+const RCODE_NAME_ERROR: u16 = 3;
+const TYPE_A: u16 = 1;
+const CLASS_IN: u16 = 1;
+// A compressed name ends with a pointer, the two top bits of its length set
+const POINTER: u8 = 0xC0;
+
+const TIMEOUT_MS: u64 = 2_000;
+const TRIES: u32 = 3;
+const CACHE_SIZE: usize = 8;
+const MAX_TTL_SECONDS: u32 = 3_600;
+
+#[derive(Clone, Copy)]
+struct Entry {
+    name: [u8; MAX_NAME],
+    length: usize,
+    address: Ipv4Addr,
+    // The timer tick it expires at
+    expires: u64,
+}
+
+static CACHE: SpinLock<[Option<Entry>; CACHE_SIZE]> = SpinLock::new([None; CACHE_SIZE]);
+
+/// The server names are looked up with, if there is one.
+pub fn server() -> Option<Ipv4Addr> {
+    dhcp::lease().and_then(|lease| lease.dns).or(config::get().net.dns)
+}
+
+/// Returns the IPv4 address of `name`, which may also be an address written out. Blocks the
+/// calling task while the server is asked, for up to six seconds; not to be called on the
+/// network task.
+pub fn resolve(name: &str) -> Result<Ipv4Addr, Error> {
+    if let Ok(address) = name.parse() {
+        return Ok(address);
+    }
+    let name = name.strip_suffix('.').unwrap_or(name);
+    if let Some(address) = cached(name) {
+        return Ok(address);
+    }
+    let server = SocketAddrV4::new(server().ok_or(Error::NoNameServer)?, SERVER_PORT);
+    let mut id = [0; 2];
+    rand::fill(&mut id);
+    let id = u16::from_ne_bytes(id);
+    let mut query = [0; HEADER_SIZE + MAX_NAME + 2 + 4];
+    let length = encode_query(&mut query, id, name).ok_or(Error::NotFound)?;
+
+    let socket = UdpSocket::bind(0)?;
+    let mut answer = [0; MAX_MESSAGE];
+    for _ in 0..TRIES {
+        socket.send_to(&query[..length], server)?;
+        // Anything but the answer, from the server, to this query is skipped
+        let until = time::uptime_ms() + TIMEOUT_MS;
+        while let Some(ms) = until.checked_sub(time::uptime_ms()).filter(|&ms| ms > 0) {
+            let Some((length, source)) = socket.recv_from_timeout(&mut answer, ms) else {
+                break;
+            };
+            if source != server {
+                continue;
+            }
+            if let Some(result) = parse_answer(&answer[..length], id) {
+                let (address, ttl) = result?;
+                cache(name, address, ttl);
+                return Ok(address);
+            }
+        }
+    }
+    Err(Error::TimedOut)
+}
+
+// Writes a query for the A records of `name` with `id` into `query`, and returns its length,
+// or None if `name` isn't one
+fn encode_query(query: &mut [u8], id: u16, name: &str) -> Option<usize> {
+    if name.is_empty() || name.len() > MAX_NAME {
+        return None;
+    }
+    query[0..2].copy_from_slice(&id.to_be_bytes());
+    query[2..4].copy_from_slice(&FLAG_RECURSION_DESIRED.to_be_bytes());
+    // One question
+    query[4..6].copy_from_slice(&1u16.to_be_bytes());
+    let mut end = HEADER_SIZE;
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > MAX_LABEL {
+            return None;
+        }
+        query[end] = label.len() as u8;
+        query[end + 1..][..label.len()].copy_from_slice(label.as_bytes());
+        end += 1 + label.len();
+    }
+    query[end] = 0;
+    query[end + 1..end + 3].copy_from_slice(&TYPE_A.to_be_bytes());
+    query[end + 3..end + 5].copy_from_slice(&CLASS_IN.to_be_bytes());
+    Some(end + 5)
+}
+
+// Reads the answer to query `id`: the first address in it and how many seconds it holds for,
+// or why there is none. Returns None if it isn't the answer.
+fn parse_answer(message: &[u8], id: u16) -> Option<Result<(Ipv4Addr, u32), Error>> {
+    if message.len() < HEADER_SIZE || message[0..2] != id.to_be_bytes() {
+        return None;
+    }
+    let flags = u16::from_be_bytes([message[2], message[3]]);
+    if flags & FLAG_RESPONSE == 0 {
+        return None;
+    }
+    match flags & RCODE {
+        0 => {},
+        RCODE_NAME_ERROR => return Some(Err(Error::NotFound)),
+        _ => return Some(Err(Error::NameServerFailed)),
+    }
+    let questions = u16::from_be_bytes([message[4], message[5]]);
+    let answers = u16::from_be_bytes([message[6], message[7]]);
+    let mut offset = HEADER_SIZE;
+    for _ in 0..questions {
+        offset = skip_name(message, offset)? + 4;
+    }
+    // Aliases (CNAME records) come before the address of the name they stand for
+    for _ in 0..answers {
+        offset = skip_name(message, offset)?;
+        let record = message.get(offset..offset + 10)?;
+        let kind = u16::from_be_bytes([record[0], record[1]]);
+        let class = u16::from_be_bytes([record[2], record[3]]);
+        let ttl = u32::from_be_bytes(record[4..8].try_into().unwrap());
+        let length = usize::from(u16::from_be_bytes([record[8], record[9]]));
+        let data = message.get(offset + 10..offset + 10 + length)?;
+        if kind == TYPE_A && class == CLASS_IN && length == 4 {
+            return Some(Ok((Ipv4Addr::from(<[u8; 4]>::try_from(data).unwrap()), ttl)));
+        }
+        offset += 10 + length;
+    }
+    Some(Err(Error::NotFound))
+}
+
+// Returns the offset just past the name at `offset` in `message`
+fn skip_name(message: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let length = *message.get(offset)?;
+        match length {
+            0 => return Some(offset + 1),
+            length if length & POINTER == POINTER => return Some(offset + 2),
+            length => offset += 1 + usize::from(length),
+        }
+    }
+}
+
+fn cached(name: &str) -> Option<Ipv4Addr> {
+    let now = timer::now();
+    CACHE.lock_irq().iter().flatten()
+        .find(|entry| entry.expires > now && entry.name[..entry.length].eq_ignore_ascii_case(name.as_bytes()))
+        .map(|entry| entry.address)
+}
+
+// Caches `address` for `name` for `ttl` seconds, replacing the entry closest to expiring
+fn cache(name: &str, address: Ipv4Addr, ttl: u32) {
+    let mut entry = Entry {
+        name: [0; MAX_NAME],
+        length: name.len(),
+        address,
+        expires: timer::now() + time::ms_to_ticks(u64::from(ttl.min(MAX_TTL_SECONDS)) * 1000),
+    };
+    entry.name[..name.len()].copy_from_slice(name.as_bytes());
+    let mut cache = CACHE.lock_irq();
+    let slot = (0..CACHE_SIZE).min_by_key(|&slot| cache[slot].map_or(0, |other| other.expires)).unwrap();
+    cache[slot] = Some(entry);
+}
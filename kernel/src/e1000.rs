@@ -0,0 +1,319 @@
+use alloc::boxed::Box;
+use core::fmt::Write;
+use core::ptr;
+use kernel::serial;
+use kernel::sync::SpinLock;
+use crate::memory::{self, Dma, Mmio, PAGE_SIZE};
+use crate::net::{self, Error, Mac, MAX_FRAME, NetworkDevice};
+use crate::pci::{self, Match, PciDevice};
+use crate::irq;
+
+// Intel 8254x (e1000) and 82574 (e1000e) network cards: QEMU's default card, and common on
+// real machines. The registers are memory-mapped from BAR 0. Frames go through two rings of
+// descriptors in DMA memory, one the card fills with the frames it receives and one it sends
+// from, each descriptor pointing at a buffer of its own. The driver owns the descriptors
+// between the card's head and its tail: it hands a receive descriptor back by moving the
+// receive tail past it once its frame is copied out, and a transmit descriptor by moving the
+// transmit tail past it once its frame is copied in. The card sets a descriptor's done bit when
+// it has filled or sent it. Receiving a frame and the link changing raise the interrupt, which
+// wakes the network task.
+const VENDOR: u16 = 0x8086;
+// 82540EM, which QEMU's e1000 is, 82545EM, 82574L, which QEMU's e1000e is, and 82579LM
+const DEVICES: [Match; 4] = [Match::Id(VENDOR, 0x100E), Match::Id(VENDOR, 0x100F), Match::Id(VENDOR, 0x10D3), Match::Id(VENDOR, 0x1502)];
+// The 82574 and later moved the EEPROM read register's fields
+const NEWER: [u16; 2] = [0x10D3, 0x1502];
+
+// Registers, as offsets into BAR 0
+const CONTROL: usize = 0x0000;
+const STATUS: usize = 0x0008;
+const EEPROM_READ: usize = 0x0014;
+const INTERRUPT_CAUSE: usize = 0x00C0;
+const INTERRUPT_MASK_SET: usize = 0x00D0;
+const INTERRUPT_MASK_CLEAR: usize = 0x00D8;
+const RECEIVE_CONTROL: usize = 0x0100;
+const TRANSMIT_CONTROL: usize = 0x0400;
+const TRANSMIT_IPG: usize = 0x0410;
+const RECEIVE_RING: usize = 0x2800;
+const TRANSMIT_RING: usize = 0x3800;
+const MULTICAST_TABLE: usize = 0x5200;
+const RECEIVE_ADDRESS: usize = 0x5400;
+// Each ring's registers, as offsets from its first: the base address, its length in bytes,
+// and the head and tail indexes
+const RING_ADDRESS_LOW: usize = 0x00;
+const RING_ADDRESS_HIGH: usize = 0x04;
+const RING_LENGTH: usize = 0x08;
+const RING_HEAD: usize = 0x10;
+const RING_TAIL: usize = 0x18;
+
+// Control bits: auto-detect the speed, set the link up, and reset
+const AUTO_SPEED: u32 = 1 << 5;
+const SET_LINK_UP: u32 = 1 << 6;
+const RESET: u32 = 1 << 26;
+// Status bit for the link being up
+const LINK_UP: u32 = 1 << 1;
+// EEPROM read: start; the done bit and address shift differ on the newer cards
+const EEPROM_START: u32 = 1;
+// The receive address register's bit for the address being valid
+const ADDRESS_VALID: u32 = 1 << 31;
+
+// Interrupt causes: the link changed, the receive ring is running low, it overran, and a
+// frame arrived
+const LINK_CHANGE: u32 = 1 << 2;
+const RECEIVE_LOW: u32 = 1 << 4;
+const RECEIVE_OVERRUN: u32 = 1 << 6;
+const RECEIVE_TIMER: u32 = 1 << 7;
+const INTERRUPTS: u32 = LINK_CHANGE | RECEIVE_LOW | RECEIVE_OVERRUN | RECEIVE_TIMER;
+
+// Receive control: enabled, taking broadcasts, with 2 KiB buffers and the checksum stripped
+const RECEIVE_ENABLE: u32 = 1 << 1;
+const BROADCAST: u32 = 1 << 15;
+const STRIP_CHECKSUM: u32 = 1 << 26;
+// Transmit control: enabled, short frames padded, and the collision settings for full duplex
+const TRANSMIT_ENABLE: u32 = 1 << 1;
+const PAD_SHORT: u32 = 1 << 3;
+const COLLISION_THRESHOLD: u32 = 0x10 << 4;
+const COLLISION_DISTANCE: u32 = 0x40 << 12;
+// The gaps between frames the manuals ask for
+const IPG: u32 = 10 | 8 << 10 | 6 << 20;
+
+// Descriptors are 16 bytes: the buffer's address, then the length and a status byte, at 12 for
+// both kinds. Transmit ones have a command byte at 11.
+const DESCRIPTOR_SIZE: usize = 16;
+const LENGTH: usize = 8;
+const COMMAND: usize = 11;
+const DESCRIPTOR_STATUS: usize = 12;
+// Descriptor status: done, and the end of the frame
+const DONE: u8 = 1;
+const END_OF_FRAME: u8 = 1 << 1;
+// Transmit commands: the end of the frame, add the checksum, and report when done
+const SEND_END_OF_FRAME: u8 = 1;
+const INSERT_CHECKSUM: u8 = 1 << 1;
+const REPORT_STATUS: u8 = 1 << 3;
+
+const DESCRIPTORS: usize = 32;
+const BUFFER_SIZE: usize = 2048;
+const BUFFER_PAGES: u64 = (DESCRIPTORS * BUFFER_SIZE) as u64 / PAGE_SIZE;
+
+// The cards, for the interrupt handler to check which raised it
+static CARDS: SpinLock<[Option<&'static Card>; net::MAX_DEVICES]> = SpinLock::new([None; net::MAX_DEVICES]);
+
+// A descriptor ring and its buffers
+struct Ring {
+    descriptors: Dma,
+    buffers: Dma,
+    // The next descriptor the driver looks at
+    next: usize,
+}
+
+impl Ring {
+    fn new() -> Option<Ring> {
+        let ring = Ring { descriptors: memory::alloc_dma(1)?, buffers: memory::alloc_dma(BUFFER_PAGES)?, next: 0 };
+        for index in 0..DESCRIPTORS {
+            let address = ring.buffers.physical + (index * BUFFER_SIZE) as u64;
+            unsafe { ring.descriptor(index).cast::<u64>().write_volatile(address.as_u64()) };
+        }
+        Some(ring)
+    }
+
+    fn descriptor(&self, index: usize) -> *mut u8 {
+        (self.descriptors.start + (index * DESCRIPTOR_SIZE) as u64).as_mut_ptr()
+    }
+
+    fn buffer(&self, index: usize) -> *mut u8 {
+        (self.buffers.start + (index * BUFFER_SIZE) as u64).as_mut_ptr()
+    }
+
+    fn status(&self, index: usize) -> u8 {
+        unsafe { self.descriptor(index).add(DESCRIPTOR_STATUS).read_volatile() }
+    }
+}
+
+struct Card {
+    registers: Mmio,
+    mac: Mac,
+    receive: SpinLock<Ring>,
+    transmit: SpinLock<Ring>,
+    // The I/O APIC input it raises its interrupt on
+    line: u8,
+}
+
+impl Card {
+    fn read(&self, register: usize) -> u32 {
+        self.registers.read32(register)
+    }
+
+    fn write(&self, register: usize, value: u32) {
+        self.registers.write32(register, value)
+    }
+
+    // Tells the card where `ring` is, with every descriptor the driver's for now
+    fn set_ring(&self, registers: usize, ring: &Ring) {
+        let address = ring.descriptors.physical.as_u64();
+        self.write(registers + RING_ADDRESS_LOW, address as u32);
+        self.write(registers + RING_ADDRESS_HIGH, (address >> 32) as u32);
+        self.write(registers + RING_LENGTH, (DESCRIPTORS * DESCRIPTOR_SIZE) as u32);
+        self.write(registers + RING_HEAD, 0);
+        self.write(registers + RING_TAIL, 0);
+    }
+
+    // Reads word `address` of the EEPROM, or None if it doesn't answer
+    fn eeprom(&self, address: u32, newer: bool) -> Option<u16> {
+        let (shift, done) = if newer { (2, 1 << 1) } else { (8, 1 << 4) };
+        self.write(EEPROM_READ, EEPROM_START | address << shift);
+        for _ in 0..100_000 {
+            let value = self.read(EEPROM_READ);
+            if value & done != 0 {
+                return Some((value >> 16) as u16);
+            }
+            core::hint::spin_loop();
+        }
+        None
+    }
+
+    // The card's address, which the receive address registers hold once the card has loaded
+    // it from the EEPROM, or else the EEPROM's first three words
+    fn read_mac(&self, newer: bool) -> Option<Mac> {
+        let high = self.read(RECEIVE_ADDRESS + 4);
+        if high & ADDRESS_VALID != 0 {
+            let [a, b, c, d] = self.read(RECEIVE_ADDRESS).to_le_bytes();
+            let [e, f, ..] = high.to_le_bytes();
+            return Some(Mac([a, b, c, d, e, f]));
+        }
+        let mut mac = [0; 6];
+        for word in 0..3 {
+            let [low, high] = self.eeprom(word, newer)?.to_le_bytes();
+            mac[word as usize * 2] = low;
+            mac[word as usize * 2 + 1] = high;
+        }
+        Some(Mac(mac))
+    }
+}
+
+impl NetworkDevice for Card {
+    fn mac(&self) -> Mac {
+        self.mac
+    }
+
+    fn link_up(&self) -> bool {
+        self.read(STATUS) & LINK_UP != 0
+    }
+
+    fn send(&self, frame: &[u8]) -> Result<(), Error> {
+        if frame.len() > MAX_FRAME {
+            return Err(Error::TooLong);
+        }
+        let mut transmit = self.transmit.lock();
+        let index = transmit.next;
+        // Every descriptor starts out done, so one that isn't is still the card's
+        if transmit.status(index) & DONE == 0 {
+            return Err(Error::Busy);
+        }
+        unsafe {
+            ptr::copy_nonoverlapping(frame.as_ptr(), transmit.buffer(index), frame.len());
+            let descriptor = transmit.descriptor(index);
+            descriptor.add(LENGTH).cast::<u16>().write_volatile(frame.len() as u16);
+            descriptor.add(COMMAND).write_volatile(SEND_END_OF_FRAME | INSERT_CHECKSUM | REPORT_STATUS);
+            descriptor.add(DESCRIPTOR_STATUS).write_volatile(0);
+        }
+        transmit.next = (index + 1) % DESCRIPTORS;
+        self.write(TRANSMIT_RING + RING_TAIL, transmit.next as u32);
+        Ok(())
+    }
+
+    fn receive(&self, buffer: &mut [u8; MAX_FRAME]) -> Option<usize> {
+        let mut receive = self.receive.lock();
+        loop {
+            let index = receive.next;
+            let status = receive.status(index);
+            if status & DONE == 0 {
+                return None;
+            }
+            let length = unsafe { receive.descriptor(index).add(LENGTH).cast::<u16>().read_volatile() } as usize;
+            // Frames are never longer than a buffer, so one spread over several is a broken one
+            let whole = status & END_OF_FRAME != 0;
+            let length = length.min(MAX_FRAME);
+            if whole {
+                unsafe { ptr::copy_nonoverlapping(receive.buffer(index), buffer.as_mut_ptr(), length) };
+            }
+            // Back to the card straight away, so it always has somewhere to put frames
+            unsafe { receive.descriptor(index).add(DESCRIPTOR_STATUS).write_volatile(0) };
+            self.write(RECEIVE_RING + RING_TAIL, index as u32);
+            receive.next = (index + 1) % DESCRIPTORS;
+            if whole {
+                return Some(length);
+            }
+        }
+    }
+}
+
+// Reading the interrupt cause acknowledges it, so each card's is read, and the network task
+// woken if any of them received a frame or had its link change
+fn handle_interrupt() {
+    let raised = CARDS.lock_irq().iter().flatten().fold(false, |raised, card| card.read(INTERRUPT_CAUSE) != 0 || raised);
+    if raised {
+        net::wake();
+    }
+}
+
+/// Registers the e1000 network cards on the PCI bus as network cards.
+pub const DRIVER: pci::Driver = pci::Driver { name: "e1000", matches: &DEVICES, probe };
+
+fn probe(function: PciDevice) -> Result<(), &'static str> {
+    let newer = NEWER.contains(&function.device_id());
+    let registers = function.map_bar(0)?;
+    function.enable_bus_master();
+    let rings = Ring::new().zip(Ring::new()).ok_or("no memory for the rings")?;
+    let mut card = Card { registers, mac: Mac([0; 6]), receive: SpinLock::new(rings.0), transmit: SpinLock::new(rings.1), line: 0 };
+
+    // Reset with interrupts masked, and wait for the card to come out of it
+    card.write(INTERRUPT_MASK_CLEAR, u32::MAX);
+    card.write(CONTROL, card.read(CONTROL) | RESET);
+    for _ in 0..100_000 {
+        if card.read(CONTROL) & RESET == 0 {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+    card.write(INTERRUPT_MASK_CLEAR, u32::MAX);
+    card.read(INTERRUPT_CAUSE);
+    card.write(CONTROL, card.read(CONTROL) | AUTO_SPEED | SET_LINK_UP);
+    card.mac = card.read_mac(newer).ok_or("can't read the MAC address")?;
+    for entry in 0..128 {
+        card.write(MULTICAST_TABLE + entry * 4, 0);
+    }
+
+    {
+        let receive = card.receive.lock();
+        card.set_ring(RECEIVE_RING, &receive);
+        // The card may fill every descriptor but the one at the tail
+        card.write(RECEIVE_RING + RING_TAIL, (DESCRIPTORS - 1) as u32);
+        let transmit = card.transmit.lock();
+        for index in 0..DESCRIPTORS {
+            unsafe { transmit.descriptor(index).add(DESCRIPTOR_STATUS).write_volatile(DONE) };
+        }
+        card.set_ring(TRANSMIT_RING, &transmit);
+    }
+    card.write(RECEIVE_CONTROL, RECEIVE_ENABLE | BROADCAST | STRIP_CHECKSUM);
+    card.write(TRANSMIT_IPG, IPG);
+    card.write(TRANSMIT_CONTROL, TRANSMIT_ENABLE | PAD_SHORT | COLLISION_THRESHOLD | COLLISION_DISTANCE);
+
+    let line = function.interrupt_line();
+    // The handler checks every card, so one on each line is enough
+    let sharing = CARDS.lock_irq().iter().flatten().any(|other| other.line == line);
+    let interrupt = sharing || irq::register(line, handle_interrupt);
+    card.line = line;
+    let card: &'static Card = Box::leak(Box::new(card));
+    if let Some(slot) = CARDS.lock_irq().iter_mut().find(|slot| slot.is_none()) {
+        *slot = Some(card);
+    }
+    if interrupt {
+        card.write(INTERRUPT_MASK_SET, INTERRUPTS);
+    }
+    let name = net::register(card, interrupt).ok_or("too many network cards, not registered")?;
+    let link = if card.link_up() { "up" } else { "down" };
+    writeln!(serial(), "{name}: e1000, MAC {}, link {link}, IRQ {line}", card.mac).unwrap();
+    if !interrupt {
+        writeln!(serial(), "{name}: can't use IRQ {line}, polling").unwrap();
+    }
+    Ok(())
+}
@@ -0,0 +1,111 @@
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+use crate::memory::{self, AddressSpace};
+
+// Loads programs in the ELF64 format into an address space. Only statically linked x86_64
+// executables are supported, linked to run in the user region: their PT_LOAD segments are
+// copied to where they ask to be, and nothing is relocated.
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const MAGIC: &[u8] = b"\x7fELF";
+// 64-bit, little endian, ELF version 1
+const IDENTIFICATION: [u8; 3] = [2, 1, 1];
+const EXECUTABLE: u16 = 2;
+const X86_64: u16 = 62;
+const LOAD: u32 = 1;
+// Segment permissions
+const EXECUTE: u32 = 1;
+const WRITE: u32 = 2;
+
+/// Why a program couldn't be loaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// Not an ELF file, or cut short.
+    NotElf,
+    /// An ELF file, but not an x86_64 executable.
+    Unsupported,
+    /// A segment that isn't all in the file, or in the user region.
+    BadSegment,
+    OutOfMemory,
+}
+
+/// Where a program was loaded.
+#[derive(Debug, Clone, Copy)]
+pub struct Image {
+    /// The program's entry point.
+    pub entry: VirtAddr,
+    /// The end of its highest segment.
+    pub end: VirtAddr,
+}
+
+/// Maps the loadable segments of the program in `image` into `space`, with the permissions
+/// they ask for, and returns where it went.
+pub fn load(image: &[u8], space: &mut AddressSpace) -> Result<Image, Error> {
+    let header = image.get(..HEADER_SIZE).ok_or(Error::NotElf)?;
+    if &header[..4] != MAGIC {
+        return Err(Error::NotElf);
+    }
+    if header[4..7] != IDENTIFICATION || u16_at(header, 16) != EXECUTABLE || u16_at(header, 18) != X86_64 {
+        return Err(Error::Unsupported);
+    }
+    let entry = u64_at(header, 24);
+    let program_headers = u64_at(header, 32);
+    let header_size = u16_at(header, 54) as u64;
+    let headers = u16_at(header, 56) as u64;
+    if header_size < PROGRAM_HEADER_SIZE as u64 {
+        return Err(Error::NotElf);
+    }
+    let mut end = VirtAddr::new(memory::USER_START);
+    for index in 0..headers {
+        let start = program_headers.saturating_add(index * header_size);
+        let header = usize::try_from(start).ok().and_then(|start| image.get(start..)?.get(..PROGRAM_HEADER_SIZE));
+        let header = header.ok_or(Error::NotElf)?;
+        if u32_at(header, 0) == LOAD {
+            end = end.max(load_segment(image, header, space)?);
+        }
+    }
+    let entry = VirtAddr::try_new(entry).ok().filter(|&entry| memory::in_user_region(entry, 1)).ok_or(Error::Unsupported)?;
+    Ok(Image { entry, end })
+}
+
+// Returns the end of the segment
+fn load_segment(image: &[u8], header: &[u8], space: &mut AddressSpace) -> Result<VirtAddr, Error> {
+    let permissions = u32_at(header, 4);
+    let offset = u64_at(header, 8);
+    let address = u64_at(header, 16);
+    let file_size = u64_at(header, 32);
+    let memory_size = u64_at(header, 40);
+
+    let contents = usize::try_from(offset).ok().zip(usize::try_from(file_size).ok())
+        .and_then(|(offset, size)| image.get(offset..)?.get(..size));
+    let contents = contents.ok_or(Error::BadSegment)?;
+    let start = VirtAddr::try_new(address).map_err(|_| Error::BadSegment)?;
+    if file_size > memory_size || !memory::in_user_region(start, memory_size) {
+        return Err(Error::BadSegment);
+    }
+
+    let mut flags = PageTableFlags::empty();
+    if permissions & WRITE != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if permissions & EXECUTE == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    // The memory is mapped zeroed, so the part past the file's contents, the BSS, is zero
+    if !space.map(start, memory_size, flags) || !space.write(start, contents) {
+        return Err(Error::OutOfMemory);
+    }
+    Ok(start + memory_size)
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
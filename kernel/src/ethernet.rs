@@ -0,0 +1,82 @@
+use kernel::sync::SpinLock;
+use crate::net::{Error, Interface, Mac, MAX_FRAME};
+
+// Ethernet framing, between the network cards and the protocols above them. A frame is the
+// destination and source MAC addresses, the EtherType saying which protocol its payload is
+// for, and the payload, padded to the shortest frame the wire carries; the card adds the
+// checksum. Protocols register a handler for their EtherType, which the network task calls
+// with the payload of every frame of that type sent to the card, to every card or to a
+// multicast group. Nothing below here knows what the protocols are.
+pub const HEADER_SIZE: usize = 14;
+/// The most a frame's payload can be.
+pub const MTU: usize = MAX_FRAME - HEADER_SIZE;
+// Frames are at least 64 bytes on the wire, with the checksum
+const MIN_FRAME: usize = 60;
+const MAX_PROTOCOLS: usize = 8;
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+
+/// What a protocol's handler is given: where a frame came from and was sent to, and its
+/// payload.
+pub struct Frame<'a> {
+    pub source: Mac,
+    pub destination: Mac,
+    pub payload: &'a [u8],
+}
+
+/// Called on the network task with each frame of the EtherType it is registered for.
+pub type Handler = fn(&'static Interface, &Frame);
+
+static HANDLERS: SpinLock<[Option<(u16, Handler)>; MAX_PROTOCOLS]> = SpinLock::new([None; MAX_PROTOCOLS]);
+
+/// Calls `handler` for every frame of `ether_type` received from now on. Returns false if
+/// there are too many protocols, or one already has that type.
+pub fn register(ether_type: u16, handler: Handler) -> bool {
+    let mut handlers = HANDLERS.lock_irq();
+    if handlers.iter().flatten().any(|&(other, _)| other == ether_type) {
+        return false;
+    }
+    match handlers.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => {
+            *slot = Some((ether_type, handler));
+            true
+        },
+        None => false,
+    }
+}
+
+/// Sends `payload`, at most `MTU` bytes, to `destination` through `interface`, as a frame of
+/// `ether_type`.
+pub fn send(interface: &Interface, destination: Mac, ether_type: u16, payload: &[u8]) -> Result<(), Error> {
+    if payload.len() > MTU {
+        return Err(Error::TooLong);
+    }
+    let mut frame = [0; MAX_FRAME];
+    frame[..6].copy_from_slice(&destination.0);
+    frame[6..12].copy_from_slice(&interface.device.mac().0);
+    frame[12..HEADER_SIZE].copy_from_slice(&ether_type.to_be_bytes());
+    frame[HEADER_SIZE..][..payload.len()].copy_from_slice(payload);
+    // Short frames are padded with the zeroes already there
+    let length = (HEADER_SIZE + payload.len()).max(MIN_FRAME);
+    interface.send(&frame[..length])
+}
+
+/// Checks that `frame`, received on `interface`, is whole and meant for it, and hands its
+/// payload to the protocol registered for its EtherType. Others are dropped.
+pub fn receive(interface: &'static Interface, frame: &[u8]) {
+    if frame.len() < HEADER_SIZE {
+        return;
+    }
+    let destination = Mac(frame[..6].try_into().unwrap());
+    let source = Mac(frame[6..12].try_into().unwrap());
+    // Multicast addresses, broadcast among them, have the lowest bit of the first byte set
+    if destination != interface.device.mac() && destination.0[0] & 1 == 0 {
+        return;
+    }
+    let ether_type = u16::from_be_bytes([frame[12], frame[13]]);
+    let handler = HANDLERS.lock_irq().iter().flatten().find(|&&(other, _)| other == ether_type).map(|&(_, handler)| handler);
+    if let Some(handler) = handler {
+        handler(interface, &Frame { source, destination, payload: &frame[HEADER_SIZE..] });
+    }
+}
@@ -0,0 +1,203 @@
+use alloc::boxed::Box;
+use core::future::{poll_fn, Future};
+use core::pin::{pin, Pin};
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::task;
+
+// A small async executor that runs as one task. Every spawned future has a slot, and its
+// waker just sets the slot's bit in READY, which is safe from interrupt handlers, so the
+// keyboard, serial and timer interrupts wake whatever is waiting on them directly.
+const MAX_FUTURES: usize = 8;
+const MAX_SLEEPERS: usize = 16;
+
+type BoxedFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+static FUTURES: [Mutex<Option<BoxedFuture>>; MAX_FUTURES] = [const { Mutex::new(None) }; MAX_FUTURES];
+static READY: AtomicU32 = AtomicU32::new(0);
+// The executor task blocks here while no future is ready
+static IDLE: task::WaitQueue = task::WaitQueue::new();
+
+/// Starts running `future` on the executor. Returns false if too many futures are running.
+pub fn spawn(future: impl Future<Output = ()> + Send + 'static) -> bool {
+    let mut future = Some(Box::pin(future) as BoxedFuture);
+    for (index, slot) in FUTURES.iter().enumerate() {
+        // A slot that is locked is being polled, so it is in use; this also lets the futures
+        // themselves spawn
+        let Some(mut slot) = slot.try_lock() else {
+            continue;
+        };
+        if slot.is_none() {
+            *slot = future.take();
+            wake(index as *const ());
+            return true;
+        }
+    }
+    false
+}
+
+/// Polls the futures that have been woken, forever. This is the executor task's entry point.
+pub fn run() {
+    loop {
+        IDLE.wait_until(|| READY.load(Ordering::SeqCst) != 0);
+        let ready = READY.swap(0, Ordering::SeqCst);
+        for (index, slot) in FUTURES.iter().enumerate().filter(|(index, _)| ready & 1 << index != 0) {
+            let waker = waker(index);
+            let mut context = Context::from_waker(&waker);
+            let mut slot = slot.lock();
+            if slot.as_mut().is_some_and(|future| future.as_mut().poll(&mut context).is_ready()) {
+                *slot = None;
+            }
+        }
+    }
+}
+
+// The waker data is the slot index rather than a pointer, so wakers never allocate
+fn waker(index: usize) -> Waker {
+    unsafe { Waker::from_raw(RawWaker::new(index as *const (), &VTABLE)) }
+}
+
+static VTABLE: RawWakerVTable = RawWakerVTable::new(|data| RawWaker::new(data, &VTABLE), wake, wake, |_| {});
+
+fn wake(data: *const ()) {
+    READY.fetch_or(1 << data as usize, Ordering::SeqCst);
+    IDLE.notify();
+}
+
+/// A queue filled by an interrupt handler and emptied by async code, which `next` wakes as
+/// soon as something arrives. When the queue is full, new items are dropped.
+pub struct InterruptQueue<T: Copy, const N: usize> {
+    items: Mutex<Ring<T, N>>,
+    waker: Mutex<Option<Waker>>,
+}
+
+struct Ring<T, const N: usize> {
+    items: [Option<T>; N],
+    start: usize,
+    length: usize,
+}
+
+impl<T: Copy, const N: usize> InterruptQueue<T, N> {
+    pub const fn new() -> Self {
+        InterruptQueue {
+            items: Mutex::new(Ring { items: [None; N], start: 0, length: 0 }),
+            waker: Mutex::new(None),
+        }
+    }
+
+    /// Adds `item` and wakes the code waiting for it. Called with interrupts disabled.
+    pub fn push(&self, item: T) {
+        {
+            let mut ring = self.items.lock();
+            if ring.length == N {
+                return;
+            }
+            let end = (ring.start + ring.length) % N;
+            ring.items[end] = Some(item);
+            ring.length += 1;
+        }
+        if let Some(waker) = self.waker.lock().take() {
+            waker.wake();
+        }
+    }
+
+    /// Removes the oldest item, if there is one.
+    pub fn pop(&self) -> Option<T> {
+        // The interrupt handler locks the queue too, so it mustn't interrupt us holding it
+        interrupts::without_interrupts(|| {
+            let mut ring = self.items.lock();
+            if ring.length == 0 {
+                return None;
+            }
+            let start = ring.start;
+            let item = ring.items[start].take();
+            ring.start = (start + 1) % N;
+            ring.length -= 1;
+            item
+        })
+    }
+
+    /// Waits for the next item.
+    pub async fn next(&self) -> T {
+        poll_fn(|context| self.poll_next(context)).await
+    }
+
+    /// Takes the oldest item, or arranges for `context` to be woken when one arrives. Only the
+    /// last context to wait is woken, so only one piece of code should wait on a queue.
+    pub fn poll_next(&self, context: &mut Context) -> Poll<T> {
+        if let Some(item) = self.pop() {
+            return Poll::Ready(item);
+        }
+        interrupts::without_interrupts(|| *self.waker.lock() = Some(context.waker().clone()));
+        // Something may have arrived before the waker was in place
+        match self.pop() {
+            Some(item) => Poll::Ready(item),
+            None => Poll::Pending,
+        }
+    }
+}
+
+// Timer ticks seen by `timer_interrupt`, and the wakers waiting for a tick
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static SLEEPERS: Mutex<[Option<(u64, Waker)>; MAX_SLEEPERS]> = Mutex::new([const { None }; MAX_SLEEPERS]);
+
+/// Counts a timer tick and wakes the sleeps that are over. Called from the timer interrupt.
+pub fn timer_interrupt() {
+    let now = TICKS.fetch_add(1, Ordering::SeqCst) + 1;
+    for sleeper in SLEEPERS.lock().iter_mut() {
+        if sleeper.as_ref().is_some_and(|(until, _)| *until <= now) {
+            sleeper.take().unwrap().1.wake();
+        }
+    }
+}
+
+/// A future that completes after a number of timer ticks, see `sleep`.
+pub struct Sleep {
+    until: u64,
+    registered: bool,
+}
+
+/// Waits for `ticks` timer ticks (at least one).
+pub fn sleep(ticks: u64) -> Sleep {
+    Sleep { until: TICKS.load(Ordering::SeqCst) + ticks.max(1), registered: false }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        if TICKS.load(Ordering::SeqCst) >= self.until {
+            return Poll::Ready(());
+        }
+        // This executor's wakers never change, so one registration is enough
+        if !self.registered {
+            let until = self.until;
+            let registered = interrupts::without_interrupts(|| {
+                let mut sleepers = SLEEPERS.lock();
+                let free = sleepers.iter_mut().find(|sleeper| sleeper.is_none());
+                free.map(|sleeper| *sleeper = Some((until, context.waker().clone()))).is_some()
+            });
+            if !registered {
+                // No room to wait: come back on the next round instead
+                context.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            self.registered = true;
+        }
+        if TICKS.load(Ordering::SeqCst) >= self.until { Poll::Ready(()) } else { Poll::Pending }
+    }
+}
+
+/// Runs `future` for at most `ticks` timer ticks. Returns None if it didn't finish in time.
+pub async fn timeout<F: Future>(ticks: u64, future: F) -> Option<F::Output> {
+    let mut future = pin!(future);
+    let mut sleep = sleep(ticks);
+    poll_fn(|context| {
+        if let Poll::Ready(output) = future.as_mut().poll(context) {
+            return Poll::Ready(Some(output));
+        }
+        Pin::new(&mut sleep).poll(context).map(|()| None)
+    }).await
+}
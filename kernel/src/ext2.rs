@@ -0,0 +1,312 @@
+use core::fmt::Write;
+use kernel::sync::SpinLock;
+use crate::block::{BlockDevice, BLOCK_SIZE};
+use crate::fs::{DirEntry, Error, Filesystem, Kind, Metadata, MAX_NAME};
+use crate::ui::TextBuffer;
+
+// ext2 filesystems, read-only. The superblock, 1 KiB into the device, says how big blocks are
+// and how the inodes are split into block groups, and the descriptor of each group says where
+// its part of the inode table is. An inode holds what a file is, its size and the numbers of
+// its first 12 blocks, then the number of a block of block numbers, of a block of those, and
+// of a block of those again; block 0 is a hole, which reads as zeroes. Directories are lists
+// of entries of varying length, each with an inode number and a name. Symbolic links keep
+// their target in the inode itself, where the block numbers would be, when it is short, and
+// in a block otherwise.
+//
+// Filesystems that need features this can't read, such as the extents of ext4, aren't opened.
+// Inode numbers are ext2's own, and the root directory is 2.
+const MAX_VOLUMES: usize = 4;
+// Where the superblock is, in bytes, and its magic number
+const SUPERBLOCK: u64 = 1024;
+const MAGIC: u16 = 0xef53;
+const ROOT: u64 = 2;
+// The features a filesystem can need and still be read: directory entries that hold the
+// type of the file, and block groups whose tables are packed together, since the descriptors
+// say where they are either way
+const FILETYPE: u32 = 0x2;
+const FLEX_BG: u32 = 0x200;
+const READABLE_FEATURES: u32 = FILETYPE | FLEX_BG;
+// The largest block size, 64 KiB, as a power of 2 of 1 KiB
+const MAX_LOG_BLOCK_SIZE: u32 = 6;
+// Revision 0 filesystems have 128-byte inodes and no field saying so
+const OLD_INODE_SIZE: u64 = 128;
+const DESCRIPTOR_SIZE: u64 = 32;
+const DIRECT_BLOCKS: u64 = 12;
+// The top 4 bits of an inode's mode say what it is
+const TYPE_MASK: u16 = 0xf000;
+const DIRECTORY: u16 = 0x4000;
+const REGULAR: u16 = 0x8000;
+const SYMLINK: u16 = 0xa000;
+
+/// An ext2 filesystem, once a volume is opened in it.
+pub struct Ext2 {
+    volume: SpinLock<Option<Volume>>,
+}
+
+static VOLUMES: [Ext2; MAX_VOLUMES] = [const { Ext2 { volume: SpinLock::new(None) } }; MAX_VOLUMES];
+
+/// Returns the filesystem for the ext2 volume on `device`, to mount, if it has one that can be
+/// read and there is room for another.
+pub fn open(device: &'static dyn BlockDevice) -> Option<&'static Ext2> {
+    let volume = Volume::new(device)?;
+    VOLUMES.iter().find(|ext2| {
+        let mut slot = ext2.volume.lock_irq();
+        let free = slot.is_none();
+        if free {
+            *slot = Some(volume);
+        }
+        free
+    })
+}
+
+// Where a volume's parts are
+#[derive(Clone, Copy)]
+struct Volume {
+    device: &'static dyn BlockDevice,
+    block_size: u64,
+    inodes: u32,
+    inodes_per_group: u32,
+    inode_size: u64,
+    // Where the group descriptors start, in bytes
+    descriptors: u64,
+}
+
+// What an inode says about a file
+#[derive(Clone, Copy)]
+struct Inode {
+    mode: u16,
+    size: u64,
+    // How many 512-byte sectors its blocks take up
+    sectors: u32,
+    blocks: [u32; 15],
+}
+
+impl Inode {
+    fn kind(&self) -> Kind {
+        match self.mode & TYPE_MASK {
+            DIRECTORY => Kind::Directory,
+            SYMLINK => Kind::Symlink,
+            _ => Kind::File,
+        }
+    }
+
+    // Whether it is a symbolic link that keeps its target in place of the block numbers
+    fn is_fast_symlink(&self) -> bool {
+        self.mode & TYPE_MASK == SYMLINK && self.sectors == 0
+    }
+}
+
+impl Volume {
+    // Reads the superblock of `device`, if it has an ext2 volume this can read
+    fn new(device: &'static dyn BlockDevice) -> Option<Volume> {
+        let mut volume = Volume { device, block_size: 1024, inodes: 0, inodes_per_group: 0, inode_size: 0, descriptors: 0 };
+        let mut superblock = [0; 1024];
+        volume.read_bytes(SUPERBLOCK, &mut superblock).ok()?;
+        let word = |at: usize| u16::from_le_bytes([superblock[at], superblock[at + 1]]);
+        let long = |at: usize| u32::from_le_bytes(superblock[at..at + 4].try_into().unwrap());
+        let (first_data_block, log_block_size, revision) = (long(20), long(24), long(76));
+        if word(56) != MAGIC || log_block_size > MAX_LOG_BLOCK_SIZE || long(96) & !READABLE_FEATURES != 0 || long(40) == 0 {
+            return None;
+        }
+        volume.block_size = 1024 << log_block_size;
+        volume.inodes = long(0);
+        volume.inodes_per_group = long(40);
+        volume.inode_size = if revision == 0 { OLD_INODE_SIZE } else { u64::from(word(88)) };
+        if !volume.inode_size.is_power_of_two() || volume.inode_size < OLD_INODE_SIZE || volume.inode_size > volume.block_size {
+            return None;
+        }
+        // The descriptors are in the block after the superblock
+        volume.descriptors = (u64::from(first_data_block) + 1) * volume.block_size;
+        Some(volume)
+    }
+
+    // Reads `buffer.len()` bytes from `position` on the device
+    fn read_bytes(&self, position: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        let mut read = 0;
+        while read < buffer.len() {
+            let at = position + read as u64;
+            let mut sector = [0; BLOCK_SIZE];
+            self.device.read(at / BLOCK_SIZE as u64, &mut sector).map_err(|_| Error::Io)?;
+            let start = (at % BLOCK_SIZE as u64) as usize;
+            let count = (BLOCK_SIZE - start).min(buffer.len() - read);
+            buffer[read..read + count].copy_from_slice(&sector[start..start + count]);
+            read += count;
+        }
+        Ok(())
+    }
+
+    fn read_u32(&self, position: u64) -> Result<u32, Error> {
+        let mut bytes = [0; 4];
+        self.read_bytes(position, &mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn inode(&self, number: u64) -> Result<Inode, Error> {
+        if number == 0 || number > u64::from(self.inodes) {
+            return Err(Error::NotFound);
+        }
+        let group = (number - 1) / u64::from(self.inodes_per_group);
+        let index = (number - 1) % u64::from(self.inodes_per_group);
+        // The inode table's block is 8 bytes into the group's descriptor
+        let table = self.read_u32(self.descriptors + group * DESCRIPTOR_SIZE + 8)?;
+        let mut inode = [0; OLD_INODE_SIZE as usize];
+        self.read_bytes(u64::from(table) * self.block_size + index * self.inode_size, &mut inode)?;
+        let long = |at: usize| u32::from_le_bytes(inode[at..at + 4].try_into().unwrap());
+        let mode = u16::from_le_bytes([inode[0], inode[1]]);
+        // Regular files keep the top half of their size where directories keep other things
+        let high = if mode & TYPE_MASK == REGULAR { long(108) } else { 0 };
+        Ok(Inode {
+            mode,
+            size: u64::from(high) << 32 | u64::from(long(4)),
+            sectors: long(28),
+            blocks: core::array::from_fn(|index| long(40 + index * 4)),
+        })
+    }
+
+    // The number of the block that is block `index` of the file, 0 for a hole
+    fn block(&self, inode: &Inode, index: u64) -> Result<u32, Error> {
+        let per_block = self.block_size / 4;
+        let indirect = |block: u32, index: u64| -> Result<u32, Error> {
+            if block == 0 {
+                return Ok(0);
+            }
+            self.read_u32(u64::from(block) * self.block_size + index * 4)
+        };
+        if index < DIRECT_BLOCKS {
+            return Ok(inode.blocks[index as usize]);
+        }
+        let index = index - DIRECT_BLOCKS;
+        if index < per_block {
+            return indirect(inode.blocks[12], index);
+        }
+        let index = index - per_block;
+        if index < per_block * per_block {
+            return indirect(indirect(inode.blocks[13], index / per_block)?, index % per_block);
+        }
+        let index = index - per_block * per_block;
+        if index < per_block * per_block * per_block {
+            let block = indirect(inode.blocks[14], index / (per_block * per_block))?;
+            return indirect(indirect(block, index / per_block % per_block)?, index % per_block);
+        }
+        Err(Error::Corrupt)
+    }
+
+    // Reads from the file `inode` at `offset` into `buffer`, and returns how many bytes it read
+    fn read(&self, inode: &Inode, offset: u64, buffer: &mut [u8]) -> Result<usize, Error> {
+        let length = inode.size.saturating_sub(offset).min(buffer.len() as u64) as usize;
+        if inode.is_fast_symlink() {
+            let target: [u8; 60] = core::array::from_fn(|index| inode.blocks[index / 4].to_le_bytes()[index % 4]);
+            let start = (offset as usize).min(target.len());
+            let length = length.min(target.len() - start);
+            buffer[..length].copy_from_slice(&target[start..start + length]);
+            return Ok(length);
+        }
+        let mut read = 0;
+        while read < length {
+            let position = offset + read as u64;
+            let start = position % self.block_size;
+            let count = ((self.block_size - start) as usize).min(length - read);
+            match self.block(inode, position / self.block_size)? {
+                0 => buffer[read..read + count].fill(0),
+                block => self.read_bytes(u64::from(block) * self.block_size + start, &mut buffer[read..read + count])?,
+            }
+            read += count;
+        }
+        Ok(read)
+    }
+
+    // Calls `visit` with the name and inode number of each entry in directory `directory`,
+    // leaving out . and .., until it returns true
+    fn each_entry(&self, directory: u64, mut visit: impl FnMut(&str, u64) -> bool) -> Result<(), Error> {
+        let inode = self.inode(directory)?;
+        if inode.kind() != Kind::Directory {
+            return Err(Error::NotADirectory);
+        }
+        let mut position = 0;
+        while position < inode.size {
+            // The inode, the length of the entry and of its name, then the name
+            let mut header = [0; 8];
+            if self.read(&inode, position, &mut header)? < header.len() {
+                return Err(Error::Corrupt);
+            }
+            let number = u32::from_le_bytes(header[..4].try_into().unwrap());
+            let length = u64::from(u16::from_le_bytes([header[4], header[5]]));
+            let name_length = usize::from(header[6]);
+            if length < 8 || length % 4 != 0 {
+                return Err(Error::Corrupt);
+            }
+            if number != 0 {
+                let mut name = [0; MAX_NAME];
+                self.read(&inode, position + 8, &mut name[..name_length])?;
+                let name = core::str::from_utf8(&name[..name_length]).unwrap_or("?");
+                if !matches!(name, "." | "..") && visit(name, u64::from(number)) {
+                    return Ok(());
+                }
+            }
+            position += length;
+        }
+        Ok(())
+    }
+}
+
+impl Ext2 {
+    fn volume(&self) -> Volume {
+        self.volume.lock_irq().expect("no ext2 volume opened")
+    }
+}
+
+impl Filesystem for Ext2 {
+    fn name(&self) -> &'static str {
+        "ext2"
+    }
+
+    fn root(&self) -> u64 {
+        ROOT
+    }
+
+    fn lookup(&self, directory: u64, name: &str) -> Result<u64, Error> {
+        let mut found = None;
+        self.volume().each_entry(directory, |entry, inode| {
+            if entry == name {
+                found = Some(inode);
+            }
+            found.is_some()
+        })?;
+        found.ok_or(Error::NotFound)
+    }
+
+    fn metadata(&self, inode: u64) -> Result<Metadata, Error> {
+        let inode = self.volume().inode(inode)?;
+        let size = if inode.kind() == Kind::Directory { 0 } else { inode.size };
+        Ok(Metadata { kind: inode.kind(), size })
+    }
+
+    fn read(&self, inode: u64, offset: u64, buffer: &mut [u8]) -> Result<usize, Error> {
+        let volume = self.volume();
+        let inode = volume.inode(inode)?;
+        if inode.kind() == Kind::Directory {
+            return Err(Error::IsADirectory);
+        }
+        volume.read(&inode, offset, buffer)
+    }
+
+    fn entry(&self, directory: u64, index: usize) -> Result<Option<DirEntry>, Error> {
+        let volume = self.volume();
+        let mut seen = 0;
+        let mut found = None;
+        volume.each_entry(directory, |name, inode| {
+            if seen == index {
+                let mut entry_name = TextBuffer::new();
+                write!(entry_name, "{name}").unwrap();
+                found = Some((entry_name, inode));
+            }
+            seen += 1;
+            found.is_some()
+        })?;
+        // Only the inode says what an entry is on filesystems without the file type in entries
+        let Some((name, inode)) = found else {
+            return Ok(None);
+        };
+        Ok(Some(DirEntry { name, kind: volume.inode(inode)?.kind() }))
+    }
+}
@@ -0,0 +1,897 @@
+use core::fmt::Write;
+use crate::block::{BlockDevice, BLOCK_SIZE};
+use crate::fs::{DirEntry, Error, Filesystem, Kind, Metadata, MAX_NAME};
+use crate::sleeplock::SleepLock;
+use crate::ui::TextBuffer;
+
+// FAT32 volumes. The boot sector (the BIOS parameter block) says where the file allocation
+// tables and the data area are; the data area is split into clusters, and each file or
+// directory is a chain of them, with the FAT holding the number of the cluster after each one,
+// or 0 for a free cluster. Directories are arrays of 32-byte entries: a short 8.3 entry for
+// each file, with its first cluster, size and attributes, preceded by long name entries
+// holding its name in pieces of 13 UTF-16 characters. Names are looked up ignoring ASCII case,
+// as on FAT.
+//
+// Writing allocates clusters as files grow, in every copy of the FAT, and keeps the count of
+// free clusters and where to look for the next one in the FSInfo sector up to date. Files
+// and directories made here get a long name unless their name fits 8.3 as it is. There is no
+// clock with the date, so they are all dated the start of 1980, the earliest FAT can store.
+//
+// Volumes must have 512-byte sectors, the size of a block.
+//
+// A change to a volume holds its `SleepLock` from start to finish, disk transfers and all, so
+// changes are made one at a time and reads start between them. Interrupts stay enabled, and
+// other tasks run while the disk works.
+//
+// Inode numbers: a file or directory is where its short entry is on the device, in bytes from
+// the start; the root directory, which has no entry, is 0.
+const MAX_VOLUMES: usize = 4;
+const ROOT: u64 = 0;
+const ENTRY_SIZE: usize = 32;
+// A name's first byte, for an entry that was deleted, and for the end of the directory
+const DELETED: u8 = 0xe5;
+const END: u8 = 0;
+// Attributes
+const VOLUME_LABEL: u8 = 0x08;
+const DIRECTORY: u8 = 0x10;
+const ARCHIVE: u8 = 0x20;
+const LONG_NAME: u8 = 0x0f;
+// Bits in a short entry's byte 12 saying that its name and extension are lowercase
+const LOWERCASE_NAME: u8 = 0x08;
+const LOWERCASE_EXTENSION: u8 = 0x10;
+// Where a short entry's creation, last access and last write dates are
+const DATES: [usize; 3] = [16, 18, 24];
+// The bit in a long name entry's number for the last piece of the name, which comes first
+const LAST_PIECE: u8 = 0x40;
+const PIECE_LENGTH: usize = 13;
+const MAX_PIECES: usize = 20;
+// Where the UTF-16 characters of a piece are in a long name entry
+const PIECE_BYTES: [core::ops::Range<usize>; 3] = [1..11, 14..26, 28..32];
+// Characters short names can have besides uppercase letters and digits
+const SHORT_NAME_SYMBOLS: &[u8] = b"!#$%&'()-@^_`{}~";
+// Characters no name can have, besides control characters
+const FORBIDDEN: &str = "\"*/:<>?\\|";
+// 1980-01-01: the year counts from 1980 in the top 7 bits, then come the month and the day
+const DATE: u16 = 1 << 5 | 1;
+// FAT32 cluster numbers are 28 bits, and ones from this on end a chain
+const CLUSTER_MASK: u32 = 0x0fff_ffff;
+const END_OF_CHAIN: u32 = 0x0fff_fff8;
+const FREE: u32 = 0;
+// The signatures at the start, middle and end of the FSInfo sector, and where its count of
+// free clusters and the cluster to look for free ones from are
+const INFO_SIGNATURES: [(usize, u32); 3] = [(0, 0x4161_5252), (484, 0x6141_7272), (508, 0xaa55_0000)];
+const INFO_FREE: usize = 488;
+const INFO_NEXT_FREE: usize = 492;
+const UNKNOWN: u32 = 0xffff_ffff;
+
+/// A FAT32 filesystem, once a volume is mounted in it.
+pub struct Fat {
+    volume: SleepLock<Option<Volume>>,
+}
+
+static VOLUMES: [Fat; MAX_VOLUMES] = [const { Fat { volume: SleepLock::new(None) } }; MAX_VOLUMES];
+
+/// Returns the filesystem for the FAT32 volume on `device`, to mount, if it has one and there
+/// is room for another.
+pub fn open(device: &'static dyn BlockDevice) -> Option<&'static Fat> {
+    let volume = Volume::new(device)?;
+    VOLUMES.iter().find(|fat| {
+        let mut slot = fat.volume.lock();
+        let free = slot.is_none();
+        if free {
+            *slot = Some(volume);
+        }
+        free
+    })
+}
+
+// Where a volume's parts are, and what is known about its free clusters
+#[derive(Clone, Copy)]
+struct Volume {
+    device: &'static dyn BlockDevice,
+    sectors_per_cluster: u64,
+    // The first sector of the first FAT, its size in sectors and how many copies there are,
+    // and the first sector of the data area, whose first cluster is 2
+    fat_start: u64,
+    fat_size: u64,
+    fats: u64,
+    data_start: u64,
+    root_cluster: u32,
+    clusters: u32,
+    // The FSInfo sector, if the volume has one
+    info_sector: Option<u64>,
+    free_clusters: Option<u32>,
+    next_free: u32,
+}
+
+// What a short entry says about a file or directory
+#[derive(Clone, Copy)]
+struct Short {
+    attributes: u8,
+    cluster: u32,
+    size: u32,
+}
+
+impl Short {
+    fn parse(entry: &[u8]) -> Short {
+        let high = u16::from_le_bytes([entry[20], entry[21]]);
+        let low = u16::from_le_bytes([entry[26], entry[27]]);
+        Short {
+            attributes: entry[11],
+            cluster: (u32::from(high) << 16 | u32::from(low)) & CLUSTER_MASK,
+            size: u32::from_le_bytes(entry[28..32].try_into().unwrap()),
+        }
+    }
+
+    fn kind(&self) -> Kind {
+        if self.attributes & DIRECTORY != 0 { Kind::Directory } else { Kind::File }
+    }
+}
+
+impl Volume {
+    // Reads the boot sector of `device`, if it has a FAT32 volume
+    fn new(device: &'static dyn BlockDevice) -> Option<Volume> {
+        let mut sector = [0; BLOCK_SIZE];
+        device.read(0, &mut sector).ok()?;
+        let word = |at: usize| u16::from_le_bytes([sector[at], sector[at + 1]]);
+        let long = |at: usize| u32::from_le_bytes(sector[at..at + 4].try_into().unwrap());
+        let sectors_per_cluster = sector[13];
+        let (reserved, fats, root_entries, fat16_size) = (word(14), sector[16], word(17), word(22));
+        let fat_size = u64::from(long(36));
+        // FAT32 has no fixed root directory and keeps the size of a FAT in a field of its own
+        if sector[510..] != [0x55, 0xaa] || usize::from(word(11)) != BLOCK_SIZE || root_entries != 0 || fat16_size != 0
+            || fat_size == 0 || !sectors_per_cluster.is_power_of_two() || fats == 0 {
+            return None;
+        }
+        let total = if word(19) != 0 { u32::from(word(19)) } else { long(32) };
+        let fat_start = u64::from(reserved);
+        let data_start = fat_start + u64::from(fats) * fat_size;
+        let sectors = u64::from(total).min(device.blocks());
+        let clusters = sectors.checked_sub(data_start)? / u64::from(sectors_per_cluster);
+        // The FAT has to have an entry for every cluster, and the first two aren't for any
+        let clusters = clusters.min(fat_size * (BLOCK_SIZE / 4) as u64 - 2).min(u64::from(CLUSTER_MASK)) as u32;
+        let mut volume = Volume {
+            device,
+            sectors_per_cluster: u64::from(sectors_per_cluster),
+            fat_start,
+            fat_size,
+            fats: u64::from(fats),
+            data_start,
+            root_cluster: long(44) & CLUSTER_MASK,
+            clusters,
+            info_sector: None,
+            free_clusters: None,
+            next_free: 2,
+        };
+        if !volume.valid_cluster(volume.root_cluster) {
+            return None;
+        }
+        let info_sector = u64::from(word(48));
+        if (1..fat_start).contains(&info_sector) {
+            volume.read_info(info_sector);
+        }
+        Some(volume)
+    }
+
+    // Takes what the FSInfo sector at `sector` knows about the free clusters, if it is one
+    fn read_info(&mut self, sector: u64) {
+        let mut info = [0; BLOCK_SIZE];
+        if self.read_sector(sector, &mut info).is_err() {
+            return;
+        }
+        let long = |at: usize| u32::from_le_bytes(info[at..at + 4].try_into().unwrap());
+        if INFO_SIGNATURES.iter().any(|&(at, signature)| long(at) != signature) {
+            return;
+        }
+        self.info_sector = Some(sector);
+        self.free_clusters = Some(long(INFO_FREE)).filter(|&free| free <= self.clusters);
+        if self.valid_cluster(long(INFO_NEXT_FREE)) {
+            self.next_free = long(INFO_NEXT_FREE);
+        }
+    }
+
+    // Writes what is known about the free clusters to the FSInfo sector, if there is one
+    fn write_info(&self) -> Result<(), Error> {
+        let Some(sector) = self.info_sector else {
+            return Ok(());
+        };
+        let mut info = [0; BLOCK_SIZE];
+        self.read_sector(sector, &mut info)?;
+        info[INFO_FREE..INFO_FREE + 4].copy_from_slice(&self.free_clusters.unwrap_or(UNKNOWN).to_le_bytes());
+        info[INFO_NEXT_FREE..INFO_NEXT_FREE + 4].copy_from_slice(&self.next_free.to_le_bytes());
+        self.write_sector(sector, &info)
+    }
+
+    fn read_sector(&self, sector: u64, buffer: &mut [u8; BLOCK_SIZE]) -> Result<(), Error> {
+        self.device.read(sector, buffer).map_err(|_| Error::Io)
+    }
+
+    fn write_sector(&self, sector: u64, bytes: &[u8; BLOCK_SIZE]) -> Result<(), Error> {
+        self.device.write(sector, bytes).map_err(|_| Error::Io)
+    }
+
+    fn cluster_size(&self) -> u64 {
+        self.sectors_per_cluster * BLOCK_SIZE as u64
+    }
+
+    fn valid_cluster(&self, cluster: u32) -> bool {
+        (2..self.clusters + 2).contains(&cluster)
+    }
+
+    fn cluster_sector(&self, cluster: u32) -> u64 {
+        self.data_start + u64::from(cluster - 2) * self.sectors_per_cluster
+    }
+
+    // The FAT entry of `cluster`
+    fn fat_entry(&self, cluster: u32) -> Result<u32, Error> {
+        let position = u64::from(cluster) * 4;
+        let mut sector = [0; BLOCK_SIZE];
+        self.read_sector(self.fat_start + position / BLOCK_SIZE as u64, &mut sector)?;
+        let at = (position % BLOCK_SIZE as u64) as usize;
+        Ok(u32::from_le_bytes(sector[at..at + 4].try_into().unwrap()) & CLUSTER_MASK)
+    }
+
+    // Sets the FAT entry of `cluster` to `value` in every copy of the FAT, keeping the top 4
+    // bits, which aren't part of it
+    fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<(), Error> {
+        let position = u64::from(cluster) * 4;
+        let at = (position % BLOCK_SIZE as u64) as usize;
+        for copy in 0..self.fats {
+            let mut sector = [0; BLOCK_SIZE];
+            let sector_number = self.fat_start + copy * self.fat_size + position / BLOCK_SIZE as u64;
+            self.read_sector(sector_number, &mut sector)?;
+            let old = u32::from_le_bytes(sector[at..at + 4].try_into().unwrap());
+            sector[at..at + 4].copy_from_slice(&(old & !CLUSTER_MASK | value).to_le_bytes());
+            self.write_sector(sector_number, &sector)?;
+        }
+        Ok(())
+    }
+
+    // The cluster after `cluster` in its chain, or None at the end of it
+    fn next_cluster(&self, cluster: u32) -> Result<Option<u32>, Error> {
+        let next = self.fat_entry(cluster)?;
+        if next >= END_OF_CHAIN {
+            return Ok(None);
+        }
+        if !self.valid_cluster(next) {
+            return Err(Error::Corrupt);
+        }
+        Ok(Some(next))
+    }
+
+    // Takes a free cluster, filled with zeroes, and puts it at the end of the chain after
+    // `previous`, if there is one
+    fn allocate(&mut self, previous: Option<u32>) -> Result<u32, Error> {
+        let start = if self.valid_cluster(self.next_free) { self.next_free } else { 2 };
+        let mut found = None;
+        for index in 0..self.clusters {
+            let cluster = 2 + (start - 2 + index) % self.clusters;
+            if self.fat_entry(cluster)? == FREE {
+                found = Some(cluster);
+                break;
+            }
+        }
+        let cluster = found.ok_or(Error::NoSpace)?;
+        let zeroes = [0; BLOCK_SIZE];
+        for index in 0..self.sectors_per_cluster {
+            self.write_sector(self.cluster_sector(cluster) + index, &zeroes)?;
+        }
+        self.set_fat_entry(cluster, CLUSTER_MASK)?;
+        if let Some(previous) = previous {
+            self.set_fat_entry(previous, cluster)?;
+        }
+        self.free_clusters = self.free_clusters.map(|free| free.saturating_sub(1));
+        self.next_free = cluster + 1;
+        self.write_info()?;
+        Ok(cluster)
+    }
+
+    // Frees the clusters in the chain from `cluster` on
+    fn free_chain(&mut self, cluster: u32) -> Result<(), Error> {
+        let mut cluster = cluster;
+        for _ in 0..self.clusters {
+            let next = self.next_cluster(cluster)?;
+            self.set_fat_entry(cluster, FREE)?;
+            self.free_clusters = self.free_clusters.map(|free| (free + 1).min(self.clusters));
+            match next {
+                Some(next) => cluster = next,
+                None => return self.write_info(),
+            }
+        }
+        Err(Error::Corrupt)
+    }
+
+    // The cluster `index` clusters into the chain from `cluster`, adding clusters to the end of
+    // the chain if `grow` and it is too short
+    fn follow(&mut self, cluster: u32, index: u64, grow: bool) -> Result<u32, Error> {
+        let mut cluster = cluster;
+        for _ in 0..index {
+            cluster = match self.next_cluster(cluster)? {
+                Some(next) => next,
+                None if grow => self.allocate(Some(cluster))?,
+                None => return Err(Error::Corrupt),
+            };
+        }
+        Ok(cluster)
+    }
+
+    // The short entry of inode `inode`, which isn't the root
+    fn short_entry(&self, inode: u64) -> Result<Short, Error> {
+        let mut sector = [0; BLOCK_SIZE];
+        self.read_sector(inode / BLOCK_SIZE as u64, &mut sector)?;
+        let at = (inode % BLOCK_SIZE as u64) as usize;
+        Ok(Short::parse(&sector[at..at + ENTRY_SIZE]))
+    }
+
+    // The short entry of `inode`, which has to be a file
+    fn file_entry(&self, inode: u64) -> Result<Short, Error> {
+        if inode == ROOT {
+            return Err(Error::IsADirectory);
+        }
+        let entry = self.short_entry(inode)?;
+        if entry.kind() == Kind::Directory {
+            return Err(Error::IsADirectory);
+        }
+        Ok(entry)
+    }
+
+    // Writes the 32-byte `entry` at `position` on the device
+    fn write_entry(&self, position: u64, entry: &[u8; ENTRY_SIZE]) -> Result<(), Error> {
+        let mut sector = [0; BLOCK_SIZE];
+        self.read_sector(position / BLOCK_SIZE as u64, &mut sector)?;
+        let at = (position % BLOCK_SIZE as u64) as usize;
+        sector[at..at + ENTRY_SIZE].copy_from_slice(entry);
+        self.write_sector(position / BLOCK_SIZE as u64, &sector)
+    }
+
+    // Sets the first cluster and size in the short entry of `inode`
+    fn update_entry(&self, inode: u64, cluster: u32, size: u32) -> Result<(), Error> {
+        let mut sector = [0; BLOCK_SIZE];
+        self.read_sector(inode / BLOCK_SIZE as u64, &mut sector)?;
+        let at = (inode % BLOCK_SIZE as u64) as usize;
+        let entry: &mut [u8; ENTRY_SIZE] = (&mut sector[at..at + ENTRY_SIZE]).try_into().unwrap();
+        set_cluster(entry, cluster);
+        entry[28..32].copy_from_slice(&size.to_le_bytes());
+        self.write_sector(inode / BLOCK_SIZE as u64, &sector)
+    }
+
+    // The first cluster of directory `inode`
+    fn directory_cluster(&self, inode: u64) -> Result<u32, Error> {
+        if inode == ROOT {
+            return Ok(self.root_cluster);
+        }
+        let entry = self.short_entry(inode)?;
+        if entry.kind() != Kind::Directory {
+            return Err(Error::NotADirectory);
+        }
+        if !self.valid_cluster(entry.cluster) {
+            return Err(Error::Corrupt);
+        }
+        Ok(entry.cluster)
+    }
+
+    // Calls `visit` with where each 32-byte slot of the directory starting at `cluster` is and
+    // what is in it, used or not, until it returns true. Returns the last cluster visited.
+    fn each_slot(&self, cluster: u32, mut visit: impl FnMut(u64, &[u8]) -> bool) -> Result<u32, Error> {
+        let mut cluster = cluster;
+        // A chain can't be longer than there are clusters, unless the FAT loops
+        for _ in 0..self.clusters {
+            for index in 0..self.sectors_per_cluster {
+                let mut sector = [0; BLOCK_SIZE];
+                let sector_number = self.cluster_sector(cluster) + index;
+                self.read_sector(sector_number, &mut sector)?;
+                for (slot, entry) in sector.chunks_exact(ENTRY_SIZE).enumerate() {
+                    if visit(sector_number * BLOCK_SIZE as u64 + (slot * ENTRY_SIZE) as u64, entry) {
+                        return Ok(cluster);
+                    }
+                }
+            }
+            match self.next_cluster(cluster)? {
+                Some(next) => cluster = next,
+                None => return Ok(cluster),
+            }
+        }
+        Err(Error::Corrupt)
+    }
+
+    // Calls `visit` with the name, inode and short entry of each file and directory in the
+    // directory starting at `cluster`, leaving out . and .., until it returns true
+    fn each_entry(&self, cluster: u32, mut visit: impl FnMut(&str, u64, &[u8]) -> bool) -> Result<(), Error> {
+        let mut long_name = LongName::new();
+        self.each_slot(cluster, |inode, entry| {
+            match entry[0] {
+                END => return true,
+                DELETED => {
+                    long_name.clear();
+                    return false;
+                },
+                _ => {},
+            }
+            if entry[11] & LONG_NAME == LONG_NAME {
+                long_name.add(entry);
+                return false;
+            }
+            let mut name = TextBuffer::<MAX_NAME>::new();
+            if long_name.is_for(entry) {
+                long_name.write(&mut name);
+            } else {
+                short_name(entry, &mut name);
+            }
+            long_name.clear();
+            if entry[11] & VOLUME_LABEL != 0 || matches!(name.as_str(), "." | "..") {
+                return false;
+            }
+            visit(name.as_str(), inode, entry)
+        })?;
+        Ok(())
+    }
+
+    // Whether an entry in the directory starting at `cluster` has short name `short`
+    fn has_short_name(&self, cluster: u32, short: &[u8; 11]) -> Result<bool, Error> {
+        let mut found = false;
+        self.each_slot(cluster, |_, entry| {
+            found = !matches!(entry[0], END | DELETED) && entry[11] & LONG_NAME != LONG_NAME && entry[..11] == short[..];
+            found || entry[0] == END
+        })?;
+        Ok(found)
+    }
+
+    // Finds `count` free slots in a row in the directory starting at `cluster`, adding
+    // clusters to it if there aren't, and writes where they are to `slots`
+    fn free_slots(&mut self, cluster: u32, count: usize, slots: &mut [u64; MAX_PIECES + 1]) -> Result<(), Error> {
+        let mut found = 0;
+        let mut last = self.each_slot(cluster, |position, entry| {
+            if matches!(entry[0], END | DELETED) {
+                slots[found] = position;
+                found += 1;
+            } else {
+                found = 0;
+            }
+            found == count
+        })?;
+        // The free slots at the end of the directory carry on into the clusters added to it
+        while found < count {
+            last = self.allocate(Some(last))?;
+            let start = self.cluster_sector(last) * BLOCK_SIZE as u64;
+            let slots_per_cluster = (self.cluster_size() / ENTRY_SIZE as u64) as usize;
+            for slot in 0..slots_per_cluster.min(count - found) {
+                slots[found] = start + (slot * ENTRY_SIZE) as u64;
+                found += 1;
+            }
+        }
+        Ok(())
+    }
+
+    // Makes an empty file or directory called `name` in directory `directory`
+    fn create(&mut self, directory: u64, name: &str, kind: Kind) -> Result<u64, Error> {
+        let cluster = self.directory_cluster(directory)?;
+        if !valid_name(name) {
+            return Err(Error::InvalidPath);
+        }
+        let (short, case) = match fits_short(name) {
+            Some((short, case)) if !self.has_short_name(cluster, &short)? => (short, Some(case)),
+            _ => (self.numbered_short_name(cluster, name)?, None),
+        };
+        // Names that don't fit 8.3 as they are get a long name too
+        let pieces = if case.is_some() { 0 } else { name.encode_utf16().count().div_ceil(PIECE_LENGTH) };
+        let mut slots = [0; MAX_PIECES + 1];
+        self.free_slots(cluster, pieces + 1, &mut slots)?;
+        let mut entry = [0; ENTRY_SIZE];
+        entry[..11].copy_from_slice(&short);
+        entry[12] = case.unwrap_or(0);
+        for at in DATES {
+            entry[at..at + 2].copy_from_slice(&DATE.to_le_bytes());
+        }
+        if kind == Kind::Directory {
+            entry[11] = DIRECTORY;
+            let first = self.allocate(None)?;
+            set_cluster(&mut entry, first);
+            self.write_dot_entries(first, if directory == ROOT { 0 } else { cluster })?;
+        } else {
+            entry[11] = ARCHIVE;
+        }
+        // The long name's pieces come last first, just before the short entry
+        let checksum = checksum(&short);
+        for (index, &slot) in slots[..pieces].iter().enumerate() {
+            let number = pieces - index;
+            self.write_entry(slot, &long_name_entry(name, number, number == pieces, checksum))?;
+        }
+        self.write_entry(slots[pieces], &entry)?;
+        Ok(slots[pieces])
+    }
+
+    // Writes the . and .. entries at the start of new directory `cluster`, whose parent starts
+    // at `parent`, or is the root if that is 0
+    fn write_dot_entries(&self, cluster: u32, parent: u32) -> Result<(), Error> {
+        let start = self.cluster_sector(cluster) * BLOCK_SIZE as u64;
+        for (index, (name, target)) in [(".", cluster), ("..", parent)].into_iter().enumerate() {
+            let mut entry = [0; ENTRY_SIZE];
+            entry[..11].fill(b' ');
+            entry[..name.len()].copy_from_slice(name.as_bytes());
+            entry[11] = DIRECTORY;
+            for at in DATES {
+                entry[at..at + 2].copy_from_slice(&DATE.to_le_bytes());
+            }
+            set_cluster(&mut entry, target);
+            self.write_entry(start + (index * ENTRY_SIZE) as u64, &entry)?;
+        }
+        Ok(())
+    }
+
+    // A short name for `name` that no entry in the directory starting at `cluster` has: as
+    // much of its name and extension as fits, with ~ and a number
+    fn numbered_short_name(&self, cluster: u32, name: &str) -> Result<[u8; 11], Error> {
+        let (base, extension) = name.trim_start_matches('.').rsplit_once('.').unwrap_or((name, ""));
+        let mut short = [b' '; 11];
+        let mut base_length = 0;
+        for byte in base.bytes().filter_map(short_name_byte).take(8) {
+            short[base_length] = byte;
+            base_length += 1;
+        }
+        for (slot, byte) in short[8..].iter_mut().zip(extension.bytes().filter_map(short_name_byte)) {
+            *slot = byte;
+        }
+        for number in 1..1_000_000u32 {
+            let mut tail = TextBuffer::<8>::new();
+            write!(tail, "~{number}").unwrap();
+            let tail = tail.as_str().as_bytes();
+            let start = base_length.min(8 - tail.len());
+            short[start..start + tail.len()].copy_from_slice(tail);
+            short[start + tail.len()..8].fill(b' ');
+            if !self.has_short_name(cluster, &short)? {
+                return Ok(short);
+            }
+        }
+        Err(Error::NoSpace)
+    }
+
+    // Writes `bytes` to file `inode` at `offset`, which can't be past its end
+    fn write_file(&mut self, inode: u64, offset: u64, bytes: &[u8]) -> Result<usize, Error> {
+        let entry = self.file_entry(inode)?;
+        // Files can't be 4 GiB or bigger
+        let length = u64::from(u32::MAX).saturating_sub(offset).min(bytes.len() as u64) as usize;
+        if length == 0 {
+            return if bytes.is_empty() { Ok(0) } else { Err(Error::NoSpace) };
+        }
+        let mut first = entry.cluster;
+        if first == 0 {
+            first = self.allocate(None)?;
+            self.update_entry(inode, first, entry.size)?;
+        }
+        let mut cluster = self.follow(first, offset / self.cluster_size(), true)?;
+        let mut position = offset % self.cluster_size();
+        let mut written = 0;
+        while written < length {
+            if position == self.cluster_size() {
+                // Running out of room ends the write early, with what fitted
+                cluster = match self.follow(cluster, 1, true) {
+                    Ok(next) => next,
+                    Err(Error::NoSpace) if written > 0 => break,
+                    Err(error) => return Err(error),
+                };
+                position = 0;
+            }
+            let mut sector = [0; BLOCK_SIZE];
+            let sector_number = self.cluster_sector(cluster) + position / BLOCK_SIZE as u64;
+            let start = (position % BLOCK_SIZE as u64) as usize;
+            let count = (BLOCK_SIZE - start).min(length - written);
+            if count < BLOCK_SIZE {
+                self.read_sector(sector_number, &mut sector)?;
+            }
+            sector[start..start + count].copy_from_slice(&bytes[written..written + count]);
+            self.write_sector(sector_number, &sector)?;
+            written += count;
+            position += count as u64;
+        }
+        let size = entry.size.max((offset + written as u64) as u32);
+        self.update_entry(inode, first, size)?;
+        Ok(written)
+    }
+
+    // Writes zeroes to file `inode` from its end up to `end`
+    fn fill_zeroes(&mut self, inode: u64, end: u64) -> Result<(), Error> {
+        let zeroes = [0; BLOCK_SIZE];
+        let mut size = u64::from(self.file_entry(inode)?.size);
+        while size < end {
+            let written = self.write_file(inode, size, &zeroes[..(end - size).min(BLOCK_SIZE as u64) as usize])?;
+            if written == 0 {
+                return Err(Error::NoSpace);
+            }
+            size += written as u64;
+        }
+        Ok(())
+    }
+
+    // Cuts file `inode` down to `size` bytes, freeing the clusters it no longer needs
+    fn shrink(&mut self, inode: u64, size: u64) -> Result<(), Error> {
+        let entry = self.file_entry(inode)?;
+        if entry.cluster == 0 {
+            return Ok(());
+        }
+        let keep = size.div_ceil(self.cluster_size());
+        if keep == 0 {
+            self.update_entry(inode, 0, 0)?;
+            return self.free_chain(entry.cluster);
+        }
+        let last = self.follow(entry.cluster, keep - 1, false)?;
+        let rest = self.next_cluster(last)?;
+        self.update_entry(inode, entry.cluster, size as u32)?;
+        if let Some(rest) = rest {
+            self.set_fat_entry(last, CLUSTER_MASK)?;
+            self.free_chain(rest)?;
+        }
+        Ok(())
+    }
+}
+
+// Sets the first cluster in short entry `entry`
+fn set_cluster(entry: &mut [u8; ENTRY_SIZE], cluster: u32) {
+    entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+}
+
+// The checksum of a short name, which its long name entries hold
+fn checksum(short: &[u8]) -> u8 {
+    short.iter().fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+// Whether `name` can be stored
+fn valid_name(name: &str) -> bool {
+    let length = name.encode_utf16().count();
+    // Names that end in a dot or a space lose them on other systems
+    (1..=MAX_NAME).contains(&length) && !name.ends_with(['.', ' '])
+        && !name.chars().any(|character| character.is_control() || FORBIDDEN.contains(character))
+}
+
+// `name` as a short name, with the case bits for it, if it fits 8.3 as it is
+fn fits_short(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, extension) = name.split_once('.').unwrap_or((name, ""));
+    if base.is_empty() || base.len() > 8 || extension.len() > 3 || extension.contains('.') {
+        return None;
+    }
+    let mut short = [b' '; 11];
+    let mut case = 0;
+    let (base_slots, extension_slots) = short.split_at_mut(8);
+    for (part, slots, lowercase) in [(base, base_slots, LOWERCASE_NAME), (extension, extension_slots, LOWERCASE_EXTENSION)] {
+        // Each part can be all lowercase or all uppercase, but not both
+        let lower = part.bytes().any(|byte| byte.is_ascii_lowercase());
+        if lower && part.bytes().any(|byte| byte.is_ascii_uppercase()) {
+            return None;
+        }
+        if lower {
+            case |= lowercase;
+        }
+        for (slot, byte) in slots.iter_mut().zip(part.bytes()) {
+            let byte = byte.to_ascii_uppercase();
+            if !byte.is_ascii_alphanumeric() && !SHORT_NAME_SYMBOLS.contains(&byte) {
+                return None;
+            }
+            *slot = byte;
+        }
+    }
+    Some((short, case))
+}
+
+// What `byte` of a long name becomes in a short name: uppercase, _ for what short names can't
+// have, and nothing for dots and spaces
+fn short_name_byte(byte: u8) -> Option<u8> {
+    let byte = byte.to_ascii_uppercase();
+    match byte {
+        b'.' | b' ' => None,
+        _ if byte.is_ascii_alphanumeric() || SHORT_NAME_SYMBOLS.contains(&byte) => Some(byte),
+        // A character that isn't ASCII is several bytes, and only the first becomes a _
+        0x80..=0xbf => None,
+        _ => Some(b'_'),
+    }
+}
+
+// Long name entry number `number` of `name`, with piece `number` of its characters
+fn long_name_entry(name: &str, number: usize, last: bool, checksum: u8) -> [u8; ENTRY_SIZE] {
+    let mut entry = [0; ENTRY_SIZE];
+    entry[0] = number as u8 | if last { LAST_PIECE } else { 0 };
+    entry[11] = LONG_NAME;
+    entry[13] = checksum;
+    // The name ends with a zero, if there is room, then 0xffff to the end of the piece
+    let mut characters = name.encode_utf16().chain([0]).chain(core::iter::repeat(0xffff)).skip((number - 1) * PIECE_LENGTH);
+    for range in PIECE_BYTES {
+        for bytes in entry[range].chunks_exact_mut(2) {
+            bytes.copy_from_slice(&characters.next().unwrap().to_le_bytes());
+        }
+    }
+    entry
+}
+
+// A long name, put together from the long name entries before a short entry
+struct LongName {
+    characters: [u16; MAX_PIECES * PIECE_LENGTH],
+    // The pieces there are, the number of the piece expected next, counting down to 1, and the
+    // checksum of the short name they belong to
+    pieces: u8,
+    next: u8,
+    checksum: u8,
+}
+
+impl LongName {
+    fn new() -> LongName {
+        LongName { characters: [0; MAX_PIECES * PIECE_LENGTH], pieces: 0, next: 0, checksum: 0 }
+    }
+
+    fn clear(&mut self) {
+        self.pieces = 0;
+        self.next = 0;
+    }
+
+    fn add(&mut self, entry: &[u8]) {
+        let number = entry[0] & !LAST_PIECE;
+        if entry[0] & LAST_PIECE != 0 {
+            self.pieces = number;
+            self.next = number;
+            self.checksum = entry[13];
+        }
+        // Pieces out of order, or with a different checksum, make the name unusable
+        if number == 0 || number != self.next || entry[13] != self.checksum || usize::from(number) > MAX_PIECES {
+            self.clear();
+            return;
+        }
+        let start = (usize::from(number) - 1) * PIECE_LENGTH;
+        let pieces = PIECE_BYTES.into_iter().flat_map(|range| entry[range].chunks_exact(2));
+        for (character, bytes) in self.characters[start..start + PIECE_LENGTH].iter_mut().zip(pieces) {
+            *character = u16::from_le_bytes([bytes[0], bytes[1]]);
+        }
+        self.next -= 1;
+    }
+
+    // Whether the name is whole and belongs to short entry `entry`
+    fn is_for(&self, entry: &[u8]) -> bool {
+        self.pieces > 0 && self.next == 0 && self.checksum == checksum(&entry[..11])
+    }
+
+    fn write(&self, name: &mut TextBuffer<MAX_NAME>) {
+        // The name ends at a zero, and the rest of the last piece is padded with 0xffff
+        let length = usize::from(self.pieces) * PIECE_LENGTH;
+        let characters = self.characters[..length].iter().copied().take_while(|&character| character != 0);
+        for character in char::decode_utf16(characters) {
+            write!(name, "{}", character.unwrap_or(char::REPLACEMENT_CHARACTER)).unwrap();
+        }
+    }
+}
+
+// Writes the 8.3 name in short entry `entry` as name.extension, or just name
+fn short_name(entry: &[u8], name: &mut TextBuffer<MAX_NAME>) {
+    let write_part = |name: &mut TextBuffer<MAX_NAME>, part: &[u8], lowercase: bool| {
+        let length = part.iter().rposition(|&byte| byte != b' ').map_or(0, |last| last + 1);
+        for (index, &byte) in part[..length].iter().enumerate() {
+            // A first byte of 0xe5 is stored as 0x05, since 0xe5 marks deleted entries
+            let byte = if index == 0 && byte == 0x05 { DELETED } else { byte };
+            let character = if byte.is_ascii() { byte as char } else { '?' };
+            let character = if lowercase { character.to_ascii_lowercase() } else { character };
+            write!(name, "{character}").unwrap();
+        }
+    };
+    write_part(name, &entry[..8], entry[12] & LOWERCASE_NAME != 0);
+    if entry[8..11].iter().any(|&byte| byte != b' ') {
+        write!(name, ".").unwrap();
+        write_part(name, &entry[8..11], entry[12] & LOWERCASE_EXTENSION != 0);
+    }
+}
+
+impl Fat {
+    fn volume(&self) -> Volume {
+        self.volume.lock().expect("no FAT32 volume mounted")
+    }
+
+    // Runs `change` on the volume, holding it so that nothing else changes it meanwhile
+    fn change<T>(&self, change: impl FnOnce(&mut Volume) -> Result<T, Error>) -> Result<T, Error> {
+        let mut volume = self.volume.lock();
+        change(volume.as_mut().expect("no FAT32 volume mounted"))
+    }
+}
+
+impl Filesystem for Fat {
+    fn name(&self) -> &'static str {
+        "fat32"
+    }
+
+    fn sync(&self) -> Result<(), Error> {
+        self.volume().device.flush().map_err(|_| Error::Io)
+    }
+
+    fn root(&self) -> u64 {
+        ROOT
+    }
+
+    fn lookup(&self, directory: u64, name: &str) -> Result<u64, Error> {
+        let volume = self.volume();
+        let mut found = None;
+        volume.each_entry(volume.directory_cluster(directory)?, |entry, inode, _| {
+            if entry.eq_ignore_ascii_case(name) {
+                found = Some(inode);
+            }
+            found.is_some()
+        })?;
+        found.ok_or(Error::NotFound)
+    }
+
+    fn metadata(&self, inode: u64) -> Result<Metadata, Error> {
+        if inode == ROOT {
+            return Ok(Metadata { kind: Kind::Directory, size: 0 });
+        }
+        let entry = self.volume().short_entry(inode)?;
+        let size = if entry.kind() == Kind::File { u64::from(entry.size) } else { 0 };
+        Ok(Metadata { kind: entry.kind(), size })
+    }
+
+    fn read(&self, inode: u64, offset: u64, buffer: &mut [u8]) -> Result<usize, Error> {
+        let mut volume = self.volume();
+        let entry = volume.file_entry(inode)?;
+        let length = (u64::from(entry.size).saturating_sub(offset)).min(buffer.len() as u64) as usize;
+        if length == 0 {
+            return Ok(0);
+        }
+        // Follow the chain to the cluster `offset` is in
+        if !volume.valid_cluster(entry.cluster) {
+            return Err(Error::Corrupt);
+        }
+        let mut cluster = volume.follow(entry.cluster, offset / volume.cluster_size(), false)?;
+        let mut position = offset % volume.cluster_size();
+        let mut read = 0;
+        while read < length {
+            if position == volume.cluster_size() {
+                cluster = volume.next_cluster(cluster)?.ok_or(Error::Corrupt)?;
+                position = 0;
+            }
+            let mut sector = [0; BLOCK_SIZE];
+            volume.read_sector(volume.cluster_sector(cluster) + position / BLOCK_SIZE as u64, &mut sector)?;
+            let start = (position % BLOCK_SIZE as u64) as usize;
+            let count = (BLOCK_SIZE - start).min(length - read);
+            buffer[read..read + count].copy_from_slice(&sector[start..start + count]);
+            read += count;
+            position += count as u64;
+        }
+        Ok(read)
+    }
+
+    fn entry(&self, directory: u64, index: usize) -> Result<Option<DirEntry>, Error> {
+        let volume = self.volume();
+        let mut seen = 0;
+        let mut found = None;
+        volume.each_entry(volume.directory_cluster(directory)?, |name, _, entry| {
+            if seen == index {
+                let mut entry_name = TextBuffer::new();
+                write!(entry_name, "{name}").unwrap();
+                found = Some(DirEntry { name: entry_name, kind: Short::parse(entry).kind() });
+            }
+            seen += 1;
+            found.is_some()
+        })?;
+        Ok(found)
+    }
+
+    fn write(&self, inode: u64, offset: u64, bytes: &[u8]) -> Result<usize, Error> {
+        if offset >= u64::from(u32::MAX) && !bytes.is_empty() {
+            return Err(Error::NoSpace);
+        }
+        self.change(|volume| {
+            // What is between the end of the file and `offset` reads as zeroes
+            volume.fill_zeroes(inode, offset)?;
+            volume.write_file(inode, offset, bytes)
+        })
+    }
+
+    fn truncate(&self, inode: u64, size: u64) -> Result<(), Error> {
+        if size > u64::from(u32::MAX) {
+            return Err(Error::NoSpace);
+        }
+        self.change(|volume| {
+            if size > u64::from(volume.file_entry(inode)?.size) {
+                volume.fill_zeroes(inode, size)
+            } else {
+                volume.shrink(inode, size)
+            }
+        })
+    }
+
+    fn create(&self, directory: u64, name: &str, kind: Kind) -> Result<u64, Error> {
+        self.change(|volume| volume.create(directory, name, kind))
+    }
+}
@@ -0,0 +1,652 @@
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use kernel::serial;
+use kernel::sync::SpinLock;
+use crate::{block, cache, ext2, fat, process, syscall};
+use crate::block::{BlockDevice, BLOCK_SIZE};
+use crate::process::Handle;
+use crate::ui::TextBuffer;
+
+// The virtual filesystem: filesystems are mounted at paths, and `open` and `lookup` find the
+// one a path is in and walk the rest of the path through its directories, so every
+// filesystem is reached the same way. A path is in the filesystem mounted at the longest
+// mount point it starts with. Symbolic links are followed wherever they are in a path, with
+// relative targets taken from the directory the link is in. Paths here are absolute; a
+// `WorkingDirectory`, which the shell and each process have, makes relative ones absolute.
+// `.` and `..` are taken as written, so `..` after a link goes back to where the link is.
+//
+// A filesystem numbers its files and directories, its inodes, however suits it. An `Inode` is
+// one of them in a mounted filesystem, and a `File` is a file open for reading and writing
+// from an offset. Both are plain values: the kernel heap never frees, so nothing is allocated
+// to open or read a file, and a process's open files are kept in its handles. The open, seek
+// and metadata system calls are here; reads, writes and closing go through the handle system
+// calls like for any other handle.
+const MAX_MOUNTS: usize = 8;
+// The most components a path may have, the longest it can be once links are followed, and
+// the most links followed in one path, so that links to each other can't go on forever
+const MAX_DEPTH: usize = 32;
+/// The longest a path can be.
+pub const MAX_PATH: usize = 1024;
+const MAX_LINKS: usize = 8;
+// Where the filesystems on the block devices are mounted, by the device's number
+const DISKS: [&str; block::MAX_DEVICES] = ["/disk0", "/disk1", "/disk2", "/disk3", "/disk4", "/disk5", "/disk6", "/disk7"];
+/// The longest name a directory entry can have.
+pub const MAX_NAME: usize = 255;
+// `open` flags: make the file if there is none, empty it, and start at its end
+const CREATE: u64 = 1;
+const TRUNCATE: u64 = 2;
+const APPEND: u64 = 4;
+
+/// Why a filesystem operation failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    /// There is something with that name already.
+    Exists,
+    /// A directory that still has something in it.
+    NotEmpty,
+    /// The filesystem can't be written to.
+    ReadOnly,
+    /// The filesystem has no room left.
+    NoSpace,
+    /// A path that isn't absolute, is too deep or too long once links are followed, or has a
+    /// name the filesystem can't store.
+    InvalidPath,
+    /// There is a filesystem mounted there or from that device already, or no room for another.
+    CantMount,
+    /// The device the filesystem is on failed.
+    Io,
+    /// The filesystem found something wrong with what it has stored.
+    Corrupt,
+    /// A seek to before the start of a file, or past the largest offset there can be.
+    InvalidOffset,
+}
+
+/// What an inode is, numbered as the metadata system call gives it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    File = 0,
+    Directory = 1,
+    /// A symbolic link, whose contents are the path it links to.
+    Symlink = 2,
+}
+
+/// What there is to know about an inode.
+#[derive(Debug, Clone, Copy)]
+pub struct Metadata {
+    pub kind: Kind,
+    /// The bytes a file has; 0 for directories.
+    pub size: u64,
+}
+
+/// An entry in a directory.
+#[derive(Clone, Copy)]
+pub struct DirEntry {
+    pub name: TextBuffer<MAX_NAME>,
+    pub kind: Kind,
+}
+
+/// A filesystem that can be mounted. Inodes are numbered by the filesystem, which is given
+/// only numbers it has handed out itself.
+pub trait Filesystem: Sync {
+    /// What kind of filesystem it is, such as "initrd".
+    fn name(&self) -> &'static str;
+    /// The root directory.
+    fn root(&self) -> u64;
+    /// Finds `name` in `directory`.
+    fn lookup(&self, directory: u64, name: &str) -> Result<u64, Error>;
+    fn metadata(&self, inode: u64) -> Result<Metadata, Error>;
+    /// Reads from file `inode` at `offset` into `buffer`, and returns how many bytes it read,
+    /// none at the end of the file.
+    fn read(&self, inode: u64, offset: u64, buffer: &mut [u8]) -> Result<usize, Error>;
+    /// Returns entry number `index` of `directory`, or None past the last one.
+    fn entry(&self, directory: u64, index: usize) -> Result<Option<DirEntry>, Error>;
+    /// Writes `bytes` to file `inode` at `offset`, growing the file if need be, and returns how
+    /// many bytes it wrote.
+    fn write(&self, _inode: u64, _offset: u64, _bytes: &[u8]) -> Result<usize, Error> {
+        Err(Error::ReadOnly)
+    }
+    /// Makes file `inode` `size` bytes long, cutting off its end or adding zeroes.
+    fn truncate(&self, _inode: u64, _size: u64) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+    /// Makes an empty file or directory called `name` in `directory`, which has nothing called
+    /// that, and returns its inode.
+    fn create(&self, _directory: u64, _name: &str, _kind: Kind) -> Result<u64, Error> {
+        Err(Error::ReadOnly)
+    }
+    /// Removes what is called `name` from `directory`: a file, a symbolic link or a directory,
+    /// which must be empty.
+    fn remove(&self, _directory: u64, _name: &str) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+    /// Makes sure every change so far is on the disk the filesystem is on, not in its cache.
+    fn sync(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+struct Mount {
+    path: &'static str,
+    filesystem: &'static dyn Filesystem,
+}
+
+static MOUNTS: SpinLock<[Option<Mount>; MAX_MOUNTS]> = SpinLock::new([const { None }; MAX_MOUNTS]);
+// How many filesystem operations are under way, on any task
+static BUSY: AtomicUsize = AtomicUsize::new(0);
+
+// Counts a filesystem operation as under way until it is dropped
+struct Busy;
+
+impl Busy {
+    fn new() -> Busy {
+        BUSY.fetch_add(1, Ordering::SeqCst);
+        Busy
+    }
+}
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        BUSY.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A file or directory in a mounted filesystem.
+#[derive(Clone, Copy)]
+pub struct Inode {
+    filesystem: &'static dyn Filesystem,
+    number: u64,
+}
+
+impl Inode {
+    pub fn metadata(&self) -> Result<Metadata, Error> {
+        let _busy = Busy::new();
+        self.filesystem.metadata(self.number)
+    }
+
+    /// Reads from the file at `offset` into `buffer`, and returns how many bytes it read, none
+    /// at the end of the file.
+    pub fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, Error> {
+        let _busy = Busy::new();
+        self.filesystem.read(self.number, offset, buffer)
+    }
+
+    /// Writes `bytes` to the file at `offset`, and returns how many bytes it wrote.
+    pub fn write_at(&self, offset: u64, bytes: &[u8]) -> Result<usize, Error> {
+        let _busy = Busy::new();
+        let written = self.filesystem.write(self.number, offset, bytes)?;
+        self.filesystem.sync()?;
+        Ok(written)
+    }
+
+    /// Makes the file `size` bytes long, cutting off its end or adding zeroes.
+    pub fn truncate(&self, size: u64) -> Result<(), Error> {
+        let _busy = Busy::new();
+        self.filesystem.truncate(self.number, size)?;
+        self.filesystem.sync()
+    }
+
+    /// Returns entry number `index` of the directory, or None past the last one.
+    pub fn entry(&self, index: usize) -> Result<Option<DirEntry>, Error> {
+        let _busy = Busy::new();
+        self.filesystem.entry(self.number, index)
+    }
+
+    /// Finds `name` in the directory.
+    pub fn child(&self, name: &str) -> Result<Inode, Error> {
+        let _busy = Busy::new();
+        if self.metadata()?.kind != Kind::Directory {
+            return Err(Error::NotADirectory);
+        }
+        Ok(Inode { filesystem: self.filesystem, number: self.filesystem.lookup(self.number, name)? })
+    }
+
+    // Makes a `kind` called `name` in the directory
+    fn create(&self, name: &str, kind: Kind) -> Result<Inode, Error> {
+        let _busy = Busy::new();
+        match self.child(name) {
+            Ok(_) => return Err(Error::Exists),
+            Err(Error::NotFound) => {},
+            Err(error) => return Err(error),
+        }
+        let number = self.filesystem.create(self.number, name, kind)?;
+        self.filesystem.sync()?;
+        Ok(Inode { filesystem: self.filesystem, number })
+    }
+
+    // Removes `name` from the directory
+    fn remove(&self, name: &str) -> Result<(), Error> {
+        let _busy = Busy::new();
+        if self.metadata()?.kind != Kind::Directory {
+            return Err(Error::NotADirectory);
+        }
+        self.filesystem.remove(self.number, name)?;
+        self.filesystem.sync()
+    }
+}
+
+/// An open file, read and written from where the last read or write left off.
+#[derive(Clone, Copy)]
+pub struct File {
+    inode: Inode,
+    offset: u64,
+}
+
+/// Where `File::seek` moves to: an offset from the start of the file, from where it is now,
+/// or from the end.
+#[derive(Debug, Clone, Copy)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+impl File {
+    /// Opens the file at `path`, which must be absolute, to read and write from its start.
+    pub fn open(path: &str) -> Result<File, Error> {
+        let inode = lookup(path)?;
+        if inode.metadata()?.kind == Kind::Directory {
+            return Err(Error::IsADirectory);
+        }
+        Ok(File { inode, offset: 0 })
+    }
+
+    /// Opens the file at `path` to write it over: it is made if there is none, and emptied if
+    /// there is.
+    pub fn create(path: &str) -> Result<File, Error> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(Error::NotFound) => return make(path, Kind::File).map(|inode| File { inode, offset: 0 }),
+            Err(error) => return Err(error),
+        };
+        file.inode.truncate(0)?;
+        Ok(file)
+    }
+
+    /// Opens the file at `path` to write at its end, making it if there is none.
+    pub fn append(path: &str) -> Result<File, Error> {
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(Error::NotFound) => File::create(path)?,
+            Err(error) => return Err(error),
+        };
+        file.seek(SeekFrom::End(0))?;
+        Ok(file)
+    }
+
+    /// Reads into `buffer`, and returns how many bytes it read, none at the end of the file.
+    pub fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Error> {
+        let read = self.inode.read_at(self.offset, buffer)?;
+        self.offset += read as u64;
+        Ok(read)
+    }
+
+    /// Writes `bytes`, and returns how many bytes it wrote.
+    pub fn write(&mut self, bytes: &[u8]) -> Result<usize, Error> {
+        let written = self.inode.write_at(self.offset, bytes)?;
+        self.offset += written as u64;
+        Ok(written)
+    }
+
+    /// Moves where the next read or write starts, and returns the offset from the start of the
+    /// file. Moving past the end is fine: writing there fills the gap with zeroes.
+    pub fn seek(&mut self, position: SeekFrom) -> Result<u64, Error> {
+        self.offset = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(delta) => self.offset.checked_add_signed(delta),
+            SeekFrom::End(delta) => self.metadata()?.size.checked_add_signed(delta),
+        }.ok_or(Error::InvalidOffset)?;
+        Ok(self.offset)
+    }
+
+    pub fn metadata(&self) -> Result<Metadata, Error> {
+        self.inode.metadata()
+    }
+
+    /// Closes the file. What was written to it is in the filesystem already, so this is the
+    /// same as dropping it.
+    pub fn close(self) {}
+}
+
+impl fmt::Debug for File {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "File({} inode {}, at {})", self.inode.filesystem.name(), self.inode.number, self.offset)
+    }
+}
+
+/// The entries of a directory, from `read_dir`.
+pub struct ReadDir {
+    directory: Inode,
+    // The next entry, or None after an error
+    index: Option<usize>,
+}
+
+impl Iterator for ReadDir {
+    type Item = Result<DirEntry, Error>;
+
+    fn next(&mut self) -> Option<Result<DirEntry, Error>> {
+        let index = self.index?;
+        match self.directory.entry(index) {
+            Ok(entry) => {
+                self.index = entry.is_some().then_some(index + 1);
+                entry.map(Ok)
+            },
+            Err(error) => {
+                self.index = None;
+                Some(Err(error))
+            },
+        }
+    }
+}
+
+/// A current directory, that relative paths are taken from.
+#[derive(Clone, Copy)]
+pub struct WorkingDirectory {
+    // The path without `.`, `..` or repeated slashes; empty for the root
+    path: TextBuffer<MAX_PATH>,
+}
+
+impl WorkingDirectory {
+    pub const fn root() -> WorkingDirectory {
+        WorkingDirectory { path: TextBuffer::new() }
+    }
+
+    pub fn path(&self) -> &str {
+        match self.path.as_str() {
+            "" => "/",
+            path => path,
+        }
+    }
+
+    /// Returns `path` if it is absolute, or else the path to it from this directory.
+    pub fn resolve(&self, path: &str) -> Result<TextBuffer<MAX_PATH>, Error> {
+        let mut absolute = TextBuffer::<MAX_PATH>::new();
+        if !path.starts_with('/') {
+            write!(absolute, "{}", self.path.as_str()).unwrap();
+        }
+        write!(absolute, "/{path}").unwrap();
+        if absolute.as_str().len() == MAX_PATH {
+            return Err(Error::InvalidPath);
+        }
+        Ok(absolute)
+    }
+
+    /// Moves to the directory at `path`, which may be relative.
+    pub fn change(&mut self, path: &str) -> Result<(), Error> {
+        let absolute = self.resolve(path)?;
+        if lookup(absolute.as_str())?.metadata()?.kind != Kind::Directory {
+            return Err(Error::NotADirectory);
+        }
+        let (names, depth) = components(absolute.as_str())?;
+        let mut path = TextBuffer::new();
+        names[..depth].iter().for_each(|name| write!(path, "/{name}").unwrap());
+        self.path = path;
+        Ok(())
+    }
+}
+
+/// Registers the system calls for files. Called once during boot.
+pub fn init() {
+    syscall::register(syscall::OPEN, open);
+    syscall::register(syscall::SEEK, seek);
+    syscall::register(syscall::METADATA, metadata);
+}
+
+/// Mounts `filesystem` at `path`, which must be absolute.
+pub fn mount(path: &'static str, filesystem: &'static dyn Filesystem) -> Result<(), Error> {
+    components(path)?;
+    let _busy = Busy::new();
+    let mut mounts = MOUNTS.lock_irq();
+    if mounts.iter().flatten().any(|mount| same_path(mount.path, path)) {
+        return Err(Error::CantMount);
+    }
+    let slot = mounts.iter().position(Option::is_none).ok_or(Error::CantMount)?;
+    mounts[slot] = Some(Mount { path, filesystem });
+    Ok(())
+}
+
+/// Returns the mount points and the kind of filesystem mounted at each.
+pub fn mounts() -> [Option<(&'static str, &'static str)>; MAX_MOUNTS] {
+    let _busy = Busy::new();
+    MOUNTS.lock_irq().each_ref().map(|mount| mount.as_ref().map(|mount| (mount.path, mount.filesystem.name())))
+}
+
+/// Returns true while a filesystem operation is under way, on any task, so that a panic
+/// handler knows whether the filesystems' locks may be held and their state half changed.
+pub fn in_use() -> bool {
+    BUSY.load(Ordering::SeqCst) != 0
+}
+
+/// Mounts the filesystem on `device` at `path`, whichever of the kinds on disks this knows it
+/// is, and returns which. Fails with NotFound if the device has none of them.
+pub fn mount_device(path: &'static str, device: &'static dyn BlockDevice) -> Result<&'static str, Error> {
+    // The filesystems read and write their volumes in sectors of this size
+    if device.sector_size() != BLOCK_SIZE {
+        return Err(Error::NotFound);
+    }
+    // Two filesystems on one volume would each keep their own idea of what is free
+    if !block::claim(device.name()) {
+        return Err(Error::CantMount);
+    }
+    let device = cache::wrap(device);
+    let filesystem: &'static dyn Filesystem = if let Some(fat) = fat::open(device) {
+        fat
+    } else if let Some(ext2) = ext2::open(device) {
+        ext2
+    } else {
+        block::release(device.name());
+        return Err(Error::NotFound);
+    };
+    mount(path, filesystem).inspect_err(|_| block::release(device.name()))?;
+    Ok(filesystem.name())
+}
+
+/// Mounts the filesystem on each block device that has one, at /disk0, /disk1 and so on by the
+/// device's number. Called once during boot, after the disk drivers' `init`.
+pub fn mount_disks() {
+    for (device, path) in block::devices().zip(DISKS) {
+        if let Ok(filesystem) = mount_device(path, device) {
+            writeln!(serial(), "{filesystem} on {} mounted at {path}", device.name()).unwrap();
+        }
+    }
+}
+
+/// Finds the file or directory at `path`, which must be absolute. `.`, `..` and symbolic links
+/// are followed.
+pub fn lookup(path: &str) -> Result<Inode, Error> {
+    let mut path_buffer = TextBuffer::<MAX_PATH>::new();
+    write!(path_buffer, "{path}").unwrap();
+    for _ in 0..=MAX_LINKS {
+        let (names, depth) = components(path_buffer.as_str())?;
+        let names = &names[..depth];
+        let (link, index) = match walk(names)? {
+            Walked::Found(inode) => return Ok(inode),
+            Walked::Link(link, index) => (link, index),
+        };
+        // The path goes on from where the link points
+        let mut target = [0; MAX_PATH];
+        let length = link.read_at(0, &mut target)?;
+        let target = core::str::from_utf8(&target[..length]).map_err(|_| Error::Corrupt)?;
+        let mut next = TextBuffer::<MAX_PATH>::new();
+        if !target.starts_with('/') {
+            names[..index].iter().for_each(|name| write!(next, "/{name}").unwrap());
+        }
+        write!(next, "/{target}").unwrap();
+        names[index + 1..].iter().for_each(|name| write!(next, "/{name}").unwrap());
+        if next.as_str().len() == MAX_PATH {
+            return Err(Error::InvalidPath);
+        }
+        path_buffer = next;
+    }
+    Err(Error::InvalidPath)
+}
+
+// How far walking a path got
+enum Walked {
+    // To the file or directory at its end
+    Found(Inode),
+    // To the symbolic link with the name at this index
+    Link(Inode, usize),
+}
+
+// Walks the path made of `names` through the directories of the filesystem it is in, up to the
+// first symbolic link
+fn walk(names: &[&str]) -> Result<Walked, Error> {
+    let _busy = Busy::new();
+    // The filesystem mounted at the longest mount point the path starts with
+    let (mount_depth, filesystem) = MOUNTS.lock_irq().iter().flatten()
+        .filter_map(|mount| {
+            let (mount_names, mount_depth) = components(mount.path).ok()?;
+            names.starts_with(&mount_names[..mount_depth]).then_some((mount_depth, mount.filesystem))
+        })
+        .max_by_key(|(mount_depth, _)| *mount_depth)
+        .ok_or(Error::NotFound)?;
+    let mut inode = Inode { filesystem, number: filesystem.root() };
+    for (index, name) in names.iter().enumerate().skip(mount_depth) {
+        inode = inode.child(name)?;
+        if inode.metadata()?.kind == Kind::Symlink {
+            return Ok(Walked::Link(inode, index));
+        }
+    }
+    Ok(Walked::Found(inode))
+}
+
+/// Lists the directory at `path`, which must be absolute.
+pub fn read_dir(path: &str) -> Result<ReadDir, Error> {
+    let directory = lookup(path)?;
+    if directory.metadata()?.kind != Kind::Directory {
+        return Err(Error::NotADirectory);
+    }
+    Ok(ReadDir { directory, index: Some(0) })
+}
+
+/// Makes an empty directory at `path`.
+pub fn create_directory(path: &str) -> Result<(), Error> {
+    make(path, Kind::Directory).map(|_| ())
+}
+
+/// Removes the file, symbolic link or empty directory at `path`. A link is removed itself,
+/// not what it links to.
+pub fn remove(path: &str) -> Result<(), Error> {
+    let Some((directory, name)) = parent(path)? else {
+        return Err(Error::InvalidPath);
+    };
+    directory.remove(name)
+}
+
+// Makes an empty `kind` at `path`, in the directory its path is in
+fn make(path: &str, kind: Kind) -> Result<Inode, Error> {
+    let Some((directory, name)) = parent(path)? else {
+        return Err(Error::Exists);
+    };
+    directory.create(name, kind)
+}
+
+// The directory the last name in `path` is in, and that name, or None if `path` is the root
+fn parent(path: &str) -> Result<Option<(Inode, &str)>, Error> {
+    let (names, depth) = components(path)?;
+    let Some((name, parent)) = names[..depth].split_last() else {
+        return Ok(None);
+    };
+    let mut parent_path = TextBuffer::<MAX_PATH>::new();
+    write!(parent_path, "/").unwrap();
+    parent.iter().for_each(|name| write!(parent_path, "{name}/").unwrap());
+    Ok(Some((lookup(parent_path.as_str())?, name)))
+}
+
+// The names in absolute `path`, after following `.` and `..`, and how many there are
+fn components(path: &str) -> Result<([&str; MAX_DEPTH], usize), Error> {
+    let path = path.strip_prefix('/').ok_or(Error::InvalidPath)?;
+    let mut names = [""; MAX_DEPTH];
+    let mut depth: usize = 0;
+    for name in path.split('/') {
+        match name {
+            "" | "." => {},
+            // The root is its own parent
+            ".." => depth = depth.saturating_sub(1),
+            _ => {
+                *names.get_mut(depth).ok_or(Error::InvalidPath)? = name;
+                depth += 1;
+            },
+        }
+    }
+    Ok((names, depth))
+}
+
+fn same_path(first: &str, second: &str) -> bool {
+    match (components(first), components(second)) {
+        (Ok((first, first_depth)), Ok((second, second_depth))) => first[..first_depth] == second[..second_depth],
+        _ => false,
+    }
+}
+
+impl From<Error> for syscall::Error {
+    fn from(error: Error) -> syscall::Error {
+        match error {
+            Error::NotFound => syscall::Error::NotFound,
+            Error::ReadOnly => syscall::Error::ReadOnly,
+            Error::NoSpace => syscall::Error::NoSpace,
+            Error::Io | Error::Corrupt => syscall::Error::Io,
+            Error::NotADirectory | Error::IsADirectory | Error::Exists | Error::NotEmpty | Error::InvalidPath
+                | Error::CantMount | Error::InvalidOffset => syscall::Error::BadArgument,
+        }
+    }
+}
+
+// open(path, length, flags): opens the file at `path`, from the process's current directory
+// if it is relative, as `flags` say, and returns the handle to it
+fn open(arguments: [u64; 6]) -> Result<u64, syscall::Error> {
+    let [path, length, flags, ..] = arguments;
+    if flags & !(CREATE | TRUNCATE | APPEND) != 0 || length > MAX_PATH as u64 {
+        return Err(syscall::Error::BadArgument);
+    }
+    let path = core::str::from_utf8(syscall::user_bytes(path, length)?).map_err(|_| syscall::Error::BadArgument)?;
+    let directory = process::with_current(|process| process.directory).unwrap_or(WorkingDirectory::root());
+    let path = directory.resolve(path)?;
+    let path = path.as_str();
+    let mut file = match File::open(path) {
+        Err(Error::NotFound) if flags & CREATE != 0 => File::create(path)?,
+        file => file?,
+    };
+    if flags & TRUNCATE != 0 {
+        file.inode.truncate(0)?;
+    }
+    if flags & APPEND != 0 {
+        file.seek(SeekFrom::End(0))?;
+    }
+    process::open(Handle::File(file)).ok_or(syscall::Error::OutOfMemory)
+}
+
+// seek(handle, offset, whence): moves file `handle` to `offset` from its start, from where it is
+// or from its end, for `whence` 0, 1 or 2, and returns where it is from the start
+fn seek(arguments: [u64; 6]) -> Result<u64, syscall::Error> {
+    let [number, offset, whence, ..] = arguments;
+    let position = match whence {
+        0 => SeekFrom::Start(offset),
+        1 => SeekFrom::Current(offset as i64),
+        2 => SeekFrom::End(offset as i64),
+        _ => return Err(syscall::Error::BadArgument),
+    };
+    let Some(Handle::File(mut file)) = process::handle(number) else {
+        return Err(syscall::Error::BadHandle);
+    };
+    let offset = file.seek(position)?;
+    process::replace(number, Handle::File(file));
+    Ok(offset)
+}
+
+// metadata(handle, buffer): writes what file `handle` is, as a `Kind`, and its size, 64 bits
+// each, at `buffer`
+fn metadata(arguments: [u64; 6]) -> Result<u64, syscall::Error> {
+    let [number, buffer, ..] = arguments;
+    let buffer = syscall::user_bytes_mut(buffer, 16)?;
+    let Some(Handle::File(file)) = process::handle(number) else {
+        return Err(syscall::Error::BadHandle);
+    };
+    let metadata = file.metadata()?;
+    buffer[..8].copy_from_slice(&(metadata.kind as u64).to_le_bytes());
+    buffer[8..].copy_from_slice(&metadata.size.to_le_bytes());
+    Ok(0)
+}
@@ -0,0 +1,187 @@
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use kernel::serial;
+use kernel::sync::SpinLock;
+use crate::input::InputEvent;
+use crate::screen::screenwriter;
+use crate::{breakout, life, pong, replay, snake, tetris, time};
+
+/// A game that can be started from the menu. The registry owns the screen and the keyboard
+/// while a game is running and calls into it from the timer and keyboard interrupts.
+pub trait Game: Send {
+    /// Name shown in the menu.
+    fn name(&self) -> &'static str;
+
+    /// Starts a new game. The screen has already been cleared.
+    fn init(&mut self);
+
+    /// Handles a key press or other input.
+    fn handle_input(&mut self, event: InputEvent);
+
+    /// Advances the game; `dt` is the number of milliseconds since the previous update.
+    fn update(&mut self, dt: u64);
+
+    /// Draws whatever changed since the previous frame. The screen is locked by whatever draws
+    /// on it, so a game must not hold a `screenwriter()` while calling the `ui` functions.
+    fn render(&mut self);
+
+    /// Makes the next `render` draw everything, after something else was drawn over the game.
+    /// The screen has already been cleared.
+    fn redraw(&mut self);
+
+    /// Returns true if leaving for the menu now would not interrupt anything.
+    fn can_quit(&self) -> bool {
+        true
+    }
+}
+
+/// Number of games in the registry.
+pub const GAME_COUNT: usize = 5;
+
+// Index into `games()` of the running game, or NO_GAME while the menu is showing
+const NO_GAME: usize = usize::MAX;
+static ACTIVE: AtomicUsize = AtomicUsize::new(NO_GAME);
+static LAST_UPDATE_MS: AtomicU64 = AtomicU64::new(0);
+static DEMO: AtomicBool = AtomicBool::new(false);
+// Set while something else (the settings screen) is drawn over the running game
+static SUSPENDED: AtomicBool = AtomicBool::new(false);
+
+// Position of pong in `games()`
+const PONG_INDEX: usize = 0;
+
+/// Every game that can be started from the menu, in menu order.
+pub fn games() -> [&'static SpinLock<dyn Game>; GAME_COUNT] {
+    [&pong::PONG, &*snake::SNAKE, &*breakout::BREAKOUT, &*tetris::TETRIS, &*life::LIFE]
+}
+
+fn active() -> Option<&'static SpinLock<dyn Game>> {
+    games().get(ACTIVE.load(Ordering::SeqCst)).copied()
+}
+
+/// Returns true while a game, rather than the menu, owns the screen.
+pub fn is_running() -> bool {
+    active().is_some()
+}
+
+/// Clears the screen and starts the game at `index` in `games()`, recording a replay.
+pub fn start(index: usize) {
+    if index < GAME_COUNT {
+        replay::start_recording(index);
+        launch(index);
+    }
+}
+
+/// Clears the screen and plays back the last recorded match, if there is one.
+/// Returns false if nothing has been recorded yet.
+pub fn start_replay() -> bool {
+    match replay::start_playback() {
+        Some(header) => {
+            launch(header.game);
+            true
+        },
+        None => false,
+    }
+}
+
+/// Starts pong waiting for another machine on the network to play against. It is not
+/// recorded, as the other machine's moves couldn't be played back.
+pub fn start_network_pong() {
+    launch(PONG_INDEX);
+    pong::PONG.lock().start_network();
+}
+
+/// Starts attract mode: pong with the computer playing both sides. It is not recorded.
+pub fn start_demo() {
+    launch(PONG_INDEX);
+    pong::PONG.lock().start_demo();
+    DEMO.store(true, Ordering::SeqCst);
+}
+
+/// Returns true while attract mode is running.
+pub fn is_demo() -> bool {
+    DEMO.load(Ordering::SeqCst)
+}
+
+fn launch(index: usize) {
+    let mut game = games()[index].lock();
+    writeln!(serial(), "Starting {} from the menu", game.name()).unwrap();
+    ACTIVE.store(index, Ordering::SeqCst);
+    LAST_UPDATE_MS.store(time::uptime_ms(), Ordering::SeqCst);
+    screenwriter().clear();
+    game.init();
+    game.render();
+}
+
+/// Stops the running game, if any. The caller is responsible for redrawing the screen.
+pub fn stop() {
+    replay::stop();
+    DEMO.store(false, Ordering::SeqCst);
+    SUSPENDED.store(false, Ordering::SeqCst);
+    ACTIVE.store(NO_GAME, Ordering::SeqCst);
+}
+
+/// Returns true if the running game can be left without interrupting it.
+/// Replays can always be left.
+pub fn can_quit() -> bool {
+    replay::is_playing() || active().is_none_or(|game| game.lock().can_quit())
+}
+
+/// Freezes the running game so something else can use the screen and keyboard.
+pub fn suspend() {
+    SUSPENDED.store(true, Ordering::SeqCst);
+}
+
+/// Returns true while the running game is frozen by `suspend`.
+pub fn is_suspended() -> bool {
+    SUSPENDED.load(Ordering::SeqCst)
+}
+
+/// Unfreezes the running game and draws it again from scratch.
+pub fn resume() {
+    SUSPENDED.store(false, Ordering::SeqCst);
+    let Some(game) = active() else {
+        return;
+    };
+    let mut game = game.lock();
+    // The time spent suspended doesn't count towards the next update
+    LAST_UPDATE_MS.store(time::uptime_ms(), Ordering::SeqCst);
+    screenwriter().clear();
+    game.redraw();
+    game.render();
+}
+
+/// Updates and redraws the running game. Called on every timer tick.
+pub fn tick() {
+    let Some(game) = active().filter(|_| !is_suspended()) else {
+        return;
+    };
+    let mut game = game.lock();
+    let dt = if replay::is_playing() {
+        // Feed the game what it saw when this tick was recorded; the game
+        // stops advancing once the recording runs out, leaving the final frame on screen
+        match replay::next_tick(|event| game.handle_input(event)) {
+            Some(dt) => dt,
+            None => return,
+        }
+    } else {
+        let now = time::uptime_ms();
+        let dt = now - LAST_UPDATE_MS.swap(now, Ordering::SeqCst);
+        replay::record_tick(dt);
+        dt
+    };
+    game.update(dt);
+    game.render();
+}
+
+/// Passes input to the running game and redraws it. Input is ignored during replays.
+pub fn handle_input(event: InputEvent) {
+    if replay::is_playing() || is_suspended() {
+        return;
+    }
+    if let Some(game) = active() {
+        replay::record_input(event);
+        let mut game = game.lock();
+        game.handle_input(event);
+        game.render();
+    }
+}
@@ -1,3 +1,4 @@
+use core::cell::SyncUnsafeCell;
 use core::ptr::addr_of;
 use lazy_static::lazy_static;
 use x86_64::instructions::segmentation::{Segment, CS, DS, ES, FS, GS, SS};
@@ -8,31 +9,32 @@ use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr( addr_of!(STACK) );
-            stack_start + STACK_SIZE as u64 // stack_end
-        };
-        tss
-    };
+/// The task state segment. The CPU takes the stack for interrupts that arrive in ring 3 from
+/// it, which `usermode` sets while a user program runs and the scheduler swaps with the task,
+/// so it can't be behind a reference.
+pub static TSS: SyncUnsafeCell<TaskStateSegment> = SyncUnsafeCell::new(TaskStateSegment::new());
 
+/// Where in the TSS the stack for interrupts from ring 3 is, for assembly code.
+pub const KERNEL_STACK_OFFSET: usize = 4;
+
+lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt: GlobalDescriptorTable = GlobalDescriptorTable::new();
 
         let code_selector = gdt.append(Descriptor::kernel_code_segment());
         let data_selector = gdt.append(Descriptor::kernel_data_segment());
-        let tss_selector = gdt.append(Descriptor::tss_segment(&TSS));
+        // In the order `syscall` and `sysret` expect: user data, then user code
+        let user_data_selector = gdt.append(Descriptor::user_data_segment());
+        let user_code_selector = gdt.append(Descriptor::user_code_segment());
+        let tss_selector = gdt.append(unsafe { Descriptor::tss_segment_unchecked(TSS.get()) });
 
         (
             gdt,
             Selectors {
                 code_selector,
                 data_selector,
+                user_data_selector,
+                user_code_selector,
                 tss_selector,
             },
         )
@@ -42,10 +44,21 @@ lazy_static! {
 struct Selectors {
     code_selector: SegmentSelector,
     data_selector: SegmentSelector,
+    user_data_selector: SegmentSelector,
+    user_code_selector: SegmentSelector,
     tss_selector: SegmentSelector,
 }
 
 pub fn init() {
+    let tss = unsafe { &mut *TSS.get() };
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
+        const STACK_SIZE: usize = 4096 * 5;
+        static STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+        let stack_start = VirtAddr::from_ptr( addr_of!(STACK) );
+        stack_start + STACK_SIZE as u64 // stack_end
+    };
+
     GDT.0.load();
     unsafe {
         CS::set_reg(GDT.1.code_selector);
@@ -57,4 +70,25 @@ pub fn init() {
 
         load_tss(GDT.1.tss_selector)
     }
-}
\ No newline at end of file
+}
+
+/// The kernel's code and data segments, for `syscall` to switch to.
+pub fn kernel_selectors() -> (SegmentSelector, SegmentSelector) {
+    (GDT.1.code_selector, GDT.1.data_selector)
+}
+
+/// The stack interrupts and system calls from ring 3 start on.
+pub fn kernel_stack() -> u64 {
+    unsafe { (*TSS.get()).privilege_stack_table[0].as_u64() }
+}
+
+/// Makes interrupts and system calls from ring 3 start on the stack at `stack`. Interrupts
+/// must be disabled, so none arrives half way.
+pub fn set_kernel_stack(stack: u64) {
+    unsafe { (*TSS.get()).privilege_stack_table[0] = VirtAddr::new(stack) };
+}
+
+/// The code and data segments user programs run in, in ring 3.
+pub fn user_selectors() -> (SegmentSelector, SegmentSelector) {
+    (GDT.1.user_code_selector, GDT.1.user_data_selector)
+}
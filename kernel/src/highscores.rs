@@ -0,0 +1,74 @@
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU32, Ordering};
+use kernel::serial;
+use crate::cmos;
+
+// High scores are kept in spare CMOS bytes so they survive a reboot, one little-endian u32
+// per game. Once there is a filesystem they can move to a file instead.
+const FIRST_REGISTER: u8 = 0x40;
+const MAGIC: u8 = 0xA5;
+const SLOT_COUNT: usize = 3;
+const DATA_LENGTH: usize = 4 * SLOT_COUNT;
+
+/// Games that keep a high score.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slot {
+    Snake,
+    Breakout,
+    Tetris,
+}
+
+impl Slot {
+    pub const ALL: [Slot; SLOT_COUNT] = [Slot::Snake, Slot::Breakout, Slot::Tetris];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Slot::Snake => "Snake",
+            Slot::Breakout => "Breakout",
+            Slot::Tetris => "Tetris",
+        }
+    }
+}
+
+// In-memory copy of what is stored in CMOS
+static SCORES: [AtomicU32; SLOT_COUNT] = [const { AtomicU32::new(0) }; SLOT_COUNT];
+
+/// Loads the stored high scores. If the stored data is missing or corrupted, all scores
+/// start from zero and the area is rewritten.
+pub fn init() {
+    let mut data = [0u8; DATA_LENGTH];
+    if !cmos::read_block(FIRST_REGISTER, MAGIC, &mut data) {
+        writeln!(serial(), "High scores missing or corrupted, starting from zero").unwrap();
+        save();
+        return;
+    }
+
+    for (score, bytes) in SCORES.iter().zip(data.chunks_exact(4)) {
+        score.store(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]), Ordering::SeqCst);
+    }
+    writeln!(serial(), "Loaded high scores").unwrap();
+}
+
+fn save() {
+    let mut data = [0u8; DATA_LENGTH];
+    for (score, bytes) in SCORES.iter().zip(data.chunks_exact_mut(4)) {
+        bytes.copy_from_slice(&score.load(Ordering::SeqCst).to_le_bytes());
+    }
+    cmos::write_block(FIRST_REGISTER, MAGIC, &data);
+}
+
+/// Returns the high score for `slot`.
+pub fn get(slot: Slot) -> u32 {
+    SCORES[slot as usize].load(Ordering::SeqCst)
+}
+
+/// Records a finished game's score. Returns true if it is a new high score.
+pub fn submit(slot: Slot, score: u32) -> bool {
+    let previous = SCORES[slot as usize].fetch_max(score, Ordering::SeqCst);
+    if score <= previous {
+        return false;
+    }
+    save();
+    writeln!(serial(), "New {} high score: {score}", slot.name()).unwrap();
+    true
+}
@@ -0,0 +1,95 @@
+use core::fmt::Write;
+use core::net::Ipv4Addr;
+use core::sync::atomic::{AtomicU32, Ordering};
+use kernel::serial;
+use crate::ipv4::{self, PROTOCOL_ICMP, Packet};
+use crate::net::Interface;
+use crate::task::{self, WaitQueue};
+use crate::{time, timer};
+
+// ICMP, the messages IPv4 sends about itself. Only echo is here: a request is answered with a
+// reply carrying the same identifier, sequence number and data, which is what `ping` sends
+// and waits for. `ping` here does the asking.
+const ECHO_REPLY: u8 = 0;
+const ECHO_REQUEST: u8 = 8;
+// Type, code, checksum, identifier and sequence number
+const HEADER_SIZE: usize = 8;
+// The identifier this machine's pings go out with
+const PING_ID: u16 = 0x4C4F;
+const PING_DATA: &[u8] = b"lab-os ping";
+const PING_TIMEOUT_MS: u64 = 1_000;
+const PING_INTERVAL_MS: u64 = 1_000;
+
+// The sequence number of the last reply to a ping, or u32::MAX for none yet
+static REPLIED: AtomicU32 = AtomicU32::new(u32::MAX);
+// `ping` waits here for its reply
+static REPLIES: WaitQueue = WaitQueue::new();
+
+/// Takes the ICMP packets the cards receive from now on. Called once during boot.
+pub fn init() {
+    ipv4::register(PROTOCOL_ICMP, receive);
+}
+
+fn receive(_interface: &'static Interface, packet: &Packet) {
+    let message = packet.payload;
+    if message.len() < HEADER_SIZE || ipv4::checksum(message) != 0 {
+        return;
+    }
+    match message[0] {
+        ECHO_REQUEST => {
+            let mut reply = [0; ipv4::MAX_PAYLOAD];
+            let reply = &mut reply[..message.len()];
+            reply.copy_from_slice(message);
+            reply[0] = ECHO_REPLY;
+            reply[2..4].fill(0);
+            let checksum = ipv4::checksum(reply);
+            reply[2..4].copy_from_slice(&checksum.to_be_bytes());
+            // Replies to a machine ARP hasn't heard of are dropped, but it asked for this
+            // card's address just before, so it is almost always known
+            let _ = ipv4::send(packet.source, PROTOCOL_ICMP, reply);
+        },
+        ECHO_REPLY if message[4..6] == PING_ID.to_be_bytes() => {
+            REPLIED.store(u32::from(u16::from_be_bytes([message[6], message[7]])), Ordering::SeqCst);
+            REPLIES.notify();
+        },
+        _ => {},
+    }
+}
+
+/// Sends `count` echo requests to `destination`, a second apart, and prints each reply and
+/// how long it took, or that none came. Blocks the calling task until done; not to be called
+/// on the network task.
+pub fn ping(destination: Ipv4Addr, count: u16) {
+    let mut received = 0;
+    for sequence in 0..count {
+        let mut request = [0; HEADER_SIZE + PING_DATA.len()];
+        request[0] = ECHO_REQUEST;
+        request[4..6].copy_from_slice(&PING_ID.to_be_bytes());
+        request[6..8].copy_from_slice(&sequence.to_be_bytes());
+        request[HEADER_SIZE..].copy_from_slice(PING_DATA);
+        let checksum = ipv4::checksum(&request);
+        request[2..4].copy_from_slice(&checksum.to_be_bytes());
+
+        REPLIED.store(u32::MAX, Ordering::SeqCst);
+        let start = time::rdtsc();
+        if let Err(error) = ipv4::send(destination, PROTOCOL_ICMP, &request) {
+            writeln!(serial(), "ping {destination}: {error:?}").unwrap();
+            return;
+        }
+        let until = timer::now() + time::ms_to_ticks(PING_TIMEOUT_MS);
+        timer::schedule(time::ms_to_ticks(PING_TIMEOUT_MS), || REPLIES.notify());
+        let answered = || REPLIED.load(Ordering::SeqCst) == u32::from(sequence);
+        REPLIES.wait_until(|| answered() || timer::now() >= until);
+        if answered() {
+            received += 1;
+            let ms = time::cycles_to_ms(time::rdtsc() - start);
+            writeln!(serial(), "Reply from {destination}: seq={sequence} time={ms} ms").unwrap();
+        } else {
+            writeln!(serial(), "No reply from {destination}: seq={sequence}").unwrap();
+        }
+        if sequence + 1 < count {
+            task::sleep(PING_INTERVAL_MS);
+        }
+    }
+    writeln!(serial(), "{destination}: {count} sent, {received} received").unwrap();
+}
@@ -0,0 +1,204 @@
+use core::fmt::Write;
+use core::ops::Range;
+use core::slice;
+use kernel::serial;
+use kernel::sync::SpinLock;
+use crate::fs::{DirEntry, Error, Filesystem, Kind, Metadata};
+use crate::ui::TextBuffer;
+
+// The initial ramdisk: a ustar archive that the bootloader loads along with the kernel, holding
+// the user programs in bin/ and whatever else the kernel needs from the start that isn't built
+// into it. Files are read where they are in the archive, which never changes. It is mounted
+// at / as a read-only filesystem, whose directories are the ones the paths in it imply.
+//
+// An archive is a 512-byte header for each file, followed by the file's contents padded to a
+// multiple of 512 bytes, and ends with an empty header. Names that need the ustar prefix
+// field, over 100 bytes, aren't supported, and only regular files are listed.
+const BLOCK_SIZE: usize = 512;
+const NAME: Range<usize> = 0..100;
+const SIZE: Range<usize> = 124..136;
+const CHECKSUM: Range<usize> = 148..156;
+const TYPE: usize = 156;
+const MAGIC: Range<usize> = 257..262;
+const PREFIX: Range<usize> = 345..500;
+// Inode numbers: a file is the offset of its header in the archive. A directory has this bit
+// set, the offset of a file in it in the bits above 16, and the length of its path in that
+// file's path, with the trailing /, in the low 16; the root is just the bit.
+const DIRECTORY: u64 = 1 << 63;
+
+static ARCHIVE: SpinLock<&'static [u8]> = SpinLock::new(&[]);
+
+/// A file in the initrd.
+#[derive(Debug, Clone, Copy)]
+pub struct File {
+    /// Its path in the archive, such as "bin/hello".
+    pub path: &'static str,
+    pub data: &'static [u8],
+}
+
+/// Takes the archive the bootloader loaded at `address`, `length` bytes long, if it did.
+/// Called once during boot.
+pub fn init(address: Option<u64>, length: u64) {
+    let Some(address) = address else {
+        writeln!(serial(), "No initrd").unwrap();
+        return;
+    };
+    // The bootloader maps it and leaves it be
+    let archive = unsafe { slice::from_raw_parts(address as *const u8, length as usize) };
+    *ARCHIVE.lock_irq() = archive;
+    writeln!(serial(), "Initrd: {} files in {} KiB", files().count(), length.div_ceil(1024)).unwrap();
+}
+
+/// Returns the files in the initrd, in the order they are in the archive.
+pub fn files() -> impl Iterator<Item = File> {
+    entries().map(|(_, file)| file)
+}
+
+// The files in the initrd, with the offsets of their headers
+fn entries() -> impl Iterator<Item = (usize, File)> {
+    let whole = *ARCHIVE.lock_irq();
+    let mut archive = whole;
+    core::iter::from_fn(move || loop {
+        let offset = whole.len() - archive.len();
+        let (file, rest) = next_entry(archive)?;
+        archive = rest;
+        if let Some(file) = file {
+            return Some((offset, file));
+        }
+    })
+}
+
+/// Returns the file at `path`, with or without a leading /, if there is one.
+pub fn open(path: &str) -> Option<File> {
+    let path = path.strip_prefix('/').unwrap_or(path);
+    files().find(|file| file.path == path)
+}
+
+// Reads the entry at the start of `archive`, and returns it if it is a file, along with the
+// rest of the archive. Returns None at the end of the archive, or if the header is broken.
+fn next_entry(archive: &'static [u8]) -> Option<(Option<File>, &'static [u8])> {
+    let header = archive.get(..BLOCK_SIZE)?;
+    if header.iter().all(|&byte| byte == 0) {
+        return None;
+    }
+    if &header[MAGIC] != b"ustar" || Some(checksum(header)) != octal(&header[CHECKSUM]) {
+        writeln!(serial(), "Broken header in the initrd").unwrap();
+        return None;
+    }
+    let size = octal(&header[SIZE])? as usize;
+    let data = archive.get(BLOCK_SIZE..BLOCK_SIZE + size)?;
+    let rest = archive.get(BLOCK_SIZE + size.next_multiple_of(BLOCK_SIZE)..).unwrap_or(&[]);
+    // Regular files, which older archives mark with a zero byte. POSIX archives, whose magic
+    // ends in a zero byte, may have the start of the name in the prefix; GNU ones keep other
+    // things there.
+    let prefixed = header[MAGIC.end] == 0 && header[PREFIX.start] != 0;
+    let is_file = matches!(header[TYPE], b'0' | 0) && !prefixed;
+    let file = is_file.then(|| File { path: text(&header[NAME]), data });
+    Some((file, rest))
+}
+
+// The sum of the header's bytes, with the checksum field counted as spaces
+fn checksum(header: &[u8]) -> u64 {
+    header.iter().enumerate().map(|(index, &byte)| {
+        if CHECKSUM.contains(&index) { u64::from(b' ') } else { u64::from(byte) }
+    }).sum()
+}
+
+// A number in octal digits, ended by a space or a zero byte
+fn octal(field: &[u8]) -> Option<u64> {
+    let digits = field.split(|&byte| byte == 0 || byte == b' ').find(|digits| !digits.is_empty())?;
+    digits.iter().try_fold(0u64, |number, &digit| {
+        matches!(digit, b'0'..=b'7').then(|| number * 8 + u64::from(digit - b'0'))
+    })
+}
+
+// A text field, up to its first zero byte; names that aren't UTF-8 are empty
+fn text(field: &'static [u8]) -> &'static str {
+    let length = field.iter().position(|&byte| byte == 0).unwrap_or(field.len());
+    core::str::from_utf8(&field[..length]).unwrap_or("")
+}
+
+/// The initrd as a filesystem.
+pub struct Initrd;
+
+/// The initrd filesystem, to mount.
+pub static FILESYSTEM: Initrd = Initrd;
+
+// The file whose header is at `offset`
+fn file_at(offset: u64) -> Result<File, Error> {
+    let archive = *ARCHIVE.lock_irq();
+    let entry = archive.get(offset as usize..).and_then(next_entry);
+    entry.and_then(|(file, _)| file).ok_or(Error::NotFound)
+}
+
+// The path of directory `inode` with a trailing /, or nothing for the root
+fn directory_path(inode: u64) -> Result<&'static str, Error> {
+    let length = (inode & 0xffff) as usize;
+    if length == 0 {
+        return Ok("");
+    }
+    let file = file_at((inode & !DIRECTORY) >> 16)?;
+    file.path.get(..length).ok_or(Error::Corrupt)
+}
+
+// What `path`, inside directory `prefix`, says about the entry of the directory it is under:
+// its name, and its inode. `offset` is that of the file with the path.
+fn entry_for(prefix: &str, path: &'static str, offset: usize) -> Option<(&'static str, u64)> {
+    let rest = path.strip_prefix(prefix)?;
+    match rest.split_once('/') {
+        None => Some((rest, offset as u64)),
+        Some((name, _)) => Some((name, DIRECTORY | (offset as u64) << 16 | (prefix.len() + name.len() + 1) as u64)),
+    }
+}
+
+impl Filesystem for Initrd {
+    fn name(&self) -> &'static str {
+        "initrd"
+    }
+
+    fn root(&self) -> u64 {
+        DIRECTORY
+    }
+
+    fn lookup(&self, directory: u64, name: &str) -> Result<u64, Error> {
+        let prefix = directory_path(directory)?;
+        entries().filter_map(|(offset, file)| entry_for(prefix, file.path, offset))
+            .find(|(entry, _)| *entry == name)
+            .map(|(_, inode)| inode)
+            .ok_or(Error::NotFound)
+    }
+
+    fn metadata(&self, inode: u64) -> Result<Metadata, Error> {
+        if inode & DIRECTORY != 0 {
+            return Ok(Metadata { kind: Kind::Directory, size: 0 });
+        }
+        Ok(Metadata { kind: Kind::File, size: file_at(inode)?.data.len() as u64 })
+    }
+
+    fn read(&self, inode: u64, offset: u64, buffer: &mut [u8]) -> Result<usize, Error> {
+        if inode & DIRECTORY != 0 {
+            return Err(Error::IsADirectory);
+        }
+        let data = file_at(inode)?.data;
+        let data = data.get(offset as usize..).unwrap_or(&[]);
+        let length = data.len().min(buffer.len());
+        buffer[..length].copy_from_slice(&data[..length]);
+        Ok(length)
+    }
+
+    fn entry(&self, directory: u64, index: usize) -> Result<Option<DirEntry>, Error> {
+        let prefix = directory_path(directory)?;
+        // A directory shows up once for every file in it, so only its first counts
+        let names = entries().filter_map(|(offset, file)| entry_for(prefix, file.path, offset));
+        let first = |name: &str, inode: u64| entries().filter_map(|(offset, file)| entry_for(prefix, file.path, offset))
+            .find(|(other, _)| *other == name)
+            .is_some_and(|(_, other)| other == inode);
+        let Some((name, inode)) = names.filter(|&(name, inode)| first(name, inode)).nth(index) else {
+            return Ok(None);
+        };
+        let mut entry_name = TextBuffer::new();
+        write!(entry_name, "{name}").unwrap();
+        let kind = if inode & DIRECTORY != 0 { Kind::Directory } else { Kind::File };
+        Ok(Some(DirEntry { name: entry_name, kind }))
+    }
+}
@@ -0,0 +1,122 @@
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use futures_util::Stream;
+use kernel::interrupts;
+use pc_keyboard::{DecodedKey, KeyCode};
+use crate::executor::{self, InterruptQueue};
+use crate::settings::{self, Setting};
+use crate::{shell, time};
+
+// The keyboard only reports key presses, and holding a key down produces a stream of
+// typematic repeats that look exactly like new presses. Presses of the same key closer
+// together than this are treated as repeats.
+const REPEAT_WINDOW_MS: u64 = 150;
+
+/// Decodes keys with the keyboard layout in the settings, now and whenever it changes. Called
+/// once during boot, after `settings::init`.
+pub fn init() {
+    interrupts::set_keyboard_layout(settings::keyboard_layout());
+    settings::subscribe(|setting| {
+        if setting == Setting::Keymap {
+            interrupts::set_keyboard_layout(settings::keyboard_layout());
+        }
+    });
+}
+
+/// Input delivered to the active game.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputEvent {
+    /// A key was pressed (or auto-repeated while held down).
+    Key(DecodedKey),
+}
+
+/// Tells fresh key presses apart from typematic repeats of a held key.
+pub struct RepeatFilter {
+    last_key: Option<DecodedKey>,
+    last_time: u64,
+}
+
+impl RepeatFilter {
+    pub const fn new() -> Self {
+        RepeatFilter { last_key: None, last_time: 0 }
+    }
+
+    /// Records a key press and returns true if it is a repeat of the previous one.
+    pub fn is_repeat(&mut self, key: DecodedKey) -> bool {
+        let now = time::uptime_ms();
+        let repeat = self.last_key == Some(key) && now - self.last_time < REPEAT_WINDOW_MS;
+        self.last_key = Some(key);
+        self.last_time = now;
+        repeat
+    }
+}
+
+impl Default for RepeatFilter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Every key press, for async code that wants to see them. Filled by the keyboard handler.
+pub static KEYS: InterruptQueue<DecodedKey, 32> = InterruptQueue::new();
+
+/// The key presses in `KEYS` as a stream of input events, for async code to read with
+/// `StreamExt::next`. It never ends. The queue only wakes one waiting reader, so there should
+/// be only one stream in use.
+pub struct KeyEvents;
+
+pub fn key_events() -> KeyEvents {
+    KeyEvents
+}
+
+impl Stream for KeyEvents {
+    type Item = InputEvent;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<InputEvent>> {
+        KEYS.poll_next(context).map(|key| Some(InputEvent::Key(key)))
+    }
+}
+
+/// Bytes received on the serial console (COM1), filled by its interrupt handler.
+pub static SERIAL_BYTES: InterruptQueue<u8, 64> = InterruptQueue::new();
+
+// Terminals send the bytes of an escape sequence all at once, so this is plenty of time to
+// wait for the rest of one
+const ESCAPE_SEQUENCE_TICKS: u64 = 1;
+
+/// Passes keys typed on the serial console to `handle`, translated to what the keyboard
+/// would have reported, while the input setting allows it; otherwise they go to the shell.
+/// Runs forever.
+pub async fn serial_keys(handle: fn(DecodedKey)) {
+    loop {
+        let byte = SERIAL_BYTES.next().await;
+        if !settings::serial_input() {
+            shell::handle_byte(byte);
+            continue;
+        }
+        let key = match byte {
+            b'\r' => DecodedKey::Unicode('\n'),
+            0x1b => escape_sequence().await,
+            0x7f => DecodedKey::Unicode('\u{8}'),
+            byte if byte.is_ascii() => DecodedKey::Unicode(byte as char),
+            _ => continue,
+        };
+        handle(key);
+    }
+}
+
+async fn escape_sequence() -> DecodedKey {
+    // Terminals send arrow keys as ESC [ A-D; an ESC with nothing straight after it is the
+    // Escape key. Whatever else follows an ESC straight away is dropped.
+    let escape = DecodedKey::Unicode('\u{1b}');
+    if executor::timeout(ESCAPE_SEQUENCE_TICKS, SERIAL_BYTES.next()).await != Some(b'[') {
+        return escape;
+    }
+    match executor::timeout(ESCAPE_SEQUENCE_TICKS, SERIAL_BYTES.next()).await {
+        Some(b'A') => DecodedKey::RawKey(KeyCode::ArrowUp),
+        Some(b'B') => DecodedKey::RawKey(KeyCode::ArrowDown),
+        Some(b'C') => DecodedKey::RawKey(KeyCode::ArrowRight),
+        Some(b'D') => DecodedKey::RawKey(KeyCode::ArrowLeft),
+        _ => escape,
+    }
+}
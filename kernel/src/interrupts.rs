@@ -1,14 +1,15 @@
 use core::fmt::Write;
-use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU8, Ordering};
 use crate::serial;
 use lazy_static::lazy_static;
 use spin::Mutex;
 use x86_64::{PhysAddr, VirtAddr};
-use crate::HandlerTable;
-use acpi::{AcpiHandler, AcpiTables, PhysicalMapping};
+use crate::{HandlerTable, UserFault};
 use pc_keyboard::{layouts, HandleControl, Keyboard, ScancodeSet1};
+use pc_keyboard::layouts::AnyLayout;
 use x86_64::registers::control::Cr2;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::PrivilegeLevel;
 use x86_64::structures::paging::{FrameAllocator, Mapper, PhysFrame, Size4KiB};
 use x86_64::instructions::port::Port;
 // This code is largely Copyright (c) 2019 Philipp Oppermann.
@@ -111,84 +112,77 @@ pub enum APICOffset {
     R0x3F0 = 0x3F0,   // RESERVED = 0x3F0
 }
 
-pub struct AcpiHandlerImpl {
-    physical_memory_offset: VirtAddr,
-}
-
-impl AcpiHandlerImpl {
-    pub fn new(physical_memory_offset: VirtAddr) -> Self {
-        Self {
-            physical_memory_offset,
-        }
-    }
-}
-
-unsafe impl Send for AcpiHandlerImpl {}
-unsafe impl Sync for AcpiHandlerImpl {}
-
-impl Clone for AcpiHandlerImpl {
-    fn clone(&self) -> Self {
-        Self {
-            physical_memory_offset: self.physical_memory_offset,
-        }
-    }
-}
-
-impl AcpiHandler for AcpiHandlerImpl {
-    unsafe fn map_physical_region<T>(
-        &self,
-        physical_address: usize,
-        size: usize,
-    ) -> PhysicalMapping<Self, T> {
-        let phys_addr = PhysAddr::new(physical_address as u64);
-        let virt_addr = self.physical_memory_offset + phys_addr.as_u64();
-
-        unsafe {
-            PhysicalMapping::new(
-                physical_address,
-                NonNull::new(virt_addr.as_mut_ptr()).expect("Failed to get virtual address"),
-                size,
-                size,
-                self.clone(),
-            )
-        }
-    }
-
-    fn unmap_physical_region<T>(_region: &PhysicalMapping<Self, T>) {
-        // No unmapping necessary as we didn't create any new mappings
-    }
-}
-
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
 
         idt.breakpoint.set_handler_fn(breakpoint_handler);
+        idt.divide_error.set_handler_fn(divide_error_handler);
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        idt.x87_floating_point.set_handler_fn(x87_floating_point_handler);
+        idt.simd_floating_point.set_handler_fn(simd_floating_point_handler);
+        idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
         idt.page_fault.set_handler_fn(page_fault_handler);
-        idt.double_fault.set_handler_fn(double_fault_handler);
+        idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
+        // A stack overflow faults again pushing the page fault on the full stack, so the double
+        // fault gets a stack of its own
+        unsafe {
+            idt.double_fault.set_handler_fn(double_fault_handler).set_stack_index(DOUBLE_FAULT_IST_INDEX);
+        }
 
         idt[InterruptIndex::Timer as u8].set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard as u8].set_handler_fn(keyboard_interrupt_handler);
+        idt[InterruptIndex::Serial as u8].set_handler_fn(serial_interrupt_handler);
+        let irq_handlers: [extern "x86-interrupt" fn(InterruptStackFrame); IRQ_LINES] = [
+            irq_handler::<0>, irq_handler::<1>, irq_handler::<2>, irq_handler::<3>, irq_handler::<4>, irq_handler::<5>,
+            irq_handler::<6>, irq_handler::<7>, irq_handler::<8>, irq_handler::<9>, irq_handler::<10>, irq_handler::<11>,
+            irq_handler::<12>, irq_handler::<13>, irq_handler::<14>, irq_handler::<15>, irq_handler::<16>, irq_handler::<17>,
+            irq_handler::<18>, irq_handler::<19>, irq_handler::<20>, irq_handler::<21>, irq_handler::<22>, irq_handler::<23>,
+        ];
+        for (irq, handler) in irq_handlers.into_iter().enumerate() {
+            idt[IRQ_BASE + irq as u8].set_handler_fn(handler);
+        }
 
         idt
     };
 
 }
 
+/// Where an ISA interrupt comes in on the I/O APIC, and how it is signalled. Unless the MADT
+/// overrides it, ISA interrupt n is input n, active high and edge-triggered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IsaRoute {
+    pub input: u8,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+// The ISA interrupts of the devices the I/O APIC is set up for here: the keyboard, and the
+// first serial port, which raises it when a byte has arrived
+const KEYBOARD_IRQ: u8 = 1;
+const SERIAL_IRQ: u8 = 4;
+
 unsafe fn init_io_apic(
     ioapic_address: usize,
+    isa_route: impl Fn(u8) -> IsaRoute,
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
 ) {
     let virt_addr = map_apic(ioapic_address as u64, mapper, frame_allocator);
 
     let ioapic_pointer = virt_addr.as_mut_ptr::<u32>();
+    IO_APIC.store(ioapic_pointer, Ordering::SeqCst);
 
-    unsafe {
-        ioapic_pointer.offset(0).write_volatile(0x12);
-        ioapic_pointer
-            .offset(4)
-            .write_volatile(InterruptIndex::Keyboard as u8 as u32);
+    for (irq, vector) in [(KEYBOARD_IRQ, InterruptIndex::Keyboard), (SERIAL_IRQ, InterruptIndex::Serial)] {
+        let route = isa_route(irq);
+        let mut entry = u32::from(vector as u8);
+        if route.active_low {
+            entry |= ACTIVE_LOW;
+        }
+        if route.level_triggered {
+            entry |= LEVEL_TRIGGERED;
+        }
+        unsafe { write_redirection(ioapic_pointer, route.input, entry) };
     }
 }
 
@@ -202,6 +196,8 @@ unsafe fn init_local_apic(
     let lapic_pointer = virtual_address.as_mut_ptr::<u32>();
     LAPIC_ADDR.lock().address = lapic_pointer;
     unsafe {
+        let id = lapic_pointer.offset(APICOffset::Ir as isize / 4).read_volatile();
+        BOOT_APIC_ID.store((id >> 24) as u8, Ordering::SeqCst);
         init_timer(lapic_pointer);
         init_keyboard(lapic_pointer);
     }
@@ -255,23 +251,18 @@ fn map_apic(
     page.start_address()
 }
 
-pub fn init_apic(rsdp: usize, offset: u64, mapper: &mut impl Mapper<Size4KiB>, frame_allocator: &mut impl FrameAllocator<Size4KiB>) -> *mut u32 {
-    let handler = AcpiHandlerImpl::new(VirtAddr::new(offset));
-    let acpi_tables = unsafe { AcpiTables::from_rsdp(handler, rsdp).expect("Failed to parse ACPI tables") };
-    let platform_info = acpi_tables.platform_info().expect("Failed to get platform info");
-
-    match platform_info.interrupt_model {
-        acpi::InterruptModel::Apic(apic) => {
-            let io_apic_address = apic.io_apics[0].address;
-            unsafe { init_io_apic(io_apic_address as usize, mapper, frame_allocator); }
-
-            let local_apic_address = apic.local_apic_address;
-            unsafe { init_local_apic(local_apic_address as usize, mapper, frame_allocator); }
-        },
-        _ => {
-            // handler other interrupt models, if necessary
-        }
-    }
+/// Sets up the local APIC and the I/O APIC the ISA interrupts come in on, at the physical
+/// addresses the MADT gives. `isa_route` says where each ISA interrupt arrives.
+pub fn init_apic(
+    local_apic_address: u64,
+    io_apic_address: u64,
+    isa_route: impl Fn(u8) -> IsaRoute,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> *mut u32 {
+    // The local APIC first, as the I/O APIC sends interrupts to its ID
+    unsafe { init_local_apic(local_apic_address as usize, mapper, frame_allocator); }
+    unsafe { init_io_apic(io_apic_address as usize, isa_route, mapper, frame_allocator); }
 
     disable_pic();
 
@@ -310,14 +301,75 @@ extern "x86-interrupt" fn breakpoint_handler(
     writeln!(serial(), "EXCEPTION: BREAKPOINT\n{:#?}", stack_frame).unwrap();
 }
 
+extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
+    user_fault(&stack_frame, "divide error", 0, None);
+    panic!("EXCEPTION: DIVIDE ERROR\n{stack_frame:#?}");
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    user_fault(&stack_frame, "invalid opcode", 0, None);
+    panic!("EXCEPTION: INVALID OPCODE\n{stack_frame:#?}");
+}
+
+extern "x86-interrupt" fn x87_floating_point_handler(stack_frame: InterruptStackFrame) {
+    user_fault(&stack_frame, "x87 floating point exception", 0, None);
+    panic!("EXCEPTION: X87 FLOATING POINT\n{stack_frame:#?}");
+}
+
+extern "x86-interrupt" fn simd_floating_point_handler(stack_frame: InterruptStackFrame) {
+    user_fault(&stack_frame, "SIMD floating point exception", 0, None);
+    panic!("EXCEPTION: SIMD FLOATING POINT\n{stack_frame:#?}");
+}
+
+extern "x86-interrupt" fn stack_segment_fault_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    user_fault(&stack_frame, "stack segment fault", error_code, None);
+    panic!("EXCEPTION: STACK SEGMENT FAULT\n ErrorCode: {error_code:#x}\n{stack_frame:#?}");
+}
+
+// The stack the TSS in gdt.rs sets up for double faults
+const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+
 extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode) {
+    user_fault(&stack_frame, "page fault", error_code.bits(), Cr2::read().ok());
+    report_fault();
     panic!("EXCEPTION: PAGE FAULT access address: {:?}\n ErrorCode: {:?}\n{:#?}", Cr2::read(), error_code, stack_frame);
 }
 
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame, _error_code: u64) -> !
 {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+    report_fault();
+    panic!("EXCEPTION: DOUBLE FAULT access address: {:?}\n{:#?}", Cr2::read(), stack_frame);
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    user_fault(&stack_frame, "general protection fault", error_code, None);
+    panic!("EXCEPTION: GENERAL PROTECTION FAULT\n ErrorCode: {error_code:#x}\n{stack_frame:#?}");
+}
+
+// Hands an exception raised in ring 3 to the user fault handler, which ends the program
+// instead of the whole kernel panicking. Returns if the exception wasn't raised in ring 3, or
+// there is no handler.
+fn user_fault(stack_frame: &InterruptStackFrame, exception: &'static str, error_code: u64, address: Option<VirtAddr>) {
+    if stack_frame.code_segment.rpl() != PrivilegeLevel::Ring3 {
+        return;
+    }
+    // Ring 3 code can't be holding the handler table
+    let handler = HANDLERS.lock().as_ref().and_then(HandlerTable::user_fault_handler);
+    if let Some(handler) = handler {
+        handler(UserFault { exception, instruction: stack_frame.instruction_pointer, error_code, address });
+    }
+}
+
+// Lets the fault handler explain the faulting address before panicking. The fault may have
+// happened with the handler table locked, and then there is no explanation.
+fn report_fault() {
+    let Some(handlers) = HANDLERS.try_lock() else {
+        return;
+    };
+    if let (Some(handlers), Ok(address)) = (&*handlers, Cr2::read()) {
+        handlers.handle_fault(address);
+    }
 }
 
 const PIC_1_OFFSET: u8 = 0x20;
@@ -326,26 +378,47 @@ const PIC_1_OFFSET: u8 = 0x20;
 enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    Serial = PIC_1_OFFSET + 4,
 }
 
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
     
-    let h = &*HANDLERS.lock();
-    if let Some(handler) = h {
-        handler.handle_timer();
-    }
+    let preempt = {
+        let h = &*HANDLERS.lock();
+        if let Some(handler) = h {
+            handler.handle_timer();
+        }
+        h.as_ref().and_then(|handler| handler.preempt_handler())
+    };
 
     end_interrupt();
+
+    // A task switch would leave the lock held and the interrupt unacknowledged until this
+    // task ran again, so it can only happen last
+    if let Some(preempt) = preempt {
+        preempt();
+    }
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+lazy_static! {
+    static ref KEYBOARD: Mutex<Keyboard<AnyLayout, ScancodeSet1>> =
+        Mutex::new(Keyboard::new(ScancodeSet1::new(), AnyLayout::Us104Key(layouts::Us104Key),
+            HandleControl::Ignore)
+        );
+}
 
-    lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            Mutex::new(Keyboard::new(ScancodeSet1::new(), layouts::Us104Key,
-                HandleControl::Ignore)
-            );
-    }
+// Whether Ctrl and Alt were down when the last key was decoded
+static CTRL_ALT: AtomicBool = AtomicBool::new(false);
+
+/// Decodes keys with `layout` from now on.
+pub fn set_keyboard_layout(layout: AnyLayout) {
+    // The keyboard interrupt handler takes the same lock
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        *KEYBOARD.lock() = Keyboard::new(ScancodeSet1::new(), layout, HandleControl::Ignore);
+    });
+}
+
+extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
 
     let mut keyboard = KEYBOARD.lock();
     let mut port = Port::new(0x60);
@@ -353,6 +426,8 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     let scancode: u8 = unsafe { port.read() };
     if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
         if let Some(key) = keyboard.process_keyevent(key_event) {
+            let modifiers = keyboard.get_modifiers();
+            CTRL_ALT.store(modifiers.is_ctrl() && modifiers.is_alt(), Ordering::SeqCst);
             let h = &*HANDLERS.lock();
             if let Some(handler) = h {
                 handler.handle_keyboard(key);
@@ -362,4 +437,94 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
 
     end_interrupt();
 
-}
\ No newline at end of file
+}
+
+/// Whether Ctrl and Alt were held down with the last key pressed.
+pub fn ctrl_alt_held() -> bool {
+    CTRL_ALT.load(Ordering::SeqCst)
+}
+
+// Device interrupts: I/O APIC input `irq` goes to vector IRQ_BASE + `irq`, whose handler
+// calls every handler added for it, since PCI devices may share a line. Each checks whether
+// its device raised the interrupt.
+const IRQ_BASE: u8 = 0x30;
+const IRQ_LINES: usize = 24;
+const HANDLERS_PER_IRQ: usize = 4;
+// The redirection table's entries start at this I/O APIC register, two registers each
+const REDIRECTION_TABLE: u32 = 0x10;
+const LEVEL_TRIGGERED: u32 = 1 << 15;
+const ACTIVE_LOW: u32 = 1 << 13;
+// The interrupts go to the CPU that booted, by its local APIC's ID
+static BOOT_APIC_ID: AtomicU8 = AtomicU8::new(0);
+
+// The handlers added for each input
+type IrqHandlers = [[Option<fn()>; HANDLERS_PER_IRQ]; IRQ_LINES];
+
+static IRQ_HANDLERS: Mutex<IrqHandlers> = Mutex::new([[None; HANDLERS_PER_IRQ]; IRQ_LINES]);
+static IO_APIC: AtomicPtr<u32> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Calls `handler` whenever I/O APIC input `irq` is raised, as well as any handlers added for
+/// it before. Returns false if there is no such input or it has too many handlers already.
+pub fn add_irq_handler(irq: u8, handler: fn()) -> bool {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut handlers = IRQ_HANDLERS.lock();
+        let Some(slot) = handlers.get_mut(usize::from(irq)).and_then(|handlers| handlers.iter_mut().find(|slot| slot.is_none())) else {
+            return false;
+        };
+        *slot = Some(handler);
+        true
+    })
+}
+
+/// Sends I/O APIC input `irq` to its vector, level-triggered as PCI interrupts are. Returns
+/// false if there is no such input.
+pub fn route_irq(irq: u8) -> bool {
+    let io_apic = IO_APIC.load(Ordering::SeqCst);
+    if usize::from(irq) >= IRQ_LINES || io_apic.is_null() {
+        return false;
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        write_redirection(io_apic, irq, u32::from(IRQ_BASE + irq) | LEVEL_TRIGGERED);
+    });
+    true
+}
+
+// Sets the redirection table entry for I/O APIC input `input`: the low half, `entry`, has the
+// vector and how the interrupt is signalled, and the high half the CPU it goes to
+unsafe fn write_redirection(io_apic: *mut u32, input: u8, entry: u32) {
+    let register = REDIRECTION_TABLE + 2 * u32::from(input);
+    unsafe {
+        io_apic.write_volatile(register + 1);
+        io_apic.offset(4).write_volatile(u32::from(BOOT_APIC_ID.load(Ordering::SeqCst)) << 24);
+        io_apic.write_volatile(register);
+        io_apic.offset(4).write_volatile(entry);
+    }
+}
+
+extern "x86-interrupt" fn irq_handler<const IRQ: usize>(_stack_frame: InterruptStackFrame) {
+    let handlers = IRQ_HANDLERS.lock()[IRQ];
+    for handler in handlers.into_iter().flatten() {
+        handler();
+    }
+
+    end_interrupt();
+}
+
+const COM1: u16 = 0x3F8;
+const COM1_LINE_STATUS: u16 = COM1 + 5;
+const DATA_READY: u8 = 1;
+
+extern "x86-interrupt" fn serial_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    // Reading every received byte also clears the interrupt in the UART
+    let mut line_status = Port::<u8>::new(COM1_LINE_STATUS);
+    let mut data = Port::<u8>::new(COM1);
+    let h = &*HANDLERS.lock();
+    while unsafe { line_status.read() } & DATA_READY != 0 {
+        let byte = unsafe { data.read() };
+        if let Some(handler) = h {
+            handler.handle_serial(byte);
+        }
+    }
+
+    end_interrupt();
+}
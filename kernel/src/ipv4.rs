@@ -0,0 +1,194 @@
+use core::net::Ipv4Addr;
+use core::sync::atomic::{AtomicU16, Ordering};
+use kernel::sync::SpinLock;
+use crate::ethernet::{self, ETHERTYPE_IPV4, Frame};
+use crate::net::{self, Error, Interface};
+use crate::arp;
+
+// IPv4, over Ethernet. A packet is a header, 20 bytes without options, then the payload for
+// the protocol the header names (ICMP, UDP, TCP), which registers a handler for it. Packets
+// for another machine, and fragments, are dropped: nothing here is big enough to need
+// fragmenting, so packets are sent whole with don't-fragment set. A packet goes out of the
+// card whose network its destination is on, straight to the destination, or else out of the
+// first card with an address, to its gateway. Sending waits for ARP to find the next hop's MAC
+// address, except on the network task, which takes ARP's replies, and so only sends to
+// addresses ARP already has.
+pub const HEADER_SIZE: usize = 20;
+/// The most a packet's payload can be.
+pub const MAX_PAYLOAD: usize = ethernet::MTU - HEADER_SIZE;
+const VERSION_4: u8 = 4;
+const DEFAULT_TTL: u8 = 64;
+// Flags and fragment offset: don't fragment, more fragments, and the offset's bits
+const DONT_FRAGMENT: u16 = 1 << 14;
+const MORE_FRAGMENTS: u16 = 1 << 13;
+const FRAGMENT_OFFSET: u16 = 0x1FFF;
+const MAX_PROTOCOLS: usize = 4;
+
+pub const PROTOCOL_ICMP: u8 = 1;
+pub const PROTOCOL_TCP: u8 = 6;
+pub const PROTOCOL_UDP: u8 = 17;
+
+/// What a protocol's handler is given: where a packet came from and was sent to, and its
+/// payload.
+pub struct Packet<'a> {
+    pub source: Ipv4Addr,
+    pub destination: Ipv4Addr,
+    pub payload: &'a [u8],
+}
+
+/// Called on the network task with each packet of the protocol it is registered for.
+pub type Handler = fn(&'static Interface, &Packet);
+
+static HANDLERS: SpinLock<[Option<(u8, Handler)>; MAX_PROTOCOLS]> = SpinLock::new([None; MAX_PROTOCOLS]);
+// Each packet sent gets the next identification
+static IDENTIFICATION: AtomicU16 = AtomicU16::new(0);
+
+/// Takes the IPv4 packets the cards receive from now on. Called once during boot.
+pub fn init() {
+    ethernet::register(ETHERTYPE_IPV4, receive);
+}
+
+/// Calls `handler` for every packet of `protocol` sent to this machine from now on. Returns
+/// false if there are too many protocols, or one already has that number.
+pub fn register(protocol: u8, handler: Handler) -> bool {
+    let mut handlers = HANDLERS.lock_irq();
+    if handlers.iter().flatten().any(|&(other, _)| other == protocol) {
+        return false;
+    }
+    match handlers.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => {
+            *slot = Some((protocol, handler));
+            true
+        },
+        None => false,
+    }
+}
+
+/// The Internet checksum of `bytes` added to `sum`, before `checksum_finish` folds and inverts
+/// it. Lets a checksum cover a pseudo-header as well as a packet.
+pub fn checksum_add(mut sum: u32, bytes: &[u8]) -> u32 {
+    let mut words = bytes.chunks_exact(2);
+    for word in &mut words {
+        sum += u32::from(u16::from_be_bytes([word[0], word[1]]));
+    }
+    if let [last] = words.remainder() {
+        sum += u32::from(*last) << 8;
+    }
+    sum
+}
+
+/// Folds and inverts a sum from `checksum_add` into the checksum to send.
+pub fn checksum_finish(mut sum: u32) -> u16 {
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// The Internet checksum of `bytes`, which comes out as 0 over bytes that include theirs.
+pub fn checksum(bytes: &[u8]) -> u16 {
+    checksum_finish(checksum_add(0, bytes))
+}
+
+/// The checksum of a UDP or TCP `segment` of `protocol`, which also covers a pseudo-header of
+/// the addresses, the protocol and the length. Comes out as 0 over a segment that includes it.
+pub fn pseudo_header_checksum(source: Ipv4Addr, destination: Ipv4Addr, protocol: u8, segment: &[u8]) -> u16 {
+    let mut pseudo_header = [0; 12];
+    pseudo_header[0..4].copy_from_slice(&source.octets());
+    pseudo_header[4..8].copy_from_slice(&destination.octets());
+    pseudo_header[9] = protocol;
+    pseudo_header[10..12].copy_from_slice(&(segment.len() as u16).to_be_bytes());
+    checksum_finish(checksum_add(checksum_add(0, &pseudo_header), segment))
+}
+
+/// Returns the card that `destination` is reached through, and the address of the next hop
+/// there, or None if there is no way to it.
+pub fn route(destination: Ipv4Addr) -> Option<(&'static Interface, Ipv4Addr)> {
+    let mut interfaces = net::interfaces().filter(|interface| interface.ipv4().is_some());
+    if destination.is_broadcast() {
+        return interfaces.next().map(|interface| (interface, destination));
+    }
+    let mut gateway = None;
+    for interface in interfaces {
+        if interface.on_network(destination) {
+            return Some((interface, destination));
+        }
+        if gateway.is_none() {
+            gateway = interface.gateway().map(|gateway| (interface, gateway));
+        }
+    }
+    gateway
+}
+
+/// Sends `payload`, at most `MAX_PAYLOAD` bytes, to `destination` as a packet of `protocol`.
+pub fn send(destination: Ipv4Addr, protocol: u8, payload: &[u8]) -> Result<(), Error> {
+    let (interface, next_hop) = route(destination).ok_or(Error::NoRoute)?;
+    send_from(interface, next_hop, destination, protocol, payload)
+}
+
+/// Like `send`, but through `interface` to `next_hop`, for packets that have to go out of a
+/// particular card.
+pub fn send_from(interface: &Interface, next_hop: Ipv4Addr, destination: Ipv4Addr, protocol: u8, payload: &[u8]) -> Result<(), Error> {
+    transmit(interface, next_hop, destination, protocol, payload, !net::on_network_task())
+}
+
+/// Like `send_from`, but never waits for ARP: if it doesn't have the next hop's MAC address
+/// yet, it asks for it and fails with `Unreachable`. For tasks that can't block, like games.
+pub fn try_send_from(interface: &Interface, next_hop: Ipv4Addr, destination: Ipv4Addr, protocol: u8, payload: &[u8]) -> Result<(), Error> {
+    transmit(interface, next_hop, destination, protocol, payload, false)
+}
+
+fn transmit(interface: &Interface, next_hop: Ipv4Addr, destination: Ipv4Addr, protocol: u8, payload: &[u8], wait: bool) -> Result<(), Error> {
+    if payload.len() > MAX_PAYLOAD {
+        return Err(Error::TooLong);
+    }
+    let mac = match wait {
+        true => arp::resolve_wait(interface, next_hop),
+        false => arp::resolve(interface, next_hop),
+    };
+    let mac = mac.ok_or(Error::Unreachable)?;
+
+    let source = interface.ipv4().unwrap_or(Ipv4Addr::UNSPECIFIED);
+    let mut packet = [0; ethernet::MTU];
+    let length = HEADER_SIZE + payload.len();
+    packet[0] = VERSION_4 << 4 | (HEADER_SIZE / 4) as u8;
+    packet[2..4].copy_from_slice(&(length as u16).to_be_bytes());
+    packet[4..6].copy_from_slice(&IDENTIFICATION.fetch_add(1, Ordering::Relaxed).to_be_bytes());
+    packet[6..8].copy_from_slice(&DONT_FRAGMENT.to_be_bytes());
+    packet[8] = DEFAULT_TTL;
+    packet[9] = protocol;
+    packet[12..16].copy_from_slice(&source.octets());
+    packet[16..20].copy_from_slice(&destination.octets());
+    let checksum = checksum(&packet[..HEADER_SIZE]);
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+    packet[HEADER_SIZE..length].copy_from_slice(payload);
+    ethernet::send(interface, mac, ETHERTYPE_IPV4, &packet[..length])
+}
+
+// Checks the header of a packet `interface` received, and hands the payload to its protocol
+fn receive(interface: &'static Interface, frame: &Frame) {
+    let packet = frame.payload;
+    if packet.len() < HEADER_SIZE || packet[0] >> 4 != VERSION_4 {
+        return;
+    }
+    let header_size = usize::from(packet[0] & 0xF) * 4;
+    let length = usize::from(u16::from_be_bytes([packet[2], packet[3]]));
+    // The frame may be padded past the packet's end, but mustn't stop before it
+    if header_size < HEADER_SIZE || length < header_size || length > packet.len() || checksum(&packet[..header_size]) != 0 {
+        return;
+    }
+    let fragment = u16::from_be_bytes([packet[6], packet[7]]);
+    if fragment & MORE_FRAGMENTS != 0 || fragment & FRAGMENT_OFFSET != 0 {
+        return;
+    }
+    let source = Ipv4Addr::from(<[u8; 4]>::try_from(&packet[12..16]).unwrap());
+    let destination = Ipv4Addr::from(<[u8; 4]>::try_from(&packet[16..20]).unwrap());
+    if !interface.accepts(destination) {
+        return;
+    }
+    let protocol = packet[9];
+    let handler = HANDLERS.lock_irq().iter().flatten().find(|&&(other, _)| other == protocol).map(|&(_, handler)| handler);
+    if let Some(handler) = handler {
+        handler(interface, &Packet { source, destination, payload: &packet[header_size..length] });
+    }
+}
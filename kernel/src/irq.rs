@@ -0,0 +1,11 @@
+use kernel::interrupts;
+
+// Device interrupts, which come through the I/O APIC: PCI devices raise the input their
+// interrupt line register names, which is shared with other devices more often than not.
+
+/// Calls `handler` whenever I/O APIC input `irq` is raised from now on, and lets it reach the
+/// CPU. The input may be shared, so the handler has to check whether its device raised it.
+/// Returns false if the input can't be used.
+pub fn register(irq: u8, handler: fn()) -> bool {
+    interrupts::add_irq_handler(irq, handler) && interrupts::route_irq(irq)
+}
@@ -0,0 +1,95 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use kernel::sync::SpinLock;
+use crate::condvar::Condvar;
+use crate::task;
+
+// Kernel threads on top of the task system: `spawn` runs a closure as a new task, and the
+// `JoinHandle` it returns waits for the thread to end and hands back what the closure
+// returned, or that it was killed first. A panic stops the whole kernel, so a thread can't end
+// that way.
+
+type ThreadMain = Box<dyn FnOnce() + Send>;
+
+// What a thread's task runs: the closure, and what to do instead if it is killed
+struct Thread {
+    main: ThreadMain,
+    killed: ThreadMain,
+}
+
+// Shared between a thread and its handle
+struct Packet<T> {
+    // How the thread ended, once it has
+    outcome: SpinLock<Option<Result<T, Killed>>>,
+    finished: Condvar,
+}
+
+impl<T> Packet<T> {
+    fn finish(&self, outcome: Result<T, Killed>) {
+        *self.outcome.lock_irq() = Some(outcome);
+        self.finished.notify_all();
+    }
+}
+
+// What each running thread does if it is killed, by its task's id
+static KILLED: SpinLock<BTreeMap<usize, ThreadMain>> = SpinLock::new(BTreeMap::new());
+
+/// Why `join` has no result: the thread was killed before its closure returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Killed;
+
+/// Owned permission to wait for a thread and take its result.
+pub struct JoinHandle<T> {
+    packet: Arc<Packet<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Waits until the thread has ended, blocking the current task meanwhile, and returns what
+    /// its closure returned, or `Killed` if the thread was killed before it could.
+    pub fn join(self) -> Result<T, Killed> {
+        let mut outcome = self.packet.finished.wait_until(&self.packet.outcome, |outcome| outcome.is_some());
+        outcome.take().unwrap()
+    }
+}
+
+/// Runs `f` in a new kernel thread. Returns None if no task could be started for it.
+pub fn spawn<F, T>(f: F) -> Option<JoinHandle<T>>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let packet = Arc::new(Packet { outcome: SpinLock::new(None), finished: Condvar::new() });
+    let (returned, killed) = (packet.clone(), packet.clone());
+    let thread = Thread {
+        main: Box::new(move || returned.finish(Ok(f()))),
+        killed: Box::new(move || killed.finish(Err(Killed))),
+    };
+
+    // The closures are fat pointers, so they are boxed again to pass them as a single word
+    let argument = Box::into_raw(Box::new(thread)) as usize;
+    if !task::spawn_with_argument("kthread", run, argument) {
+        drop(unsafe { Box::from_raw(argument as *mut Thread) });
+        return None;
+    }
+    Some(JoinHandle { packet })
+}
+
+fn run(argument: usize) {
+    let Thread { main, killed } = *unsafe { Box::from_raw(argument as *mut Thread) };
+    let id = task::current();
+    KILLED.lock_irq().insert(id, killed);
+    task::on_kill(thread_killed);
+    main();
+    // A kill from here on isn't noticed, as the task doesn't wait again before it finishes
+    task::restore_on_kill(None);
+    KILLED.lock_irq().remove(&id);
+}
+
+// The `on_kill` hook of every thread's task
+fn thread_killed() {
+    let killed = KILLED.lock_irq().remove(&task::current());
+    if let Some(killed) = killed {
+        killed();
+    }
+}
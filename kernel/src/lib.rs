@@ -4,18 +4,110 @@
 
 use core::cell::UnsafeCell;
 use core::panic::PanicInfo;
-use core::fmt::Write;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
 use uart_16550::SerialPort;
 use pc_keyboard::DecodedKey;
+use spin::Once;
+use x86_64::VirtAddr;
+use x86_64::instructions::port::Port;
 
-mod interrupts;
+pub mod interrupts;
+pub mod sync;
 
 extern crate alloc;
 
-pub fn serial() -> SerialPort {
-    let mut port = unsafe { SerialPort::new(0x3F8) };
-    port.init();
-    port
+static SERIAL_INITIALIZED: AtomicBool = AtomicBool::new(false);
+// The last bytes sent on the serial port, for a crash report to end with. Kept without a lock,
+// since anything may be printing, the panic handler too
+const LOG_SIZE: usize = 4096;
+static LOG: [AtomicU8; LOG_SIZE] = [const { AtomicU8::new(0) }; LOG_SIZE];
+static LOG_END: AtomicUsize = AtomicUsize::new(0);
+static PANIC_HANDLER: Once<fn(&PanicInfo)> = Once::new();
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+const COM1: u16 = 0x3F8;
+const LINE_CONTROL: u16 = COM1 + 3;
+// Set in the line control register, the first two ports set the speed instead
+const DIVISOR_LATCH: u8 = 0x80;
+// The UART's clock, which its speed is a divisor of
+const UART_CLOCK: u32 = 115_200;
+
+/// The serial port, whose output is also kept for `log_tail`.
+pub struct Serial(SerialPort);
+
+impl Serial {
+    /// Sends `byte` on the serial port.
+    pub fn send(&mut self, byte: u8) {
+        let index = LOG_END.fetch_add(1, Ordering::Relaxed) % LOG_SIZE;
+        LOG[index].store(byte, Ordering::Relaxed);
+        self.0.send(byte);
+    }
+}
+
+impl fmt::Write for Serial {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        text.bytes().for_each(|byte| self.send(byte));
+        Ok(())
+    }
+}
+
+pub fn serial() -> Serial {
+    let mut port = unsafe { SerialPort::new(COM1) };
+    // Initializing again would clear bytes waiting in the receive FIFO
+    if !SERIAL_INITIALIZED.swap(true, Ordering::SeqCst) {
+        port.init();
+    }
+    Serial(port)
+}
+
+/// Copies the last bytes sent on the serial port, up to 4 KiB of them, into `buffer`, oldest
+/// first, and returns how many it copied.
+pub fn log_tail(buffer: &mut [u8]) -> usize {
+    let end = LOG_END.load(Ordering::Relaxed);
+    let length = end.min(LOG_SIZE).min(buffer.len());
+    for (index, byte) in (end - length..).zip(&mut buffer[..length]) {
+        *byte = LOG[index % LOG_SIZE].load(Ordering::Relaxed);
+    }
+    length
+}
+
+/// How many bytes have been sent on the serial port since boot, to pass to `log_since`.
+pub fn log_position() -> usize {
+    LOG_END.load(Ordering::Relaxed)
+}
+
+/// Copies the bytes sent on the serial port since `position`, from `log_position`, into
+/// `buffer`, oldest first, and returns how many it copied and the position after them. Bytes
+/// that were sent more than 4 KiB ago are gone, and skipped.
+pub fn log_since(position: usize, buffer: &mut [u8]) -> (usize, usize) {
+    let end = LOG_END.load(Ordering::Relaxed);
+    let start = position.max(end.saturating_sub(LOG_SIZE));
+    let length = end.saturating_sub(start).min(buffer.len());
+    for (index, byte) in (start..).zip(&mut buffer[..length]) {
+        *byte = LOG[index % LOG_SIZE].load(Ordering::Relaxed);
+    }
+    (length, start + length)
+}
+
+/// Sets the serial port's speed to `baud` bits per second, which must divide 115200.
+/// Returns false, leaving the speed as it was, if it doesn't.
+pub fn set_baud_rate(baud: u32) -> bool {
+    if baud == 0 || !UART_CLOCK.is_multiple_of(baud) {
+        return false;
+    }
+    let divisor = (UART_CLOCK / baud) as u16;
+    // Set up the port first, so that doesn't set the speed back later
+    serial();
+    let mut line_control = Port::<u8>::new(LINE_CONTROL);
+    unsafe {
+        let previous = line_control.read();
+        line_control.write(previous | DIVISOR_LATCH);
+        Port::<u8>::new(COM1).write(divisor as u8);
+        Port::<u8>::new(COM1 + 1).write((divisor >> 8) as u8);
+        line_control.write(previous);
+    }
+    true
 }
 
 /// Table of interrupt handlers. This struct uses the
@@ -24,22 +116,53 @@ pub fn serial() -> SerialPort {
 /// up the handlers. When ready, call the **.start()** method to start up your pluggable
 /// interrupt operating system.
 ///
-/// For now, it only includes timer and keyboard handlers.
+/// For now, it only includes timer, keyboard and serial port handlers.
 pub struct HandlerTable {
     timer: Option<fn()>,
     keyboard: Option<fn(DecodedKey)>,
+    serial: Option<fn(u8)>,
     startup: Option<fn()>,
+    panic: Option<fn(&PanicInfo)>,
+    preempt: Option<fn()>,
+    fault: Option<fn(VirtAddr)>,
+    user_fault: Option<fn(UserFault) -> !>,
     cpu_loop: fn() -> !,
 }
 
+/// A CPU exception raised by code running in ring 3.
+#[derive(Debug, Clone, Copy)]
+pub struct UserFault {
+    /// What the exception was, such as "page fault".
+    pub exception: &'static str,
+    /// The instruction that raised it.
+    pub instruction: VirtAddr,
+    pub error_code: u64,
+    /// For page faults, the address that was accessed.
+    pub address: Option<VirtAddr>,
+}
+
+// What is worth knowing to find the bug: where, why, and for page faults what was accessed
+impl fmt::Display for UserFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {:#x}, error code {:#x}", self.exception, self.instruction.as_u64(), self.error_code)?;
+        if let Some(address) = self.address {
+            write!(f, ", accessing {:#x}", address.as_u64())?;
+        }
+        Ok(())
+    }
+}
+
 impl HandlerTable {
     /// Creates a new HandlerTable with no handlers.
     pub fn new() -> Self {
-        HandlerTable {timer: None, keyboard: None, startup: None, cpu_loop: hlt_loop}
+        HandlerTable {timer: None, keyboard: None, serial: None, startup: None, panic: None, preempt: None, fault: None, user_fault: None, cpu_loop: hlt_loop}
     }
 
     /// Starts up a simple operating system using the specified handlers.
     pub fn start(self, lapic_ptr: *mut u32) -> ! {
+        if let Some(panic) = self.panic {
+            PANIC_HANDLER.call_once(|| panic);
+        }
         self.startup.map(|f| f());
         let fore = self.cpu_loop;
         
@@ -62,6 +185,50 @@ impl HandlerTable {
         }
     }
 
+    /// Sets the preemption handler, which runs at the very end of every timer interrupt, after
+    /// the interrupt has been acknowledged and the handler table released. Unlike the timer
+    /// handler, it may switch to another task's stack.
+    /// Returns Self for chained [Builder pattern construction](https://doc.rust-lang.org/1.0.0/style/ownership/builders.html).
+    pub fn preempt(mut self, preempt_handler: fn()) -> Self {
+        self.preempt = Some(preempt_handler);
+        self
+    }
+
+    /// Returns the preemption handler. The low-level timer interrupt routine calls it itself,
+    /// once it is done with the handler table.
+    pub fn preempt_handler(&self) -> Option<fn()> {
+        self.preempt
+    }
+
+    /// Sets the fault handler, called with the faulting address when a page fault or double
+    /// fault is about to panic, to say what was at that address.
+    /// Returns Self for chained [Builder pattern construction](https://doc.rust-lang.org/1.0.0/style/ownership/builders.html).
+    pub fn fault(mut self, fault_handler: fn(VirtAddr)) -> Self {
+        self.fault = Some(fault_handler);
+        self
+    }
+
+    /// Called by the low-level fault routines with the faulting address.
+    pub fn handle_fault(&self, address: VirtAddr) {
+        if let Some(fault) = self.fault {
+            (fault)(address)
+        }
+    }
+
+    /// Sets the user fault handler, called instead of panicking when code running in ring 3
+    /// raises an exception. It doesn't return to the faulting code.
+    /// Returns Self for chained [Builder pattern construction](https://doc.rust-lang.org/1.0.0/style/ownership/builders.html).
+    pub fn user_fault(mut self, user_fault_handler: fn(UserFault) -> !) -> Self {
+        self.user_fault = Some(user_fault_handler);
+        self
+    }
+
+    /// Returns the user fault handler. The low-level exception routines call it themselves,
+    /// as it never returns to let go of the handler table.
+    pub fn user_fault_handler(&self) -> Option<fn(UserFault) -> !> {
+        self.user_fault
+    }
+
     /// Sets the keyboard handler. The [DecodedKey](https://docs.rs/pc-keyboard/0.5.1/pc_keyboard/enum.DecodedKey.html)
     /// enum comes from the [pc_keyboard](https://crates.io/crates/pc-keyboard) crate.
     ///
@@ -78,6 +245,20 @@ impl HandlerTable {
         }
     }
 
+    /// Sets the serial handler, called with every byte received on the first serial port (COM1).
+    /// Returns Self for chained [Builder pattern construction](https://doc.rust-lang.org/1.0.0/style/ownership/builders.html).
+    pub fn serial(mut self, serial_handler: fn(u8)) -> Self {
+        self.serial = Some(serial_handler);
+        self
+    }
+
+    /// Called by the low-level interrupt routines to handle a byte received on the serial port.
+    pub fn handle_serial(&self, byte: u8) {
+        if let Some(serial) = self.serial {
+            (serial)(byte)
+        }
+    }
+
     /// Sets the startup handler.
     /// Returns Self for chained [Builder pattern construction](https://doc.rust-lang.org/1.0.0/style/ownership/builders.html).
     pub fn startup(mut self, startup_handler: fn()) -> Self {
@@ -85,6 +266,14 @@ impl HandlerTable {
         self
     }
 
+    /// Sets the panic handler, called with what the panic was about once it has been printed
+    /// on the serial port, unless it panics itself.
+    /// Returns Self for chained [Builder pattern construction](https://doc.rust-lang.org/1.0.0/style/ownership/builders.html).
+    pub fn panic(mut self, panic_handler: fn(&PanicInfo)) -> Self {
+        self.panic = Some(panic_handler);
+        self
+    }
+
     /// Sets the cpu loop handler.
     /// This function should contain an infinite loop.
     /// Returns Self for chained [Builder pattern construction](https://doc.rust-lang.org/1.0.0/style/ownership/builders.html).
@@ -103,6 +292,10 @@ pub fn hlt_loop() -> ! {
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     let _ = writeln!(serial(), "PANIC: {info}");
+    // Only the first panic goes to the handler, so one in the handler only gets printed
+    if let Some(handler) = PANIC_HANDLER.get().filter(|_| !PANICKING.swap(true, Ordering::SeqCst)) {
+        handler(info);
+    }
     hlt_loop();
 }
 
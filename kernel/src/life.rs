@@ -0,0 +1,206 @@
+use core::fmt::Write;
+use kernel::serial;
+use lazy_static::lazy_static;
+use pc_keyboard::DecodedKey;
+use kernel::sync::SpinLock;
+use crate::{rand, ui};
+use crate::game::Game;
+use crate::input::InputEvent;
+use crate::screen::CHAR_HEIGHT;
+
+// Conway's Game of Life on a fixed grid; cells beyond the edges count as dead. Every
+// generation is computed from one buffer into the other, and rendering only touches cells
+// that changed since the last frame, which still means thousands of them right after seeding.
+const SCREEN_WIDTH: usize = 640;
+const HEADER_HEIGHT: usize = 32;
+const CELL_SIZE: usize = 8;
+const GRID_WIDTH: usize = SCREEN_WIDTH / CELL_SIZE;
+const GRID_HEIGHT: usize = 56;
+
+const GENERATION_MS: u64 = 100;
+// Upper bound on the generations computed in one update, so a long stall doesn't freeze the game
+const MAX_GENERATIONS_PER_UPDATE: u64 = 5;
+// Share of cells alive after random seeding, in percent
+const RANDOM_DENSITY: u32 = 25;
+
+const CELL_COLOR: ui::Color = (0, 220, 120);
+
+// Gosper's glider gun, which fires a new glider every 30 generations
+const GLIDER_GUN: [(usize, usize); 36] = [
+    (24, 0), (22, 1), (24, 1), (12, 2), (13, 2), (20, 2), (21, 2), (34, 2), (35, 2),
+    (11, 3), (15, 3), (20, 3), (21, 3), (34, 3), (35, 3), (0, 4), (1, 4), (10, 4),
+    (16, 4), (20, 4), (21, 4), (0, 5), (1, 5), (10, 5), (14, 5), (16, 5), (17, 5),
+    (22, 5), (24, 5), (10, 6), (16, 6), (24, 6), (11, 7), (15, 7), (12, 8), (13, 8),
+];
+
+const HELP: &str = "SPACE: run/pause  N: step  R: random  G: glider gun  C: clear";
+
+// Cells hold 1 when alive and 0 when dead, so neighbours can simply be added up
+type Grid = [[u8; GRID_WIDTH]; GRID_HEIGHT];
+
+pub struct Life {
+    // Two generations: grids[current] is shown, the next one is computed into the other
+    grids: [Grid; 2],
+    current: usize,
+    // What is currently on screen, so rendering only touches cells that changed
+    drawn: Grid,
+    generation: u32,
+    population: u32,
+    paused: bool,
+    elapsed_ms: u64,
+    header_dirty: bool,
+}
+
+lazy_static! {
+    pub static ref LIFE: SpinLock<Life> = SpinLock::new(Life {
+        grids: [[[0; GRID_WIDTH]; GRID_HEIGHT]; 2],
+        current: 0,
+        drawn: [[0; GRID_WIDTH]; GRID_HEIGHT],
+        generation: 0,
+        population: 0,
+        paused: false,
+        elapsed_ms: 0,
+        header_dirty: true,
+    });
+}
+
+impl Life {
+    fn grid(&mut self) -> &mut Grid {
+        &mut self.grids[self.current]
+    }
+
+    /// Empties the grid and restarts the generation counter.
+    fn clear(&mut self) {
+        *self.grid() = [[0; GRID_WIDTH]; GRID_HEIGHT];
+        self.generation = 0;
+        self.population = 0;
+        self.elapsed_ms = 0;
+        self.header_dirty = true;
+    }
+
+    fn seed_random(&mut self) {
+        self.clear();
+        for row in self.grid().iter_mut() {
+            for cell in row.iter_mut() {
+                *cell = (rand::below(100) < RANDOM_DENSITY) as u8;
+            }
+        }
+        self.count_population();
+        writeln!(serial(), "Life: random grid with {} cells", self.population).unwrap();
+    }
+
+    fn seed_glider_gun(&mut self) {
+        self.clear();
+        for (x, y) in GLIDER_GUN {
+            self.grid()[y + 2][x + 2] = 1;
+        }
+        self.count_population();
+        writeln!(serial(), "Life: glider gun").unwrap();
+    }
+
+    fn count_population(&mut self) {
+        self.population = self.grids[self.current].iter().flatten().map(|cell| *cell as u32).sum();
+    }
+
+    /// Computes the next generation into the other buffer and makes it the current one.
+    fn step(&mut self) {
+        let (first, second) = self.grids.split_at_mut(1);
+        let (old, new) = if self.current == 0 { (&first[0], &mut second[0]) } else { (&second[0], &mut first[0]) };
+        let mut population = 0;
+        for y in 0..GRID_HEIGHT {
+            let rows = y.saturating_sub(1)..(y + 2).min(GRID_HEIGHT);
+            for x in 0..GRID_WIDTH {
+                let columns = x.saturating_sub(1)..(x + 2).min(GRID_WIDTH);
+                let around: u8 = old[rows.clone()].iter().map(|row| row[columns.clone()].iter().sum::<u8>()).sum();
+                // `around` includes the cell itself: a live cell survives with two or three
+                // neighbours and a dead one comes alive with exactly three
+                let alive = around == 3 || (old[y][x] == 1 && around == 4);
+                new[y][x] = alive as u8;
+                population += alive as u32;
+            }
+        }
+        self.current = 1 - self.current;
+        self.generation += 1;
+        self.population = population;
+        self.header_dirty = true;
+    }
+
+    fn draw_header(&self) {
+        ui::fill_rect(0, 0, SCREEN_WIDTH, HEADER_HEIGHT, ui::BLACK);
+        let mut text = ui::TextBuffer::<64>::new();
+        write!(text, "Generation: {}   Population: {}", self.generation, self.population).unwrap();
+        ui::draw_label(0, 0, text.as_str(), ui::WHITE);
+        if self.paused {
+            ui::draw_label(SCREEN_WIDTH - ui::text_width("PAUSED"), 0, "PAUSED", ui::WHITE);
+        }
+        ui::draw_label(0, CHAR_HEIGHT, HELP, ui::GREY);
+    }
+}
+
+impl Game for Life {
+    fn name(&self) -> &'static str {
+        "Game of Life"
+    }
+
+    /// Starts running from a random grid.
+    fn init(&mut self) {
+        self.paused = false;
+        self.seed_random();
+        self.drawn = [[0; GRID_WIDTH]; GRID_HEIGHT];
+    }
+
+    /// Computes one generation per `GENERATION_MS` that passed, unless paused.
+    fn update(&mut self, dt: u64) {
+        if self.paused {
+            return;
+        }
+        self.elapsed_ms += dt;
+        let generations = (self.elapsed_ms / GENERATION_MS).min(MAX_GENERATIONS_PER_UPDATE);
+        self.elapsed_ms %= GENERATION_MS;
+        for _ in 0..generations {
+            self.step();
+        }
+    }
+
+    /// Handles a key press while the simulation is on screen.
+    fn handle_input(&mut self, event: InputEvent) {
+        let InputEvent::Key(key) = event;
+        match key {
+            DecodedKey::Unicode(' ') => {
+                self.paused = !self.paused;
+                self.elapsed_ms = 0;
+                self.header_dirty = true;
+            },
+            DecodedKey::Unicode('n') | DecodedKey::Unicode('N') if self.paused => self.step(),
+            DecodedKey::Unicode('r') | DecodedKey::Unicode('R') => self.seed_random(),
+            DecodedKey::Unicode('g') | DecodedKey::Unicode('G') => self.seed_glider_gun(),
+            DecodedKey::Unicode('c') | DecodedKey::Unicode('C') => self.clear(),
+            _ => {},
+        }
+    }
+
+    fn render(&mut self) {
+        if self.header_dirty {
+            self.draw_header();
+            self.header_dirty = false;
+        }
+        let grid = &self.grids[self.current];
+        for (y, (row, drawn_row)) in grid.iter().zip(self.drawn.iter_mut()).enumerate() {
+            for (x, (cell, drawn)) in row.iter().zip(drawn_row.iter_mut()).enumerate() {
+                if cell == drawn {
+                    continue;
+                }
+                let color = if *cell == 1 { CELL_COLOR } else { ui::BLACK };
+                ui::fill_rect(x * CELL_SIZE + 1, HEADER_HEIGHT + y * CELL_SIZE + 1, CELL_SIZE - 1, CELL_SIZE - 1, color);
+                *drawn = *cell;
+            }
+        }
+    }
+
+    fn redraw(&mut self) {
+        // The screen is blank, so only the live cells need drawing again
+        self.drawn = [[0; GRID_WIDTH]; GRID_HEIGHT];
+        self.header_dirty = true;
+        self.elapsed_ms = 0;
+    }
+}
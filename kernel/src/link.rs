@@ -0,0 +1,59 @@
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+use kernel::serial;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use uart_16550::SerialPort;
+use x86_64::instructions::port::Port;
+
+// A byte pipe to another machine over the second serial port (COM2), wired to the other end
+// with a null-modem cable or, under QEMU, a socket backend. COM1 stays the log console.
+const COM2: u16 = 0x2F8;
+const SCRATCH: u16 = COM2 + 7;
+
+lazy_static! {
+    static ref PORT: Mutex<SerialPort> = Mutex::new(unsafe { SerialPort::new(COM2) });
+}
+static PRESENT: AtomicBool = AtomicBool::new(false);
+
+/// Sets up COM2. Returns false if the machine has no second serial port.
+pub fn init() -> bool {
+    // A missing UART reads back all ones; a real one remembers what was written to its
+    // scratch register
+    let present = unsafe {
+        let mut scratch = Port::<u8>::new(SCRATCH);
+        scratch.write(0x5A);
+        scratch.read() == 0x5A
+    };
+    if present {
+        PORT.lock().init();
+    }
+    PRESENT.store(present, Ordering::SeqCst);
+    let state = if present { "found" } else { "not found" };
+    writeln!(serial(), "Serial link port {state}").unwrap();
+    present
+}
+
+/// Returns true if `init` found a second serial port.
+pub fn is_present() -> bool {
+    PRESENT.load(Ordering::SeqCst)
+}
+
+/// Sends `bytes` to the other machine, waiting while the transmitter is busy.
+pub fn send(bytes: &[u8]) {
+    if !is_present() {
+        return;
+    }
+    let mut port = PORT.lock();
+    for byte in bytes {
+        port.send_raw(*byte);
+    }
+}
+
+/// Returns the next byte received from the other machine, if there is one.
+pub fn receive() -> Option<u8> {
+    if !is_present() {
+        return None;
+    }
+    PORT.lock().try_receive().ok()
+}
@@ -1,35 +1,122 @@
 #![feature(sync_unsafe_cell)]
-#![feature(abi_x86_interrupt)]
 #![no_std] // don't link the Rust standard library
 #![no_main] // disable all Rust-level entry points
 
 extern crate alloc;
 
 mod screen;
+mod acpi;
+mod ahci;
 mod allocator;
+mod arp;
+mod assets;
+mod ata;
+mod block;
+mod cache;
+mod breakout;
+mod channel;
+mod cmos;
+mod condvar;
+mod config;
+mod console;
+mod crash;
+mod devfs;
+mod dhcp;
+mod dns;
+mod display;
+mod e1000;
+mod elf;
+mod ethernet;
+mod executor;
+mod ext2;
+mod fat;
 mod frame_allocator;
-mod interrupts;
+mod fs;
+mod kthread;
+mod life;
+mod link;
+mod game;
 mod gdt;
+mod highscores;
+mod icmp;
+mod initrd;
+mod input;
+mod ipv4;
+mod irq;
+mod memory;
+mod menu;
+mod net;
+mod netplay;
+mod nvme;
+mod particles;
+mod partition;
+mod physics;
+mod pit;
+mod pipe;
 mod pong;
+mod power;
+mod pci;
+mod process;
+mod ramfs;
+mod ramdisk;
+mod rand;
+mod replay;
+mod rtl8139;
+mod settings;
+mod semaphore;
+mod settings_menu;
+mod shell;
+mod shm;
+mod sleeplock;
+mod snake;
+mod sound;
+mod sprite;
+mod syscall;
+mod task;
+mod tcp;
+mod telnet;
+mod tetris;
+mod time;
+mod timer;
+mod udp;
+mod ui;
+mod usermode;
+mod virtio;
+mod virtio_blk;
+mod virtio_net;
+mod workqueue;
 
 use alloc::boxed::Box;
 use core::fmt::Write;
 use core::slice;
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::AtomicBool;
 use bootloader_api::{entry_point, BootInfo, BootloaderConfig};
 use bootloader_api::config::Mapping::Dynamic;
 use bootloader_api::info::MemoryRegionKind;
-use kernel::{HandlerTable, serial};
+use futures_util::StreamExt;
+use kernel::{HandlerTable, interrupts, serial};
 use pc_keyboard::{DecodedKey, KeyCode};
 use x86_64::registers::control::Cr3;
 use x86_64::VirtAddr;
 use crate::frame_allocator::BootInfoFrameAllocator;
 use crate::screen::{Writer, screenwriter};
+use crate::channel::Channel;
+use crate::semaphore::Semaphore;
+use crate::input::InputEvent;
 
 // Track key states locally
 static KEY_W_ACTIVE: AtomicBool = AtomicBool::new(false);
 static KEY_S_ACTIVE: AtomicBool = AtomicBool::new(false);
 
+// One permit for every timer tick the game task hasn't handled yet
+static PENDING_TICKS: Semaphore = Semaphore::new(0);
+// Key presses from the keyboard interrupt and the serial console, for the input task
+static KEYS: Channel<DecodedKey, 32> = Channel::new();
+// Held by the game and input tasks while they run the menus and games, which they share. It
+// is a semaphore rather than a spin lock, so a task preempted while holding it doesn't leave
+// the other one spinning.
+static GAME_STATE: Semaphore = Semaphore::new(1);
+
 const BOOTLOADER_CONFIG: BootloaderConfig = {
     let mut config = BootloaderConfig::new_default();
     config.mappings.physical_memory = Some(Dynamic); // obtain physical memory offset
@@ -81,11 +168,20 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
     let mut mapper = frame_allocator::init(VirtAddr::new(physical_offset));
     let mut frame_allocator = BootInfoFrameAllocator::new(&boot_info.memory_regions);
     
+    initrd::init(boot_info.ramdisk_addr.into_option(), boot_info.ramdisk_len);
+    fs::mount("/", &initrd::FILESYSTEM).unwrap();
+    fs::mount("/tmp", &ramfs::FILESYSTEM).unwrap();
+    fs::mount("/dev", &devfs::FILESYSTEM).unwrap();
     gdt::init();
+    syscall::init();
+    usermode::init();
+    console::init();
+    display::init();
+    process::init();
+    pipe::init();
+    shm::init();
+    fs::init();
 
-    // Initialize pong game before starting the kernel
-    pong::init_game();
-    
     // print out values from heap allocation
     let x = Box::new(42);
     let y = Box::new(24);
@@ -95,63 +191,211 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
     
     writeln!(serial(), "Starting kernel...").unwrap();
 
-    let lapic_ptr = interrupts::init_apic(rsdp.expect("Failed to get RSDP address") as usize, physical_offset, &mut mapper, &mut frame_allocator);
+    let rsdp = rsdp.expect("Failed to get RSDP address") as usize;
+    acpi::init(rsdp, physical_offset);
+    let controllers = acpi::interrupt_controllers().expect("Failed to find the APICs in the MADT");
+    // The ISA interrupts start at global system interrupt 0, on the I/O APIC that takes it
+    let io_apic = controllers.io_apic_for(0).expect("Failed to find the I/O APIC for the ISA interrupts");
+    let isa_route = |irq| {
+        let interrupt = controllers.isa_interrupt(irq);
+        interrupts::IsaRoute { input: (interrupt.gsi - io_apic.gsi_base) as u8, active_low: interrupt.active_low, level_triggered: interrupt.level_triggered }
+    };
+    let lapic_ptr = interrupts::init_apic(controllers.local_apic.as_u64(), io_apic.address.as_u64(), isa_route, &mut mapper, &mut frame_allocator);
+    memory::init(mapper, frame_allocator);
+    // Drivers map their devices' registers, so the disks are found once memory can be mapped
+    config::init();
+    pci::init();
+    cache::init();
+    block::init();
+    ramdisk::init();
+    ata::init();
+    // Network cards without a MAC address of their own are given a random one
+    rand::init();
+    pci::probe();
+    partition::scan();
+    fs::mount_disks();
+    assets::init();
+    time::calibrate_tsc();
+    time::calibrate(lapic_ptr);
+    if let Some(hz) = config::get().timer_hz {
+        time::set_frequency(lapic_ptr, hz);
+    }
+    highscores::init();
+    settings::init();
+    input::init();
+    sound::init();
+    link::init();
+    net::init();
+    task::init();
+    task::set_time_slice(time::ms_to_ticks(config::get().time_slice_ms));
+    // Input is handled on the async task, so it goes first
+    task::set_policy(task::Policy::Priority);
+    task::spawn("game", game_loop);
+    task::spawn_with_priority("selftest", self_test, task::Priority::Background);
+    task::spawn_with_priority("async", executor::run, task::Priority::High);
+    task::spawn_with_priority("input", input_loop, task::Priority::High);
+    task::spawn("worker", workqueue::run);
+    task::spawn("block", block::run);
+    task::spawn("net", net::run);
+    task::spawn("dhcp", dhcp::run);
+    task::spawn("telnet", telnet::run);
+    executor::spawn(log_keys());
+    executor::spawn(input::serial_keys(serial_key));
     HandlerTable::new()
-        .keyboard(key)
+        .keyboard(keyboard_key)
+        .serial(serial_byte)
         .timer(tick)
+        .preempt(task::preempt)
+        .fault(task::report_fault)
+        .user_fault(usermode::fault)
+        .panic(crash::record)
         .startup(start)
+        .cpu_loop(task::idle)
         .start(lapic_ptr)
 }
 
+// The boot context stays on as task 0: it lets the other tasks run, then sleeps until the
+// next interrupt
 fn start() {
-    writeln!(Writer, "Welcome to Pong OS!").unwrap();
+    assets::show_splash();
 }
 
 fn tick() {
-    // Update the game state on each timer tick
-    pong::update_game();
+    let start = time::rdtsc();
+    // The game task does the work
+    PENDING_TICKS.release();
+    executor::timer_interrupt();
+    task::account_interrupt(start);
 }
 
-fn key(key: DecodedKey) {
-    // Debug output to see what keys are being detected
-    writeln!(serial(), "Key detected: {:?}", key).unwrap();
-    
-    match key {
-        DecodedKey::Unicode(character) => {
-            match character {
-                'w' => {
-                    // Direct key state setting - no toggling
-                    pong::set_key_w(true);
-                    writeln!(serial(), "W key pressed").unwrap();
-                },
-                's' => {
-                    pong::set_key_s(true);
-                    writeln!(serial(), "S key pressed").unwrap();
-                },
-                ' ' => {
-                    pong::start_game();
-                    // Reset only left paddle key states
-                    pong::set_key_w(false);
-                    pong::set_key_s(false);
-                    writeln!(serial(), "Space pressed - game started").unwrap();
-                },
-                'q' => {
-                    // Release left paddle keys
-                    pong::set_key_w(false);
-                    pong::set_key_s(false);
-                    writeln!(serial(), "Keys released with Q").unwrap();
-                },
-                _ => write!(Writer, "{}", character).unwrap(),
-            }
-        },
-        DecodedKey::RawKey(key) => {
-            writeln!(serial(), "Raw key: {:?}", key).unwrap();
-            // Only handle W and S raw key codes
-            match key {
-                KeyCode::W => pong::set_key_w(true),
-                KeyCode::S => pong::set_key_s(true),
-                _ => write!(Writer, "{:?}", key).unwrap(),
-            }
+fn serial_byte(byte: u8) {
+    let start = time::rdtsc();
+    input::SERIAL_BYTES.push(byte);
+    task::account_interrupt(start);
+}
+
+// Keys typed on the serial console arrive on the async task
+fn serial_key(key: DecodedKey) {
+    console::key(key);
+    if KEYS.sender().send(key).is_err() {
+        writeln!(serial(), "Too many keys pressed, dropping {key:?}").unwrap();
+    }
+}
+
+async fn log_keys() {
+    let mut events = input::key_events();
+    while let Some(InputEvent::Key(key)) = events.next().await {
+        // Debug output to see what keys are being detected
+        if config::logs(config::LogLevel::Debug) {
+            writeln!(serial(), "Key detected: {:?}", key).unwrap();
+        }
+    }
+}
+
+// Runs once after boot, like the heap check above: two kernel threads each add up half of a
+// range and the results are joined
+fn self_test() {
+    const END: u64 = 10_000;
+    let sum = |range: core::ops::Range<u64>| move || range.sum::<u64>();
+    let halves = (kthread::spawn(sum(0..END / 2)), kthread::spawn(sum(END / 2..END)));
+    let (Some(first), Some(second)) = halves else {
+        writeln!(serial(), "Kernel threads: could not start").unwrap();
+        return;
+    };
+    match (first.join(), second.join()) {
+        (Ok(first), Ok(second)) => {
+            let total = first + second;
+            let result = if total == END * (END - 1) / 2 { "ok" } else { "WRONG RESULT" };
+            writeln!(serial(), "Kernel threads: {result} ({total})").unwrap();
         },
+        _ => writeln!(serial(), "Kernel threads: killed").unwrap(),
+    }
+
+    const SLEEP_MS: u64 = 100;
+    let start = timer::now();
+    task::sleep(SLEEP_MS);
+    let result = if timer::now() - start >= time::ms_to_ticks(SLEEP_MS) { "ok" } else { "WOKE UP EARLY" };
+    writeln!(serial(), "Task sleep: {result}").unwrap();
+}
+
+fn game_loop() {
+    // Killed, the game task leaves the screen to the menu, which the input task keeps using
+    task::on_kill(|| with_game_state(menu::show));
+    loop {
+        PENDING_TICKS.acquire();
+        with_game_state(game_tick);
+        // Input woken up meanwhile shouldn't have to wait for the remaining ticks
+        task::yield_now();
     }
-}
\ No newline at end of file
+}
+
+fn game_tick() {
+    let start = time::rdtsc();
+    timer::advance();
+    if display::take_released() {
+        menu::show();
+    }
+    // Update the game state on each timer tick, unless a user program has the screen
+    if !display::is_taken() {
+        game::tick();
+    }
+    time::check_deadline(start);
+}
+
+// What the Delete key decodes to
+const DELETE: char = '\u{7f}';
+
+fn keyboard_key(key: DecodedKey) {
+    let start = time::rdtsc();
+    // Logged from the async task, to keep slow serial output out of the interrupt handler
+    input::KEYS.push(key);
+    if key == DecodedKey::Unicode(DELETE) && interrupts::ctrl_alt_held() {
+        // Ctrl+Alt+Del reboots, on the worker task, as writing the disks back waits for them
+        workqueue::queue(|| {
+            power::reboot();
+        });
+    } else {
+        console::key(key);
+        // Nothing to do but drop the key when the input task is this far behind
+        if KEYS.sender().send(key).is_err() {
+            workqueue::queue(|| writeln!(serial(), "Too many keys pressed, dropped one").unwrap());
+        }
+    }
+    task::account_interrupt(start);
+}
+
+fn input_loop() {
+    let mut keys = KEYS.receiver().unwrap();
+    loop {
+        let key = keys.recv();
+        with_game_state(|| self::key(key));
+    }
+}
+
+// Runs `f` once the other task is done with the menus and games. Interrupts stay enabled.
+fn with_game_state(f: impl FnOnce()) {
+    GAME_STATE.acquire();
+    f();
+    GAME_STATE.release();
+}
+
+fn key(key: DecodedKey) {
+    if display::is_taken() {
+        // The user program with the screen reads the keys from its console
+    } else if settings_menu::is_open() {
+        settings_menu::handle_key(key);
+    } else if game::is_demo() {
+        // Any key ends attract mode
+        menu::show();
+    } else if !game::is_running() {
+        menu::handle_key(key);
+    } else if key == DecodedKey::RawKey(KeyCode::F2) && !replay::is_playing() {
+        // Settings can be changed in the middle of a game, but not while watching a replay
+        settings_menu::open();
+    } else if key == DecodedKey::Unicode('\u{1b}') && game::can_quit() {
+        menu::show();
+        writeln!(serial(), "Back to the menu").unwrap();
+    } else {
+        game::handle_input(InputEvent::Key(key));
+    }
+}
@@ -0,0 +1,556 @@
+use core::fmt::Write;
+use kernel::serial;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::{Cr3, Cr3Flags};
+use x86_64::structures::paging::{FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB, Translate};
+use x86_64::structures::paging::mapper::{MapperFlush, TranslateResult};
+use x86_64::{PhysAddr, VirtAddr};
+use crate::frame_allocator::BootInfoFrameAllocator;
+
+// Virtual memory after boot: the kernel's page table and the physical frame allocator, kept
+// so memory can be mapped on demand. New mappings go into a part of the address space the
+// bootloader left unused, handed out from the bottom up. Freed pages give their frames back
+// for reuse, but their addresses aren't handed out again; there are plenty. Drivers map their
+// devices' registers there too, and the memory the devices read and write themselves.
+//
+// User programs get address spaces of their own, which share all of the kernel's mappings and
+// add their own in the user region, one level 4 entry the kernel leaves empty, so a program
+// can't see or touch another's memory. Each task runs in an address space, the kernel's own
+// unless it runs a program, and the scheduler switches between them with it. Shared memory
+// can be mapped into several of them; its pages are marked so that an address space doesn't
+// free them along with its own.
+pub const PAGE_SIZE: u64 = 4096;
+
+/// Where user programs are mapped in their address space. They are linked to run here.
+pub const USER_START: u64 = USER_INDEX as u64 * LEVEL_4_ENTRY_SIZE;
+pub const USER_END: u64 = USER_START + LEVEL_4_ENTRY_SIZE;
+
+struct Memory {
+    mapper: OffsetPageTable<'static>,
+    frames: BootInfoFrameAllocator,
+    // Frames given back by `free_pages`, as a list kept in the frames themselves: each one
+    // starts with the address of the next, or END_OF_LIST
+    free_frames: Option<PhysFrame>,
+    // The level 4 table of the kernel's own address space
+    kernel_level_4: PhysFrame,
+    // Next unused address in the kernel's own part of the address space
+    next: VirtAddr,
+    end: VirtAddr,
+}
+
+// The memory map the frame allocator reads is never written after boot
+unsafe impl Send for Memory {}
+
+static MEMORY: Mutex<Option<Memory>> = Mutex::new(None);
+
+// Each level 4 page table entry covers 512 GiB
+const LEVEL_4_ENTRY_SIZE: u64 = 1 << 39;
+const END_OF_LIST: u64 = u64::MAX;
+// The level 4 entry of the user region, at 64 TiB
+const USER_INDEX: usize = 128;
+// Marks the pages of an address space that map shared memory, which it doesn't own
+const SHARED: PageTableFlags = PageTableFlags::BIT_9;
+// The most pages shared memory has: the addresses of its frames fill one frame
+const MAX_SHARED_PAGES: u64 = PAGE_SIZE / 8;
+
+// Takes a frame from the free list, or a new one from the boot allocator
+unsafe impl FrameAllocator<Size4KiB> for Memory {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let Some(frame) = self.free_frames else {
+            return self.frames.allocate_frame();
+        };
+        let next = unsafe { self.frame_pointer(frame).read() };
+        self.free_frames = (next != END_OF_LIST).then(|| PhysFrame::containing_address(PhysAddr::new(next)));
+        Some(frame)
+    }
+}
+
+impl Memory {
+    fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let next = self.free_frames.map_or(END_OF_LIST, |frame| frame.start_address().as_u64());
+        unsafe { self.frame_pointer(frame).write(next) };
+        self.free_frames = Some(frame);
+    }
+
+    // Where `frame` is in the bootloader's mapping of all physical memory
+    fn frame_pointer(&self, frame: PhysFrame) -> *mut u64 {
+        (self.mapper.phys_offset() + frame.start_address().as_u64()).as_mut_ptr()
+    }
+
+    fn allocate_zeroed_frame(&mut self) -> Option<PhysFrame> {
+        let frame = self.allocate_frame()?;
+        unsafe { (self.frame_pointer(frame) as *mut u8).write_bytes(0, PAGE_SIZE as usize) };
+        Some(frame)
+    }
+
+    fn table(&self, frame: PhysFrame) -> &'static mut PageTable {
+        unsafe { &mut *(self.frame_pointer(frame) as *mut PageTable) }
+    }
+
+    // A mapper for the page table whose level 4 table is `level_4`
+    fn mapper_for(&self, level_4: PhysFrame) -> OffsetPageTable<'static> {
+        unsafe { OffsetPageTable::new(self.table(level_4), self.mapper.phys_offset()) }
+    }
+}
+
+/// Takes over the page table and frame allocator set up during boot.
+pub fn init(mapper: OffsetPageTable<'static>, frames: BootInfoFrameAllocator) {
+    assert!(mapper.level_4_table()[USER_INDEX].is_unused(), "The user region is mapped already");
+    // Use the first level 4 entry in the higher half that nothing is mapped in
+    let free_entry = (256..512).find(|&index| mapper.level_4_table()[index].is_unused())
+        .expect("No free level 4 page table entry");
+    let start = VirtAddr::new_truncate(free_entry as u64 * LEVEL_4_ENTRY_SIZE);
+    writeln!(serial(), "Kernel mappings start at {start:?}").unwrap();
+    let kernel_level_4 = Cr3::read().0;
+    let mut memory = Memory { mapper, frames, free_frames: None, kernel_level_4, next: start, end: start + LEVEL_4_ENTRY_SIZE };
+
+    // Address spaces copy the kernel's level 4 entries when they are made, so the entry for
+    // the kernel's own mappings has to be there from the start
+    let table = memory.allocate_zeroed_frame().expect("No memory for the kernel's page table");
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    memory.mapper.level_4_table_mut()[free_entry].set_frame(table, flags);
+    *MEMORY.lock() = Some(memory);
+}
+
+/// Maps `pages` fresh pages of writable memory at an unused address and returns where they
+/// start, or None if there is no memory left. The page right below them is left unmapped, so
+/// that running off their bottom, like a stack overflowing, faults instead of overwriting other
+/// memory.
+pub fn alloc_guarded_pages(pages: u64) -> Option<VirtAddr> {
+    // Tasks allocate with interrupts disabled, so a task preempted here mustn't keep the lock
+    interrupts::without_interrupts(|| map_pages(pages))
+}
+
+fn map_pages(pages: u64) -> Option<VirtAddr> {
+    let mut memory = MEMORY.lock();
+    let memory = memory.as_mut()?;
+    // Skips the guard page
+    let start = memory.next + PAGE_SIZE;
+    if start + pages * PAGE_SIZE > memory.end {
+        return None;
+    }
+    // Claimed up front, so a failure half way doesn't leave mapped pages to be handed out again
+    memory.next = start + pages * PAGE_SIZE;
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    for i in 0..pages {
+        let page = Page::<Size4KiB>::containing_address(start + i * PAGE_SIZE);
+        let frame = memory.allocate_frame()?;
+        unsafe {
+            memory.mapper.map_to(page, frame, flags, &mut memory.frames).ok()?.flush();
+        }
+    }
+    Some(start)
+}
+
+/// Unmaps `pages` pages starting at `start`, which `alloc_guarded_pages` returned, and makes their
+/// memory available again.
+pub fn free_pages(start: VirtAddr, pages: u64) {
+    interrupts::without_interrupts(|| {
+        let mut memory = MEMORY.lock();
+        let memory = memory.as_mut().expect("memory not initialised");
+        for i in 0..pages {
+            let page = Page::<Size4KiB>::containing_address(start + i * PAGE_SIZE);
+            match memory.mapper.unmap(page) {
+                Ok((frame, flush)) => {
+                    flush.flush();
+                    memory.deallocate_frame(frame);
+                },
+                Err(error) => writeln!(serial(), "Can't free page {:?}: {error:?}", page.start_address()).unwrap(),
+            }
+        }
+    });
+}
+
+/// Maps the `length` bytes of device registers at `physical` uncached at an unused address,
+/// and returns where they start, or None if there is no room left.
+pub fn map_mmio(physical: PhysAddr, length: u64) -> Option<VirtAddr> {
+    interrupts::without_interrupts(|| {
+        let mut memory = MEMORY.lock();
+        let memory = memory.as_mut()?;
+        let first = PhysFrame::<Size4KiB>::containing_address(physical);
+        let offset = physical - first.start_address();
+        let pages = (offset + length.max(1)).div_ceil(PAGE_SIZE);
+        let start = memory.next + PAGE_SIZE;
+        if start + pages * PAGE_SIZE > memory.end {
+            return None;
+        }
+        memory.next = start + pages * PAGE_SIZE;
+
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE
+            | PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH;
+        for i in 0..pages {
+            let page = Page::<Size4KiB>::containing_address(start + i * PAGE_SIZE);
+            let frame = first + i;
+            unsafe {
+                memory.mapper.map_to(page, frame, flags, &mut memory.frames).ok()?.flush();
+            }
+        }
+        Some(start + offset)
+    })
+}
+
+/// `size` bytes of device registers, mapped uncached, read and written by their offsets from
+/// the first. Reaching past the end, or a register at an offset that isn't a multiple of its
+/// size, panics rather than touching whatever is mapped after.
+#[derive(Debug, Clone, Copy)]
+pub struct Mmio {
+    start: VirtAddr,
+    size: u64,
+}
+
+impl Mmio {
+    /// Maps the `size` bytes of registers at `physical`, or returns None if there is no room
+    /// left.
+    pub fn map(physical: PhysAddr, size: u64) -> Option<Mmio> {
+        Some(Mmio { start: map_mmio(physical, size)?, size })
+    }
+
+    pub fn start(&self) -> VirtAddr {
+        self.start
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn register<T>(&self, offset: usize) -> *mut T {
+        assert!(offset as u64 + size_of::<T>() as u64 <= self.size, "register {offset:#x} past the end of {:#x} bytes", self.size);
+        assert!(offset.is_multiple_of(size_of::<T>()), "register {offset:#x} not aligned");
+        (self.start + offset as u64).as_mut_ptr()
+    }
+
+    pub fn read8(&self, offset: usize) -> u8 {
+        unsafe { self.register::<u8>(offset).read_volatile() }
+    }
+
+    pub fn write8(&self, offset: usize, value: u8) {
+        unsafe { self.register::<u8>(offset).write_volatile(value) }
+    }
+
+    pub fn read16(&self, offset: usize) -> u16 {
+        unsafe { self.register::<u16>(offset).read_volatile() }
+    }
+
+    pub fn write16(&self, offset: usize, value: u16) {
+        unsafe { self.register::<u16>(offset).write_volatile(value) }
+    }
+
+    pub fn read32(&self, offset: usize) -> u32 {
+        unsafe { self.register::<u32>(offset).read_volatile() }
+    }
+
+    pub fn write32(&self, offset: usize, value: u32) {
+        unsafe { self.register::<u32>(offset).write_volatile(value) }
+    }
+
+    pub fn read64(&self, offset: usize) -> u64 {
+        unsafe { self.register::<u64>(offset).read_volatile() }
+    }
+
+    pub fn write64(&self, offset: usize, value: u64) {
+        unsafe { self.register::<u64>(offset).write_volatile(value) }
+    }
+}
+
+/// Memory for a device to read and write itself: pages that are next to each other in physical
+/// memory as well as in the kernel's address space, so one physical address covers them all.
+#[derive(Debug, Clone, Copy)]
+pub struct Dma {
+    pub start: VirtAddr,
+    pub physical: PhysAddr,
+}
+
+/// Maps `pages` zeroed pages that are contiguous in physical memory, for a device to use, or
+/// returns None if there is no memory or room left. They are never freed.
+pub fn alloc_dma(pages: u64) -> Option<Dma> {
+    interrupts::without_interrupts(|| {
+        let mut memory = MEMORY.lock();
+        let memory = memory.as_mut()?;
+        let start = memory.next + PAGE_SIZE;
+        if start + pages * PAGE_SIZE > memory.end {
+            return None;
+        }
+
+        // The boot allocator hands out frames in order, so a run of them is usually there; the
+        // ones before a gap go on the free list for anything that doesn't need a run
+        let mut first = memory.frames.allocate_frame()?;
+        let mut length = 1;
+        while length < pages {
+            let frame = memory.frames.allocate_frame()?;
+            if frame == first + length {
+                length += 1;
+                continue;
+            }
+            for skipped in 0..length {
+                memory.deallocate_frame(first + skipped);
+            }
+            first = frame;
+            length = 1;
+        }
+        memory.next = start + pages * PAGE_SIZE;
+
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        for i in 0..pages {
+            let page = Page::<Size4KiB>::containing_address(start + i * PAGE_SIZE);
+            unsafe {
+                (memory.frame_pointer(first + i) as *mut u8).write_bytes(0, PAGE_SIZE as usize);
+                memory.mapper.map_to(page, first + i, flags, &mut memory.frames).ok()?.flush();
+            }
+        }
+        Some(Dma { start, physical: first.start_address() })
+    })
+}
+
+/// The page table of a user program: the kernel's mappings, shared with every other address
+/// space, and the program's own in the user region. Its memory is freed when it is dropped,
+/// which mustn't happen while it is in use.
+pub struct AddressSpace {
+    level_4: PhysFrame,
+}
+
+impl AddressSpace {
+    /// Makes an address space with nothing in the user region, or returns None if there is no
+    /// memory for its page table.
+    pub fn new() -> Option<AddressSpace> {
+        interrupts::without_interrupts(|| {
+            let mut memory = MEMORY.lock();
+            let memory = memory.as_mut()?;
+            let level_4 = memory.allocate_zeroed_frame()?;
+            let table = memory.table(level_4);
+            for (index, entry) in memory.mapper.level_4_table().iter().enumerate() {
+                if index != USER_INDEX {
+                    table[index] = entry.clone();
+                }
+            }
+            Some(AddressSpace { level_4 })
+        })
+    }
+
+    /// Its level 4 table, for a task to run in with `task::set_page_table`.
+    pub fn page_table(&self) -> PhysFrame {
+        self.level_4
+    }
+
+    /// Maps zeroed memory over every page that `length` bytes from `start` touch, for the
+    /// program to use as `flags` allow. Pages that are mapped already are kept, and allow what
+    /// `flags` do as well. Returns false if that isn't all in the user region or memory runs out.
+    pub fn map(&mut self, start: VirtAddr, length: u64, flags: PageTableFlags) -> bool {
+        if !in_user_region(start, length) {
+            return false;
+        }
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        // Whatever the pages allow, the tables above them mustn't forbid it
+        let table_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        let last = start + length.max(1) - 1;
+        interrupts::without_interrupts(|| {
+            let mut memory = MEMORY.lock();
+            let Some(memory) = memory.as_mut() else {
+                return false;
+            };
+            let mut mapper = memory.mapper_for(self.level_4);
+            for page in Page::<Size4KiB>::range_inclusive(Page::containing_address(start), Page::containing_address(last)) {
+                let mapped = if let TranslateResult::Mapped { flags: old_flags, .. } = mapper.translate(page.start_address()) {
+                    // Executable if either asks for it
+                    let no_execute = old_flags & flags & PageTableFlags::NO_EXECUTE;
+                    let flags = ((old_flags | flags) - PageTableFlags::NO_EXECUTE) | no_execute;
+                    unsafe { mapper.update_flags(page, flags) }.map(MapperFlush::flush).is_ok()
+                } else if let Some(frame) = memory.allocate_zeroed_frame() {
+                    unsafe { mapper.map_to_with_table_flags(page, frame, flags, table_flags, memory) }.map(MapperFlush::flush).is_ok()
+                } else {
+                    false
+                };
+                if !mapped {
+                    return false;
+                }
+            }
+            true
+        })
+    }
+
+    /// Maps the first `pages` pages of `shared` at `start`, for the program to use as `flags`
+    /// allow. Returns false if some of them are mapped already, or aren't in the user region, or
+    /// memory for the page tables runs out.
+    pub fn map_shared(&mut self, start: VirtAddr, shared: &SharedMemory, pages: u64, flags: PageTableFlags) -> bool {
+        if pages > shared.pages || !in_user_region(start, pages * PAGE_SIZE) {
+            return false;
+        }
+        let flags = flags | SHARED | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let table_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        interrupts::without_interrupts(|| {
+            let mut memory = MEMORY.lock();
+            let Some(memory) = memory.as_mut() else {
+                return false;
+            };
+            let mut mapper = memory.mapper_for(self.level_4);
+            (0..pages).all(|index| {
+                let page = Page::<Size4KiB>::containing_address(start + index * PAGE_SIZE);
+                let frame = shared.frame(memory, index);
+                unsafe { mapper.map_to_with_table_flags(page, frame, flags, table_flags, memory) }.map(MapperFlush::flush).is_ok()
+            })
+        })
+    }
+
+    /// Copies `bytes` to `start`, which `map` has mapped. Returns false if some of it isn't.
+    pub fn write(&mut self, start: VirtAddr, bytes: &[u8]) -> bool {
+        if !in_user_region(start, bytes.len() as u64) {
+            return false;
+        }
+        interrupts::without_interrupts(|| {
+            let memory = MEMORY.lock();
+            let Some(memory) = memory.as_ref() else {
+                return false;
+            };
+            let mapper = memory.mapper_for(self.level_4);
+            // A page at a time, as the frames behind them needn't be next to each other
+            let mut written = 0;
+            while written < bytes.len() {
+                let address = start + written as u64;
+                let Some(physical) = mapper.translate_addr(address) else {
+                    return false;
+                };
+                let length = ((PAGE_SIZE - u64::from(address.page_offset())) as usize).min(bytes.len() - written);
+                let destination: *mut u8 = (memory.mapper.phys_offset() + physical.as_u64()).as_mut_ptr();
+                unsafe { destination.copy_from_nonoverlapping(bytes[written..].as_ptr(), length) };
+                written += length;
+            }
+            true
+        })
+    }
+}
+
+/// The level 4 table of the kernel's own address space, with nothing in the user region.
+pub fn kernel_page_table() -> PhysFrame {
+    interrupts::without_interrupts(|| MEMORY.lock().as_ref().expect("memory not initialised").kernel_level_4)
+}
+
+/// Makes the address space whose level 4 table is `level_4` the one the CPU uses, unless it
+/// is already.
+///
+/// # Safety
+/// It mustn't be freed while it is in use.
+pub unsafe fn switch_page_table(level_4: PhysFrame) {
+    if Cr3::read().0 != level_4 {
+        unsafe { Cr3::write(level_4, Cr3Flags::empty()) };
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| {
+            let mut memory = MEMORY.lock();
+            let memory = memory.as_mut().expect("memory not initialised");
+            let user_entry = &memory.table(self.level_4)[USER_INDEX];
+            if !user_entry.is_unused() {
+                free_table(memory, user_entry.frame().unwrap(), 3);
+            }
+            memory.deallocate_frame(self.level_4);
+        });
+    }
+}
+
+// Frees a page table in the user region, the tables below it and the memory they map. Level 1
+// tables map memory; the others map tables.
+fn free_table(memory: &mut Memory, table: PhysFrame, level: u8) {
+    for entry in memory.table(table).iter().filter(|entry| !entry.is_unused()) {
+        let frame = entry.frame().unwrap();
+        if level > 1 {
+            free_table(memory, frame, level - 1);
+        } else if !entry.flags().contains(SHARED) {
+            memory.deallocate_frame(frame);
+        }
+    }
+    memory.deallocate_frame(table);
+}
+
+/// Zeroed memory that can be mapped into several address spaces with `map_shared`. It is freed
+/// when dropped, which mustn't happen while it is still mapped in an address space in use.
+pub struct SharedMemory {
+    // A frame holding the addresses of the frames of its pages
+    list: PhysFrame,
+    pages: u64,
+}
+
+impl SharedMemory {
+    /// Makes shared memory of `pages` pages, or returns None if that is more than it can have or
+    /// there isn't the memory.
+    pub fn new(pages: u64) -> Option<SharedMemory> {
+        if pages == 0 || pages > MAX_SHARED_PAGES {
+            return None;
+        }
+        interrupts::without_interrupts(|| {
+            let mut memory = MEMORY.lock();
+            let memory = memory.as_mut()?;
+            let list = memory.allocate_zeroed_frame()?;
+            for index in 0..pages {
+                let Some(frame) = memory.allocate_zeroed_frame() else {
+                    // Gives back what it got
+                    free_shared(memory, list, index);
+                    return None;
+                };
+                unsafe { memory.frame_pointer(list).add(index as usize).write(frame.start_address().as_u64()) };
+            }
+            Some(SharedMemory { list, pages })
+        })
+    }
+
+    /// The bytes it has.
+    pub fn size(&self) -> u64 {
+        self.pages * PAGE_SIZE
+    }
+
+    fn frame(&self, memory: &Memory, index: u64) -> PhysFrame {
+        let address = unsafe { memory.frame_pointer(self.list).add(index as usize).read() };
+        PhysFrame::containing_address(PhysAddr::new(address))
+    }
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| {
+            let mut memory = MEMORY.lock();
+            free_shared(memory.as_mut().expect("memory not initialised"), self.list, self.pages);
+        });
+    }
+}
+
+// Frees the first `pages` frames that `list` lists, and `list`
+fn free_shared(memory: &mut Memory, list: PhysFrame, pages: u64) {
+    for index in 0..pages {
+        let address = unsafe { memory.frame_pointer(list).add(index as usize).read() };
+        memory.deallocate_frame(PhysFrame::containing_address(PhysAddr::new(address)));
+    }
+    memory.deallocate_frame(list);
+}
+
+/// Returns true if the user program whose address space is active may read the `length` bytes
+/// from `start`, and write them too if `write` is set.
+pub fn user_can_access(start: VirtAddr, length: u64, write: bool) -> bool {
+    if !in_user_region(start, length) {
+        return false;
+    }
+    if length == 0 {
+        return true;
+    }
+    let mut needed = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if write {
+        needed |= PageTableFlags::WRITABLE;
+    }
+    interrupts::without_interrupts(|| {
+        let memory = MEMORY.lock();
+        let Some(memory) = memory.as_ref() else {
+            return false;
+        };
+        let mapper = memory.mapper_for(Cr3::read().0);
+        let pages = Page::<Size4KiB>::range_inclusive(Page::containing_address(start), Page::containing_address(start + length - 1));
+        pages.into_iter().all(|page| {
+            matches!(mapper.translate(page.start_address()), TranslateResult::Mapped { flags, .. } if flags.contains(needed))
+        })
+    })
+}
+
+/// Returns true if the `length` bytes from `start` are all in the user region.
+pub fn in_user_region(start: VirtAddr, length: u64) -> bool {
+    (USER_START..=USER_END).contains(&start.as_u64()) && USER_END - start.as_u64() >= length
+}
@@ -0,0 +1,131 @@
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use kernel::serial;
+use pc_keyboard::{DecodedKey, KeyCode};
+use crate::{config, display, game, power, settings_menu, time, timer};
+use crate::highscores::{self, Slot};
+use crate::netplay::{self, Transport};
+use crate::screen::{screenwriter, CHAR_HEIGHT};
+use crate::settings;
+use crate::ui::{self, ListView, GREY};
+
+// The boot menu lists every registered game, followed by pong against another machine on the
+// network, replays, the settings and shutting down. The network entry only starts once a card
+// has an IPv4 address, and says so until then.
+static SELECTED: AtomicUsize = AtomicUsize::new(0);
+
+// Like an arcade cabinet, the menu starts a demo after sitting idle for a while
+const IDLE_TIMEOUT_MS: u64 = 30_000;
+static LAST_KEY_MS: AtomicU64 = AtomicU64::new(0);
+static IDLE_ALARM_PENDING: AtomicBool = AtomicBool::new(false);
+
+const TITLE: &str = "Welcome to Pong OS!";
+const NETWORK_ENTRY: usize = game::GAME_COUNT;
+const REPLAY_ENTRY: usize = game::GAME_COUNT + 1;
+const SETTINGS_ENTRY: usize = game::GAME_COUNT + 2;
+const SHUTDOWN_ENTRY: usize = game::GAME_COUNT + 3;
+const ENTRY_COUNT: usize = game::GAME_COUNT + 4;
+
+/// Stops the running game, clears the screen and shows the boot menu.
+pub fn show() {
+    game::stop();
+    screenwriter().clear();
+    draw();
+    LAST_KEY_MS.store(time::uptime_ms(), Ordering::SeqCst);
+    arm_idle_alarm(IDLE_TIMEOUT_MS);
+}
+
+/// Shows what comes up after boot: the default game set in kernel.cfg, or else the menu.
+pub fn show_first() {
+    match config::get().default_game {
+        Some(index) => game::start(index),
+        None => show(),
+    }
+}
+
+fn arm_idle_alarm(delay_ms: u64) {
+    if !IDLE_ALARM_PENDING.swap(true, Ordering::SeqCst) && !timer::schedule(time::ms_to_ticks(delay_ms), check_idle) {
+        IDLE_ALARM_PENDING.store(false, Ordering::SeqCst);
+    }
+}
+
+fn check_idle() {
+    IDLE_ALARM_PENDING.store(false, Ordering::SeqCst);
+    if game::is_running() || settings_menu::is_open() || display::is_taken() {
+        // show() arms the alarm again on the way back to the menu
+        return;
+    }
+    let idle = time::uptime_ms() - LAST_KEY_MS.load(Ordering::SeqCst);
+    if idle >= IDLE_TIMEOUT_MS {
+        writeln!(serial(), "Menu idle, starting the demo").unwrap();
+        game::start_demo();
+    } else {
+        arm_idle_alarm(IDLE_TIMEOUT_MS - idle);
+    }
+}
+
+fn draw() {
+    let mut items = [""; ENTRY_COUNT];
+    for (item, game) in items.iter_mut().zip(game::games()) {
+        *item = game.lock().name();
+    }
+    items[NETWORK_ENTRY] = match netplay::available(Transport::Udp) {
+        true => "Pong over the network",
+        false => "Pong over the network (no address yet)",
+    };
+    items[REPLAY_ENTRY] = "Replay last match";
+    items[SETTINGS_ENTRY] = "Settings";
+    items[SHUTDOWN_ENTRY] = "Shut down";
+    ListView { title: TITLE, items: &items, selected: SELECTED.load(Ordering::SeqCst) }.draw_centered();
+    let help_y = screenwriter().height() - 2 * CHAR_HEIGHT;
+    draw_high_scores(help_y - 2 * CHAR_HEIGHT);
+    ui::draw_label_centered(help_y, "Up/Down: choose   Enter: start", GREY);
+}
+
+fn draw_high_scores(y: usize) {
+    let mut text = ui::TextBuffer::<96>::new();
+    write!(text, "High scores:").unwrap();
+    for slot in Slot::ALL {
+        write!(text, "   {} {}", slot.name(), highscores::get(slot)).unwrap();
+    }
+    ui::draw_label_centered(y, text.as_str(), settings::theme().accent);
+}
+
+fn activate(entry: usize) {
+    match entry {
+        NETWORK_ENTRY => {
+            if netplay::available(Transport::Udp) {
+                game::start_network_pong();
+            } else {
+                writeln!(serial(), "No network card has an IPv4 address yet").unwrap();
+                draw();
+            }
+        },
+        REPLAY_ENTRY => {
+            if !game::start_replay() {
+                writeln!(serial(), "No match recorded yet").unwrap();
+            }
+        },
+        SETTINGS_ENTRY => settings_menu::open(),
+        SHUTDOWN_ENTRY => power::shutdown(),
+        entry => game::start(entry),
+    }
+}
+
+/// Handles a key press while the menu is showing.
+pub fn handle_key(key: DecodedKey) {
+    LAST_KEY_MS.store(time::uptime_ms(), Ordering::SeqCst);
+    let selected = SELECTED.load(Ordering::SeqCst);
+    match key {
+        DecodedKey::RawKey(KeyCode::ArrowUp) => {
+            SELECTED.store(ui::move_selection(selected, ENTRY_COUNT, -1), Ordering::SeqCst);
+            draw();
+        },
+        DecodedKey::RawKey(KeyCode::ArrowDown) => {
+            SELECTED.store(ui::move_selection(selected, ENTRY_COUNT, 1), Ordering::SeqCst);
+            draw();
+        },
+        DecodedKey::Unicode('\n') => activate(selected),
+        _ => {}
+    }
+}
@@ -0,0 +1,261 @@
+use alloc::boxed::Box;
+use core::fmt::{self, Write};
+use core::net::Ipv4Addr;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use kernel::serial;
+use kernel::sync::SpinLock;
+use crate::task::{self, WaitQueue};
+use crate::{arp, config, ethernet, icmp, ipv4, tcp, timer, udp};
+
+// Network cards, which send and receive Ethernet frames. Every driver registers its cards
+// here, where they are named eth0 on in the order they were found, and what is above only
+// sees the `NetworkDevice` trait. A card keeps the frames it receives in its own buffers until
+// the network task takes them: its interrupt only wakes the task, which takes every frame
+// waiting on every card, so nothing above runs in an interrupt handler. Cards whose interrupt
+// can't be used are polled on each timer tick instead. The task hands each frame to the
+// Ethernet layer (ethernet.rs), which passes it on to the protocol it is for. A card has an
+// IPv4 address once it is given one, by DHCP (dhcp.rs) or `[net] address` in kernel.cfg.
+pub const MAX_DEVICES: usize = 4;
+/// The longest frame a card sends or receives, without the checksum at its end: a 14-byte
+/// header and 1500 bytes of payload.
+pub const MAX_FRAME: usize = 1514;
+
+const NAMES: [&str; MAX_DEVICES] = ["eth0", "eth1", "eth2", "eth3"];
+
+pub use crate::tcp::{TcpListener, TcpStream};
+pub use crate::udp::UdpSocket;
+
+/// Why sending a frame or a packet, binding or using a socket, or looking up a name, failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The frame is longer than `MAX_FRAME`, or the packet than its protocol allows.
+    TooLong,
+    /// The card has no room for another frame until it has sent some of those it has.
+    Busy,
+    /// The card has no link.
+    LinkDown,
+    /// No card has an address on the destination's network, or a gateway to it.
+    NoRoute,
+    /// ARP found no MAC address for the next hop.
+    Unreachable,
+    /// Another socket has the port.
+    AddressInUse,
+    /// There is no room, or no memory, for another socket.
+    TooManySockets,
+    /// The other side answered a connection's SYN with RST: nothing listens on the port.
+    ConnectionRefused,
+    /// The other side reset the connection.
+    ConnectionReset,
+    /// The other side stopped acknowledging what was sent.
+    TimedOut,
+    /// The connection is closed, or closing.
+    NotConnected,
+    /// There is no DNS server to look names up with.
+    NoNameServer,
+    /// The DNS server has no IPv4 address for the name, or it isn't a name.
+    NotFound,
+    /// The DNS server couldn't look the name up.
+    NameServerFailed,
+}
+
+/// A network card's hardware address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mac(pub [u8; 6]);
+
+impl Mac {
+    /// The address every card on the network takes frames for.
+    pub const BROADCAST: Mac = Mac([0xFF; 6]);
+}
+
+impl fmt::Display for Mac {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
+
+/// A network card, sending and receiving whole Ethernet frames.
+pub trait NetworkDevice: Sync {
+    fn mac(&self) -> Mac;
+    /// Whether the card is connected to anything.
+    fn link_up(&self) -> bool {
+        true
+    }
+    /// Hands `frame`, at most `MAX_FRAME` bytes, to the card to send. Returns once the card
+    /// has it, not once it is sent.
+    fn send(&self, frame: &[u8]) -> Result<(), Error>;
+    /// Moves a frame the card has received into `buffer` and returns its length, or None if
+    /// it has no more.
+    fn receive(&self, buffer: &mut [u8; MAX_FRAME]) -> Option<usize>;
+}
+
+/// A registered card, with what the network stack keeps about it.
+pub struct Interface {
+    pub name: &'static str,
+    pub device: &'static dyn NetworkDevice,
+    // Its IPv4 address, or 0 for none, its network's mask and its gateway, or 0 for none
+    address: AtomicU32,
+    netmask: AtomicU32,
+    gateway: AtomicU32,
+    received: AtomicU64,
+    sent: AtomicU64,
+}
+
+impl Interface {
+    /// Sends `frame` through the card, counting it.
+    pub fn send(&self, frame: &[u8]) -> Result<(), Error> {
+        if frame.len() > MAX_FRAME {
+            return Err(Error::TooLong);
+        }
+        if !self.device.link_up() {
+            return Err(Error::LinkDown);
+        }
+        self.device.send(frame)?;
+        self.sent.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// The card's IPv4 address, if it has one.
+    pub fn ipv4(&self) -> Option<Ipv4Addr> {
+        Some(Ipv4Addr::from_bits(self.address.load(Ordering::Relaxed))).filter(|address| !address.is_unspecified())
+    }
+
+    /// The mask of the card's network.
+    pub fn netmask(&self) -> Ipv4Addr {
+        Ipv4Addr::from_bits(self.netmask.load(Ordering::Relaxed))
+    }
+
+    /// The router the card sends packets for other networks to, if it has one.
+    pub fn gateway(&self) -> Option<Ipv4Addr> {
+        Some(Ipv4Addr::from_bits(self.gateway.load(Ordering::Relaxed))).filter(|gateway| !gateway.is_unspecified())
+    }
+
+    /// Gives the card the IPv4 address `address` on the network `netmask` covers, with
+    /// `gateway` as its router, and announces it on its network.
+    pub fn set_ipv4(&self, address: Ipv4Addr, netmask: Ipv4Addr, gateway: Option<Ipv4Addr>) {
+        self.netmask.store(netmask.to_bits(), Ordering::Relaxed);
+        self.gateway.store(gateway.unwrap_or(Ipv4Addr::UNSPECIFIED).to_bits(), Ordering::Relaxed);
+        self.address.store(address.to_bits(), Ordering::Relaxed);
+        arp::announce(self);
+    }
+
+    /// Whether `address` is on the card's network.
+    pub fn on_network(&self, address: Ipv4Addr) -> bool {
+        let mask = self.netmask.load(Ordering::Relaxed);
+        self.ipv4().is_some_and(|own| own.to_bits() & mask == address.to_bits() & mask)
+    }
+
+    /// Whether the card takes packets sent to `address`: its own, and broadcasts to every
+    /// machine or to every one on its network.
+    pub fn accepts(&self, address: Ipv4Addr) -> bool {
+        let broadcast = self.ipv4().map(|own| own.to_bits() | !self.netmask.load(Ordering::Relaxed));
+        address.is_broadcast() || self.ipv4() == Some(address) || broadcast == Some(address.to_bits())
+    }
+
+    /// How many frames the card has received and sent.
+    pub fn frames(&self) -> (u64, u64) {
+        (self.received.load(Ordering::Relaxed), self.sent.load(Ordering::Relaxed))
+    }
+}
+
+static INTERFACES: SpinLock<[Option<&'static Interface>; MAX_DEVICES]> = SpinLock::new([None; MAX_DEVICES]);
+// The network task waits here for a card to receive something
+static ARRIVED: WaitQueue = WaitQueue::new();
+static PENDING: AtomicBool = AtomicBool::new(false);
+// Whether a card without an interrupt is being polled
+static POLLING: AtomicBool = AtomicBool::new(false);
+// The network task's id, once it has started
+static NETWORK_TASK: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// Registers `device`, and returns the name it is given, or None if there are too many cards.
+/// `interrupt` says whether the card's interrupt handler calls `wake`, or it has to be polled.
+pub fn register(device: &'static dyn NetworkDevice, interrupt: bool) -> Option<&'static str> {
+    let mut interfaces = INTERFACES.lock_irq();
+    let slot = interfaces.iter().position(Option::is_none)?;
+    let name = NAMES[slot];
+    // Cards are found once, at boot, so they may as well stay allocated
+    interfaces[slot] = Some(Box::leak(Box::new(Interface {
+        name,
+        device,
+        address: AtomicU32::new(0),
+        netmask: AtomicU32::new(0),
+        gateway: AtomicU32::new(0),
+        received: AtomicU64::new(0),
+        sent: AtomicU64::new(0),
+    })));
+    drop(interfaces);
+    if !interrupt && !POLLING.swap(true, Ordering::SeqCst) {
+        poll();
+    }
+    Some(name)
+}
+
+/// Starts the protocols and, unless the first card is to ask DHCP for its address, gives it
+/// the one kernel.cfg asks for. Called once during boot, after the drivers have registered
+/// their cards and `config::init`.
+pub fn init() {
+    arp::init();
+    ipv4::init();
+    icmp::init();
+    udp::init();
+    tcp::init();
+    if !config::get().net.dhcp {
+        configure_static();
+    }
+}
+
+/// Gives the first card the address `[net]` in kernel.cfg asks for, if it asks for one. Done
+/// at boot if DHCP is off, or by DHCP if no server answers.
+pub fn configure_static() {
+    let net = config::get().net;
+    let Some(address) = net.address else {
+        return;
+    };
+    match interfaces().next() {
+        Some(interface) => {
+            interface.set_ipv4(address, net.netmask, net.gateway);
+            writeln!(serial(), "{}: address {address}, netmask {}, gateway {:?}", interface.name, net.netmask, net.gateway).unwrap();
+        },
+        None => writeln!(serial(), "No network card for address {address}").unwrap(),
+    }
+}
+
+fn poll() {
+    wake();
+    timer::schedule(1, poll);
+}
+
+/// Returns the registered cards, in the order they were registered.
+pub fn interfaces() -> impl Iterator<Item = &'static Interface> {
+    let interfaces = *INTERFACES.lock_irq();
+    interfaces.into_iter().flatten()
+}
+
+/// Wakes the network task to take the frames the cards have received, and to send what TCP
+/// has to. Called by the cards' interrupt handlers.
+pub fn wake() {
+    PENDING.store(true, Ordering::SeqCst);
+    ARRIVED.notify();
+}
+
+/// Whether this is the network task, which mustn't wait for anything the network brings.
+pub fn on_network_task() -> bool {
+    NETWORK_TASK.load(Ordering::Relaxed) == task::current()
+}
+
+/// Takes the frames the cards receive as they come, forever. This is the network task's entry
+/// point.
+pub fn run() {
+    NETWORK_TASK.store(task::current(), Ordering::Relaxed);
+    let mut frame = [0; MAX_FRAME];
+    loop {
+        ARRIVED.wait_until(|| PENDING.swap(false, Ordering::SeqCst));
+        for interface in interfaces() {
+            while let Some(length) = interface.device.receive(&mut frame) {
+                interface.received.fetch_add(1, Ordering::Relaxed);
+                ethernet::receive(interface, &frame[..length]);
+            }
+        }
+        tcp::poll();
+    }
+}
@@ -0,0 +1,450 @@
+use core::fmt::Write;
+use core::net::{Ipv4Addr, SocketAddrV4};
+use kernel::serial;
+use spin::Mutex;
+use crate::net::{self, UdpSocket};
+use crate::{link, rand, time};
+
+// Two-machine pong over the serial link (see link.rs) or the network, as UDP datagrams to
+// port 7777 (see udp.rs). Both machines run pong and the one
+// that wins the handshake becomes the host: it plays the left paddle, simulates the ball and
+// sends the match state on every tick. The guest plays the right paddle and only sends where
+// its paddle is. Every packet carries a timestamp and an echo of the other side's latest one,
+// which gives the round trip time; half of it is how old a packet is on arrival, so each side
+// can predict where the remote paddle (and, on the guest, the ball) is by now. The remote
+// paddle is drawn moving towards that prediction rather than jumping to it.
+//
+// Frames are: SYNC, kind, payload length, sequence number (u16), send time (u32 ms), echoed
+// time (u32 ms), time the echo was held before sending (u16 ms), payload, checksum over
+// everything after SYNC. Over the network each datagram is one frame. Datagrams can arrive out
+// of order or twice, so a frame numbered before the latest one is dropped. Until the other
+// machine answers, hellos are broadcast, and from then on only that machine is listened to.
+
+const SYNC: u8 = 0xA7;
+const HEADER_LENGTH: usize = 15;
+const MAX_PAYLOAD: usize = 24;
+const MAX_FRAME: usize = HEADER_LENGTH + MAX_PAYLOAD + 1;
+
+const KIND_HELLO: u8 = 1;
+const KIND_PADDLE: u8 = 2;
+const KIND_STATE: u8 = 3;
+
+const PORT: u16 = 7777;
+
+/// The connection counts as lost after this long without a packet.
+pub const TIMEOUT_MS: u64 = 5_000;
+// Predicting further ahead than this mostly overshoots
+const MAX_PREDICTION_MS: u64 = 500;
+// The remote paddle covers this share of the way to where it is predicted to be each tick,
+// and jumps there if it is this many pixels or fewer away
+const SMOOTHING_PERCENT: i32 = 50;
+const SNAP_DISTANCE: i32 = 2;
+
+/// What the two machines play over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    /// The second serial port.
+    Link,
+    /// UDP, on the first card with an IPv4 address.
+    Udp,
+}
+
+impl Transport {
+    pub fn name(self) -> &'static str {
+        match self {
+            Transport::Link => "serial link",
+            Transport::Udp => "network",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// Plays the left paddle and runs the match.
+    Host,
+    /// Plays the right paddle and shows what the host sends.
+    Guest,
+}
+
+/// Everything the guest needs to show the match, as seen by the host. Positions and speeds
+/// are in pong's fixed point; the speeds are how far the ball moves per tick.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MatchState {
+    pub ball_x: i32,
+    pub ball_y: i32,
+    pub ball_step_x: i32,
+    pub ball_step_y: i32,
+    pub host_paddle_y: i32,
+    pub left_score: u8,
+    pub right_score: u8,
+    pub active: bool,
+    pub game_over: bool,
+}
+
+// A remote paddle position and the remote clock when it was sent
+#[derive(Clone, Copy)]
+struct PaddleSample {
+    y: i32,
+    time: u32,
+}
+
+struct NetPlay {
+    transport: Transport,
+    // The socket and, once it has said hello, the other machine, over the network
+    socket: Option<UdpSocket>,
+    peer: Option<SocketAddrV4>,
+    // The number of the next frame sent, and of the latest one received
+    sequence: u16,
+    peer_sequence: Option<u16>,
+    nonce: u32,
+    peer_nonce: u32,
+    role: Option<Role>,
+
+    // Frame being received
+    frame: [u8; MAX_FRAME],
+    frame_length: usize,
+
+    // Latest timestamp from the other side and when it arrived, for echoing back
+    peer_time: u32,
+    peer_time_received: u64,
+    round_trip_ms: Option<u32>,
+    last_packet_ms: u64,
+
+    paddles: [Option<PaddleSample>; 2],
+    paddle_received_ms: u64,
+    state: Option<MatchState>,
+    state_received_ms: u64,
+}
+
+impl NetPlay {
+    const fn new() -> Self {
+        NetPlay {
+            transport: Transport::Link,
+            socket: None,
+            peer: None,
+            sequence: 0,
+            peer_sequence: None,
+            nonce: 0,
+            peer_nonce: 0,
+            role: None,
+            frame: [0; MAX_FRAME],
+            frame_length: 0,
+            peer_time: 0,
+            peer_time_received: 0,
+            round_trip_ms: None,
+            last_packet_ms: 0,
+            paddles: [None; 2],
+            paddle_received_ms: 0,
+            state: None,
+            state_received_ms: 0,
+        }
+    }
+
+    fn send(&mut self, kind: u8, payload: &[u8]) {
+        let now = time::uptime_ms();
+        let held = (now - self.peer_time_received).min(u16::MAX as u64) as u16;
+        let mut frame = [0u8; MAX_FRAME];
+        frame[0] = SYNC;
+        frame[1] = kind;
+        frame[2] = payload.len() as u8;
+        frame[3..5].copy_from_slice(&self.sequence.to_le_bytes());
+        frame[5..9].copy_from_slice(&(now as u32).to_le_bytes());
+        frame[9..13].copy_from_slice(&self.peer_time.to_le_bytes());
+        frame[13..15].copy_from_slice(&held.to_le_bytes());
+        frame[HEADER_LENGTH..HEADER_LENGTH + payload.len()].copy_from_slice(payload);
+        let end = HEADER_LENGTH + payload.len();
+        frame[end] = checksum(&frame[1..end]);
+        self.sequence = self.sequence.wrapping_add(1);
+        match (self.transport, &self.socket) {
+            (Transport::Link, _) => link::send(&frame[..=end]),
+            (Transport::Udp, Some(socket)) => {
+                let destination = self.peer.unwrap_or(SocketAddrV4::new(Ipv4Addr::BROADCAST, PORT));
+                // The game can't wait for ARP, and a frame that doesn't go now would be stale
+                // by the time it could, so it is dropped like a lost datagram
+                let _ = socket.try_send_to(&frame[..=end], destination);
+            },
+            (Transport::Udp, None) => {},
+        }
+    }
+
+    /// Handles a datagram from `source`, which holds a whole frame.
+    fn receive_datagram(&mut self, datagram: &[u8], source: SocketAddrV4) {
+        // Once connected only the other machine is listened to, and this one's own
+        // broadcasts never are
+        if self.peer.is_some_and(|peer| peer != source) || net::interfaces().any(|interface| interface.ipv4() == Some(*source.ip())) {
+            return;
+        }
+        self.frame_length = 0;
+        for &byte in datagram {
+            self.receive(byte);
+        }
+        if self.peer.is_none() && self.peer_nonce != 0 {
+            self.peer = Some(source);
+        }
+    }
+
+    /// Adds a received byte to the current frame, handling the frame once it is complete.
+    fn receive(&mut self, byte: u8) {
+        if self.frame_length == 0 && byte != SYNC {
+            return;
+        }
+        self.frame[self.frame_length] = byte;
+        self.frame_length += 1;
+        if self.frame_length < 3 {
+            return;
+        }
+
+        let payload_length = self.frame[2] as usize;
+        if payload_length > MAX_PAYLOAD {
+            // Not a real frame: start looking for the next SYNC
+            self.frame_length = 0;
+            return;
+        }
+        let end = HEADER_LENGTH + payload_length;
+        if self.frame_length <= end {
+            return;
+        }
+        self.frame_length = 0;
+        if checksum(&self.frame[1..end]) != self.frame[end] {
+            return;
+        }
+        let sequence = u16::from_le_bytes([self.frame[3], self.frame[4]]);
+        if self.peer_sequence.is_some_and(|latest| sequence.wrapping_sub(latest) as i16 <= 0) {
+            return;
+        }
+        self.peer_sequence = Some(sequence);
+        let frame = self.frame;
+        self.handle(frame[1], &frame[5..HEADER_LENGTH], &frame[HEADER_LENGTH..end]);
+    }
+
+    fn handle(&mut self, kind: u8, timing: &[u8], payload: &[u8]) {
+        let now = time::uptime_ms();
+        let sent = read_u32(&timing[0..4]);
+        let echo = read_u32(&timing[4..8]);
+        let held = u16::from_le_bytes([timing[8], timing[9]]) as u32;
+        self.last_packet_ms = now;
+        self.peer_time = sent;
+        self.peer_time_received = now;
+        if echo != 0 {
+            let sample = (now as u32).wrapping_sub(echo).saturating_sub(held);
+            // Smooth out the jitter of single measurements
+            self.round_trip_ms = Some(match self.round_trip_ms {
+                Some(average) => (7 * average + sample) / 8,
+                None => sample,
+            });
+        }
+
+        match kind {
+            KIND_HELLO if payload.len() == 8 => {
+                self.peer_nonce = read_u32(&payload[0..4]);
+                if read_u32(&payload[4..8]) != self.nonce {
+                    // Answer so the other side learns that we know its nonce
+                    self.send_hello();
+                } else if self.role.is_none() {
+                    self.connect();
+                }
+            },
+            // Whoever connects first stops saying hello and starts playing, which tells
+            // the other side that its hello arrived
+            KIND_PADDLE | KIND_STATE if self.role.is_none() && self.peer_nonce != 0 => {
+                self.connect();
+                if self.role.is_some() {
+                    self.handle(kind, timing, payload);
+                }
+            },
+            KIND_PADDLE if payload.len() == 4 => {
+                self.record_paddle(read_u32(payload) as i32, sent);
+            },
+            KIND_STATE if payload.len() == 23 => {
+                let state = MatchState {
+                    ball_x: read_u32(&payload[0..4]) as i32,
+                    ball_y: read_u32(&payload[4..8]) as i32,
+                    ball_step_x: read_u32(&payload[8..12]) as i32,
+                    ball_step_y: read_u32(&payload[12..16]) as i32,
+                    host_paddle_y: read_u32(&payload[16..20]) as i32,
+                    left_score: payload[20],
+                    right_score: payload[21],
+                    active: payload[22] & 1 != 0,
+                    game_over: payload[22] & 2 != 0,
+                };
+                self.record_paddle(state.host_paddle_y, sent);
+                self.state = Some(state);
+                self.state_received_ms = now;
+            },
+            _ => {},
+        }
+    }
+
+    fn connect(&mut self) {
+        if self.peer_nonce == self.nonce {
+            // Both sides picked the same number; try again with a new one
+            self.nonce = new_nonce();
+            return;
+        }
+        let role = if self.nonce > self.peer_nonce { Role::Host } else { Role::Guest };
+        self.role = Some(role);
+        writeln!(serial(), "Connected over the {} as {role:?}", self.transport.name()).unwrap();
+    }
+
+    fn send_hello(&mut self) {
+        let mut payload = [0u8; 8];
+        payload[0..4].copy_from_slice(&self.nonce.to_le_bytes());
+        payload[4..8].copy_from_slice(&self.peer_nonce.to_le_bytes());
+        self.send(KIND_HELLO, &payload);
+    }
+
+    fn record_paddle(&mut self, y: i32, time: u32) {
+        if self.paddles[1].is_some_and(|latest| latest.time == time) {
+            return;
+        }
+        self.paddles = [self.paddles[1], Some(PaddleSample { y, time })];
+        self.paddle_received_ms = time::uptime_ms();
+    }
+
+    /// How old data received at `received_ms` is by now, counting the trip over the link.
+    fn age_ms(&self, received_ms: u64) -> u64 {
+        let one_way = self.round_trip_ms.unwrap_or(0) as u64 / 2;
+        (one_way + time::uptime_ms() - received_ms).min(MAX_PREDICTION_MS)
+    }
+}
+
+static NETPLAY: Mutex<NetPlay> = Mutex::new(NetPlay::new());
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, byte| sum.rotate_left(1) ^ byte)
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn new_nonce() -> u32 {
+    // Zero means "no nonce yet" on the wire
+    (rand::next_u64() as u32).max(1)
+}
+
+/// Returns true if there is a way to play over `transport`: a serial link port, or a card
+/// with an IPv4 address.
+pub fn available(transport: Transport) -> bool {
+    match transport {
+        Transport::Link => link::is_present(),
+        Transport::Udp => net::interfaces().any(|interface| interface.ipv4().is_some()),
+    }
+}
+
+/// Starts looking for another machine over `transport`. Returns why not if it can't.
+pub fn start(transport: Transport) -> Result<(), &'static str> {
+    let mut netplay = NETPLAY.lock();
+    *netplay = NetPlay::new();
+    match transport {
+        Transport::Link if !available(transport) => return Err("no serial link port"),
+        Transport::Link => {},
+        Transport::Udp if !available(transport) => return Err("no IPv4 address"),
+        Transport::Udp => netplay.socket = Some(UdpSocket::bind(PORT).map_err(|_| "can't bind the port")?),
+    }
+    netplay.transport = transport;
+    netplay.nonce = new_nonce();
+    netplay.last_packet_ms = time::uptime_ms();
+    netplay.send_hello();
+    Ok(())
+}
+
+/// Drops the connection, if any.
+pub fn stop() {
+    *NETPLAY.lock() = NetPlay::new();
+}
+
+/// Handles everything received since the previous call. While not connected yet, it also
+/// says hello again in case the other machine started later.
+pub fn poll() {
+    let mut netplay = NETPLAY.lock();
+    match netplay.transport {
+        Transport::Link => {
+            while let Some(byte) = link::receive() {
+                netplay.receive(byte);
+            }
+        },
+        Transport::Udp => {
+            let mut datagram = [0; MAX_FRAME];
+            while let Some((length, source)) = netplay.socket.as_ref().and_then(|socket| socket.try_recv_from(&mut datagram)) {
+                netplay.receive_datagram(&datagram[..length], source);
+            }
+        },
+    }
+    if netplay.role.is_none() {
+        netplay.send_hello();
+    }
+}
+
+/// Returns what the match is played over.
+pub fn transport() -> Transport {
+    NETPLAY.lock().transport
+}
+
+/// Returns this machine's side once the handshake has finished.
+pub fn role() -> Option<Role> {
+    NETPLAY.lock().role
+}
+
+/// Returns true if nothing has arrived from the other machine for `TIMEOUT_MS`.
+pub fn is_lost() -> bool {
+    time::uptime_ms() - NETPLAY.lock().last_packet_ms > TIMEOUT_MS
+}
+
+/// Returns the smoothed round trip time to the other machine, once it has been measured.
+pub fn round_trip_ms() -> Option<u32> {
+    NETPLAY.lock().round_trip_ms
+}
+
+/// Sends the guest's paddle position to the host.
+pub fn send_paddle(y: i32) {
+    NETPLAY.lock().send(KIND_PADDLE, &y.to_le_bytes());
+}
+
+/// Sends the match state from the host to the guest.
+pub fn send_state(state: &MatchState) {
+    let mut payload = [0u8; 23];
+    for (bytes, value) in payload.chunks_exact_mut(4).zip([
+        state.ball_x,
+        state.ball_y,
+        state.ball_step_x,
+        state.ball_step_y,
+        state.host_paddle_y,
+    ]) {
+        bytes.copy_from_slice(&value.to_le_bytes());
+    }
+    payload[20] = state.left_score;
+    payload[21] = state.right_score;
+    payload[22] = state.active as u8 | (state.game_over as u8) << 1;
+    NETPLAY.lock().send(KIND_STATE, &payload);
+}
+
+/// Predicts where the other machine's paddle is now, carrying on its latest movement for as
+/// long as the position has been on its way.
+pub fn remote_paddle() -> Option<i32> {
+    let netplay = NETPLAY.lock();
+    let latest = netplay.paddles[1]?;
+    let Some(previous) = netplay.paddles[0] else {
+        return Some(latest.y);
+    };
+    let interval = latest.time.wrapping_sub(previous.time).max(1) as i64;
+    let age = netplay.age_ms(netplay.paddle_received_ms) as i64;
+    Some(latest.y + ((latest.y - previous.y) as i64 * age / interval) as i32)
+}
+
+/// Returns the latest match state from the host and how many milliseconds old it is.
+pub fn match_state() -> Option<(MatchState, u64)> {
+    let netplay = NETPLAY.lock();
+    netplay.state.map(|state| (state, netplay.age_ms(netplay.state_received_ms)))
+}
+
+/// Moves the remote paddle, drawn at `shown`, part of the way to `predicted`, so corrections
+/// to the prediction don't make it jump.
+pub fn smooth(shown: i32, predicted: i32) -> i32 {
+    let distance = predicted - shown;
+    if distance.abs() <= SNAP_DISTANCE {
+        predicted
+    } else {
+        shown + distance * SMOOTHING_PERCENT / 100
+    }
+}
@@ -0,0 +1,335 @@
+use alloc::boxed::Box;
+use core::fmt::Write;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering, fence};
+use kernel::serial;
+use kernel::sync::SpinLock;
+use x86_64::PhysAddr;
+use crate::block::{self, BLOCK_SIZE, BlockDevice, Error};
+use crate::memory::{self, Dma, Mmio, PAGE_SIZE};
+use crate::pci::{self, Match, PciDevice};
+use crate::ui::TextBuffer;
+
+// NVMe controllers, which PCI finds by their class, with as little as works: the admin queue
+// pair, to ask the controller what it is and set up the rest, and one I/O queue pair, which
+// reads and writes go through one command at a time. Each queue is a page of DMA memory; the
+// driver puts commands at the submission queue's tail and rings its doorbell, and the
+// controller puts what became of them in the completion queue, whose entries' phase bit flips
+// each time round so new ones can be told from old. Completions are polled for, with the
+// controller's interrupts left off.
+//
+// Only the first namespace is used, as a block device called nvme<controller>n1, and only if
+// it is formatted with 512-byte sectors, as the block layer's blocks are. Data goes through a
+// DMA bounce buffer, whose pages past the first are listed in a PRP list made once.
+const MASS_STORAGE_NVME: Match = Match::Class(0x01, 0x08, 0x02);
+
+// Controller registers, as offsets into BAR 0
+const CAPABILITIES: usize = 0x00;
+const CONFIGURATION: usize = 0x14;
+const STATUS: usize = 0x1C;
+const ADMIN_QUEUE_ATTRIBUTES: usize = 0x24;
+const ADMIN_SUBMISSION_QUEUE: usize = 0x28;
+const ADMIN_COMPLETION_QUEUE: usize = 0x30;
+const DOORBELLS: usize = 0x1000;
+
+// Configuration: enabled, with 64-byte submission and 16-byte completion queue entries
+const ENABLE: u32 = 1;
+const ENTRY_SIZES: u32 = 6 << 16 | 4 << 20;
+// Status bits: ready, and fatal error
+const READY: u32 = 1;
+const FATAL: u32 = 1 << 1;
+
+// Entries in each queue: a page of submissions
+const QUEUE_SIZE: u16 = 64;
+const SUBMISSION_SIZE: usize = 64;
+const COMPLETION_SIZE: usize = 16;
+const ADMIN_QUEUE: u16 = 0;
+const IO_QUEUE: u16 = 1;
+
+// Admin commands
+const CREATE_SUBMISSION_QUEUE: u8 = 0x01;
+const CREATE_COMPLETION_QUEUE: u8 = 0x05;
+const IDENTIFY: u8 = 0x06;
+// What IDENTIFY is about
+const IDENTIFY_NAMESPACE: u32 = 0;
+const IDENTIFY_CONTROLLER: u32 = 1;
+// I/O commands
+const FLUSH: u8 = 0x00;
+const WRITE: u8 = 0x01;
+const READ: u8 = 0x02;
+// Queue creation flag for a queue in physically contiguous memory
+const CONTIGUOUS: u32 = 1;
+const NAMESPACE: u32 = 1;
+
+const BUFFER_PAGES: u64 = 16;
+// How many times to check on the controller before giving up on it
+const TIMEOUT_POLLS: u32 = 10_000_000;
+
+const NAMES: [&str; block::MAX_DEVICES] = ["nvme0n1", "nvme1n1", "nvme2n1", "nvme3n1", "nvme4n1", "nvme5n1", "nvme6n1", "nvme7n1"];
+// Which of `NAMES` the next controller found gets
+static NEXT_NAME: AtomicUsize = AtomicUsize::new(0);
+
+// A submission queue and the completion queue its commands finish on
+struct Queue {
+    id: u16,
+    submissions: Dma,
+    completions: Dma,
+    tail: u16,
+    head: u16,
+    // The phase bit of new completions, which flips each time round the queue
+    phase: bool,
+}
+
+impl Queue {
+    fn new(id: u16) -> Option<Queue> {
+        let submissions = memory::alloc_dma(1)?;
+        let completions = memory::alloc_dma(1)?;
+        Some(Queue { id, submissions, completions, tail: 0, head: 0, phase: true })
+    }
+}
+
+// A controller's registers and queues, and the bounce buffer data goes through
+struct Controller {
+    registers: Mmio,
+    // Bytes between doorbells
+    stride: usize,
+    admin: Queue,
+    io: Queue,
+    buffer: Dma,
+    prp_list: Dma,
+}
+
+impl Controller {
+    fn read(&self, register: usize) -> u32 {
+        self.registers.read32(register)
+    }
+
+    fn write(&self, register: usize, value: u32) {
+        self.registers.write32(register, value)
+    }
+
+    fn write_address(&self, register: usize, address: PhysAddr) {
+        self.write(register, address.as_u64() as u32);
+        self.write(register + 4, (address.as_u64() >> 32) as u32);
+    }
+
+    // Waits until the controller's ready bit is `ready`
+    fn wait_ready(&self, ready: bool) -> Result<(), Error> {
+        for _ in 0..TIMEOUT_POLLS {
+            let status = self.read(STATUS);
+            if status & FATAL != 0 {
+                return Err(Error::Io);
+            }
+            if (status & READY != 0) == ready {
+                return Ok(());
+            }
+        }
+        Err(Error::Io)
+    }
+
+    // Turns the controller off, points it at the admin queues and turns it on again
+    fn reset(&self) -> Result<(), Error> {
+        self.write(CONFIGURATION, self.read(CONFIGURATION) & !ENABLE);
+        self.wait_ready(false)?;
+        let size = u32::from(QUEUE_SIZE - 1);
+        self.write(ADMIN_QUEUE_ATTRIBUTES, size << 16 | size);
+        self.write_address(ADMIN_SUBMISSION_QUEUE, self.admin.submissions.physical);
+        self.write_address(ADMIN_COMPLETION_QUEUE, self.admin.completions.physical);
+        self.write(CONFIGURATION, ENTRY_SIZES | ENABLE);
+        self.wait_ready(true)
+    }
+
+    // Puts `command` on the I/O queue, or the admin queue if `admin`, waits for it to finish
+    // and returns the first dword of its completion
+    fn run(&mut self, admin: bool, mut command: [u32; 16]) -> Result<u32, Error> {
+        let (registers, stride) = (self.registers, self.stride);
+        let queue = if admin { &mut self.admin } else { &mut self.io };
+        // The command's id is its place in the queue, since only one runs at a time
+        command[0] |= u32::from(queue.tail) << 16;
+        let entry: *mut u32 = (queue.submissions.start + usize::from(queue.tail) as u64 * SUBMISSION_SIZE as u64).as_mut_ptr();
+        for (index, value) in command.into_iter().enumerate() {
+            unsafe { entry.add(index).write_volatile(value) };
+        }
+        fence(Ordering::SeqCst);
+        queue.tail = (queue.tail + 1) % QUEUE_SIZE;
+        let doorbell = |index: u16| DOORBELLS + usize::from(index) * stride;
+        registers.write32(doorbell(queue.id * 2), u32::from(queue.tail));
+
+        let completion: *mut u32 = (queue.completions.start + usize::from(queue.head) as u64 * COMPLETION_SIZE as u64).as_mut_ptr();
+        for _ in 0..TIMEOUT_POLLS {
+            let status = unsafe { completion.add(3).read_volatile() } >> 16;
+            if (status & 1 != 0) != queue.phase {
+                continue;
+            }
+            fence(Ordering::SeqCst);
+            let result = unsafe { completion.read_volatile() };
+            queue.head = (queue.head + 1) % QUEUE_SIZE;
+            if queue.head == 0 {
+                queue.phase = !queue.phase;
+            }
+            registers.write32(doorbell(queue.id * 2 + 1), u32::from(queue.head));
+            // The status code and type, past the phase bit
+            return if status >> 1 == 0 { Ok(result) } else { Err(Error::Io) };
+        }
+        Err(Error::Io)
+    }
+
+    // Asks the controller about `about`, into the first page of the bounce buffer
+    fn identify(&mut self, about: u32, namespace: u32) -> Result<&[u8], Error> {
+        let mut command = [0; 16];
+        command[0] = u32::from(IDENTIFY);
+        command[1] = namespace;
+        set_address(&mut command, 6, self.buffer.physical);
+        command[10] = about;
+        self.run(true, command)?;
+        Ok(unsafe { core::slice::from_raw_parts(self.buffer.start.as_ptr(), PAGE_SIZE as usize) })
+    }
+
+    // Makes the I/O queue pair
+    fn create_io_queues(&mut self) -> Result<(), Error> {
+        let size = u32::from(QUEUE_SIZE - 1) << 16 | u32::from(IO_QUEUE);
+        let mut command = [0; 16];
+        command[0] = u32::from(CREATE_COMPLETION_QUEUE);
+        set_address(&mut command, 6, self.io.completions.physical);
+        command[10] = size;
+        command[11] = CONTIGUOUS;
+        self.run(true, command)?;
+
+        let mut command = [0; 16];
+        command[0] = u32::from(CREATE_SUBMISSION_QUEUE);
+        set_address(&mut command, 6, self.io.submissions.physical);
+        command[10] = size;
+        command[11] = u32::from(IO_QUEUE) << 16 | CONTIGUOUS;
+        self.run(true, command).map(|_| ())
+    }
+
+    // Reads or writes `count` sectors from `sector` on, to or from the bounce buffer
+    fn transfer(&mut self, opcode: u8, sector: u64, count: usize) -> Result<(), Error> {
+        let pages = (count * BLOCK_SIZE).div_ceil(PAGE_SIZE as usize);
+        let mut command = [0; 16];
+        command[0] = u32::from(opcode);
+        command[1] = NAMESPACE;
+        set_address(&mut command, 6, self.buffer.physical);
+        // The second page, or the list of them past the first
+        match pages {
+            0 | 1 => {},
+            2 => set_address(&mut command, 8, self.buffer.physical + PAGE_SIZE),
+            _ => set_address(&mut command, 8, self.prp_list.physical),
+        }
+        command[10] = sector as u32;
+        command[11] = (sector >> 32) as u32;
+        // Counts are one less than how many there are
+        command[12] = (count as u32).saturating_sub(1);
+        self.run(false, command).map(|_| ())
+    }
+}
+
+fn set_address(command: &mut [u32; 16], index: usize, address: PhysAddr) {
+    command[index] = address.as_u64() as u32;
+    command[index + 1] = (address.as_u64() >> 32) as u32;
+}
+
+// The first namespace of a controller, as a disk
+struct Namespace {
+    name: &'static str,
+    controller: SpinLock<Controller>,
+    sectors: u64,
+    // The most sectors one command may move
+    max_sectors: usize,
+}
+
+impl BlockDevice for Namespace {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn blocks(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read(&self, block: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        block::check(self.sectors, block, buffer.len())?;
+        let mut controller = self.controller.lock();
+        for (chunk, sectors) in (block..).step_by(self.max_sectors).zip(buffer.chunks_mut(self.max_sectors * BLOCK_SIZE)) {
+            controller.transfer(READ, chunk, sectors.len() / BLOCK_SIZE)?;
+            unsafe { ptr::copy_nonoverlapping(controller.buffer.start.as_ptr(), sectors.as_mut_ptr(), sectors.len()) };
+        }
+        Ok(())
+    }
+
+    fn write(&self, block: u64, bytes: &[u8]) -> Result<(), Error> {
+        block::check(self.sectors, block, bytes.len())?;
+        let mut controller = self.controller.lock();
+        for (chunk, sectors) in (block..).step_by(self.max_sectors).zip(bytes.chunks(self.max_sectors * BLOCK_SIZE)) {
+            unsafe { ptr::copy_nonoverlapping(sectors.as_ptr(), controller.buffer.start.as_mut_ptr(), sectors.len()) };
+            controller.transfer(WRITE, chunk, sectors.len() / BLOCK_SIZE)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        let mut command = [0; 16];
+        command[0] = u32::from(FLUSH);
+        command[1] = NAMESPACE;
+        self.controller.lock().run(false, command).map(|_| ())
+    }
+}
+
+/// Registers the first namespace of each NVMe controller on the PCI bus as a block device,
+/// nvme0n1 on.
+pub const DRIVER: pci::Driver = pci::Driver { name: "NVMe", matches: &[MASS_STORAGE_NVME], probe };
+
+fn probe(function: PciDevice) -> Result<(), &'static str> {
+    let name = NAMES.get(NEXT_NAME.fetch_add(1, Ordering::Relaxed)).copied().ok_or("too many controllers")?;
+    let registers = function.map_bar(0)?;
+    function.enable_bus_master();
+    let Some(namespace) = set_up(registers).map_err(|_| "the controller didn't answer")? else {
+        return Ok(());
+    };
+    if !block::register(Box::leak(Box::new(Namespace { name, ..namespace }))) {
+        writeln!(serial(), "{name}: too many block devices, not registered").unwrap();
+    }
+    Ok(())
+}
+
+// Sets up the controller with `registers`, and returns its first namespace, if it can be used
+fn set_up(registers: Mmio) -> Result<Option<Namespace>, Error> {
+    // The capabilities say how far apart the doorbells are; both queue pairs' have to fit
+    let stride = 4 << (registers.read32(CAPABILITIES + 4) & 0xF);
+    if (DOORBELLS + 4 * stride) as u64 > registers.size() {
+        return Err(Error::Io);
+    }
+    let admin = Queue::new(ADMIN_QUEUE).ok_or(Error::Io)?;
+    let io = Queue::new(IO_QUEUE).ok_or(Error::Io)?;
+    let buffer = memory::alloc_dma(BUFFER_PAGES).ok_or(Error::Io)?;
+    let prp_list = memory::alloc_dma(1).ok_or(Error::Io)?;
+    let list: *mut u64 = prp_list.start.as_mut_ptr();
+    for page in 1..BUFFER_PAGES {
+        unsafe { list.add(page as usize - 1).write_volatile((buffer.physical + page * PAGE_SIZE).as_u64()) };
+    }
+    let mut controller = Controller { registers, stride, admin, io, buffer, prp_list };
+    controller.reset()?;
+
+    let identity = controller.identify(IDENTIFY_CONTROLLER, 0)?;
+    let mut model = TextBuffer::<40>::new();
+    write!(model, "{}", core::str::from_utf8(&identity[24..64]).unwrap_or("").trim()).unwrap();
+    // The most a command may move, as a power of two of the smallest page size; 0 for no limit
+    let limit = match identity[77] {
+        0 => usize::MAX,
+        power => (PAGE_SIZE as usize / BLOCK_SIZE) << power,
+    };
+    let max_sectors = limit.min((BUFFER_PAGES * PAGE_SIZE) as usize / BLOCK_SIZE);
+    controller.create_io_queues()?;
+
+    let identity = controller.identify(IDENTIFY_NAMESPACE, NAMESPACE)?;
+    let sectors = u64::from_le_bytes(identity[0..8].try_into().unwrap());
+    // The sector size of the format in use, as a power of two
+    let format = 128 + 4 * usize::from(identity[26] & 0xF);
+    let sector_size = 1u64 << identity[format + 2];
+    if sectors == 0 || sector_size != BLOCK_SIZE as u64 {
+        writeln!(serial(), "NVMe {}: namespace 1 has {sectors} sectors of {sector_size} bytes, not used", model.as_str()).unwrap();
+        return Ok(None);
+    }
+    writeln!(serial(), "NVMe {}: namespace 1, {sectors} sectors", model.as_str()).unwrap();
+    Ok(Some(Namespace { name: "", controller: SpinLock::new(controller), sectors, max_sectors }))
+}
@@ -0,0 +1,109 @@
+use crate::rand;
+use crate::screen::Surface;
+use crate::ui::Color;
+
+// Positions and velocities are in 1/256 pixel steps so slow particles still drift smoothly
+const FIXED_SHIFT: u32 = 8;
+const PARTICLE_SIZE: usize = 3;
+
+#[derive(Clone, Copy)]
+struct Particle {
+    x: i32,
+    y: i32,
+    vx: i32,
+    vy: i32,
+    life: u16,
+    lifetime: u16,
+    color: Color,
+}
+
+const DEAD: Particle = Particle { x: 0, y: 0, vx: 0, vy: 0, life: 0, lifetime: 1, color: (0, 0, 0) };
+
+/// A fixed pool of at most `N` short-lived particles that fade out as they age.
+/// Spawning into a full pool does nothing, so effects can never cost more than `N`
+/// particles' worth of drawing per frame.
+pub struct ParticleSystem<const N: usize> {
+    particles: [Particle; N],
+    count: usize,
+}
+
+impl<const N: usize> ParticleSystem<N> {
+    pub const fn new() -> Self {
+        ParticleSystem { particles: [DEAD; N], count: 0 }
+    }
+
+    /// Returns true if no particles are alive.
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Adds a particle at (x, y) pixels moving by (vx, vy) 1/256 pixels per update, which lives
+    /// for `lifetime` updates. Returns false if the pool is full.
+    pub fn spawn(&mut self, x: usize, y: usize, vx: i32, vy: i32, lifetime: u16, color: Color) -> bool {
+        if self.count == N || lifetime == 0 {
+            return false;
+        }
+        self.particles[self.count] = Particle {
+            x: (x as i32) << FIXED_SHIFT,
+            y: (y as i32) << FIXED_SHIFT,
+            vx,
+            vy,
+            life: lifetime,
+            lifetime,
+            color,
+        };
+        self.count += 1;
+        true
+    }
+
+    /// Spawns up to `count` particles at (x, y) flying off in random directions at up to
+    /// `speed` 1/256 pixels per update.
+    pub fn burst(&mut self, x: usize, y: usize, count: usize, speed: u32, lifetime: u16, color: Color) {
+        for _ in 0..count {
+            let vx = rand::below(2 * speed + 1) as i32 - speed as i32;
+            let vy = rand::below(2 * speed + 1) as i32 - speed as i32;
+            if !self.spawn(x, y, vx, vy, lifetime, color) {
+                break;
+            }
+        }
+    }
+
+    /// Moves every particle and removes those that have run out of life.
+    pub fn update(&mut self) {
+        let mut i = 0;
+        while i < self.count {
+            let particle = &mut self.particles[i];
+            particle.x += particle.vx;
+            particle.y += particle.vy;
+            particle.life -= 1;
+            if particle.life == 0 || particle.x < 0 || particle.y < 0 {
+                // order doesn't matter, so fill the gap with the last particle
+                self.count -= 1;
+                self.particles[i] = self.particles[self.count];
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    /// Blends every particle onto `surface`, more transparent the older it is.
+    pub fn draw(&self, surface: &mut Surface) {
+        let (width, height) = (surface.width(), surface.height());
+        for particle in &self.particles[..self.count] {
+            let alpha = (255 * particle.life as u32 / particle.lifetime as u32) as u8;
+            let (x, y) = ((particle.x >> FIXED_SHIFT) as usize, (particle.y >> FIXED_SHIFT) as usize);
+            let (r, g, b) = particle.color;
+            for py in y..(y + PARTICLE_SIZE).min(height) {
+                for px in x..(x + PARTICLE_SIZE).min(width) {
+                    surface.blend_pixel(px, py, r, g, b, alpha);
+                }
+            }
+        }
+    }
+}
+
+impl<const N: usize> Default for ParticleSystem<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,232 @@
+use alloc::boxed::Box;
+use alloc::format;
+use core::fmt::Write;
+use kernel::serial;
+use crate::block::{self, BLOCK_SIZE, BlockDevice, Error};
+
+// Partition tables, which split a disk into parts that each have a filesystem of their own.
+// Each partition found is registered as a block device in itself, named after its disk with
+// its number added (ata0p1, vda2), whose sectors are its own counted from 0, so filesystems
+// mount partitions the same as whole disks. The disk is claimed, so nothing is mounted from
+// it over them. Two kinds of table are read:
+//
+// - MBR: four entries at the end of the first sector, giving each partition's type, first
+//   sector and length. An extended partition holds more, logical ones from number 5 on, each
+//   with a sector before it that has its entry and the next one's place in the extended one.
+// - GPT: a header in the second sector, and an array of entries it points at with each
+//   partition's first and last sectors. Its disks have an MBR with a single entry of type
+//   0xEE covering the disk, so older tools leave them alone.
+//
+// The GPT's checksums aren't checked, nor is the copy at the end of the disk read.
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const MBR_ENTRIES: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+// An entry's status is one of these, or it isn't a partition table
+const INACTIVE: u8 = 0x00;
+const ACTIVE: u8 = 0x80;
+const EMPTY: u8 = 0x00;
+const EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+const GPT_PROTECTIVE: u8 = 0xEE;
+// Where a FAT32 volume's boot sector, which has the same signature, names its kind
+const FAT32_NAME: usize = 82;
+// Logical partitions are numbered after the four primary ones
+const FIRST_LOGICAL: usize = 5;
+// In case a chain of logical partitions goes round in a circle
+const MAX_LOGICAL: usize = 32;
+
+const GPT_HEADER: u64 = 1;
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+// Where the header keeps the entries' first sector, how many there are and how big each is
+const GPT_ENTRIES_START: usize = 72;
+const GPT_ENTRY_COUNT: usize = 80;
+const GPT_ENTRY_SIZE: usize = 84;
+const MIN_GPT_ENTRY_SIZE: usize = 128;
+const MAX_GPT_ENTRIES: u32 = 128;
+// An entry's first and last sectors, after its type and its own id
+const GPT_FIRST: usize = 32;
+const GPT_LAST: usize = 40;
+
+// A partition, sectors `start` to `start + sectors` of `disk`
+struct Partition {
+    name: &'static str,
+    disk: &'static dyn BlockDevice,
+    start: u64,
+    sectors: u64,
+}
+
+impl BlockDevice for Partition {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn sector_size(&self) -> usize {
+        self.disk.sector_size()
+    }
+
+    fn blocks(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read(&self, block: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        block::check(self.sectors, block, buffer.len())?;
+        self.disk.read(self.start + block, buffer)
+    }
+
+    fn write(&self, block: u64, bytes: &[u8]) -> Result<(), Error> {
+        block::check(self.sectors, block, bytes.len())?;
+        self.disk.write(self.start + block, bytes)
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        self.disk.flush()
+    }
+}
+
+// A partition's number and where it is on its disk
+type Found = (usize, u64, u64);
+
+fn u32_at(sector: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(sector[at..at + 4].try_into().unwrap())
+}
+
+fn u64_at(sector: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(sector[at..at + 8].try_into().unwrap())
+}
+
+// The type, first sector and length of each of the four entries in `sector`, or None if it
+// isn't a partition table: a FAT32 boot sector ends the same way, but with boot code where the
+// entries would be, which doesn't look like them
+fn mbr_entries(sector: &[u8; BLOCK_SIZE]) -> Option<[(u8, u64, u64); 4]> {
+    if sector[510..] != MBR_SIGNATURE || &sector[FAT32_NAME..FAT32_NAME + 5] == b"FAT32" {
+        return None;
+    }
+    let mut entries = [(EMPTY, 0, 0); 4];
+    for (index, entry) in entries.iter_mut().enumerate() {
+        let raw = &sector[MBR_ENTRIES + index * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+        if raw[0] != INACTIVE && raw[0] != ACTIVE {
+            return None;
+        }
+        *entry = (raw[4], u64::from(u32_at(raw, 8)), u64::from(u32_at(raw, 12)));
+    }
+    Some(entries)
+}
+
+// Reads the MBR on `disk`, or the GPT if the MBR says there is one, into `found`, and returns
+// how many partitions it found
+fn read_table(disk: &dyn BlockDevice, found: &mut [Found; block::MAX_DEVICES]) -> Result<usize, Error> {
+    // The tables are laid out in 512-byte sectors
+    if disk.sector_size() != BLOCK_SIZE {
+        return Ok(0);
+    }
+    let mut sector = [0; BLOCK_SIZE];
+    disk.read(0, &mut sector)?;
+    let Some(entries) = mbr_entries(&sector) else {
+        return Ok(0);
+    };
+    if entries.iter().any(|&(kind, _, _)| kind == GPT_PROTECTIVE) {
+        return read_gpt(disk, found);
+    }
+
+    let mut count = 0;
+    let mut add = |number: usize, start: u64, sectors: u64| {
+        // Partitions past the end of the disk are from some other disk's table
+        if sectors != 0 && start.checked_add(sectors).is_some_and(|end| end <= disk.blocks()) && count < found.len() {
+            found[count] = (number, start, sectors);
+            count += 1;
+        }
+    };
+    for (index, &(kind, start, sectors)) in entries.iter().enumerate() {
+        if kind == EMPTY {
+            continue;
+        }
+        if !EXTENDED.contains(&kind) {
+            add(index + 1, start, sectors);
+            continue;
+        }
+        // Each logical partition's entry is relative to the sector before it, and the next one's
+        // place to the start of the extended partition
+        let mut next = 0;
+        for number in FIRST_LOGICAL..FIRST_LOGICAL + MAX_LOGICAL {
+            let table = start + next;
+            if disk.read(table, &mut sector).is_err() {
+                break;
+            }
+            let Some([(kind, logical_start, logical_sectors), (_, link, _), ..]) = mbr_entries(&sector) else {
+                break;
+            };
+            if kind != EMPTY {
+                add(number, table + logical_start, logical_sectors);
+            }
+            if link == 0 {
+                break;
+            }
+            next = link;
+        }
+    }
+    Ok(count)
+}
+
+fn read_gpt(disk: &dyn BlockDevice, found: &mut [Found; block::MAX_DEVICES]) -> Result<usize, Error> {
+    let mut header = [0; BLOCK_SIZE];
+    disk.read(GPT_HEADER, &mut header)?;
+    let entry_size = u32_at(&header, GPT_ENTRY_SIZE) as usize;
+    // Entries are a power of two long, at least 128 bytes, so they never cross a sector
+    if &header[..8] != GPT_SIGNATURE || entry_size < MIN_GPT_ENTRY_SIZE || !entry_size.is_power_of_two() || entry_size > BLOCK_SIZE {
+        return Ok(0);
+    }
+    let start = u64_at(&header, GPT_ENTRIES_START);
+    let entries = u32_at(&header, GPT_ENTRY_COUNT).min(MAX_GPT_ENTRIES) as usize;
+
+    let mut count = 0;
+    let mut sector = [0; BLOCK_SIZE];
+    for index in 0..entries {
+        let at = index * entry_size;
+        if at.is_multiple_of(BLOCK_SIZE) {
+            disk.read(start + (at / BLOCK_SIZE) as u64, &mut sector)?;
+        }
+        let entry = &sector[at % BLOCK_SIZE..][..entry_size];
+        // An entry with no type is unused
+        if entry[..16].iter().all(|&byte| byte == 0) {
+            continue;
+        }
+        let (first, last) = (u64_at(entry, GPT_FIRST), u64_at(entry, GPT_LAST));
+        if first > last || last >= disk.blocks() {
+            continue;
+        }
+        if count == found.len() {
+            break;
+        }
+        found[count] = (index + 1, first, last - first + 1);
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Reads the partition table on each registered block device that has one, and registers its
+/// partitions as block devices of their own. Called once during boot, after the disk drivers'
+/// `init` and before `fs::mount_disks`.
+pub fn scan() {
+    for disk in block::devices() {
+        let mut found = [(0, 0, 0); block::MAX_DEVICES];
+        let count = match read_table(disk, &mut found) {
+            Ok(count) => count,
+            Err(error) => {
+                writeln!(serial(), "{}: can't read the partition table: {error:?}", disk.name()).unwrap();
+                continue;
+            },
+        };
+        if count == 0 || !block::claim(disk.name()) {
+            continue;
+        }
+        for &(number, start, sectors) in &found[..count] {
+            // Linux's way: a p goes between a name ending in a digit and the number
+            let separator = if disk.name().ends_with(|c: char| c.is_ascii_digit()) { "p" } else { "" };
+            let name = format!("{}{separator}{number}", disk.name()).leak();
+            writeln!(serial(), "{name}: partition of {}, {sectors} sectors from {start}", disk.name()).unwrap();
+            // Partitions are found once, at boot, so they may as well stay allocated
+            if !block::register(Box::leak(Box::new(Partition { name, disk, start, sectors }))) {
+                writeln!(serial(), "{name}: too many block devices, not registered").unwrap();
+            }
+        }
+    }
+}
@@ -0,0 +1,775 @@
+use core::fmt::{self, Write};
+use kernel::serial;
+use kernel::sync::SpinLock;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::{PhysAddr, VirtAddr};
+use crate::memory::{self, Mmio};
+use crate::{acpi, ahci, config, e1000, nvme, rtl8139, virtio_blk, virtio_net};
+
+// PCI configuration space, through configuration mechanism #1: the bus, device, function and
+// register go in CONFIG_ADDRESS, then the register is read or written at CONFIG_DATA, 32 bits
+// at a time. That only reaches each function's first 256 bytes. PCI Express has 4 KiB, which
+// ECAM maps into memory, 1 MiB for each bus, where the ACPI MCFG table says; when there is
+// one, each bus's is mapped the first time one of its functions is used, and configuration
+// space is read and written there instead. Every bus is scanned once at boot, and what each function is and where its
+// registers are is kept. Then each function is offered to the drivers in `DRIVERS`, and the first
+// whose matches take it sets it up.
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+const ENABLE: u32 = 1 << 31;
+// What ECAM maps for each bus, and for each function on it
+const ECAM_BUS_SIZE: u64 = 1 << 20;
+/// The size of a function's configuration space with ECAM.
+pub const EXTENDED_SIZE: u16 = 0x1000;
+
+// Registers, as offsets into a function's configuration space
+const VENDOR_ID: u8 = 0x00;
+const COMMAND: u8 = 0x04;
+const STATUS: u8 = 0x06;
+const CLASS: u8 = 0x08;
+const HEADER_TYPE: u8 = 0x0E;
+const BARS: u8 = 0x10;
+const CAPABILITIES_POINTER: u8 = 0x34;
+const INTERRUPT_LINE: u8 = 0x3C;
+const INTERRUPT_PIN: u8 = 0x3D;
+
+// Capability ids, and the most capabilities a list is followed for, in case it loops
+const CAPABILITY_POWER_MANAGEMENT: u8 = 0x01;
+const CAPABILITY_MSI: u8 = 0x05;
+const CAPABILITY_MSI_X: u8 = 0x11;
+const MAX_CAPABILITIES: usize = 48;
+
+// No function answers with this vendor
+const NO_VENDOR: u16 = 0xFFFF;
+// Header type bit for a device with more functions than the first
+const MULTI_FUNCTION: u8 = 0x80;
+
+const BAR_COUNT: usize = 6;
+// Functions kept from the scan, more than any machine this runs on has
+const MAX_FUNCTIONS: usize = 64;
+
+/// Command register bits: the function answers at its I/O ports.
+pub const IO_SPACE: u16 = 1 << 0;
+/// The function answers at its memory.
+pub const MEMORY_SPACE: u16 = 1 << 1;
+/// The function reads and writes memory itself.
+pub const BUS_MASTER: u16 = 1 << 2;
+/// The function doesn't raise its interrupt pin, as it signals with MSI instead.
+pub const INTERRUPT_DISABLE: u16 = 1 << 10;
+
+/// Status register bits: the function has a list of capabilities.
+pub const CAPABILITIES_LIST: u16 = 1 << 4;
+/// Its interrupt pin is raised.
+pub const INTERRUPT_STATUS: u16 = 1 << 3;
+/// Errors it saw or signalled, each cleared by writing it back.
+pub const PARITY_ERROR_DETECTED: u16 = 1 << 15;
+pub const SIGNALED_SYSTEM_ERROR: u16 = 1 << 14;
+pub const RECEIVED_MASTER_ABORT: u16 = 1 << 13;
+pub const RECEIVED_TARGET_ABORT: u16 = 1 << 12;
+
+// Where ECAM maps segment 0's configuration space, for buses `first_bus` to `last_bus`
+#[derive(Clone, Copy)]
+struct Ecam {
+    physical: PhysAddr,
+    first_bus: u8,
+    last_bus: u8,
+}
+
+static ECAM: SpinLock<Option<Ecam>> = SpinLock::new(None);
+// Where each bus's configuration space is mapped, once it has been
+static ECAM_BUSES: SpinLock<[Option<VirtAddr>; 256]> = SpinLock::new([None; 256]);
+
+/// A function of a device on the PCI bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciDevice {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciDevice {
+    /// Reads the 32-bit register at `offset`, which is rounded down to a multiple of 4.
+    pub fn read(&self, offset: u8) -> u32 {
+        match self.read_extended(u16::from(offset)) {
+            Some(value) => value,
+            None => self.read_port(offset),
+        }
+    }
+
+    /// Writes the 32-bit register at `offset`, which is rounded down to a multiple of 4.
+    pub fn write(&self, offset: u8, value: u32) {
+        if !self.write_extended(u16::from(offset), value) {
+            self.write_port(offset, value);
+        }
+    }
+
+    /// Reads the 32-bit register at `offset` anywhere in the function's 4 KiB of PCI Express
+    /// configuration space, or returns None without ECAM to reach it.
+    pub fn read_extended(&self, offset: u16) -> Option<u32> {
+        let register = self.ecam_register(offset)?;
+        Some(unsafe { register.read_volatile() })
+    }
+
+    /// Writes the 32-bit register at `offset` in the function's PCI Express configuration
+    /// space, and returns whether there was ECAM to reach it.
+    pub fn write_extended(&self, offset: u16, value: u32) -> bool {
+        let Some(register) = self.ecam_register(offset) else {
+            return false;
+        };
+        unsafe { register.write_volatile(value) };
+        true
+    }
+
+    // Where the register at `offset` is mapped with ECAM, mapping the function's bus first if
+    // it hasn't been
+    fn ecam_register(&self, offset: u16) -> Option<*mut u32> {
+        if offset >= EXTENDED_SIZE {
+            return None;
+        }
+        let ecam = (*ECAM.lock_irq())?;
+        if !(ecam.first_bus..=ecam.last_bus).contains(&self.bus) {
+            return None;
+        }
+        let mut buses = ECAM_BUSES.lock_irq();
+        let bus = match buses[usize::from(self.bus)] {
+            Some(bus) => bus,
+            None => {
+                let physical = ecam.physical + u64::from(self.bus - ecam.first_bus) * ECAM_BUS_SIZE;
+                let bus = memory::map_mmio(physical, ECAM_BUS_SIZE)?;
+                buses[usize::from(self.bus)] = Some(bus);
+                bus
+            },
+        };
+        let function = u64::from(self.device) << 15 | u64::from(self.function) << 12;
+        Some((bus + function + u64::from(offset & !3)).as_mut_ptr())
+    }
+
+    // Configuration mechanism #1, which needs no mapping, so the scan uses it to look for
+    // functions without mapping every bus
+    fn read_port(&self, offset: u8) -> u32 {
+        interrupts::without_interrupts(|| unsafe {
+            Port::<u32>::new(CONFIG_ADDRESS).write(self.address(offset));
+            Port::<u32>::new(CONFIG_DATA).read()
+        })
+    }
+
+    fn write_port(&self, offset: u8, value: u32) {
+        interrupts::without_interrupts(|| unsafe {
+            Port::<u32>::new(CONFIG_ADDRESS).write(self.address(offset));
+            Port::<u32>::new(CONFIG_DATA).write(value);
+        })
+    }
+
+    /// Reads the 16-bit register at `offset`, which is rounded down to a multiple of 2.
+    pub fn read16(&self, offset: u8) -> u16 {
+        (self.read(offset) >> (u32::from(offset & 2) * 8)) as u16
+    }
+
+    /// Reads the 8-bit register at `offset`.
+    pub fn read8(&self, offset: u8) -> u8 {
+        (self.read(offset) >> (u32::from(offset & 3) * 8)) as u8
+    }
+
+    /// Writes the 16-bit register at `offset`, which is rounded down to a multiple of 2, and
+    /// the other half of its 32 bits as they read. Not for the command and status registers,
+    /// where writing the status back would clear it.
+    pub fn write16(&self, offset: u8, value: u16) {
+        let shift = u32::from(offset & 2) * 8;
+        let old = self.read(offset) & !(0xFFFF << shift);
+        self.write(offset, old | u32::from(value) << shift);
+    }
+
+    /// Writes the 8-bit register at `offset`, and the rest of its 32 bits as they read, with
+    /// the same caveat as `write16`.
+    pub fn write8(&self, offset: u8, value: u8) {
+        let shift = u32::from(offset & 3) * 8;
+        let old = self.read(offset) & !(0xFF << shift);
+        self.write(offset, old | u32::from(value) << shift);
+    }
+
+    fn address(&self, offset: u8) -> u32 {
+        ENABLE | u32::from(self.bus) << 16 | u32::from(self.device) << 11 | u32::from(self.function) << 8 | u32::from(offset & 0xFC)
+    }
+
+    pub fn vendor_id(&self) -> u16 {
+        self.read16(VENDOR_ID)
+    }
+
+    pub fn device_id(&self) -> u16 {
+        self.read16(VENDOR_ID + 2)
+    }
+
+    /// The function's class, subclass and programming interface.
+    pub fn class(&self) -> (u8, u8, u8) {
+        let [_, prog_if, subclass, class] = self.read(CLASS).to_le_bytes();
+        (class, subclass, prog_if)
+    }
+
+    // Whether a function answers here, and its header type, both through configuration
+    // mechanism #1 for the scan
+    fn present(&self) -> bool {
+        self.read_port(VENDOR_ID) as u16 != NO_VENDOR
+    }
+
+    fn header_type(&self) -> u8 {
+        (self.read_port(HEADER_TYPE) >> (u32::from(HEADER_TYPE & 3) * 8)) as u8
+    }
+
+    /// The command register, which turns the function's ports, memory, DMA and interrupt
+    /// pin on and off.
+    pub fn command(&self) -> u16 {
+        self.read16(COMMAND)
+    }
+
+    /// Writes the command register, leaving the status register as it is.
+    pub fn set_command(&self, command: u16) {
+        // Status bits are cleared by writing ones, so its half is written as zeros
+        self.write(COMMAND, u32::from(command));
+    }
+
+    // Sets or clears `bits` of the command register
+    fn switch(&self, bits: u16, on: bool) {
+        let command = self.command();
+        self.set_command(if on { command | bits } else { command & !bits });
+    }
+
+    /// Lets the function read and write memory itself, or stops it.
+    pub fn set_bus_master(&self, on: bool) {
+        self.switch(BUS_MASTER, on);
+    }
+
+    /// Lets the function answer at its memory, or stops it, as while sizing a base address
+    /// register.
+    pub fn set_memory_space(&self, on: bool) {
+        self.switch(MEMORY_SPACE, on);
+    }
+
+    /// Lets the function answer at its I/O ports, or stops it.
+    pub fn set_io_space(&self, on: bool) {
+        self.switch(IO_SPACE, on);
+    }
+
+    /// Stops the function raising its interrupt pin, or lets it again.
+    pub fn set_interrupt_disable(&self, disabled: bool) {
+        self.switch(INTERRUPT_DISABLE, disabled);
+    }
+
+    /// The status register.
+    pub fn status(&self) -> u16 {
+        self.read16(STATUS)
+    }
+
+    /// Clears the error bits of the status register in `bits`.
+    pub fn clear_status(&self, bits: u16) {
+        self.write(COMMAND, u32::from(bits) << 16 | u32::from(self.command()));
+    }
+
+    /// Base address register `index`, as it reads, the type bits with the address.
+    pub fn bar(&self, index: u8) -> u32 {
+        self.read(BARS + index * 4)
+    }
+
+    /// Writes base address register `index`.
+    pub fn set_bar(&self, index: u8, value: u32) {
+        self.write(BARS + index * 4, value);
+    }
+
+    /// The physical address of the memory that base address register `index` points at, or
+    /// None if it is unused or for I/O ports.
+    pub fn memory_bar(&self, index: u8) -> Option<u64> {
+        let low = self.bar(index);
+        if low == 0 || low & 1 != 0 {
+            return None;
+        }
+        // Bits 1 and 2 are 2 for a 64-bit address, whose top half is in the next register
+        let high = if (low >> 1) & 3 == 2 { self.bar(index + 1) } else { 0 };
+        Some(u64::from(high) << 32 | u64::from(low & !0xF))
+    }
+
+    /// How many bytes of memory or I/O ports base address register `index` covers, or 0 if
+    /// it is unused. Found by writing ones to it and seeing which bits stay, with the function
+    /// kept from answering at its memory and ports meanwhile.
+    pub fn bar_size(&self, index: u8) -> u64 {
+        interrupts::without_interrupts(|| {
+            let command = self.command();
+            self.set_command(command & !(IO_SPACE | MEMORY_SPACE));
+            let low = self.bar(index);
+            let io = low & 1 != 0;
+            let wide = !io && (low >> 1) & 3 == 2;
+            self.set_bar(index, u32::MAX);
+            let mut mask = u64::from(self.bar(index));
+            self.set_bar(index, low);
+            if wide {
+                let high = self.bar(index + 1);
+                self.set_bar(index + 1, u32::MAX);
+                mask |= u64::from(self.bar(index + 1)) << 32;
+                self.set_bar(index + 1, high);
+            } else {
+                mask |= 0xFFFF_FFFF << 32;
+            }
+            self.set_command(command);
+            // The type bits at the bottom never change
+            let mask = mask & if io { !0x3 } else { !0xF };
+            if mask as u32 == 0 && !wide { 0 } else { (!mask).wrapping_add(1) }
+        })
+    }
+
+    /// Maps all of the memory base address register `index` points at, uncached, with the
+    /// function answering at it.
+    pub fn map_bar(&self, index: u8) -> Result<Mmio, &'static str> {
+        let address = self.memory_bar(index).ok_or("no memory at the BAR")?;
+        let size = self.bar_size(index);
+        if size == 0 {
+            return Err("no memory at the BAR");
+        }
+        let registers = Mmio::map(PhysAddr::new(address), size).ok_or("no room to map the BAR")?;
+        self.set_memory_space(true);
+        Ok(registers)
+    }
+
+    /// The first of the I/O ports that base address register `index` points at, or None if
+    /// it is unused or for memory.
+    pub fn io_bar(&self, index: u8) -> Option<u16> {
+        let bar = self.bar(index);
+        (bar & 1 != 0).then_some((bar & !0x3) as u16).filter(|&port| port != 0)
+    }
+
+    /// The I/O APIC input the function raises its interrupt on, as the firmware set it up.
+    pub fn interrupt_line(&self) -> u8 {
+        self.read8(INTERRUPT_LINE)
+    }
+
+    /// Sets the I/O APIC input the function's interrupt is routed to, for other drivers to
+    /// read.
+    pub fn set_interrupt_line(&self, line: u8) {
+        self.write8(INTERRUPT_LINE, line);
+    }
+
+    /// The interrupt pin the function raises, 1 to 4 for INTA# to INTD#, or None if it has
+    /// none.
+    pub fn interrupt_pin(&self) -> Option<u8> {
+        Some(self.read8(INTERRUPT_PIN)).filter(|&pin| pin != 0)
+    }
+
+    /// The offset of the first capability in the function's list, if it has one.
+    pub fn capabilities_pointer(&self) -> Option<u8> {
+        if self.status() & CAPABILITIES_LIST == 0 {
+            return None;
+        }
+        // The bottom two bits are reserved
+        Some(self.read8(CAPABILITIES_POINTER) & 0xFC).filter(|&offset| offset != 0)
+    }
+
+    /// Walks the function's list of capabilities, each read as it is reached.
+    pub fn capabilities(&self) -> impl Iterator<Item = Capability> {
+        let device = *self;
+        let mut next = self.capabilities_pointer();
+        core::iter::from_fn(move || {
+            // Capabilities come after the header, and all of one fits before the end
+            let offset = next.filter(|offset| (0x40..=0xF4).contains(offset))?;
+            let header = device.read16(offset);
+            next = Some((header >> 8) as u8 & 0xFC).filter(|&offset| offset != 0);
+            Some(Capability::read(device, header as u8, offset))
+        }).take(MAX_CAPABILITIES)
+    }
+
+    /// The function's MSI capability, if it has one.
+    pub fn msi(&self) -> Option<Msi> {
+        self.capabilities().find_map(|capability| match capability {
+            Capability::Msi(msi) => Some(msi),
+            _ => None,
+        })
+    }
+
+    /// The function's MSI-X capability, if it has one.
+    pub fn msi_x(&self) -> Option<MsiX> {
+        self.capabilities().find_map(|capability| match capability {
+            Capability::MsiX(msi_x) => Some(msi_x),
+            _ => None,
+        })
+    }
+
+    /// The function's power management capability, if it has one.
+    pub fn power_management(&self) -> Option<PowerManagement> {
+        self.capabilities().find_map(|capability| match capability {
+            Capability::PowerManagement(power) => Some(power),
+            _ => None,
+        })
+    }
+
+    /// The best way the function can signal its interrupts: MSI-X, then MSI, then its pin.
+    pub fn interrupt_delivery(&self) -> Delivery {
+        let (mut msi, mut msi_x) = (None, None);
+        for capability in self.capabilities() {
+            match capability {
+                Capability::Msi(found) => msi = Some(found),
+                Capability::MsiX(found) => msi_x = Some(found),
+                _ => {},
+            }
+        }
+        match (msi_x, msi, self.interrupt_pin()) {
+            (Some(msi_x), _, _) => Delivery::MsiX(msi_x),
+            (None, Some(msi), _) => Delivery::Msi(msi),
+            (None, None, Some(pin)) => Delivery::Pin { pin, line: self.interrupt_line() },
+            (None, None, None) => Delivery::None,
+        }
+    }
+
+    /// Lets the function answer at the ports and memory its registers point at, and read and
+    /// write memory itself, which its driver has to before using it.
+    pub fn enable_bus_master(&self) {
+        self.switch(IO_SPACE | MEMORY_SPACE | BUS_MASTER, true);
+    }
+}
+
+/// Where a base address register points.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    /// Unused, or the top half of the 64-bit address before it.
+    None,
+    Memory { address: u64, wide: bool, prefetchable: bool },
+    Io(u16),
+}
+
+/// A capability in a function's list, read when the list was walked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    PowerManagement(PowerManagement),
+    Msi(Msi),
+    MsiX(MsiX),
+    /// One this doesn't read, by its id.
+    Other { id: u8, offset: u8 },
+}
+
+/// Power management: the power states the function has besides D0 and D3, and the one it is in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PowerManagement {
+    pub offset: u8,
+    pub d1: bool,
+    pub d2: bool,
+    /// The state it is in, 0 to 3 for D0 to D3hot.
+    pub state: u8,
+}
+
+/// Message signalled interrupts: the function writes a message to the local APIC instead of
+/// raising its pin, for up to 32 vectors in a row.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Msi {
+    pub offset: u8,
+    pub enabled: bool,
+    /// How many vectors it can use, a power of two.
+    pub vectors: u8,
+    /// Whether it takes a 64-bit message address.
+    pub wide: bool,
+    /// Whether each vector can be masked.
+    pub masking: bool,
+}
+
+/// MSI-X: like MSI, but each vector has its own address and message, in a table in memory one
+/// of the base address registers points at, with a bit array of pending vectors beside it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MsiX {
+    pub offset: u8,
+    pub enabled: bool,
+    pub vectors: u16,
+    pub table_bar: u8,
+    pub table_offset: u32,
+    pub pending_bar: u8,
+    pub pending_offset: u32,
+}
+
+impl Capability {
+    fn read(device: PciDevice, id: u8, offset: u8) -> Capability {
+        let control = device.read16(offset + 2);
+        match id {
+            CAPABILITY_POWER_MANAGEMENT => Capability::PowerManagement(PowerManagement {
+                offset,
+                d1: control & (1 << 9) != 0,
+                d2: control & (1 << 10) != 0,
+                state: device.read8(offset + 4) & 3,
+            }),
+            CAPABILITY_MSI => Capability::Msi(Msi {
+                offset,
+                enabled: control & 1 != 0,
+                vectors: 1 << ((control >> 1) & 7).min(5),
+                wide: control & (1 << 7) != 0,
+                masking: control & (1 << 8) != 0,
+            }),
+            CAPABILITY_MSI_X => {
+                // The BAR is in the bottom three bits of each offset, which are multiples of 8
+                let (table, pending) = (device.read(offset + 4), device.read(offset + 8));
+                Capability::MsiX(MsiX {
+                    offset,
+                    enabled: control & (1 << 15) != 0,
+                    vectors: (control & 0x7FF) + 1,
+                    table_bar: (table & 7) as u8,
+                    table_offset: table & !7,
+                    pending_bar: (pending & 7) as u8,
+                    pending_offset: pending & !7,
+                })
+            },
+            id => Capability::Other { id, offset },
+        }
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Capability::PowerManagement(power) => {
+                write!(f, "power management, in D{}", power.state)?;
+                for (name, has) in [("D1", power.d1), ("D2", power.d2)] {
+                    if has {
+                        write!(f, ", {name}")?;
+                    }
+                }
+                Ok(())
+            },
+            Capability::Msi(msi) => {
+                let width = if msi.wide { 64 } else { 32 };
+                let enabled = if msi.enabled { "enabled" } else { "disabled" };
+                write!(f, "MSI, {} vectors, {width}-bit, {enabled}", msi.vectors)
+            },
+            Capability::MsiX(msi_x) => {
+                let enabled = if msi_x.enabled { "enabled" } else { "disabled" };
+                write!(f, "MSI-X, {} vectors, table in BAR {} at {:#x}, {enabled}", msi_x.vectors, msi_x.table_bar, msi_x.table_offset)
+            },
+            Capability::Other { id, offset } => write!(f, "{id:#04x} at {offset:#x}"),
+        }
+    }
+}
+
+/// How a function signals its interrupts, best first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    MsiX(MsiX),
+    Msi(Msi),
+    /// Its pin, INTA# to INTD# as 1 to 4, routed to I/O APIC input `line`.
+    Pin { pin: u8, line: u8 },
+    None,
+}
+
+/// A function found on the PCI bus at boot, and what it said it was.
+#[derive(Debug, Clone, Copy)]
+pub struct Function {
+    pub device: PciDevice,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    /// Class, subclass and programming interface.
+    pub class: (u8, u8, u8),
+    pub bars: [Bar; BAR_COUNT],
+    /// The driver that set it up, if one did.
+    pub driver: Option<&'static str>,
+}
+
+impl Function {
+    fn read(device: PciDevice) -> Function {
+        let mut bars = [Bar::None; BAR_COUNT];
+        let mut index = 0;
+        while index < BAR_COUNT {
+            let low = device.bar(index as u8);
+            if low & 1 != 0 {
+                bars[index] = device.io_bar(index as u8).map_or(Bar::None, Bar::Io);
+            } else if low != 0 {
+                let wide = (low >> 1) & 3 == 2;
+                let address = device.memory_bar(index as u8).unwrap();
+                bars[index] = Bar::Memory { address, wide, prefetchable: low & 0x8 != 0 };
+                // The next register holds the top half of the address
+                if wide {
+                    index += 1;
+                }
+            }
+            index += 1;
+        }
+        Function { device, vendor_id: device.vendor_id(), device_id: device.device_id(), class: device.class(), bars, driver: None }
+    }
+}
+
+impl fmt::Display for Function {
+    /// One line, as `lspci` prints it: where the function is, what it is and its ids.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (class, subclass, prog_if) = self.class;
+        write!(f, "{} {} [{class:02x}{subclass:02x}", self.device, class_name(class, subclass))?;
+        if prog_if != 0 {
+            write!(f, ".{prog_if:02x}")?;
+        }
+        write!(f, "]: {:04x}:{:04x}", self.vendor_id, self.device_id)
+    }
+}
+
+impl fmt::Display for PciDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+/// Which functions a driver takes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Match {
+    /// Those with this vendor and device id.
+    Id(u16, u16),
+    /// Those of this class, subclass and programming interface.
+    Class(u8, u8, u8),
+}
+
+impl Match {
+    fn takes(&self, function: &Function) -> bool {
+        match *self {
+            Match::Id(vendor_id, device_id) => (function.vendor_id, function.device_id) == (vendor_id, device_id),
+            Match::Class(class, subclass, prog_if) => function.class == (class, subclass, prog_if),
+        }
+    }
+}
+
+/// A driver for PCI functions: which ones it takes, and how it sets one up.
+pub struct Driver {
+    pub name: &'static str,
+    pub matches: &'static [Match],
+    /// Sets up the function, or says why it can't.
+    pub probe: fn(PciDevice) -> Result<(), &'static str>,
+}
+
+// Every driver for PCI functions, offered each function in this order; a new one only has to
+// be added here
+const DRIVERS: [&Driver; 6] = [&ahci::DRIVER, &nvme::DRIVER, &virtio_blk::DRIVER, &virtio_net::DRIVER, &e1000::DRIVER, &rtl8139::DRIVER];
+
+static FUNCTIONS: SpinLock<[Option<Function>; MAX_FUNCTIONS]> = SpinLock::new([None; MAX_FUNCTIONS]);
+
+/// Finds ECAM in the ACPI tables, then scans every bus and keeps what is found, which the
+/// drivers look through. Logs the functions found at the debug level. Called once during boot,
+/// after `acpi::init` and `memory::init`.
+pub fn init() {
+    // Only segment 0 is scanned, as machines with more are servers this won't run on
+    match acpi::pci_config_region(0) {
+        Some(region) => {
+            let ecam = Ecam { physical: region.physical, first_bus: region.first_bus, last_bus: region.last_bus };
+            writeln!(serial(), "PCI: ECAM at {:#x} for buses {} to {}", ecam.physical.as_u64(), ecam.first_bus, ecam.last_bus).unwrap();
+            *ECAM.lock_irq() = Some(ecam);
+        },
+        None => writeln!(serial(), "PCI: no MCFG table, only the first 256 bytes of configuration space can be reached").unwrap(),
+    }
+    let mut functions = FUNCTIONS.lock();
+    let mut found = 0;
+    for (slot, device) in scan().enumerate() {
+        match functions.get_mut(slot) {
+            Some(slot) => *slot = Some(Function::read(device)),
+            None => {
+                writeln!(serial(), "PCI: more than {MAX_FUNCTIONS} functions; {device} and after are left out").unwrap();
+                break;
+            },
+        }
+        found += 1;
+    }
+    writeln!(serial(), "PCI: {found} functions").unwrap();
+    if config::logs(config::LogLevel::Debug) {
+        for function in functions.iter().flatten() {
+            writeln!(serial(), "{function}").unwrap();
+        }
+    }
+}
+
+/// Offers each function found at boot to the drivers, and has the first that takes it set it
+/// up. Called once during boot, after `memory::init`, `block::init` and `rand::init`.
+pub fn probe() {
+    // Unlocked while drivers set functions up, as they may look through them too
+    let functions = *FUNCTIONS.lock();
+    for (slot, function) in functions.into_iter().enumerate() {
+        let Some(function) = function else {
+            continue;
+        };
+        let Some(driver) = DRIVERS.into_iter().find(|driver| driver.matches.iter().any(|found| found.takes(&function))) else {
+            continue;
+        };
+        match (driver.probe)(function.device) {
+            Ok(()) => {
+                if let Some(function) = &mut FUNCTIONS.lock()[slot] {
+                    function.driver = Some(driver.name);
+                }
+            },
+            Err(error) => writeln!(serial(), "{} at {}: {error}", driver.name, function.device).unwrap(),
+        }
+    }
+}
+
+/// Returns the functions found at boot.
+pub fn functions() -> impl Iterator<Item = Function> {
+    let functions = *FUNCTIONS.lock();
+    functions.into_iter().flatten()
+}
+
+/// Returns every function on the PCI bus.
+pub fn devices() -> impl Iterator<Item = PciDevice> {
+    functions().map(|function| function.device)
+}
+
+/// Writes a listing of the functions, as `lspci -v` does: a line for each, one for each of its
+/// base address registers in use and capabilities, and one for its driver.
+pub fn list(out: &mut impl Write) -> fmt::Result {
+    for function in functions() {
+        writeln!(out, "{function}")?;
+        for (index, bar) in function.bars.iter().enumerate() {
+            match *bar {
+                Bar::None => {},
+                Bar::Memory { address, wide, prefetchable } => {
+                    let width = if wide { 64 } else { 32 };
+                    let prefetchable = if prefetchable { ", prefetchable" } else { "" };
+                    writeln!(out, "        BAR {index}: memory at {address:#x} ({width}-bit{prefetchable})")?;
+                },
+                Bar::Io(port) => writeln!(out, "        BAR {index}: I/O ports at {port:#x}")?,
+            }
+        }
+        for capability in function.device.capabilities() {
+            writeln!(out, "        Capability: {capability}")?;
+        }
+        if let Some(driver) = function.driver {
+            writeln!(out, "        Driver: {driver}")?;
+        }
+    }
+    Ok(())
+}
+
+/// What a class and subclass are called, or the class alone if the subclass isn't known.
+pub fn class_name(class: u8, subclass: u8) -> &'static str {
+    match (class, subclass) {
+        (0x00, _) => "Unclassified device",
+        (0x01, 0x01) => "IDE interface",
+        (0x01, 0x06) => "SATA controller",
+        (0x01, 0x08) => "Non-Volatile memory controller",
+        (0x01, _) => "Mass storage controller",
+        (0x02, 0x00) => "Ethernet controller",
+        (0x02, _) => "Network controller",
+        (0x03, 0x00) => "VGA compatible controller",
+        (0x03, _) => "Display controller",
+        (0x04, 0x01) => "Multimedia audio controller",
+        (0x04, 0x03) => "Audio device",
+        (0x04, _) => "Multimedia controller",
+        (0x05, _) => "Memory controller",
+        (0x06, 0x00) => "Host bridge",
+        (0x06, 0x01) => "ISA bridge",
+        (0x06, 0x04) => "PCI bridge",
+        (0x06, _) => "Bridge",
+        (0x07, _) => "Communication controller",
+        (0x08, _) => "System peripheral",
+        (0x09, _) => "Input device controller",
+        (0x0C, 0x03) => "USB controller",
+        (0x0C, 0x05) => "SMBus",
+        (0x0C, _) => "Serial bus controller",
+        (0x0D, _) => "Wireless controller",
+        _ => "Unknown device",
+    }
+}
+
+// Every function on the bus, checking each bus and device in turn
+fn scan() -> impl Iterator<Item = PciDevice> {
+    (0..=255u8).flat_map(|bus| (0..32u8).map(move |device| (bus, device))).flat_map(|(bus, device)| {
+        let first = PciDevice { bus, device, function: 0 };
+        let functions = match first.present() {
+            true if first.header_type() & MULTI_FUNCTION != 0 => 8,
+            true => 1,
+            false => 0,
+        };
+        (0..functions).map(move |function| PciDevice { bus, device, function })
+            .filter(PciDevice::present)
+    })
+}
@@ -0,0 +1,47 @@
+// Ball and paddle physics shared by the paddle games (pong and breakout).
+// Fixed-point values are 24.8: whole pixels in the upper bits, 1/256 pixel steps in the lower 8.
+
+pub const FIXED_SHIFT: i32 = 8;
+const ONE: i32 = 1 << FIXED_SHIFT;
+
+/// Converts whole pixels to fixed point.
+pub const fn to_fixed(pixels: i32) -> i32 {
+    pixels << FIXED_SHIFT
+}
+
+/// Converts fixed point to whole pixels, rounding down.
+pub const fn to_pixels(value: i32) -> i32 {
+    value >> FIXED_SHIFT
+}
+
+/// Speed along a paddle after the ball bounces off it. `offset` is the distance from the
+/// paddle centre to the ball centre and `reach` the largest offset at which they still touch.
+/// A hit near the centre sends the ball back straight, a hit near either edge deflects it
+/// at up to `max_speed`, towards the side it hit.
+pub fn deflection(offset: i32, reach: i32, max_speed: i32) -> i32 {
+    // Scale and round back to whole pixels per tick
+    to_pixels(deflection_fixed(offset, reach, to_fixed(max_speed)) + ONE / 2)
+}
+
+/// Like `deflection`, but with `max_speed` and the result in fixed point.
+pub fn deflection_fixed(offset: i32, reach: i32, max_speed: i32) -> i32 {
+    // Hit position from -1.0 to 1.0 in fixed point
+    let hit = ((offset << FIXED_SHIFT) / reach.max(1)).clamp(-ONE, ONE);
+    to_pixels(hit * max_speed)
+}
+
+/// Swept collision along one axis: if something moving from `from` to `to` in one step
+/// passes `plane` (approaching from either side), returns how far through the step it gets
+/// there, as a fixed-point fraction from 0 to 1.0.
+pub fn sweep(from: i32, to: i32, plane: i32) -> Option<i32> {
+    let crosses = (from < plane) != (to < plane) || to == plane;
+    if !crosses || from == to {
+        return None;
+    }
+    Some(((((plane - from) as i64) << FIXED_SHIFT) / (to - from) as i64) as i32)
+}
+
+/// Returns the point `fraction` (fixed point, 0 to 1.0) of the way from `from` to `to`.
+pub fn lerp(from: i32, to: i32, fraction: i32) -> i32 {
+    from + (((to - from) as i64 * fraction as i64) >> FIXED_SHIFT) as i32
+}
@@ -0,0 +1,124 @@
+use kernel::sync::SpinLock;
+use crate::process::{self, Handle};
+use crate::syscall::{self, Error};
+use crate::task::WaitQueue;
+
+// Anonymous pipes: a buffer that processes write into at one end and read from at the other,
+// through handles. Reading waits while the pipe is empty and there are still handles to write
+// with, and reads nothing once there aren't; writing waits while the pipe is full and fails
+// once there are no handles left to read with. Each end counts the handles to it, and the
+// pipe is freed when both are closed.
+const MAX_PIPES: usize = 8;
+const PIPE_SIZE: usize = 4096;
+
+/// An end of a pipe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum End {
+    Read,
+    Write,
+}
+
+struct Pipe {
+    buffer: [u8; PIPE_SIZE],
+    // Where the oldest byte is, and how many there are
+    start: usize,
+    length: usize,
+    readers: usize,
+    writers: usize,
+}
+
+static PIPES: SpinLock<[Option<Pipe>; MAX_PIPES]> = SpinLock::new([const { None }; MAX_PIPES]);
+// Every pipe's readers and writers wait here, for something to change in any of them
+static CHANGED: WaitQueue = WaitQueue::new();
+
+/// Registers the pipe system call. Called once during boot.
+pub fn init() {
+    syscall::register(syscall::PIPE, pipe);
+}
+
+/// Makes a pipe with one handle to each end, and returns its number, or None if there are
+/// too many pipes.
+pub fn create() -> Option<usize> {
+    let mut pipes = PIPES.lock_irq();
+    let id = pipes.iter().position(Option::is_none)?;
+    pipes[id] = Some(Pipe { buffer: [0; PIPE_SIZE], start: 0, length: 0, readers: 1, writers: 1 });
+    Some(id)
+}
+
+/// Closes a handle to the `end` of pipe `id`, freeing the pipe once no handles are left.
+pub fn close(id: usize, end: End) {
+    let mut pipes = PIPES.lock_irq();
+    let pipe = pipes[id].as_mut().expect("no such pipe");
+    match end {
+        End::Read => pipe.readers -= 1,
+        End::Write => pipe.writers -= 1,
+    }
+    if pipe.readers == 0 && pipe.writers == 0 {
+        pipes[id] = None;
+    }
+    drop(pipes);
+    // Whoever waits on the other end may be done waiting
+    CHANGED.notify();
+}
+
+/// Reads from pipe `id` into `buffer`, waiting for something to read unless `wait` is false.
+/// Returns how many bytes were read, none once nothing is left and nothing can write.
+pub fn read(id: usize, buffer: &mut [u8], wait: bool) -> usize {
+    let mut read = 0;
+    CHANGED.wait_until(|| {
+        let mut pipes = PIPES.lock_irq();
+        let pipe = pipes[id].as_mut().expect("no such pipe");
+        while read < buffer.len() && pipe.length > 0 {
+            buffer[read] = pipe.buffer[pipe.start];
+            pipe.start = (pipe.start + 1) % PIPE_SIZE;
+            pipe.length -= 1;
+            read += 1;
+        }
+        read > 0 || pipe.writers == 0 || !wait || buffer.is_empty()
+    });
+    if read > 0 {
+        CHANGED.notify();
+    }
+    read
+}
+
+/// Writes as much of `bytes` to pipe `id` as fits, waiting until some does. Returns how many
+/// bytes were written, or `BrokenPipe` if nothing can read them.
+pub fn write(id: usize, bytes: &[u8]) -> Result<usize, Error> {
+    let mut written = 0;
+    let mut broken = false;
+    CHANGED.wait_until(|| {
+        let mut pipes = PIPES.lock_irq();
+        let pipe = pipes[id].as_mut().expect("no such pipe");
+        if pipe.readers == 0 {
+            broken = true;
+            return true;
+        }
+        while written < bytes.len() && pipe.length < PIPE_SIZE {
+            pipe.buffer[(pipe.start + pipe.length) % PIPE_SIZE] = bytes[written];
+            pipe.length += 1;
+            written += 1;
+        }
+        written > 0 || bytes.is_empty()
+    });
+    if broken {
+        return Err(Error::BrokenPipe);
+    }
+    CHANGED.notify();
+    Ok(written)
+}
+
+// pipe(handles): makes a pipe and writes the numbers of the handles to read from and write to
+// it, 64 bits each, at `handles`
+fn pipe(arguments: [u64; 6]) -> Result<u64, Error> {
+    let handles = syscall::user_bytes_mut(arguments[0], 16)?;
+    let id = create().ok_or(Error::OutOfMemory)?;
+    let Some((reader, writer)) = process::open_pair(Handle::PipeRead(id), Handle::PipeWrite(id)) else {
+        close(id, End::Read);
+        close(id, End::Write);
+        return Err(Error::OutOfMemory);
+    };
+    handles[..8].copy_from_slice(&reader.to_le_bytes());
+    handles[8..].copy_from_slice(&writer.to_le_bytes());
+    Ok(0)
+}
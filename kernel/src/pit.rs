@@ -0,0 +1,60 @@
+use x86_64::instructions::port::Port;
+
+// Programmable Interval Timer (8253/8254). Only channel 2 is used here: its output drives
+// the PC speaker and, with the speaker muted, serves as a reference clock for calibration.
+pub const FREQUENCY: u32 = 1_193_182;
+
+const CHANNEL2_DATA: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+const SPEAKER_CONTROL: u16 = 0x61;
+
+// Bits of the speaker control port
+const GATE2: u8 = 0x01;
+const SPEAKER_ENABLE: u8 = 0x02;
+const OUTPUT2: u8 = 0x20;
+
+unsafe fn load_channel2(mode: u8, count: u16) {
+    unsafe {
+        // channel 2, low byte then high byte, binary counting
+        Port::<u8>::new(COMMAND).write(0b1011_0000 | (mode << 1));
+        let mut data = Port::<u8>::new(CHANNEL2_DATA);
+        data.write(count as u8);
+        data.write((count >> 8) as u8);
+    }
+}
+
+/// Starts a square wave of the given frequency on the PC speaker.
+pub fn speaker_on(frequency: u32) {
+    let divisor = (FREQUENCY / frequency.max(1)).clamp(1, u16::MAX as u32) as u16;
+    unsafe {
+        load_channel2(3, divisor);
+        let mut control = Port::<u8>::new(SPEAKER_CONTROL);
+        let value = control.read();
+        control.write(value | GATE2 | SPEAKER_ENABLE);
+    }
+}
+
+/// Silences the PC speaker.
+pub fn speaker_off() {
+    unsafe {
+        let mut control = Port::<u8>::new(SPEAKER_CONTROL);
+        let value = control.read();
+        control.write(value & !(GATE2 | SPEAKER_ENABLE));
+    }
+}
+
+/// Busy-waits until channel 2 has counted down `count` PIT cycles (about `count / FREQUENCY` seconds).
+/// The speaker is muted while waiting.
+pub fn wait(count: u16) {
+    unsafe {
+        let mut control = Port::<u8>::new(SPEAKER_CONTROL);
+        let value = control.read();
+        control.write((value & !(GATE2 | SPEAKER_ENABLE)) | GATE2);
+
+        // mode 0: output goes high once the count reaches zero
+        load_channel2(0, count);
+        while control.read() & OUTPUT2 == 0 {}
+
+        control.write(value & !(GATE2 | SPEAKER_ENABLE));
+    }
+}
@@ -1,301 +1,1071 @@
-use crate::screen::{Writer, screenwriter};
-use alloc::format;
+use crate::game::Game;
+use crate::input::InputEvent;
+use crate::netplay::{self, Role, Transport};
+use crate::particles::ParticleSystem;
+use crate::screen::{screenwriter, Writer, Surface, CHAR_HEIGHT, CHAR_WIDTH};
+use crate::settings::{self, Difficulty};
+use crate::physics::{self, to_fixed, to_pixels};
+use crate::{config, rand, sound, time, timer, ui};
 use core::fmt::Write;
-use core::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use kernel::serial;
+use pc_keyboard::{DecodedKey, KeyCode};
+use kernel::sync::SpinLock;
 
 // Game dimensions and constants
 const SCREEN_WIDTH: usize = 640;
 const SCREEN_HEIGHT: usize = 480;
 const PADDLE_WIDTH: usize = 10;
-const PADDLE_HEIGHT: usize = 60;
 const BALL_SIZE: usize = 10;
 const PADDLE_OFFSET: usize = 20;
-const PADDLE_SPEED: i32 = 5;
-const INITIAL_BALL_SPEED_X: i32 = 2;
-const INITIAL_BALL_SPEED_Y: i32 = 2;
-
-// Game state using atomics for thread safety
-static LEFT_PADDLE_Y: AtomicI32 = AtomicI32::new((SCREEN_HEIGHT as i32 - PADDLE_HEIGHT as i32) / 2);
-static RIGHT_PADDLE_Y: AtomicI32 = AtomicI32::new((SCREEN_HEIGHT as i32 - PADDLE_HEIGHT as i32) / 2);
-static BALL_X: AtomicI32 = AtomicI32::new((SCREEN_WIDTH as i32 - BALL_SIZE as i32) / 2);
-static BALL_Y: AtomicI32 = AtomicI32::new((SCREEN_HEIGHT as i32 - BALL_SIZE as i32) / 2);
-static BALL_VEL_X: AtomicI32 = AtomicI32::new(INITIAL_BALL_SPEED_X);
-static BALL_VEL_Y: AtomicI32 = AtomicI32::new(INITIAL_BALL_SPEED_Y);
-static LEFT_SCORE: AtomicI32 = AtomicI32::new(0);
-static RIGHT_SCORE: AtomicI32 = AtomicI32::new(0);
-static GAME_ACTIVE: AtomicBool = AtomicBool::new(false);
-
-// Add key state tracking
-static KEY_W_PRESSED: AtomicBool = AtomicBool::new(false);
-static KEY_S_PRESSED: AtomicBool = AtomicBool::new(false);
-
-// Add simulation key release timer
-static KEY_RELEASE_TIMER: AtomicI32 = AtomicI32::new(0);
+// Ball positions and speeds are in 24.8 fixed point (see physics.rs), speeds per tick
+const INITIAL_BALL_SPEED_X: i32 = to_fixed(2);
+const INITIAL_BALL_SPEED_Y: i32 = to_fixed(2);
+const MAX_BALL_SPEED_X: i32 = to_fixed(8); // The ball stops speeding up at this speed
+const MAX_BOUNCE_SPEED_Y: i32 = to_fixed(4); // Vertical speed after a hit on the very edge of a paddle
 const KEY_RELEASE_DELAY: i32 = 5; // Auto-release keys after this many ticks
+const SCORE_Y: usize = 3; // Top of the score digits
+const SCORE_MARGIN: usize = 24; // Gap between the score digits and the centre line
 
-// Add state for right paddle oscillation
-static RIGHT_PADDLE_DIRECTION: AtomicI32 = AtomicI32::new(1); // 1 = down, -1 = up
-const RIGHT_PADDLE_SPEED: i32 = 3; // Speed for automatic movement
-
-pub fn init_game() {
-    // Reset game state
-    LEFT_PADDLE_Y.store((SCREEN_HEIGHT as i32 - PADDLE_HEIGHT as i32) / 2, Ordering::SeqCst);
-    RIGHT_PADDLE_Y.store((SCREEN_HEIGHT as i32 - PADDLE_HEIGHT as i32) / 2, Ordering::SeqCst);
-    BALL_X.store((SCREEN_WIDTH as i32 - BALL_SIZE as i32) / 2, Ordering::SeqCst);
-    BALL_Y.store((SCREEN_HEIGHT as i32 - BALL_SIZE as i32) / 2, Ordering::SeqCst);
-    BALL_VEL_X.store(INITIAL_BALL_SPEED_X, Ordering::SeqCst);
-    BALL_VEL_Y.store(INITIAL_BALL_SPEED_Y, Ordering::SeqCst);
-    LEFT_SCORE.store(0, Ordering::SeqCst);
-    RIGHT_SCORE.store(0, Ordering::SeqCst);
-    GAME_ACTIVE.store(true, Ordering::SeqCst);
-    
-    // Initialize key states
-    KEY_W_PRESSED.store(false, Ordering::SeqCst);
-    KEY_S_PRESSED.store(false, Ordering::SeqCst);
-    
-    // Initialize oscillation direction for right paddle
-    RIGHT_PADDLE_DIRECTION.store(1, Ordering::SeqCst);
-    
-    // Display initial game state
-    draw_game();
-    
-    // Show instructions
-    write!(Writer, "\n\nControls:\n").unwrap();
-    write!(Writer, "W/S: Move left paddle\n").unwrap();
-    write!(Writer, "Press SPACE to start\n").unwrap();
+// Particle effects: the ball leaves a fading trail and a point scored ends in a burst
+const MAX_PARTICLES: usize = 64;
+const TRAIL_LIFETIME: u16 = 6;
+const TRAIL_COLOR: ui::Color = (120, 120, 255);
+const BURST_PARTICLES: usize = 24;
+const BURST_SPEED: u32 = 3 << 8; // 3 pixels per tick
+const BURST_LIFETIME: u16 = 12;
+
+// Sound effects: (frequency in Hz, duration in ms)
+const PADDLE_SOUND: (u32, u64) = (880, 40);
+const WALL_SOUND: (u32, u64) = (440, 30);
+const SCORE_SOUND: (u32, u64) = (220, 200);
+const POWER_UP_SOUND: (u32, u64) = (1320, 80);
+
+// Power-ups appear at random in the middle of the field and are collected by hitting them
+// with a ball; the player who last hit that ball gets the effect
+const MAX_BALLS: usize = 3;
+const POWER_UP_SIZE: usize = 16;
+const POWER_UP_LIFETIME: i32 = 400; // Ticks before an uncollected power-up disappears
+const BIG_PADDLE_PERCENT: i32 = 150;
+const SLOW_MOTION_PERCENT: i32 = 50;
+
+const INSTRUCTIONS: [&str; 9] = [
+    "Controls:",
+    "W/S: Move left paddle",
+    "Up/Down: Move right paddle (2 players)",
+    "Press 1 for player vs AI, 2 for player vs player",
+    "Press 3 to play against another machine over the serial link",
+    "Press 4 to play against another machine over the network",
+    "Press E/N/H for Easy/Normal/Hard difficulty",
+    "Hit power-ups with the ball: B big paddle, M multi-ball, S slow motion",
+    "Press SPACE to start, P to pause, F2 for settings, ESC for the menu",
+];
+
+// Gameplay parameters that change with the difficulty setting
+struct DifficultyParams {
+    ai_reaction_delay: i32, // Ticks between looks at the ball
+    ai_max_speed: i32,      // Maximum pixels the AI paddle moves per tick
+    ball_acceleration: i32, // Horizontal speed gained on every paddle hit (fixed point)
+    paddle_height: i32,
 }
 
-// Set key state functions
-pub fn set_key_w(pressed: bool) {
-    KEY_W_PRESSED.store(pressed, Ordering::SeqCst);
-    if pressed {
-        KEY_RELEASE_TIMER.store(0, Ordering::SeqCst);
+fn params() -> DifficultyParams {
+    match settings::difficulty() {
+        Difficulty::Easy => DifficultyParams { ai_reaction_delay: 8, ai_max_speed: 2, ball_acceleration: 0, paddle_height: 80 },
+        Difficulty::Normal => DifficultyParams { ai_reaction_delay: 4, ai_max_speed: 3, ball_acceleration: to_fixed(1) / 4, paddle_height: 60 },
+        Difficulty::Hard => DifficultyParams { ai_reaction_delay: 2, ai_max_speed: 5, ball_acceleration: to_fixed(1) / 2, paddle_height: 40 },
     }
 }
 
-pub fn set_key_s(pressed: bool) {
-    KEY_S_PRESSED.store(pressed, Ordering::SeqCst);
-    if pressed {
-        KEY_RELEASE_TIMER.store(0, Ordering::SeqCst);
+/// Paddle height without power-ups.
+fn base_paddle_height() -> i32 {
+    params().paddle_height * settings::paddle_size_percent() / 100
+}
+
+pub static PONG: SpinLock<Pong> = SpinLock::new(Pong::new());
+
+// Computer-controlled paddle
+#[derive(Clone, Copy)]
+struct AiPaddle {
+    target_y: i32,
+    reaction_timer: i32,
+}
+
+impl AiPaddle {
+    const fn new() -> Self {
+        AiPaddle { target_y: (SCREEN_HEIGHT as i32) / 2, reaction_timer: 0 }
+    }
+
+    /// Moves `paddle_y` towards the ball if it is `approaching`, or back to the middle otherwise.
+    fn update(&mut self, paddle_y: &mut i32, paddle_height: i32, ball_y: i32, approaching: bool) {
+        let params = params();
+
+        // Only look at the ball every few ticks, like a human with a slow reaction time
+        self.reaction_timer += 1;
+        if self.reaction_timer >= params.ai_reaction_delay {
+            self.reaction_timer = 0;
+
+            self.target_y = if approaching {
+                // Ball is coming towards us: line the paddle centre up with the ball centre
+                ball_y + BALL_SIZE as i32 / 2 - paddle_height / 2
+            } else {
+                // Ball is moving away: drift back to the middle
+                (SCREEN_HEIGHT as i32 - paddle_height) / 2
+            };
+        }
+
+        let step = (self.target_y - *paddle_y).clamp(-params.ai_max_speed, params.ai_max_speed);
+        Pong::move_paddle(paddle_y, step, paddle_height);
     }
 }
 
-pub fn start_game() {
-    GAME_ACTIVE.store(true, Ordering::SeqCst);
+#[derive(Clone, Copy)]
+struct Ball {
+    x: i32,
+    y: i32,
+    vel_x: i32,
+    vel_y: i32,
 }
 
-pub fn move_left_paddle_up() {
-    if GAME_ACTIVE.load(Ordering::SeqCst) {
-        let current = LEFT_PADDLE_Y.load(Ordering::SeqCst);
-        if current > PADDLE_SPEED {
-            LEFT_PADDLE_Y.store(current - PADDLE_SPEED, Ordering::SeqCst);
-        } else {
-            LEFT_PADDLE_Y.store(0, Ordering::SeqCst);
+impl Ball {
+    const fn new() -> Self {
+        Ball {
+            x: to_fixed(SCREEN_WIDTH as i32 - BALL_SIZE as i32) / 2,
+            y: to_fixed(SCREEN_HEIGHT as i32 - BALL_SIZE as i32) / 2,
+            vel_x: INITIAL_BALL_SPEED_X,
+            vel_y: INITIAL_BALL_SPEED_Y,
         }
     }
+
+    /// Returns true if the ball overlaps the square of `size` pixels at (x, y).
+    fn overlaps(&self, x: i32, y: i32, size: i32) -> bool {
+        let (ball_x, ball_y) = (to_pixels(self.x), to_pixels(self.y));
+        ball_x < x + size && x < ball_x + BALL_SIZE as i32 && ball_y < y + size && y < ball_y + BALL_SIZE as i32
+    }
 }
 
-pub fn move_left_paddle_down() {
-    if GAME_ACTIVE.load(Ordering::SeqCst) {
-        let current = LEFT_PADDLE_Y.load(Ordering::SeqCst);
-        if current < (SCREEN_HEIGHT as i32 - PADDLE_HEIGHT as i32) - PADDLE_SPEED {
-            LEFT_PADDLE_Y.store(current + PADDLE_SPEED, Ordering::SeqCst);
-        } else {
-            LEFT_PADDLE_Y.store(SCREEN_HEIGHT as i32 - PADDLE_HEIGHT as i32, Ordering::SeqCst);
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PowerUpKind {
+    BigPaddle,
+    MultiBall,
+    SlowMotion,
+}
+
+impl PowerUpKind {
+    const ALL: [PowerUpKind; 3] = [PowerUpKind::BigPaddle, PowerUpKind::MultiBall, PowerUpKind::SlowMotion];
+
+    fn label(self) -> &'static str {
+        match self {
+            PowerUpKind::BigPaddle => "B",
+            PowerUpKind::MultiBall => "M",
+            PowerUpKind::SlowMotion => "S",
         }
     }
+
+    fn color(self) -> ui::Color {
+        match self {
+            PowerUpKind::BigPaddle => (0, 200, 0),
+            PowerUpKind::MultiBall => (220, 60, 220),
+            PowerUpKind::SlowMotion => (0, 160, 255),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct PowerUp {
+    kind: PowerUpKind,
+    // Top left corner in pixels
+    x: i32,
+    y: i32,
+    ticks_left: i32,
 }
 
-pub fn update_game() {
-    if !GAME_ACTIVE.load(Ordering::SeqCst) {
-        return;
-    }
-    
-    // Auto-release key simulation
-    let timer = KEY_RELEASE_TIMER.fetch_add(1, Ordering::SeqCst);
-    if timer >= KEY_RELEASE_DELAY {
-        KEY_RELEASE_TIMER.store(0, Ordering::SeqCst);
-        
-        // Auto-release all keys - only for left paddle now
-        KEY_W_PRESSED.store(false, Ordering::SeqCst);
-        KEY_S_PRESSED.store(false, Ordering::SeqCst);
-    }
-    
-    // Check for active key states and move left paddle accordingly
-    if KEY_W_PRESSED.load(Ordering::SeqCst) {
-        move_left_paddle_up();
-    }
-    if KEY_S_PRESSED.load(Ordering::SeqCst) {
-        move_left_paddle_down();
-    }
-    
-    // Automatically oscillate right paddle
-    let right_paddle_y = RIGHT_PADDLE_Y.load(Ordering::SeqCst);
-    let right_paddle_dir = RIGHT_PADDLE_DIRECTION.load(Ordering::SeqCst);
-    
-    // Check if we need to change direction
-    if right_paddle_y <= 0 {
-        RIGHT_PADDLE_DIRECTION.store(1, Ordering::SeqCst);
-    } else if right_paddle_y >= SCREEN_HEIGHT as i32 - PADDLE_HEIGHT as i32 {
-        RIGHT_PADDLE_DIRECTION.store(-1, Ordering::SeqCst);
-    }
-    
-    // Move the paddle based on current direction
-    if right_paddle_dir > 0 {
-        // Move down
-        if right_paddle_y < SCREEN_HEIGHT as i32 - PADDLE_HEIGHT as i32 - RIGHT_PADDLE_SPEED {
-            RIGHT_PADDLE_Y.store(right_paddle_y + RIGHT_PADDLE_SPEED, Ordering::SeqCst);
+// Timer tick at which each running power-up effect ends, or 0 if it isn't running. The timer
+// wheel calls `expire_effects` when they are due.
+#[derive(Clone, Copy)]
+struct Effects {
+    big_paddle_until: [u64; 2], // Left and right paddle
+    multi_ball_until: u64,
+    slow_motion_until: u64,
+}
+
+impl Effects {
+    const fn new() -> Self {
+        Effects { big_paddle_until: [0; 2], multi_ball_until: 0, slow_motion_until: 0 }
+    }
+}
+
+pub struct Pong {
+    // Paddle positions depend on the paddle height and are set up by reset_match
+    left_paddle_y: i32,
+    right_paddle_y: i32,
+    // balls[0] is always in play; multi-ball adds more
+    balls: [Ball; MAX_BALLS],
+    ball_count: usize,
+    power_up: Option<PowerUp>,
+    effects: Effects,
+    left_score: i32,
+    right_score: i32,
+    active: bool,
+    paused: bool,
+    game_over: bool,
+    // In two-player mode the right paddle follows the arrow keys instead of the AI
+    two_player: bool,
+
+    // Key state tracking; the keyboard only reports presses, so keys are released
+    // automatically after KEY_RELEASE_DELAY ticks
+    key_w: bool,
+    key_s: bool,
+    key_up: bool,
+    key_down: bool,
+    key_release_timer: i32,
+
+    // Right paddle AI, and the left one too in demo mode
+    right_ai: AiPaddle,
+    left_ai: AiPaddle,
+    // Attract mode: the computer plays itself, silently, until a key is pressed
+    demo: bool,
+    // Playing against another machine over the serial link or the network (see netplay.rs)
+    linked: bool,
+
+    particles: ParticleSystem<MAX_PARTICLES>,
+
+    // Set whenever the state changed and the next render has to redraw the screen
+    dirty: bool,
+}
+
+impl Pong {
+    pub const fn new() -> Self {
+        Pong {
+            left_paddle_y: 0,
+            right_paddle_y: 0,
+            balls: [Ball::new(); MAX_BALLS],
+            ball_count: 1,
+            power_up: None,
+            effects: Effects::new(),
+            left_score: 0,
+            right_score: 0,
+            active: false,
+            paused: false,
+            game_over: false,
+            two_player: false,
+            key_w: false,
+            key_s: false,
+            key_up: false,
+            key_down: false,
+            key_release_timer: 0,
+            right_ai: AiPaddle::new(),
+            left_ai: AiPaddle::new(),
+            demo: false,
+            linked: false,
+            particles: ParticleSystem::new(),
+            dirty: true,
+        }
+    }
+
+    fn reset_match(&mut self) {
+        let (two_player, demo, linked) = (self.two_player, self.demo, self.linked);
+        *self = Pong { two_player, demo, linked, ..Pong::new() };
+        self.left_paddle_y = (SCREEN_HEIGHT as i32 - base_paddle_height()) / 2;
+        self.right_paddle_y = (SCREEN_HEIGHT as i32 - base_paddle_height()) / 2;
+        // Serve the first ball of the match in a random direction
+        self.balls[0].vel_x = random_sign(INITIAL_BALL_SPEED_X);
+        self.balls[0].vel_y = random_sign(INITIAL_BALL_SPEED_Y);
+    }
+
+    fn press_key(&mut self, key: KeyCode) {
+        match key {
+            KeyCode::W => self.key_w = true,
+            KeyCode::S => self.key_s = true,
+            KeyCode::ArrowUp => self.key_up = true,
+            KeyCode::ArrowDown => self.key_down = true,
+            _ => return,
+        }
+        self.key_release_timer = 0;
+    }
+
+    fn start_game(&mut self) {
+        if self.linked && netplay::role() != Some(Role::Host) {
+            // The host decides when a linked match starts
+            return;
+        }
+        if self.game_over {
+            // Play again with the same mode
+            self.reset_match();
+        }
+        self.active = true;
+        self.dirty = true;
+    }
+
+    /// Changes the difficulty. Only allowed from the menu, before a match has started.
+    fn set_difficulty(&mut self, difficulty: Difficulty) {
+        if self.active || self.game_over {
+            return;
+        }
+        settings::set_difficulty(difficulty);
+        // Paddle size depends on the difficulty
+        self.reset_match();
+    }
+
+    /// Pauses a running game, or resumes it if it is already paused.
+    fn toggle_pause(&mut self) {
+        // A linked match runs on two machines and can't be paused on just one
+        if !self.active || self.linked {
+            return;
+        }
+
+        self.paused = !self.paused;
+        if !self.paused {
+            // Resuming: forget keys pressed while paused so paddles don't jump
+            self.release_keys();
+        }
+        self.dirty = true;
+    }
+
+    fn release_keys(&mut self) {
+        self.key_release_timer = 0;
+        self.key_w = false;
+        self.key_s = false;
+        self.key_up = false;
+        self.key_down = false;
+    }
+
+    /// Chooses between player vs AI (`two_player == false`) and player vs player, then starts the game.
+    fn select_mode(&mut self, two_player: bool) {
+        self.two_player = two_player;
+        self.start_game();
+    }
+
+    /// Waits for another machine to play against over `transport`.
+    fn start_link(&mut self, transport: Transport) {
+        if self.active || self.game_over {
+            return;
+        }
+        if let Err(reason) = netplay::start(transport) {
+            writeln!(serial(), "Can't play over the {}: {reason}", transport.name()).unwrap();
+            return;
+        }
+        self.linked = true;
+        self.two_player = false;
+    }
+
+    fn move_paddle(paddle: &mut i32, dy: i32, height: i32) {
+        *paddle = (*paddle + dy).clamp(0, SCREEN_HEIGHT as i32 - height);
+    }
+
+    /// Height of the left or right paddle, including the big paddle power-up.
+    fn paddle_height(&self, left: bool) -> i32 {
+        let big = self.effects.big_paddle_until[if left { 0 } else { 1 }] != 0;
+        if big { base_paddle_height() * BIG_PADDLE_PERCENT / 100 } else { base_paddle_height() }
+    }
+
+    fn move_left_paddle(&mut self, dy: i32) {
+        let height = self.paddle_height(true);
+        Self::move_paddle(&mut self.left_paddle_y, dy, height);
+    }
+
+    fn move_right_paddle(&mut self, dy: i32) {
+        let height = self.paddle_height(false);
+        Self::move_paddle(&mut self.right_paddle_y, dy, height);
+    }
+
+    /// Waits for another machine on the network to play against, for the menu's network
+    /// entry. Must be called after `init`.
+    pub fn start_network(&mut self) {
+        self.start_link(Transport::Udp);
+        self.dirty = true;
+    }
+
+    /// Starts an AI vs AI match for attract mode. Must be called after `init`.
+    pub fn start_demo(&mut self) {
+        self.demo = true;
+        self.two_player = false;
+        self.active = true;
+        self.dirty = true;
+    }
+
+    fn play(&self, sound: (u32, u64)) {
+        if !self.demo {
+            sound::beep(sound.0, sound.1);
+        }
+    }
+
+    /// Scores a point for the left or right player with the ball at `index`, which leaves play.
+    fn score_point(&mut self, left_player: bool, index: usize) {
+        self.play(SCORE_SOUND);
+        let ball = self.balls[index];
+        let x = to_pixels(ball.x).clamp(0, SCREEN_WIDTH as i32) as usize;
+        let y = to_pixels(ball.y).clamp(0, SCREEN_HEIGHT as i32) as usize;
+        self.particles.burst(x, y, BURST_PARTICLES, BURST_SPEED, BURST_LIFETIME, settings::theme().accent);
+        let score = if left_player { &mut self.left_score } else { &mut self.right_score };
+        *score += 1;
+        let winning_score = config::get().pong.winning_score;
+        if *score >= winning_score && self.demo {
+            // The demo just keeps playing
+            self.reset_match();
+            self.active = true;
+        } else if *score >= winning_score {
+            // Match is over: stop the game until the players restart or go back to the menu
+            self.active = false;
+            self.game_over = true;
+        } else if self.ball_count > 1 {
+            // Play goes on with the other balls
+            self.ball_count -= 1;
+            self.balls[index] = self.balls[self.ball_count];
         } else {
-            RIGHT_PADDLE_Y.store(SCREEN_HEIGHT as i32 - PADDLE_HEIGHT as i32, Ordering::SeqCst);
-            RIGHT_PADDLE_DIRECTION.store(-1, Ordering::SeqCst);
+            self.reset_ball();
         }
-    } else {
-        // Move up
-        if right_paddle_y > RIGHT_PADDLE_SPEED {
-            RIGHT_PADDLE_Y.store(right_paddle_y - RIGHT_PADDLE_SPEED, Ordering::SeqCst);
+        self.dirty = true;
+    }
+
+    fn reset_ball(&mut self) {
+        let ball = &mut self.balls[0];
+        let vel_x = if ball.vel_x < 0 { INITIAL_BALL_SPEED_X } else { -INITIAL_BALL_SPEED_X };
+        *ball = Ball { vel_x, vel_y: random_sign(INITIAL_BALL_SPEED_Y), ..Ball::new() };
+    }
+
+    fn release_keys_when_due(&mut self) {
+        // Auto-release key simulation
+        self.key_release_timer += 1;
+        if self.key_release_timer > KEY_RELEASE_DELAY {
+            // Auto-release all keys
+            self.release_keys();
+        }
+    }
+
+    /// Advances a running match by one tick.
+    fn step(&mut self) {
+        self.release_keys_when_due();
+        let speed = config::get().pong.paddle_speed;
+
+        // The paddle size can change mid-match, so keep both paddles on screen
+        self.move_left_paddle(0);
+        self.move_right_paddle(0);
+
+        // Check for active key states and move left paddle accordingly
+        if self.demo {
+            let (ball_y, approaching) = self.tracked_ball(true);
+            let height = self.paddle_height(true);
+            self.left_ai.update(&mut self.left_paddle_y, height, ball_y, approaching);
+        }
+        // Against another machine, the arrow keys move the local paddle too
+        if self.key_w || (self.linked && self.key_up) {
+            self.move_left_paddle(-speed);
+        }
+        if self.key_s || (self.linked && self.key_down) {
+            self.move_left_paddle(speed);
+        }
+
+        // Right paddle is the second player, the other machine or the AI
+        if self.linked {
+            if let Some(y) = netplay::remote_paddle() {
+                self.right_paddle_y = netplay::smooth(self.right_paddle_y, y);
+                self.move_right_paddle(0);
+            }
+        } else if self.two_player {
+            if self.key_up {
+                self.move_right_paddle(-speed);
+            }
+            if self.key_down {
+                self.move_right_paddle(speed);
+            }
         } else {
-            RIGHT_PADDLE_Y.store(0, Ordering::SeqCst);
-            RIGHT_PADDLE_DIRECTION.store(1, Ordering::SeqCst);
-        }
-    }
-    
-    // Move ball
-    let mut ball_x = BALL_X.load(Ordering::SeqCst);
-    let mut ball_y = BALL_Y.load(Ordering::SeqCst);
-    let mut vel_x = BALL_VEL_X.load(Ordering::SeqCst);
-    let mut vel_y = BALL_VEL_Y.load(Ordering::SeqCst);
-    
-    ball_x += vel_x;
-    ball_y += vel_y;
-    
-    // Check for collisions with top/bottom walls
-    if ball_y <= 0 || ball_y >= SCREEN_HEIGHT as i32 - BALL_SIZE as i32 {
-        vel_y = -vel_y;
-    }
-    
-    // Check for collisions with paddles
-    let left_paddle_y = LEFT_PADDLE_Y.load(Ordering::SeqCst);
-    
-    // Left paddle collision
-    if ball_x <= PADDLE_OFFSET as i32 + PADDLE_WIDTH as i32 && 
-       ball_x >= PADDLE_OFFSET as i32 &&
-       ball_y + BALL_SIZE as i32 >= left_paddle_y && 
-       ball_y <= left_paddle_y + PADDLE_HEIGHT as i32 {
-        ball_x = PADDLE_OFFSET as i32 + PADDLE_WIDTH as i32;
-        vel_x = -vel_x;
-        // Increase velocity slightly for difficulty
-        if vel_x < 0 { vel_x -= 1; } else { vel_x += 1; }
-    }
-    
-    // Right paddle collision
-    if ball_x + BALL_SIZE as i32 >= SCREEN_WIDTH as i32 - PADDLE_OFFSET as i32 - PADDLE_WIDTH as i32 && 
-       ball_x + BALL_SIZE as i32 <= SCREEN_WIDTH as i32 - PADDLE_OFFSET as i32 &&
-       ball_y + BALL_SIZE as i32 >= right_paddle_y && 
-       ball_y <= right_paddle_y + PADDLE_HEIGHT as i32 {
-        ball_x = SCREEN_WIDTH as i32 - PADDLE_OFFSET as i32 - PADDLE_WIDTH as i32 - BALL_SIZE as i32;
-        vel_x = -vel_x;
-        // Increase velocity slightly for difficulty
-        if vel_x < 0 { vel_x -= 1; } else { vel_x += 1; }
-    }
-    
-    // Check for scoring
-    if ball_x <= 0 {
-        // Right player scores
-        RIGHT_SCORE.fetch_add(1, Ordering::SeqCst);
-        reset_ball();
-        draw_scores();
-        return;
-    }
-    
-    if ball_x >= SCREEN_WIDTH as i32 - BALL_SIZE as i32 {
-        // Left player scores
-        LEFT_SCORE.fetch_add(1, Ordering::SeqCst);
-        reset_ball();
-        draw_scores();
-        return;
-    }
-    
-    // Update ball state
-    BALL_X.store(ball_x, Ordering::SeqCst);
-    BALL_Y.store(ball_y, Ordering::SeqCst);
-    BALL_VEL_X.store(vel_x, Ordering::SeqCst);
-    BALL_VEL_Y.store(vel_y, Ordering::SeqCst);
-    
-    draw_game();
-}
+            let (ball_y, approaching) = self.tracked_ball(false);
+            let height = self.paddle_height(false);
+            self.right_ai.update(&mut self.right_paddle_y, height, ball_y, approaching);
+        }
 
-fn reset_ball() {
-    BALL_X.store((SCREEN_WIDTH as i32 - BALL_SIZE as i32) / 2, Ordering::SeqCst);
-    BALL_Y.store((SCREEN_HEIGHT as i32 - BALL_SIZE as i32) / 2, Ordering::SeqCst);
-    BALL_VEL_X.store(if BALL_VEL_X.load(Ordering::SeqCst) < 0 { INITIAL_BALL_SPEED_X } else { -INITIAL_BALL_SPEED_X }, Ordering::SeqCst);
-    BALL_VEL_Y.store(INITIAL_BALL_SPEED_Y, Ordering::SeqCst);
-}
+        // The guest of a linked match couldn't show power-ups, so they are left out there
+        if !self.linked {
+            self.update_power_up();
+        }
 
-fn draw_scores() {
-    let left_score = LEFT_SCORE.load(Ordering::SeqCst);
-    let right_score = RIGHT_SCORE.load(Ordering::SeqCst);
-    
-    // Clear score area
-    for x in 0..SCREEN_WIDTH {
-        for y in 5..30 {
-            screenwriter().draw_pixel(x, y, 0, 0, 0);
+        // Move the balls, scaled by the ball speed setting and slow motion
+        let mut speed_percent = settings::ball_speed_percent();
+        if self.effects.slow_motion_until != 0 {
+            speed_percent = speed_percent * SLOW_MOTION_PERCENT / 100;
         }
+        for index in 0..self.ball_count {
+            if let Some(left_player) = self.move_ball(index, speed_percent) {
+                // The other balls wait for the next tick
+                self.score_point(left_player, index);
+                return;
+            }
+            self.collect_power_up(index);
+        }
+        self.dirty = true;
     }
-    
-    // Draw score text
-    let score_text = format!("Score: {} - {}", left_score, right_score);
-    write!(Writer, "\r{}", score_text).unwrap();
-}
 
-fn draw_game() {
-    // Clear screen (except text area)
-    for y in 30..SCREEN_HEIGHT {
-        for x in 0..SCREEN_WIDTH {
-            screenwriter().draw_pixel(x, y, 0, 0, 0);
-        }
-    }
-    
-    // Draw center line
-    for y in 30..SCREEN_HEIGHT {
-        if y % 8 < 4 {
-            screenwriter().draw_pixel(SCREEN_WIDTH / 2, y, 255, 255, 255);
-        }
-    }
-    
-    // Draw paddles
-    let left_paddle_y = LEFT_PADDLE_Y.load(Ordering::SeqCst) as usize;
-    let right_paddle_y = RIGHT_PADDLE_Y.load(Ordering::SeqCst) as usize;
-    
-    // Left paddle
-    for y in left_paddle_y..left_paddle_y + PADDLE_HEIGHT {
-        for x in PADDLE_OFFSET..PADDLE_OFFSET + PADDLE_WIDTH {
-            if y < SCREEN_HEIGHT && x < SCREEN_WIDTH {
-                screenwriter().draw_pixel(x, y, 255, 255, 255);
+    /// Returns the pixel row of the ball the left or right AI should follow, and whether that
+    /// ball is coming towards it: the closest approaching ball, or the first ball if none is.
+    fn tracked_ball(&self, left: bool) -> (i32, bool) {
+        let approaching = self.balls[..self.ball_count].iter()
+            .filter(|ball| (ball.vel_x < 0) == left)
+            .min_by_key(|ball| if left { ball.x } else { -ball.x });
+        match approaching {
+            Some(ball) => (to_pixels(ball.y), true),
+            None => (to_pixels(self.balls[0].y), false),
+        }
+    }
+
+    /// Moves the ball at `index` by one tick at `speed_percent` of its speed, bouncing it off
+    /// the walls and paddles. Returns Some(true) if it went past the right paddle (a point for
+    /// the left player) or Some(false) if it went past the left one.
+    fn move_ball(&mut self, index: usize, speed_percent: i32) -> Option<bool> {
+        let ball = self.balls[index];
+
+        // Leave a trail behind the ball
+        let trail_x = (to_pixels(ball.x) + BALL_SIZE as i32 / 2 - 1) as usize;
+        let trail_y = (to_pixels(ball.y) + BALL_SIZE as i32 / 2 - 1) as usize;
+        self.particles.spawn(trail_x, trail_y, 0, 0, TRAIL_LIFETIME, TRAIL_COLOR);
+
+        let (start_x, start_y) = (ball.x, ball.y);
+        let mut vel_x = ball.vel_x;
+        let mut vel_y = ball.vel_y;
+        let (step_x, step_y) = (vel_x * speed_percent / 100, vel_y * speed_percent / 100);
+        let mut ball_x = start_x + step_x;
+        let mut ball_y = start_y + step_y;
+
+        // Check for collisions with top/bottom walls, bouncing back off the wall by
+        // however far the ball would have gone past it
+        let max_y = to_fixed(SCREEN_HEIGHT as i32 - BALL_SIZE as i32);
+        if ball_y <= 0 {
+            ball_y = (-ball_y).min(max_y);
+            vel_y = vel_y.abs();
+            self.play(WALL_SOUND);
+        } else if ball_y >= max_y {
+            ball_y = (2 * max_y - ball_y).max(0);
+            vel_y = -vel_y.abs();
+            self.play(WALL_SOUND);
+        }
+
+        // Paddle collisions are swept: check where the ball was at the moment its leading
+        // edge crossed the paddle's face, so a fast ball can't skip past a paddle in one tick
+        let left_face = to_fixed((PADDLE_OFFSET + PADDLE_WIDTH) as i32);
+        let right_face = to_fixed((SCREEN_WIDTH - PADDLE_OFFSET - PADDLE_WIDTH - BALL_SIZE) as i32);
+        let paddle_hit = if vel_x < 0 {
+            physics::sweep(start_x, ball_x, left_face).map(|t| (left_face, t, self.left_paddle_y, self.paddle_height(true)))
+        } else {
+            physics::sweep(start_x, ball_x, right_face).map(|t| (right_face, t, self.right_paddle_y, self.paddle_height(false)))
+        };
+        if let Some((face, t, paddle_y, paddle_height)) = paddle_hit {
+            let hit_y = physics::lerp(start_y, start_y + step_y, t).clamp(0, max_y);
+            if touches_paddle(to_pixels(hit_y), paddle_y, paddle_height) {
+                ball_x = face;
+                ball_y = hit_y;
+                // Speed up slightly for difficulty, up to the speed cap
+                let speed = (vel_x.abs() + params().ball_acceleration).min(MAX_BALL_SPEED_X);
+                vel_x = if vel_x < 0 { speed } else { -speed };
+                vel_y = bounce_velocity_y(to_pixels(hit_y), paddle_y, paddle_height);
+                self.play(PADDLE_SOUND);
             }
         }
+
+        // Update ball state
+        self.balls[index] = Ball { x: ball_x, y: ball_y, vel_x, vel_y };
+
+        // Check for scoring
+        if ball_x <= 0 {
+            // Right player scores
+            Some(false)
+        } else if ball_x >= to_fixed(SCREEN_WIDTH as i32 - BALL_SIZE as i32) {
+            // Left player scores
+            Some(true)
+        } else {
+            None
+        }
+    }
+
+    /// Ages the power-up on the field, or maybe spawns a new one.
+    fn update_power_up(&mut self) {
+        if let Some(power_up) = &mut self.power_up {
+            power_up.ticks_left -= 1;
+            if power_up.ticks_left <= 0 {
+                self.power_up = None;
+            }
+            return;
+        }
+        let chance = config::get().pong.power_up_chance;
+        if chance == 0 || rand::below(chance) != 0 {
+            return;
+        }
+
+        // Somewhere in the middle third, away from the paddles
+        let kind = PowerUpKind::ALL[rand::below(PowerUpKind::ALL.len() as u32) as usize];
+        let x = (SCREEN_WIDTH / 3) as i32 + rand::below((SCREEN_WIDTH / 3 - POWER_UP_SIZE) as u32) as i32;
+        let y = 40 + rand::below((SCREEN_HEIGHT - 40 - POWER_UP_SIZE) as u32) as i32;
+        self.power_up = Some(PowerUp { kind, x, y, ticks_left: POWER_UP_LIFETIME });
+    }
+
+    /// Collects the power-up if the ball at `index` hit it.
+    fn collect_power_up(&mut self, index: usize) {
+        let ball = self.balls[index];
+        let Some(power_up) = self.power_up.filter(|p| ball.overlaps(p.x, p.y, POWER_UP_SIZE as i32)) else {
+            return;
+        };
+        self.power_up = None;
+        self.play(POWER_UP_SOUND);
+        let centre = (POWER_UP_SIZE / 2) as i32;
+        let (x, y) = ((power_up.x + centre) as usize, (power_up.y + centre) as usize);
+        self.particles.burst(x, y, BURST_PARTICLES / 2, BURST_SPEED, BURST_LIFETIME, power_up.kind.color());
+
+        // The effect ends through the timer wheel, which calls expire_effects once it is due
+        let ticks = time::ms_to_ticks(config::get().pong.power_up_ms);
+        let until = timer::now() + ticks;
+        if !timer::schedule(ticks, expire_effects) {
+            // Without a timer the effect would never end, so don't start it
+            return;
+        }
+        // A ball moving right was last hit by the left player
+        let left_player = ball.vel_x > 0;
+        writeln!(serial(), "Power-up {:?} for the {} player", power_up.kind, if left_player { "left" } else { "right" }).unwrap();
+        match power_up.kind {
+            PowerUpKind::BigPaddle => self.effects.big_paddle_until[if left_player { 0 } else { 1 }] = until,
+            PowerUpKind::SlowMotion => self.effects.slow_motion_until = until,
+            PowerUpKind::MultiBall => {
+                self.effects.multi_ball_until = until;
+                // New balls split off from this one at different angles
+                for vel_y in [-ball.vel_y, ball.vel_y / 2] {
+                    if self.ball_count == MAX_BALLS {
+                        break;
+                    }
+                    self.balls[self.ball_count] = Ball { vel_y, ..ball };
+                    self.ball_count += 1;
+                }
+            },
+        }
+    }
+
+    /// Ends every power-up effect that is due at timer tick `now`.
+    fn expire_effects(&mut self, now: u64) {
+        let expired = |until: &mut u64| {
+            let due = *until != 0 && now >= *until;
+            if due {
+                *until = 0;
+            }
+            due
+        };
+        for until in &mut self.effects.big_paddle_until {
+            expired(until);
+        }
+        expired(&mut self.effects.slow_motion_until);
+        if expired(&mut self.effects.multi_ball_until) {
+            self.ball_count = 1;
+        }
+        self.dirty = true;
+    }
+
+    /// Advances a match against another machine: the host runs it like a local match and
+    /// sends the result, the guest shows what the host sent.
+    fn update_link(&mut self, dt: u64) {
+        netplay::poll();
+        let Some(role) = netplay::role() else {
+            // Still waiting for the other machine
+            return;
+        };
+        if netplay::is_lost() {
+            writeln!(serial(), "Lost the other machine over the {}", netplay::transport().name()).unwrap();
+            netplay::stop();
+            self.linked = false;
+            self.reset_match();
+            self.dirty = true;
+            return;
+        }
+
+        match role {
+            Role::Host => {
+                if !self.active && !self.game_over {
+                    // Connected: play straight away
+                    self.active = true;
+                }
+                if self.active {
+                    self.step();
+                }
+                netplay::send_state(&self.match_state());
+            },
+            Role::Guest => self.follow_host(dt),
+        }
+    }
+
+    fn match_state(&self) -> netplay::MatchState {
+        let speed_percent = settings::ball_speed_percent();
+        netplay::MatchState {
+            ball_x: self.balls[0].x,
+            ball_y: self.balls[0].y,
+            ball_step_x: self.balls[0].vel_x * speed_percent / 100,
+            ball_step_y: self.balls[0].vel_y * speed_percent / 100,
+            host_paddle_y: self.left_paddle_y,
+            left_score: self.left_score as u8,
+            right_score: self.right_score as u8,
+            active: self.active,
+            game_over: self.game_over,
+        }
+    }
+
+    /// Moves the guest's own paddle and shows the match as the host last sent it, carried on
+    /// for as long as it took to get here. The host's settings decide the ball speed and
+    /// where the paddles are hit.
+    fn follow_host(&mut self, dt: u64) {
+        self.release_keys_when_due();
+        let speed = config::get().pong.paddle_speed;
+        if self.key_w || self.key_up {
+            self.move_right_paddle(-speed);
+        }
+        if self.key_s || self.key_down {
+            self.move_right_paddle(speed);
+        }
+        netplay::send_paddle(self.right_paddle_y);
+
+        if let Some(y) = netplay::remote_paddle() {
+            self.left_paddle_y = netplay::smooth(self.left_paddle_y, y);
+            self.move_left_paddle(0);
+        }
+        let Some((state, age_ms)) = netplay::match_state() else {
+            return;
+        };
+
+        let scores = (state.left_score as i32, state.right_score as i32);
+        if scores.0 + scores.1 > self.left_score + self.right_score {
+            self.play(SCORE_SOUND);
+            let (x, y) = (to_pixels(self.balls[0].x).max(0) as usize, to_pixels(self.balls[0].y).max(0) as usize);
+            self.particles.burst(x, y, BURST_PARTICLES, BURST_SPEED, BURST_LIFETIME, settings::theme().accent);
+        }
+        (self.left_score, self.right_score) = scores;
+        self.active = state.active;
+        self.game_over = state.game_over;
+
+        let ticks = (age_ms / dt.max(1)) as i32;
+        let max_x = to_fixed(SCREEN_WIDTH as i32 - BALL_SIZE as i32);
+        let max_y = to_fixed(SCREEN_HEIGHT as i32 - BALL_SIZE as i32);
+        self.balls[0].x = (state.ball_x + state.ball_step_x * ticks).clamp(0, max_x);
+        self.balls[0].y = reflect(state.ball_y + state.ball_step_y * ticks, max_y);
+        self.dirty = true;
     }
-    
-    // Right paddle
-    for y in right_paddle_y..right_paddle_y + PADDLE_HEIGHT {
-        for x in (SCREEN_WIDTH - PADDLE_OFFSET - PADDLE_WIDTH)..(SCREEN_WIDTH - PADDLE_OFFSET) {
-            if y < SCREEN_HEIGHT && x < SCREEN_WIDTH {
-                screenwriter().draw_pixel(x, y, 255, 255, 255);
+
+    fn draw_link_status(&self, surface: &mut Surface) {
+        let mut text = ui::TextBuffer::<80>::new();
+        match netplay::role() {
+            None => write!(text, "Waiting for the other machine on the {}...", netplay::transport().name()).unwrap(),
+            Some(role) => {
+                let side = if role == Role::Host { "left" } else { "right" };
+                write!(text, "Over the {}: you are the {side} paddle", netplay::transport().name()).unwrap();
+                if let Some(round_trip) = netplay::round_trip_ms() {
+                    write!(text, ", round trip {round_trip} ms").unwrap();
+                }
+            },
+        }
+        let x = (SCREEN_WIDTH - text.as_str().len() * CHAR_WIDTH) / 2;
+        let (r, g, b) = settings::theme().accent;
+        surface.draw_text(x, SCREEN_HEIGHT - 3 * CHAR_HEIGHT, text.as_str(), r, g, b);
+    }
+
+    fn draw_pause_overlay(&self, surface: &mut Surface) {
+        // Darken the playfield so the frozen game stays visible underneath
+        for y in 30..SCREEN_HEIGHT {
+            for x in 0..SCREEN_WIDTH {
+                surface.blend_pixel(x, y, 0, 0, 64, 160);
             }
         }
+
+        let text = "PAUSED";
+        let x = (SCREEN_WIDTH - text.len() * CHAR_WIDTH) / 2;
+        let y = (SCREEN_HEIGHT - CHAR_HEIGHT) / 2;
+        let (r, g, b) = settings::theme().accent;
+        surface.draw_text(x, y, text, r, g, b);
     }
-    
-    // Draw ball
-    let ball_x = BALL_X.load(Ordering::SeqCst) as usize;
-    let ball_y = BALL_Y.load(Ordering::SeqCst) as usize;
-    
-    for y in ball_y..ball_y + BALL_SIZE {
-        for x in ball_x..ball_x + BALL_SIZE {
-            if y < SCREEN_HEIGHT && x < SCREEN_WIDTH {
-                screenwriter().draw_pixel(x, y, 255, 255, 255);
+
+    fn draw_game_over(&self, surface: &mut Surface) {
+        // Clear the playfield
+        for y in 30..SCREEN_HEIGHT {
+            for x in 0..SCREEN_WIDTH {
+                surface.draw_pixel(x, y, 0, 0, 0);
+            }
+        }
+
+        let winner = if self.left_score > self.right_score {
+            "LEFT PLAYER WINS!"
+        } else if self.two_player || self.linked {
+            "RIGHT PLAYER WINS!"
+        } else {
+            "THE COMPUTER WINS!"
+        };
+        let mut final_score = ui::TextBuffer::<32>::new();
+        write!(final_score, "Final score: {} - {}", self.left_score, self.right_score).unwrap();
+        let options = "SPACE: play again   ESC: menu";
+
+        let y = SCREEN_HEIGHT / 2 - 2 * CHAR_HEIGHT;
+        let (r, g, b) = settings::theme().foreground;
+        for (i, line) in [winner, final_score.as_str(), options].iter().enumerate() {
+            let x = (SCREEN_WIDTH - line.len() * CHAR_WIDTH) / 2;
+            surface.draw_text(x, y + i * 2 * CHAR_HEIGHT, line, r, g, b);
+        }
+    }
+
+    fn draw_scores(&self) {
+        // Clear score area
+        let mut surface = screenwriter();
+        for x in 0..SCREEN_WIDTH {
+            for y in 0..30 {
+                surface.draw_pixel(x, y, 0, 0, 0);
+            }
+        }
+        drop(surface);
+
+        // Big digits on either side of the centre line
+        let left = self.left_score as u32;
+        let right = self.right_score as u32;
+        let color = settings::theme().foreground;
+        ui::draw_number(SCREEN_WIDTH / 2 - SCORE_MARGIN - ui::number_width(left), SCORE_Y, left, color);
+        ui::draw_number(SCREEN_WIDTH / 2 + SCORE_MARGIN, SCORE_Y, right, color);
+    }
+
+    fn draw_game(&self, surface: &mut Surface) {
+        // Clear screen (except text area)
+        for y in 30..SCREEN_HEIGHT {
+            for x in 0..SCREEN_WIDTH {
+                surface.draw_pixel(x, y, 0, 0, 0);
+            }
+        }
+
+        let theme = settings::theme();
+        let (r, g, b) = theme.foreground;
+
+        // Draw center line
+        for y in 30..SCREEN_HEIGHT {
+            if y % 8 < 4 {
+                surface.draw_pixel(SCREEN_WIDTH / 2, y, r, g, b);
+            }
+        }
+
+        // Draw paddles
+        let left_paddle_y = self.left_paddle_y as usize;
+        let right_paddle_y = self.right_paddle_y as usize;
+
+        // Left paddle
+        for y in left_paddle_y..left_paddle_y + self.paddle_height(true) as usize {
+            for x in PADDLE_OFFSET..PADDLE_OFFSET + PADDLE_WIDTH {
+                if y < SCREEN_HEIGHT && x < SCREEN_WIDTH {
+                    surface.draw_pixel(x, y, r, g, b);
+                }
+            }
+        }
+
+        // Right paddle
+        for y in right_paddle_y..right_paddle_y + self.paddle_height(false) as usize {
+            for x in (SCREEN_WIDTH - PADDLE_OFFSET - PADDLE_WIDTH)..(SCREEN_WIDTH - PADDLE_OFFSET) {
+                if y < SCREEN_HEIGHT && x < SCREEN_WIDTH {
+                    surface.draw_pixel(x, y, r, g, b);
+                }
+            }
+        }
+
+        // Draw balls
+        for ball in &self.balls[..self.ball_count] {
+            let ball_x = to_pixels(ball.x) as usize;
+            let ball_y = to_pixels(ball.y) as usize;
+
+            for y in ball_y..ball_y + BALL_SIZE {
+                for x in ball_x..ball_x + BALL_SIZE {
+                    if y < SCREEN_HEIGHT && x < SCREEN_WIDTH {
+                        surface.draw_pixel(x, y, r, g, b);
+                    }
+                }
+            }
+        }
+
+        // Draw the power-up waiting to be collected
+        if let Some(power_up) = self.power_up {
+            let (x, y) = (power_up.x as usize, power_up.y as usize);
+            let (power_r, power_g, power_b) = power_up.kind.color();
+            for py in y..(y + POWER_UP_SIZE).min(SCREEN_HEIGHT) {
+                for px in x..(x + POWER_UP_SIZE).min(SCREEN_WIDTH) {
+                    surface.draw_pixel(px, py, power_r, power_g, power_b);
+                }
             }
+            let label_x = x + (POWER_UP_SIZE - CHAR_WIDTH) / 2;
+            let label_y = y + (POWER_UP_SIZE - CHAR_HEIGHT) / 2;
+            surface.draw_text(label_x, label_y, power_up.kind.label(), 0, 0, 0);
+        }
+
+        // Show the controls and the selected difficulty while waiting on the menu
+        if self.linked {
+            self.draw_link_status(surface);
+        } else if !self.active && !self.game_over {
+            for (i, line) in INSTRUCTIONS.iter().enumerate() {
+                surface.draw_text(0, 2 * CHAR_HEIGHT + i * CHAR_HEIGHT, line, r, g, b);
+            }
+            let difficulty = settings::difficulty().name();
+            let (accent_r, accent_g, accent_b) = theme.accent;
+            surface.draw_text(SCREEN_WIDTH / 2 + CHAR_WIDTH, 40, "Difficulty: ", r, g, b);
+            surface.draw_text(SCREEN_WIDTH / 2 + 13 * CHAR_WIDTH, 40, difficulty, accent_r, accent_g, accent_b);
+        }
+        if self.demo {
+            let text = "DEMO - press any key";
+            let x = (SCREEN_WIDTH - text.len() * CHAR_WIDTH) / 2;
+            let (r, g, b) = theme.accent;
+            surface.draw_text(x, SCREEN_HEIGHT - 3 * CHAR_HEIGHT, text, r, g, b);
+        }
+    }
+}
+
+impl Default for Pong {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Game for Pong {
+    fn name(&self) -> &'static str {
+        "Pong"
+    }
+
+    fn init(&mut self) {
+        self.demo = false;
+        self.linked = false;
+        netplay::stop();
+        self.reset_match();
+        // Wait for the players to pick a mode
+        self.active = false;
+        self.paused = false;
+        self.game_over = false;
+    }
+
+    fn handle_input(&mut self, event: InputEvent) {
+        let InputEvent::Key(key) = event;
+        match key {
+            DecodedKey::Unicode(character) => {
+                match character {
+                    'w' => {
+                        // Direct key state setting - no toggling
+                        self.press_key(KeyCode::W);
+                        writeln!(serial(), "W key pressed").unwrap();
+                    },
+                    's' => {
+                        self.press_key(KeyCode::S);
+                        writeln!(serial(), "S key pressed").unwrap();
+                    },
+                    ' ' => {
+                        self.start_game();
+                        // Reset only left paddle key states
+                        self.key_w = false;
+                        self.key_s = false;
+                        writeln!(serial(), "Space pressed - game started").unwrap();
+                    },
+                    '1' => {
+                        self.select_mode(false);
+                        writeln!(serial(), "Player vs AI selected").unwrap();
+                    },
+                    '2' => {
+                        self.select_mode(true);
+                        writeln!(serial(), "Player vs player selected").unwrap();
+                    },
+                    '3' => self.start_link(Transport::Link),
+                    '4' => self.start_link(Transport::Udp),
+                    'e' => self.set_difficulty(Difficulty::Easy),
+                    'n' => self.set_difficulty(Difficulty::Normal),
+                    'h' => self.set_difficulty(Difficulty::Hard),
+                    'p' => {
+                        self.toggle_pause();
+                        writeln!(serial(), "Pause toggled").unwrap();
+                    },
+                    'q' => {
+                        // Release left paddle keys
+                        self.key_w = false;
+                        self.key_s = false;
+                        writeln!(serial(), "Keys released with Q").unwrap();
+                    },
+                    '\u{1b}' => {},
+                    _ => write!(Writer, "{}", character).unwrap(),
+                }
+            },
+            DecodedKey::RawKey(key) => {
+                writeln!(serial(), "Raw key: {:?}", key).unwrap();
+                // W and S for the left paddle, arrow keys for the right paddle
+                match key {
+                    KeyCode::W | KeyCode::S | KeyCode::ArrowUp | KeyCode::ArrowDown => self.press_key(key),
+                    _ => write!(Writer, "{:?}", key).unwrap(),
+                }
+            },
+        }
+        self.dirty = true;
+    }
+
+    fn update(&mut self, dt: u64) {
+        // A paused game also freezes the key auto-release timer, the AI and the particles
+        if self.paused {
+            return;
         }
+        if !self.particles.is_empty() {
+            self.particles.update();
+            self.dirty = true;
+        }
+        if self.linked {
+            self.update_link(dt);
+        } else if self.active {
+            self.step();
+        }
+    }
+
+    fn render(&mut self) {
+        if !self.dirty {
+            return;
+        }
+        self.dirty = false;
+
+        if self.game_over {
+            self.draw_game_over(&mut screenwriter());
+        } else {
+            self.draw_game(&mut screenwriter());
+        }
+        // The score digits are drawn by `ui`, so the screen mustn't be locked here
+        self.draw_scores();
+        let mut surface = screenwriter();
+        self.particles.draw(&mut surface);
+        if self.paused {
+            self.draw_pause_overlay(&mut surface);
+        }
+    }
+
+    fn redraw(&mut self) {
+        self.dirty = true;
     }
-    
-    // Draw scores
-    draw_scores();
+
+    /// Leaving is allowed unless it would interrupt a match in progress.
+    fn can_quit(&self) -> bool {
+        !self.active || self.paused
+    }
+}
+
+/// Returns `speed` or `-speed` at random.
+fn random_sign(speed: i32) -> i32 {
+    if rand::coin_flip() { speed } else { -speed }
+}
+
+/// Reflects `y` back into 0..=max, as if it bounced off the top or bottom wall.
+fn reflect(y: i32, max: i32) -> i32 {
+    if y < 0 {
+        (-y).min(max)
+    } else if y > max {
+        (2 * max - y).max(0)
+    } else {
+        y
+    }
+}
+
+/// Timer wheel callback that ends the power-up effects which are due.
+fn expire_effects() {
+    PONG.lock().expire_effects(timer::now());
+}
+
+/// Returns true if a ball at pixel row `ball_y` overlaps the paddle at `paddle_y` vertically.
+fn touches_paddle(ball_y: i32, paddle_y: i32, paddle_height: i32) -> bool {
+    ball_y + BALL_SIZE as i32 >= paddle_y && ball_y <= paddle_y + paddle_height
+}
+
+/// Vertical ball speed (fixed point) after hitting a paddle: a hit near the centre sends the
+/// ball back flat, a hit near either edge sends it back at up to MAX_BOUNCE_SPEED_Y.
+fn bounce_velocity_y(ball_y: i32, paddle_y: i32, paddle_height: i32) -> i32 {
+    let ball_centre = ball_y + BALL_SIZE as i32 / 2;
+    let paddle_centre = paddle_y + paddle_height / 2;
+    let reach = (paddle_height + BALL_SIZE as i32) / 2;
+    physics::deflection_fixed(ball_centre - paddle_centre, reach, MAX_BOUNCE_SPEED_Y)
 }
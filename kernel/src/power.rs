@@ -0,0 +1,178 @@
+use ::acpi::address::{AddressSpace, GenericAddress};
+use ::acpi::fadt::Fadt;
+use core::fmt::Write;
+use kernel::serial;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::instructions::tables::lidt;
+use x86_64::structures::DescriptorTablePointer;
+use x86_64::{PhysAddr, VirtAddr};
+use crate::pci::PciDevice;
+use crate::{acpi, cache, memory};
+
+// Turning the machine off is ACPI's S5 state: the sleep type the DSDT gives for S5 goes in
+// the PM1 control registers the FADT points at, with SLP_EN set. QEMU's PM1a control register
+// is at a fixed port, so if the tables can't be read that is tried as well.
+const SLP_TYP_SHIFT: u16 = 10;
+const SLP_TYP: u16 = 0b111 << SLP_TYP_SHIFT;
+const SLP_EN: u16 = 1 << 13;
+// Set in PM1 control once the firmware has handed power management to the OS
+const SCI_EN: u16 = 1 << 0;
+const QEMU_PM1A_CONTROL: u16 = 0x604;
+// Spins to wait for ACPI mode, the keyboard controller, and then for the power to go
+const ENABLE_ATTEMPTS: usize = 1_000_000;
+const POWER_OFF_SPINS: usize = 10_000_000;
+
+// Rebooting tries the keyboard controller's reset line first, as every PC has had since the AT,
+// then the reset register the FADT gives, and if the machine is still running, a triple fault,
+// which the CPU can only get out of by resetting
+const KEYBOARD_STATUS: u16 = 0x64;
+const KEYBOARD_COMMAND: u16 = 0x64;
+// Set while the controller hasn't taken the last byte written to it
+const INPUT_FULL: u8 = 1 << 1;
+const PULSE_RESET: u8 = 0xFE;
+
+/// Writes the disks' cached sectors back and turns the machine off, or halts it, saying so, if
+/// it can't be turned off.
+pub fn shutdown() -> ! {
+    writeln!(serial(), "Shutting down").unwrap();
+    sync();
+    interrupts::disable();
+    if let Err(error) = power_off() {
+        writeln!(serial(), "ACPI shutdown failed: {error}").unwrap();
+    }
+    unsafe { Port::<u16>::new(QEMU_PM1A_CONTROL).write(SLP_EN) };
+    wait();
+    writeln!(serial(), "Couldn't turn the machine off; it is safe to switch it off now").unwrap();
+    kernel::hlt_loop()
+}
+
+/// Writes the disks' cached sectors back and restarts the machine.
+pub fn reboot() -> ! {
+    writeln!(serial(), "Rebooting").unwrap();
+    sync();
+    interrupts::disable();
+    pulse_reset_line();
+    wait();
+    match reset_register() {
+        Ok(()) => wait(),
+        Err(error) => writeln!(serial(), "ACPI reset failed: {error}").unwrap(),
+    }
+    writeln!(serial(), "Still running, rebooting with a triple fault").unwrap();
+    triple_fault()
+}
+
+fn sync() {
+    if let Err(error) = cache::flush_all() {
+        writeln!(serial(), "Can't sync: {error:?}").unwrap();
+    }
+}
+
+fn pulse_reset_line() {
+    let mut status = Port::<u8>::new(KEYBOARD_STATUS);
+    for _ in 0..ENABLE_ATTEMPTS {
+        if unsafe { status.read() } & INPUT_FULL == 0 {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+    unsafe { Port::<u8>::new(KEYBOARD_COMMAND).write(PULSE_RESET) };
+}
+
+fn reset_register() -> Result<(), &'static str> {
+    let fadt = acpi::fadt().ok_or("no FADT")?;
+    // The flags are packed into the FADT, so they are copied out first
+    let flags = fadt.flags;
+    if !flags.supports_system_reset_via_fadt() {
+        return Err("no reset register");
+    }
+    let register = fadt.reset_register().map_err(|_| "bad reset register")?;
+    let value = fadt.reset_value;
+    match register.address_space {
+        AddressSpace::SystemIo => {
+            let port = u16::try_from(register.address).map_err(|_| "reset port out of range")?;
+            unsafe { Port::<u8>::new(port).write(value) };
+        },
+        AddressSpace::SystemMemory => {
+            let address = memory::map_mmio(PhysAddr::new(register.address), 1).ok_or("no room to map the reset register")?;
+            unsafe { address.as_mut_ptr::<u8>().write_volatile(value) };
+        },
+        AddressSpace::PciConfigSpace => {
+            // On bus 0, with the device, function and offset packed into the address
+            let device = PciDevice { bus: 0, device: (register.address >> 32) as u8, function: (register.address >> 16) as u8 };
+            device.write8(register.address as u8, value);
+        },
+        _ => return Err("reset register in an address space that isn't supported"),
+    }
+    Ok(())
+}
+
+fn triple_fault() -> ! {
+    // With an empty interrupt table, the breakpoint can't be handled, nor can the double fault
+    // that causes
+    let empty = DescriptorTablePointer { limit: 0, base: VirtAddr::zero() };
+    unsafe {
+        lidt(&empty);
+        core::arch::asm!("int3", options(noreturn));
+    }
+}
+
+fn power_off() -> Result<(), &'static str> {
+    let fadt = acpi::fadt().ok_or("no FADT")?;
+    let (sleep_type_a, sleep_type_b) = acpi::s5_sleep_types().ok_or("no \\_S5 in the DSDT")?;
+    let pm1a = fadt.pm1a_control_block().map_err(|_| "no PM1a control block")?;
+    let pm1b = fadt.pm1b_control_block().map_err(|_| "bad PM1b control block")?;
+    enable(&fadt, pm1a)?;
+    write_control(pm1a, sleep_type_a)?;
+    if let Some(pm1b) = pm1b {
+        write_control(pm1b, sleep_type_b)?;
+    }
+    wait();
+    Err("the machine is still on")
+}
+
+// Switches the firmware to ACPI mode, if it isn't already, through its SMI command port
+fn enable(fadt: &Fadt, pm1a: GenericAddress) -> Result<(), &'static str> {
+    let (smi_command, acpi_enable) = (fadt.smi_cmd_port, fadt.acpi_enable);
+    if read_control(pm1a)? & SCI_EN != 0 || smi_command == 0 || acpi_enable == 0 {
+        return Ok(());
+    }
+    let port = u16::try_from(smi_command).map_err(|_| "SMI command port out of range")?;
+    unsafe { Port::<u8>::new(port).write(acpi_enable) };
+    for _ in 0..ENABLE_ATTEMPTS {
+        if read_control(pm1a)? & SCI_EN != 0 {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err("the firmware didn't switch to ACPI mode")
+}
+
+// The PM1 control registers are in I/O space on every PC this runs on
+fn control_port(register: GenericAddress) -> Result<Port<u16>, &'static str> {
+    if register.address_space != AddressSpace::SystemIo {
+        return Err("PM1 control block isn't in I/O space");
+    }
+    let port = u16::try_from(register.address).map_err(|_| "PM1 control port out of range")?;
+    Ok(Port::new(port))
+}
+
+fn read_control(register: GenericAddress) -> Result<u16, &'static str> {
+    Ok(unsafe { control_port(register)?.read() })
+}
+
+fn write_control(register: GenericAddress, sleep_type: u16) -> Result<(), &'static str> {
+    let mut port = control_port(register)?;
+    unsafe {
+        let value = port.read() & !SLP_TYP;
+        port.write(value | ((sleep_type << SLP_TYP_SHIFT) & SLP_TYP) | SLP_EN);
+    }
+    Ok(())
+}
+
+// Gives the machine a moment to turn off or reset
+fn wait() {
+    for _ in 0..POWER_OFF_SPINS {
+        core::hint::spin_loop();
+    }
+}
@@ -0,0 +1,345 @@
+use core::fmt::Write;
+use kernel::serial;
+use kernel::sync::SpinLock;
+use crate::{console, fs, kthread, pipe, shm, syscall, task, ui, usermode};
+use crate::pipe::End;
+use crate::usermode::{Error, Exit, UserMemory};
+
+// The processes: user programs, each with an address space, the task running it (its main
+// thread), the handles it has open and, once it has ended, how it ended. `spawn` starts one
+// on a kernel thread of its own. Processes are numbered from 1 and numbers aren't reused. A
+// process that has ended stays in the table, so its exit can still be looked at, until its
+// slot is needed for a new one.
+//
+// The handles are the process's descriptor table: the read and write system calls go to
+// whatever the handle they are given stands for, be it the console, a pipe or a file, and a
+// process's handles are closed when it ends.
+const MAX_PROCESSES: usize = 16;
+const MAX_HANDLES: usize = 8;
+
+/// A process number.
+pub type Pid = u32;
+
+/// A process in the table.
+pub struct Process {
+    pub pid: Pid,
+    pub name: &'static str,
+    /// The id of the task running the program, once it has one.
+    pub thread: Option<usize>,
+    pub state: State,
+    /// What the handle numbers the program passes to system calls stand for.
+    pub handles: [Option<Handle>; MAX_HANDLES],
+    /// What relative paths the program passes to system calls are taken from.
+    pub directory: fs::WorkingDirectory,
+    /// The program's memory, freed once it has ended.
+    pub memory: Option<UserMemory>,
+}
+
+/// Whether a process is still running.
+#[derive(Debug, Clone, Copy)]
+pub enum State {
+    /// Waiting for its turn to run.
+    Waiting,
+    Running,
+    Ended(Exit),
+}
+
+/// Something a process has open.
+#[derive(Debug, Clone, Copy)]
+pub enum Handle {
+    /// Reads keys typed on the console.
+    ConsoleInput,
+    /// Writes to the console.
+    ConsoleOutput,
+    /// Reads from pipe number .0.
+    PipeRead(usize),
+    /// Writes to pipe number .0.
+    PipeWrite(usize),
+    /// Shared memory object number .0, to map with `mmap`.
+    Shared(usize),
+    /// A file, read and written from where the last read or write left off.
+    File(fs::File),
+}
+
+// `read` flags: return straight away, even if there is nothing to read
+const NO_WAIT: u64 = 1;
+
+struct Processes {
+    processes: [Option<Process>; MAX_PROCESSES],
+    next_pid: Pid,
+}
+
+static PROCESSES: SpinLock<Processes> = SpinLock::new(Processes { processes: [const { None }; MAX_PROCESSES], next_pid: 1 });
+
+/// Registers the system calls for handles. Called once during boot.
+pub fn init() {
+    syscall::register(syscall::READ, read);
+    syscall::register(syscall::WRITE, write);
+    syscall::register(syscall::CLOSE, close);
+}
+
+/// Starts the program at `path` in a new process, on a kernel thread of its own, with
+/// `arguments` after its name as its arguments, `standard` as handles 0 and 1 and `directory`
+/// as its current directory. The process takes the handles over, and they are closed if it
+/// can't be started. How it ends is reported on the serial port.
+pub fn spawn(path: &str, arguments: &[&str], standard: [Handle; 2], directory: &fs::WorkingDirectory) -> Result<Pid, Error> {
+    let loaded = usermode::program(path).ok_or(Error::NoSuchProgram)
+        .and_then(|(name, image)| Ok((name, usermode::load(name, image, arguments, standard, directory)?)));
+    let (name, (pid, start)) = match loaded {
+        Ok(loaded) => loaded,
+        Err(error) => {
+            standard.into_iter().for_each(close_handle);
+            return Err(error);
+        }
+    };
+    let thread = kthread::spawn(move || match usermode::run(pid, start) {
+        Exit::Exited(code) => writeln!(serial(), "{name} ({pid}) exited with {code}").unwrap(),
+        Exit::Faulted(fault) => writeln!(serial(), "{name} ({pid}) stopped: {}", fault.exception).unwrap(),
+        Exit::Killed => writeln!(serial(), "{name} ({pid}) was killed").unwrap(),
+    });
+    if thread.is_none() {
+        if let Some(mut process) = remove(pid) {
+            close_all(&mut process);
+        }
+        return Err(Error::NoThread);
+    }
+    Ok(pid)
+}
+
+/// Adds a process waiting to run, with `standard` as handles 0 and 1, which it takes over, in
+/// `directory`. Returns its number, or None if every slot holds a process that hasn't ended.
+pub fn create(name: &'static str, memory: UserMemory, standard: [Handle; 2], directory: &fs::WorkingDirectory) -> Option<Pid> {
+    let mut processes = PROCESSES.lock_irq();
+    let processes = &mut *processes;
+    // A free slot, or else the one of the process that ended first
+    let slot = processes.processes.iter().position(Option::is_none).or_else(|| {
+        let ended = processes.processes.iter().enumerate()
+            .filter(|(_, process)| matches!(process, Some(Process { state: State::Ended(_), .. })));
+        ended.min_by_key(|(_, process)| process.as_ref().unwrap().pid).map(|(slot, _)| slot)
+    })?;
+    let pid = processes.next_pid;
+    processes.next_pid += 1;
+    let mut handles = [None; MAX_HANDLES];
+    handles[0] = Some(standard[0]);
+    handles[1] = Some(standard[1]);
+    let process = Process { pid, name, thread: None, state: State::Waiting, handles, directory: *directory, memory: Some(memory) };
+    processes.processes[slot] = Some(process);
+    Some(pid)
+}
+
+/// Marks process `pid` as ended with `exit`, closes its handles and returns its memory to be
+/// freed, which mustn't happen while its address space is active.
+pub fn end(pid: Pid, exit: Exit) -> Option<UserMemory> {
+    with_process(pid, |process| {
+        process.state = State::Ended(exit);
+        close_all(process);
+        process.memory.take()
+    }).flatten()
+}
+
+/// Gives the current process a handle to `handle`, and returns its number, or None if it
+/// hasn't room for it.
+pub fn open(handle: Handle) -> Option<u64> {
+    with_current(|process| {
+        let number = process.handles.iter().position(Option::is_none)?;
+        process.handles[number] = Some(handle);
+        Some(number as u64)
+    }).flatten()
+}
+
+/// Gives the current process handles to `first` and `second`, and returns their numbers, or
+/// None if it hasn't room for both.
+pub fn open_pair(first: Handle, second: Handle) -> Option<(u64, u64)> {
+    with_current(|process| {
+        let mut free = process.handles.iter().enumerate().filter(|(_, handle)| handle.is_none()).map(|(number, _)| number);
+        let (first_number, second_number) = (free.next()?, free.next()?);
+        process.handles[first_number] = Some(first);
+        process.handles[second_number] = Some(second);
+        Some((first_number as u64, second_number as u64))
+    }).flatten()
+}
+
+fn close_all(process: &mut Process) {
+    process.handles.iter_mut().filter_map(Option::take).for_each(close_handle);
+}
+
+/// Closes `handle`, which nothing has open any more.
+pub fn close_handle(handle: Handle) {
+    match handle {
+        Handle::ConsoleInput | Handle::ConsoleOutput => {},
+        Handle::PipeRead(id) => pipe::close(id, End::Read),
+        Handle::PipeWrite(id) => pipe::close(id, End::Write),
+        Handle::Shared(id) => shm::release(id),
+        Handle::File(file) => file.close(),
+    }
+}
+
+// Takes process `pid` out of the table
+fn remove(pid: Pid) -> Option<Process> {
+    let mut processes = PROCESSES.lock_irq();
+    processes.processes.iter_mut().find(|process| matches!(process, Some(process) if process.pid == pid))?.take()
+}
+
+/// Calls `f` on process `pid`, if there is one.
+pub fn with_process<R>(pid: Pid, f: impl FnOnce(&mut Process) -> R) -> Option<R> {
+    let mut processes = PROCESSES.lock_irq();
+    processes.processes.iter_mut().flatten().find(|process| process.pid == pid).map(f)
+}
+
+/// Calls `f` on the process of the current task, if it has one that hasn't ended. Interrupts
+/// are disabled meanwhile.
+pub fn with_current<R>(f: impl FnOnce(&mut Process) -> R) -> Option<R> {
+    let thread = Some(task::current());
+    let mut processes = PROCESSES.lock_irq();
+    processes.processes.iter_mut().flatten()
+        .find(|process| process.thread == thread && !matches!(process.state, State::Ended(_)))
+        .map(f)
+}
+
+/// Returns what handle `number` of the current process stands for, if it is open.
+pub fn handle(number: u64) -> Option<Handle> {
+    let number = usize::try_from(number).ok()?;
+    with_current(|process| process.handles.get(number).copied().flatten()).flatten()
+}
+
+/// Makes handle `number` of the current process, if it is open, stand for `handle` instead,
+/// such as a file that has moved on.
+pub fn replace(number: u64, handle: Handle) {
+    let Ok(number) = usize::try_from(number) else {
+        return;
+    };
+    with_current(|process| {
+        if let Some(open @ Some(_)) = process.handles.get_mut(number) {
+            *open = Some(handle);
+        }
+    });
+}
+
+// read(handle, buffer, length, flags): waits for something to read, unless `flags` has
+// NO_WAIT, and returns how many bytes were read; none from a pipe means nothing can write to
+// it any more, and none from a file its end
+fn read(arguments: [u64; 6]) -> Result<u64, syscall::Error> {
+    let [number, buffer, length, flags, ..] = arguments;
+    if flags & !NO_WAIT != 0 {
+        return Err(syscall::Error::BadArgument);
+    }
+    let wait = flags & NO_WAIT == 0;
+    let handle = handle(number).ok_or(syscall::Error::BadHandle)?;
+    let buffer = syscall::user_bytes_mut(buffer, length)?;
+    let read = match handle {
+        Handle::ConsoleInput => console::read(buffer, wait),
+        Handle::PipeRead(id) => pipe::read(id, buffer, wait),
+        Handle::File(mut file) => {
+            let read = file.read(buffer)?;
+            replace(number, Handle::File(file));
+            read
+        },
+        Handle::ConsoleOutput | Handle::PipeWrite(_) | Handle::Shared(_) => return Err(syscall::Error::BadHandle),
+    };
+    Ok(read as u64)
+}
+
+// write(handle, buffer, length): returns how many bytes were written, which for a pipe may be
+// fewer than `length`
+fn write(arguments: [u64; 6]) -> Result<u64, syscall::Error> {
+    let [number, buffer, length, ..] = arguments;
+    let handle = handle(number).ok_or(syscall::Error::BadHandle)?;
+    let bytes = syscall::user_bytes(buffer, length)?;
+    let written = match handle {
+        Handle::ConsoleOutput => console::write(bytes),
+        Handle::PipeWrite(id) => pipe::write(id, bytes)?,
+        Handle::File(mut file) => {
+            let written = file.write(bytes)?;
+            replace(number, Handle::File(file));
+            written
+        },
+        Handle::ConsoleInput | Handle::PipeRead(_) | Handle::Shared(_) => return Err(syscall::Error::BadHandle),
+    };
+    Ok(written as u64)
+}
+
+// close(handle)
+fn close(arguments: [u64; 6]) -> Result<u64, syscall::Error> {
+    let number = usize::try_from(arguments[0]).map_err(|_| syscall::Error::BadHandle)?;
+    let handle = with_current(|process| process.handles.get_mut(number).and_then(Option::take)).flatten();
+    close_handle(handle.ok_or(syscall::Error::BadHandle)?);
+    Ok(0)
+}
+
+/// What `processes` reports about a process.
+#[derive(Clone, Copy)]
+pub struct ProcessInfo {
+    pub pid: Pid,
+    pub name: &'static str,
+    pub thread: Option<usize>,
+    pub state: State,
+    pub handles: [Option<Handle>; MAX_HANDLES],
+    /// The bytes of heap and of `mmap` memory, while it runs.
+    pub memory: Option<(u64, u64)>,
+}
+
+/// Returns what there is to know about every process in the table.
+pub fn processes() -> [Option<ProcessInfo>; MAX_PROCESSES] {
+    let processes = PROCESSES.lock_irq();
+    processes.processes.each_ref().map(|process| {
+        process.as_ref().map(|process| ProcessInfo {
+            pid: process.pid,
+            name: process.name,
+            thread: process.thread,
+            state: process.state,
+            handles: process.handles,
+            memory: process.memory.as_ref().map(|memory| (memory.heap_size(), memory.mapped_size())),
+        })
+    })
+}
+
+/// Prints the processes on the serial port.
+pub fn dump() {
+    writeln!(serial(), "{:>4}  {:<10} {:>5}  STATE", "PID", "NAME", "TASK").unwrap();
+    for process in processes().iter().flatten() {
+        let mut port = serial();
+        let mut thread = ui::TextBuffer::<8>::new();
+        match process.thread {
+            Some(id) => write!(thread, "{id}").unwrap(),
+            None => write!(thread, "-").unwrap(),
+        }
+        write!(port, "{:>4}  {:<10} {:>5}  ", process.pid, process.name, thread.as_str()).unwrap();
+        match process.state {
+            State::Waiting => writeln!(port, "waiting").unwrap(),
+            State::Running => writeln!(port, "running").unwrap(),
+            State::Ended(Exit::Exited(code)) => writeln!(port, "exited with {code}").unwrap(),
+            State::Ended(Exit::Faulted(fault)) => writeln!(port, "stopped: {}", fault.exception).unwrap(),
+            State::Ended(Exit::Killed) => writeln!(port, "killed").unwrap(),
+        }
+    }
+}
+
+/// Prints what there is to know about process `pid` on the serial port. Returns false if
+/// there is no such process.
+pub fn inspect(pid: Pid) -> bool {
+    let Some(process) = processes().into_iter().flatten().find(|process| process.pid == pid) else {
+        return false;
+    };
+    let mut port = serial();
+    write!(port, "Process {} ({})", process.pid, process.name).unwrap();
+    match process.thread {
+        Some(id) => writeln!(port, ", on task {id}").unwrap(),
+        None => writeln!(port).unwrap(),
+    }
+    match process.state {
+        State::Waiting => writeln!(port, "Waiting to run").unwrap(),
+        State::Running => writeln!(port, "Running").unwrap(),
+        State::Ended(Exit::Exited(code)) => writeln!(port, "Exited with {code}").unwrap(),
+        State::Ended(Exit::Faulted(fault)) => writeln!(port, "Stopped: {fault}").unwrap(),
+        State::Ended(Exit::Killed) => writeln!(port, "Killed").unwrap(),
+    }
+    for (number, handle) in process.handles.iter().enumerate() {
+        if let Some(handle) = handle {
+            writeln!(port, "Handle {number}: {handle:?}").unwrap();
+        }
+    }
+    if let Some((heap, mapped)) = process.memory {
+        writeln!(port, "Heap: {} KiB, mapped: {} KiB", heap.div_ceil(1024), mapped.div_ceil(1024)).unwrap();
+    }
+    true
+}
@@ -0,0 +1,66 @@
+use alloc::boxed::Box;
+use core::fmt::Write;
+use core::ptr;
+use kernel::serial;
+use crate::block::{self, BLOCK_SIZE, BlockDevice, Error};
+use crate::config;
+use crate::memory::{self, PAGE_SIZE};
+
+// A disk kept in memory, as big as kernel.cfg's [ramdisk] asks, registered as ram0. It starts
+// out zeroed and is gone at a reboot, so it is for trying out the partition, cache and
+// filesystem code without a disk image: copy an image in the initrd onto it with the shell's
+// `copy`, say, and mount it. Its pages are mapped at boot, not taken from the heap.
+const NAME: &str = "ram0";
+const KIB: u64 = 1024;
+
+struct RamDisk {
+    start: *mut u8,
+    sectors: u64,
+}
+
+// Only reached through its filesystem's or the block I/O task's requests, one at a time
+unsafe impl Send for RamDisk {}
+unsafe impl Sync for RamDisk {}
+
+impl BlockDevice for RamDisk {
+    fn name(&self) -> &str {
+        NAME
+    }
+
+    fn blocks(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read(&self, block: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        block::check(self.sectors, block, buffer.len())?;
+        unsafe { ptr::copy_nonoverlapping(self.start.add(block as usize * BLOCK_SIZE), buffer.as_mut_ptr(), buffer.len()) };
+        Ok(())
+    }
+
+    fn write(&self, block: u64, bytes: &[u8]) -> Result<(), Error> {
+        block::check(self.sectors, block, bytes.len())?;
+        unsafe { ptr::copy_nonoverlapping(bytes.as_ptr(), self.start.add(block as usize * BLOCK_SIZE), bytes.len()) };
+        Ok(())
+    }
+}
+
+/// Maps the RAM disk, if kernel.cfg asks for one, and registers it. Called once during boot,
+/// after `config::init`, `memory::init` and `block::init`.
+pub fn init() {
+    let kib = config::get().ramdisk_kib;
+    if kib == 0 {
+        return;
+    }
+    let bytes = kib * KIB;
+    let Some(start) = memory::alloc_guarded_pages(bytes.div_ceil(PAGE_SIZE)) else {
+        writeln!(serial(), "{NAME}: no memory for {kib} KiB").unwrap();
+        return;
+    };
+    let start: *mut u8 = start.as_mut_ptr();
+    unsafe { start.write_bytes(0, bytes as usize) };
+    let sectors = bytes / BLOCK_SIZE as u64;
+    writeln!(serial(), "{NAME}: RAM disk, {sectors} sectors").unwrap();
+    if !block::register(Box::leak(Box::new(RamDisk { start, sectors }))) {
+        writeln!(serial(), "{NAME}: too many block devices, not registered").unwrap();
+    }
+}
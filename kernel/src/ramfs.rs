@@ -0,0 +1,262 @@
+use core::fmt::Write;
+use kernel::sync::SpinLock;
+use x86_64::VirtAddr;
+use crate::fs::{DirEntry, Error, Filesystem, Kind, Metadata, MAX_NAME};
+use crate::memory::{self, PAGE_SIZE};
+use crate::ui::TextBuffer;
+
+// A filesystem kept in memory, mounted at /tmp as scratch space: what is written to it is gone
+// after a reboot. It does everything `fs::Filesystem` can ask for in the plainest way, so it is
+// also the one to read first when writing another.
+//
+// Its files and directories are nodes in a fixed table, and a node's inode number is its slot
+// with the slot's generation above it, which goes up when the node is removed, so an inode kept
+// after its node was removed isn't taken for whatever is made in the slot next. A file's bytes
+// are in pages of their own, mapped as it grows and given back when it shrinks or is removed,
+// since the kernel heap never frees. Bytes past the end of a file are always zero, so growing
+// one only has to move its end.
+const MAX_NODES: usize = 64;
+// The most pages a file has, so it can't be more than 256 KiB
+const MAX_PAGES: usize = 64;
+const PAGE: usize = PAGE_SIZE as usize;
+const MAX_SIZE: u64 = (MAX_PAGES * PAGE) as u64;
+const ROOT: usize = 0;
+
+/// The filesystem mounted at /tmp.
+pub static FILESYSTEM: Ramfs = Ramfs { nodes: SpinLock::new(Nodes::new()) };
+
+/// A filesystem in memory.
+pub struct Ramfs {
+    nodes: SpinLock<Nodes>,
+}
+
+#[derive(Clone, Copy)]
+struct Node {
+    kind: Kind,
+    // The slot of the directory it is in; the root is in itself
+    parent: usize,
+    name: TextBuffer<MAX_NAME>,
+    size: u64,
+    // Where each page of a file is mapped, or 0 where it has none yet, which reads as zeroes
+    pages: [u64; MAX_PAGES],
+}
+
+#[derive(Clone, Copy)]
+struct Slot {
+    generation: u32,
+    node: Option<Node>,
+}
+
+struct Nodes([Slot; MAX_NODES]);
+
+impl Node {
+    const fn new(kind: Kind, parent: usize) -> Node {
+        Node { kind, parent, name: TextBuffer::new(), size: 0, pages: [0; MAX_PAGES] }
+    }
+
+    // Gives back the pages from page `first` on
+    fn free_pages(&mut self, first: usize) {
+        for address in self.pages.iter_mut().skip(first).filter(|address| **address != 0) {
+            memory::free_pages(VirtAddr::new(*address), 1);
+            *address = 0;
+        }
+    }
+}
+
+impl Nodes {
+    const fn new() -> Nodes {
+        let mut slots = [Slot { generation: 0, node: None }; MAX_NODES];
+        slots[ROOT].node = Some(Node::new(Kind::Directory, ROOT));
+        Nodes(slots)
+    }
+
+    // The slot of the node `inode` is, unless it has been removed
+    fn slot(&self, inode: u64) -> Result<usize, Error> {
+        let slot = (inode & 0xffff_ffff) as usize;
+        match self.0.get(slot) {
+            Some(Slot { generation, node: Some(_) }) if u64::from(*generation) == inode >> 32 => Ok(slot),
+            _ => Err(Error::NotFound),
+        }
+    }
+
+    fn node(&self, inode: u64) -> Result<&Node, Error> {
+        let slot = self.slot(inode)?;
+        Ok(self.0[slot].node.as_ref().unwrap())
+    }
+
+    fn node_mut(&mut self, inode: u64) -> Result<&mut Node, Error> {
+        let slot = self.slot(inode)?;
+        Ok(self.0[slot].node.as_mut().unwrap())
+    }
+
+    // The inode of the node in `slot`
+    fn inode(&self, slot: usize) -> u64 {
+        u64::from(self.0[slot].generation) << 32 | slot as u64
+    }
+
+    // The slots of what is in the directory in slot `directory`, in order
+    fn children(&self, directory: usize) -> impl Iterator<Item = (usize, &Node)> {
+        self.0.iter().enumerate()
+            .filter_map(|(slot, entry)| Some((slot, entry.node.as_ref()?)))
+            .filter(move |(slot, node)| node.parent == directory && *slot != ROOT)
+    }
+
+    // The directory `inode` is, and the slot of what is called `name` in it, if anything is
+    fn find(&self, inode: u64, name: &str) -> Result<(usize, Option<usize>), Error> {
+        let directory = self.slot(inode)?;
+        if self.0[directory].node.unwrap().kind != Kind::Directory {
+            return Err(Error::NotADirectory);
+        }
+        let child = self.children(directory).find(|(_, node)| node.name.as_str() == name).map(|(slot, _)| slot);
+        Ok((directory, child))
+    }
+}
+
+// The bytes of the file page at `address`, only touched with the nodes locked
+fn page(address: u64) -> &'static mut [u8; PAGE] {
+    unsafe { &mut *(address as *mut [u8; PAGE]) }
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= MAX_NAME && !name.contains('/') && name != "." && name != ".."
+}
+
+impl Filesystem for Ramfs {
+    fn name(&self) -> &'static str {
+        "ramfs"
+    }
+
+    fn root(&self) -> u64 {
+        ROOT as u64
+    }
+
+    fn lookup(&self, directory: u64, name: &str) -> Result<u64, Error> {
+        let nodes = self.nodes.lock_irq();
+        match nodes.find(directory, name)? {
+            (_, Some(slot)) => Ok(nodes.inode(slot)),
+            (_, None) => Err(Error::NotFound),
+        }
+    }
+
+    fn metadata(&self, inode: u64) -> Result<Metadata, Error> {
+        let nodes = self.nodes.lock_irq();
+        let node = nodes.node(inode)?;
+        Ok(Metadata { kind: node.kind, size: node.size })
+    }
+
+    fn read(&self, inode: u64, offset: u64, buffer: &mut [u8]) -> Result<usize, Error> {
+        let nodes = self.nodes.lock_irq();
+        let node = nodes.node(inode)?;
+        if node.kind == Kind::Directory {
+            return Err(Error::IsADirectory);
+        }
+        let length = node.size.saturating_sub(offset).min(buffer.len() as u64) as usize;
+        let mut read = 0;
+        while read < length {
+            let position = offset as usize + read;
+            let (index, start) = (position / PAGE, position % PAGE);
+            let count = (PAGE - start).min(length - read);
+            let into = &mut buffer[read..read + count];
+            match node.pages[index] {
+                0 => into.fill(0),
+                address => into.copy_from_slice(&page(address)[start..start + count]),
+            }
+            read += count;
+        }
+        Ok(length)
+    }
+
+    fn entry(&self, directory: u64, index: usize) -> Result<Option<DirEntry>, Error> {
+        let nodes = self.nodes.lock_irq();
+        let slot = nodes.slot(directory)?;
+        if nodes.0[slot].node.unwrap().kind != Kind::Directory {
+            return Err(Error::NotADirectory);
+        }
+        Ok(nodes.children(slot).nth(index).map(|(_, node)| DirEntry { name: node.name, kind: node.kind }))
+    }
+
+    fn write(&self, inode: u64, offset: u64, bytes: &[u8]) -> Result<usize, Error> {
+        let mut nodes = self.nodes.lock_irq();
+        let node = nodes.node_mut(inode)?;
+        if node.kind == Kind::Directory {
+            return Err(Error::IsADirectory);
+        }
+        if offset >= MAX_SIZE && !bytes.is_empty() {
+            return Err(Error::NoSpace);
+        }
+        let length = MAX_SIZE.saturating_sub(offset).min(bytes.len() as u64) as usize;
+        let mut written = 0;
+        while written < length {
+            let position = offset as usize + written;
+            let (index, start) = (position / PAGE, position % PAGE);
+            let count = (PAGE - start).min(length - written);
+            if node.pages[index] == 0 {
+                let Some(address) = memory::alloc_guarded_pages(1) else {
+                    break;
+                };
+                // The frame may have been used before
+                page(address.as_u64()).fill(0);
+                node.pages[index] = address.as_u64();
+            }
+            page(node.pages[index])[start..start + count].copy_from_slice(&bytes[written..written + count]);
+            written += count;
+        }
+        if written == 0 && length > 0 {
+            return Err(Error::NoSpace);
+        }
+        if written > 0 {
+            node.size = node.size.max(offset + written as u64);
+        }
+        Ok(written)
+    }
+
+    fn truncate(&self, inode: u64, size: u64) -> Result<(), Error> {
+        let mut nodes = self.nodes.lock_irq();
+        let node = nodes.node_mut(inode)?;
+        if node.kind == Kind::Directory {
+            return Err(Error::IsADirectory);
+        }
+        if size > MAX_SIZE {
+            return Err(Error::NoSpace);
+        }
+        if size < node.size {
+            let size = size as usize;
+            node.free_pages(size.div_ceil(PAGE));
+            // What is left of the last page past the new end
+            if let Some(&address) = node.pages.get(size / PAGE).filter(|&&address| address != 0) {
+                page(address)[size % PAGE..].fill(0);
+            }
+        }
+        node.size = size;
+        Ok(())
+    }
+
+    fn create(&self, directory: u64, name: &str, kind: Kind) -> Result<u64, Error> {
+        if !valid_name(name) {
+            return Err(Error::InvalidPath);
+        }
+        let mut nodes = self.nodes.lock_irq();
+        let (parent, None) = nodes.find(directory, name)? else {
+            return Err(Error::Exists);
+        };
+        let slot = nodes.0.iter().position(|slot| slot.node.is_none()).ok_or(Error::NoSpace)?;
+        let mut node = Node::new(kind, parent);
+        write!(node.name, "{name}").unwrap();
+        nodes.0[slot].node = Some(node);
+        Ok(nodes.inode(slot))
+    }
+
+    fn remove(&self, directory: u64, name: &str) -> Result<(), Error> {
+        let mut nodes = self.nodes.lock_irq();
+        let (_, Some(slot)) = nodes.find(directory, name)? else {
+            return Err(Error::NotFound);
+        };
+        if nodes.children(slot).next().is_some() {
+            return Err(Error::NotEmpty);
+        }
+        let entry = &mut nodes.0[slot];
+        entry.node.take().unwrap().free_pages(0);
+        entry.generation = entry.generation.wrapping_add(1);
+        Ok(())
+    }
+}
@@ -0,0 +1,124 @@
+use core::arch::x86_64::{__cpuid, __cpuid_count, _rdrand64_step, _rdseed64_step};
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU64, Ordering};
+use kernel::serial;
+use x86_64::instructions::port::Port;
+use crate::time;
+
+// xorshift64* generator shared by the games. Not suitable for anything security related.
+static STATE: AtomicU64 = AtomicU64::new(0x2545_F491_4F6C_DD1D);
+// A second one for `fill`, so what is read from /dev/random doesn't change what the games get,
+// which replays depend on
+static FILL_STATE: AtomicU64 = AtomicU64::new(0x2545_F491_4F6C_DD1D);
+
+// RDRAND and RDSEED may fail transiently when the hardware entropy source is drained
+const HARDWARE_RETRIES: usize = 10;
+
+// Number of timing samples mixed together when there is no hardware random number generator
+const JITTER_SAMPLES: usize = 64;
+
+/// Seeds the generator from the best entropy source the CPU offers: RDSEED, then RDRAND,
+/// and finally the jitter in how long port I/O takes, measured with the TSC.
+pub fn init() {
+    let (source, value) = if let Some(value) = rdseed() {
+        ("RDSEED", value)
+    } else if let Some(value) = rdrand() {
+        ("RDRAND", value)
+    } else {
+        ("TSC jitter", tsc_jitter())
+    };
+    seed(value);
+    FILL_STATE.store((value.rotate_left(32) ^ time::rdtsc()) | 1, Ordering::SeqCst);
+    writeln!(serial(), "Random number generator seeded from {source}").unwrap();
+}
+
+/// Reseeds the generator. A zero seed is replaced since xorshift would get stuck at zero.
+pub fn seed(seed: u64) {
+    let seed = if seed == 0 { 0x2545_F491_4F6C_DD1D } else { seed };
+    STATE.store(seed, Ordering::SeqCst);
+}
+
+/// Returns the generator's current state. Passing it to `seed` later repeats the same sequence.
+pub fn state() -> u64 {
+    STATE.load(Ordering::SeqCst)
+}
+
+/// Returns the next pseudo-random 64-bit value.
+pub fn next_u64() -> u64 {
+    step(&STATE)
+}
+
+/// Fills `buffer` with random bytes, from RDRAND if the CPU has it. They don't come from the
+/// games' generator, whose sequence stays the same.
+pub fn fill(buffer: &mut [u8]) {
+    for chunk in buffer.chunks_mut(8) {
+        let value = rdrand().unwrap_or_else(|| step(&FILL_STATE));
+        chunk.copy_from_slice(&value.to_le_bytes()[..chunk.len()]);
+    }
+}
+
+fn step(state: &AtomicU64) -> u64 {
+    let mut x = state.load(Ordering::SeqCst);
+    x ^= x >> 12;
+    x ^= x << 25;
+    x ^= x >> 27;
+    state.store(x, Ordering::SeqCst);
+    x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+}
+
+/// Returns a pseudo-random number in `0..bound`. `bound` must not be zero.
+pub fn below(bound: u32) -> u32 {
+    // the high bits of xorshift64* are the good ones
+    (((next_u64() >> 32) * bound as u64) >> 32) as u32
+}
+
+/// Returns true or false with equal probability.
+pub fn coin_flip() -> bool {
+    next_u64() >> 63 != 0
+}
+
+fn rdseed() -> Option<u64> {
+    // CPUID leaf 7, EBX bit 18
+    let supported = unsafe { __cpuid_count(7, 0) }.ebx & (1 << 18) != 0;
+    if !supported {
+        return None;
+    }
+    let mut value = 0;
+    (0..HARDWARE_RETRIES).find(|_| unsafe { rdseed_step(&mut value) })?;
+    Some(value)
+}
+
+fn rdrand() -> Option<u64> {
+    // CPUID leaf 1, ECX bit 30
+    let supported = unsafe { __cpuid(1) }.ecx & (1 << 30) != 0;
+    if !supported {
+        return None;
+    }
+    let mut value = 0;
+    (0..HARDWARE_RETRIES).find(|_| unsafe { rdrand_step(&mut value) })?;
+    Some(value)
+}
+
+#[target_feature(enable = "rdseed")]
+unsafe fn rdseed_step(value: &mut u64) -> bool {
+    unsafe { _rdseed64_step(value) == 1 }
+}
+
+#[target_feature(enable = "rdrand")]
+unsafe fn rdrand_step(value: &mut u64) -> bool {
+    unsafe { _rdrand64_step(value) == 1 }
+}
+
+/// Collects entropy from the variation in how many TSC cycles a port read takes.
+/// Only the low bits of each sample are unpredictable, so many samples are mixed together.
+fn tsc_jitter() -> u64 {
+    let mut status = Port::<u8>::new(0x61);
+    let mut value = time::rdtsc();
+    for _ in 0..JITTER_SAMPLES {
+        let start = time::rdtsc();
+        unsafe { status.read() };
+        let elapsed = time::rdtsc() - start;
+        value = (value.rotate_left(7) ^ elapsed).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    }
+    value
+}
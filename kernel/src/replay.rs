@@ -0,0 +1,161 @@
+use alloc::vec::Vec;
+use core::fmt::Write;
+use kernel::serial;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::input::InputEvent;
+use crate::rand;
+use crate::settings::{self, Snapshot};
+
+// Replays record everything a game sees from the outside (its input events, settings changes,
+// the time between updates and the random number generator state) so a match can be simulated again exactly.
+// Playback checks the generator state on every tick, which makes it a cheap way to spot
+// changes to the game logic that alter how a recorded match plays out.
+
+// The heap is small and never gives memory back, so the buffer is allocated once at this
+// size and reused for every recording
+const MAX_ENTRIES: usize = 2048;
+
+#[derive(Clone, Copy)]
+enum Entry {
+    Input(InputEvent),
+    Settings(Snapshot),
+    Tick { dt: u32, rng_state: u64 },
+}
+
+/// Everything needed to start a game the same way it was started when it was recorded.
+#[derive(Clone, Copy)]
+pub struct Header {
+    pub game: usize,
+    pub settings: Snapshot,
+    pub rng_state: u64,
+}
+
+#[derive(PartialEq, Eq)]
+enum Mode {
+    Idle,
+    Recording,
+    Playing { position: usize, tick: usize },
+    // Playback reached the end; the game stays frozen on its last frame
+    Finished,
+}
+
+struct Replay {
+    header: Option<Header>,
+    entries: Vec<Entry>,
+    mode: Mode,
+    diverged: bool,
+    // The player's own settings, put back when playback stops
+    saved_settings: Option<Snapshot>,
+}
+
+lazy_static! {
+    static ref REPLAY: Mutex<Replay> = Mutex::new(Replay {
+        header: None,
+        entries: Vec::new(),
+        mode: Mode::Idle,
+        diverged: false,
+        saved_settings: None,
+    });
+}
+
+impl Replay {
+    fn push(&mut self, entry: Entry) {
+        if self.mode != Mode::Recording {
+            return;
+        }
+        if self.entries.len() == MAX_ENTRIES {
+            writeln!(serial(), "Replay buffer full, recording stopped").unwrap();
+            self.mode = Mode::Idle;
+            return;
+        }
+        self.entries.push(entry);
+    }
+}
+
+/// Starts recording a new match of game `game`, replacing the previous recording.
+/// Must be called right before the game is initialized.
+pub fn start_recording(game: usize) {
+    let mut replay = REPLAY.lock();
+    if replay.entries.capacity() == 0 {
+        replay.entries.reserve_exact(MAX_ENTRIES);
+    }
+    replay.entries.clear();
+    replay.header = Some(Header { game, settings: settings::snapshot(), rng_state: rand::state() });
+    replay.mode = Mode::Recording;
+}
+
+/// Records an input event passed to the game.
+pub fn record_input(event: InputEvent) {
+    REPLAY.lock().push(Entry::Input(event));
+}
+
+/// Records that the settings changed, so playback changes them at the same point.
+pub fn record_settings() {
+    REPLAY.lock().push(Entry::Settings(settings::snapshot()));
+}
+
+/// Records the start of a tick, before the game is updated by `dt` milliseconds.
+pub fn record_tick(dt: u64) {
+    REPLAY.lock().push(Entry::Tick { dt: dt.min(u32::MAX as u64) as u32, rng_state: rand::state() });
+}
+
+/// Stops recording or playing back.
+pub fn stop() {
+    let mut replay = REPLAY.lock();
+    replay.mode = Mode::Idle;
+    if let Some(saved) = replay.saved_settings.take() {
+        // Recorded key presses may have changed (and saved) settings during playback
+        settings::restore(saved);
+        settings::save();
+    }
+}
+
+/// Returns true while a recording is being played back, or has been played to the end.
+pub fn is_playing() -> bool {
+    matches!(REPLAY.lock().mode, Mode::Playing { .. } | Mode::Finished)
+}
+
+/// Starts playing back the last recording, switching to the settings and random number
+/// generator it started with until playback stops. Returns its header, or None if nothing has been recorded.
+pub fn start_playback() -> Option<Header> {
+    let mut replay = REPLAY.lock();
+    let header = replay.header?;
+    replay.saved_settings.get_or_insert(settings::snapshot());
+    settings::restore(header.settings);
+    rand::seed(header.rng_state);
+    replay.mode = Mode::Playing { position: 0, tick: 0 };
+    replay.diverged = false;
+    writeln!(serial(), "Playing back {} recorded events", replay.entries.len()).unwrap();
+    Some(header)
+}
+
+/// Feeds the recorded input events of the next tick to `input` and returns the time the
+/// tick advanced by, or None once the recording has ended.
+pub fn next_tick(mut input: impl FnMut(InputEvent)) -> Option<u64> {
+    let mut replay = REPLAY.lock();
+    let Mode::Playing { mut position, tick } = replay.mode else {
+        return None;
+    };
+
+    while let Some(entry) = replay.entries.get(position).copied() {
+        position += 1;
+        match entry {
+            Entry::Input(event) => input(event),
+            Entry::Settings(snapshot) => settings::restore(snapshot),
+            Entry::Tick { dt, rng_state } => {
+                if rng_state != rand::state() && !replay.diverged {
+                    replay.diverged = true;
+                    writeln!(serial(), "Replay diverged from the recording at tick {tick}").unwrap();
+                }
+                replay.mode = Mode::Playing { position, tick: tick + 1 };
+                return Some(dt as u64);
+            },
+        }
+    }
+
+    let result = if replay.diverged { "diverged" } else { "matched the recording" };
+    writeln!(serial(), "Replay finished after {tick} ticks and {result}").unwrap();
+    replay.mode = Mode::Finished;
+    None
+}
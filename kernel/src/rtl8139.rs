@@ -0,0 +1,251 @@
+use alloc::boxed::Box;
+use core::fmt::Write;
+use core::ptr;
+use kernel::serial;
+use kernel::sync::SpinLock;
+use x86_64::instructions::port::Port;
+use crate::memory::{self, Dma};
+use crate::net::{self, Error, Mac, MAX_FRAME, NetworkDevice};
+use crate::pci::{self, Match, PciDevice};
+use crate::irq;
+
+// Realtek RTL8139 network cards, about the simplest there are: the registers are I/O ports
+// from BAR 0, and there are no descriptor rings. Received frames go one after another into a
+// single ring buffer in DMA memory, each after a status and a length and aligned to 4 bytes,
+// and the driver tells the card how far it has read. Frames are sent from four buffers in
+// turn, each with a start address and a status register the card marks once it has copied the
+// frame out. The card only reaches the first 4 GiB, so its memory has to be there.
+const VENDOR: u16 = 0x10EC;
+const DEVICE: u16 = 0x8139;
+
+// Registers, as offsets from BAR 0
+const MAC_ADDRESS: u16 = 0x00;
+const MULTICAST: u16 = 0x08;
+const TRANSMIT_STATUS: u16 = 0x10;
+const TRANSMIT_ADDRESS: u16 = 0x20;
+const RECEIVE_BUFFER: u16 = 0x30;
+const COMMAND: u16 = 0x37;
+const READ_POINTER: u16 = 0x38;
+const INTERRUPT_MASK: u16 = 0x3C;
+const INTERRUPT_STATUS: u16 = 0x3E;
+const RECEIVE_CONFIG: u16 = 0x44;
+const CONFIG_1: u16 = 0x52;
+const MEDIA_STATUS: u16 = 0x58;
+
+// Command bits: the receive buffer is empty, transmit and receive are enabled, and reset
+const BUFFER_EMPTY: u8 = 1;
+const TRANSMIT_ENABLE: u8 = 1 << 2;
+const RECEIVE_ENABLE: u8 = 1 << 3;
+const RESET: u8 = 1 << 4;
+// Interrupts: a frame received, a receive error, the buffer overflowed, the link changed
+const RECEIVE_OK: u16 = 1;
+const RECEIVE_ERROR: u16 = 1 << 1;
+const RECEIVE_OVERFLOW: u16 = 1 << 4;
+const LINK_CHANGE: u16 = 1 << 5;
+const INTERRUPTS: u16 = RECEIVE_OK | RECEIVE_ERROR | RECEIVE_OVERFLOW | LINK_CHANGE;
+// Receive configuration: frames to this card's address, multicast and broadcast ones, and
+// frames that run past the end of the buffer carry on after it rather than wrapping
+const ACCEPT_PHYSICAL: u32 = 1 << 1;
+const ACCEPT_MULTICAST: u32 = 1 << 2;
+const ACCEPT_BROADCAST: u32 = 1 << 3;
+const NO_WRAP: u32 = 1 << 7;
+// Transmit status: the card has copied the frame out
+const OWN: u32 = 1 << 13;
+// Media status bit for the link being down
+const LINK_DOWN: u8 = 1 << 2;
+
+// The receive buffer's 8 KiB and 16 bytes, which is what the card is told, with room after it
+// for the longest frame that starts at its end
+const RECEIVE_SIZE: usize = 8192;
+const RECEIVE_PAGES: u64 = 3;
+// Each received frame's header: its status, whose first bit says it is good, and its length
+// with the checksum
+const HEADER_SIZE: usize = 4;
+const CHECKSUM_SIZE: usize = 4;
+const FRAME_OK: u16 = 1;
+// The card reads the pointer 16 bytes behind where the driver is
+const READ_POINTER_OFFSET: usize = 16;
+const TRANSMIT_BUFFERS: usize = 4;
+const TRANSMIT_SIZE: usize = 2048;
+const TRANSMIT_PAGES: u64 = 2;
+// The card has 32-bit DMA addresses
+const DMA_LIMIT: u64 = 1 << 32;
+
+// The cards, for the interrupt handler to check which raised it
+static CARDS: SpinLock<[Option<&'static Card>; net::MAX_DEVICES]> = SpinLock::new([None; net::MAX_DEVICES]);
+
+// The receive buffer, and how far the driver has read in it
+struct Receive {
+    buffer: Dma,
+    offset: usize,
+}
+
+// The transmit buffers, and the next one to send from
+struct Transmit {
+    buffers: Dma,
+    next: usize,
+}
+
+struct Card {
+    io: u16,
+    mac: Mac,
+    line: u8,
+    receive: SpinLock<Receive>,
+    transmit: SpinLock<Transmit>,
+}
+
+impl Card {
+    fn read8(&self, register: u16) -> u8 {
+        unsafe { Port::<u8>::new(self.io + register).read() }
+    }
+
+    fn write8(&self, register: u16, value: u8) {
+        unsafe { Port::<u8>::new(self.io + register).write(value) }
+    }
+
+    fn read16(&self, register: u16) -> u16 {
+        unsafe { Port::<u16>::new(self.io + register).read() }
+    }
+
+    fn write16(&self, register: u16, value: u16) {
+        unsafe { Port::<u16>::new(self.io + register).write(value) }
+    }
+
+    fn read32(&self, register: u16) -> u32 {
+        unsafe { Port::<u32>::new(self.io + register).read() }
+    }
+
+    fn write32(&self, register: u16, value: u32) {
+        unsafe { Port::<u32>::new(self.io + register).write(value) }
+    }
+}
+
+impl NetworkDevice for Card {
+    fn mac(&self) -> Mac {
+        self.mac
+    }
+
+    fn link_up(&self) -> bool {
+        self.read8(MEDIA_STATUS) & LINK_DOWN == 0
+    }
+
+    fn send(&self, frame: &[u8]) -> Result<(), Error> {
+        if frame.len() > MAX_FRAME {
+            return Err(Error::TooLong);
+        }
+        let mut transmit = self.transmit.lock();
+        let index = transmit.next;
+        let status = TRANSMIT_STATUS + 4 * index as u16;
+        // Each buffer's status starts out owned by the driver, and the card sets it back once
+        // it has the frame
+        if self.read32(status) & OWN == 0 {
+            return Err(Error::Busy);
+        }
+        let buffer: *mut u8 = (transmit.buffers.start + (index * TRANSMIT_SIZE) as u64).as_mut_ptr();
+        unsafe { ptr::copy_nonoverlapping(frame.as_ptr(), buffer, frame.len()) };
+        // Writing the length with the own bit clear starts the send
+        self.write32(status, frame.len() as u32);
+        transmit.next = (index + 1) % TRANSMIT_BUFFERS;
+        Ok(())
+    }
+
+    fn receive(&self, frame: &mut [u8; MAX_FRAME]) -> Option<usize> {
+        let mut receive = self.receive.lock();
+        loop {
+            if self.read8(COMMAND) & BUFFER_EMPTY != 0 {
+                return None;
+            }
+            let start: *const u8 = (receive.buffer.start + receive.offset as u64).as_ptr();
+            let (status, length) = unsafe { (start.cast::<u16>().read_volatile(), start.add(2).cast::<u16>().read_volatile()) };
+            let length = usize::from(length);
+            let good = status & FRAME_OK != 0 && (HEADER_SIZE..=MAX_FRAME + CHECKSUM_SIZE).contains(&length);
+            let copied = length.saturating_sub(CHECKSUM_SIZE).min(MAX_FRAME);
+            if good {
+                unsafe { ptr::copy_nonoverlapping(start.add(HEADER_SIZE), frame.as_mut_ptr(), copied) };
+            }
+            // The next frame starts at the next 4 bytes, back at the start once past the end
+            receive.offset = (receive.offset + HEADER_SIZE + length + 3) & !3;
+            receive.offset %= RECEIVE_SIZE;
+            self.write16(READ_POINTER, receive.offset.wrapping_sub(READ_POINTER_OFFSET) as u16);
+            if good {
+                return Some(copied);
+            }
+        }
+    }
+}
+
+// Writing the interrupt status back acknowledges it, so each card's is read and written, and
+// the network task woken if any of them received a frame or had its link change
+fn handle_interrupt() {
+    let raised = CARDS.lock_irq().iter().flatten().fold(false, |raised, card| {
+        let status = card.read16(INTERRUPT_STATUS);
+        card.write16(INTERRUPT_STATUS, status);
+        status & INTERRUPTS != 0 || raised
+    });
+    if raised {
+        net::wake();
+    }
+}
+
+/// Registers the RTL8139 network cards on the PCI bus as network cards.
+pub const DRIVER: pci::Driver = pci::Driver { name: "RTL8139", matches: &[Match::Id(VENDOR, DEVICE)], probe };
+
+fn probe(function: PciDevice) -> Result<(), &'static str> {
+    let io = function.io_bar(0).ok_or("no I/O ports")?;
+    function.enable_bus_master();
+    let receive = memory::alloc_dma(RECEIVE_PAGES).ok_or("no memory for the receive buffer")?;
+    let transmit = memory::alloc_dma(TRANSMIT_PAGES).ok_or("no memory for the transmit buffers")?;
+    if (receive.physical.as_u64() + RECEIVE_PAGES * memory::PAGE_SIZE).max(transmit.physical.as_u64() + TRANSMIT_PAGES * memory::PAGE_SIZE) > DMA_LIMIT {
+        return Err("no memory below 4 GiB for the buffers");
+    }
+    let mut card = Card {
+        io,
+        mac: Mac([0; 6]),
+        line: function.interrupt_line(),
+        receive: SpinLock::new(Receive { buffer: receive, offset: 0 }),
+        transmit: SpinLock::new(Transmit { buffers: transmit, next: 0 }),
+    };
+
+    // Power on, and reset, which the card says it is done with by clearing the bit
+    card.write8(CONFIG_1, 0);
+    card.write8(COMMAND, RESET);
+    let mut polls = 0;
+    while card.read8(COMMAND) & RESET != 0 {
+        polls += 1;
+        if polls == 1_000_000 {
+            return Err("reset timed out");
+        }
+        core::hint::spin_loop();
+    }
+    card.mac = Mac(core::array::from_fn(|index| card.read8(MAC_ADDRESS + index as u16)));
+
+    card.write32(RECEIVE_BUFFER, receive.physical.as_u64() as u32);
+    for index in 0..TRANSMIT_BUFFERS as u16 {
+        card.write32(TRANSMIT_ADDRESS + 4 * index, (transmit.physical + u64::from(index) * TRANSMIT_SIZE as u64).as_u64() as u32);
+    }
+    // Every multicast group, so nothing above has to say which it wants
+    card.write32(MULTICAST, u32::MAX);
+    card.write32(MULTICAST + 4, u32::MAX);
+    card.write8(COMMAND, RECEIVE_ENABLE | TRANSMIT_ENABLE);
+    card.write32(RECEIVE_CONFIG, ACCEPT_PHYSICAL | ACCEPT_MULTICAST | ACCEPT_BROADCAST | NO_WRAP);
+    card.write16(READ_POINTER, 0u16.wrapping_sub(READ_POINTER_OFFSET as u16));
+
+    let line = card.line;
+    // The handler checks every card, so one on each line is enough
+    let sharing = CARDS.lock_irq().iter().flatten().any(|other| other.line == line);
+    let interrupt = sharing || irq::register(line, handle_interrupt);
+    let card: &'static Card = Box::leak(Box::new(card));
+    if let Some(slot) = CARDS.lock_irq().iter_mut().find(|slot| slot.is_none()) {
+        *slot = Some(card);
+    }
+    if interrupt {
+        card.write16(INTERRUPT_MASK, INTERRUPTS);
+    }
+    let name = net::register(card, interrupt).ok_or("too many network cards, not registered")?;
+    let link = if card.link_up() { "up" } else { "down" };
+    writeln!(serial(), "{name}: RTL8139, MAC {}, link {link}, IRQ {line}", card.mac).unwrap();
+    if !interrupt {
+        writeln!(serial(), "{name}: can't use IRQ {line}, polling").unwrap();
+    }
+    Ok(())
+}
@@ -1,24 +1,46 @@
 // Original code from rust-osdev/bootloader crate https://github.com/rust-osdev/bootloader
 
 use core::{fmt, ptr};
-use noto_sans_mono_bitmap::{FontWeight, get_raster, RasterizedChar};
+use core::ops::{Deref, DerefMut};
+use noto_sans_mono_bitmap::{FontWeight, get_raster, get_raster_width};
 use bootloader_api::info::{FrameBuffer, FrameBufferInfo, PixelFormat};
 use noto_sans_mono_bitmap::RasterHeight::Size16;
-use kernel::RacyCell;
-
-static WRITER: RacyCell<Option<ScreenWriter>> = RacyCell::new(None);
+use crate::assets::Font;
+use crate::sleeplock::{SleepLock, SleepLockGuard};
+
+// Drawn on by the game and input tasks, which take turns (see `main.rs`), and by the tasks of
+// user programs through `display`. Whoever has it may be preempted in the middle of a frame,
+// so it is a sleeping lock, and the others wait without spinning. Never drawn on by interrupt
+// handlers.
+static WRITER: SleepLock<Option<ScreenWriter>> = SleepLock::new(None);
 pub struct Writer;
 
 impl fmt::Write for Writer {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        let writer = unsafe { WRITER.get_mut() }.as_mut().unwrap();
-        writer.write_str(s)
+        fmt::Write::write_str(&mut *screenwriter(), s)
+    }
+}
+
+/// Exclusive access to the screen until it is dropped. Anything else drawing on the screen
+/// meanwhile, such as the `ui` functions, would wait forever.
+pub struct ScreenLock(SleepLockGuard<'static, Option<ScreenWriter>>);
+
+impl Deref for ScreenLock {
+    type Target = ScreenWriter;
+
+    fn deref(&self) -> &ScreenWriter {
+        self.0.as_ref().unwrap()
     }
 }
 
-pub fn screenwriter() -> &'static mut ScreenWriter {
-    let writer = unsafe { WRITER.get_mut() }.as_mut().unwrap();
-    writer
+impl DerefMut for ScreenLock {
+    fn deref_mut(&mut self) -> &mut ScreenWriter {
+        self.0.as_mut().unwrap()
+    }
+}
+
+pub fn screenwriter() -> ScreenLock {
+    ScreenLock(WRITER.lock())
 }
 
 
@@ -26,17 +48,27 @@ pub fn init(buffer: &'static mut FrameBuffer) {
     let info = buffer.info();
     let framebuffer = buffer.buffer_mut();
     let writer = ScreenWriter::new(framebuffer, info);
-    *unsafe { WRITER.get_mut() } = Some(writer);
+    *WRITER.lock() = Some(writer);
 }
 
 /// Additional vertical space between lines
 const LINE_SPACING: usize = 0;
 
+/// Width in pixels of every character drawn by the screen writer
+pub const CHAR_WIDTH: usize = get_raster_width(FontWeight::Regular, Size16);
+/// Height in pixels of every character drawn by the screen writer
+pub const CHAR_HEIGHT: usize = Size16 as usize;
+
+/// Drawing target handed to games when they render a frame.
+pub type Surface = ScreenWriter;
+
 pub struct ScreenWriter {
     framebuffer: &'static mut [u8],
     info: FrameBufferInfo,
     x_pos: usize,
     y_pos: usize,
+    // Drawn instead of the built-in font for the characters it has
+    font: Option<&'static Font>,
 }
 
 impl ScreenWriter {
@@ -46,6 +78,7 @@ impl ScreenWriter {
             info,
             x_pos: 0,
             y_pos: 0,
+            font: None,
         };
         logger.clear();
         logger
@@ -67,44 +100,41 @@ impl ScreenWriter {
         self.framebuffer.fill(0);
     }
 
-    fn width(&self) -> usize {
+    pub fn width(&self) -> usize {
         self.info.width.into()
     }
 
-    fn height(&self) -> usize {
+    pub fn height(&self) -> usize {
         self.info.height.into()
     }
 
+    /// Draws text in `font` from now on, falling back on the built-in font for characters it
+    /// hasn't got.
+    pub fn set_font(&mut self, font: &'static Font) {
+        self.font = Some(font);
+    }
+
     fn write_char(&mut self, c: char) {
         match c {
             '\n' => self.newline(),
             '\r' => self.carriage_return(),
             c => {
-                match get_raster(c, FontWeight::Regular, Size16) {
-                    Some(bitmap_char) => {
-                        if self.x_pos + bitmap_char.width() > self.width() {
-                            self.newline();
-                        }
-                        if self.y_pos + bitmap_char.height() > self.height() {
-                            self.clear();
-                        }
-                        self.write_rendered_char(bitmap_char);
-                    },
-                    None => {}
+                if !has_char(self.font, c) {
+                    return;
                 }
+                if self.x_pos + CHAR_WIDTH > self.width() {
+                    self.newline();
+                }
+                if self.y_pos + CHAR_HEIGHT > self.height() {
+                    self.clear();
+                }
+                let (x, y) = (self.x_pos, self.y_pos);
+                draw_char(self.font, c, |dx, dy, intensity| self.write_pixel(x + dx, y + dy, intensity));
+                self.x_pos += CHAR_WIDTH;
             }
         }
     }
 
-    fn write_rendered_char(&mut self, rendered_char: RasterizedChar) {
-        for (y, row) in rendered_char.raster().iter().enumerate() {
-            for (x, byte) in row.iter().enumerate() {
-                self.write_pixel(self.x_pos + x, self.y_pos + y, *byte);
-            }
-        }
-        self.x_pos += rendered_char.width();
-    }
-
     pub fn write_pixel(&mut self, x: usize, y: usize, intensity: u8) {
         let pixel_offset = y * usize::from(self.info.stride) + x;
         let color = match self.info.pixel_format {
@@ -143,6 +173,61 @@ impl ScreenWriter {
         let _ = unsafe { ptr::read_volatile(&self.framebuffer[byte_offset]) };
     }
 
+    /// Returns the (r, g, b) colour currently shown at (x, y).
+    pub fn read_pixel(&self, x: usize, y: usize) -> (u8, u8, u8) {
+        let pixel_offset = y * self.info.stride + x;
+        let byte_offset = pixel_offset * self.info.bytes_per_pixel;
+        let bytes = &self.framebuffer[byte_offset..];
+        match self.info.pixel_format {
+            PixelFormat::Bgr => (bytes[2], bytes[1], bytes[0]),
+            _ => (bytes[0], bytes[1], bytes[2]),
+        }
+    }
+
+    /// Mixes the colour (r, g, b) into the pixel at (x, y).
+    /// An `alpha` of 0 leaves the pixel unchanged, 255 replaces it entirely.
+    pub fn blend_pixel(&mut self, x: usize, y: usize, r: u8, g: u8, b: u8, alpha: u8) {
+        let (old_r, old_g, old_b) = self.read_pixel(x, y);
+        let mix = |new: u8, old: u8| ((new as u16 * alpha as u16 + old as u16 * (255 - alpha as u16)) / 255) as u8;
+        self.draw_pixel(x, y, mix(r, old_r), mix(g, old_g), mix(b, old_b));
+    }
+
+    /// Draws `text` in colour (r, g, b) with its top-left corner at (x, y).
+    /// Unlike the `fmt::Write` implementation this does not move the text cursor.
+    pub fn draw_text(&mut self, x: usize, y: usize, text: &str, r: u8, g: u8, b: u8) {
+        for (i, c) in text.chars().enumerate() {
+            draw_char(self.font, c, |dx, dy, intensity| {
+                if intensity > 0 {
+                    let scale = |channel: u8| (channel as u16 * intensity as u16 / 255) as u8;
+                    self.draw_pixel(x + i * CHAR_WIDTH + dx, y + dy, scale(r), scale(g), scale(b));
+                }
+            });
+        }
+    }
+
+}
+
+// Whether `font` or the built-in font has character `c`
+fn has_char(font: Option<&Font>, c: char) -> bool {
+    font.and_then(|font| font.glyph(c)).is_some() || get_raster(c, FontWeight::Regular, Size16).is_some()
+}
+
+// Calls `pixel` with the place in the character cell and the intensity of each pixel of `c`,
+// from `font` if it has `c` and from the built-in font if not
+fn draw_char(font: Option<&Font>, c: char, mut pixel: impl FnMut(usize, usize, u8)) {
+    if let Some(glyph) = font.and_then(|font| font.glyph(c)) {
+        for y in 0..CHAR_HEIGHT {
+            for x in 0..CHAR_WIDTH {
+                pixel(x, y, if glyph.pixel(x, y) { 255 } else { 0 });
+            }
+        }
+    } else if let Some(raster) = get_raster(c, FontWeight::Regular, Size16) {
+        for (y, row) in raster.raster().iter().enumerate() {
+            for (x, intensity) in row.iter().enumerate() {
+                pixel(x, y, *intensity);
+            }
+        }
+    }
 }
 
 unsafe impl Send for ScreenWriter {}
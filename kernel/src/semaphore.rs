@@ -0,0 +1,32 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::task::WaitQueue;
+
+/// A counting semaphore: `acquire` takes one of a number of permits, blocking the task while
+/// there are none left, and `release` puts one back. Releasing is safe in interrupt handlers,
+/// so a handler can hand work over to a task by releasing a permit for each item.
+pub struct Semaphore {
+    permits: AtomicUsize,
+    waiting: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Semaphore { permits: AtomicUsize::new(permits), waiting: WaitQueue::new() }
+    }
+
+    /// Takes a permit, waiting for one if there are none.
+    pub fn acquire(&self) {
+        self.waiting.wait_until(|| self.try_acquire());
+    }
+
+    /// Takes a permit if there is one, without waiting.
+    pub fn try_acquire(&self) -> bool {
+        self.permits.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |permits| permits.checked_sub(1)).is_ok()
+    }
+
+    /// Puts a permit back and wakes the tasks waiting for one.
+    pub fn release(&self) {
+        self.permits.fetch_add(1, Ordering::SeqCst);
+        self.waiting.notify();
+    }
+}
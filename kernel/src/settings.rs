@@ -0,0 +1,356 @@
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use kernel::serial;
+use kernel::sync::SpinLock;
+use pc_keyboard::layouts::{self, AnyLayout};
+use crate::{cmos, fs, workqueue};
+use crate::fs::MAX_PATH;
+use crate::ui::{Color, TextBuffer};
+
+// User settings shared by the games, the menu and the rest of the kernel. Every setting is a
+// small number picking one of its options. Whenever one changes they are saved, so they
+// survive a reboot, and whoever subscribed is told.
+//
+// They are saved to settings.cfg, a `key = value` line for each with the name of its option,
+// on the first disk that takes it, or else in /tmp; and always to spare CMOS bytes (after the
+// high scores) as well, since the disks may be images in the initrd, which don't outlast a
+// reboot. At boot the file is read if there is one, or else the CMOS copy. The file is written
+// on the worker task, so the game or menu that changed a setting doesn't wait for the disk.
+const FIRST_REGISTER: u8 = 0x50;
+const MAGIC: u8 = 0x5E;
+pub const SETTING_COUNT: usize = 7;
+const FILE_NAME: &str = "settings.cfg";
+// Room for every line of the file
+const MAX_FILE_SIZE: usize = 512;
+const MAX_SUBSCRIBERS: usize = 8;
+
+/// Everything that can be changed on the settings screen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Setting {
+    Difficulty,
+    BallSpeed,
+    PaddleSize,
+    Sound,
+    Theme,
+    Input,
+    Keymap,
+}
+
+impl Setting {
+    pub const ALL: [Setting; SETTING_COUNT] = [
+        Setting::Difficulty,
+        Setting::BallSpeed,
+        Setting::PaddleSize,
+        Setting::Sound,
+        Setting::Theme,
+        Setting::Input,
+        Setting::Keymap,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Setting::Difficulty => "Difficulty",
+            Setting::BallSpeed => "Ball speed",
+            Setting::PaddleSize => "Paddle size",
+            Setting::Sound => "Sound",
+            Setting::Theme => "Theme",
+            Setting::Input => "Input",
+            Setting::Keymap => "Keyboard layout",
+        }
+    }
+
+    // Its key in settings.cfg
+    fn key(self) -> &'static str {
+        match self {
+            Setting::Difficulty => "difficulty",
+            Setting::BallSpeed => "ball_speed",
+            Setting::PaddleSize => "paddle_size",
+            Setting::Sound => "sound",
+            Setting::Theme => "theme",
+            Setting::Input => "input",
+            Setting::Keymap => "keymap",
+        }
+    }
+
+    fn options(self) -> &'static [&'static str] {
+        match self {
+            Setting::Difficulty => &["Easy", "Normal", "Hard"],
+            Setting::BallSpeed => &["Slow", "Normal", "Fast"],
+            Setting::PaddleSize => &["Small", "Normal", "Large"],
+            Setting::Sound => &["Off", "On"],
+            Setting::Theme => &["Classic", "Green", "Amber"],
+            // The keyboard keeps working either way, so nobody can lock themselves out
+            Setting::Input => &["Keyboard", "Keyboard + serial"],
+            Setting::Keymap => &["US", "UK", "German", "French", "Dvorak"],
+        }
+    }
+
+    const fn default_value(self) -> u8 {
+        // Normal for the scales, sound on, the classic theme, keyboard only and US keys
+        match self {
+            Setting::Theme | Setting::Input | Setting::Keymap => 0,
+            _ => 1,
+        }
+    }
+}
+
+static VALUES: [AtomicU8; SETTING_COUNT] = [
+    AtomicU8::new(Setting::Difficulty.default_value()),
+    AtomicU8::new(Setting::BallSpeed.default_value()),
+    AtomicU8::new(Setting::PaddleSize.default_value()),
+    AtomicU8::new(Setting::Sound.default_value()),
+    AtomicU8::new(Setting::Theme.default_value()),
+    AtomicU8::new(Setting::Input.default_value()),
+    AtomicU8::new(Setting::Keymap.default_value()),
+];
+
+/// Told which setting changed, see `subscribe`.
+pub type Subscriber = fn(Setting);
+
+static SUBSCRIBERS: SpinLock<[Option<Subscriber>; MAX_SUBSCRIBERS]> = SpinLock::new([None; MAX_SUBSCRIBERS]);
+// Whether a write of settings.cfg is queued for the worker task
+static FILE_QUEUED: AtomicBool = AtomicBool::new(false);
+
+/// The value of every setting at one point in time, see `snapshot`.
+pub type Snapshot = [u8; SETTING_COUNT];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Difficulty {
+    Easy,
+    Normal,
+    Hard,
+}
+
+impl Difficulty {
+    pub fn name(self) -> &'static str {
+        match self {
+            Difficulty::Easy => "Easy",
+            Difficulty::Normal => "Normal",
+            Difficulty::Hard => "Hard",
+        }
+    }
+
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Difficulty::Easy,
+            2 => Difficulty::Hard,
+            _ => Difficulty::Normal,
+        }
+    }
+}
+
+/// Foreground and highlight colours used by pong and the menus.
+#[derive(Clone, Copy)]
+pub struct Theme {
+    pub foreground: Color,
+    pub accent: Color,
+}
+
+const THEMES: [Theme; 3] = [
+    Theme { foreground: (255, 255, 255), accent: (255, 255, 0) },
+    Theme { foreground: (64, 255, 96), accent: (200, 255, 200) },
+    Theme { foreground: (255, 176, 0), accent: (255, 230, 160) },
+];
+
+/// Loads the saved settings, from settings.cfg if there is one or else from CMOS. If they are
+/// missing or corrupted, the defaults are saved instead. Called once during boot, after the
+/// filesystems are mounted.
+pub fn init() {
+    let mut data = [0u8; SETTING_COUNT];
+    if let Some(path) = read_file() {
+        writeln!(serial(), "Loaded settings from {}", path.as_str()).unwrap();
+    } else if cmos::read_block(FIRST_REGISTER, MAGIC, &mut data) {
+        restore(data);
+        writeln!(serial(), "Loaded settings from CMOS").unwrap();
+    } else {
+        writeln!(serial(), "Settings missing or corrupted, using the defaults").unwrap();
+    }
+    // So both copies agree, and there is a file to look at
+    save();
+}
+
+/// Saves the current settings: to CMOS at once, and to settings.cfg soon after.
+pub fn save() {
+    cmos::write_block(FIRST_REGISTER, MAGIC, &snapshot());
+    // A write already queued saves whatever the settings are when it runs
+    if !FILE_QUEUED.swap(true, Ordering::SeqCst) && !workqueue::queue(save_file) {
+        save_file();
+    }
+}
+
+fn save_file() {
+    FILE_QUEUED.store(false, Ordering::SeqCst);
+    let mut text = TextBuffer::<MAX_FILE_SIZE>::new();
+    for setting in Setting::ALL {
+        writeln!(text, "{} = {}", setting.key(), value_name(setting)).unwrap();
+    }
+    if !locations().any(|directory| write_file(directory, text.as_str())) {
+        writeln!(serial(), "Couldn't save {FILE_NAME} anywhere").unwrap();
+    }
+}
+
+/// Calls `subscriber` with the setting whenever one changes. Returns false if there are too
+/// many subscribers already.
+pub fn subscribe(subscriber: Subscriber) -> bool {
+    let mut subscribers = SUBSCRIBERS.lock_irq();
+    let Some(slot) = subscribers.iter_mut().find(|slot| slot.is_none()) else {
+        return false;
+    };
+    *slot = Some(subscriber);
+    true
+}
+
+// The directories settings.cfg is kept in, in the order they are tried: the disks, then /tmp
+fn locations() -> impl Iterator<Item = &'static str> {
+    let disks = fs::mounts().into_iter().flatten().map(|(path, _)| path).filter(|path| path.starts_with("/disk"));
+    disks.chain(["/tmp"])
+}
+
+fn file_path(directory: &str) -> TextBuffer<MAX_PATH> {
+    let mut path = TextBuffer::new();
+    write!(path, "{directory}/{FILE_NAME}").unwrap();
+    path
+}
+
+// Takes the settings from the first settings.cfg there is, and returns where it was. Lines
+// that aren't a setting and one of its options are skipped, leaving that setting as it was.
+fn read_file() -> Option<TextBuffer<MAX_PATH>> {
+    let (path, mut file) = locations().find_map(|directory| {
+        let path = file_path(directory);
+        let file = fs::File::open(path.as_str()).ok()?;
+        Some((path, file))
+    })?;
+    let mut bytes = [0; MAX_FILE_SIZE];
+    let mut length = 0;
+    while let Ok(read @ 1..) = file.read(&mut bytes[length..]) {
+        length += read;
+    }
+    let mut values = snapshot();
+    let text = core::str::from_utf8(&bytes[..length]).unwrap_or("");
+    for (key, value) in text.lines().filter_map(|line| line.split_once('=')) {
+        let Some(setting) = Setting::ALL.into_iter().find(|setting| setting.key() == key.trim()) else {
+            continue;
+        };
+        if let Some(option) = find_option(setting, value.trim()) {
+            values[setting as usize] = option;
+        }
+    }
+    restore(values);
+    Some(path)
+}
+
+fn write_file(directory: &str, text: &str) -> bool {
+    let Ok(mut file) = fs::File::create(file_path(directory).as_str()) else {
+        return false;
+    };
+    let mut bytes = text.as_bytes();
+    while !bytes.is_empty() {
+        match file.write(bytes) {
+            Ok(written @ 1..) => bytes = &bytes[written..],
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// Returns the selected option of `setting`, as an index into its options.
+pub fn get(setting: Setting) -> u8 {
+    VALUES[setting as usize].load(Ordering::SeqCst)
+}
+
+/// Returns the name of the selected option of `setting`.
+pub fn value_name(setting: Setting) -> &'static str {
+    setting.options()[get(setting) as usize]
+}
+
+/// Selects the option `delta` places after the current one, wrapping around, and saves.
+pub fn cycle(setting: Setting, delta: isize) {
+    let count = setting.options().len() as isize;
+    let value = (get(setting) as isize + delta).rem_euclid(count);
+    set(setting, value as u8);
+}
+
+/// Returns the index of the option of `setting` called `name`, in any case.
+pub fn find_option(setting: Setting, name: &str) -> Option<u8> {
+    setting.options().iter().position(|option| option.eq_ignore_ascii_case(name)).map(|index| index as u8)
+}
+
+/// Selects option `value` of `setting` without saving it, for `init` to keep if no settings
+/// were saved. Values out of range are ignored.
+pub fn set_default(setting: Setting, value: u8) {
+    if (value as usize) < setting.options().len() {
+        store(setting, value);
+    }
+}
+
+fn set(setting: Setting, value: u8) {
+    store(setting, value);
+    save();
+}
+
+// Selects option `value` of `setting`, and tells the subscribers if that is a change
+fn store(setting: Setting, value: u8) {
+    if VALUES[setting as usize].swap(value, Ordering::SeqCst) != value {
+        let subscribers = *SUBSCRIBERS.lock_irq();
+        for subscriber in subscribers.into_iter().flatten() {
+            subscriber(setting);
+        }
+    }
+}
+
+/// Returns the current value of every setting.
+pub fn snapshot() -> Snapshot {
+    Setting::ALL.map(get)
+}
+
+/// Sets every setting to the values in `snapshot` without saving them. Values out of range
+/// fall back to the defaults.
+pub fn restore(snapshot: Snapshot) {
+    for (setting, value) in Setting::ALL.into_iter().zip(snapshot) {
+        let value = if (value as usize) < setting.options().len() { value } else { setting.default_value() };
+        store(setting, value);
+    }
+}
+
+pub fn difficulty() -> Difficulty {
+    Difficulty::from_u8(get(Setting::Difficulty))
+}
+
+pub fn set_difficulty(difficulty: Difficulty) {
+    set(Setting::Difficulty, difficulty as u8);
+}
+
+/// Ball speed as a percentage of the normal speed.
+pub fn ball_speed_percent() -> i32 {
+    [75, 100, 130][get(Setting::BallSpeed) as usize]
+}
+
+/// Paddle height as a percentage of the height the difficulty asks for.
+pub fn paddle_size_percent() -> i32 {
+    [75, 100, 125][get(Setting::PaddleSize) as usize]
+}
+
+pub fn sound_enabled() -> bool {
+    get(Setting::Sound) != 0
+}
+
+pub fn theme() -> Theme {
+    THEMES[get(Setting::Theme) as usize]
+}
+
+/// Returns true if keys typed on the serial console should work like keyboard input.
+pub fn serial_input() -> bool {
+    get(Setting::Input) != 0
+}
+
+/// The keyboard layout keys are decoded with.
+pub fn keyboard_layout() -> AnyLayout {
+    match get(Setting::Keymap) {
+        1 => AnyLayout::Uk105Key(layouts::Uk105Key),
+        2 => AnyLayout::De105Key(layouts::De105Key),
+        3 => AnyLayout::Azerty(layouts::Azerty),
+        4 => AnyLayout::Dvorak104Key(layouts::Dvorak104Key),
+        _ => AnyLayout::Us104Key(layouts::Us104Key),
+    }
+}
@@ -0,0 +1,90 @@
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use kernel::serial;
+use pc_keyboard::{DecodedKey, KeyCode};
+use crate::{game, menu, replay};
+use crate::screen::{screenwriter, CHAR_HEIGHT};
+use crate::settings::{self, Setting, SETTING_COUNT};
+use crate::ui::{self, ListView, TextBuffer, GREY};
+
+// The settings screen lists every setting with its current value. It opens from the menu, or
+// with F2 over a running game, which stays frozen until the screen closes and then carries on
+// with the new settings.
+static OPEN: AtomicBool = AtomicBool::new(false);
+static OVER_GAME: AtomicBool = AtomicBool::new(false);
+static SELECTED: AtomicUsize = AtomicUsize::new(0);
+
+const TITLE: &str = "Settings";
+const BACK_ENTRY: usize = SETTING_COUNT;
+const ENTRY_COUNT: usize = SETTING_COUNT + 1;
+
+/// Shows the settings screen, freezing the running game if there is one.
+pub fn open() {
+    let over_game = game::is_running();
+    if over_game {
+        game::suspend();
+    }
+    OVER_GAME.store(over_game, Ordering::SeqCst);
+    OPEN.store(true, Ordering::SeqCst);
+    SELECTED.store(0, Ordering::SeqCst);
+    draw();
+}
+
+/// Returns true while the settings screen owns the screen and keyboard.
+pub fn is_open() -> bool {
+    OPEN.load(Ordering::SeqCst)
+}
+
+fn close() {
+    OPEN.store(false, Ordering::SeqCst);
+    if OVER_GAME.load(Ordering::SeqCst) {
+        game::resume();
+    } else {
+        menu::show();
+    }
+}
+
+fn draw() {
+    let mut labels = [const { TextBuffer::<40>::new() }; SETTING_COUNT];
+    for (label, setting) in labels.iter_mut().zip(Setting::ALL) {
+        write!(label, "{}: {}", setting.name(), settings::value_name(setting)).unwrap();
+    }
+    let mut items = [""; ENTRY_COUNT];
+    for (item, label) in items.iter_mut().zip(&labels) {
+        *item = label.as_str();
+    }
+    items[BACK_ENTRY] = "Back";
+
+    // The list changes width with the values, so start from a blank screen every time
+    screenwriter().clear();
+    ListView { title: TITLE, items: &items, selected: SELECTED.load(Ordering::SeqCst) }.draw_centered();
+    let help_y = screenwriter().height() - 2 * CHAR_HEIGHT;
+    ui::draw_label_centered(help_y, "Up/Down: choose   Left/Right: change   Esc: back", GREY);
+}
+
+fn change(setting: Setting, delta: isize) {
+    settings::cycle(setting, delta);
+    replay::record_settings();
+    writeln!(serial(), "{} set to {}", setting.name(), settings::value_name(setting)).unwrap();
+    draw();
+}
+
+/// Handles a key press while the settings screen is showing.
+pub fn handle_key(key: DecodedKey) {
+    let selected = SELECTED.load(Ordering::SeqCst);
+    match key {
+        DecodedKey::RawKey(KeyCode::ArrowUp) => {
+            SELECTED.store(ui::move_selection(selected, ENTRY_COUNT, -1), Ordering::SeqCst);
+            draw();
+        },
+        DecodedKey::RawKey(KeyCode::ArrowDown) => {
+            SELECTED.store(ui::move_selection(selected, ENTRY_COUNT, 1), Ordering::SeqCst);
+            draw();
+        },
+        DecodedKey::RawKey(KeyCode::ArrowLeft) if selected != BACK_ENTRY => change(Setting::ALL[selected], -1),
+        DecodedKey::RawKey(KeyCode::ArrowRight) if selected != BACK_ENTRY => change(Setting::ALL[selected], 1),
+        DecodedKey::Unicode('\n') if selected != BACK_ENTRY => change(Setting::ALL[selected], 1),
+        DecodedKey::Unicode('\n') | DecodedKey::Unicode('\u{1b}') => close(),
+        _ => {}
+    }
+}
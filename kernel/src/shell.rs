@@ -0,0 +1,566 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+use kernel::serial;
+use kernel::sync::SpinLock;
+use spin::Mutex;
+use x86_64::VirtAddr;
+use crate::{arp, block, cache, dhcp, dns, fs, icmp, kthread, net, pci, pipe, power, process, task, time, timer, workqueue};
+use crate::block::{BLOCK_SIZE, BlockDevice, Completion, Operation, Request};
+use crate::memory::{self, PAGE_SIZE};
+use crate::process::Handle;
+use crate::ui::TextBuffer;
+use crate::usermode::Error;
+
+// A command line on the serial console, for looking inside the running kernel. It gets the
+// bytes typed on the console while the input setting doesn't send them to the games, and
+// prints everything back on the console.
+const MAX_LINE: usize = 80;
+const PROMPT: &str = "> ";
+
+struct Line {
+    bytes: [u8; MAX_LINE],
+    length: usize,
+}
+
+// Only the async task types into the shell
+static LINE: Mutex<Line> = Mutex::new(Line { bytes: [0; MAX_LINE], length: 0 });
+// Where relative paths in commands are from, and the programs run start in
+static DIRECTORY: Mutex<fs::WorkingDirectory> = Mutex::new(fs::WorkingDirectory::root());
+// The sector `dump` reads into, allocated by the first dump, and whether a dump has it. Like
+// the copy's state below, it is reached from completions, which may run in interrupt handlers.
+static SECTOR: SpinLock<Option<&'static mut [u8]>> = SpinLock::new(None);
+static DUMPING: AtomicBool = AtomicBool::new(false);
+// The copy under way: the disk copied, the one copied onto, and the page the sectors go
+// through, which is kept for the next copy
+#[derive(Clone, Copy)]
+struct DiskCopy {
+    from: &'static dyn BlockDevice,
+    to: &'static dyn BlockDevice,
+    page: VirtAddr,
+}
+
+static COPYING: SpinLock<Option<DiskCopy>> = SpinLock::new(None);
+static COPY_PAGE: Mutex<Option<VirtAddr>> = Mutex::new(None);
+// How many sectors `copy` moves in each request: what the page it has holds
+const COPY_SECTORS: usize = PAGE_SIZE as usize / BLOCK_SIZE;
+
+struct Command {
+    name: &'static str,
+    description: &'static str,
+    // Gets the rest of the line
+    run: fn(&str),
+}
+
+const COMMANDS: &[Command] = &[
+    Command { name: "help", description: "lists the commands", run: help },
+    Command { name: "ps", description: "lists the tasks, their state and the stack and CPU time they have used", run: ps },
+    Command { name: "kill", description: "kill <id> ends a task the next time it waits or yields", run: kill },
+    Command { name: "pin", description: "pin <id> <cpu>... lets a task only run on those CPUs, such as the game away from a busy one", run: pin },
+    Command { name: "run", description: "run <program> [arguments] starts a user program in a new process; | chains programs", run: run_program },
+    Command { name: "procs", description: "lists the processes and how the ended ones ended", run: procs },
+    Command { name: "proc", description: "proc <pid> shows a process's state, handles and memory", run: inspect_process },
+    Command { name: "ls", description: "ls [path] lists a directory, the current one if none is given, with the sizes of its files", run: list },
+    Command { name: "cd", description: "cd [path] moves to a directory, the root if none is given", run: change_directory },
+    Command { name: "pwd", description: "prints the current directory", run: print_directory },
+    Command { name: "mounts", description: "lists the mounted filesystems", run: mounts },
+    Command { name: "disks", description: "lists the block devices and their sizes", run: disks },
+    Command { name: "mount", description: "mount <disk> <path> mounts the filesystem on a block device", run: mount_disk },
+    Command { name: "dump", description: "dump <disk> <sector> prints a sector in hex, read on the block I/O task", run: dump },
+    Command { name: "copy", description: "copy <disk> <disk> copies a disk onto another, which isn't mounted, on the block I/O task", run: copy_disk },
+    Command { name: "sync", description: "writes the block cache's dirty sectors back and flushes the disks", run: sync },
+    Command { name: "shutdown", description: "writes the cached sectors back and turns the machine off", run: shutdown },
+    Command { name: "reboot", description: "writes the cached sectors back and restarts the machine", run: reboot },
+    Command { name: "lspci", description: "lists the PCI functions, what each is and where its registers are", run: lspci },
+    Command { name: "ifconfig", description: "lists the network cards, their MAC and IPv4 addresses, where those came from, links and frame counts", run: ifconfig },
+    Command { name: "arp", description: "lists the cached ARP entries and how long each has left", run: arp_cache },
+    Command { name: "ping", description: "ping <host> sends four ICMP echo requests and prints the replies", run: ping },
+    Command { name: "host", description: "host <name> looks up a name's IPv4 address with DNS", run: host },
+    Command { name: "cat", description: "cat <path> prints a file", run: cat },
+    Command { name: "write", description: "write <path> <text> writes a line to a file, replacing what was in it", run: write_file },
+    Command { name: "append", description: "append <path> <text> adds a line to the end of a file", run: append_file },
+    Command { name: "mkdir", description: "mkdir <path> makes a directory", run: make_directory },
+    Command { name: "rm", description: "rm <path> removes a file, a link or an empty directory", run: remove },
+];
+
+/// Handles a byte typed on the serial console, or the remote one: echoes it, and runs the
+/// command once Enter is pressed.
+pub fn handle_byte(byte: u8) {
+    let mut line = LINE.lock();
+    match byte {
+        b'\r' | b'\n' => {
+            writeln!(serial()).unwrap();
+            let length = line.length;
+            line.length = 0;
+            // The command may take a while, and nothing else can type meanwhile anyway
+            let bytes = line.bytes;
+            drop(line);
+            if let Ok(command) = core::str::from_utf8(&bytes[..length]) {
+                run(command.trim());
+            }
+            show_prompt();
+        },
+        // Backspace and delete
+        0x08 | 0x7f if line.length > 0 => {
+            line.length -= 1;
+            write!(serial(), "\u{8} \u{8}").unwrap();
+        },
+        b' '..=b'~' if line.length < MAX_LINE => {
+            let length = line.length;
+            line.bytes[length] = byte;
+            line.length += 1;
+            serial().send(byte);
+        },
+        _ => {},
+    }
+}
+
+/// Prints the prompt, for a console that has just started showing what the shell prints.
+pub fn show_prompt() {
+    write!(serial(), "{}{PROMPT}", DIRECTORY.lock().path()).unwrap();
+}
+
+fn run(command: &str) {
+    if command.is_empty() {
+        return;
+    }
+    let (name, arguments) = command.split_once(' ').unwrap_or((command, ""));
+    match COMMANDS.iter().find(|command| command.name == name) {
+        Some(command) => (command.run)(arguments.trim()),
+        None => writeln!(serial(), "Unknown command {name}; try help").unwrap(),
+    }
+}
+
+fn help(_arguments: &str) {
+    for command in COMMANDS {
+        writeln!(serial(), "{:<8} {}", command.name, command.description).unwrap();
+    }
+}
+
+fn ps(_arguments: &str) {
+    task::dump();
+}
+
+fn kill(arguments: &str) {
+    match arguments.parse() {
+        Ok(id) if task::kill(id) => {},
+        Ok(id) => writeln!(serial(), "No task {id} that can be killed").unwrap(),
+        Err(_) => writeln!(serial(), "Usage: kill <id>, with an id from ps").unwrap(),
+    }
+}
+
+fn pin(arguments: &str) {
+    let mut words = arguments.split_whitespace();
+    let id = words.next().and_then(|id| id.parse::<usize>().ok());
+    let cpus = words.try_fold(0u32, |cpus, cpu| cpu.parse::<u32>().ok().filter(|&cpu| cpu < u32::BITS).map(|cpu| cpus | 1 << cpu));
+    match (id, cpus) {
+        (Some(id), Some(cpus @ 1..)) if task::set_affinity(id, cpus) => {},
+        (Some(id), Some(1..)) => writeln!(serial(), "Task {id} can't run on those CPUs, or there is no such task").unwrap(),
+        _ => writeln!(serial(), "Usage: pin <id> <cpu>..., with an id from ps").unwrap(),
+    }
+}
+
+// Starts each program of a pipeline, with a pipe from each one's output to the next one's input
+fn run_program(arguments: &str) {
+    let stages: Vec<&str> = arguments.split('|').map(str::trim).collect();
+    if stages.iter().any(|stage| stage.is_empty()) {
+        writeln!(serial(), "Usage: run <program> [arguments] [| <program> [arguments]]...").unwrap();
+        return;
+    }
+    let mut input = Handle::ConsoleInput;
+    for (index, stage) in stages.iter().enumerate() {
+        let output = if index + 1 == stages.len() {
+            Handle::ConsoleOutput
+        } else if let Some(id) = pipe::create() {
+            Handle::PipeWrite(id)
+        } else {
+            writeln!(serial(), "Too many pipes").unwrap();
+            process::close_handle(input);
+            return;
+        };
+        let mut words = stage.split_whitespace();
+        let path = words.next().unwrap();
+        let arguments: Vec<&str> = words.collect();
+        match process::spawn(path, &arguments, [input, output], &DIRECTORY.lock()) {
+            Ok(pid) => writeln!(serial(), "Started {path} as process {pid}").unwrap(),
+            Err(Error::NoSuchProgram) => writeln!(serial(), "No program called {path}").unwrap(),
+            Err(error) => writeln!(serial(), "Can't start {path}: {error:?}").unwrap(),
+        }
+        // The next program reads what this one writes, or nothing if it didn't start
+        if let Handle::PipeWrite(id) = output {
+            input = Handle::PipeRead(id);
+        }
+    }
+}
+
+fn procs(_arguments: &str) {
+    process::dump();
+}
+
+fn inspect_process(arguments: &str) {
+    match arguments.parse() {
+        Ok(pid) if process::inspect(pid) => {},
+        Ok(pid) => writeln!(serial(), "No process {pid}").unwrap(),
+        Err(_) => writeln!(serial(), "Usage: proc <pid>, with a pid from procs").unwrap(),
+    }
+}
+
+fn list(arguments: &str) {
+    let path = if arguments.is_empty() { "." } else { arguments };
+    let listed = absolute(path).and_then(|absolute| Ok((fs::lookup(absolute.as_str())?, fs::read_dir(absolute.as_str())?)));
+    let (directory, entries) = match listed {
+        Ok(listed) => listed,
+        Err(error) => {
+            writeln!(serial(), "Can't list {path}: {error:?}").unwrap();
+            return;
+        },
+    };
+    for entry in entries {
+        match entry {
+            Ok(entry) if entry.kind == fs::Kind::Directory => writeln!(serial(), "{:>8}  {}/", "", entry.name.as_str()).unwrap(),
+            Ok(entry) if entry.kind == fs::Kind::Symlink => {
+                let mut target = [0; 256];
+                let length = directory.child(entry.name.as_str()).and_then(|link| link.read_at(0, &mut target)).unwrap_or(0);
+                let target = core::str::from_utf8(&target[..length]).unwrap_or("?");
+                writeln!(serial(), "{:>8}  {} -> {target}", "", entry.name.as_str()).unwrap();
+            },
+            Ok(entry) => {
+                let size = directory.child(entry.name.as_str()).and_then(|file| file.metadata()).map_or(0, |metadata| metadata.size);
+                writeln!(serial(), "{size:>8}  {}", entry.name.as_str()).unwrap();
+            },
+            Err(error) => writeln!(serial(), "Can't list {path}: {error:?}").unwrap(),
+        }
+    }
+}
+
+fn change_directory(arguments: &str) {
+    let path = if arguments.is_empty() { "/" } else { arguments };
+    if let Err(error) = DIRECTORY.lock().change(path) {
+        writeln!(serial(), "Can't move to {path}: {error:?}").unwrap();
+    }
+}
+
+fn print_directory(_arguments: &str) {
+    writeln!(serial(), "{}", DIRECTORY.lock().path()).unwrap();
+}
+
+fn mounts(_arguments: &str) {
+    for (path, filesystem) in fs::mounts().into_iter().flatten() {
+        writeln!(serial(), "{path:<12} {filesystem}").unwrap();
+    }
+}
+
+fn disks(_arguments: &str) {
+    for device in block::devices() {
+        let size = device.capacity();
+        writeln!(serial(), "{:<12} {:>10} sectors of {:>4} bytes {:>8} MiB", device.name(), device.blocks(), device.sector_size(), size >> 20).unwrap();
+    }
+}
+
+fn lspci(_arguments: &str) {
+    pci::list(&mut serial()).unwrap();
+}
+
+fn ifconfig(_arguments: &str) {
+    for interface in net::interfaces() {
+        let link = if interface.device.link_up() { "up" } else { "down" };
+        let (received, sent) = interface.frames();
+        write!(serial(), "{:<6} {} link {:<4} {received} frames received, {sent} sent", interface.name, interface.device.mac(), link).unwrap();
+        match interface.ipv4() {
+            Some(address) => match dhcp::lease().filter(|lease| lease.interface == interface.name) {
+                Some(lease) => writeln!(serial(), ", address {address} from DHCP server {}", lease.server).unwrap(),
+                None => writeln!(serial(), ", address {address}").unwrap(),
+            },
+            None => writeln!(serial(), ", no address").unwrap(),
+        }
+    }
+    if let Some(server) = dns::server() {
+        writeln!(serial(), "DNS server {server}").unwrap();
+    }
+}
+
+fn ping(arguments: &str) {
+    let Some(name) = host_name(arguments) else {
+        writeln!(serial(), "Usage: ping <host>").unwrap();
+        return;
+    };
+    // On a thread of its own, as it waits for DNS, ARP and the replies
+    let pinged = kthread::spawn(move || match dns::resolve(name.as_str()) {
+        Ok(address) => icmp::ping(address, 4),
+        Err(error) => writeln!(serial(), "ping {}: {error:?}", name.as_str()).unwrap(),
+    });
+    if pinged.is_none() {
+        writeln!(serial(), "No task free to ping with").unwrap();
+    }
+}
+
+fn host(arguments: &str) {
+    let Some(name) = host_name(arguments) else {
+        writeln!(serial(), "Usage: host <name>").unwrap();
+        return;
+    };
+    let looked_up = kthread::spawn(move || match dns::resolve(name.as_str()) {
+        Ok(address) => writeln!(serial(), "{} has address {address}", name.as_str()).unwrap(),
+        Err(error) => writeln!(serial(), "{}: {error:?}", name.as_str()).unwrap(),
+    });
+    if looked_up.is_none() {
+        writeln!(serial(), "No task free to look the name up with").unwrap();
+    }
+}
+
+// A host name or address from a command's arguments, copied to go to another thread
+fn host_name(arguments: &str) -> Option<TextBuffer<{ dns::MAX_NAME }>> {
+    let name = arguments.trim();
+    if name.is_empty() || name.len() > dns::MAX_NAME {
+        return None;
+    }
+    let mut buffer = TextBuffer::new();
+    buffer.write_str(name).unwrap();
+    Some(buffer)
+}
+
+fn arp_cache(_arguments: &str) {
+    let now = timer::now();
+    let ticks_per_second = time::ms_to_ticks(1000).max(1);
+    for entry in arp::entries() {
+        let left = entry.expires.saturating_sub(now) / ticks_per_second;
+        writeln!(serial(), "{:<15} {} on {:<6} {left:>3} s left", entry.address, entry.mac, entry.interface).unwrap();
+    }
+}
+
+fn sync(_arguments: &str) {
+    if let Err(error) = cache::flush_all() {
+        writeln!(serial(), "Can't sync: {error:?}").unwrap();
+    }
+}
+
+fn shutdown(_arguments: &str) {
+    power::shutdown();
+}
+
+fn reboot(_arguments: &str) {
+    power::reboot();
+}
+
+fn dump(arguments: &str) {
+    let (name, sector) = arguments.split_once(' ').unwrap_or((arguments, ""));
+    let (Some(device), Ok(sector)) = (block::find(name), sector.trim().parse::<u64>()) else {
+        writeln!(serial(), "Usage: dump <disk> <sector>").unwrap();
+        return;
+    };
+    if DUMPING.swap(true, Ordering::SeqCst) {
+        writeln!(serial(), "A dump is under way already").unwrap();
+        return;
+    }
+    // Allocated once and handed back by every dump, as the heap never gives memory back
+    let buffer = SECTOR.lock_irq().take().unwrap_or_else(|| Box::leak(Box::new([0; BLOCK_SIZE])));
+    let request = Request { device, operation: Operation::Read, block: sector, buffer, tag: sector as usize };
+    if let Err(request) = block::submit(request, sector_read) {
+        writeln!(serial(), "Too many block requests queued").unwrap();
+        *SECTOR.lock_irq() = Some(request.buffer);
+        DUMPING.store(false, Ordering::SeqCst);
+    }
+}
+
+// Has the worker task print the sector `dump` read, as that is slow for an interrupt handler.
+// The buffer is put back first, so it isn't lost if too much work is queued.
+fn sector_read(completion: Completion) {
+    let Completion { buffer, tag, result } = completion;
+    *SECTOR.lock_irq() = Some(buffer);
+    if !workqueue::queue(move || print_sector(tag, result)) {
+        DUMPING.store(false, Ordering::SeqCst);
+    }
+}
+
+// Prints the sector `dump` read, sixteen bytes a line
+fn print_sector(sector: usize, result: Result<(), block::Error>) {
+    // Taken out while it is printed, so interrupts aren't held off meanwhile
+    let buffer = SECTOR.lock_irq().take().unwrap();
+    match result {
+        Ok(()) => {
+            writeln!(serial(), "Sector {sector}:").unwrap();
+            for (line, bytes) in buffer.chunks(16).enumerate() {
+                let mut text = TextBuffer::<80>::new();
+                write!(text, "{:04x}:", line * 16).unwrap();
+                bytes.iter().for_each(|byte| write!(text, " {byte:02x}").unwrap());
+                writeln!(serial(), "{}", text.as_str()).unwrap();
+            }
+        },
+        Err(error) => writeln!(serial(), "Can't read sector {sector}: {error:?}").unwrap(),
+    }
+    *SECTOR.lock_irq() = Some(buffer);
+    DUMPING.store(false, Ordering::SeqCst);
+}
+
+fn copy_disk(arguments: &str) {
+    let (from, to) = arguments.split_once(' ').unwrap_or((arguments, ""));
+    let (Some(from), Some(to)) = (block::find(from), block::find(to.trim())) else {
+        writeln!(serial(), "Usage: copy <disk> <disk>").unwrap();
+        return;
+    };
+    if to.blocks() < from.blocks() || to.name() == from.name() {
+        writeln!(serial(), "{} can't hold a copy of {}", to.name(), from.name()).unwrap();
+        return;
+    }
+    let mut copying = COPYING.lock_irq();
+    if copying.is_some() {
+        writeln!(serial(), "A copy is under way already").unwrap();
+        return;
+    }
+    // The page is kept for the next copy, as pages are scarcer than copies
+    let Some(page) = COPY_PAGE.lock().or_else(|| memory::alloc_guarded_pages(1)) else {
+        writeln!(serial(), "No memory to copy with").unwrap();
+        return;
+    };
+    *COPY_PAGE.lock() = Some(page);
+    // Claimed, so nothing is mounted from it while it is being written over
+    if !block::claim(to.name()) {
+        writeln!(serial(), "{} is in use", to.name()).unwrap();
+        return;
+    }
+    *copying = Some(DiskCopy { from, to, page });
+    drop(copying);
+    writeln!(serial(), "Copying {} onto {}", from.name(), to.name()).unwrap();
+    copy_next(0);
+}
+
+// Reads the chunk of the disk being copied from `sector` on, or flushes the one written once
+// there are no more; each request's completion submits the next
+fn copy_next(sector: u64) {
+    let Some(DiskCopy { from, to, page }) = *COPYING.lock_irq() else {
+        return;
+    };
+    let buffer = unsafe { core::slice::from_raw_parts_mut(page.as_mut_ptr(), PAGE_SIZE as usize) };
+    let submitted = match from.blocks() - sector {
+        0 => block::submit(Request { device: to, operation: Operation::Flush, block: 0, buffer, tag: 0 }, copy_done),
+        left => {
+            let buffer = &mut buffer[..left.min(COPY_SECTORS as u64) as usize * BLOCK_SIZE];
+            block::submit(Request { device: from, operation: Operation::Read, block: sector, buffer, tag: sector as usize }, copy_read)
+        },
+    };
+    if submitted.is_err() {
+        finish_copy(Err(block::Error::Io));
+    }
+}
+
+// Writes the chunk just read to the same place on the other disk
+fn copy_read(completion: Completion) {
+    let Some(DiskCopy { to, .. }) = *COPYING.lock_irq() else {
+        return;
+    };
+    let Completion { buffer, tag, result } = completion;
+    let submitted = result.and_then(|()| {
+        let request = Request { device: to, operation: Operation::Write, block: tag as u64, buffer, tag };
+        block::submit(request, copy_written).map_err(|_| block::Error::Io)
+    });
+    if let Err(error) = submitted {
+        finish_copy(Err(error));
+    }
+}
+
+fn copy_written(completion: Completion) {
+    match completion.result {
+        Ok(()) => copy_next(completion.tag as u64 + (completion.buffer.len() / BLOCK_SIZE) as u64),
+        Err(error) => finish_copy(Err(error)),
+    }
+}
+
+fn copy_done(completion: Completion) {
+    finish_copy(completion.result);
+}
+
+fn finish_copy(result: Result<(), block::Error>) {
+    let Some(DiskCopy { from, to, .. }) = COPYING.lock_irq().take() else {
+        return;
+    };
+    block::release(to.name());
+    match result {
+        Ok(()) => writeln!(serial(), "Copied {} onto {}", from.name(), to.name()).unwrap(),
+        Err(error) => writeln!(serial(), "Copying {} onto {} failed: {error:?}", from.name(), to.name()).unwrap(),
+    }
+}
+
+fn mount_disk(arguments: &str) {
+    let Some((name, path)) = arguments.split_once(' ') else {
+        writeln!(serial(), "Usage: mount <disk> <path>").unwrap();
+        return;
+    };
+    let Some(device) = block::find(name) else {
+        writeln!(serial(), "No disk called {name}").unwrap();
+        return;
+    };
+    let mounted = absolute(path.trim()).and_then(|path| {
+        // Nothing is ever unmounted, so the path may as well stay allocated
+        fs::mount_device(String::from(path.as_str()).leak(), device)
+    });
+    match mounted {
+        Ok(filesystem) => writeln!(serial(), "{filesystem} on {name} mounted").unwrap(),
+        Err(error) => writeln!(serial(), "Can't mount {name}: {error:?}").unwrap(),
+    }
+}
+
+fn cat(arguments: &str) {
+    let mut file = match absolute(arguments).and_then(|path| fs::File::open(path.as_str())) {
+        Ok(file) => file,
+        Err(error) => {
+            writeln!(serial(), "Can't open {arguments}: {error:?}").unwrap();
+            return;
+        },
+    };
+    let mut buffer = [0; 512];
+    loop {
+        match file.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => buffer[..read].iter().for_each(|&byte| serial().send(byte)),
+            Err(error) => {
+                writeln!(serial(), "Can't read {arguments}: {error:?}").unwrap();
+                break;
+            },
+        }
+    }
+}
+
+fn write_file(arguments: &str) {
+    write_line(arguments, fs::File::create);
+}
+
+fn append_file(arguments: &str) {
+    write_line(arguments, fs::File::append);
+}
+
+// Writes the text after the path in `arguments`, and a newline, to the file `open` opens
+fn write_line(arguments: &str, open: fn(&str) -> Result<fs::File, fs::Error>) {
+    let (path, text) = arguments.split_once(' ').unwrap_or((arguments, ""));
+    let written = absolute(path).and_then(|path| open(path.as_str())).and_then(|mut file| {
+        for bytes in [text.as_bytes(), b"\n"] {
+            let mut bytes = bytes;
+            while !bytes.is_empty() {
+                let written = file.write(bytes)?;
+                bytes = &bytes[written..];
+            }
+        }
+        Ok(())
+    });
+    if let Err(error) = written {
+        writeln!(serial(), "Can't write {path}: {error:?}").unwrap();
+    }
+}
+
+fn make_directory(arguments: &str) {
+    if let Err(error) = absolute(arguments).and_then(|path| fs::create_directory(path.as_str())) {
+        writeln!(serial(), "Can't make {arguments}: {error:?}").unwrap();
+    }
+}
+
+fn remove(arguments: &str) {
+    if let Err(error) = absolute(arguments).and_then(|path| fs::remove(path.as_str())) {
+        writeln!(serial(), "Can't remove {arguments}: {error:?}").unwrap();
+    }
+}
+
+// `path` from the current directory, if it isn't absolute
+fn absolute(path: &str) -> Result<TextBuffer<{ fs::MAX_PATH }>, fs::Error> {
+    DIRECTORY.lock().resolve(path)
+}
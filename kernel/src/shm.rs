@@ -0,0 +1,96 @@
+use kernel::sync::SpinLock;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+use crate::memory::{AddressSpace, SharedMemory, PAGE_SIZE};
+use crate::process::{self, Handle};
+use crate::syscall::{self, Error};
+
+// Shared memory objects: memory with a name, which processes open with `shm_open` and map
+// with `mmap` into their address spaces, so that what one writes the others see straight away.
+// Every handle to an object and every mapping of it counts as a use, and the object is freed
+// when the last one is gone: handles when they are closed, and mappings when the process's
+// memory is freed.
+const MAX_OBJECTS: usize = 8;
+const MAX_NAME: usize = 32;
+
+struct Object {
+    name: [u8; MAX_NAME],
+    name_length: usize,
+    memory: SharedMemory,
+    users: usize,
+}
+
+impl Object {
+    fn name(&self) -> &[u8] {
+        &self.name[..self.name_length]
+    }
+}
+
+static OBJECTS: SpinLock<[Option<Object>; MAX_OBJECTS]> = SpinLock::new([const { None }; MAX_OBJECTS]);
+
+/// Registers the shm_open system call. Called once during boot.
+pub fn init() {
+    syscall::register(syscall::SHM_OPEN, shm_open);
+}
+
+// Returns the object called `name`, made with `size` bytes if there is none and `size` isn't 0,
+// with one more use
+fn open(name: &[u8], size: u64) -> Result<usize, Error> {
+    let mut objects = OBJECTS.lock_irq();
+    if let Some(id) = objects.iter().position(|object| matches!(object, Some(object) if object.name() == name)) {
+        objects[id].as_mut().unwrap().users += 1;
+        return Ok(id);
+    }
+    if size == 0 {
+        return Err(Error::NotFound);
+    }
+    let id = objects.iter().position(Option::is_none).ok_or(Error::OutOfMemory)?;
+    let pages = size.checked_next_multiple_of(PAGE_SIZE).ok_or(Error::BadArgument)? / PAGE_SIZE;
+    let memory = SharedMemory::new(pages).ok_or(Error::OutOfMemory)?;
+    let mut object = Object { name: [0; MAX_NAME], name_length: name.len(), memory, users: 1 };
+    object.name[..name.len()].copy_from_slice(name);
+    objects[id] = Some(object);
+    Ok(id)
+}
+
+/// Gives up a use of object `id`, a handle to it or a mapping of it, freeing it if that was
+/// the last.
+pub fn release(id: usize) {
+    let mut objects = OBJECTS.lock_irq();
+    let object = objects[id].as_mut().expect("no such shared memory object");
+    object.users -= 1;
+    if object.users == 0 {
+        objects[id] = None;
+    }
+}
+
+/// Maps the first `length` bytes of object `id` at `start` in `space`, for the program to use
+/// as `flags` allow. The mapping is a use of the object, to `release` once `space` is freed.
+pub fn map(id: usize, space: &mut AddressSpace, start: VirtAddr, length: u64, flags: PageTableFlags) -> Result<(), Error> {
+    let mut objects = OBJECTS.lock_irq();
+    let object = objects[id].as_mut().expect("no such shared memory object");
+    if length == 0 || length > object.memory.size() {
+        return Err(Error::BadArgument);
+    }
+    if !space.map_shared(start, &object.memory, length.div_ceil(PAGE_SIZE), flags) {
+        return Err(Error::OutOfMemory);
+    }
+    object.users += 1;
+    Ok(())
+}
+
+// shm_open(name, length, size): opens the shared memory object called `name`, `length` bytes
+// long, and returns a handle to it. If there is none it is made, `size` bytes of zeroes,
+// unless `size` is 0.
+fn shm_open(arguments: [u64; 6]) -> Result<u64, Error> {
+    let [name, length, size, ..] = arguments;
+    if length == 0 || length > MAX_NAME as u64 {
+        return Err(Error::BadArgument);
+    }
+    let name = syscall::user_bytes(name, length)?;
+    let id = open(name, size)?;
+    process::open(Handle::Shared(id)).ok_or_else(|| {
+        release(id);
+        Error::OutOfMemory
+    })
+}
@@ -0,0 +1,64 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::task::WaitQueue;
+
+/// A lock for data that is held for a long time, such as the screen while a frame is drawn or
+/// a volume while a file is written. A task that finds it held is blocked until it is free,
+/// rather than spinning, so the holder can be preempted or wait for a disk meanwhile, with
+/// interrupts enabled. Interrupt handlers can't take it.
+///
+/// Killing a task waiting for the lock doesn't cut the wait short, as the task may already
+/// hold other locks it has to let go of.
+pub struct SleepLock<T: ?Sized> {
+    locked: AtomicBool,
+    waiting: WaitQueue,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for SleepLock<T> {}
+unsafe impl<T: ?Sized + Send> Sync for SleepLock<T> {}
+
+/// Access to the data in a `SleepLock`, which is unlocked again when the guard is dropped.
+pub struct SleepLockGuard<'a, T: ?Sized> {
+    lock: &'a SleepLock<T>,
+}
+
+impl<T> SleepLock<T> {
+    pub const fn new(value: T) -> Self {
+        SleepLock { locked: AtomicBool::new(false), waiting: WaitQueue::new(), value: UnsafeCell::new(value) }
+    }
+}
+
+impl<T: ?Sized> SleepLock<T> {
+    /// Waits until the lock is free and takes it.
+    pub fn lock(&self) -> SleepLockGuard<'_, T> {
+        self.waiting.wait_until_done(|| self.acquire());
+        SleepLockGuard { lock: self }
+    }
+
+    fn acquire(&self) -> bool {
+        self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok()
+    }
+}
+
+impl<T: ?Sized> Deref for SleepLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for SleepLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for SleepLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+        self.lock.waiting.notify();
+    }
+}
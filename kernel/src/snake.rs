@@ -0,0 +1,257 @@
+use core::fmt::Write;
+use kernel::serial;
+use lazy_static::lazy_static;
+use pc_keyboard::{DecodedKey, KeyCode};
+use kernel::sync::SpinLock;
+use crate::{rand, sound, ui};
+use crate::game::Game;
+use crate::highscores::{self, Slot};
+use crate::input::InputEvent;
+use crate::screen::{CHAR_HEIGHT, CHAR_WIDTH};
+
+// Playfield dimensions, matching the area pong uses
+const SCREEN_WIDTH: usize = 640;
+const SCORE_HEIGHT: usize = 32;
+const CELL_SIZE: usize = 16;
+const GRID_WIDTH: usize = SCREEN_WIDTH / CELL_SIZE;
+const GRID_HEIGHT: usize = 28;
+const MAX_LENGTH: usize = GRID_WIDTH * GRID_HEIGHT;
+const INITIAL_LENGTH: usize = 4;
+const GROWTH_PER_FOOD: usize = 3;
+
+const SNAKE_COLOR: ui::Color = (0, 200, 0);
+const HEAD_COLOR: ui::Color = (120, 255, 120);
+const FOOD_COLOR: ui::Color = (255, 0, 0);
+const WALL_COLOR: ui::Color = (80, 80, 80);
+
+// Sound effects: (frequency in Hz, duration in ms)
+const EAT_SOUND: (u32, u64) = (660, 40);
+const DEATH_SOUND: (u32, u64) = (110, 300);
+
+type Cell = (u8, u8);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+impl Direction {
+    fn opposite(self) -> Self {
+        match self {
+            Direction::Up => Direction::Down,
+            Direction::Down => Direction::Up,
+            Direction::Left => Direction::Right,
+            Direction::Right => Direction::Left,
+        }
+    }
+}
+
+pub struct Snake {
+    // Ring buffer of body cells; `head` indexes the head and the tail is `length - 1` cells behind it
+    body: [Cell; MAX_LENGTH],
+    head: usize,
+    length: usize,
+    pending_growth: usize,
+    direction: Direction,
+    // Direction requested by the player, applied on the next move so two quick
+    // presses can't turn the snake back into itself
+    next_direction: Direction,
+    food: Cell,
+    score: u32,
+    alive: bool,
+    new_high_score: bool,
+}
+
+lazy_static! {
+    pub static ref SNAKE: SpinLock<Snake> = SpinLock::new(Snake {
+        body: [(0, 0); MAX_LENGTH],
+        head: 0,
+        length: 0,
+        pending_growth: 0,
+        direction: Direction::Right,
+        next_direction: Direction::Right,
+        food: (0, 0),
+        score: 0,
+        alive: false,
+        new_high_score: false,
+    });
+}
+
+impl Snake {
+    fn segment(&self, index: usize) -> Cell {
+        self.body[(self.head + MAX_LENGTH - index) % MAX_LENGTH]
+    }
+
+    fn occupies(&self, cell: Cell) -> bool {
+        (0..self.length).any(|i| self.segment(i) == cell)
+    }
+
+    fn spawn_food(&mut self) {
+        // Pick random cells until one is free. The grid is much larger than any realistic
+        // snake, but give up after a while rather than spin forever.
+        for _ in 0..MAX_LENGTH {
+            let cell = (rand::below(GRID_WIDTH as u32) as u8, rand::below(GRID_HEIGHT as u32) as u8);
+            if !self.occupies(cell) {
+                self.food = cell;
+                draw_cell(cell, FOOD_COLOR);
+                return;
+            }
+        }
+    }
+}
+
+impl Game for Snake {
+    fn name(&self) -> &'static str {
+        "Snake"
+    }
+
+    /// Resets the snake to the middle of the grid and draws the playfield.
+    fn init(&mut self) {
+        self.head = INITIAL_LENGTH - 1;
+        self.length = INITIAL_LENGTH;
+        self.pending_growth = 0;
+        self.direction = Direction::Right;
+        self.next_direction = Direction::Right;
+        self.score = 0;
+        self.alive = true;
+        for i in 0..INITIAL_LENGTH {
+            self.body[i] = ((GRID_WIDTH / 2 - INITIAL_LENGTH + i) as u8, (GRID_HEIGHT / 2) as u8);
+        }
+
+        draw_game(self);
+        self.spawn_food();
+    }
+
+    /// Moves the snake one cell. Called on every timer tick while snake is on screen.
+    fn update(&mut self, _dt: u64) {
+        if !self.alive {
+            return;
+        }
+
+        self.direction = self.next_direction;
+        let (x, y) = self.segment(0);
+        let next = match self.direction {
+            Direction::Up => (x as isize, y as isize - 1),
+            Direction::Down => (x as isize, y as isize + 1),
+            Direction::Left => (x as isize - 1, y as isize),
+            Direction::Right => (x as isize + 1, y as isize),
+        };
+
+        // Walls are deadly
+        if next.0 < 0 || next.1 < 0 || next.0 >= GRID_WIDTH as isize || next.1 >= GRID_HEIGHT as isize {
+            die(self);
+            return;
+        }
+        let next = (next.0 as u8, next.1 as u8);
+
+        // The tail moves out of the way this tick unless the snake is growing
+        let tail = self.segment(self.length - 1);
+        let growing = self.pending_growth > 0;
+        if self.occupies(next) && (growing || next != tail) {
+            die(self);
+            return;
+        }
+
+        if growing {
+            self.pending_growth -= 1;
+            self.length = (self.length + 1).min(MAX_LENGTH);
+        } else {
+            draw_cell(tail, ui::BLACK);
+        }
+
+        // Only redraw the cells that changed
+        draw_cell(self.segment(0), SNAKE_COLOR);
+        self.head = (self.head + 1) % MAX_LENGTH;
+        self.body[self.head] = next;
+        draw_cell(next, HEAD_COLOR);
+
+        if next == self.food {
+            self.score += 1;
+            self.pending_growth += GROWTH_PER_FOOD;
+            sound::beep(EAT_SOUND.0, EAT_SOUND.1);
+            draw_score(self.score);
+            self.spawn_food();
+        }
+    }
+
+    /// Handles a key press while snake is on screen.
+    fn handle_input(&mut self, event: InputEvent) {
+        let InputEvent::Key(key) = event;
+        let direction = match key {
+            DecodedKey::RawKey(KeyCode::ArrowUp) | DecodedKey::Unicode('w') => Direction::Up,
+            DecodedKey::RawKey(KeyCode::ArrowDown) | DecodedKey::Unicode('s') => Direction::Down,
+            DecodedKey::RawKey(KeyCode::ArrowLeft) | DecodedKey::Unicode('a') => Direction::Left,
+            DecodedKey::RawKey(KeyCode::ArrowRight) | DecodedKey::Unicode('d') => Direction::Right,
+            DecodedKey::Unicode(' ') if !self.alive => {
+                self.init();
+                return;
+            },
+            _ => return,
+        };
+
+        // Reversing straight into the neck would be instant death
+        if direction != self.direction.opposite() {
+            self.next_direction = direction;
+        }
+    }
+
+    fn render(&mut self) {
+        // Cells are drawn as they change, so there is nothing left to do here
+    }
+
+    fn redraw(&mut self) {
+        draw_game(self);
+        draw_cell(self.food, FOOD_COLOR);
+        if !self.alive {
+            draw_game_over(self.new_high_score);
+        }
+    }
+}
+
+fn die(snake: &mut Snake) {
+    snake.alive = false;
+    sound::beep(DEATH_SOUND.0, DEATH_SOUND.1);
+    writeln!(serial(), "Snake died with score {}", snake.score).unwrap();
+    snake.new_high_score = highscores::submit(Slot::Snake, snake.score);
+    draw_game_over(snake.new_high_score);
+}
+
+fn draw_game_over(record: bool) {
+    let y = SCORE_HEIGHT + (GRID_HEIGHT * CELL_SIZE) / 2 - CHAR_HEIGHT;
+    let text = if record { "NEW HIGH SCORE!" } else { "GAME OVER" };
+    let options = "SPACE: play again   ESC: menu";
+    for (i, line) in [text, options].iter().enumerate() {
+        let x = (SCREEN_WIDTH - ui::text_width(line)) / 2;
+        let line_y = y + i * 2 * CHAR_HEIGHT;
+        ui::fill_rect(x - CHAR_WIDTH, line_y - 2, ui::text_width(line) + 2 * CHAR_WIDTH, CHAR_HEIGHT + 4, ui::BLACK);
+        ui::draw_label(x, line_y, line, ui::WHITE);
+    }
+}
+
+fn draw_cell(cell: Cell, color: ui::Color) {
+    let x = cell.0 as usize * CELL_SIZE;
+    let y = SCORE_HEIGHT + cell.1 as usize * CELL_SIZE;
+    // leave a one pixel gap so the segments are distinguishable
+    ui::fill_rect(x + 1, y + 1, CELL_SIZE - 2, CELL_SIZE - 2, color);
+}
+
+fn draw_score(score: u32) {
+    let mut text = ui::TextBuffer::<32>::new();
+    write!(text, "Score: {score}").unwrap();
+    ui::fill_rect(0, 0, SCREEN_WIDTH, SCORE_HEIGHT - 1, ui::BLACK);
+    ui::draw_label(CHAR_WIDTH, 8, text.as_str(), ui::WHITE);
+}
+
+fn draw_game(snake: &Snake) {
+    ui::fill_rect(0, 0, SCREEN_WIDTH, SCORE_HEIGHT + GRID_HEIGHT * CELL_SIZE, ui::BLACK);
+    ui::fill_rect(0, SCORE_HEIGHT - 1, SCREEN_WIDTH, 1, WALL_COLOR);
+    ui::fill_rect(0, SCORE_HEIGHT + GRID_HEIGHT * CELL_SIZE, SCREEN_WIDTH, 1, WALL_COLOR);
+
+    for i in 0..snake.length {
+        draw_cell(snake.segment(i), if i == 0 { HEAD_COLOR } else { SNAKE_COLOR });
+    }
+    draw_score(snake.score);
+}
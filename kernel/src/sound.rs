@@ -0,0 +1,38 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::settings::{self, Setting};
+use crate::{pit, time, timer};
+
+// Tick at which the current beep ends, so the stop timer of an earlier beep
+// cannot cut a newer one short
+static BEEP_END: AtomicU64 = AtomicU64::new(0);
+
+/// Silences a beep that is playing when sound is turned off. Called once during boot.
+pub fn init() {
+    settings::subscribe(|setting| {
+        if setting == Setting::Sound && !settings::sound_enabled() {
+            pit::speaker_off();
+        }
+    });
+}
+
+/// Plays a tone of `frequency` Hz on the PC speaker for about `duration_ms` milliseconds
+/// (rounded up to whole timer ticks). Returns immediately; the timer wheel stops the tone.
+/// Does nothing while sound is turned off in the settings.
+pub fn beep(frequency: u32, duration_ms: u64) {
+    if !settings::sound_enabled() {
+        return;
+    }
+    let ticks = time::ms_to_ticks(duration_ms);
+    BEEP_END.store(timer::now() + ticks, Ordering::SeqCst);
+    pit::speaker_on(frequency);
+    if !timer::schedule(ticks, stop) {
+        // no timer left to end the beep, so don't start it at all
+        pit::speaker_off();
+    }
+}
+
+fn stop() {
+    if timer::now() >= BEEP_END.load(Ordering::SeqCst) {
+        pit::speaker_off();
+    }
+}
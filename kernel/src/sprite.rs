@@ -0,0 +1,109 @@
+use crate::screen::screenwriter;
+use crate::ui::Color;
+
+// Sprites and dirty-rectangle tracking, for games that move a few small objects around
+// and want to avoid redrawing the whole screen every frame.
+
+/// An axis-aligned rectangle in screen pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    pub const fn new(x: usize, y: usize, width: usize, height: usize) -> Self {
+        Rect { x, y, width, height }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    pub fn intersects(&self, other: &Rect) -> bool {
+        self.x < other.x + other.width && other.x < self.x + self.width
+            && self.y < other.y + other.height && other.y < self.y + self.height
+    }
+
+    /// Returns the smallest rectangle containing both `self` and `other`.
+    pub fn union(&self, other: &Rect) -> Rect {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        let right = (self.x + self.width).max(other.x + other.width);
+        let bottom = (self.y + self.height).max(other.y + other.height);
+        Rect::new(x, y, right - x, bottom - y)
+    }
+}
+
+/// A small image drawn on top of whatever is below it. `None` pixels are transparent.
+pub struct Sprite {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: &'static [Option<Color>],
+}
+
+impl Sprite {
+    pub fn draw(&self, x: usize, y: usize) {
+        let mut screen = screenwriter();
+        for (i, pixel) in self.pixels.iter().enumerate() {
+            if let Some((r, g, b)) = pixel {
+                let (px, py) = (x + i % self.width, y + i / self.width);
+                if px < screen.width() && py < screen.height() {
+                    screen.draw_pixel(px, py, *r, *g, *b);
+                }
+            }
+        }
+    }
+
+    /// The area covered by the sprite when drawn at (x, y).
+    pub fn bounds(&self, x: usize, y: usize) -> Rect {
+        Rect::new(x, y, self.width, self.height)
+    }
+}
+
+/// Areas of the screen that changed since the last frame. Overlapping areas are merged,
+/// and if more than `N` separate areas pile up they collapse into one bounding box.
+pub struct DirtyRects<const N: usize> {
+    rects: [Rect; N],
+    count: usize,
+}
+
+impl<const N: usize> DirtyRects<N> {
+    pub const fn new() -> Self {
+        DirtyRects { rects: [Rect::new(0, 0, 0, 0); N], count: 0 }
+    }
+
+    pub fn add(&mut self, rect: Rect) {
+        if rect.is_empty() {
+            return;
+        }
+        if let Some(existing) = self.rects[..self.count].iter_mut().find(|r| r.intersects(&rect)) {
+            *existing = existing.union(&rect);
+            return;
+        }
+        if self.count == N {
+            let bounds = self.rects.iter().fold(rect, |acc, r| acc.union(r));
+            self.rects[0] = bounds;
+            self.count = 1;
+            return;
+        }
+        self.rects[self.count] = rect;
+        self.count += 1;
+    }
+
+    /// Calls `repaint` for every dirty area and marks the screen clean.
+    pub fn drain(&mut self, mut repaint: impl FnMut(Rect)) {
+        for rect in &self.rects[..self.count] {
+            repaint(*rect);
+        }
+        self.count = 0;
+    }
+}
+
+impl<const N: usize> Default for DirtyRects<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
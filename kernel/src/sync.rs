@@ -0,0 +1,84 @@
+use core::cell::UnsafeCell;
+use core::hint;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::interrupts;
+
+/// A lock that spins until it is free.
+///
+/// Data that interrupt handlers also use must be locked with `lock_irq`, which keeps interrupts
+/// disabled while the lock is held. Otherwise an interrupt handler could find the lock held by
+/// the code it interrupted and spin forever.
+pub struct SpinLock<T: ?Sized> {
+    locked: AtomicBool,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for SpinLock<T> {}
+unsafe impl<T: ?Sized + Send> Sync for SpinLock<T> {}
+
+/// Access to the data in a `SpinLock`, which is unlocked again when the guard is dropped.
+pub struct SpinLockGuard<'a, T: ?Sized> {
+    lock: &'a SpinLock<T>,
+    // Whether dropping the guard turns interrupts back on
+    enable_interrupts: bool,
+}
+
+impl<T> SpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        SpinLock { locked: AtomicBool::new(false), value: UnsafeCell::new(value) }
+    }
+}
+
+impl<T: ?Sized> SpinLock<T> {
+    /// Waits until the lock is free and takes it.
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        while !self.acquire() {
+            hint::spin_loop();
+        }
+        SpinLockGuard { lock: self, enable_interrupts: false }
+    }
+
+    /// Takes the lock if it is free.
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        self.acquire().then_some(SpinLockGuard { lock: self, enable_interrupts: false })
+    }
+
+    /// Disables interrupts, then waits until the lock is free and takes it. Interrupts are
+    /// enabled again when the guard is dropped, if they were enabled before.
+    pub fn lock_irq(&self) -> SpinLockGuard<'_, T> {
+        let enabled = interrupts::are_enabled();
+        interrupts::disable();
+        let mut guard = self.lock();
+        guard.enable_interrupts = enabled;
+        guard
+    }
+
+    fn acquire(&self) -> bool {
+        self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok()
+    }
+}
+
+impl<T: ?Sized> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        // Unlock first, so an interrupt arriving as soon as they are enabled finds it free
+        self.lock.locked.store(false, Ordering::Release);
+        if self.enable_interrupts {
+            interrupts::enable();
+        }
+    }
+}
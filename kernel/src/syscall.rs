@@ -0,0 +1,171 @@
+use core::arch::global_asm;
+use core::slice;
+use kernel::sync::SpinLock;
+use x86_64::instructions::interrupts;
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+use crate::gdt::{self, KERNEL_STACK_OFFSET, TSS};
+use crate::{memory, task};
+
+// System calls: user programs put the call's number in rax and up to six arguments in rdi,
+// rsi, rdx, r10, r8 and r9, and run `syscall`. The entry code switches to the kernel stack and
+// looks the number up in a table that subsystems fill in with `register`; the handler's
+// result comes back in rax, with errors as negative numbers. Handlers run with interrupts
+// enabled, in the calling program's address space, and must check any memory they are given
+// with `user_bytes` and `user_bytes_mut`.
+const MAX_CALLS: usize = 32;
+
+// The call numbers, which user/src/lib.rs has too
+pub const EXIT: usize = 0;
+pub const WRITE: usize = 1;
+pub const READ: usize = 2;
+pub const SLEEP: usize = 3;
+pub const GET_TIME: usize = 4;
+pub const BRK: usize = 5;
+pub const MMAP: usize = 6;
+pub const SCREEN_SIZE: usize = 7;
+pub const DRAW: usize = 8;
+pub const FILL: usize = 9;
+pub const PIPE: usize = 10;
+pub const CLOSE: usize = 11;
+pub const SHM_OPEN: usize = 12;
+pub const OPEN: usize = 13;
+pub const SEEK: usize = 14;
+pub const METADATA: usize = 15;
+
+/// Handles a system call, given its arguments.
+pub type Handler = fn([u64; 6]) -> Result<u64, Error>;
+
+/// Why a system call failed. The program gets the number, negated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// There is no system call with that number.
+    NoSuchCall = 1,
+    /// Memory passed in that the program can't access.
+    BadAddress = 2,
+    /// An argument that doesn't make sense.
+    BadArgument = 3,
+    /// There is no memory left for what was asked.
+    OutOfMemory = 4,
+    /// A handle the program doesn't have open, or can't be used that way.
+    BadHandle = 5,
+    /// Writing to a pipe that nothing can read from.
+    BrokenPipe = 6,
+    /// There is nothing by the name given.
+    NotFound = 7,
+    /// Writing to a filesystem that can't be written to.
+    ReadOnly = 8,
+    /// The filesystem has no room left.
+    NoSpace = 9,
+    /// The disk a file is on failed, or what is stored on it is wrong.
+    Io = 10,
+}
+
+static HANDLERS: SpinLock<[Option<Handler>; MAX_CALLS]> = SpinLock::new([None; MAX_CALLS]);
+
+/// Lets user programs make system calls. Called once during boot.
+pub fn init() {
+    let (kernel_code, kernel_data) = gdt::kernel_selectors();
+    let (user_code, user_data) = gdt::user_selectors();
+    Star::write(user_code, user_data, kernel_code, kernel_data).unwrap();
+    LStar::write(VirtAddr::new(syscall_entry as usize as u64));
+    // System calls start with interrupts disabled, until they are on the kernel stack
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG);
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+}
+
+/// Makes `handler` handle system call `number`.
+pub fn register(number: usize, handler: Handler) {
+    let mut handlers = HANDLERS.lock_irq();
+    assert!(handlers[number].is_none(), "system call {number} registered twice");
+    handlers[number] = Some(handler);
+}
+
+/// The `length` bytes at `address` in the calling program's memory, if it may read them.
+pub fn user_bytes(address: u64, length: u64) -> Result<&'static [u8], Error> {
+    let start = VirtAddr::try_new(address).map_err(|_| Error::BadAddress)?;
+    if !memory::user_can_access(start, length, false) {
+        return Err(Error::BadAddress);
+    }
+    Ok(unsafe { slice::from_raw_parts(start.as_ptr(), length as usize) })
+}
+
+/// The `length` bytes at `address` in the calling program's memory, if it may write them.
+pub fn user_bytes_mut(address: u64, length: u64) -> Result<&'static mut [u8], Error> {
+    let start = VirtAddr::try_new(address).map_err(|_| Error::BadAddress)?;
+    if !memory::user_can_access(start, length, true) {
+        return Err(Error::BadAddress);
+    }
+    Ok(unsafe { slice::from_raw_parts_mut(start.as_mut_ptr(), length as usize) })
+}
+
+// The registers the entry code saves, as it lays them out on the stack
+#[repr(C)]
+struct Registers {
+    number: u64,
+    arguments: [u64; 6],
+}
+
+extern "C" fn dispatch(registers: &Registers) -> u64 {
+    interrupts::enable();
+    // A program that doesn't wait on anything is killed on its next call
+    task::exit_if_killed();
+    let handler = usize::try_from(registers.number).ok()
+        .and_then(|number| HANDLERS.lock_irq().get(number).copied().flatten());
+    let result = match handler {
+        Some(handler) => handler(registers.arguments),
+        None => Err(Error::NoSuchCall),
+    };
+    result.unwrap_or_else(|error| (error as u64).wrapping_neg())
+}
+
+unsafe extern "C" {
+    fn syscall_entry();
+}
+
+// Where the program's stack pointer is kept until it is on the kernel stack. Interrupts are
+// disabled meanwhile, so nothing else can use it.
+static mut USER_STACK: u64 = 0;
+
+global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    // `syscall` leaves the program's stack in place, so the kernel's is taken from the TSS
+    "mov [rip + {user_stack}], rsp",
+    "mov rsp, [rip + {tss} + {kernel_stack}]",
+    // `syscall` put the return address in rcx and the flags in r11. The other registers are
+    // given back as they were, apart from rax with the result.
+    "push [rip + {user_stack}]",
+    "push rcx",
+    "push r11",
+    "push rbp",
+    "push r9",
+    "push r8",
+    "push r10",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rax",
+    "mov rbp, rsp",
+    "mov rdi, rsp",
+    "and rsp, -16",
+    "call {dispatch}",
+    "cli",
+    "lea rsp, [rbp + 8]",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop r10",
+    "pop r8",
+    "pop r9",
+    "pop rbp",
+    "pop r11",
+    "pop rcx",
+    "pop rsp",
+    "sysretq",
+    user_stack = sym USER_STACK,
+    tss = sym TSS,
+    kernel_stack = const KERNEL_STACK_OFFSET,
+    dispatch = sym dispatch,
+);
@@ -0,0 +1,812 @@
+use core::arch::global_asm;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use kernel::serial;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::VirtAddr;
+use crate::memory::{self, PAGE_SIZE};
+use crate::{gdt, time, timer, ui};
+
+// Round-robin multitasking: a task runs until it calls `yield_now` or the timer interrupt finds
+// it has used up its time slice. Either way its registers are saved on its own stack and the
+// next ready task in turn carries on. Task 0 is the boot context, which stays on as the idle
+// task: it only runs when nothing else can and halts the CPU until the next interrupt. Every
+// other task gets a stack of its own from `memory`, which the reaper task frees once the
+// task has finished and switched away from it for good. Below each stack is an unmapped guard
+// page, and its bottom word holds a canary that is checked at every switch, so a task that
+// overflows its stack is reported by name instead of quietly corrupting memory. A task waiting on a
+// `WaitQueue` or in `sleep` is blocked and skipped until it is woken up. With the priority
+// policy, the ready task with the highest priority runs instead, and a task passed over too
+// many times in a row gets a turn regardless, so low priority tasks never starve.
+//
+// Every task runs in an address space, the kernel's own unless it runs a user program, and has
+// the kernel stack that interrupts and system calls from ring 3 start on while it does; a
+// switch puts the next task's in place of the last one's.
+//
+// `kill` asks a task to end. The task only notices at the points where it gives up the CPU
+// of its own accord (yielding, sleeping and waiting), where it isn't in the middle of
+// changing shared state, and ends there after running its `on_kill` hook.
+//
+// Each CPU has a run queue of its own, the tasks it takes turns between, and its own idle
+// task. A new task goes on the queue of the CPU with the fewest, and a CPU with nothing ready
+// on its queue steals a ready task from the busiest other one. `set_affinity` keeps a task to
+// some of the CPUs, such as the game away from one doing heavy background work. Only the
+// bootstrap processor is started so far, so there is one queue with every task on it, until
+// application processors are brought up and go online.
+//
+// The scheduler lock is only ever taken with interrupts disabled, so the timer interrupt can
+// never find it held by the task it interrupted.
+const MAX_TASKS: usize = 16; // at most 32, the bits in a `WaitQueue`
+const MAX_CPUS: usize = 8; // at most 32, the bits in an affinity mask
+// The bootstrap processor's idle task, and the CPU number it has
+const IDLE_TASK: usize = 0;
+const BOOT_CPU: usize = 0;
+/// An affinity mask with every CPU in it.
+pub const ALL_CPUS: u32 = u32::MAX;
+const STACK_PAGES: u64 = 32; // 128 KiB: debug builds use a lot of stack, and interrupts run on it too
+const STACK_SIZE: u64 = STACK_PAGES * PAGE_SIZE;
+// New stacks are filled with this, so the part that was never used can be told apart
+const STACK_FILL: u64 = 0x5354_4143_4b5f_4649; // "STACK_FI"
+// The bottom word of every stack, which only an overflowing task overwrites
+const STACK_CANARY: u64 = 0x4f56_4552_464c_4f57; // "OVERFLOW"
+// Times a ready task can be passed over for higher priority ones before it runs anyway
+const STARVATION_LIMIT: u32 = 20;
+
+/// How urgently a task should get the CPU under `Policy::Priority`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Background,
+    Normal,
+    High,
+}
+
+/// How the scheduler picks the next task to run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// Every ready task in turn.
+    RoundRobin,
+    /// The ready task with the highest priority, with starvation protection.
+    Priority,
+}
+
+/// What a task is doing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Ready,
+    Running,
+    /// Waiting on a `WaitQueue`.
+    Blocked,
+    /// In `sleep`.
+    Sleeping,
+    /// Done, waiting for the reaper.
+    Finished,
+}
+
+impl State {
+    pub fn name(self) -> &'static str {
+        match self {
+            State::Ready => "ready",
+            State::Running => "running",
+            State::Blocked => "blocked",
+            State::Sleeping => "sleeping",
+            State::Finished => "finished",
+        }
+    }
+}
+
+// What a task runs
+#[derive(Clone, Copy)]
+enum Entry {
+    Function(fn()),
+    WithArgument(fn(usize), usize),
+}
+
+struct Task {
+    name: &'static str,
+    entry: Entry,
+    state: State,
+    priority: Priority,
+    // Times in a row the task was ready but another one was picked
+    passed_over: u32,
+    // TSC cycles the task has run for, not counting interrupt handlers
+    cycles: u64,
+    kill_requested: bool,
+    // Run by the task itself when it is killed
+    on_kill: Option<fn()>,
+    // The CPUs it may run on, a bit each
+    affinity: u32,
+    // Saved stack pointer while the task isn't running
+    stack_pointer: u64,
+    // Top of the task's stack, kept so a finished task's stack can be used again
+    stack_top: u64,
+    // The level 4 table of the address space it runs in
+    page_table: PhysFrame,
+    // Where interrupts from ring 3 start on its stack, while it runs a user program
+    kernel_stack: u64,
+}
+
+// One CPU's part of the scheduler
+struct Cpu {
+    // The tasks on its run queue, a bit each like a `WaitQueue`'s; its idle task isn't
+    queue: u32,
+    idle: usize,
+    current: usize,
+    // Timer ticks the current task has run for in its time slice
+    slice_used: u64,
+    // When the current task was switched to, and INTERRUPT_CYCLES at that point
+    running_since: u64,
+    interrupt_cycles_since: u64,
+}
+
+struct Scheduler {
+    tasks: [Option<Task>; MAX_TASKS],
+    cpus: [Cpu; MAX_CPUS],
+    // The CPUs that have been started and schedule tasks, a bit each
+    online: u32,
+    policy: Policy,
+}
+
+// The CPU this runs on. Only the bootstrap processor is started so far; the others will tell
+// themselves apart by their local APIC ids once they are.
+fn this_cpu() -> usize {
+    BOOT_CPU
+}
+
+impl Scheduler {
+    fn cpu(&mut self) -> &mut Cpu {
+        &mut self.cpus[this_cpu()]
+    }
+
+    fn current(&self) -> usize {
+        self.cpus[this_cpu()].current
+    }
+
+    // The ready tasks on `cpu`'s run queue
+    fn ready_on(&self, cpu: usize) -> impl Iterator<Item = usize> + '_ {
+        let queue = self.cpus[cpu].queue;
+        let is_ready = |index: &usize| self.tasks[*index].as_ref().is_some_and(|task| task.state == State::Ready);
+        (0..MAX_TASKS).filter(move |&index| queue & 1 << index != 0).filter(is_ready)
+    }
+
+    // The online CPU out of those in `affinity` with the fewest tasks on its queue
+    fn least_busy(&self, affinity: u32) -> Option<usize> {
+        (0..MAX_CPUS).filter(|&cpu| self.online & affinity & 1 << cpu != 0).min_by_key(|&cpu| self.cpus[cpu].queue.count_ones())
+    }
+
+    // Moves a ready task that may run on `cpu` onto its queue, from the other CPU with the most
+    // ready tasks, for a CPU with none of its own
+    fn steal(&mut self, cpu: usize) {
+        let busiest = (0..MAX_CPUS).filter(|&other| other != cpu && self.online & 1 << other != 0).max_by_key(|&other| self.ready_on(other).count());
+        let Some(other) = busiest else {
+            return;
+        };
+        let allowed = |index: &usize| self.tasks[*index].as_ref().is_some_and(|task| task.affinity & 1 << cpu != 0);
+        let stolen = self.ready_on(other).find(allowed);
+        if let Some(index) = stolen {
+            self.cpus[other].queue &= !(1 << index);
+            self.cpus[cpu].queue |= 1 << index;
+        }
+    }
+
+    /// Returns the task to switch to, or None to keep running the current one.
+    fn next_ready(&mut self) -> Option<usize> {
+        let cpu = this_cpu();
+        if self.ready_on(cpu).next().is_none() {
+            self.steal(cpu);
+        }
+        // The other tasks on this CPU's queue, in round-robin order after the current one
+        let Cpu { queue, idle: idle_task, current, .. } = self.cpus[cpu];
+        let order = (1..MAX_TASKS).map(move |offset| (current + offset) % MAX_TASKS).filter(move |&index| queue & 1 << index != 0);
+        let is_ready = |task: &Option<Task>| task.as_ref().is_some_and(|task| task.state == State::Ready);
+        // A running task moved to another CPU's queue has to stop running here
+        let still_running = |task: &Task| task.state == State::Running && queue & 1 << current != 0;
+        // With nothing else to run, a task that stops running hands over to the idle task
+        let idle = (current != idle_task && !still_running(self.tasks[current].as_ref().unwrap())).then_some(idle_task);
+        if self.policy == Policy::RoundRobin {
+            return order.clone().find(|&index| is_ready(&self.tasks[index])).or(idle);
+        }
+
+        let mut starving = None;
+        let mut best: Option<(usize, Priority)> = None;
+        for index in order.clone().filter(|&index| is_ready(&self.tasks[index])) {
+            let task = self.tasks[index].as_ref().unwrap();
+            if starving.is_none() && task.passed_over >= STARVATION_LIMIT {
+                starving = Some(index);
+            }
+            // The first of the highest priority, so tasks of equal priority take turns
+            if best.is_none_or(|(_, priority)| task.priority > priority) {
+                best = Some((index, task.priority));
+            }
+        }
+        // A running task keeps the CPU unless something at least as important is ready
+        let running = self.tasks[current].as_ref().filter(|task| still_running(task));
+        let best = best.filter(|(_, priority)| running.is_none_or(|task| *priority >= task.priority));
+        let next = starving.or(best.map(|(index, _)| index)).or(idle);
+
+        for index in order {
+            if let Some(task) = self.tasks[index].as_mut().filter(|task| task.state == State::Ready) {
+                task.passed_over = if Some(index) == next { 0 } else { task.passed_over + 1 };
+            }
+        }
+        next
+    }
+}
+
+impl Scheduler {
+    /// Returns the cycles the current task ran for since it was switched to, or since the last
+    /// call, leaving out the interrupt handlers that ran meanwhile.
+    fn time_since_switch(&mut self) -> u64 {
+        let now = time::rdtsc();
+        let interrupt_cycles = INTERRUPT_CYCLES.load(Ordering::SeqCst);
+        let cpu = self.cpu();
+        let elapsed = (now - cpu.running_since).saturating_sub(interrupt_cycles - cpu.interrupt_cycles_since);
+        cpu.running_since = now;
+        cpu.interrupt_cycles_since = interrupt_cycles;
+        elapsed
+    }
+}
+
+static SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler {
+    tasks: [const { None }; MAX_TASKS],
+    cpus: [const { Cpu { queue: 0, idle: IDLE_TASK, current: IDLE_TASK, slice_used: 0, running_since: 0, interrupt_cycles_since: 0 } }; MAX_CPUS],
+    online: 0,
+    policy: Policy::RoundRobin,
+});
+
+// TSC cycles spent in interrupt handlers, see `account_interrupt`
+static INTERRUPT_CYCLES: AtomicU64 = AtomicU64::new(0);
+
+/// What `tasks` reports about a task.
+pub struct TaskInfo {
+    pub id: usize,
+    pub name: &'static str,
+    pub state: State,
+    /// The most stack the task has used so far, in bytes; unknown for the idle task, which runs
+    /// on the boot stack.
+    pub stack_used: Option<u64>,
+    /// TSC cycles the task has run for, not counting interrupt handlers.
+    pub cycles: u64,
+    /// The CPU whose run queue it is on, or whose idle task it is.
+    pub cpu: usize,
+}
+
+// Timer ticks a task may run before it is preempted
+static TIME_SLICE: AtomicU64 = AtomicU64::new(1);
+
+// Saves the callee-saved registers on the current stack and its stack pointer to `*save_to`,
+// then switches to the stack at `load_from` and restores the registers saved there. The
+// `ret` returns into whatever called `switch_stacks` on that stack, or into `task_entry` for
+// a task that hasn't run yet.
+global_asm!(
+    ".global switch_stacks",
+    "switch_stacks:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+);
+
+unsafe extern "C" {
+    fn switch_stacks(save_to: *mut u64, load_from: u64);
+}
+
+// Registers popped by `switch_stacks` before it returns
+const SAVED_REGISTERS: u64 = 6;
+
+/// Makes the code running now task 0, which becomes the idle task once it calls `idle`, and
+/// starts the reaper. Called once during boot, after `memory::init`.
+pub fn init() {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.tasks[0] = Some(Task {
+            name: "idle",
+            entry: Entry::Function(|| {}),
+            state: State::Running,
+            // Not used: the idle task is only picked when nothing else is ready
+            priority: Priority::Background,
+            passed_over: 0,
+            cycles: 0,
+            kill_requested: false,
+            on_kill: None,
+            affinity: 1 << BOOT_CPU,
+            stack_pointer: 0,
+            stack_top: 0,
+            page_table: memory::kernel_page_table(),
+            kernel_stack: 0,
+        });
+        scheduler.online = 1 << BOOT_CPU;
+        scheduler.cpus[BOOT_CPU].running_since = time::rdtsc();
+        drop(scheduler);
+        spawn_task("reaper", Entry::Function(reap), Priority::Background);
+    });
+}
+
+/// Sets how many timer ticks a task may run before another ready task gets the CPU.
+pub fn set_time_slice(ticks: u64) {
+    TIME_SLICE.store(ticks.max(1), Ordering::SeqCst);
+}
+
+/// Chooses how the next task to run is picked from now on.
+pub fn set_policy(policy: Policy) {
+    interrupts::without_interrupts(|| SCHEDULER.lock().policy = policy);
+}
+
+/// Starts `entry` as a new task with normal priority, which first runs the next time the
+/// current task yields. Returns false if there are too many tasks or no memory for its stack.
+pub fn spawn(name: &'static str, entry: fn()) -> bool {
+    spawn_with_priority(name, entry, Priority::Normal)
+}
+
+/// Like `spawn`, but with the given priority.
+pub fn spawn_with_priority(name: &'static str, entry: fn(), priority: Priority) -> bool {
+    interrupts::without_interrupts(|| spawn_task(name, Entry::Function(entry), priority))
+}
+
+/// Like `spawn`, but the task runs `entry(argument)`.
+pub fn spawn_with_argument(name: &'static str, entry: fn(usize), argument: usize) -> bool {
+    interrupts::without_interrupts(|| spawn_task(name, Entry::WithArgument(entry, argument), Priority::Normal))
+}
+
+fn spawn_task(name: &'static str, entry: Entry, priority: Priority) -> bool {
+    let mut scheduler = SCHEDULER.lock();
+    let Some(index) = scheduler.tasks.iter().position(Option::is_none) else {
+        writeln!(serial(), "Can't spawn task {name}: too many tasks").unwrap();
+        return false;
+    };
+    let cpu = scheduler.least_busy(ALL_CPUS).unwrap_or(BOOT_CPU);
+    let Some(stack_top) = memory::alloc_guarded_pages(STACK_PAGES).map(|start| (start + STACK_SIZE).as_u64()) else {
+        writeln!(serial(), "Can't spawn task {name}: out of memory for its stack").unwrap();
+        return false;
+    };
+
+    // Lay the stack out the way `switch_stacks` leaves a task that yielded, with `task_entry`
+    // as the return address. Below that is a zero return address for `task_entry` itself, which
+    // also leaves the stack aligned the way a call would.
+    let stack_pointer = stack_top - (2 + SAVED_REGISTERS) * 8;
+    unsafe {
+        let bottom = (stack_top - STACK_SIZE) as *mut u64;
+        for i in 0..(stack_pointer - (stack_top - STACK_SIZE)) as usize / 8 {
+            bottom.add(i).write(STACK_FILL);
+        }
+        bottom.write(STACK_CANARY);
+        let stack = stack_pointer as *mut u64;
+        for i in 0..SAVED_REGISTERS as usize {
+            stack.add(i).write(0);
+        }
+        stack.add(SAVED_REGISTERS as usize).write(task_entry as usize as u64);
+        stack.add(SAVED_REGISTERS as usize + 1).write(0);
+    }
+    scheduler.tasks[index] = Some(Task {
+        name,
+        entry,
+        state: State::Ready,
+        priority,
+        passed_over: 0,
+        cycles: 0,
+        kill_requested: false,
+        on_kill: None,
+        affinity: ALL_CPUS,
+        stack_pointer,
+        stack_top,
+        page_table: memory::kernel_page_table(),
+        kernel_stack: 0,
+    });
+    scheduler.cpus[cpu].queue |= 1 << index;
+    writeln!(serial(), "Spawned task {index} ({name})").unwrap();
+    true
+}
+
+/// Switches to the next ready task, if there is one, and starts a new time slice. Returns
+/// whether it switched, once the current task runs again.
+/// Interrupts must be disabled, so the switch finishes before an interrupt handler can run on
+/// the new stack.
+fn switch_to_next() -> bool {
+    let (save_to, load_from) = {
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.cpu().slice_used = 0;
+        let current = scheduler.current();
+        let Some(next) = scheduler.next_ready() else {
+            return false;
+        };
+        let cycles = scheduler.time_since_switch();
+        if current != scheduler.cpu().idle {
+            check_canary(current, scheduler.tasks[current].as_ref().unwrap());
+        }
+        let old = scheduler.tasks[current].as_mut().unwrap();
+        old.cycles += cycles;
+        if old.state == State::Running {
+            old.state = State::Ready;
+        }
+        old.kernel_stack = gdt::kernel_stack();
+        let save_to = &mut old.stack_pointer as *mut u64;
+        let new = scheduler.tasks[next].as_mut().unwrap();
+        new.state = State::Running;
+        gdt::set_kernel_stack(new.kernel_stack);
+        // The stacks are in the kernel's part, the same in every address space
+        unsafe { memory::switch_page_table(new.page_table) };
+        let load_from = new.stack_pointer;
+        scheduler.cpu().current = next;
+        (save_to, load_from)
+    };
+    // The lock is released before switching; the tasks live in a static, so `save_to`
+    // stays valid, and only the task switching away ever writes it
+    unsafe { switch_stacks(save_to, load_from) };
+    true
+}
+
+/// Lets the next ready task run. Returns once every other task has had its turn, or straight
+/// away if there is nothing else to run.
+pub fn yield_now() {
+    interrupts::without_interrupts(switch_to_next);
+    exit_if_killed();
+}
+
+/// The idle task's loop: runs whatever is ready and halts the CPU when nothing is.
+pub fn idle() -> ! {
+    loop {
+        // Enabling interrupts in the same instruction as the halt means a task woken by an
+        // interrupt right after the check can't be left waiting until the next interrupt
+        interrupts::disable();
+        if switch_to_next() {
+            interrupts::enable();
+        } else {
+            interrupts::enable_and_hlt();
+        }
+    }
+}
+
+/// Called at the end of every timer interrupt, once it has been acknowledged: counts the tick
+/// against the running task and switches to the next one when its time slice is used up. The
+/// interrupted task carries on from the interrupt when it is switched back to.
+pub fn preempt() {
+    let used_up = {
+        let mut scheduler = SCHEDULER.lock();
+        let cpu = scheduler.cpu();
+        cpu.slice_used += 1;
+        cpu.slice_used >= TIME_SLICE.load(Ordering::SeqCst)
+    };
+    if used_up {
+        switch_to_next();
+    }
+}
+
+// Puts the current task in `state`, Blocked or Sleeping, and switches away from it; it carries
+// on from here once `wake` makes it ready again. Interrupts must be disabled.
+fn block(state: State) {
+    {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current();
+        scheduler.tasks[current].as_mut().unwrap().state = state;
+    }
+    switch_to_next();
+}
+
+// Makes every blocked or sleeping task whose bit is set in `tasks` ready again. Interrupts must
+// be disabled.
+fn wake(tasks: u32) {
+    let mut scheduler = SCHEDULER.lock();
+    for (index, task) in scheduler.tasks.iter_mut().enumerate() {
+        let waiting = |task: &&mut Task| matches!(task.state, State::Blocked | State::Sleeping);
+        if let Some(task) = task.as_mut().filter(|task| tasks & 1 << index != 0 && waiting(task)) {
+            task.state = State::Ready;
+        }
+    }
+}
+
+/// Makes the current task run in the address space whose level 4 table is `page_table`.
+///
+/// # Safety
+/// The address space mustn't be freed while the task runs in it.
+pub unsafe fn set_page_table(page_table: PhysFrame) {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current();
+        scheduler.tasks[current].as_mut().unwrap().page_table = page_table;
+        unsafe { memory::switch_page_table(page_table) };
+    });
+}
+
+/// Returns the id of the current task.
+pub fn current() -> usize {
+    interrupts::without_interrupts(|| SCHEDULER.lock().current())
+}
+
+/// Blocks the current task for at least `ms` milliseconds. The timer wheel wakes it up again.
+pub fn sleep(ms: u64) {
+    let until = timer::now() + time::ms_to_ticks(ms);
+    let current = current();
+    interrupts::without_interrupts(|| {
+        // Something else may wake the task early, so it goes back to sleep until it's time
+        while timer::now() < until && !kill_requested() {
+            if timer::schedule_with_argument(until - timer::now(), |task| interrupts::without_interrupts(|| wake(1 << task)), current) {
+                block(State::Sleeping);
+            } else {
+                // No timer to spare: keep taking turns with the other tasks instead
+                switch_to_next();
+            }
+        }
+    });
+    exit_if_killed();
+}
+
+/// Tasks waiting for something to happen, such as input arriving or a thread finishing.
+pub struct WaitQueue {
+    // One bit per waiting task
+    waiting: AtomicU32,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue { waiting: AtomicU32::new(0) }
+    }
+
+    /// Blocks the current task until `condition` returns true. The condition is checked with
+    /// interrupts disabled, first straight away and then each time the queue is notified, so
+    /// a `notify` can't slip in between checking it and blocking.
+    pub fn wait_until(&self, mut condition: impl FnMut() -> bool) {
+        interrupts::without_interrupts(|| {
+            while !condition() && !kill_requested() {
+                self.waiting.fetch_or(1 << SCHEDULER.lock().current(), Ordering::SeqCst);
+                block(State::Blocked);
+            }
+        });
+        exit_if_killed();
+    }
+
+    /// Like `wait_until`, but killing the task doesn't cut the wait short; it ends after. For
+    /// waits that have to finish, like for a device to be done with memory the task gave it.
+    pub fn wait_until_done(&self, mut condition: impl FnMut() -> bool) {
+        interrupts::without_interrupts(|| {
+            while !condition() {
+                self.waiting.fetch_or(1 << SCHEDULER.lock().current(), Ordering::SeqCst);
+                block(State::Blocked);
+            }
+        });
+    }
+
+    /// Wakes every task waiting on the queue, so they check their condition again. Can be
+    /// called from interrupt handlers.
+    pub fn notify(&self) {
+        let waiting = self.waiting.swap(0, Ordering::SeqCst);
+        if waiting != 0 {
+            interrupts::without_interrupts(|| wake(waiting));
+        }
+    }
+}
+
+// The reaper waits here for tasks to finish
+static FINISHED: WaitQueue = WaitQueue::new();
+
+/// Asks task `id` to end, waking it if it is waiting. Returns false if there is no such task,
+/// or it is the idle task, which can't be killed.
+pub fn kill(id: usize) -> bool {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let task = scheduler.tasks.get_mut(id).and_then(Option::as_mut).filter(|task| task.state != State::Finished);
+        let Some(task) = task.filter(|_| id != IDLE_TASK) else {
+            return false;
+        };
+        task.kill_requested = true;
+        writeln!(serial(), "Killing task {id} ({})", task.name).unwrap();
+        drop(scheduler);
+        wake(1 << id);
+        true
+    })
+}
+
+/// Lets task `id` only run on the CPUs whose bits are set in `cpus`, moving it to the least busy
+/// of them if it is queued on another. Returns false if there is no such task, it is the idle
+/// task, or none of those CPUs are online.
+pub fn set_affinity(id: usize, cpus: u32) -> bool {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let Some(cpu) = scheduler.least_busy(cpus) else {
+            return false;
+        };
+        let task = scheduler.tasks.get_mut(id).and_then(Option::as_mut).filter(|task| task.state != State::Finished);
+        let Some(task) = task.filter(|_| id != IDLE_TASK) else {
+            return false;
+        };
+        task.affinity = cpus;
+        // A task running on a CPU it may no longer use stops there at its next switch
+        let queued = (0..MAX_CPUS).find(|&other| scheduler.cpus[other].queue & 1 << id != 0);
+        if queued.is_none_or(|other| cpus & 1 << other == 0) {
+            if let Some(other) = queued {
+                scheduler.cpus[other].queue &= !(1 << id);
+            }
+            scheduler.cpus[cpu].queue |= 1 << id;
+        }
+        true
+    })
+}
+
+/// Sets a function for the current task to run when it is killed, to put back what it was
+/// using, and returns the one it replaces. It runs on the task itself, with interrupts enabled.
+pub fn on_kill(cleanup: fn()) -> Option<fn()> {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current();
+        scheduler.tasks[current].as_mut().unwrap().on_kill.replace(cleanup)
+    })
+}
+
+/// Puts back the `on_kill` function that `on_kill` replaced, once what the newer one puts back
+/// is put back anyway.
+pub fn restore_on_kill(previous: Option<fn()>) {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current();
+        scheduler.tasks[current].as_mut().unwrap().on_kill = previous;
+    });
+}
+
+fn kill_requested() -> bool {
+    interrupts::without_interrupts(|| {
+        let scheduler = SCHEDULER.lock();
+        scheduler.tasks[scheduler.current()].as_ref().unwrap().kill_requested
+    })
+}
+
+/// Ends the current task if it has been killed, running its `on_kill` hook first. Called where
+/// a killed task may end.
+pub fn exit_if_killed() {
+    if !kill_requested() {
+        return;
+    }
+    let cleanup = interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current();
+        let task = scheduler.tasks[current].as_mut().unwrap();
+        // The hook may wait too, and mustn't be killed half way
+        task.kill_requested = false;
+        task.on_kill.take()
+    });
+    if let Some(cleanup) = cleanup {
+        cleanup();
+    }
+    exit();
+}
+
+/// Counts the time since `start`, taken from `time::rdtsc` when an interrupt handler began, as
+/// spent in interrupt handlers rather than by the task they interrupted.
+pub fn account_interrupt(start: u64) {
+    INTERRUPT_CYCLES.fetch_add(time::rdtsc() - start, Ordering::SeqCst);
+}
+
+/// Returns the TSC cycles spent in interrupt handlers so far.
+pub fn interrupt_cycles() -> u64 {
+    INTERRUPT_CYCLES.load(Ordering::SeqCst)
+}
+
+/// Returns what there is to know about every task.
+pub fn tasks() -> [Option<TaskInfo>; MAX_TASKS] {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        // Bring the running task's time up to date
+        let cycles = scheduler.time_since_switch();
+        let current = scheduler.current();
+        scheduler.tasks[current].as_mut().unwrap().cycles += cycles;
+        core::array::from_fn(|id| scheduler.tasks[id].as_ref().map(|task| TaskInfo {
+            id,
+            name: task.name,
+            state: task.state,
+            stack_used: (id != IDLE_TASK).then(|| stack_used(task.stack_top)),
+            cycles: task.cycles,
+            cpu: (0..MAX_CPUS).find(|&cpu| scheduler.cpus[cpu].queue & 1 << id != 0 || scheduler.cpus[cpu].idle == id).unwrap_or(BOOT_CPU),
+        }))
+    })
+}
+
+// Measures how far down the stack ending at `stack_top` has ever been used, by looking for the
+// first word from the bottom that isn't STACK_FILL any more
+fn stack_used(stack_top: u64) -> u64 {
+    let bottom = (stack_top - STACK_SIZE) as *const u64;
+    // Past the canary
+    let untouched = (1..STACK_SIZE as usize / 8).take_while(|&i| unsafe { bottom.add(i).read() } == STACK_FILL).count();
+    STACK_SIZE - 8 - untouched as u64 * 8
+}
+
+// Stops everything if the task has written over its stack's canary, since it will have
+// overwritten whatever was below that too had the guard page not been there
+fn check_canary(id: usize, task: &Task) {
+    let canary = unsafe { ((task.stack_top - STACK_SIZE) as *const u64).read() };
+    if canary != STACK_CANARY {
+        panic!("Stack overflow in task {id} ({}): used {} of {} bytes of stack", task.name, stack_used(task.stack_top), STACK_SIZE);
+    }
+}
+
+/// Names the task whose stack overflowed, if `address` is in the guard page below a stack.
+/// The fault handler calls this before it panics.
+pub fn report_fault(address: VirtAddr) {
+    // The fault may have happened in the middle of switching tasks
+    let Some(scheduler) = SCHEDULER.try_lock() else {
+        return;
+    };
+    let address = address.as_u64();
+    for (id, task) in scheduler.tasks.iter().enumerate().skip(IDLE_TASK + 1) {
+        let Some(task) = task else {
+            continue;
+        };
+        let bottom = task.stack_top - STACK_SIZE;
+        if (bottom - PAGE_SIZE..bottom).contains(&address) {
+            writeln!(serial(), "Stack overflow in task {id} ({}): ran off the bottom of its {} KiB stack", task.name, STACK_SIZE / 1024).unwrap();
+        }
+    }
+}
+
+/// Lists every task on the serial port: its ID, name, state, CPU, the most stack it has used
+/// and the CPU time it has used, and the time spent in interrupt handlers.
+pub fn dump() {
+    writeln!(serial(), "{:>3}  {:<10} {:<9} {:>3} {:>12} {:>10}", "ID", "NAME", "STATE", "CPU", "STACK USED", "CPU MS").unwrap();
+    for task in tasks().iter().flatten() {
+        let mut stack = ui::TextBuffer::<16>::new();
+        match task.stack_used {
+            Some(used) => write!(stack, "{}/{} KiB", used.div_ceil(1024), STACK_SIZE / 1024).unwrap(),
+            None => write!(stack, "-").unwrap(),
+        }
+        let cpu_ms = time::cycles_to_ms(task.cycles);
+        writeln!(serial(), "{:>3}  {:<10} {:<9} {:>3} {:>12} {:>10}", task.id, task.name, task.state.name(), task.cpu, stack.as_str(), cpu_ms).unwrap();
+    }
+    writeln!(serial(), "     {:<10} {:<9} {:>3} {:>12} {:>10}", "interrupts", "", "", "", time::cycles_to_ms(interrupt_cycles())).unwrap();
+}
+
+/// Ends the current task. The reaper frees its stack and slot once it has switched away.
+pub fn exit() -> ! {
+    interrupts::disable();
+    {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current();
+        let task = scheduler.tasks[current].as_mut().unwrap();
+        task.state = State::Finished;
+        writeln!(serial(), "Task {current} ({}) finished", task.name).unwrap();
+    }
+    FINISHED.notify();
+    // The idle task never finishes, so there is always another task to switch to
+    switch_to_next();
+    unreachable!("finished task was scheduled again");
+}
+
+// The reaper task: removes finished tasks and frees their stacks. Interrupts stay disabled from
+// a task finishing until it has switched away, so by the time the reaper runs, nothing is
+// using the stack any more.
+fn reap() {
+    let is_finished = |task: &Option<Task>| task.as_ref().is_some_and(|task| task.state == State::Finished);
+    loop {
+        FINISHED.wait_until(|| SCHEDULER.lock().tasks.iter().any(is_finished));
+        let stack_top = interrupts::without_interrupts(|| {
+            let mut scheduler = SCHEDULER.lock();
+            let index = scheduler.tasks.iter().position(is_finished).unwrap();
+            scheduler.cpus.iter_mut().for_each(|cpu| cpu.queue &= !(1 << index));
+            scheduler.tasks[index].take().unwrap().stack_top
+        });
+        memory::free_pages(VirtAddr::new(stack_top - STACK_SIZE), STACK_PAGES);
+    }
+}
+
+/// First code a new task runs, reached through the return address `spawn` put on its stack.
+extern "C" fn task_entry() -> ! {
+    // Every switch happens with interrupts disabled
+    let entry = {
+        let scheduler = SCHEDULER.lock();
+        scheduler.tasks[scheduler.current()].as_ref().unwrap().entry
+    };
+    interrupts::enable();
+    match entry {
+        Entry::Function(function) => function(),
+        Entry::WithArgument(function, argument) => function(argument),
+    }
+    exit();
+}
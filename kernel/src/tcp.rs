@@ -0,0 +1,863 @@
+use core::mem;
+use core::net::{Ipv4Addr, SocketAddrV4};
+use kernel::sync::SpinLock;
+use x86_64::VirtAddr;
+use crate::ipv4::{self, PROTOCOL_TCP, Packet};
+use crate::memory::{self, PAGE_SIZE};
+use crate::net::{self, Error, Interface};
+use crate::task::WaitQueue;
+use crate::{arp, rand, time, timer};
+
+// TCP, a reliable stream of bytes each way between ports on two machines, over IPv4. This is a
+// small TCP: a connection sends and takes bytes in order, one window's worth in flight, and
+// drops segments that come early rather than keeping them for later; what isn't acknowledged
+// in time is sent again, from the oldest byte on, waiting twice as long each time, until the
+// connection gives up. There are no options but the largest segment size, sent with SYN.
+// Each connection has a page to send from and a page to receive into, in memory of its own
+// that is given back once it is closed. Everything is sent from the network task, which is
+// woken to send what a task writes and by a timer to send again what wasn't acknowledged; it
+// can't wait for ARP, so `connect` finds the next hop before the SYN goes. A `TcpStream`
+// dropped while open sends FIN and the connection is closed on the network task, after.
+pub const HEADER_SIZE: usize = 20;
+/// The most data a segment from this machine carries, and the most it takes.
+pub const MSS: usize = ipv4::MAX_PAYLOAD - HEADER_SIZE;
+// What a machine that doesn't say takes
+const DEFAULT_MSS: usize = 536;
+const MAX_CONNECTIONS: usize = 8;
+const MAX_LISTENERS: usize = 4;
+// Connections to a listener that haven't been accepted yet
+const BACKLOG: usize = 2;
+const BUFFER_SIZE: usize = PAGE_SIZE as usize;
+const BUFFER_PAGES: u64 = mem::size_of::<Buffers>().div_ceil(PAGE_SIZE as usize) as u64;
+// How long a segment waits to be acknowledged before it is sent again, doubled each time, and
+// how many times it is
+const RETRANSMIT_MS: u64 = 1_000;
+const MAX_RETRIES: u32 = 5;
+// How long a closed connection waits for segments still on the way, and how long one this
+// side closed waits for the other side to close
+const TIME_WAIT_MS: u64 = 2_000;
+const FIN_WAIT_MS: u64 = 60_000;
+// How often the network task is woken to look at the connections' timers
+const TICK_MS: u64 = 100;
+// Where ports picked for connections come from
+const FIRST_EPHEMERAL: u16 = 49152;
+
+const FIN: u8 = 1 << 0;
+const SYN: u8 = 1 << 1;
+const RST: u8 = 1 << 2;
+const PSH: u8 = 1 << 3;
+const ACK: u8 = 1 << 4;
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_MSS: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    SynSent,
+    SynReceived,
+    Established,
+    // This side has closed, and waits for its FIN to be acknowledged
+    FinWait1,
+    // ... and then for the other side to close
+    FinWait2,
+    // The other side has closed, and this one hasn't yet
+    CloseWait,
+    // Both sides closed at once
+    Closing,
+    // This side closed after the other, and waits for its FIN to be acknowledged
+    LastAck,
+    TimeWait,
+    Closed,
+}
+
+struct Buffers {
+    send: [u8; BUFFER_SIZE],
+    receive: [u8; BUFFER_SIZE],
+}
+
+struct Connection {
+    state: State,
+    local_port: u16,
+    remote: SocketAddrV4,
+    // The port of the listener it came in on, until it is accepted
+    listener: Option<u16>,
+    // Whether a `TcpStream` has it. Closed connections nothing has are freed.
+    owned: bool,
+    // Why it closed, if it didn't close cleanly
+    error: Option<Error>,
+    // The sequence number of SYN
+    initial: u32,
+    // The oldest sequence number sent but not acknowledged, and the next to send
+    unacknowledged: u32,
+    next: u32,
+    // How much the other side takes beyond what it has acknowledged, and in a segment
+    window: u32,
+    mss: usize,
+    buffers: &'static mut Buffers,
+    // The sequence number of the first byte in the send buffer, and how many there are; they
+    // stay there until acknowledged
+    send_base: u32,
+    send_length: usize,
+    // Whether FIN goes after what is in the send buffer
+    fin_queued: bool,
+    // The next sequence number to take, and the bytes taken, in a ring
+    receive_next: u32,
+    receive_start: usize,
+    receive_length: usize,
+    fin_received: bool,
+    ack_pending: bool,
+    rst_pending: bool,
+    // The tick the connection's timer runs out at, if it is running
+    deadline: Option<u64>,
+    retries: u32,
+}
+
+struct Sockets {
+    connections: [Option<Connection>; MAX_CONNECTIONS],
+    listeners: [Option<u16>; MAX_LISTENERS],
+}
+
+// What a received segment says
+struct Segment<'a> {
+    remote: SocketAddrV4,
+    local_port: u16,
+    sequence: u32,
+    acknowledgment: u32,
+    flags: u8,
+    window: u16,
+    mss: usize,
+    data: &'a [u8],
+}
+
+// The header of a segment to send
+struct Header {
+    local_port: u16,
+    remote_port: u16,
+    sequence: u32,
+    acknowledgment: u32,
+    flags: u8,
+    window: u16,
+}
+
+static SOCKETS: SpinLock<Sockets> = SpinLock::new(Sockets {
+    connections: [const { None }; MAX_CONNECTIONS],
+    listeners: [None; MAX_LISTENERS],
+});
+// Tasks waiting for a connection to change: to be accepted, to open, to take bytes or to have
+// room for them, or to close
+static CHANGED: WaitQueue = WaitQueue::new();
+static NEXT_EPHEMERAL: SpinLock<u16> = SpinLock::new(FIRST_EPHEMERAL);
+
+/// Takes the TCP packets the cards receive from now on. Called once during boot.
+pub fn init() {
+    ipv4::register(PROTOCOL_TCP, receive);
+    timer::schedule(time::ms_to_ticks(TICK_MS), tick);
+}
+
+fn tick() {
+    net::wake();
+    timer::schedule(time::ms_to_ticks(TICK_MS), tick);
+}
+
+// Whether sequence number `a` comes before `b`, allowing for them wrapping around
+fn before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+impl Sockets {
+    fn in_use(&self, port: u16) -> bool {
+        self.listeners.iter().flatten().any(|&other| other == port)
+            || self.connections.iter().flatten().any(|connection| connection.local_port == port)
+    }
+
+    // Returns `port` to listen on, or a free port from 49152 up if it is 0
+    fn pick_port(&self, port: u16) -> Result<u16, Error> {
+        match port {
+            0 => {
+                // Round from where the last one was picked, so a port isn't reused straight away
+                let count = u16::MAX - FIRST_EPHEMERAL + 1;
+                let mut next = NEXT_EPHEMERAL.lock();
+                let port = (0..count).map(|offset| FIRST_EPHEMERAL + (*next - FIRST_EPHEMERAL + offset) % count)
+                    .find(|&port| !self.in_use(port)).ok_or(Error::AddressInUse)?;
+                *next = port.checked_add(1).unwrap_or(FIRST_EPHEMERAL);
+                Ok(port)
+            },
+            // Connections a listener accepted stay on its port, and don't keep it from being
+            // listened on again
+            port if self.listeners.iter().flatten().any(|&other| other == port) => Err(Error::AddressInUse),
+            port => Ok(port),
+        }
+    }
+
+    fn free_slot(&self) -> Result<usize, Error> {
+        self.connections.iter().position(Option::is_none).ok_or(Error::TooManySockets)
+    }
+}
+
+impl Header {
+    // Writes the header, and `options`, at the start of `segment`, and returns their length.
+    // The checksum is left 0 for `transmit`.
+    fn write(&self, segment: &mut [u8], options: &[u8]) -> usize {
+        let length = HEADER_SIZE + options.len();
+        segment[..length].fill(0);
+        segment[0..2].copy_from_slice(&self.local_port.to_be_bytes());
+        segment[2..4].copy_from_slice(&self.remote_port.to_be_bytes());
+        segment[4..8].copy_from_slice(&self.sequence.to_be_bytes());
+        segment[8..12].copy_from_slice(&self.acknowledgment.to_be_bytes());
+        segment[12] = ((length / 4) << 4) as u8;
+        segment[13] = self.flags;
+        segment[14..16].copy_from_slice(&self.window.to_be_bytes());
+        segment[HEADER_SIZE..length].copy_from_slice(options);
+        length
+    }
+}
+
+impl Connection {
+    fn new(local_port: u16, remote: SocketAddrV4, state: State, listener: Option<u16>) -> Option<Connection> {
+        let pages = memory::alloc_guarded_pages(BUFFER_PAGES)?;
+        let mut initial = [0; 4];
+        rand::fill(&mut initial);
+        let initial = u32::from_ne_bytes(initial);
+        Some(Connection {
+            state,
+            local_port,
+            remote,
+            listener,
+            owned: listener.is_none(),
+            error: None,
+            initial,
+            unacknowledged: initial,
+            next: initial,
+            window: 0,
+            mss: DEFAULT_MSS,
+            buffers: unsafe { &mut *pages.as_mut_ptr::<Buffers>() },
+            send_base: initial.wrapping_add(1),
+            send_length: 0,
+            fin_queued: false,
+            receive_next: 0,
+            receive_start: 0,
+            receive_length: 0,
+            fin_received: false,
+            ack_pending: false,
+            rst_pending: false,
+            deadline: None,
+            retries: 0,
+        })
+    }
+
+    fn header(&self, sequence: u32, flags: u8) -> Header {
+        Header {
+            local_port: self.local_port,
+            remote_port: self.remote.port(),
+            sequence,
+            acknowledgment: self.receive_next,
+            flags,
+            window: (BUFFER_SIZE - self.receive_length) as u16,
+        }
+    }
+
+    fn start_timer(&mut self) {
+        if self.deadline.is_none() {
+            self.deadline = Some(timer::now() + time::ms_to_ticks(RETRANSMIT_MS << self.retries));
+        }
+    }
+
+    fn fail(&mut self, error: Error) {
+        self.state = State::Closed;
+        self.error = Some(error);
+        self.deadline = None;
+    }
+
+    // Writes the next segment the connection has to send into `segment` and returns its
+    // length, or None if there is nothing to send
+    fn next_segment(&mut self, segment: &mut [u8]) -> Option<usize> {
+        if self.rst_pending {
+            self.rst_pending = false;
+            return Some(self.header(self.next, RST | ACK).write(segment, &[]));
+        }
+        match self.state {
+            State::Closed => return None,
+            State::SynSent | State::SynReceived => {
+                if self.next != self.initial {
+                    return None;
+                }
+                let flags = if self.state == State::SynSent { SYN } else { SYN | ACK };
+                let [high, low] = (MSS as u16).to_be_bytes();
+                self.next = self.next.wrapping_add(1);
+                self.ack_pending = false;
+                self.start_timer();
+                return Some(self.header(self.initial, flags).write(segment, &[OPTION_MSS, 4, high, low]));
+            },
+            _ => {},
+        }
+
+        let offset = self.next.wrapping_sub(self.send_base) as usize;
+        if offset < self.send_length {
+            let in_flight = self.next.wrapping_sub(self.unacknowledged) as usize;
+            // A closed window is probed a byte at a time, so its opening isn't missed
+            let room = match (self.window as usize).saturating_sub(in_flight) {
+                0 if in_flight == 0 => 1,
+                room => room,
+            };
+            let length = (self.send_length - offset).min(self.mss).min(room);
+            if length > 0 {
+                let header_length = self.header(self.next, ACK | PSH).write(segment, &[]);
+                segment[header_length..][..length].copy_from_slice(&self.buffers.send[offset..][..length]);
+                self.next = self.next.wrapping_add(length as u32);
+                self.ack_pending = false;
+                self.start_timer();
+                return Some(header_length + length);
+            }
+        }
+        if self.fin_queued && offset == self.send_length {
+            let length = self.header(self.next, FIN | ACK).write(segment, &[]);
+            self.next = self.next.wrapping_add(1);
+            self.ack_pending = false;
+            self.start_timer();
+            return Some(length);
+        }
+        if self.ack_pending {
+            self.ack_pending = false;
+            return Some(self.header(self.next, ACK).write(segment, &[]));
+        }
+        None
+    }
+
+    // Takes a segment from the other side
+    fn receive(&mut self, segment: &Segment) {
+        if self.state == State::SynSent {
+            if segment.flags & ACK != 0 && segment.acknowledgment != self.initial.wrapping_add(1) {
+                return;
+            }
+            if segment.flags & RST != 0 {
+                if segment.flags & ACK != 0 {
+                    self.fail(Error::ConnectionRefused);
+                }
+                return;
+            }
+            if segment.flags & (SYN | ACK) == SYN | ACK {
+                self.receive_next = segment.sequence.wrapping_add(1);
+                self.unacknowledged = segment.acknowledgment;
+                self.window = u32::from(segment.window);
+                self.mss = segment.mss;
+                self.state = State::Established;
+                self.deadline = None;
+                self.retries = 0;
+                self.ack_pending = true;
+            }
+            return;
+        }
+
+        if segment.flags & RST != 0 {
+            // Only a reset inside the window is believed, so a stray one can't end a connection
+            if segment.sequence.wrapping_sub(self.receive_next) <= (BUFFER_SIZE - self.receive_length) as u32 {
+                self.fail(Error::ConnectionReset);
+            }
+            return;
+        }
+        if segment.flags & SYN != 0 {
+            // The other side didn't get SYN|ACK, and sent SYN again
+            if self.state == State::SynReceived {
+                self.next = self.unacknowledged;
+            } else {
+                self.ack_pending = true;
+            }
+            return;
+        }
+        if segment.flags & ACK == 0 {
+            return;
+        }
+        if self.state == State::SynReceived {
+            if segment.acknowledgment != self.initial.wrapping_add(1) {
+                return;
+            }
+            self.state = State::Established;
+        }
+        self.acknowledge(segment.acknowledgment, segment.window);
+        self.take(segment);
+    }
+
+    // Drops the bytes, and FIN, that the other side has acknowledged from the send buffer
+    fn acknowledge(&mut self, acknowledgment: u32, window: u16) {
+        if before(self.next, acknowledgment) {
+            // It acknowledges what wasn't sent
+            self.ack_pending = true;
+            return;
+        }
+        if before(acknowledgment, self.unacknowledged) {
+            return;
+        }
+        self.window = u32::from(window);
+        if acknowledgment == self.unacknowledged {
+            return;
+        }
+        self.unacknowledged = acknowledgment;
+        let acknowledged = (acknowledgment.wrapping_sub(self.send_base) as usize).min(self.send_length);
+        self.buffers.send.copy_within(acknowledged..self.send_length, 0);
+        self.send_length -= acknowledged;
+        self.send_base = self.send_base.wrapping_add(acknowledged as u32);
+        self.retries = 0;
+        self.deadline = None;
+        if self.unacknowledged != self.next {
+            self.start_timer();
+        }
+        let fin_acknowledged = self.fin_queued && acknowledgment == self.send_base.wrapping_add(self.send_length as u32 + 1);
+        if fin_acknowledged {
+            match self.state {
+                State::FinWait1 => {
+                    self.state = State::FinWait2;
+                    self.deadline = Some(timer::now() + time::ms_to_ticks(FIN_WAIT_MS));
+                },
+                State::Closing => {
+                    self.state = State::TimeWait;
+                    self.deadline = Some(timer::now() + time::ms_to_ticks(TIME_WAIT_MS));
+                },
+                State::LastAck => self.state = State::Closed,
+                _ => {},
+            }
+        }
+    }
+
+    // Takes the segment's data, and FIN, if they are the next in the stream
+    fn take(&mut self, segment: &Segment) {
+        let has_fin = segment.flags & FIN != 0;
+        if !matches!(self.state, State::Established | State::FinWait1 | State::FinWait2) {
+            // Sent again after the other side closed: acknowledge it again
+            self.ack_pending |= has_fin || !segment.data.is_empty();
+            return;
+        }
+        let mut data = segment.data;
+        if before(segment.sequence, self.receive_next) {
+            // Bytes already taken are skipped
+            let skip = self.receive_next.wrapping_sub(segment.sequence) as usize;
+            if skip > data.len() {
+                self.ack_pending = true;
+                return;
+            }
+            data = &data[skip..];
+        } else if segment.sequence != self.receive_next {
+            // Early: dropped, and the acknowledgment says what is wanted next
+            self.ack_pending = true;
+            return;
+        }
+        let length = data.len().min(BUFFER_SIZE - self.receive_length);
+        for (index, &byte) in data[..length].iter().enumerate() {
+            self.buffers.receive[(self.receive_start + self.receive_length + index) % BUFFER_SIZE] = byte;
+        }
+        self.receive_length += length;
+        self.receive_next = self.receive_next.wrapping_add(length as u32);
+        self.ack_pending |= !data.is_empty();
+        // FIN is only taken once all the bytes before it are
+        if has_fin && length == data.len() {
+            self.receive_next = self.receive_next.wrapping_add(1);
+            self.fin_received = true;
+            self.ack_pending = true;
+            match self.state {
+                State::Established => self.state = State::CloseWait,
+                State::FinWait1 => self.state = State::Closing,
+                State::FinWait2 => {
+                    self.state = State::TimeWait;
+                    self.deadline = Some(timer::now() + time::ms_to_ticks(TIME_WAIT_MS));
+                },
+                _ => {},
+            }
+        }
+    }
+
+    // The connection's timer has run out: it is done waiting, or sends again what wasn't
+    // acknowledged
+    fn timeout(&mut self) {
+        self.deadline = None;
+        match self.state {
+            State::TimeWait | State::FinWait2 => self.state = State::Closed,
+            _ if self.retries == MAX_RETRIES => {
+                self.fail(Error::TimedOut);
+                self.rst_pending = true;
+            },
+            _ => {
+                self.retries += 1;
+                self.next = self.unacknowledged;
+            },
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        memory::free_pages(VirtAddr::from_ptr(self.buffers as *mut Buffers), BUFFER_PAGES);
+    }
+}
+
+/// Sends what the connections have to send, sends again what wasn't acknowledged in time, and
+/// frees the connections that are done with. Called on the network task each time it wakes.
+pub fn poll() {
+    let now = timer::now();
+    let mut sockets = SOCKETS.lock_irq();
+    let mut changed = false;
+    for connection in sockets.connections.iter_mut().flatten() {
+        if connection.deadline.is_some_and(|deadline| deadline <= now) {
+            connection.timeout();
+            changed = true;
+        }
+    }
+    drop(sockets);
+    for slot in 0..MAX_CONNECTIONS {
+        flush(slot);
+    }
+    for slot in SOCKETS.lock_irq().connections.iter_mut() {
+        if slot.as_ref().is_some_and(|connection| connection.state == State::Closed && !connection.owned && !connection.rst_pending) {
+            *slot = None;
+        }
+    }
+    if changed {
+        CHANGED.notify();
+    }
+}
+
+// Sends every segment connection `slot` has to send
+fn flush(slot: usize) {
+    let mut segment = [0; ipv4::MAX_PAYLOAD];
+    loop {
+        let mut sockets = SOCKETS.lock_irq();
+        let Some(connection) = sockets.connections[slot].as_mut() else {
+            return;
+        };
+        let Some(length) = connection.next_segment(&mut segment) else {
+            return;
+        };
+        let remote = connection.remote;
+        drop(sockets);
+        transmit(remote, &mut segment[..length]);
+    }
+}
+
+// Fills in `segment`'s checksum and sends it to `remote`
+fn transmit(remote: SocketAddrV4, segment: &mut [u8]) {
+    let Some((interface, next_hop)) = ipv4::route(*remote.ip()) else {
+        return;
+    };
+    let source = interface.ipv4().unwrap_or(Ipv4Addr::UNSPECIFIED);
+    let checksum = ipv4::pseudo_header_checksum(source, *remote.ip(), PROTOCOL_TCP, segment);
+    segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+    // One that can't go now is sent again when its connection's timer runs out
+    let _ = ipv4::send_from(interface, next_hop, *remote.ip(), PROTOCOL_TCP, segment);
+}
+
+// The largest segment the sender of a SYN takes, from its options
+fn parse_mss(mut options: &[u8]) -> usize {
+    while let [kind, rest @ ..] = options {
+        match *kind {
+            OPTION_END => break,
+            OPTION_NOP => options = rest,
+            kind => {
+                let Some(&length) = rest.first() else {
+                    break;
+                };
+                let length = usize::from(length);
+                if length < 2 || length > options.len() {
+                    break;
+                }
+                if kind == OPTION_MSS && length == 4 {
+                    return usize::from(u16::from_be_bytes([options[2], options[3]])).clamp(1, MSS);
+                }
+                options = &options[length..];
+            },
+        }
+    }
+    DEFAULT_MSS
+}
+
+// Checks a segment and hands it to its connection, or starts one if it opens a connection to
+// a listener. Anything else is answered with RST.
+fn receive(_interface: &'static Interface, packet: &Packet) {
+    let bytes = packet.payload;
+    if bytes.len() < HEADER_SIZE || ipv4::pseudo_header_checksum(packet.source, packet.destination, PROTOCOL_TCP, bytes) != 0 {
+        return;
+    }
+    let header_length = usize::from(bytes[12] >> 4) * 4;
+    if header_length < HEADER_SIZE || header_length > bytes.len() {
+        return;
+    }
+    let segment = Segment {
+        remote: SocketAddrV4::new(packet.source, u16::from_be_bytes([bytes[0], bytes[1]])),
+        local_port: u16::from_be_bytes([bytes[2], bytes[3]]),
+        sequence: u32::from_be_bytes(bytes[4..8].try_into().unwrap()),
+        acknowledgment: u32::from_be_bytes(bytes[8..12].try_into().unwrap()),
+        flags: bytes[13],
+        window: u16::from_be_bytes([bytes[14], bytes[15]]),
+        mss: parse_mss(&bytes[HEADER_SIZE..header_length]),
+        data: &bytes[header_length..],
+    };
+
+    let mut sockets = SOCKETS.lock_irq();
+    let existing = sockets.connections.iter().position(|slot| slot.as_ref().is_some_and(|connection| {
+        connection.state != State::Closed && connection.local_port == segment.local_port && connection.remote == segment.remote
+    }));
+    let slot = match existing {
+        Some(slot) => {
+            sockets.connections[slot].as_mut().unwrap().receive(&segment);
+            Some(slot)
+        },
+        None if segment.flags & (SYN | ACK | RST) == SYN => open(&mut sockets, &segment),
+        None => None,
+    };
+    drop(sockets);
+    match slot {
+        Some(slot) => {
+            CHANGED.notify();
+            flush(slot);
+        },
+        None if segment.flags & RST == 0 => reset(&segment),
+        None => {},
+    }
+}
+
+// Starts a connection for a SYN to a listener, if it has room for another. Returns its slot.
+fn open(sockets: &mut Sockets, segment: &Segment) -> Option<usize> {
+    let port = segment.local_port;
+    if !sockets.listeners.iter().flatten().any(|&listener| listener == port) {
+        return None;
+    }
+    let waiting = sockets.connections.iter().flatten().filter(|connection| connection.listener == Some(port)).count();
+    if waiting == BACKLOG {
+        return None;
+    }
+    let slot = sockets.free_slot().ok()?;
+    let mut connection = Connection::new(port, segment.remote, State::SynReceived, Some(port))?;
+    connection.receive_next = segment.sequence.wrapping_add(1);
+    connection.window = u32::from(segment.window);
+    connection.mss = segment.mss;
+    sockets.connections[slot] = Some(connection);
+    Some(slot)
+}
+
+// Answers a segment that no connection takes with RST
+fn reset(segment: &Segment) {
+    let (sequence, acknowledgment, flags) = match segment.flags & ACK {
+        0 => {
+            let length = segment.data.len() as u32 + u32::from(segment.flags & SYN != 0) + u32::from(segment.flags & FIN != 0);
+            (0, segment.sequence.wrapping_add(length), RST | ACK)
+        },
+        _ => (segment.acknowledgment, 0, RST),
+    };
+    let header = Header {
+        local_port: segment.local_port,
+        remote_port: segment.remote.port(),
+        sequence,
+        acknowledgment,
+        flags,
+        window: 0,
+    };
+    let mut bytes = [0; HEADER_SIZE];
+    header.write(&mut bytes, &[]);
+    transmit(segment.remote, &mut bytes);
+}
+
+/// A TCP port listened on by this machine, whose connections are taken with `accept`. A
+/// couple of connections can wait to be accepted; more are refused until one is. The port is
+/// free again once it is dropped, and the connections still waiting are reset.
+pub struct TcpListener {
+    port: u16,
+}
+
+impl TcpListener {
+    /// Listens on `port`, or on a free port from 49152 up if it is 0.
+    pub fn bind(port: u16) -> Result<TcpListener, Error> {
+        let mut sockets = SOCKETS.lock_irq();
+        let port = sockets.pick_port(port)?;
+        let slot = sockets.listeners.iter_mut().find(|slot| slot.is_none()).ok_or(Error::TooManySockets)?;
+        *slot = Some(port);
+        Ok(TcpListener { port })
+    }
+
+    /// The port listened on.
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    /// Returns a connection that has opened to the port, or None if none is waiting.
+    pub fn try_accept(&self) -> Option<TcpStream> {
+        let mut sockets = SOCKETS.lock_irq();
+        let (slot, connection) = sockets.connections.iter_mut().enumerate()
+            .filter_map(|(slot, connection)| Some((slot, connection.as_mut()?)))
+            .find(|(_, connection)| connection.listener == Some(self.port) && matches!(connection.state, State::Established | State::CloseWait))?;
+        connection.listener = None;
+        connection.owned = true;
+        Some(TcpStream { slot, local_port: connection.local_port, remote: connection.remote })
+    }
+
+    /// Like `try_accept`, but blocks the calling task until a connection opens. Not to be
+    /// called on the network task.
+    pub fn accept(&self) -> TcpStream {
+        let mut accepted = None;
+        CHANGED.wait_until(|| {
+            accepted = self.try_accept();
+            accepted.is_some()
+        });
+        // The wait only ends early if the task is being killed, which it doesn't come back from
+        accepted.unwrap()
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        let mut sockets = SOCKETS.lock_irq();
+        for slot in sockets.listeners.iter_mut() {
+            if *slot == Some(self.port) {
+                *slot = None;
+            }
+        }
+        for connection in sockets.connections.iter_mut().flatten() {
+            if connection.listener == Some(self.port) {
+                connection.listener = None;
+                connection.state = State::Closed;
+                connection.rst_pending = true;
+            }
+        }
+        drop(sockets);
+        net::wake();
+    }
+}
+
+/// A TCP connection between a port on this machine and one on another. Dropping it closes
+/// this side, after what was written is sent.
+pub struct TcpStream {
+    slot: usize,
+    local_port: u16,
+    remote: SocketAddrV4,
+}
+
+impl TcpStream {
+    /// Opens a connection to `remote`, from a free port from 49152 up, and blocks the calling
+    /// task until it is open, refused or given up on. Not to be called on the network task.
+    pub fn connect(remote: SocketAddrV4) -> Result<TcpStream, Error> {
+        // The network task sends SYN, and can't wait for ARP, so ARP is asked first
+        let (interface, next_hop) = ipv4::route(*remote.ip()).ok_or(Error::NoRoute)?;
+        arp::resolve_wait(interface, next_hop).ok_or(Error::Unreachable)?;
+
+        let mut sockets = SOCKETS.lock_irq();
+        let local_port = sockets.pick_port(0)?;
+        let slot = sockets.free_slot()?;
+        sockets.connections[slot] = Some(Connection::new(local_port, remote, State::SynSent, None).ok_or(Error::TooManySockets)?);
+        drop(sockets);
+        net::wake();
+
+        let stream = TcpStream { slot, local_port, remote };
+        CHANGED.wait_until(|| stream.with(|connection| connection.state) != State::SynSent);
+        stream.with(|connection| match connection.state {
+            State::Established | State::CloseWait => Ok(()),
+            _ => Err(connection.error.unwrap_or(Error::ConnectionRefused)),
+        })?;
+        Ok(stream)
+    }
+
+    /// The port this side of the connection is on.
+    pub fn local_port(&self) -> u16 {
+        self.local_port
+    }
+
+    /// The address and port the other side of the connection is on.
+    pub fn peer_addr(&self) -> SocketAddrV4 {
+        self.remote
+    }
+
+    fn with<T>(&self, f: impl FnOnce(&mut Connection) -> T) -> T {
+        f(SOCKETS.lock_irq().connections[self.slot].as_mut().unwrap())
+    }
+
+    /// Moves bytes that have arrived into `buffer` and returns how many, without waiting.
+    /// Returns None if none have, Ok(0) once the other side has closed and every byte is
+    /// taken, or why the connection ended if it was reset or given up on.
+    pub fn try_read(&self, buffer: &mut [u8]) -> Option<Result<usize, Error>> {
+        let (read, window_opened) = self.with(|connection| {
+            if connection.receive_length == 0 {
+                return match connection.state {
+                    _ if connection.fin_received => (Some(Ok(0)), false),
+                    State::Closed => (Some(Err(connection.error.unwrap_or(Error::NotConnected))), false),
+                    _ => (None, false),
+                };
+            }
+            let was_small = BUFFER_SIZE - connection.receive_length < MSS;
+            let length = connection.receive_length.min(buffer.len());
+            for (index, byte) in buffer[..length].iter_mut().enumerate() {
+                *byte = connection.buffers.receive[(connection.receive_start + index) % BUFFER_SIZE];
+            }
+            connection.receive_start = (connection.receive_start + length) % BUFFER_SIZE;
+            connection.receive_length -= length;
+            // The other side is told once there is room for a whole segment again
+            let window_opened = was_small && BUFFER_SIZE - connection.receive_length >= MSS;
+            connection.ack_pending |= window_opened;
+            (Some(Ok(length)), window_opened)
+        });
+        if window_opened {
+            net::wake();
+        }
+        read
+    }
+
+    /// Like `try_read`, but blocks the calling task until there is something to return. Not
+    /// to be called on the network task.
+    pub fn read(&self, buffer: &mut [u8]) -> Result<usize, Error> {
+        let mut read = None;
+        CHANGED.wait_until(|| {
+            read = self.try_read(buffer);
+            read.is_some()
+        });
+        read.unwrap()
+    }
+
+    /// Puts as much of `data` as there is room for in the send buffer, blocking the calling
+    /// task until there is room for some, and returns how much. The network task sends it.
+    /// Not to be called on the network task.
+    pub fn write(&self, data: &[u8]) -> Result<usize, Error> {
+        let mut written = None;
+        CHANGED.wait_until(|| {
+            written = self.with(|connection| {
+                if !matches!(connection.state, State::Established | State::CloseWait) {
+                    return Some(Err(connection.error.unwrap_or(Error::NotConnected)));
+                }
+                let length = data.len().min(BUFFER_SIZE - connection.send_length);
+                if length == 0 && !data.is_empty() {
+                    return None;
+                }
+                let start = connection.send_length;
+                connection.buffers.send[start..][..length].copy_from_slice(&data[..length]);
+                connection.send_length += length;
+                Some(Ok(length))
+            });
+            written.is_some()
+        });
+        net::wake();
+        written.unwrap()
+    }
+
+    /// Like `write`, but blocks until all of `data` is in the send buffer.
+    pub fn write_all(&self, mut data: &[u8]) -> Result<(), Error> {
+        while !data.is_empty() {
+            let written = self.write(data)?;
+            data = &data[written..];
+        }
+        Ok(())
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        let mut sockets = SOCKETS.lock_irq();
+        let slot = &mut sockets.connections[self.slot];
+        let connection = slot.as_mut().unwrap();
+        connection.owned = false;
+        match connection.state {
+            State::Established => connection.state = State::FinWait1,
+            State::CloseWait => connection.state = State::LastAck,
+            State::SynSent => connection.state = State::Closed,
+            _ => {},
+        }
+        connection.fin_queued = matches!(connection.state, State::FinWait1 | State::LastAck);
+        if connection.state == State::Closed && !connection.rst_pending {
+            *slot = None;
+        }
+        drop(sockets);
+        net::wake();
+    }
+}
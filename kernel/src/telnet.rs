@@ -0,0 +1,134 @@
+use core::fmt::Write;
+use kernel::serial;
+use crate::net::{TcpListener, TcpStream};
+use crate::{config, shell, task};
+
+// The shell over TCP, for a machine without a serial cable: `telnet <address>` gets the same
+// console as the serial port. What is typed goes to the shell, and everything sent on the
+// serial port from then on, the shell's output among it, is sent back, with each newline made
+// the carriage return and line feed telnet expects. The shell echoes what is typed, so the
+// client is asked not to, and to send each key as it is pressed. One client is served at a
+// time, on `[net] console_port` in kernel.cfg; Ctrl+D or closing the connection ends it.
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+const ECHO: u8 = 1;
+const SUPPRESS_GO_AHEAD: u8 = 3;
+const END_OF_TRANSMISSION: u8 = 0x04;
+// How often the connection is checked for input and the serial port for output
+const POLL_MS: u64 = 20;
+const BANNER: &str = "lab-os remote console; Ctrl+D to leave\r\n";
+
+// Where a byte from the client is in telnet's commands
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    Data,
+    // After a carriage return, which a line feed or NUL may follow
+    Return,
+    Command,
+    // After WILL, WONT, DO or DONT, before the option
+    Option,
+    Subnegotiation,
+    SubnegotiationCommand,
+}
+
+/// Serves the console to one client after another, forever. This is the remote console's
+/// task's entry point; it ends straight away if kernel.cfg turns the console off.
+pub fn run() {
+    let port = config::get().net.console_port;
+    if port == 0 {
+        return;
+    }
+    let listener = match TcpListener::bind(port) {
+        Ok(listener) => listener,
+        Err(error) => {
+            writeln!(serial(), "Can't listen for the remote console on port {port}: {error:?}").unwrap();
+            return;
+        },
+    };
+    loop {
+        let stream = listener.accept();
+        writeln!(serial(), "Remote console opened from {}", stream.peer_addr()).unwrap();
+        serve(&stream);
+        writeln!(serial(), "Remote console from {} closed", stream.peer_addr()).unwrap();
+    }
+}
+
+// Passes what the client types to the shell and sends it the serial port's output, until it
+// leaves
+fn serve(stream: &TcpStream) {
+    let greeting = [IAC, WILL, ECHO, IAC, WILL, SUPPRESS_GO_AHEAD];
+    if stream.write_all(&greeting).and_then(|()| stream.write_all(BANNER.as_bytes())).is_err() {
+        return;
+    }
+    let mut position = kernel::log_position();
+    shell::show_prompt();
+    let mut state = State::Data;
+    let mut input = [0; 64];
+    loop {
+        let length = match stream.try_read(&mut input) {
+            Some(Ok(0) | Err(_)) => return,
+            Some(Ok(length)) => length,
+            None => 0,
+        };
+        for &byte in &input[..length] {
+            match receive(&mut state, byte) {
+                Some(END_OF_TRANSMISSION) => return,
+                Some(byte) => shell::handle_byte(byte),
+                None => {},
+            }
+        }
+        position = match forward(stream, position) {
+            Some(position) => position,
+            None => return,
+        };
+        if length == 0 {
+            task::sleep(POLL_MS);
+        }
+    }
+}
+
+// Takes a byte from the client, and returns it if it is typed rather than part of a command
+fn receive(state: &mut State, byte: u8) -> Option<u8> {
+    let (next, typed) = match (*state, byte) {
+        (State::Data | State::Return, IAC) => (State::Command, None),
+        // Enter comes as a carriage return and a line feed or NUL, which would end another line
+        (State::Return, b'\n' | 0) => (State::Data, None),
+        (State::Data | State::Return, b'\r') => (State::Return, Some(b'\r')),
+        (State::Data | State::Return, byte) => (State::Data, Some(byte)),
+        (State::Command, WILL..=DONT) => (State::Option, None),
+        (State::Command, SB) => (State::Subnegotiation, None),
+        // Anything else, IAC IAC for a 255 among them, means nothing to the shell
+        (State::Command | State::Option, _) => (State::Data, None),
+        (State::Subnegotiation, IAC) => (State::SubnegotiationCommand, None),
+        (State::Subnegotiation, _) => (State::Subnegotiation, None),
+        (State::SubnegotiationCommand, SE) => (State::Data, None),
+        (State::SubnegotiationCommand, _) => (State::Subnegotiation, None),
+    };
+    *state = next;
+    typed
+}
+
+// Sends the client what was sent on the serial port since `position`, and returns the
+// position after it, or None if the connection is gone
+fn forward(stream: &TcpStream, mut position: usize) -> Option<usize> {
+    let mut output = [0; 256];
+    loop {
+        let (length, next) = kernel::log_since(position, &mut output);
+        if length == 0 {
+            return Some(position);
+        }
+        position = next;
+        for line in output[..length].split_inclusive(|&byte| byte == b'\n') {
+            match line.split_last() {
+                Some((&b'\n', text)) => {
+                    stream.write_all(text).ok()?;
+                    stream.write_all(b"\r\n").ok()?;
+                },
+                _ => stream.write_all(line).ok()?,
+            }
+        }
+    }
+}
@@ -0,0 +1,371 @@
+use core::fmt::Write;
+use kernel::serial;
+use lazy_static::lazy_static;
+use pc_keyboard::{DecodedKey, KeyCode};
+use kernel::sync::SpinLock;
+use crate::{rand, sound, ui};
+use crate::game::Game;
+use crate::highscores::{self, Slot};
+use crate::input::{InputEvent, RepeatFilter};
+use crate::screen::CHAR_HEIGHT;
+use crate::time::FixedStep;
+
+// Playfield dimensions, matching the area pong uses
+const SCREEN_WIDTH: usize = 640;
+const BOARD_WIDTH: usize = 10;
+const BOARD_HEIGHT: usize = 20;
+const CELL_SIZE: usize = 20;
+const BOARD_X: usize = (SCREEN_WIDTH - BOARD_WIDTH * CELL_SIZE) / 2;
+const BOARD_Y: usize = 40;
+const PREVIEW_X: usize = BOARD_X + (BOARD_WIDTH + 2) * CELL_SIZE;
+const PREVIEW_Y: usize = BOARD_Y + 2 * CELL_SIZE;
+
+// Gravity: one row per this many milliseconds on level 1, getting faster every level
+const INITIAL_DROP_MS: u64 = 1000;
+const DROP_MS_PER_LEVEL: u64 = 80;
+const MIN_DROP_MS: u64 = 100;
+const LINES_PER_LEVEL: u32 = 10;
+
+// Points for clearing 1-4 lines at once, multiplied by the level
+const LINE_SCORES: [u32; 4] = [100, 300, 500, 800];
+
+// Sound effects: (frequency in Hz, duration in ms)
+const LOCK_SOUND: (u32, u64) = (330, 20);
+const LINE_SOUND: (u32, u64) = (990, 120);
+const GAME_OVER_SOUND: (u32, u64) = (110, 400);
+
+// Each tetromino as four rotation states, clockwise, of a 4x4 bitmask
+// (0x8000 is the top-left cell, rows are read left to right, top to bottom)
+const SHAPES: [[u16; 4]; 7] = [
+    [0x0F00, 0x2222, 0x00F0, 0x4444], // I
+    [0x44C0, 0x8E00, 0x6440, 0x0E20], // J
+    [0x4460, 0x0E80, 0xC440, 0x2E00], // L
+    [0xCC00, 0xCC00, 0xCC00, 0xCC00], // O
+    [0x06C0, 0x8C40, 0x6C00, 0x4620], // S
+    [0x0E40, 0x4C40, 0x4E00, 0x4640], // T
+    [0x0C60, 0x4C80, 0xC600, 0x2640], // Z
+];
+const COLORS: [ui::Color; 7] = [
+    (0, 240, 240),
+    (0, 0, 240),
+    (240, 160, 0),
+    (240, 240, 0),
+    (0, 240, 0),
+    (160, 0, 240),
+    (240, 0, 0),
+];
+
+// Offsets tried, in order, when a rotated piece doesn't fit where it is
+const WALL_KICKS: [(i32, i32); 6] = [(0, 0), (-1, 0), (1, 0), (0, -1), (-2, 0), (2, 0)];
+
+#[derive(Clone, Copy)]
+struct Piece {
+    kind: usize,
+    rotation: usize,
+    x: i32,
+    y: i32,
+}
+
+impl Piece {
+    fn spawn(kind: usize) -> Self {
+        Piece { kind, rotation: 0, x: (BOARD_WIDTH as i32 - 4) / 2, y: 0 }
+    }
+
+    fn cells(&self) -> impl Iterator<Item = (i32, i32)> + '_ {
+        let mask = SHAPES[self.kind][self.rotation];
+        (0..16).filter(move |bit| mask & (0x8000 >> bit) != 0)
+            .map(move |bit| (self.x + bit % 4, self.y + bit / 4))
+    }
+}
+
+// Board cells hold the piece kind + 1, or 0 when empty
+type Board = [[u8; BOARD_WIDTH]; BOARD_HEIGHT];
+
+pub struct Tetris {
+    board: Board,
+    // What is currently on screen, so rendering only touches cells that changed
+    drawn: Board,
+    piece: Piece,
+    next: usize,
+    bag: [usize; 7],
+    bag_left: usize,
+    score: u32,
+    lines: u32,
+    level: u32,
+    game_over: bool,
+    new_high_score: bool,
+    gravity: FixedStep,
+    repeats: RepeatFilter,
+}
+
+lazy_static! {
+    pub static ref TETRIS: SpinLock<Tetris> = SpinLock::new(Tetris {
+        board: [[0; BOARD_WIDTH]; BOARD_HEIGHT],
+        drawn: [[0; BOARD_WIDTH]; BOARD_HEIGHT],
+        piece: Piece::spawn(0),
+        next: 0,
+        bag: [0, 1, 2, 3, 4, 5, 6],
+        bag_left: 0,
+        score: 0,
+        lines: 0,
+        level: 1,
+        game_over: false,
+        new_high_score: false,
+        gravity: FixedStep::new(),
+        repeats: RepeatFilter::new(),
+    });
+}
+
+impl Tetris {
+    /// Draws pieces from a shuffled bag of all seven, so droughts of one piece stay short.
+    fn next_from_bag(&mut self) -> usize {
+        if self.bag_left == 0 {
+            for i in (1..self.bag.len()).rev() {
+                let j = rand::below(i as u32 + 1) as usize;
+                self.bag.swap(i, j);
+            }
+            self.bag_left = self.bag.len();
+        }
+        self.bag_left -= 1;
+        self.bag[self.bag_left]
+    }
+
+    fn fits(&self, piece: &Piece) -> bool {
+        piece.cells().all(|(x, y)| {
+            x >= 0 && x < BOARD_WIDTH as i32 && y >= 0 && y < BOARD_HEIGHT as i32
+                && self.board[y as usize][x as usize] == 0
+        })
+    }
+
+    fn try_move(&mut self, dx: i32, dy: i32) -> bool {
+        let moved = Piece { x: self.piece.x + dx, y: self.piece.y + dy, ..self.piece };
+        if self.fits(&moved) {
+            self.piece = moved;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn rotate(&mut self, direction: usize) {
+        let rotated = Piece { rotation: (self.piece.rotation + direction) % 4, ..self.piece };
+        for (dx, dy) in WALL_KICKS {
+            let kicked = Piece { x: rotated.x + dx, y: rotated.y + dy, ..rotated };
+            if self.fits(&kicked) {
+                self.piece = kicked;
+                return;
+            }
+        }
+    }
+
+    fn drop_interval_ms(&self) -> u64 {
+        INITIAL_DROP_MS.saturating_sub((self.level as u64 - 1) * DROP_MS_PER_LEVEL).max(MIN_DROP_MS)
+    }
+
+    /// Moves the piece down one row, locking it in place if it can't go any further.
+    fn fall(&mut self) {
+        if !self.try_move(0, 1) {
+            self.lock_piece();
+        }
+    }
+
+    fn hard_drop(&mut self) {
+        let mut rows = 0;
+        while self.try_move(0, 1) {
+            rows += 1;
+        }
+        self.score += 2 * rows;
+        self.lock_piece();
+    }
+
+    fn lock_piece(&mut self) {
+        let piece = self.piece;
+        for (x, y) in piece.cells() {
+            self.board[y as usize][x as usize] = piece.kind as u8 + 1;
+        }
+        sound::beep(LOCK_SOUND.0, LOCK_SOUND.1);
+        self.clear_lines();
+
+        self.piece = Piece::spawn(self.next);
+        self.next = self.next_from_bag();
+        if !self.fits(&self.piece) {
+            self.game_over = true;
+            self.new_high_score = highscores::submit(Slot::Tetris, self.score);
+            sound::beep(GAME_OVER_SOUND.0, GAME_OVER_SOUND.1);
+            writeln!(serial(), "Tetris over with score {}", self.score).unwrap();
+        }
+        draw_sidebar(self);
+    }
+
+    fn clear_lines(&mut self) {
+        let mut cleared = 0;
+        let mut y = BOARD_HEIGHT;
+        while y > 0 {
+            y -= 1;
+            if self.board[y].iter().all(|cell| *cell != 0) {
+                // shift everything above down one row and check this row again
+                for row in (1..=y).rev() {
+                    self.board[row] = self.board[row - 1];
+                }
+                self.board[0] = [0; BOARD_WIDTH];
+                cleared += 1;
+                y += 1;
+            }
+        }
+
+        if cleared > 0 {
+            self.score += LINE_SCORES[cleared - 1] * self.level;
+            self.lines += cleared as u32;
+            self.level = self.lines / LINES_PER_LEVEL + 1;
+            self.gravity.reset(self.drop_interval_ms());
+            sound::beep(LINE_SOUND.0, LINE_SOUND.1);
+        }
+    }
+
+    /// Redraws the board cells whose contents changed since they were last drawn.
+    fn redraw_board(&mut self) {
+        let mut frame = self.board;
+        if !self.game_over {
+            for (x, y) in self.piece.cells() {
+                frame[y as usize][x as usize] = self.piece.kind as u8 + 1;
+            }
+        }
+        for (y, (row, drawn_row)) in frame.iter().zip(self.drawn.iter()).enumerate() {
+            for (x, (cell, drawn_cell)) in row.iter().zip(drawn_row.iter()).enumerate() {
+                if cell != drawn_cell {
+                    draw_cell(BOARD_X + x * CELL_SIZE, BOARD_Y + y * CELL_SIZE, *cell);
+                }
+            }
+        }
+        self.drawn = frame;
+        if self.game_over {
+            draw_game_over(self.new_high_score);
+        }
+    }
+}
+
+impl Game for Tetris {
+    fn name(&self) -> &'static str {
+        "Tetris"
+    }
+
+    /// Starts a new game on an empty board.
+    fn init(&mut self) {
+        self.board = [[0; BOARD_WIDTH]; BOARD_HEIGHT];
+        self.drawn = [[0; BOARD_WIDTH]; BOARD_HEIGHT];
+        self.bag_left = 0;
+        let first = self.next_from_bag();
+        self.piece = Piece::spawn(first);
+        self.next = self.next_from_bag();
+        self.score = 0;
+        self.lines = 0;
+        self.level = 1;
+        self.game_over = false;
+        let interval = self.drop_interval_ms();
+        self.gravity.reset(interval);
+
+        draw_board_frame();
+        draw_sidebar(self);
+    }
+
+    /// Applies gravity for however much time passed since the last update.
+    fn update(&mut self, _dt: u64) {
+        if self.game_over {
+            return;
+        }
+        let steps = self.gravity.steps();
+        for _ in 0..steps {
+            self.fall();
+            if self.game_over {
+                break;
+            }
+        }
+    }
+
+    /// Handles a key press while tetris is on screen.
+    fn handle_input(&mut self, event: InputEvent) {
+        let InputEvent::Key(key) = event;
+        if self.game_over {
+            if key == DecodedKey::Unicode(' ') {
+                self.init();
+            }
+            return;
+        }
+
+        // Holding a key repeats moves and soft drops, but rotating or hard dropping
+        // again needs a fresh press
+        let repeat = self.repeats.is_repeat(key);
+        match key {
+            DecodedKey::RawKey(KeyCode::ArrowLeft) | DecodedKey::Unicode('a') => {
+                self.try_move(-1, 0);
+            },
+            DecodedKey::RawKey(KeyCode::ArrowRight) | DecodedKey::Unicode('d') => {
+                self.try_move(1, 0);
+            },
+            DecodedKey::RawKey(KeyCode::ArrowDown) | DecodedKey::Unicode('s') => {
+                if self.try_move(0, 1) {
+                    self.score += 1;
+                }
+                draw_sidebar(self);
+            },
+            DecodedKey::RawKey(KeyCode::ArrowUp) | DecodedKey::Unicode('w') | DecodedKey::Unicode('x') if !repeat => self.rotate(1),
+            DecodedKey::Unicode('z') if !repeat => self.rotate(3),
+            DecodedKey::Unicode(' ') if !repeat => self.hard_drop(),
+            _ => {},
+        }
+    }
+
+    fn render(&mut self) {
+        self.redraw_board();
+    }
+
+    fn redraw(&mut self) {
+        // Mark every cell as stale so the next render draws the whole board
+        self.drawn = [[u8::MAX; BOARD_WIDTH]; BOARD_HEIGHT];
+        // Don't let pieces fall for all the time the game was hidden
+        let interval = self.drop_interval_ms();
+        self.gravity.reset(interval);
+        draw_board_frame();
+        draw_sidebar(self);
+    }
+}
+
+fn draw_cell(x: usize, y: usize, cell: u8) {
+    if cell == 0 {
+        ui::fill_rect(x, y, CELL_SIZE, CELL_SIZE, ui::BLACK);
+    } else {
+        ui::fill_rect(x + 1, y + 1, CELL_SIZE - 2, CELL_SIZE - 2, COLORS[cell as usize - 1]);
+    }
+}
+
+fn draw_board_frame() {
+    ui::draw_frame(BOARD_X - 2, BOARD_Y - 2, BOARD_WIDTH * CELL_SIZE + 4, BOARD_HEIGHT * CELL_SIZE + 4, ui::GREY);
+}
+
+fn draw_sidebar(game: &Tetris) {
+    ui::fill_rect(PREVIEW_X, BOARD_Y, SCREEN_WIDTH - PREVIEW_X, BOARD_HEIGHT * CELL_SIZE, ui::BLACK);
+    ui::draw_label(PREVIEW_X, BOARD_Y, "Next:", ui::WHITE);
+    for (x, y) in Piece::spawn(game.next).cells() {
+        let x = PREVIEW_X + (x - Piece::spawn(game.next).x) as usize * CELL_SIZE;
+        draw_cell(x, PREVIEW_Y + y as usize * CELL_SIZE, game.next as u8 + 1);
+    }
+
+    let stats_y = PREVIEW_Y + 5 * CELL_SIZE;
+    let stats = [("Score", game.score), ("Lines", game.lines), ("Level", game.level)];
+    for (i, (name, value)) in stats.iter().enumerate() {
+        let mut text = ui::TextBuffer::<32>::new();
+        write!(text, "{name}: {value}").unwrap();
+        ui::draw_label(PREVIEW_X, stats_y + i * 2 * CHAR_HEIGHT, text.as_str(), ui::WHITE);
+    }
+}
+
+fn draw_game_over(record: bool) {
+    let y = BOARD_Y + BOARD_HEIGHT * CELL_SIZE / 2 - CHAR_HEIGHT;
+    let title = if record { "NEW HIGH SCORE!" } else { "GAME OVER" };
+    for (i, line) in [title, "SPACE: again", "ESC: menu"].iter().enumerate() {
+        let x = BOARD_X + (BOARD_WIDTH * CELL_SIZE - ui::text_width(line)) / 2;
+        let line_y = y + i * 2 * CHAR_HEIGHT;
+        ui::fill_rect(x - 4, line_y - 2, ui::text_width(line) + 8, CHAR_HEIGHT + 4, ui::BLACK);
+        ui::draw_label(x, line_y, line, ui::WHITE);
+    }
+}
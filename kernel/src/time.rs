@@ -0,0 +1,166 @@
+use core::arch::x86_64::_rdtsc;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU64, Ordering};
+use kernel::serial;
+use kernel::interrupts::APICOffset;
+use crate::pit;
+
+// Number of LAPIC timer counts to sample when calibrating against the TSC
+const CALIBRATION_COUNTS: u32 = 0x10_0000;
+
+// Length of the PIT countdown used to measure the TSC frequency
+const TSC_CALIBRATION_MS: u64 = 10;
+
+// TSC cycles per second and per LAPIC timer period (0 until calibrated)
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+static CYCLES_PER_TICK: AtomicU64 = AtomicU64::new(0);
+static MISSED_DEADLINES: AtomicU64 = AtomicU64::new(0);
+
+/// Reads the CPU time-stamp counter.
+pub fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Measures the TSC frequency against the PIT, which runs at a known rate.
+pub fn calibrate_tsc() {
+    let count = (pit::FREQUENCY as u64 * TSC_CALIBRATION_MS / 1000) as u16;
+    let start = rdtsc();
+    pit::wait(count);
+    let hz = (rdtsc() - start) * 1000 / TSC_CALIBRATION_MS;
+    TSC_HZ.store(hz, Ordering::SeqCst);
+    writeln!(serial(), "TSC frequency: {hz} Hz").unwrap();
+}
+
+/// Measures the length of the LAPIC timer period in TSC cycles.
+/// The timer must already be running in periodic mode (see `interrupts::init_apic`).
+pub fn calibrate(lapic_pointer: *mut u32) {
+    let (initial, start_count, end_count, cycles) = unsafe {
+        let ticr = lapic_pointer.offset(APICOffset::Ticr as isize / 4);
+        let tccr = lapic_pointer.offset(APICOffset::Tccr as isize / 4);
+        let initial = ticr.read_volatile();
+
+        // wait for a fresh period so the sample window cannot wrap around
+        let mut previous = tccr.read_volatile();
+        loop {
+            let current = tccr.read_volatile();
+            if current > previous {
+                break;
+            }
+            previous = current;
+        }
+
+        let start_count = tccr.read_volatile();
+        let start = rdtsc();
+        let mut end_count = start_count;
+        while start_count - end_count < CALIBRATION_COUNTS.min(initial / 2) {
+            end_count = tccr.read_volatile();
+        }
+        (initial, start_count, end_count, rdtsc() - start)
+    };
+
+    let per_tick = cycles * initial as u64 / (start_count - end_count) as u64;
+    CYCLES_PER_TICK.store(per_tick, Ordering::SeqCst);
+    writeln!(serial(), "Timer period: {per_tick} TSC cycles").unwrap();
+}
+
+/// Makes the LAPIC timer interrupt `hz` times a second, from what `calibrate` measured.
+pub fn set_frequency(lapic_pointer: *mut u32, hz: u32) {
+    let per_tick = cycles_per_tick();
+    if per_tick == 0 || hz == 0 {
+        return;
+    }
+    let cycles = TSC_HZ.load(Ordering::SeqCst) / u64::from(hz);
+    let count = unsafe {
+        let ticr = lapic_pointer.offset(APICOffset::Ticr as isize / 4);
+        // The counter runs at a fixed rate, so the count scales with the period
+        let count = (u64::from(ticr.read_volatile()) * cycles / per_tick).clamp(1, u64::from(u32::MAX));
+        // Which starts a new period
+        ticr.write_volatile(count as u32);
+        count
+    };
+    CYCLES_PER_TICK.store(cycles, Ordering::SeqCst);
+    writeln!(serial(), "Timer set to {hz} Hz: {count} counts, {cycles} TSC cycles").unwrap();
+}
+
+/// Returns the calibrated timer period in TSC cycles, or 0 if `calibrate` has not run yet.
+pub fn cycles_per_tick() -> u64 {
+    CYCLES_PER_TICK.load(Ordering::SeqCst)
+}
+
+/// Converts a duration in milliseconds to TSC cycles (0 before `calibrate_tsc` has run).
+pub fn ms_to_cycles(ms: u64) -> u64 {
+    ms * TSC_HZ.load(Ordering::SeqCst) / 1000
+}
+
+/// Converts a number of TSC cycles to milliseconds (0 before `calibrate_tsc` has run).
+pub fn cycles_to_ms(cycles: u64) -> u64 {
+    cycles / (TSC_HZ.load(Ordering::SeqCst) / 1000).max(1)
+}
+
+/// Milliseconds since the CPU was reset, measured with the TSC.
+pub fn uptime_ms() -> u64 {
+    rdtsc() / (TSC_HZ.load(Ordering::SeqCst) / 1000).max(1)
+}
+
+/// Converts a duration in milliseconds to timer ticks, rounding up to at least one tick.
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    let hz = TSC_HZ.load(Ordering::SeqCst);
+    let per_tick = cycles_per_tick();
+    if hz == 0 || per_tick == 0 {
+        return 1;
+    }
+    (ms * hz / 1000).div_ceil(per_tick).max(1)
+}
+
+/// Fixed-timestep accumulator: turns however much real time passed between calls into a
+/// whole number of simulation steps of equal length, so a game runs at the same speed no
+/// matter how irregularly it gets called (dropped ticks, slow frames, ...).
+pub struct FixedStep {
+    step_cycles: u64,
+    last: u64,
+    accumulated: u64,
+}
+
+impl FixedStep {
+    // Upper bound on the steps returned at once, so a long stall doesn't fast-forward the game
+    const MAX_STEPS: u32 = 5;
+
+    pub const fn new() -> Self {
+        FixedStep { step_cycles: 0, last: 0, accumulated: 0 }
+    }
+
+    /// Sets the step length and starts measuring from now.
+    pub fn reset(&mut self, step_ms: u64) {
+        self.step_cycles = ms_to_cycles(step_ms).max(1);
+        self.last = rdtsc();
+        self.accumulated = 0;
+    }
+
+    /// Returns how many steps have elapsed since the previous call.
+    pub fn steps(&mut self) -> u32 {
+        let now = rdtsc();
+        self.accumulated += now - self.last;
+        self.last = now;
+
+        let steps = self.accumulated / self.step_cycles;
+        self.accumulated %= self.step_cycles;
+        (steps as u32).min(Self::MAX_STEPS)
+    }
+}
+
+impl Default for FixedStep {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Checks whether the tick handler that started at TSC value `start` ran longer than one
+/// timer period. Overruns mean the next timer interrupt was dropped, so each one is logged.
+pub fn check_deadline(start: u64) {
+    let elapsed = rdtsc() - start;
+    let period = cycles_per_tick();
+    if period != 0 && elapsed > period {
+        let missed = MISSED_DEADLINES.fetch_add(1, Ordering::SeqCst) + 1;
+        writeln!(serial(), "WARNING: tick took {elapsed} cycles, period is {period} ({missed} deadlines missed)").unwrap();
+    }
+}
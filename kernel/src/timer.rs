@@ -0,0 +1,112 @@
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// Hashed timer wheel, turned by the game task once per timer tick. A timer lives in the slot
+// matching its expiry tick and is checked whenever the wheel passes that slot; timers further
+// away than one revolution simply stay in their slot for another lap.
+// Timers come out of a fixed pool so scheduling never touches the heap. The wheel is locked
+// with interrupts disabled, since the tasks and interrupt handlers using it can preempt each other.
+const WHEEL_SLOTS: usize = 32;
+const MAX_TIMERS: usize = 64;
+
+#[derive(Clone, Copy)]
+enum Callback {
+    Plain(fn()),
+    WithArgument(fn(usize), usize),
+}
+
+#[derive(Clone, Copy)]
+struct Timer {
+    expires: u64,
+    callback: Callback,
+    next: Option<usize>,
+}
+
+struct Wheel {
+    now: u64,
+    slots: [Option<usize>; WHEEL_SLOTS],
+    timers: [Option<Timer>; MAX_TIMERS],
+}
+
+lazy_static! {
+    static ref WHEEL: Mutex<Wheel> = Mutex::new(Wheel {
+        now: 0,
+        slots: [None; WHEEL_SLOTS],
+        timers: [None; MAX_TIMERS],
+    });
+}
+
+/// Returns the number of timer ticks since the wheel started turning.
+pub fn now() -> u64 {
+    interrupts::without_interrupts(|| WHEEL.lock().now)
+}
+
+/// Runs `callback` on the game task once `delay_ticks` ticks have passed (at least one).
+/// Returns false if too many timers are already pending.
+pub fn schedule(delay_ticks: u64, callback: fn()) -> bool {
+    insert(delay_ticks, Callback::Plain(callback))
+}
+
+/// Like `schedule`, but runs `callback(argument)`.
+pub fn schedule_with_argument(delay_ticks: u64, callback: fn(usize), argument: usize) -> bool {
+    insert(delay_ticks, Callback::WithArgument(callback, argument))
+}
+
+fn insert(delay_ticks: u64, callback: Callback) -> bool {
+    interrupts::without_interrupts(|| insert_timer(delay_ticks, callback))
+}
+
+fn insert_timer(delay_ticks: u64, callback: Callback) -> bool {
+    let mut wheel = WHEEL.lock();
+    let Some(index) = wheel.timers.iter().position(Option::is_none) else {
+        return false;
+    };
+
+    let expires = wheel.now + delay_ticks.max(1);
+    let slot = expires as usize % WHEEL_SLOTS;
+    wheel.timers[index] = Some(Timer { expires, callback, next: wheel.slots[slot] });
+    wheel.slots[slot] = Some(index);
+    true
+}
+
+/// Advances the wheel by one tick and runs every timer that has expired.
+/// Must be called exactly once per timer tick.
+pub fn advance() {
+    let mut expired: [Option<Callback>; MAX_TIMERS] = [None; MAX_TIMERS];
+    let mut count = 0;
+
+    interrupts::without_interrupts(|| {
+        let mut wheel = WHEEL.lock();
+        wheel.now += 1;
+        let now = wheel.now;
+        let slot = now as usize % WHEEL_SLOTS;
+
+        let mut previous: Option<usize> = None;
+        let mut current = wheel.slots[slot];
+        while let Some(index) = current {
+            let timer = wheel.timers[index].unwrap();
+            current = timer.next;
+            if timer.expires <= now {
+                // unlink and free the timer
+                match previous {
+                    Some(previous) => wheel.timers[previous].as_mut().unwrap().next = timer.next,
+                    None => wheel.slots[slot] = timer.next,
+                }
+                wheel.timers[index] = None;
+                expired[count] = Some(timer.callback);
+                count += 1;
+            } else {
+                previous = Some(index);
+            }
+        }
+    });
+
+    // callbacks run without the lock held so they can schedule new timers
+    for callback in expired.iter().flatten() {
+        match *callback {
+            Callback::Plain(callback) => callback(),
+            Callback::WithArgument(callback, argument) => callback(argument),
+        }
+    }
+}
@@ -0,0 +1,246 @@
+use core::future::poll_fn;
+use core::mem;
+use core::net::{Ipv4Addr, SocketAddrV4};
+use core::task::{Poll, Waker};
+use kernel::sync::SpinLock;
+use x86_64::VirtAddr;
+use crate::ipv4::{self, PROTOCOL_UDP, Packet};
+use crate::memory::{self, PAGE_SIZE};
+use crate::net::{Error, Interface};
+use crate::task::WaitQueue;
+use crate::{time, timer};
+
+// UDP, datagrams between ports on two machines, over IPv4. A `UdpSocket` is bound to a local
+// port and sends from it to any address and port; the datagrams sent to its port wait in its
+// queue, the oldest first, until it takes them. A socket's queue is in pages of its own, given
+// back when the socket is dropped, and holds a few datagrams; any more that arrive before it
+// is emptied are dropped, as UDP allows. The network task puts datagrams in the queues and
+// wakes the tasks and futures waiting on them. The sockets are locked with interrupts off, as
+// tasks look in their queues while deciding whether to block.
+pub const HEADER_SIZE: usize = 8;
+/// The most a datagram can carry.
+pub const MAX_DATAGRAM: usize = ipv4::MAX_PAYLOAD - HEADER_SIZE;
+const MAX_SOCKETS: usize = 16;
+const QUEUE_LENGTH: usize = 4;
+const QUEUE_PAGES: u64 = mem::size_of::<Queue>().div_ceil(PAGE_SIZE as usize) as u64;
+// Where ports picked for sockets bound to port 0 come from
+const FIRST_EPHEMERAL: u16 = 49152;
+
+struct Datagram {
+    source: SocketAddrV4,
+    length: usize,
+    data: [u8; MAX_DATAGRAM],
+}
+
+// The datagrams waiting for a socket, in a ring
+struct Queue {
+    datagrams: [Datagram; QUEUE_LENGTH],
+    start: usize,
+    length: usize,
+}
+
+struct Slot {
+    port: u16,
+    queue: &'static mut Queue,
+    // The future waiting for a datagram, if there is one
+    waker: Option<Waker>,
+}
+
+static SOCKETS: SpinLock<[Option<Slot>; MAX_SOCKETS]> = SpinLock::new([const { None }; MAX_SOCKETS]);
+// Tasks waiting for a datagram, woken by every one that arrives
+static RECEIVED: WaitQueue = WaitQueue::new();
+static NEXT_EPHEMERAL: SpinLock<u16> = SpinLock::new(FIRST_EPHEMERAL);
+
+/// Takes the UDP packets the cards receive from now on. Called once during boot.
+pub fn init() {
+    ipv4::register(PROTOCOL_UDP, receive);
+}
+
+/// A UDP port bound on this machine, which datagrams are sent from and received on. The port
+/// is free again once it is dropped.
+pub struct UdpSocket {
+    slot: usize,
+    port: u16,
+}
+
+impl UdpSocket {
+    /// Binds `port`, or a free port from 49152 up if it is 0.
+    pub fn bind(port: u16) -> Result<UdpSocket, Error> {
+        let mut sockets = SOCKETS.lock_irq();
+        let in_use = |port| sockets.iter().flatten().any(|slot| slot.port == port);
+        let port = match port {
+            0 => {
+                // Round from where the last one was picked, so a port isn't reused straight away
+                let count = u16::MAX - FIRST_EPHEMERAL + 1;
+                let mut next = NEXT_EPHEMERAL.lock();
+                let port = (0..count).map(|offset| FIRST_EPHEMERAL + (*next - FIRST_EPHEMERAL + offset) % count)
+                    .find(|&port| !in_use(port)).ok_or(Error::AddressInUse)?;
+                *next = port.checked_add(1).unwrap_or(FIRST_EPHEMERAL);
+                port
+            },
+            port if in_use(port) => return Err(Error::AddressInUse),
+            port => port,
+        };
+        let slot = sockets.iter().position(Option::is_none).ok_or(Error::TooManySockets)?;
+        let pages = memory::alloc_guarded_pages(QUEUE_PAGES).ok_or(Error::TooManySockets)?;
+        let queue = unsafe { &mut *pages.as_mut_ptr::<Queue>() };
+        queue.start = 0;
+        queue.length = 0;
+        sockets[slot] = Some(Slot { port, queue, waker: None });
+        Ok(UdpSocket { slot, port })
+    }
+
+    /// The port the socket is bound to.
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    /// Sends `data`, at most `MAX_DATAGRAM` bytes, to `destination`. Waits for ARP to find
+    /// the next hop, unless on the network task.
+    pub fn send_to(&self, data: &[u8], destination: SocketAddrV4) -> Result<(), Error> {
+        let (interface, next_hop) = ipv4::route(*destination.ip()).ok_or(Error::NoRoute)?;
+        self.send_from(interface, next_hop, data, destination)
+    }
+
+    /// Like `send_to`, but never waits for ARP: if the next hop's MAC address isn't known yet,
+    /// asks for it and fails with `Unreachable`, so a later datagram can go.
+    pub fn try_send_to(&self, data: &[u8], destination: SocketAddrV4) -> Result<(), Error> {
+        let (interface, next_hop) = ipv4::route(*destination.ip()).ok_or(Error::NoRoute)?;
+        self.send_datagram(interface, next_hop, data, destination, false)
+    }
+
+    /// Like `send_to`, but through `interface` to `next_hop`, for datagrams that have to go
+    /// out of a particular card, even one without an address yet.
+    pub fn send_from(&self, interface: &Interface, next_hop: Ipv4Addr, data: &[u8], destination: SocketAddrV4) -> Result<(), Error> {
+        self.send_datagram(interface, next_hop, data, destination, true)
+    }
+
+    // Sends a datagram, waiting for ARP if `wait` and not on the network task
+    fn send_datagram(&self, interface: &Interface, next_hop: Ipv4Addr, data: &[u8], destination: SocketAddrV4, wait: bool) -> Result<(), Error> {
+        if data.len() > MAX_DATAGRAM {
+            return Err(Error::TooLong);
+        }
+        let mut datagram = [0; ipv4::MAX_PAYLOAD];
+        let length = HEADER_SIZE + data.len();
+        datagram[0..2].copy_from_slice(&self.port.to_be_bytes());
+        datagram[2..4].copy_from_slice(&destination.port().to_be_bytes());
+        datagram[4..6].copy_from_slice(&(length as u16).to_be_bytes());
+        datagram[HEADER_SIZE..length].copy_from_slice(data);
+        let source = interface.ipv4().unwrap_or(Ipv4Addr::UNSPECIFIED);
+        // A checksum that comes out as 0 is sent as all ones, as 0 means there is none
+        let checksum = match ipv4::pseudo_header_checksum(source, *destination.ip(), PROTOCOL_UDP, &datagram[..length]) {
+            0 => 0xFFFF,
+            checksum => checksum,
+        };
+        datagram[6..8].copy_from_slice(&checksum.to_be_bytes());
+        match wait {
+            true => ipv4::send_from(interface, next_hop, *destination.ip(), PROTOCOL_UDP, &datagram[..length]),
+            false => ipv4::try_send_from(interface, next_hop, *destination.ip(), PROTOCOL_UDP, &datagram[..length]),
+        }
+    }
+
+    /// Moves the oldest datagram waiting for the socket into `buffer`, cutting it short if it
+    /// doesn't fit, and returns its length and where it came from, or None if none is waiting.
+    pub fn try_recv_from(&self, buffer: &mut [u8]) -> Option<(usize, SocketAddrV4)> {
+        let mut sockets = SOCKETS.lock_irq();
+        let queue = &mut sockets[self.slot].as_mut().unwrap().queue;
+        if queue.length == 0 {
+            return None;
+        }
+        let datagram = &queue.datagrams[queue.start];
+        let length = datagram.length.min(buffer.len());
+        buffer[..length].copy_from_slice(&datagram.data[..length]);
+        let source = datagram.source;
+        queue.start = (queue.start + 1) % QUEUE_LENGTH;
+        queue.length -= 1;
+        Some((length, source))
+    }
+
+    /// Like `try_recv_from`, but blocks the calling task until a datagram arrives. Not to be
+    /// called on the network task.
+    pub fn recv_from(&self, buffer: &mut [u8]) -> (usize, SocketAddrV4) {
+        let mut received = None;
+        RECEIVED.wait_until(|| {
+            received = self.try_recv_from(buffer);
+            received.is_some()
+        });
+        // The wait only ends early if the task is being killed, which it doesn't come back from
+        received.unwrap()
+    }
+
+    /// Like `recv_from`, but gives up and returns None after `ms` milliseconds.
+    pub fn recv_from_timeout(&self, buffer: &mut [u8], ms: u64) -> Option<(usize, SocketAddrV4)> {
+        let until = timer::now() + time::ms_to_ticks(ms);
+        timer::schedule(time::ms_to_ticks(ms), || RECEIVED.notify());
+        let mut received = None;
+        RECEIVED.wait_until(|| {
+            received = self.try_recv_from(buffer);
+            received.is_some() || timer::now() >= until
+        });
+        received
+    }
+
+    /// Like `recv_from`, for async code: waits for a datagram without blocking the executor.
+    /// Only one future should wait on a socket at a time.
+    pub async fn recv_from_async(&self, buffer: &mut [u8]) -> (usize, SocketAddrV4) {
+        poll_fn(|context| {
+            if let Some(received) = self.try_recv_from(buffer) {
+                return Poll::Ready(received);
+            }
+            SOCKETS.lock_irq()[self.slot].as_mut().unwrap().waker = Some(context.waker().clone());
+            // A datagram may have arrived before the waker was in place
+            match self.try_recv_from(buffer) {
+                Some(received) => Poll::Ready(received),
+                None => Poll::Pending,
+            }
+        }).await
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        if let Some(slot) = SOCKETS.lock_irq()[self.slot].take() {
+            memory::free_pages(VirtAddr::from_ptr(slot.queue as *mut Queue), QUEUE_PAGES);
+        }
+    }
+}
+
+// Checks a datagram and puts it in the queue of the socket bound to its port
+fn receive(_interface: &'static Interface, packet: &Packet) {
+    let datagram = packet.payload;
+    if datagram.len() < HEADER_SIZE {
+        return;
+    }
+    let length = usize::from(u16::from_be_bytes([datagram[4], datagram[5]]));
+    if length < HEADER_SIZE || length > datagram.len() {
+        return;
+    }
+    let datagram = &datagram[..length];
+    let sent_checksum = u16::from_be_bytes([datagram[6], datagram[7]]);
+    if sent_checksum != 0 && ipv4::pseudo_header_checksum(packet.source, packet.destination, PROTOCOL_UDP, datagram) != 0 {
+        return;
+    }
+    let source = SocketAddrV4::new(packet.source, u16::from_be_bytes([datagram[0], datagram[1]]));
+    let port = u16::from_be_bytes([datagram[2], datagram[3]]);
+    let data = &datagram[HEADER_SIZE..];
+
+    let mut sockets = SOCKETS.lock_irq();
+    let Some(slot) = sockets.iter_mut().flatten().find(|slot| slot.port == port) else {
+        return;
+    };
+    let queue = &mut slot.queue;
+    if queue.length == QUEUE_LENGTH {
+        return;
+    }
+    let entry = &mut queue.datagrams[(queue.start + queue.length) % QUEUE_LENGTH];
+    entry.source = source;
+    entry.length = data.len();
+    entry.data[..data.len()].copy_from_slice(data);
+    queue.length += 1;
+    let waker = slot.waker.take();
+    drop(sockets);
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+    RECEIVED.notify();
+}
@@ -0,0 +1,182 @@
+use core::fmt;
+use crate::screen::{screenwriter, CHAR_HEIGHT, CHAR_WIDTH};
+use crate::settings;
+
+// A small widget toolkit on top of the screen writer, used by the menus and games.
+// All positions and sizes are in pixels.
+
+pub type Color = (u8, u8, u8);
+
+pub const BLACK: Color = (0, 0, 0);
+pub const WHITE: Color = (255, 255, 255);
+pub const GREY: Color = (128, 128, 128);
+pub const HIGHLIGHT: Color = (0, 0, 160);
+
+const PADDING: usize = 8;
+
+/// Fills a rectangle with a solid colour, clipped to the screen.
+pub fn fill_rect(x: usize, y: usize, width: usize, height: usize, color: Color) {
+    let mut screen = screenwriter();
+    let (right, bottom) = ((x + width).min(screen.width()), (y + height).min(screen.height()));
+    for py in y..bottom {
+        for px in x..right {
+            screen.draw_pixel(px, py, color.0, color.1, color.2);
+        }
+    }
+}
+
+/// Draws a one pixel wide rectangle outline.
+pub fn draw_frame(x: usize, y: usize, width: usize, height: usize, color: Color) {
+    fill_rect(x, y, width, 1, color);
+    fill_rect(x, y + height - 1, width, 1, color);
+    fill_rect(x, y, 1, height, color);
+    fill_rect(x + width - 1, y, 1, height, color);
+}
+
+/// Width in pixels of `text` when drawn with `draw_label`.
+pub fn text_width(text: &str) -> usize {
+    text.chars().count() * CHAR_WIDTH
+}
+
+/// Draws a line of text with its top-left corner at (x, y).
+pub fn draw_label(x: usize, y: usize, text: &str, color: Color) {
+    screenwriter().draw_text(x, y, text, color.0, color.1, color.2);
+}
+
+/// Draws a line of text horizontally centred on the screen.
+pub fn draw_label_centered(y: usize, text: &str, color: Color) {
+    let x = screenwriter().width().saturating_sub(text_width(text)) / 2;
+    draw_label(x, y, text, color);
+}
+
+// Seven-segment digits: bit 0 is the top segment, then clockwise, with bit 6 in the middle
+const SEGMENTS: [u8; 10] = [0x3F, 0x06, 0x5B, 0x4F, 0x66, 0x6D, 0x7D, 0x07, 0x7F, 0x6F];
+pub const DIGIT_WIDTH: usize = 14;
+pub const DIGIT_HEIGHT: usize = 24;
+const SEGMENT_THICKNESS: usize = 3;
+const DIGIT_SPACING: usize = 4;
+
+/// Draws a single decimal digit (0-9) as a seven-segment display.
+pub fn draw_digit(x: usize, y: usize, digit: u8, color: Color) {
+    let (w, h, t) = (DIGIT_WIDTH, DIGIT_HEIGHT, SEGMENT_THICKNESS);
+    let half = h / 2;
+    let segments = [
+        (x, y, w, t),                       // top
+        (x + w - t, y, t, half),            // upper right
+        (x + w - t, y + half, t, h - half), // lower right
+        (x, y + h - t, w, t),               // bottom
+        (x, y + half, t, h - half),         // lower left
+        (x, y, t, half),                    // upper left
+        (x, y + (h - t) / 2, w, t),         // middle
+    ];
+    let lit = SEGMENTS[digit as usize % 10];
+    for (i, (sx, sy, sw, sh)) in segments.into_iter().enumerate() {
+        if lit & (1 << i) != 0 {
+            fill_rect(sx, sy, sw, sh, color);
+        }
+    }
+}
+
+/// Width in pixels of `value` when drawn with `draw_number`.
+pub fn number_width(value: u32) -> usize {
+    let digits = value.checked_ilog10().unwrap_or(0) as usize + 1;
+    digits * (DIGIT_WIDTH + DIGIT_SPACING) - DIGIT_SPACING
+}
+
+/// Draws `value` in seven-segment digits with its top-left corner at (x, y).
+pub fn draw_number(x: usize, y: usize, value: u32, color: Color) {
+    let width = number_width(value);
+    let mut rest = value;
+    let mut digit_x = x + width - DIGIT_WIDTH;
+    loop {
+        draw_digit(digit_x, y, (rest % 10) as u8, color);
+        rest /= 10;
+        if rest == 0 {
+            break;
+        }
+        digit_x -= DIGIT_WIDTH + DIGIT_SPACING;
+    }
+}
+
+/// A framed, vertically stacked list of entries with one highlighted selection.
+pub struct ListView<'a> {
+    pub title: &'a str,
+    pub items: &'a [&'a str],
+    pub selected: usize,
+}
+
+impl ListView<'_> {
+    /// Draws the list in the middle of the screen.
+    pub fn draw_centered(&self) {
+        let row_height = CHAR_HEIGHT + PADDING;
+        let content_width = self.items.iter()
+            .map(|item| text_width(item) + 2 * CHAR_WIDTH)
+            .chain(core::iter::once(text_width(self.title)))
+            .max()
+            .unwrap_or(0);
+        let width = content_width + 2 * PADDING;
+        let height = (self.items.len() + 1) * row_height + 2 * PADDING;
+
+        let (screen_width, screen_height) = {
+            let screen = screenwriter();
+            (screen.width(), screen.height())
+        };
+        let x = screen_width.saturating_sub(width) / 2;
+        let y = screen_height.saturating_sub(height) / 2;
+
+        let theme = settings::theme();
+        fill_rect(x, y, width, height, BLACK);
+        draw_frame(x, y, width, height, theme.foreground);
+        draw_label(x + PADDING, y + PADDING, self.title, theme.accent);
+
+        for (i, item) in self.items.iter().enumerate() {
+            let item_y = y + PADDING + (i + 1) * row_height;
+            if i == self.selected {
+                fill_rect(x + 1, item_y - PADDING / 2, width - 2, row_height, HIGHLIGHT);
+                draw_label(x + PADDING, item_y, ">", theme.accent);
+            }
+            draw_label(x + PADDING + 2 * CHAR_WIDTH, item_y, item, theme.foreground);
+        }
+    }
+}
+
+/// Fixed-size text buffer for formatting labels with `write!` without touching the heap.
+/// Text that does not fit is cut off.
+#[derive(Clone, Copy)]
+pub struct TextBuffer<const N: usize> {
+    bytes: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> TextBuffer<N> {
+    pub const fn new() -> Self {
+        TextBuffer { bytes: [0; N], len: 0 }
+    }
+
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or("")
+    }
+}
+
+impl<const N: usize> Default for TextBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> fmt::Write for TextBuffer<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut end = s.len().min(N - self.len);
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.bytes[self.len..self.len + end].copy_from_slice(&s.as_bytes()[..end]);
+        self.len += end;
+        Ok(())
+    }
+}
+
+/// Moves a list selection by `delta` entries, wrapping around at either end.
+pub fn move_selection(selected: usize, len: usize, delta: isize) -> usize {
+    (selected as isize + delta).rem_euclid(len as isize) as usize
+}
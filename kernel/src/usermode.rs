@@ -0,0 +1,371 @@
+use core::arch::global_asm;
+use core::fmt::Write;
+use kernel::serial;
+use kernel::sync::SpinLock;
+use kernel::UserFault;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+use crate::{console, display, elf, fs, initrd, process, shm, syscall, task, time};
+use crate::gdt::{self, KERNEL_STACK_OFFSET, TSS};
+use crate::memory::{self, AddressSpace, PAGE_SIZE, USER_END, USER_START};
+use crate::process::{Handle, Pid};
+
+// Runs user programs in ring 3, on the task that starts them. `load` loads the program into an
+// address space of its own, with its arguments on the stack, and `run` switches to it and
+// drops to ring 3 at the program's entry point.
+// Where the kernel stack was left off goes in the TSS, so interrupts and system calls from
+// the program carry on below it; when the program exits or faults, the kernel goes back to
+// that point and `run` returns.
+//
+// The task runs in the program's address space for as long as the program runs, and the
+// scheduler switches address spaces along with tasks, so programs run side by side, each
+// seeing only its own memory and the kernel's, which ring 3 can't touch.
+//
+// A program's memory is laid out in the user region as: the program itself, then its heap,
+// which `brk` moves the end of, up to the middle of the region; above that the memory `mmap`
+// hands out, fresh or shared memory objects, from the bottom up; and the stack at the top. The arguments are at the top of
+// the stack: the strings, and below them a null-terminated array of pointers to them, whose
+// length and address the program gets as its first two arguments.
+const STACK_PAGES: u64 = 16;
+const STACK_SIZE: u64 = STACK_PAGES * PAGE_SIZE;
+const MAPPED_START: u64 = USER_START + (USER_END - USER_START) / 2;
+const MAPPED_END: u64 = USER_END - STACK_SIZE;
+// Protection bits `mmap` takes; the memory can always be read
+const PROTECT_WRITE: u64 = 2;
+const PROTECT_EXECUTE: u64 = 4;
+// `mmap` flags: map the shared memory object of the handle given, rather than fresh memory
+const MAP_SHARED: u64 = 1;
+// The most shared memory objects a process can map
+const MAX_SHARED_MAPPINGS: usize = 8;
+// The most stack the arguments may take
+const MAX_ARGUMENTS_SIZE: u64 = PAGE_SIZE;
+
+// How the program that just left ended, for `run` to return. Interrupts stay disabled from
+// `leave` until `run` takes it, so no other program can end meanwhile.
+static EXIT: SpinLock<Option<Exit>> = SpinLock::new(None);
+
+/// How a user program ended.
+#[derive(Debug, Clone, Copy)]
+pub enum Exit {
+    /// It exited with this code.
+    Exited(u64),
+    /// It was stopped for raising this exception.
+    Faulted(UserFault),
+    /// Its task was killed.
+    Killed,
+}
+
+/// Why a program couldn't be started.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    NoSuchProgram,
+    Load(elf::Error),
+    /// The arguments don't fit on the stack.
+    ArgumentsTooLong,
+    /// The process table is full of running processes.
+    TooManyProcesses,
+    /// No task could be started to run it.
+    NoThread,
+}
+
+/// Where a loaded program starts: its entry point, its stack, and the arguments on it.
+#[derive(Debug, Clone, Copy)]
+pub struct Start {
+    entry: VirtAddr,
+    stack: VirtAddr,
+    argument_count: u64,
+    arguments: VirtAddr,
+}
+
+/// The memory of a process: its address space, and where its heap and `mmap` memory are.
+pub struct UserMemory {
+    space: AddressSpace,
+    heap_start: VirtAddr,
+    // The end of the heap the program asked for, and of the pages mapped for it, which only grows
+    heap_end: VirtAddr,
+    heap_mapped: VirtAddr,
+    // Where `mmap` maps next
+    next_mapping: VirtAddr,
+    // The shared memory objects mapped, which are given up once the address space is freed
+    shared: [Option<usize>; MAX_SHARED_MAPPINGS],
+}
+
+/// Registers the system calls for ending programs, for time and for memory. Called once
+/// during boot.
+pub fn init() {
+    syscall::register(syscall::EXIT, exit);
+    syscall::register(syscall::SLEEP, sleep);
+    syscall::register(syscall::GET_TIME, get_time);
+    syscall::register(syscall::BRK, brk);
+    syscall::register(syscall::MMAP, mmap);
+}
+
+impl UserMemory {
+    /// The bytes the heap has.
+    pub fn heap_size(&self) -> u64 {
+        self.heap_end - self.heap_start
+    }
+
+    /// The bytes `mmap` has mapped.
+    pub fn mapped_size(&self) -> u64 {
+        self.next_mapping.as_u64() - MAPPED_START
+    }
+
+    // Memory the heap gives back stays mapped, to be used again when it grows
+    fn move_break(&mut self, end: u64) -> Result<u64, syscall::Error> {
+        if end == 0 {
+            return Ok(self.heap_end.as_u64());
+        }
+        let end = VirtAddr::try_new(end).map_err(|_| syscall::Error::BadArgument)?;
+        if end < self.heap_start || end.as_u64() > MAPPED_START {
+            return Err(syscall::Error::BadArgument);
+        }
+        if end > self.heap_mapped {
+            let mapped = end.align_up(PAGE_SIZE);
+            let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+            if !self.space.map(self.heap_mapped, mapped - self.heap_mapped, flags) {
+                return Err(syscall::Error::OutOfMemory);
+            }
+            self.heap_mapped = mapped;
+        }
+        self.heap_end = end;
+        Ok(end.as_u64())
+    }
+
+    fn map(&mut self, length: u64, flags: PageTableFlags) -> Result<u64, syscall::Error> {
+        let start = self.next_mapping;
+        let length = length.checked_next_multiple_of(PAGE_SIZE).ok_or(syscall::Error::BadArgument)?;
+        if length > MAPPED_END - start.as_u64() || !self.space.map(start, length, flags) {
+            return Err(syscall::Error::OutOfMemory);
+        }
+        self.next_mapping = start + length;
+        Ok(start.as_u64())
+    }
+
+    fn map_shared(&mut self, id: usize, length: u64, flags: PageTableFlags) -> Result<u64, syscall::Error> {
+        let start = self.next_mapping;
+        let slot = self.shared.iter().position(Option::is_none).ok_or(syscall::Error::OutOfMemory)?;
+        if length > MAPPED_END - start.as_u64() {
+            return Err(syscall::Error::OutOfMemory);
+        }
+        shm::map(id, &mut self.space, start, length, flags)?;
+        self.shared[slot] = Some(id);
+        self.next_mapping = (start + length).align_up(PAGE_SIZE);
+        Ok(start.as_u64())
+    }
+}
+
+impl Drop for UserMemory {
+    fn drop(&mut self) {
+        // The address space is freed after this, but it isn't in use and leaves shared memory be
+        self.shared.iter().flatten().for_each(|&id| shm::release(id));
+    }
+}
+
+/// Returns the name and image of the program at `path` in the initrd, if there is one. A
+/// path that is only a name is looked for in /bin.
+pub fn program(path: &str) -> Option<(&'static str, &'static [u8])> {
+    let file = if path.contains('/') {
+        initrd::open(path)?
+    } else {
+        initrd::files().find(|file| file.path.strip_prefix("bin/") == Some(path))?
+    };
+    let name = file.path.rsplit('/').next().unwrap();
+    Some((name, file.data))
+}
+
+/// Loads the program in `image` into a new process called `name`, to be run by `run`, with
+/// `name` and then `arguments` as its arguments, `standard` as handles 0 and 1 and `directory`
+/// as its current directory.
+pub fn load(name: &'static str, image: &[u8], arguments: &[&str], standard: [Handle; 2], directory: &fs::WorkingDirectory) -> Result<(Pid, Start), Error> {
+    let mut space = AddressSpace::new().ok_or(Error::Load(elf::Error::OutOfMemory))?;
+    let loaded = elf::load(image, &mut space).map_err(Error::Load)?;
+    let stack = VirtAddr::new(USER_END - STACK_SIZE);
+    if !space.map(stack, STACK_SIZE, PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE) {
+        return Err(Error::Load(elf::Error::OutOfMemory));
+    }
+    let heap_start = loaded.end.align_up(PAGE_SIZE);
+    if heap_start.as_u64() > MAPPED_START {
+        return Err(Error::Load(elf::Error::BadSegment));
+    }
+
+    let arguments = || core::iter::once(name).chain(arguments.iter().copied());
+    let argument_count = arguments().count() as u64;
+    // Each string with a null after it, which the zeroed stack has already
+    let strings_size: u64 = arguments().map(|argument| argument.len() as u64 + 1).sum();
+    let pointers_size = (argument_count + 1) * 8;
+    if strings_size + pointers_size > MAX_ARGUMENTS_SIZE {
+        return Err(Error::ArgumentsTooLong);
+    }
+    let mut string = VirtAddr::new(USER_END - strings_size);
+    let pointers = (string - pointers_size).align_down(16u64);
+    for (index, argument) in arguments().enumerate() {
+        let pointer = pointers + index as u64 * 8;
+        if !space.write(string, argument.as_bytes()) || !space.write(pointer, &string.as_u64().to_le_bytes()) {
+            return Err(Error::Load(elf::Error::OutOfMemory));
+        }
+        string += argument.len() as u64 + 1;
+    }
+
+    let next_mapping = VirtAddr::new(MAPPED_START);
+    let memory = UserMemory { space, heap_start, heap_end: heap_start, heap_mapped: heap_start, next_mapping, shared: [None; MAX_SHARED_MAPPINGS] };
+    let pid = process::create(name, memory, standard, directory).ok_or(Error::TooManyProcesses)?;
+    // Entered the way a function is called, a return address below an aligned stack
+    let start = Start { entry: loaded.entry, stack: pointers - 8u64, argument_count, arguments: pointers };
+    Ok((pid, start))
+}
+
+/// Runs process `pid`, which `load` returned with `start`, on the current task until it ends,
+/// and returns how it ended.
+pub fn run(pid: Pid, start: Start) -> Exit {
+    console::discard_input();
+    // A kill ends the program where its task waits, and `run` cleans up as usual
+    let on_kill = task::on_kill(|| leave(Exit::Killed));
+    let (code, data) = gdt::user_selectors();
+    process::with_process(pid, |process| {
+        process.thread = Some(task::current());
+        process.state = process::State::Running;
+        // The memory is only freed once the task is back in the kernel's address space
+        unsafe { task::set_page_table(process.memory.as_ref().unwrap().space.page_table()) };
+    });
+    unsafe {
+        let Start { entry, stack, argument_count, arguments } = start;
+        enter_user(entry.as_u64(), stack.as_u64(), code.0 as u64, data.0 as u64, argument_count, arguments.as_u64());
+    }
+    // Back from `leave_user`, with interrupts disabled
+    unsafe { task::set_page_table(memory::kernel_page_table()) };
+    let exit = EXIT.lock().take().unwrap();
+    let memory = process::end(pid, exit);
+    display::release(pid);
+    interrupts::enable();
+    drop(memory);
+    task::restore_on_kill(on_kill);
+    exit
+}
+
+/// Handles a user program raising an exception, by ending it and nothing else. Called by the
+/// exception handlers.
+pub fn fault(fault: UserFault) -> ! {
+    if let Some((name, pid)) = process::with_current(|process| (process.name, process.pid)) {
+        writeln!(serial(), "{name} ({pid}) faulted: {fault}").unwrap();
+    }
+    leave(Exit::Faulted(fault))
+}
+
+// Goes back to where `run` entered the program
+fn leave(exit: Exit) -> ! {
+    interrupts::disable();
+    *EXIT.lock() = Some(exit);
+    unsafe { leave_user() }
+}
+
+// exit(code)
+fn exit(arguments: [u64; 6]) -> Result<u64, syscall::Error> {
+    leave(Exit::Exited(arguments[0]))
+}
+
+// sleep(ms)
+fn sleep(arguments: [u64; 6]) -> Result<u64, syscall::Error> {
+    task::sleep(arguments[0]);
+    Ok(0)
+}
+
+// get_time(): milliseconds since boot
+fn get_time(_: [u64; 6]) -> Result<u64, syscall::Error> {
+    Ok(time::uptime_ms())
+}
+
+// brk(end): moves the end of the heap to `end`, or leaves it be if `end` is 0, and returns
+// where it is
+fn brk(arguments: [u64; 6]) -> Result<u64, syscall::Error> {
+    with_memory(|memory| memory.move_break(arguments[0]))
+}
+
+// mmap(length, protection, flags, handle): maps `length` bytes of zeroed memory, or with
+// MAP_SHARED the first `length` bytes of the shared memory object `handle` stands for,
+// readable and writable or executable as `protection` says, and returns where
+fn mmap(arguments: [u64; 6]) -> Result<u64, syscall::Error> {
+    let [length, protection, map_flags, handle, ..] = arguments;
+    if length == 0 || protection & !(PROTECT_WRITE | PROTECT_EXECUTE) != 0 || map_flags & !MAP_SHARED != 0 {
+        return Err(syscall::Error::BadArgument);
+    }
+    let mut flags = PageTableFlags::empty();
+    if protection & PROTECT_WRITE != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if protection & PROTECT_EXECUTE == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    if map_flags & MAP_SHARED != 0 {
+        let Some(Handle::Shared(id)) = process::handle(handle) else {
+            return Err(syscall::Error::BadHandle);
+        };
+        return with_memory(|memory| memory.map_shared(id, length, flags));
+    }
+    with_memory(|memory| memory.map(length, flags))
+}
+
+// Calls `f` on the memory of the calling process
+fn with_memory(f: impl FnOnce(&mut UserMemory) -> Result<u64, syscall::Error>) -> Result<u64, syscall::Error> {
+    process::with_current(|process| f(process.memory.as_mut().unwrap())).unwrap()
+}
+
+unsafe extern "C" {
+    // Saves the registers a function call must preserve and the stack pointer, and enters
+    // ring 3 at `entry` with the stack at `stack`, passing the program `first` and `second`.
+    fn enter_user(entry: u64, stack: u64, code_selector: u64, data_selector: u64, first: u64, second: u64);
+    // Returns from `enter_user` on the stack it saved.
+    fn leave_user() -> !;
+}
+
+global_asm!(
+    ".global enter_user",
+    "enter_user:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rip + {tss} + {kernel_stack}], rsp",
+    // The frame `iretq` pops: instruction, code segment, flags with interrupts enabled,
+    // stack, stack segment
+    "or rdx, 3",
+    "or rcx, 3",
+    "push rcx",
+    "push rsi",
+    "push 0x202",
+    "push rdx",
+    "push rdi",
+    // The program's arguments, and nothing else of the kernel's left for it to see
+    "mov rdi, r8",
+    "mov rsi, r9",
+    "xor eax, eax",
+    "xor ebx, ebx",
+    "xor ecx, ecx",
+    "xor edx, edx",
+    "xor ebp, ebp",
+    "xor r8d, r8d",
+    "xor r9d, r9d",
+    "xor r10d, r10d",
+    "xor r11d, r11d",
+    "xor r12d, r12d",
+    "xor r13d, r13d",
+    "xor r14d, r14d",
+    "xor r15d, r15d",
+    "iretq",
+
+    ".global leave_user",
+    "leave_user:",
+    "mov rsp, [rip + {tss} + {kernel_stack}]",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+    tss = sym TSS,
+    kernel_stack = const KERNEL_STACK_OFFSET,
+);
@@ -0,0 +1,274 @@
+use core::sync::atomic::{Ordering, fence};
+use x86_64::PhysAddr;
+use x86_64::instructions::port::Port;
+use crate::memory::{self, Dma, PAGE_SIZE};
+use crate::pci::PciDevice;
+
+// Virtio devices, the paravirtual ones QEMU and KVM offer, through the legacy PCI transport:
+// the registers are I/O ports from BAR 0, followed by the device's own configuration. The
+// driver hands the device buffers through virtqueues, rings in memory they share:
+//
+// - the descriptor table, where each descriptor is a buffer's physical address and length,
+//   whether the device writes it, and the next descriptor if it is chained to one
+// - the available ring, the chains the driver has put there for the device, in order
+// - the used ring, on the next page boundary, the chains the device is done with and how much
+//   it wrote to each
+//
+// Both rings' indexes only go up, wrapping at 2^16, and are taken modulo the queue's size.
+pub const VENDOR: u16 = 0x1AF4;
+
+// Registers, as offsets from BAR 0
+const DEVICE_FEATURES: u16 = 0x00;
+const DRIVER_FEATURES: u16 = 0x04;
+const QUEUE_ADDRESS: u16 = 0x08;
+const QUEUE_SIZE: u16 = 0x0C;
+const QUEUE_SELECT: u16 = 0x0E;
+const QUEUE_NOTIFY: u16 = 0x10;
+const DEVICE_STATUS: u16 = 0x12;
+const INTERRUPT_STATUS: u16 = 0x13;
+// Where the device's configuration starts, with MSI-X off
+const DEVICE_CONFIG: u16 = 0x14;
+
+// Device status bits: the guest has noticed the device, knows how to drive it, and is ready
+const ACKNOWLEDGE: u8 = 1;
+const DRIVER: u8 = 2;
+const DRIVER_OK: u8 = 4;
+const FAILED: u8 = 0x80;
+
+/// The interrupt status bit for a virtqueue having used buffers.
+pub const QUEUE_INTERRUPT: u8 = 1;
+/// The interrupt status bit for the device's configuration having changed.
+pub const CONFIG_INTERRUPT: u8 = 2;
+
+// Descriptor flags: chained to the next one, and written by the device
+const NEXT: u16 = 1;
+const WRITE: u16 = 2;
+
+const DESCRIPTOR_SIZE: usize = 16;
+// The rings' headers: flags and index, with an event index after the ring itself
+const RING_HEADER: usize = 4;
+const USED_ELEMENT_SIZE: usize = 8;
+// The queue's address is given as a page number
+const QUEUE_PAGE_SHIFT: u32 = 12;
+
+/// A virtio device.
+pub struct Device {
+    io: u16,
+    pci: PciDevice,
+}
+
+impl Device {
+    /// Resets the device `pci` and tells it a driver is setting it up. Returns None if it has
+    /// no I/O ports, which only devices without the legacy transport lack.
+    pub fn new(pci: PciDevice) -> Option<Device> {
+        let io = pci.io_bar(0)?;
+        pci.enable_bus_master();
+        let device = Device { io, pci };
+        device.write8(DEVICE_STATUS, 0);
+        device.write8(DEVICE_STATUS, ACKNOWLEDGE | DRIVER);
+        Some(device)
+    }
+
+    fn read8(&self, register: u16) -> u8 {
+        unsafe { Port::<u8>::new(self.io + register).read() }
+    }
+
+    fn write8(&self, register: u16, value: u8) {
+        unsafe { Port::<u8>::new(self.io + register).write(value) }
+    }
+
+    fn read16(&self, register: u16) -> u16 {
+        unsafe { Port::<u16>::new(self.io + register).read() }
+    }
+
+    fn write16(&self, register: u16, value: u16) {
+        unsafe { Port::<u16>::new(self.io + register).write(value) }
+    }
+
+    fn read32(&self, register: u16) -> u32 {
+        unsafe { Port::<u32>::new(self.io + register).read() }
+    }
+
+    fn write32(&self, register: u16, value: u32) {
+        unsafe { Port::<u32>::new(self.io + register).write(value) }
+    }
+
+    /// The I/O APIC input the device raises its interrupt on.
+    pub fn interrupt_line(&self) -> u8 {
+        self.pci.interrupt_line()
+    }
+
+    /// Takes the features in `wanted` that the device offers, and returns which those are.
+    pub fn negotiate(&self, wanted: u32) -> u32 {
+        let features = self.read32(DEVICE_FEATURES) & wanted;
+        self.write32(DRIVER_FEATURES, features);
+        features
+    }
+
+    /// Sets up virtqueue `index` in fresh DMA memory, or returns None if the device has no such
+    /// queue or there is no memory for it.
+    pub fn queue(&self, index: u16) -> Option<Virtqueue> {
+        self.write16(QUEUE_SELECT, index);
+        let size = self.read16(QUEUE_SIZE);
+        if size == 0 {
+            return None;
+        }
+        let used_offset = (usize::from(size) * DESCRIPTOR_SIZE + RING_HEADER + 2 * usize::from(size) + 2).next_multiple_of(PAGE_SIZE as usize);
+        let used_size = RING_HEADER + USED_ELEMENT_SIZE * usize::from(size) + 2;
+        let memory = memory::alloc_dma((used_offset + used_size).div_ceil(PAGE_SIZE as usize) as u64)?;
+        let mut queue = Virtqueue { index, size, memory, used_offset, free: 0, free_count: size, available: 0, used: 0 };
+        // Every descriptor starts out free, each chained to the next
+        for descriptor in 0..size {
+            queue.set_descriptor(descriptor, PhysAddr::zero(), 0, NEXT, (descriptor + 1) % size);
+        }
+        self.write32(QUEUE_ADDRESS, (memory.physical.as_u64() >> QUEUE_PAGE_SHIFT) as u32);
+        Some(queue)
+    }
+
+    /// Tells the device the driver has set it up.
+    pub fn ready(&self) {
+        self.write8(DEVICE_STATUS, self.read8(DEVICE_STATUS) | DRIVER_OK);
+    }
+
+    /// Tells the device the driver has given up on it.
+    pub fn fail(&self) {
+        self.write8(DEVICE_STATUS, self.read8(DEVICE_STATUS) | FAILED);
+    }
+
+    /// Tells the device there are new chains in `queue`'s available ring.
+    pub fn notify(&self, queue: &Virtqueue) {
+        fence(Ordering::SeqCst);
+        self.write16(QUEUE_NOTIFY, queue.index);
+    }
+
+    /// Reads the interrupt status, which also acknowledges the interrupt.
+    pub fn interrupt_status(&self) -> u8 {
+        self.read8(INTERRUPT_STATUS)
+    }
+
+    /// Reads the byte at `offset` in the device's configuration.
+    pub fn config8(&self, offset: u16) -> u8 {
+        self.read8(DEVICE_CONFIG + offset)
+    }
+
+    /// Reads the 16 bits at `offset` in the device's configuration.
+    pub fn config16(&self, offset: u16) -> u16 {
+        self.read16(DEVICE_CONFIG + offset)
+    }
+
+    /// Reads the 32 bits at `offset` in the device's configuration.
+    pub fn config32(&self, offset: u16) -> u32 {
+        self.read32(DEVICE_CONFIG + offset)
+    }
+
+    /// Reads the 64 bits at `offset` in the device's configuration, low half first.
+    pub fn config64(&self, offset: u16) -> u64 {
+        u64::from(self.config32(offset)) | u64::from(self.config32(offset + 4)) << 32
+    }
+}
+
+/// A virtqueue, with the descriptors the driver isn't using kept chained together.
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    memory: Dma,
+    used_offset: usize,
+    // The first free descriptor, and how many there are
+    free: u16,
+    free_count: u16,
+    // The available ring's index as the driver last set it, and the used ring's as it last
+    // read it
+    available: u16,
+    used: u16,
+}
+
+// Only used by one driver, behind its lock
+unsafe impl Send for Virtqueue {}
+
+impl Virtqueue {
+    fn pointer<T>(&self, offset: usize) -> *mut T {
+        (self.memory.start + offset as u64).as_mut_ptr()
+    }
+
+    fn set_descriptor(&mut self, descriptor: u16, address: PhysAddr, length: u32, flags: u16, next: u16) {
+        let offset = usize::from(descriptor) * DESCRIPTOR_SIZE;
+        unsafe {
+            self.pointer::<u64>(offset).write_volatile(address.as_u64());
+            self.pointer::<u32>(offset + 8).write_volatile(length);
+            self.pointer::<u16>(offset + 12).write_volatile(flags);
+            self.pointer::<u16>(offset + 14).write_volatile(next);
+        }
+    }
+
+    fn next_descriptor(&self, descriptor: u16) -> (u16, bool) {
+        let offset = usize::from(descriptor) * DESCRIPTOR_SIZE;
+        let flags = unsafe { self.pointer::<u16>(offset + 12).read_volatile() };
+        let next = unsafe { self.pointer::<u16>(offset + 14).read_volatile() };
+        (next, flags & NEXT != 0)
+    }
+
+    /// Puts `buffers`, each a physical address, a length and whether the device writes it, in
+    /// the available ring as one chain, and returns the chain's id, which `take_used` gives
+    /// back once the device is done with it. The device still has to be notified. Returns None
+    /// if there aren't enough free descriptors.
+    pub fn add(&mut self, buffers: &[(PhysAddr, u32, bool)]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > usize::from(self.free_count) {
+            return None;
+        }
+        let head = self.free;
+        let mut descriptor = head;
+        for (index, &(address, length, written)) in buffers.iter().enumerate() {
+            let (next, _) = self.next_descriptor(descriptor);
+            let last = index + 1 == buffers.len();
+            let flags = if written { WRITE } else { 0 } | if last { 0 } else { NEXT };
+            self.set_descriptor(descriptor, address, length, flags, next);
+            if last {
+                self.free = next;
+            }
+            descriptor = next;
+        }
+        self.free_count -= buffers.len() as u16;
+
+        let ring = usize::from(self.size) * DESCRIPTOR_SIZE;
+        let slot = usize::from(self.available % self.size);
+        unsafe { self.pointer::<u16>(ring + RING_HEADER + 2 * slot).write_volatile(head) };
+        // The device mustn't see the new index before the entry it covers
+        fence(Ordering::SeqCst);
+        self.available = self.available.wrapping_add(1);
+        unsafe { self.pointer::<u16>(ring + 2).write_volatile(self.available) };
+        Some(head)
+    }
+
+    /// Returns true if the device has finished with a chain `take_used` hasn't returned yet.
+    pub fn has_used(&self) -> bool {
+        unsafe { self.pointer::<u16>(self.used_offset + 2).read_volatile() != self.used }
+    }
+
+    /// Returns the id of a chain the device has finished with and how many bytes it wrote,
+    /// and frees its descriptors, or None if it hasn't finished any more.
+    pub fn take_used(&mut self) -> Option<(u16, u32)> {
+        if !self.has_used() {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+        let element = self.used_offset + RING_HEADER + USED_ELEMENT_SIZE * usize::from(self.used % self.size);
+        let (head, written) = unsafe { (self.pointer::<u32>(element).read_volatile() as u16, self.pointer::<u32>(element + 4).read_volatile()) };
+        self.used = self.used.wrapping_add(1);
+
+        // The chain goes back on the front of the free ones
+        let mut last = head;
+        let mut length = 1;
+        while let (next, true) = self.next_descriptor(last) {
+            last = next;
+            length += 1;
+        }
+        let offset = usize::from(last) * DESCRIPTOR_SIZE;
+        unsafe {
+            self.pointer::<u16>(offset + 12).write_volatile(NEXT);
+            self.pointer::<u16>(offset + 14).write_volatile(self.free);
+        }
+        self.free = head;
+        self.free_count += length;
+        Some((head, written))
+    }
+}
@@ -0,0 +1,371 @@
+use alloc::boxed::Box;
+use core::fmt::Write;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use kernel::serial;
+use kernel::sync::SpinLock;
+use x86_64::instructions::interrupts;
+use crate::block::{self, BLOCK_SIZE, BlockDevice, Callback, Completion, Error, Operation, Request};
+use crate::memory::{self, Dma, PAGE_SIZE};
+use crate::pci::{self, Match, PciDevice};
+use crate::task::WaitQueue;
+use crate::virtio::{self, Device, Virtqueue};
+use crate::irq;
+
+// Virtio block devices, the quickest disks under QEMU and KVM. A request is a chain of three
+// buffers on the device's one virtqueue: a header saying what to do and from which sector,
+// the data, and a status byte the device writes when it is done. Requests go one at a time,
+// with the data in a DMA bounce buffer, which a task claims for as long as it copies to it,
+// waits for the device and copies from it. The device raises its interrupt when it has
+// finished a request, which wakes the task waiting for it; with interrupts off, during boot,
+// the task polls the used ring instead.
+//
+// Submitted requests don't need a task at all: they wait in the disk's own queue for the
+// bounce buffer, and the interrupt handler completes each one, calling it back, and starts the
+// next. Tasks waiting for the buffer go first, as they are holding up whoever called them.
+// Requests too big for the buffer, or for disks without an interrupt, go to the block I/O task.
+//
+// The spin lock around the virtqueue is only held, with interrupts disabled, while the queue
+// is changed or looked at, never while a task waits: the bounce buffer is claimed instead.
+const BLOCK_DEVICE: u16 = 0x1001;
+const REQUEST_QUEUE: u16 = 0;
+
+// Features: the device can flush its cache, and is read-only
+const FLUSH_FEATURE: u32 = 1 << 9;
+const READ_ONLY_FEATURE: u32 = 1 << 5;
+// Where the device's size in sectors is in its configuration
+const CAPACITY: u16 = 0;
+
+// Request types
+const READ: u32 = 0;
+const WRITE: u32 = 1;
+const FLUSH: u32 = 4;
+const HEADER_SIZE: u32 = 16;
+// Where the status byte goes, after the header in the same page
+const STATUS_OFFSET: u64 = 16;
+const STATUS_OK: u8 = 0;
+// Written to the status first, so a request the device never finished can't look done
+const STATUS_PENDING: u8 = 0xFF;
+
+const BUFFER_PAGES: u64 = 16;
+const MAX_SECTORS: usize = (BUFFER_PAGES * PAGE_SIZE) as usize / BLOCK_SIZE;
+
+// How many submitted requests each disk can hold before giving them back
+const MAX_SUBMITTED: usize = 16;
+
+const NAMES: [&str; block::MAX_DEVICES] = ["vda", "vdb", "vdc", "vdd", "vde", "vdf", "vdg", "vdh"];
+
+// Which of `NAMES` the next disk found gets
+static NEXT_NAME: AtomicUsize = AtomicUsize::new(0);
+// The disks, for the interrupt handler to check which raised it
+static DISKS: SpinLock<[Option<&'static Disk>; block::MAX_DEVICES]> = SpinLock::new([None; block::MAX_DEVICES]);
+// Tasks waiting for a request to finish or a bounce buffer to be free
+static FINISHED: WaitQueue = WaitQueue::new();
+
+// Who has the bounce buffer
+enum Owner {
+    // A task, which starts its requests and waits for them itself
+    Task,
+    // A submitted request, which the interrupt handler completes
+    Submitted(Request, Callback),
+}
+
+struct Requests {
+    queue: Virtqueue,
+    owner: Option<Owner>,
+    // How many tasks are waiting for the bounce buffer
+    waiting: usize,
+    // The submitted requests waiting for it, oldest at `first`
+    submitted: [Option<(Request, Callback)>; MAX_SUBMITTED],
+    first: usize,
+    count: usize,
+}
+
+struct Disk {
+    name: &'static str,
+    device: Device,
+    requests: SpinLock<Requests>,
+    // A request's header and status, and the data buffer, only used by whoever has the buffer
+    header: Dma,
+    buffer: Dma,
+    sectors: u64,
+    flush: bool,
+    read_only: bool,
+    // Whether the device's interrupt reaches the handler, or requests have to be polled
+    interrupt: bool,
+}
+
+impl Disk {
+    // Whether tasks can sleep until the device's interrupt, rather than spin
+    fn sleeps(&self) -> bool {
+        self.interrupt && interrupts::are_enabled()
+    }
+
+    // Waits until `condition`, which looks at the requests with their lock held, is true. A
+    // task spinning completes submitted requests itself, as their interrupt may not come.
+    fn wait(&self, mut condition: impl FnMut(&mut Requests) -> bool) {
+        let mut check = || condition(&mut self.requests.lock_irq());
+        if self.sleeps() {
+            FINISHED.wait_until_done(check);
+        } else {
+            while !check() {
+                self.complete();
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    // Claims the bounce buffer for the current task, waiting for whoever has it to be done
+    fn claim(&self) {
+        self.requests.lock_irq().waiting += 1;
+        self.wait(|requests| {
+            if requests.owner.is_some() {
+                return false;
+            }
+            requests.owner = Some(Owner::Task);
+            requests.waiting -= 1;
+            true
+        });
+    }
+
+    // Gives the bounce buffer to the next task waiting for it, or else to the next submitted
+    // request
+    fn release(&self) {
+        self.requests.lock_irq().owner = None;
+        self.complete();
+        FINISHED.notify();
+    }
+
+    // Starts a request of type `kind` on `bytes` bytes of the bounce buffer from `sector` on
+    fn start(&self, queue: &mut Virtqueue, kind: u32, sector: u64, bytes: usize) -> Result<(), Error> {
+        let header: *mut u8 = self.header.start.as_mut_ptr();
+        unsafe {
+            header.cast::<u32>().write_volatile(kind);
+            header.add(4).cast::<u32>().write_volatile(0);
+            header.add(8).cast::<u64>().write_volatile(sector);
+            header.add(STATUS_OFFSET as usize).write_volatile(STATUS_PENDING);
+        }
+        let header_address = self.header.physical;
+        let status = (header_address + STATUS_OFFSET, 1, true);
+        let chain = [(header_address, HEADER_SIZE, false), (self.buffer.physical, bytes as u32, kind == READ), status];
+        // A flush has no data
+        let chain: &[_] = if bytes == 0 { &[chain[0], status] } else { &chain };
+        queue.add(chain).ok_or(Error::Io)?;
+        self.device.notify(queue);
+        Ok(())
+    }
+
+    // How the request that just finished went
+    fn status(&self) -> Result<(), Error> {
+        let header: *const u8 = self.header.start.as_ptr();
+        match unsafe { header.add(STATUS_OFFSET as usize).read_volatile() } {
+            STATUS_OK => Ok(()),
+            _ => Err(Error::Io),
+        }
+    }
+
+    // Runs a request and waits for it. The current task must have claimed the buffer.
+    fn run(&self, kind: u32, sector: u64, bytes: usize) -> Result<(), Error> {
+        self.start(&mut self.requests.lock_irq().queue, kind, sector, bytes)?;
+        self.wait(|requests| requests.queue.take_used().is_some());
+        self.status()
+    }
+
+    // Claims the bounce buffer and runs `transfer` with it, then lets the next one have it
+    fn with_buffer<T>(&self, transfer: impl FnOnce(*mut u8) -> T) -> T {
+        self.claim();
+        let result = transfer(self.buffer.start.as_mut_ptr());
+        self.release();
+        result
+    }
+
+    // Completes the submitted request the device has finished, if it has, and starts the next
+    // one, calling back outside the lock. Called from the interrupt handler.
+    fn complete(&self) {
+        loop {
+            let completed = {
+                let mut requests = self.requests.lock_irq();
+                self.finish_submitted(&mut requests).or_else(|| self.start_submitted(&mut requests))
+            };
+            let Some((done, completion)) = completed else {
+                return;
+            };
+            done(completion);
+        }
+    }
+
+    // Takes back the bounce buffer from a submitted request the device has finished
+    fn finish_submitted(&self, requests: &mut Requests) -> Option<(Callback, Completion)> {
+        if !matches!(requests.owner, Some(Owner::Submitted(..))) || requests.queue.take_used().is_none() {
+            return None;
+        }
+        let Some(Owner::Submitted(request, done)) = requests.owner.take() else {
+            return None;
+        };
+        let result = self.status();
+        if request.operation == Operation::Read && result.is_ok() {
+            unsafe { ptr::copy_nonoverlapping(self.buffer.start.as_ptr(), request.buffer.as_mut_ptr(), request.buffer.len()) };
+        }
+        Some((done, Completion { buffer: request.buffer, tag: request.tag, result }))
+    }
+
+    // Gives the bounce buffer to the oldest submitted request and starts it, unless someone
+    // has it or a task is waiting for it. Returns the request if it couldn't be started.
+    fn start_submitted(&self, requests: &mut Requests) -> Option<(Callback, Completion)> {
+        if requests.owner.is_some() || requests.waiting > 0 || requests.count == 0 {
+            return None;
+        }
+        let (request, done) = requests.submitted[requests.first].take()?;
+        requests.first = (requests.first + 1) % MAX_SUBMITTED;
+        requests.count -= 1;
+        let bytes = request.buffer.len();
+        let kind = match request.operation {
+            Operation::Read => READ,
+            Operation::Write => {
+                unsafe { ptr::copy_nonoverlapping(request.buffer.as_ptr(), self.buffer.start.as_mut_ptr(), bytes) };
+                WRITE
+            },
+            Operation::Flush => FLUSH,
+        };
+        let bytes = if kind == FLUSH { 0 } else { bytes };
+        match self.start(&mut requests.queue, kind, request.block, bytes) {
+            Ok(()) => {
+                requests.owner = Some(Owner::Submitted(request, done));
+                None
+            },
+            Err(error) => Some((done, Completion { buffer: request.buffer, tag: request.tag, result: Err(error) })),
+        }
+    }
+}
+
+impl BlockDevice for Disk {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn blocks(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read(&self, block: u64, buffer: &mut [u8]) -> Result<(), Error> {
+        block::check(self.sectors, block, buffer.len())?;
+        self.with_buffer(|bounce| {
+            for (chunk, sectors) in (block..).step_by(MAX_SECTORS).zip(buffer.chunks_mut(MAX_SECTORS * BLOCK_SIZE)) {
+                self.run(READ, chunk, sectors.len())?;
+                unsafe { ptr::copy_nonoverlapping(bounce, sectors.as_mut_ptr(), sectors.len()) };
+            }
+            Ok(())
+        })
+    }
+
+    fn write(&self, block: u64, bytes: &[u8]) -> Result<(), Error> {
+        block::check(self.sectors, block, bytes.len())?;
+        if self.read_only {
+            return Err(Error::Io);
+        }
+        self.with_buffer(|bounce| {
+            for (chunk, sectors) in (block..).step_by(MAX_SECTORS).zip(bytes.chunks(MAX_SECTORS * BLOCK_SIZE)) {
+                unsafe { ptr::copy_nonoverlapping(sectors.as_ptr(), bounce, sectors.len()) };
+                self.run(WRITE, chunk, sectors.len())?;
+            }
+            Ok(())
+        })
+    }
+
+    // Without the flush feature the device doesn't cache writes
+    fn flush(&self) -> Result<(), Error> {
+        match self.flush {
+            true => self.with_buffer(|_| self.run(FLUSH, 0, 0)),
+            false => Ok(()),
+        }
+    }
+
+    // Requests the interrupt handler can't carry out in one go, or that fail without reaching
+    // the device, go to the block I/O task, which splits or fails them the usual way
+    fn submit(&self, request: Request, done: Callback) -> Result<(), (Request, Callback)> {
+        let bytes = request.buffer.len();
+        let fits = bytes > 0 && bytes <= MAX_SECTORS * BLOCK_SIZE && block::check(self.sectors, request.block, bytes).is_ok();
+        let direct = match request.operation {
+            Operation::Read => fits,
+            Operation::Write => fits && !self.read_only,
+            Operation::Flush => self.flush,
+        };
+        if !self.interrupt || !direct {
+            return block::queue(request, done);
+        }
+        {
+            let mut requests = self.requests.lock_irq();
+            if requests.count == MAX_SUBMITTED {
+                return Err((request, done));
+            }
+            let slot = (requests.first + requests.count) % MAX_SUBMITTED;
+            requests.submitted[slot] = Some((request, done));
+            requests.count += 1;
+        }
+        self.complete();
+        Ok(())
+    }
+}
+
+// Reading the interrupt status acknowledges it, so each disk's is read. A disk that finished
+// a request completes it if it was submitted, and the waiting tasks are woken.
+fn handle_interrupt() {
+    let disks = *DISKS.lock_irq();
+    let mut finished = false;
+    for disk in disks.into_iter().flatten() {
+        if disk.device.interrupt_status() & virtio::QUEUE_INTERRUPT != 0 {
+            disk.complete();
+            finished = true;
+        }
+    }
+    if finished {
+        FINISHED.notify();
+    }
+}
+
+/// Registers the virtio block devices on the PCI bus as block devices, vda on.
+pub const DRIVER: pci::Driver = pci::Driver { name: "virtio-blk", matches: &[Match::Id(virtio::VENDOR, BLOCK_DEVICE)], probe };
+
+fn probe(function: PciDevice) -> Result<(), &'static str> {
+    let device = Device::new(function).ok_or("no I/O ports")?;
+    let Some(name) = NAMES.get(NEXT_NAME.fetch_add(1, Ordering::Relaxed)).copied() else {
+        device.fail();
+        return Err("too many disks");
+    };
+    let features = device.negotiate(FLUSH_FEATURE | READ_ONLY_FEATURE);
+    let requests = device.queue(REQUEST_QUEUE).and_then(|queue| {
+        Some((queue, memory::alloc_dma(1)?, memory::alloc_dma(BUFFER_PAGES)?))
+    });
+    let Some((queue, header, buffer)) = requests else {
+        device.fail();
+        return Err("can't set up the request queue");
+    };
+    let sectors = device.config64(CAPACITY);
+    let line = device.interrupt_line();
+    // The handler checks every disk, so one on each line is enough
+    let sharing = DISKS.lock_irq().iter().flatten().find(|other| other.device.interrupt_line() == line).map(|other| other.interrupt);
+    let interrupt = sharing.unwrap_or_else(|| irq::register(line, handle_interrupt));
+    if !interrupt {
+        writeln!(serial(), "{name}: can't use IRQ {line}, polling").unwrap();
+    }
+    let disk: &'static Disk = Box::leak(Box::new(Disk {
+        name,
+        device,
+        requests: SpinLock::new(Requests { queue, owner: None, waiting: 0, submitted: [const { None }; MAX_SUBMITTED], first: 0, count: 0 }),
+        header,
+        buffer,
+        sectors,
+        flush: features & FLUSH_FEATURE != 0,
+        read_only: features & READ_ONLY_FEATURE != 0,
+        interrupt,
+    }));
+    if let Some(slot) = DISKS.lock_irq().iter_mut().find(|slot| slot.is_none()) {
+        *slot = Some(disk);
+    }
+    disk.device.ready();
+    writeln!(serial(), "{name}: virtio disk, {sectors} sectors, IRQ {line}{}", if disk.read_only { ", read-only" } else { "" }).unwrap();
+    if !block::register(disk) {
+        writeln!(serial(), "{name}: too many block devices, not registered").unwrap();
+    }
+    Ok(())
+}
@@ -0,0 +1,188 @@
+use alloc::boxed::Box;
+use core::fmt::Write;
+use core::ptr;
+use kernel::serial;
+use kernel::sync::SpinLock;
+use x86_64::PhysAddr;
+use crate::memory::{self, Dma, PAGE_SIZE};
+use crate::net::{self, Error, Mac, MAX_FRAME, NetworkDevice};
+use crate::pci::{self, Match, PciDevice};
+use crate::virtio::{self, Device, Virtqueue};
+use crate::{irq, rand};
+
+// Virtio network cards, what QEMU gives with `-nic user,model=virtio-net-pci`. Frames go
+// through two virtqueues, one the device fills with the frames it receives and one it sends
+// from, each frame after a header for offloads, which are all left off. Each queue has buffers
+// of its own, in DMA memory, that frames are copied in and out of. The receive queue is kept
+// full of empty buffers, each given back as soon as its frame is copied out, and the device's
+// interrupt wakes the network task to take them; a sent buffer is taken back the next time a
+// frame is sent after the device is done with it.
+const NETWORK_DEVICE: u16 = 0x1000;
+const RECEIVE_QUEUE: u16 = 0;
+const TRANSMIT_QUEUE: u16 = 1;
+
+// Features: the device has a MAC address, and tells whether its link is up
+const MAC_FEATURE: u32 = 1 << 5;
+const STATUS_FEATURE: u32 = 1 << 16;
+// Where those are in its configuration
+const MAC_ADDRESS: u16 = 0;
+const STATUS: u16 = 6;
+const LINK_UP: u16 = 1;
+
+// The header before each frame, which is all zeroes for no offloads. Without the any-layout
+// feature it has to be in a descriptor of its own.
+const HEADER_SIZE: usize = 10;
+// Each buffer is a header and the longest frame, rounded up so two fit in a page
+const BUFFER_SIZE: usize = 2048;
+const BUFFERS: usize = 16;
+const BUFFER_PAGES: u64 = (BUFFERS * BUFFER_SIZE) as u64 / PAGE_SIZE;
+
+// The cards, for the interrupt handler to check which raised it
+static CARDS: SpinLock<[Option<&'static Card>; net::MAX_DEVICES]> = SpinLock::new([None; net::MAX_DEVICES]);
+
+// A virtqueue and its buffers
+struct Ring {
+    queue: Virtqueue,
+    buffers: Dma,
+    // The chain each buffer is in, or None while the device doesn't have it
+    chains: [Option<u16>; BUFFERS],
+}
+
+impl Ring {
+    fn new(device: &Device, index: u16) -> Option<Ring> {
+        Some(Ring { queue: device.queue(index)?, buffers: memory::alloc_dma(BUFFER_PAGES)?, chains: [None; BUFFERS] })
+    }
+
+    fn buffer(&self, index: usize) -> *mut u8 {
+        (self.buffers.start + (index * BUFFER_SIZE) as u64).as_mut_ptr()
+    }
+
+    fn physical(&self, index: usize) -> PhysAddr {
+        self.buffers.physical + (index * BUFFER_SIZE) as u64
+    }
+
+    // Gives buffer `index`, with a frame `length` bytes long, to the device, which writes it if
+    // `written`. Returns false if the queue is full.
+    fn give(&mut self, index: usize, length: usize, written: bool) -> bool {
+        let address = self.physical(index);
+        let chain = [(address, HEADER_SIZE as u32, written), (address + HEADER_SIZE as u64, length as u32, written)];
+        self.chains[index] = self.queue.add(&chain);
+        self.chains[index].is_some()
+    }
+
+    // Takes back a buffer the device is done with, and returns it and how much the device wrote
+    fn take(&mut self) -> Option<(usize, u32)> {
+        let (chain, written) = self.queue.take_used()?;
+        let index = self.chains.iter().position(|&other| other == Some(chain))?;
+        self.chains[index] = None;
+        Some((index, written))
+    }
+}
+
+struct Card {
+    device: Device,
+    mac: Mac,
+    receive: SpinLock<Ring>,
+    transmit: SpinLock<Ring>,
+    // Whether the device tells the link's status; without it the link is taken to be up
+    status: bool,
+}
+
+impl NetworkDevice for Card {
+    fn mac(&self) -> Mac {
+        self.mac
+    }
+
+    fn link_up(&self) -> bool {
+        !self.status || self.device.config16(STATUS) & LINK_UP != 0
+    }
+
+    fn send(&self, frame: &[u8]) -> Result<(), Error> {
+        if frame.len() > MAX_FRAME {
+            return Err(Error::TooLong);
+        }
+        let mut transmit = self.transmit.lock();
+        while transmit.take().is_some() {}
+        let index = transmit.chains.iter().position(Option::is_none).ok_or(Error::Busy)?;
+        unsafe {
+            let buffer = transmit.buffer(index);
+            buffer.write_bytes(0, HEADER_SIZE);
+            ptr::copy_nonoverlapping(frame.as_ptr(), buffer.add(HEADER_SIZE), frame.len());
+        }
+        if !transmit.give(index, frame.len(), false) {
+            return Err(Error::Busy);
+        }
+        self.device.notify(&transmit.queue);
+        Ok(())
+    }
+
+    fn receive(&self, buffer: &mut [u8; MAX_FRAME]) -> Option<usize> {
+        let mut receive = self.receive.lock();
+        let (index, written) = receive.take()?;
+        let length = (written as usize).saturating_sub(HEADER_SIZE).min(MAX_FRAME);
+        unsafe { ptr::copy_nonoverlapping(receive.buffer(index).add(HEADER_SIZE), buffer.as_mut_ptr(), length) };
+        // Back to the device straight away, so it always has somewhere to put frames
+        receive.give(index, MAX_FRAME, true);
+        self.device.notify(&receive.queue);
+        Some(length)
+    }
+}
+
+// Reading the interrupt status acknowledges it, so each card's is read, and the network task
+// woken if any of them received a frame or had its link change
+fn handle_interrupt() {
+    let raised = CARDS.lock_irq().iter().flatten().fold(false, |raised, card| card.device.interrupt_status() != 0 || raised);
+    if raised {
+        net::wake();
+    }
+}
+
+/// Registers the virtio network cards on the PCI bus as network cards.
+pub const DRIVER: pci::Driver = pci::Driver { name: "virtio-net", matches: &[Match::Id(virtio::VENDOR, NETWORK_DEVICE)], probe };
+
+fn probe(function: PciDevice) -> Result<(), &'static str> {
+    let device = Device::new(function).ok_or("no I/O ports")?;
+    let features = device.negotiate(MAC_FEATURE | STATUS_FEATURE);
+    let rings = Ring::new(&device, RECEIVE_QUEUE).zip(Ring::new(&device, TRANSMIT_QUEUE));
+    let Some((mut receive, transmit)) = rings else {
+        device.fail();
+        return Err("can't set up the queues");
+    };
+    let mac = match features & MAC_FEATURE {
+        0 => {
+            // A random one, marked as locally administered rather than a manufacturer's
+            let mut mac = [0; 6];
+            rand::fill(&mut mac);
+            mac[0] = (mac[0] & !1) | 2;
+            Mac(mac)
+        },
+        _ => Mac(core::array::from_fn(|index| device.config8(MAC_ADDRESS + index as u16))),
+    };
+    for index in 0..BUFFERS {
+        receive.give(index, MAX_FRAME, true);
+    }
+
+    let line = device.interrupt_line();
+    // The handler checks every card, so one on each line is enough
+    let sharing = CARDS.lock_irq().iter().flatten().any(|other| other.device.interrupt_line() == line);
+    let interrupt = sharing || irq::register(line, handle_interrupt);
+    let card: &'static Card = Box::leak(Box::new(Card {
+        device,
+        mac,
+        receive: SpinLock::new(receive),
+        transmit: SpinLock::new(transmit),
+        status: features & STATUS_FEATURE != 0,
+    }));
+    if let Some(slot) = CARDS.lock_irq().iter_mut().find(|slot| slot.is_none()) {
+        *slot = Some(card);
+    }
+    card.device.ready();
+    card.device.notify(&card.receive.lock().queue);
+    let name = net::register(card, interrupt).ok_or("too many network cards, not registered")?;
+    let link = if card.link_up() { "up" } else { "down" };
+    writeln!(serial(), "{name}: virtio-net, MAC {mac}, link {link}, IRQ {line}").unwrap();
+    if !interrupt {
+        writeln!(serial(), "{name}: can't use IRQ {line}, polling").unwrap();
+    }
+    Ok(())
+}
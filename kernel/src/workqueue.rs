@@ -0,0 +1,27 @@
+use alloc::boxed::Box;
+use crate::channel::Channel;
+
+// Work deferred out of interrupt handlers, run in order by the worker task. Interrupt handlers
+// should only do what can't wait; anything slow, such as logging over serial, goes here.
+const MAX_QUEUED: usize = 32;
+
+type Work = Box<dyn FnOnce() + Send>;
+
+static WORK: Channel<Work, MAX_QUEUED> = Channel::new();
+
+/// Queues `work` to run on the worker task. Safe to call from interrupt handlers. Returns false
+/// if too much work is queued already.
+///
+/// The heap never gives memory back, so in code that runs often, queue closures that capture
+/// nothing: those take no memory to box.
+pub fn queue(work: impl FnOnce() + Send + 'static) -> bool {
+    WORK.sender().send(Box::new(work)).is_ok()
+}
+
+/// Runs the queued work, forever. This is the worker task's entry point.
+pub fn run() {
+    let mut work = WORK.receiver().unwrap();
+    loop {
+        (work.recv())();
+    }
+}
@@ -19,6 +19,43 @@ fn main() {
     // set kernel image
     cmd.arg("-drive").arg(format!("format=raw,file={uefi_path}"));
     cmd.arg("-serial").arg("stdio");
+
+    // Optional disk image, attached as an IDE drive after the boot disk, e.g. DISK=disk.img
+    if let Ok(disk) = std::env::var("DISK") {
+        cmd.arg("-drive").arg(format!("format=raw,file={disk}"));
+    }
+
+    // Optional disk image on an AHCI controller, as on real SATA hardware, e.g. SATA_DISK=disk.img
+    if let Ok(disk) = std::env::var("SATA_DISK") {
+        cmd.arg("-device").arg("ahci,id=ahci");
+        cmd.arg("-drive").arg(format!("id=sata,if=none,format=raw,file={disk}"));
+        cmd.arg("-device").arg("ide-hd,drive=sata,bus=ahci.0");
+    }
+
+    // Optional disk image on an NVMe controller, e.g. NVME_DISK=disk.img
+    if let Ok(disk) = std::env::var("NVME_DISK") {
+        cmd.arg("-drive").arg(format!("id=nvme,if=none,format=raw,file={disk}"));
+        cmd.arg("-device").arg("nvme,drive=nvme,serial=lab-os");
+    }
+
+    // Optional disk image as a virtio block device, e.g. VIRTIO_DISK=disk.img
+    if let Ok(disk) = std::env::var("VIRTIO_DISK") {
+        cmd.arg("-drive").arg(format!("if=virtio,format=raw,file={disk}"));
+    }
+
+    // Optional network card on QEMU's user-mode network, by its QEMU model, e.g. NET=virtio-net-pci,
+    // with port 2323 on the host forwarded to the remote console
+    if let Ok(model) = std::env::var("NET") {
+        cmd.arg("-nic").arg(format!("user,model={model},hostfwd=tcp:127.0.0.1:2323-:23"));
+    }
+
+    // Optional second serial port for two-machine pong, e.g. PONG_LINK=tcp::4444,server
+    // on one instance and PONG_LINK=tcp:localhost:4444 on the other
+    if let Ok(link) = std::env::var("PONG_LINK") {
+        cmd.arg("-serial").arg(link);
+        // Both instances boot from the same files, so neither may write to them
+        cmd.arg("-snapshot");
+    }
     
     // launch qemu and wait until it terminates
     let mut child = cmd.spawn().unwrap();
@@ -0,0 +1,6 @@
+// User programs are loaded where they were linked to run, and the kernel maps them in the
+// user region at 64 TiB (`memory::USER_START`), so they are linked as plain executables there
+fn main() {
+    println!("cargo:rustc-link-arg-bins=--no-pie");
+    println!("cargo:rustc-link-arg-bins=--image-base=0x400000000000");
+}
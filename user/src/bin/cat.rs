@@ -0,0 +1,40 @@
+#![no_std]
+#![no_main]
+
+use user::{println, STDOUT};
+
+// Prints the files named, one after the other: `run cat /tmp/notes`, or `run cat /tmp/notes | upper`
+#[unsafe(no_mangle)]
+fn main() -> u64 {
+    let mut failed = false;
+    for path in user::args().skip(1) {
+        if print_file(path).is_err() {
+            println!("cat: can't read {path}");
+            failed = true;
+        }
+    }
+    u64::from(failed)
+}
+
+fn print_file(path: &str) -> Result<(), i64> {
+    let file = user::open(path, 0)?;
+    let copied = copy(file);
+    user::close(file)?;
+    copied
+}
+
+// Copies file `file` to the output, from where it is to its end
+fn copy(file: u64) -> Result<(), i64> {
+    let mut buffer = [0; 256];
+    loop {
+        let count = user::read(file, &mut buffer)?;
+        if count == 0 {
+            return Ok(());
+        }
+        let mut bytes = &buffer[..count];
+        while !bytes.is_empty() {
+            let written = user::write(STDOUT, bytes)?;
+            bytes = &bytes[written..];
+        }
+    }
+}
@@ -0,0 +1,32 @@
+#![no_std]
+#![no_main]
+
+use core::arch::asm;
+use user::println;
+
+// Crashes on purpose, to see the kernel end only the program that did: `crash null` reads
+// address 0, `crash kernel` reads kernel memory, `crash divide` divides by zero and
+// `crash opcode` runs an invalid instruction
+#[unsafe(no_mangle)]
+fn main() -> u64 {
+    match user::args().nth(1) {
+        Some("null") => println!("Read {} from address 0", read(0)),
+        // Where the kernel's own mappings start, which ring 3 can't read
+        Some("kernel") => println!("Read {} from kernel memory", read(0xffff_8000_0000_0000)),
+        Some("divide") => unsafe { asm!("div {0}", in(reg) 0u64, inout("rax") 1u64 => _, inout("rdx") 0u64 => _) },
+        Some("opcode") => unsafe { asm!("ud2") },
+        _ => {
+            println!("Usage: crash null|kernel|divide|opcode");
+            return 1;
+        },
+    }
+    println!("Still here");
+    0
+}
+
+// Reads the word at `address` with an instruction of its own, as Rust would rather not
+fn read(address: u64) -> u64 {
+    let value;
+    unsafe { asm!("mov {0}, [{1}]", out(reg) value, in(reg) address) };
+    value
+}
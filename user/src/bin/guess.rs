@@ -0,0 +1,63 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use user::{STDIN, print, println};
+
+// Guess the number: the program picks a number from 1 to 100 and says whether each guess is
+// too high or too low
+const TOP: u32 = 100;
+
+#[unsafe(no_mangle)]
+fn main() -> u64 {
+    // Spread the uptime's bits around so numbers don't follow each other
+    let secret = (user::uptime_ms().wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32) as u32 % TOP + 1;
+    println!("I'm thinking of a number from 1 to {TOP}.");
+    let mut guesses = Vec::new();
+    loop {
+        print!("Your guess: ");
+        let Some(guess) = read_number() else {
+            println!("That's not a number.");
+            continue;
+        };
+        guesses.push(guess);
+        if guess < secret {
+            println!("Too low.");
+        } else if guess > secret {
+            println!("Too high.");
+        } else {
+            println!("Got it in {}: {guesses:?}", guesses.len());
+            return guesses.len() as u64;
+        }
+    }
+}
+
+// Reads a line, echoing it, and parses it
+fn read_number() -> Option<u32> {
+    let mut number: Option<u32> = None;
+    let mut valid = true;
+    loop {
+        let mut byte = [0];
+        if user::read(STDIN, &mut byte).is_err() {
+            return None;
+        }
+        match byte[0] {
+            b'\n' | b'\r' => {
+                println!();
+                return number.filter(|_| valid);
+            }
+            digit @ b'0'..=b'9' => {
+                print!("{}", digit as char);
+                let value = u32::from(digit - b'0');
+                number = number.unwrap_or(0).checked_mul(10).and_then(|number| number.checked_add(value));
+                valid &= number.is_some();
+            }
+            other => {
+                print!("{}", other as char);
+                valid = false;
+            }
+        }
+    }
+}
@@ -0,0 +1,17 @@
+#![no_std]
+#![no_main]
+
+use user::println;
+
+#[unsafe(no_mangle)]
+fn main() -> u64 {
+    // Greets whoever is named, or the world
+    let mut names = user::args().skip(1).peekable();
+    if names.peek().is_none() {
+        println!("Hello, world!");
+    }
+    for name in names {
+        println!("Hello, {name}!");
+    }
+    0
+}
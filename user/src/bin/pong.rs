@@ -0,0 +1,231 @@
+#![no_std]
+#![no_main]
+
+use user::{STDIN, println};
+
+// Pong as a user program: the screen through `fill`, the keys from the console and the pace
+// from `sleep`. W and S move the left paddle; the computer plays the right one, unless 2 is
+// pressed to hand it to the arrow keys. Q quits, and the first to five points wins.
+const FRAME_MS: u64 = 16;
+const PADDLE_WIDTH: u32 = 10;
+const PADDLE_HEIGHT: u32 = 60;
+const PADDLE_OFFSET: u32 = 20;
+const PADDLE_SPEED: i32 = 6;
+const BALL_SIZE: u32 = 10;
+const BALL_SPEED: i32 = 4;
+const AI_SPEED: i32 = 4;
+const WINNING_SCORE: u32 = 5;
+// How long the final score stays up before the menu comes back
+const GAME_OVER_MS: u64 = 2000;
+// The keyboard only reports presses, so a paddle keeps moving for this many frames after one
+const HOLD_FRAMES: u32 = 8;
+// Score digits, drawn from blocks of this many pixels on a 3 by 5 grid
+const DIGIT_SCALE: u32 = 6;
+const DIGITS: [[u8; 5]; 10] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b111, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b010, 0b010, 0b010],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+];
+const BLACK: u32 = 0x000000;
+const WHITE: u32 = 0xffffff;
+const GREY: u32 = 0x808080;
+
+struct Paddle {
+    y: i32,
+    // Direction it is moving in, and for how many more frames
+    direction: i32,
+    held: u32,
+    score: u32,
+}
+
+impl Paddle {
+    fn press(&mut self, direction: i32) {
+        self.direction = direction;
+        self.held = HOLD_FRAMES;
+    }
+}
+
+// Where things were drawn, to erase them before drawing them again
+#[derive(Clone, Copy)]
+struct Drawn {
+    ball: (i32, i32),
+    left_y: i32,
+    right_y: i32,
+    scores: (u32, u32),
+}
+
+struct Pong {
+    width: i32,
+    height: i32,
+    left: Paddle,
+    right: Paddle,
+    ball: (i32, i32),
+    speed: (i32, i32),
+    two_players: bool,
+    // Bytes of an arrow key's escape sequence read so far
+    escape: usize,
+}
+
+#[unsafe(no_mangle)]
+fn main() -> u64 {
+    let (width, height) = user::screen_size();
+    let (width, height) = (width as i32, height as i32);
+    let middle = (height - PADDLE_HEIGHT as i32) / 2;
+    let paddle = |y| Paddle { y, direction: 0, held: 0, score: 0 };
+    let mut pong = Pong {
+        width,
+        height,
+        left: paddle(middle),
+        right: paddle(middle),
+        ball: (width / 2, height / 2),
+        speed: (BALL_SPEED, BALL_SPEED),
+        two_players: false,
+        escape: 0,
+    };
+    user::fill(0, 0, width as u32, height as u32, BLACK);
+    loop {
+        let old = pong.drawn();
+        if !pong.read_keys() {
+            return 0;
+        }
+        pong.update();
+        pong.draw(old);
+        if let Some(winner) = pong.winner() {
+            println!("The {winner} player wins");
+            user::sleep(GAME_OVER_MS);
+            return 0;
+        }
+        user::sleep(FRAME_MS);
+    }
+}
+
+impl Pong {
+    // Handles what was typed; returns false to quit
+    fn read_keys(&mut self) -> bool {
+        let mut bytes = [0; 16];
+        let Ok(count) = user::try_read(STDIN, &mut bytes) else {
+            return false;
+        };
+        for &byte in &bytes[..count] {
+            let escape = self.escape;
+            self.escape = match (escape, byte) {
+                (0, 0x1b) => 1,
+                (1, b'[') => 2,
+                _ => 0,
+            };
+            match (escape, byte) {
+                (2, b'A') if self.two_players => self.right.press(-1),
+                (2, b'B') if self.two_players => self.right.press(1),
+                (0, b'w' | b'W') => self.left.press(-1),
+                (0, b's' | b'S') => self.left.press(1),
+                (0, b'2') => self.two_players = true,
+                (0, b'q' | b'Q') => return false,
+                _ => {},
+            }
+        }
+        true
+    }
+
+    fn update(&mut self) {
+        if !self.two_players {
+            // The computer follows the ball
+            let centre = self.right.y + PADDLE_HEIGHT as i32 / 2;
+            let difference = self.ball.1 + BALL_SIZE as i32 / 2 - centre;
+            self.right.y += difference.clamp(-AI_SPEED, AI_SPEED);
+        }
+        let bottom = self.height - PADDLE_HEIGHT as i32;
+        for paddle in [&mut self.left, &mut self.right] {
+            if paddle.held > 0 {
+                paddle.held -= 1;
+                paddle.y += paddle.direction * PADDLE_SPEED;
+            }
+            paddle.y = paddle.y.clamp(0, bottom);
+        }
+
+        let (mut x, mut y) = (self.ball.0 + self.speed.0, self.ball.1 + self.speed.1);
+        if y <= 0 || y >= self.height - BALL_SIZE as i32 {
+            self.speed.1 = -self.speed.1;
+            y = y.clamp(0, self.height - BALL_SIZE as i32);
+        }
+        let left_face = (PADDLE_OFFSET + PADDLE_WIDTH) as i32;
+        let right_face = self.width - (PADDLE_OFFSET + PADDLE_WIDTH) as i32 - BALL_SIZE as i32;
+        let hits = |paddle: &Paddle| y + BALL_SIZE as i32 > paddle.y && y < paddle.y + PADDLE_HEIGHT as i32;
+        if self.speed.0 < 0 && x <= left_face && x > left_face - BALL_SPEED * 2 && hits(&self.left) {
+            self.speed.0 = -self.speed.0;
+            x = left_face;
+        } else if self.speed.0 > 0 && x >= right_face && x < right_face + BALL_SPEED * 2 && hits(&self.right) {
+            self.speed.0 = -self.speed.0;
+            x = right_face;
+        }
+        if x < 0 || x > self.width - BALL_SIZE as i32 {
+            // A point, and the ball goes back to the middle towards whoever lost it
+            if x < 0 {
+                self.right.score += 1;
+            } else {
+                self.left.score += 1;
+            }
+            self.speed.0 = -self.speed.0;
+            (x, y) = (self.width / 2, self.height / 2);
+        }
+        self.ball = (x, y);
+    }
+
+    fn drawn(&self) -> Drawn {
+        Drawn { ball: self.ball, left_y: self.left.y, right_y: self.right.y, scores: (self.left.score, self.right.score) }
+    }
+
+    // Erases what was drawn as `old` and draws the game as it is now
+    fn draw(&self, old: Drawn) {
+        let right_x = self.width as u32 - PADDLE_OFFSET - PADDLE_WIDTH;
+        user::fill(old.ball.0 as u32, old.ball.1 as u32, BALL_SIZE, BALL_SIZE, BLACK);
+        user::fill(PADDLE_OFFSET, old.left_y as u32, PADDLE_WIDTH, PADDLE_HEIGHT, BLACK);
+        user::fill(right_x, old.right_y as u32, PADDLE_WIDTH, PADDLE_HEIGHT, BLACK);
+        if old.scores != self.drawn().scores {
+            self.draw_scores(BLACK, old.scores.0, old.scores.1);
+        }
+        // The centre line, which the ball may have crossed
+        let centre = self.width as u32 / 2 - 1;
+        for y in (0..self.height as u32).step_by(20) {
+            user::fill(centre, y, 2, 10, GREY);
+        }
+        self.draw_scores(WHITE, self.left.score, self.right.score);
+        user::fill(PADDLE_OFFSET, self.left.y as u32, PADDLE_WIDTH, PADDLE_HEIGHT, WHITE);
+        user::fill(right_x, self.right.y as u32, PADDLE_WIDTH, PADDLE_HEIGHT, WHITE);
+        user::fill(self.ball.0 as u32, self.ball.1 as u32, BALL_SIZE, BALL_SIZE, WHITE);
+    }
+
+    fn draw_scores(&self, colour: u32, left: u32, right: u32) {
+        let centre = self.width as u32 / 2;
+        let digit_width = 3 * DIGIT_SCALE;
+        draw_digit(centre - 4 * DIGIT_SCALE - digit_width, left, colour);
+        draw_digit(centre + 4 * DIGIT_SCALE, right, colour);
+    }
+
+    fn winner(&self) -> Option<&'static str> {
+        if self.left.score >= WINNING_SCORE {
+            Some("left")
+        } else if self.right.score >= WINNING_SCORE {
+            Some("right")
+        } else {
+            None
+        }
+    }
+}
+
+fn draw_digit(x: u32, digit: u32, colour: u32) {
+    let rows = DIGITS[digit as usize % 10];
+    for (row, bits) in rows.iter().enumerate() {
+        for column in 0..3 {
+            if bits & 0b100 >> column != 0 {
+                user::fill(x + column * DIGIT_SCALE, DIGIT_SCALE + row as u32 * DIGIT_SCALE, DIGIT_SCALE, DIGIT_SCALE, colour);
+            }
+        }
+    }
+}
@@ -0,0 +1,27 @@
+#![no_std]
+#![no_main]
+
+use user::{STDIN, STDOUT};
+
+// Copies its input to its output in upper case, until the input ends: a filter to put after
+// another program with `run program | upper`
+#[unsafe(no_mangle)]
+fn main() -> u64 {
+    let mut buffer = [0; 256];
+    loop {
+        let count = match user::read(STDIN, &mut buffer) {
+            Ok(0) => return 0,
+            Ok(count) => count,
+            Err(_) => return 1,
+        };
+        buffer[..count].make_ascii_uppercase();
+        let mut bytes = &buffer[..count];
+        while !bytes.is_empty() {
+            let Ok(written) = user::write(STDOUT, bytes) else {
+                // Nothing reads what comes out any more
+                return 1;
+            };
+            bytes = &bytes[written..];
+        }
+    }
+}
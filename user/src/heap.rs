@@ -0,0 +1,47 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::Cell;
+use core::ptr::null_mut;
+
+// A bump allocator like the kernel's, on the heap the kernel grows with `brk`. Memory is never
+// given back, which is fine for programs that don't run for long.
+#[global_allocator]
+static ALLOCATOR: BumpAllocator = BumpAllocator { next: Cell::new(0), end: Cell::new(0) };
+
+// How much the heap grows by at least, to keep system calls few
+const GROWTH: u64 = 64 * 1024;
+
+struct BumpAllocator {
+    next: Cell<u64>,
+    end: Cell<u64>,
+}
+
+// Programs have one thread
+unsafe impl Sync for BumpAllocator {}
+
+unsafe impl GlobalAlloc for BumpAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if self.end.get() == 0 {
+            let Ok(start) = crate::brk(0) else {
+                return null_mut();
+            };
+            self.next.set(start);
+            self.end.set(start);
+        }
+        let Some(start) = self.next.get().checked_next_multiple_of(layout.align() as u64) else {
+            return null_mut();
+        };
+        let Some(end) = start.checked_add(layout.size() as u64) else {
+            return null_mut();
+        };
+        if end > self.end.get() {
+            let Ok(new_end) = crate::brk(end.max(self.end.get() + GROWTH)) else {
+                return null_mut();
+            };
+            self.end.set(new_end);
+        }
+        self.next.set(end);
+        start as *mut u8
+    }
+
+    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {}
+}
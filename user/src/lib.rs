@@ -0,0 +1,305 @@
+#![no_std]
+
+// The runtime every user program links against: the entry point, system calls and a panic
+// handler. A program is a binary in src/bin that defines `main`, returning its exit code:
+//
+//     #![no_std]
+//     #![no_main]
+//     use user as _;
+//
+//     #[unsafe(no_mangle)]
+//     fn main() -> u64 { 0 }
+//
+// `args` returns the arguments it was started with, the first being its name.
+// Programs can use `alloc` with `extern crate alloc;`, on a heap that grows with `brk`.
+
+mod heap;
+
+use core::arch::asm;
+use core::ffi::{CStr, c_char};
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+unsafe extern "Rust" {
+    fn main() -> u64;
+}
+
+// The arguments the kernel left on the stack
+static ARGUMENT_COUNT: AtomicUsize = AtomicUsize::new(0);
+static ARGUMENTS: AtomicPtr<*const c_char> = AtomicPtr::new(core::ptr::null_mut());
+
+#[unsafe(no_mangle)]
+extern "C" fn _start(argument_count: usize, arguments: *mut *const c_char) -> ! {
+    ARGUMENT_COUNT.store(argument_count, Ordering::Relaxed);
+    ARGUMENTS.store(arguments, Ordering::Relaxed);
+    exit(unsafe { main() })
+}
+
+/// Returns the arguments the program was started with, its name first. Arguments that aren't
+/// UTF-8 are empty.
+pub fn args() -> impl Iterator<Item = &'static str> {
+    let arguments = ARGUMENTS.load(Ordering::Relaxed);
+    (0..ARGUMENT_COUNT.load(Ordering::Relaxed)).map(move |index| {
+        let argument = unsafe { CStr::from_ptr(*arguments.add(index)) };
+        argument.to_str().unwrap_or("")
+    })
+}
+
+// The system call numbers, as in kernel/src/syscall.rs
+const EXIT: u64 = 0;
+const WRITE: u64 = 1;
+const READ: u64 = 2;
+const SLEEP: u64 = 3;
+const GET_TIME: u64 = 4;
+const BRK: u64 = 5;
+const MMAP: u64 = 6;
+const SCREEN_SIZE: u64 = 7;
+const DRAW: u64 = 8;
+const FILL: u64 = 9;
+const PIPE: u64 = 10;
+const CLOSE: u64 = 11;
+const SHM_OPEN: u64 = 12;
+const OPEN: u64 = 13;
+const SEEK: u64 = 14;
+const METADATA: u64 = 15;
+// `read` flags
+const NO_WAIT: u64 = 1;
+// `mmap` flags
+const MAP_SHARED: u64 = 1;
+
+/// `mmap` protection: the memory can be written.
+pub const PROTECT_WRITE: u64 = 2;
+/// `mmap` protection: the memory can be executed.
+pub const PROTECT_EXECUTE: u64 = 4;
+
+/// `open` flags: make the file if there is none.
+pub const CREATE: u64 = 1;
+/// `open` flags: empty the file.
+pub const TRUNCATE: u64 = 2;
+/// `open` flags: start at the end of the file instead of its start.
+pub const APPEND: u64 = 4;
+
+/// The handle `read` takes input from: the console's keys, unless the program was started
+/// reading from a pipe.
+pub const STDIN: u64 = 0;
+/// The handle `write` prints to: the console, unless the program was started writing to a pipe.
+pub const STDOUT: u64 = 1;
+
+/// Makes system call `number` with up to six `arguments`, and returns the result: negative
+/// numbers are errors.
+///
+/// # Safety
+/// The arguments must be what the system call expects, such as pointers to memory it may use.
+pub unsafe fn syscall(number: u64, arguments: [u64; 6]) -> i64 {
+    let result: i64;
+    unsafe {
+        asm!(
+            "syscall",
+            inlateout("rax") number as i64 => result,
+            in("rdi") arguments[0],
+            in("rsi") arguments[1],
+            in("rdx") arguments[2],
+            in("r10") arguments[3],
+            in("r8") arguments[4],
+            in("r9") arguments[5],
+            // `syscall` puts the return address and flags in these
+            out("rcx") _,
+            out("r11") _,
+            options(nostack),
+        );
+    }
+    result
+}
+
+/// Ends the program with `code`.
+pub fn exit(code: u64) -> ! {
+    unsafe { syscall(EXIT, [code, 0, 0, 0, 0, 0]) };
+    unreachable!("exit returned")
+}
+
+/// Writes `bytes` to the console, pipe or file handle `handle`. Returns how many were written, which
+/// for a pipe may be fewer than all of them, or the negated error.
+pub fn write(handle: u64, bytes: &[u8]) -> Result<usize, i64> {
+    let result = unsafe { syscall(WRITE, [handle, bytes.as_ptr() as u64, bytes.len() as u64, 0, 0, 0]) };
+    usize::try_from(result).map_err(|_| result)
+}
+
+/// Waits for input on the console, pipe or file handle `handle` and reads what there is into
+/// `buffer`. Returns how many bytes were read, none once a pipe has no writers left or at the
+/// end of a file, or the negated error.
+pub fn read(handle: u64, buffer: &mut [u8]) -> Result<usize, i64> {
+    let result = unsafe { syscall(READ, [handle, buffer.as_mut_ptr() as u64, buffer.len() as u64, 0, 0, 0]) };
+    usize::try_from(result).map_err(|_| result)
+}
+
+/// Reads what input there is on the console or pipe handle `handle` into `buffer`, without waiting.
+/// Returns how many bytes were read, which may be none, or the negated error.
+pub fn try_read(handle: u64, buffer: &mut [u8]) -> Result<usize, i64> {
+    let result = unsafe { syscall(READ, [handle, buffer.as_mut_ptr() as u64, buffer.len() as u64, NO_WAIT, 0, 0]) };
+    usize::try_from(result).map_err(|_| result)
+}
+
+/// Makes a pipe, and returns the handles to read from it and to write to it, or the negated
+/// error.
+pub fn pipe() -> Result<(u64, u64), i64> {
+    let mut handles = [0u64; 2];
+    let result = unsafe { syscall(PIPE, [handles.as_mut_ptr() as u64, 0, 0, 0, 0, 0]) };
+    if result < 0 {
+        return Err(result);
+    }
+    Ok((handles[0], handles[1]))
+}
+
+/// Closes `handle`. Returns the negated error if it isn't open.
+pub fn close(handle: u64) -> Result<(), i64> {
+    let result = unsafe { syscall(CLOSE, [handle, 0, 0, 0, 0, 0]) };
+    if result < 0 {
+        return Err(result);
+    }
+    Ok(())
+}
+
+/// Opens the file at `path`, which must be absolute, as `flags` (`CREATE`, `TRUNCATE` and
+/// `APPEND`) say. Returns a handle to read and write it with, or the negated error.
+pub fn open(path: &str, flags: u64) -> Result<u64, i64> {
+    let result = unsafe { syscall(OPEN, [path.as_ptr() as u64, path.len() as u64, flags, 0, 0, 0]) };
+    u64::try_from(result).map_err(|_| result)
+}
+
+/// Where `seek` moves a file handle to: an offset from the start of the file, from where it
+/// is now, or from the end.
+#[derive(Debug, Clone, Copy)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+/// Moves where the next read or write of file `handle` starts. Returns the offset from the
+/// start of the file, or the negated error.
+pub fn seek(handle: u64, position: SeekFrom) -> Result<u64, i64> {
+    let (offset, whence) = match position {
+        SeekFrom::Start(offset) => (offset, 0),
+        SeekFrom::Current(offset) => (offset as u64, 1),
+        SeekFrom::End(offset) => (offset as u64, 2),
+    };
+    let result = unsafe { syscall(SEEK, [handle, offset, whence, 0, 0, 0]) };
+    u64::try_from(result).map_err(|_| result)
+}
+
+/// What `metadata` tells about a file.
+#[derive(Debug, Clone, Copy)]
+pub struct Metadata {
+    pub is_directory: bool,
+    pub size: u64,
+}
+
+/// Returns what there is to know about file `handle`, or the negated error.
+pub fn metadata(handle: u64) -> Result<Metadata, i64> {
+    let mut metadata = [0u64; 2];
+    let result = unsafe { syscall(METADATA, [handle, metadata.as_mut_ptr() as u64, 0, 0, 0, 0]) };
+    if result < 0 {
+        return Err(result);
+    }
+    Ok(Metadata { is_directory: metadata[0] == 1, size: metadata[1] })
+}
+
+/// Waits for at least `ms` milliseconds.
+pub fn sleep(ms: u64) {
+    unsafe { syscall(SLEEP, [ms, 0, 0, 0, 0, 0]) };
+}
+
+/// Returns the milliseconds since the kernel booted.
+pub fn uptime_ms() -> u64 {
+    unsafe { syscall(GET_TIME, [0; 6]) as u64 }
+}
+
+/// Moves the end of the heap to `end`, or leaves it be if `end` is 0. Returns where the end
+/// is, or the negated error.
+pub fn brk(end: u64) -> Result<u64, i64> {
+    let result = unsafe { syscall(BRK, [end, 0, 0, 0, 0, 0]) };
+    u64::try_from(result).map_err(|_| result)
+}
+
+/// Maps `length` bytes of zeroed memory, which can be read and also written or executed if
+/// `protection` has `PROTECT_WRITE` or `PROTECT_EXECUTE`. Returns where, or the negated error.
+pub fn mmap(length: u64, protection: u64) -> Result<*mut u8, i64> {
+    let result = unsafe { syscall(MMAP, [length, protection, 0, 0, 0, 0]) };
+    u64::try_from(result).map(|address| address as *mut u8).map_err(|_| result)
+}
+
+/// Opens the shared memory object called `name`, making it with `size` bytes of zeroes if
+/// there is none and `size` isn't 0. Returns a handle to map it with `mmap_shared`, or the
+/// negated error.
+pub fn shm_open(name: &str, size: u64) -> Result<u64, i64> {
+    let result = unsafe { syscall(SHM_OPEN, [name.as_ptr() as u64, name.len() as u64, size, 0, 0, 0]) };
+    u64::try_from(result).map_err(|_| result)
+}
+
+/// Maps the first `length` bytes of the shared memory object `handle` stands for, as
+/// `protection` allows like for `mmap`. Other processes that map it see the same memory.
+/// Returns where, or the negated error.
+pub fn mmap_shared(handle: u64, length: u64, protection: u64) -> Result<*mut u8, i64> {
+    let result = unsafe { syscall(MMAP, [length, protection, MAP_SHARED, handle, 0, 0]) };
+    u64::try_from(result).map(|address| address as *mut u8).map_err(|_| result)
+}
+
+/// Returns the width and height of the screen in pixels.
+pub fn screen_size() -> (u32, u32) {
+    let size = unsafe { syscall(SCREEN_SIZE, [0; 6]) } as u64;
+    ((size >> 32) as u32, size as u32)
+}
+
+/// Copies `pixels`, `width` to a row, to the screen with their top-left corner at (x, y).
+/// Pixels are 0xRRGGBB. The first drawing takes the screen from the kernel's menus and games
+/// until the program ends.
+pub fn draw(x: u32, y: u32, width: u32, pixels: &[u32]) {
+    let height = pixels.len() as u64 / u64::from(width.max(1));
+    let arguments = [x.into(), y.into(), width.into(), height, pixels.as_ptr() as u64, 0];
+    unsafe { syscall(DRAW, arguments) };
+}
+
+/// Fills a `width` by `height` rectangle of the screen at (x, y) with `colour`, 0xRRGGBB.
+pub fn fill(x: u32, y: u32, width: u32, height: u32, colour: u32) {
+    unsafe { syscall(FILL, [x.into(), y.into(), width.into(), height.into(), colour.into(), 0]) };
+}
+
+/// Standard output, to format text onto with `write!`, or `print!` and `println!`.
+pub struct Console;
+
+impl Write for Console {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        // A pipe may take only some of it at a time
+        let mut bytes = text.as_bytes();
+        while !bytes.is_empty() {
+            let written = write(STDOUT, bytes).map_err(|_| fmt::Error)?;
+            bytes = &bytes[written..];
+        }
+        Ok(())
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(arguments: fmt::Arguments) {
+    let _ = Console.write_fmt(arguments);
+}
+
+/// Prints to the console.
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::_print(format_args!($($arg)*)));
+}
+
+/// Prints to the console, with a newline.
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    println!("{info}");
+    exit(u64::MAX)
+}